@@ -0,0 +1,34 @@
+//! Records what the binary was built from, reported by `/build-info`.
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    println!("cargo:rustc-env=MITMPROXY_BUILD_FEATURES={}", features.join(","));
+
+    let git_commit = output("git", &["rev-parse", "HEAD"]).unwrap_or_default();
+    println!("cargo:rustc-env=MITMPROXY_BUILD_GIT_COMMIT={}", git_commit);
+    let dirty = output("git", &["status", "--porcelain", "--untracked-files=no"]).is_some_and(|s| !s.is_empty());
+    println!("cargo:rustc-env=MITMPROXY_BUILD_GIT_DIRTY={}", dirty);
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    println!("cargo:rustc-env=MITMPROXY_BUILD_RUSTC={}", output(&rustc, &["--version"]).unwrap_or_default());
+    println!("cargo:rustc-env=MITMPROXY_BUILD_TARGET={}", std::env::var("TARGET").unwrap_or_default());
+    println!("cargo:rustc-env=MITMPROXY_BUILD_PROFILE={}", std::env::var("PROFILE").unwrap_or_default());
+
+    println!("cargo:rerun-if-changed=build.rs");
+    // Only watch files that exist: a missing one makes cargo rerun every build
+    for path in [".git/HEAD", ".git/index"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
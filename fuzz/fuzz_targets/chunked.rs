@@ -0,0 +1,24 @@
+//! The body of a chunked request, read by the HTTP/1 server layer.
+//! Corpus inputs are bodies as captured, without the request head.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mitmproxy_rs::proxy::layers::http::Http1Server;
+use mitmproxy_rs::proxy::{AnyEvent, Context, DataReceived, Layer, Start};
+
+const HEAD: &[u8] = b"POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+fuzz_target!(|data: &[u8]| {
+    let mut server = Http1Server::new(Context::default());
+    let mut commands = server.handle_event(AnyEvent::Start(Start));
+    while commands.next_command().is_some() {}
+    // Feed the body in small reads to exercise chunks split across them
+    for data in std::iter::once(HEAD).chain(data.chunks(7)) {
+        let mut commands = server.handle_event(AnyEvent::DataReceived(DataReceived {
+            connection: Default::default(),
+            data: data.to_vec(),
+        }));
+        while commands.next_command().is_some() {}
+    }
+});
@@ -0,0 +1,10 @@
+//! TLS records carrying a ClientHello, as read from a client.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mitmproxy_rs::proxy::layers::tls::parse_client_hello;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_client_hello(data);
+});
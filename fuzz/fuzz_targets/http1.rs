@@ -0,0 +1,18 @@
+//! Requests as a client sends them, read by the HTTP/1 server layer.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mitmproxy_rs::proxy::layers::http::Http1Server;
+use mitmproxy_rs::proxy::{AnyEvent, Context, DataReceived, Layer, Start};
+
+fuzz_target!(|data: &[u8]| {
+    let mut server = Http1Server::new(Context::default());
+    let mut commands = server.handle_event(AnyEvent::Start(Start));
+    while commands.next_command().is_some() {}
+    let mut commands = server.handle_event(AnyEvent::DataReceived(DataReceived {
+        connection: Default::default(),
+        data: data.to_vec(),
+    }));
+    while commands.next_command().is_some() {}
+});
@@ -0,0 +1,14 @@
+//! A `text/event-stream` body, parsed in two chunks.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mitmproxy_rs::SseParser;
+
+fuzz_target!(|data: &[u8]| {
+    let (first, second) = data.split_at(data.len() / 2);
+    let mut parser = SseParser::new();
+    let _ = parser.parse_chunk(first);
+    let _ = parser.parse_chunk(second);
+    let _ = parser.flush();
+});
@@ -0,0 +1,797 @@
+//! External content adaptation.
+//!
+//! Requests and responses matching a service's filter are sent to an
+//! external adaptation service before they are forwarded, and the service's
+//! verdict is applied: leave the message unchanged, replace it, or block the
+//! flow with a response of its own. This lets existing DLP and antivirus
+//! appliances inspect traffic. Two protocols are spoken:
+//!
+//! - ICAP (RFC 3507) for `icap://` services, using REQMOD for requests and
+//!   RESPMOD for responses, with `Allow: 204` so unchanged messages are not
+//!   sent back.
+//! - A JSON callback for `http://` services: the message is POSTed as JSON
+//!   and the reply says what to do with it.
+//!
+//! Services run in configuration order. Each outcome is recorded in the
+//! flow's metadata under `adaptation`, and modifications in its changelog.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::changelog;
+use crate::filter::Filter;
+use crate::flow::{HTTPFlow, HTTPRequest, HTTPResponse};
+use crate::{Error, Result};
+
+/// Flow metadata key under which adaptation outcomes are recorded
+pub const METADATA_KEY: &str = "adaptation";
+
+/// Default port of ICAP services
+pub const ICAP_PORT: u16 = 1344;
+
+/// Limit on the size of an ICAP response head
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// The message a service adapts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Request,
+    Response,
+}
+
+/// An adaptation service as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdaptationService {
+    pub name: String,
+    /// `icap://host[:port]/service` or `http://host[:port]/path`
+    pub url: String,
+    /// Filter expression selecting the flows sent; empty matches all
+    #[serde(default)]
+    pub filter: String,
+    /// Messages sent to the service
+    #[serde(default = "default_phases")]
+    pub phases: Vec<Phase>,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Messages with larger bodies are forwarded without adaptation
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    /// Forward messages unchanged if the service fails, instead of failing
+    /// the flow
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+fn default_phases() -> Vec<Phase> {
+    vec![Phase::Request, Phase::Response]
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_max_body_size() -> usize {
+    10 * 1024 * 1024
+}
+
+/// What a service did with a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Unchanged,
+    Modified,
+    /// The service answered in place of the server
+    Blocked,
+    /// The message was too large to send
+    Skipped,
+    Failed,
+}
+
+/// Reply of a JSON callback service
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum CallbackReply {
+    Continue,
+    /// Replace parts of the message; absent fields are kept
+    Modify {
+        method: Option<String>,
+        path: Option<String>,
+        status_code: Option<u16>,
+        headers: Option<Vec<(String, String)>>,
+        body_base64: Option<String>,
+    },
+    /// Answer the client with this response
+    Block {
+        #[serde(default = "default_block_status")]
+        status_code: u16,
+        #[serde(default)]
+        headers: Vec<(String, String)>,
+        body_base64: Option<String>,
+    },
+}
+
+fn default_block_status() -> u16 {
+    403
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Icap,
+    Http,
+}
+
+/// A configured service, ready to use
+#[derive(Debug, Clone)]
+struct Service {
+    spec: AdaptationService,
+    filter: Filter,
+    protocol: Protocol,
+    host: String,
+    port: u16,
+    /// Path and query of the service URL
+    target: String,
+}
+
+/// The configured adaptation services, run in order
+#[derive(Debug, Clone, Default)]
+pub struct Adapter {
+    services: Vec<Service>,
+}
+
+impl Adapter {
+    pub fn new(specs: &[AdaptationService]) -> Result<Self> {
+        let mut services = Vec::new();
+        for spec in specs {
+            let url = Url::parse(&spec.url)
+                .map_err(|e| Error::invalid_request(format!("Adaptation service {}: invalid URL: {}", spec.name, e)))?;
+            let protocol = match url.scheme() {
+                "icap" => Protocol::Icap,
+                "http" => Protocol::Http,
+                other => {
+                    return Err(Error::invalid_request(format!(
+                        "Adaptation service {}: unsupported scheme {}",
+                        spec.name, other
+                    )))
+                }
+            };
+            let host = url
+                .host_str()
+                .ok_or_else(|| Error::invalid_request(format!("Adaptation service {}: URL has no host", spec.name)))?;
+            let default_port = if protocol == Protocol::Icap { ICAP_PORT } else { 80 };
+            let target = match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            };
+            services.push(Service {
+                filter: Filter::new(spec.name.clone(), spec.filter.clone())?,
+                protocol,
+                host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+                port: url.port().unwrap_or(default_port),
+                target,
+                spec: spec.clone(),
+            });
+        }
+        Ok(Self { services })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    pub fn services(&self) -> Vec<AdaptationService> {
+        self.services.iter().map(|service| service.spec.clone()).collect()
+    }
+
+    /// Run the services for `phase` on a flow. Returns false if the message
+    /// must not be forwarded: a service blocked it, in which case the flow
+    /// has its response, or a service failed and the flow has an error.
+    pub async fn adapt(&self, flow: &mut HTTPFlow, phase: Phase) -> bool {
+        for service in &self.services {
+            if !service.spec.phases.contains(&phase) || !service.filter.matches(flow) {
+                continue;
+            }
+            if phase == Phase::Response && flow.response.is_none() {
+                break;
+            }
+
+            let before = flow.clone();
+            let timeout = Duration::from_millis(service.spec.timeout_ms);
+            let result = if body_len(flow, phase) > service.spec.max_body_size {
+                Ok(Outcome::Skipped)
+            } else {
+                match tokio::time::timeout(timeout, service.adapt(flow, phase)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(Error::Proxy(format!("timed out after {}ms", service.spec.timeout_ms))),
+                }
+            };
+            let outcome = match result {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    warn!("Adaptation service {} failed: {}", service.spec.name, e);
+                    *flow = before.clone();
+                    if !service.spec.fail_open {
+                        flow.flow.set_error(format!("Adaptation service {} failed: {}", service.spec.name, e));
+                    }
+                    Outcome::Failed
+                }
+            };
+            debug!("Adaptation service {}: {:?}", service.spec.name, outcome);
+            changelog::record(flow, &before, &format!("adaptation:{}", service.spec.name), Some(phase_name(phase)));
+            record_outcome(flow, &service.spec.name, phase, &outcome);
+
+            match outcome {
+                Outcome::Blocked => return false,
+                Outcome::Failed if !service.spec.fail_open => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+impl Service {
+    async fn adapt(&self, flow: &mut HTTPFlow, phase: Phase) -> Result<Outcome> {
+        match self.protocol {
+            Protocol::Icap => self.adapt_icap(flow, phase).await,
+            Protocol::Http => self.adapt_http(flow, phase).await,
+        }
+    }
+
+    async fn adapt_icap(&self, flow: &mut HTTPFlow, phase: Phase) -> Result<Outcome> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        stream.write_all(&self.icap_request(flow, phase)).await?;
+        let response = read_icap_response(&mut stream).await?;
+
+        match response.status {
+            204 => Ok(Outcome::Unchanged),
+            200 => apply_icap(flow, phase, &response),
+            status => Err(Error::Proxy(format!("ICAP status {}", status))),
+        }
+    }
+
+    fn icap_request(&self, flow: &HTTPFlow, phase: Phase) -> Vec<u8> {
+        let request_head = http_request_head(&flow.request);
+        let mut sections = vec![("req-hdr", request_head)];
+        let body = match phase {
+            Phase::Request => flow.request.content.as_deref(),
+            Phase::Response => {
+                let response = flow.response.as_ref().expect("response phase runs on flows with a response");
+                sections.push(("res-hdr", http_response_head(response)));
+                response.content.as_deref()
+            }
+        };
+        let body = body.filter(|body| !body.is_empty());
+
+        let mut encapsulated = Vec::new();
+        let mut offset = 0;
+        for (name, head) in &sections {
+            encapsulated.push(format!("{}={}", name, offset));
+            offset += head.len();
+        }
+        let body_section = match (phase, body) {
+            (_, None) => "null-body",
+            (Phase::Request, Some(_)) => "req-body",
+            (Phase::Response, Some(_)) => "res-body",
+        };
+        encapsulated.push(format!("{}={}", body_section, offset));
+
+        let method = if phase == Phase::Request { "REQMOD" } else { "RESPMOD" };
+        let mut message = format!(
+            "{} icap://{}:{}{} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nConnection: close\r\nEncapsulated: {}\r\n\r\n",
+            method,
+            self.host,
+            self.port,
+            self.target,
+            self.host,
+            encapsulated.join(", ")
+        )
+        .into_bytes();
+        for (_, head) in sections {
+            message.extend_from_slice(&head);
+        }
+        if let Some(body) = body {
+            message.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            message.extend_from_slice(body);
+            message.extend_from_slice(b"\r\n0\r\n\r\n");
+        }
+        message
+    }
+
+    async fn adapt_http(&self, flow: &mut HTTPFlow, phase: Phase) -> Result<Outcome> {
+        let payload = json!({
+            "phase": phase,
+            "flow_id": flow.flow.id,
+            "request": request_json(&flow.request),
+            "response": flow.response.as_ref().map(response_json),
+        });
+
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(connection);
+        let request = hyper::Request::post(self.target.as_str())
+            .header(hyper::header::HOST, format!("{}:{}", self.host, self.port))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(serde_json::to_vec(&payload)?)))
+            .map_err(|e| Error::internal(e.to_string()))?;
+        let response = sender.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(Error::Proxy(format!("callback returned {}", status)));
+        }
+
+        match serde_json::from_slice::<CallbackReply>(&body)? {
+            CallbackReply::Continue => Ok(Outcome::Unchanged),
+            CallbackReply::Modify { method, path, status_code, headers, body_base64 } => {
+                let body = body_base64.map(|b| decode_base64(&b)).transpose()?;
+                match phase {
+                    Phase::Request => {
+                        let request = &mut flow.request;
+                        if let Some(method) = method {
+                            request.method = method;
+                        }
+                        if let Some(path) = path {
+                            request.path = path;
+                        }
+                        if let Some(headers) = headers {
+                            request.headers = headers;
+                        }
+                        if let Some(body) = body {
+                            request.set_content(body);
+                        }
+                    }
+                    Phase::Response => {
+                        let response = flow.response.as_mut().expect("response phase runs on flows with a response");
+                        if let Some(status_code) = status_code {
+                            response.status_code = status_code;
+                            response.reason = reason_phrase(status_code);
+                        }
+                        if let Some(headers) = headers {
+                            response.headers = headers;
+                        }
+                        if let Some(body) = body {
+                            response.set_content(body);
+                        }
+                    }
+                }
+                Ok(Outcome::Modified)
+            }
+            CallbackReply::Block { status_code, headers, body_base64 } => {
+                let mut response = HTTPResponse::new(status_code, reason_phrase(status_code));
+                response.headers = headers;
+                response.set_content(body_base64.map(|b| decode_base64(&b)).transpose()?.unwrap_or_default());
+                flow.response = Some(response);
+                Ok(Outcome::Blocked)
+            }
+        }
+    }
+}
+
+/// A parsed ICAP response
+#[derive(Debug, Default)]
+struct IcapResponse {
+    status: u16,
+    /// Encapsulated HTTP header sections by name, e.g. `res-hdr`
+    sections: Vec<(String, Vec<u8>)>,
+    /// Encapsulated body, `None` for `null-body`
+    body: Option<Vec<u8>>,
+}
+
+async fn read_icap_response<R: AsyncRead + Unpin>(reader: &mut R) -> Result<IcapResponse> {
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(pos) = find(&buf, b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD_SIZE || read_more(reader, &mut buf).await? == 0 {
+            return Err(Error::Proxy("truncated ICAP response".to_string()));
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.strip_prefix("ICAP/1.0 "))
+        .and_then(|rest| rest.get(..3))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| Error::Proxy("invalid ICAP status line".to_string()))?;
+    let encapsulated = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("encapsulated"))
+        .map(|(_, value)| parse_encapsulated(value))
+        .transpose()?;
+
+    let mut response = IcapResponse { status, ..Default::default() };
+    let Some(encapsulated) = encapsulated.filter(|_| status == 200) else {
+        return Ok(response);
+    };
+    let (body_name, body_offset) = encapsulated.last().cloned().expect("parse_encapsulated returns entries");
+    while buf.len() < head_end + body_offset {
+        if read_more(reader, &mut buf).await? == 0 {
+            return Err(Error::Proxy("truncated ICAP response".to_string()));
+        }
+    }
+    for pair in encapsulated.windows(2) {
+        let (name, start) = &pair[0];
+        let end = pair[1].1;
+        if end < *start {
+            return Err(Error::Proxy("invalid Encapsulated offsets".to_string()));
+        }
+        response.sections.push((name.clone(), buf[head_end + start..head_end + end].to_vec()));
+    }
+
+    if body_name != "null-body" {
+        let start = head_end + body_offset;
+        response.body = Some(loop {
+            if let Some(body) = decode_chunked(&buf[start..])? {
+                break body;
+            }
+            if read_more(reader, &mut buf).await? == 0 {
+                return Err(Error::Proxy("truncated ICAP body".to_string()));
+            }
+        });
+    }
+    Ok(response)
+}
+
+async fn read_more<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> Result<usize> {
+    let mut chunk = [0u8; 8192];
+    let n = reader.read(&mut chunk).await?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+/// Parse an Encapsulated header such as `res-hdr=0, res-body=120`
+fn parse_encapsulated(value: &str) -> Result<Vec<(String, usize)>> {
+    let entries = value
+        .split(',')
+        .map(|entry| {
+            let (name, offset) = entry.trim().split_once('=')?;
+            Some((name.trim().to_ascii_lowercase(), offset.trim().parse().ok()?))
+        })
+        .collect::<Option<Vec<(String, usize)>>>()
+        .filter(|entries| !entries.is_empty())
+        .ok_or_else(|| Error::Proxy(format!("invalid Encapsulated header: {}", value.trim())))?;
+    Ok(entries)
+}
+
+/// Decode a complete chunked body, or return `None` if more data is needed
+fn decode_chunked(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+    loop {
+        let Some(line_end) = find(&data[pos..], b"\r\n") else {
+            return Ok(None);
+        };
+        let line = String::from_utf8_lossy(&data[pos..pos + line_end]);
+        // Extensions such as `; ieof` follow the size
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| Error::Proxy(format!("invalid chunk size: {}", line)))?;
+        pos += line_end + 2;
+        if size == 0 {
+            return Ok(find(&data[pos..], b"\r\n").map(|_| body));
+        }
+        if data.len() < pos + size + 2 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&data[pos..pos + size]);
+        pos += size + 2;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Apply a 200 ICAP response to the adapted message
+fn apply_icap(flow: &mut HTTPFlow, phase: Phase, response: &IcapResponse) -> Result<Outcome> {
+    let section = |name: &str| response.sections.iter().find(|(n, _)| n == name).map(|(_, head)| head.as_slice());
+    let body = response.body.clone().unwrap_or_default();
+
+    // A response to REQMOD is the service answering in place of the server
+    if let Some(head) = section("res-hdr") {
+        let mut adapted = parse_response_head(head)?;
+        adapted.set_content(body);
+        let outcome = if phase == Phase::Request { Outcome::Blocked } else { Outcome::Modified };
+        flow.response = Some(adapted);
+        return Ok(outcome);
+    }
+    if let (Phase::Request, Some(head)) = (phase, section("req-hdr")) {
+        let request = &mut flow.request;
+        apply_request_head(request, head)?;
+        if response.body.is_some() || request.content.as_deref().is_some_and(|c| !c.is_empty()) {
+            request.set_content(body);
+        }
+        return Ok(Outcome::Modified);
+    }
+    Err(Error::Proxy("ICAP response without an encapsulated message".to_string()))
+}
+
+fn apply_request_head(request: &mut HTTPRequest, head: &[u8]) -> Result<()> {
+    let mut headers = [httparse::EMPTY_HEADER; 128];
+    let mut parsed = httparse::Request::new(&mut headers);
+    if !matches!(parsed.parse(head), Ok(httparse::Status::Complete(_))) {
+        return Err(Error::Proxy("invalid encapsulated request head".to_string()));
+    }
+    let target = parsed.path.unwrap_or("/");
+    request.method = parsed.method.unwrap_or("GET").to_string();
+    // Services may answer with the absolute form of the request target
+    request.path = match Url::parse(target) {
+        Ok(url) => match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        },
+        Err(_) => target.to_string(),
+    };
+    request.headers = header_pairs(parsed.headers);
+    Ok(())
+}
+
+fn parse_response_head(head: &[u8]) -> Result<HTTPResponse> {
+    let mut headers = [httparse::EMPTY_HEADER; 128];
+    let mut parsed = httparse::Response::new(&mut headers);
+    match parsed.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {
+            let status = parsed.code.unwrap_or(200);
+            let mut response = HTTPResponse::new(status, parsed.reason.map_or_else(|| reason_phrase(status), str::to_string));
+            response.headers = header_pairs(parsed.headers);
+            Ok(response)
+        }
+        _ => Err(Error::Proxy("invalid encapsulated response head".to_string())),
+    }
+}
+
+fn header_pairs(headers: &[httparse::Header]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| (h.name.to_string(), String::from_utf8_lossy(h.value).into_owned()))
+        .collect()
+}
+
+fn http_request_head(request: &HTTPRequest) -> Vec<u8> {
+    let mut head = format!("{} {} {}\r\n", request.method, request.path, request.http_version);
+    if request.get_header("host").is_none() {
+        head.push_str(&format!("Host: {}\r\n", request.pretty_host));
+    }
+    for (name, value) in &request.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+fn http_response_head(response: &HTTPResponse) -> Vec<u8> {
+    let mut head = format!("{} {} {}\r\n", response.http_version, response.status_code, response.reason);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+fn request_json(request: &HTTPRequest) -> Value {
+    json!({
+        "method": request.method,
+        "url": request.url(),
+        "path": request.path,
+        "http_version": request.http_version,
+        "headers": request.headers,
+        "body_base64": request.content.as_deref().map(|c| STANDARD.encode(c)),
+    })
+}
+
+fn response_json(response: &HTTPResponse) -> Value {
+    json!({
+        "status_code": response.status_code,
+        "reason": response.reason,
+        "http_version": response.http_version,
+        "headers": response.headers,
+        "body_base64": response.content.as_deref().map(|c| STANDARD.encode(c)),
+    })
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(data)
+        .map_err(|e| Error::Proxy(format!("invalid base64 body: {}", e)))
+}
+
+fn reason_phrase(status: u16) -> String {
+    http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn body_len(flow: &HTTPFlow, phase: Phase) -> usize {
+    let content = match phase {
+        Phase::Request => flow.request.content.as_ref(),
+        Phase::Response => flow.response.as_ref().and_then(|r| r.content.as_ref()),
+    };
+    content.map_or(0, Vec::len)
+}
+
+fn phase_name(phase: Phase) -> &'static str {
+    match phase {
+        Phase::Request => "request",
+        Phase::Response => "response",
+    }
+}
+
+fn record_outcome(flow: &mut HTTPFlow, service: &str, phase: Phase, outcome: &Outcome) {
+    let entry = json!({ "service": service, "phase": phase, "outcome": outcome });
+    match flow.flow.metadata.get_mut(METADATA_KEY).and_then(Value::as_array_mut) {
+        Some(entries) => entries.push(entry),
+        None => {
+            flow.flow.metadata.insert(METADATA_KEY.to_string(), json!([entry]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn flow() -> HTTPFlow {
+        let mut request = HTTPRequest::new("POST".to_string(), "https".to_string(), "upload.example".to_string(), 443, "/files".to_string());
+        request.set_header("Host".to_string(), "upload.example".to_string());
+        request.set_content(b"secret document".to_vec());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_header("Content-Type".to_string(), "text/plain".to_string());
+        response.set_content(b"EICAR test".to_vec());
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    fn service(url: String) -> AdaptationService {
+        AdaptationService {
+            name: "scanner".to_string(),
+            url,
+            filter: String::new(),
+            phases: default_phases(),
+            timeout_ms: 2000,
+            max_body_size: default_max_body_size(),
+            fail_open: false,
+        }
+    }
+
+    /// Serve one connection, replying with `reply` once `expect_end` was
+    /// received, and return what the client sent
+    async fn serve_once(reply: &'static [u8], expect_end: &'static [u8]) -> (u16, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            while !received.ends_with(expect_end) {
+                let mut chunk = [0u8; 4096];
+                let n = stream.read(&mut chunk).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&chunk[..n]);
+            }
+            stream.write_all(reply).await.unwrap();
+            received
+        });
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn test_icap_respmod() {
+        let reply: &[u8] = b"ICAP/1.0 200 OK\r\nEncapsulated: res-hdr=0, res-body=42\r\n\r\n\
+            HTTP/1.1 403 Forbidden\r\nX-Virus: EICAR\r\n\r\n\
+            7\r\nblocked\r\n0\r\n\r\n";
+        let (port, server) = serve_once(reply, b"0\r\n\r\n").await;
+        let adapter = Adapter::new(&[service(format!("icap://127.0.0.1:{}/avscan", port))]).unwrap();
+
+        let mut flow = flow();
+        assert!(adapter.adapt(&mut flow, Phase::Response).await);
+        let response = flow.response.as_ref().unwrap();
+        assert_eq!(response.status_code, 403);
+        assert_eq!(response.get_header("x-virus").map(String::as_str), Some("EICAR"));
+        assert_eq!(response.content.as_deref(), Some(&b"blocked"[..]));
+        assert_eq!(flow.flow.metadata[METADATA_KEY][0]["outcome"], "modified");
+        assert!(flow.flow.changes.iter().any(|c| c.source == "adaptation:scanner"));
+
+        let sent = String::from_utf8(server.await.unwrap()).unwrap();
+        assert!(sent.starts_with(&format!("RESPMOD icap://127.0.0.1:{}/avscan ICAP/1.0\r\n", port)));
+        assert!(sent.contains("Encapsulated: req-hdr=0, res-hdr="));
+        assert!(sent.ends_with("a\r\nEICAR test\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_icap_unchanged_and_reqmod_block() {
+        let (port, _) = serve_once(b"ICAP/1.0 204 No Content\r\nEncapsulated: null-body=0\r\n\r\n", b"0\r\n\r\n").await;
+        let adapter = Adapter::new(&[service(format!("icap://127.0.0.1:{}/dlp", port))]).unwrap();
+        let mut unchanged = flow();
+        assert!(adapter.adapt(&mut unchanged, Phase::Request).await);
+        assert_eq!(unchanged.request.content.as_deref(), Some(&b"secret document"[..]));
+        assert_eq!(unchanged.flow.metadata[METADATA_KEY][0]["outcome"], "unchanged");
+
+        let reply: &[u8] = b"ICAP/1.0 200 OK\r\nEncapsulated: res-hdr=0, null-body=20\r\n\r\nHTTP/1.1 451 DLP\r\n\r\n";
+        let (port, _) = serve_once(reply, b"0\r\n\r\n").await;
+        let adapter = Adapter::new(&[service(format!("icap://127.0.0.1:{}/dlp", port))]).unwrap();
+        let mut blocked = flow();
+        blocked.response = None;
+        assert!(!adapter.adapt(&mut blocked, Phase::Request).await);
+        assert_eq!(blocked.response.as_ref().unwrap().status_code, 451);
+    }
+
+    #[tokio::test]
+    async fn test_http_callback() {
+        let body = r#"{"action": "modify", "headers": [["X-Redacted", "1"]], "body_base64": "cmVkYWN0ZWQ="}"#;
+        let reply = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+        let (port, server) = serve_once(Box::leak(reply.into_boxed_str()).as_bytes(), b"}").await;
+        let adapter = Adapter::new(&[service(format!("http://127.0.0.1:{}/adapt", port))]).unwrap();
+
+        let mut flow = flow();
+        assert!(adapter.adapt(&mut flow, Phase::Request).await);
+        assert_eq!(flow.request.content.as_deref(), Some(&b"redacted"[..]));
+        assert_eq!(flow.request.headers, vec![("X-Redacted".to_string(), "1".to_string())]);
+
+        let sent = String::from_utf8(server.await.unwrap()).unwrap();
+        assert!(sent.starts_with("POST /adapt HTTP/1.1\r\n"));
+        assert!(sent.contains(r#""phase":"request""#));
+    }
+
+    #[tokio::test]
+    async fn test_service_failure() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let mut spec = service(format!("icap://127.0.0.1:{}/avscan", port));
+        let mut failed = flow();
+        assert!(!Adapter::new(&[spec.clone()]).unwrap().adapt(&mut failed, Phase::Response).await);
+        assert!(failed.flow.error.as_ref().unwrap().msg.starts_with("Adaptation service scanner failed"));
+
+        spec.fail_open = true;
+        let mut forwarded = flow();
+        assert!(Adapter::new(&[spec]).unwrap().adapt(&mut forwarded, Phase::Response).await);
+        assert!(forwarded.flow.error.is_none());
+        assert_eq!(forwarded.flow.metadata[METADATA_KEY][0]["outcome"], "failed");
+
+        assert!(Adapter::new(&[service("ftp://scanner/".to_string())]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hooks_stop_blocked_and_failed_messages() {
+        let reply: &[u8] = b"ICAP/1.0 200 OK\r\nEncapsulated: res-hdr=0, null-body=20\r\n\r\nHTTP/1.1 451 DLP\r\n\r\n";
+        let (port, _) = serve_once(reply, b"0\r\n\r\n").await;
+        let mut spec = service(format!("icap://127.0.0.1:{}/dlp", port));
+        spec.phases = vec![Phase::Request];
+        let config = crate::config::Config { adaptation_services: vec![spec], ..Default::default() };
+        let proxy = crate::proxy::ProxyServer::new(std::sync::Arc::new(config));
+        let mut blocked = flow();
+        blocked.response = None;
+        assert!(!proxy.request_hook(crate::listeners::HTTP3, &mut blocked).await);
+        assert_eq!(blocked.response.as_ref().unwrap().status_code, 451);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let mut spec = service(format!("icap://127.0.0.1:{}/avscan", port));
+        spec.phases = vec![Phase::Response];
+        let config = crate::config::Config { adaptation_services: vec![spec], ..Default::default() };
+        let proxy = crate::proxy::ProxyServer::new(std::sync::Arc::new(config));
+        let mut failed = flow();
+        assert!(proxy.request_hook(crate::listeners::HTTP3, &mut failed).await);
+        assert!(proxy.response_hook(crate::listeners::HTTP3, &mut failed).await.is_none());
+        assert!(failed.flow.error.is_some());
+    }
+}
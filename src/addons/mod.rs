@@ -0,0 +1,316 @@
+//! Addon framework.
+//!
+//! Addons are registered with the proxy's [`AddonManager`] and receive flow
+//! hooks in registration order. When loaded, an addon gets an
+//! [`AddonContext`] through which it schedules periodic work on the proxy
+//! runtime instead of spawning its own tasks, and registers custom filters
+//! and body transforms; everything scheduled or registered this way is dropped when the addon is
+//! removed or the proxy shuts down.
+
+pub mod timers;
+
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+
+use crate::changelog;
+use crate::filter::{self, FilterPredicate};
+use crate::flow::HTTPFlow;
+use crate::listeners::ListenerScope;
+use crate::panics;
+use crate::transforms::{self, BodyTransformFn};
+use crate::{Error, Result};
+pub use timers::{TimerHandle, TimerInfo, Timers};
+
+/// Extension hooking into the flow lifecycle. Hooks take `&self`; addons
+/// keep mutable state behind their own locks so timers can update it.
+pub trait Addon: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Called once when the addon is registered
+    fn load(&self, _ctx: &AddonContext) {}
+
+    /// Called with a complete request before it is forwarded
+    fn request(&self, _flow: &mut HTTPFlow) {}
+
+    /// Called with a complete response before it is sent to the client
+    fn response(&self, _flow: &mut HTTPFlow) {}
+
+    /// Called with a DNS query; setting the flow's DNS response answers it
+    /// without asking the server
+    fn dns_request(&self, _flow: &mut HTTPFlow) {}
+
+    /// Called with a DNS answer before it is sent to the client
+    fn dns_response(&self, _flow: &mut HTTPFlow) {}
+
+    /// Called with a flow about to be pruned from the store, e.g. to push
+    /// it to an export sink
+    fn archive(&self, _flow: &HTTPFlow) {}
+
+    /// Called when the addon is removed or the proxy shuts down
+    fn done(&self) {}
+}
+
+/// Facilities available to an addon, scoped to that addon
+#[derive(Debug, Clone)]
+pub struct AddonContext {
+    name: String,
+    timers: Timers,
+}
+
+impl AddonContext {
+    /// Run `callback` once after `delay`
+    pub fn once<F, Fut>(&self, delay: Duration, callback: F) -> TimerHandle
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.timers.once(&self.name, delay, callback)
+    }
+
+    /// Run `callback` every `interval`, delayed by up to `jitter` each time
+    pub fn every<F, Fut>(&self, interval: Duration, jitter: Duration, callback: F) -> TimerHandle
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.timers.every(&self.name, interval, jitter, callback)
+    }
+
+    /// Make `~name` usable in filter expressions, e.g. `~fraud` or
+    /// `~fraud high`, with `predicate` receiving the text after the name
+    pub fn register_filter<F>(&self, name: &str, help: &str, predicate: F) -> Result<()>
+    where
+        F: Fn(&HTTPFlow, Option<&str>) -> bool + Send + Sync + 'static,
+    {
+        let predicate: FilterPredicate = Arc::new(predicate);
+        filter::register_custom_filter(&self.name, name, help, predicate)
+    }
+
+    /// Decode bodies of `content_type` with `transform` before they are
+    /// shown in content views or matched by `~b`. `content_type` is a media
+    /// type such as `application/x-envelope`, or `application/*`.
+    pub fn register_body_transform<F>(&self, name: &str, content_type: &str, transform: F) -> Result<()>
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        let transform: BodyTransformFn = Arc::new(transform);
+        transforms::register_body_transform(&self.name, name, content_type, transform)
+    }
+}
+
+/// Summary of a registered addon
+#[derive(Debug, Clone, Serialize)]
+pub struct AddonInfo {
+    pub name: String,
+    pub timers: Vec<TimerInfo>,
+}
+
+/// Registered addons in hook order
+#[derive(Default)]
+pub struct AddonManager {
+    addons: Vec<Arc<dyn Addon>>,
+    timers: Timers,
+}
+
+impl std::fmt::Debug for AddonManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AddonManager")
+            .field("addons", &self.addons.iter().map(|a| a.name()).collect::<Vec<_>>())
+            .field("timers", &self.timers)
+            .finish()
+    }
+}
+
+impl AddonManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register and load an addon. Names must be unique.
+    pub fn add(&mut self, addon: Arc<dyn Addon>) -> Result<()> {
+        let name = addon.name().to_string();
+        if self.addons.iter().any(|a| a.name() == name) {
+            return Err(Error::invalid_request(format!("Addon {} is already registered", name)));
+        }
+        addon.load(&AddonContext { name, timers: self.timers.clone() });
+        self.addons.push(addon);
+        Ok(())
+    }
+
+    /// Remove an addon, cancel its timers and drop its filters and body
+    /// transforms
+    pub fn remove(&mut self, name: &str) -> bool {
+        let Some(index) = self.addons.iter().position(|a| a.name() == name) else {
+            return false;
+        };
+        let addon = self.addons.remove(index);
+        self.timers.cancel_owner(name);
+        filter::unregister_custom_filters(name);
+        transforms::unregister_body_transforms(name);
+        addon.done();
+        true
+    }
+
+    pub fn list(&self) -> Vec<AddonInfo> {
+        let timers = self.timers.list();
+        self.addons
+            .iter()
+            .map(|addon| AddonInfo {
+                name: addon.name().to_string(),
+                timers: timers.iter().filter(|t| t.owner == addon.name()).cloned().collect(),
+            })
+            .collect()
+    }
+
+    pub fn request(&self, flow: &mut HTTPFlow) {
+        self.request_scoped(flow, &ListenerScope::default());
+    }
+
+    pub fn response(&self, flow: &mut HTTPFlow) {
+        self.response_scoped(flow, &ListenerScope::default());
+    }
+
+    /// Run the request hook of the addons enabled on a listener
+    pub fn request_scoped(&self, flow: &mut HTTPFlow, scope: &ListenerScope) {
+        self.run_hook("request", flow, scope, |addon, flow| addon.request(flow));
+    }
+
+    /// Run the response hook of the addons enabled on a listener
+    pub fn response_scoped(&self, flow: &mut HTTPFlow, scope: &ListenerScope) {
+        self.run_hook("response", flow, scope, |addon, flow| addon.response(flow));
+    }
+
+    /// Run the DNS request hook of the addons enabled on a listener
+    pub fn dns_request_scoped(&self, flow: &mut HTTPFlow, scope: &ListenerScope) {
+        self.run_hook("dns_request", flow, scope, |addon, flow| addon.dns_request(flow));
+    }
+
+    /// Run the DNS response hook of the addons enabled on a listener
+    pub fn dns_response_scoped(&self, flow: &mut HTTPFlow, scope: &ListenerScope) {
+        self.run_hook("dns_response", flow, scope, |addon, flow| addon.dns_response(flow));
+    }
+
+    pub fn archive(&self, flow: &HTTPFlow) {
+        for addon in &self.addons {
+            if let Err(report) = panics::catch(|| addon.archive(flow)) {
+                error!("Addon {} {} in archive hook\n{}", addon.name(), report, report.backtrace);
+            }
+        }
+    }
+
+    /// Run a hook on every addon, recording each addon's modifications in
+    /// the flow's changelog. If an addon panics, its partial modifications
+    /// are discarded and the flow fails with the panic as its error.
+    fn run_hook(&self, hook: &str, flow: &mut HTTPFlow, scope: &ListenerScope, call: impl Fn(&dyn Addon, &mut HTTPFlow)) {
+        for addon in self.addons.iter().filter(|addon| scope.runs_addon(addon.name())) {
+            let before = flow.clone();
+            if let Err(report) = panics::catch(|| call(addon.as_ref(), flow)) {
+                error!("Addon {} {} in {} hook\n{}", addon.name(), report, hook, report.backtrace);
+                *flow = before.clone();
+                report.apply(flow);
+            }
+            changelog::record(flow, &before, &format!("addon:{}", addon.name()), Some(hook));
+        }
+    }
+
+    /// Cancel all timers and unload every addon
+    pub fn shutdown(&mut self) {
+        self.timers.cancel_all();
+        for addon in self.addons.drain(..) {
+            filter::unregister_custom_filters(addon.name());
+            transforms::unregister_body_transforms(addon.name());
+            addon.done();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+    use std::sync::RwLock;
+
+    /// Rewrites requests with a token that a timer keeps fresh
+    struct TokenRefresher {
+        token: Arc<RwLock<u32>>,
+    }
+
+    impl Addon for TokenRefresher {
+        fn name(&self) -> &str {
+            "token"
+        }
+
+        fn load(&self, ctx: &AddonContext) {
+            let token = self.token.clone();
+            ctx.every(Duration::from_millis(5), Duration::ZERO, move || {
+                *token.write().unwrap() += 1;
+                async {}
+            });
+        }
+
+        fn request(&self, flow: &mut HTTPFlow) {
+            let token = *self.token.read().unwrap();
+            flow.request.set_header("Authorization".to_string(), format!("Bearer {}", token));
+        }
+    }
+
+    struct Faulty;
+
+    impl Addon for Faulty {
+        fn name(&self) -> &str {
+            "faulty"
+        }
+
+        fn request(&self, flow: &mut HTTPFlow) {
+            flow.request.path = "/half-done".to_string();
+            panic!("unexpected state");
+        }
+    }
+
+    fn flow() -> HTTPFlow {
+        HTTPFlow::new(HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "api.example.com".to_string(),
+            443,
+            "/".to_string(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_addon_timer_refreshes_state() {
+        let mut manager = AddonManager::new();
+        manager.add(Arc::new(TokenRefresher { token: Arc::new(RwLock::new(0)) })).unwrap();
+        assert!(manager.add(Arc::new(TokenRefresher { token: Arc::new(RwLock::new(0)) })).is_err());
+        assert_eq!(manager.list()[0].timers.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut f = flow();
+        manager.request(&mut f);
+        assert_ne!(f.request.get_header("authorization").map(String::as_str), Some("Bearer 0"));
+        assert_eq!(f.flow.changes[0].source, "addon:token");
+        assert_eq!(f.flow.changes[0].field, "request.headers.authorization");
+
+        assert!(manager.remove("token"));
+        assert!(manager.timers.list().is_empty());
+        assert!(!manager.remove("token"));
+    }
+
+    #[tokio::test]
+    async fn test_addon_panic_fails_flow() {
+        let mut manager = AddonManager::new();
+        manager.add(Arc::new(Faulty)).unwrap();
+        manager.add(Arc::new(TokenRefresher { token: Arc::new(RwLock::new(0)) })).unwrap();
+
+        let mut f = flow();
+        manager.request(&mut f);
+        assert_eq!(f.request.path, "/");
+        assert_eq!(f.flow.error.as_ref().unwrap().msg, "Internal error: unexpected state");
+        // Later addons still run
+        assert!(f.request.get_header("authorization").is_some());
+        manager.shutdown();
+    }
+}
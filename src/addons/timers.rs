@@ -0,0 +1,272 @@
+//! Scheduled callbacks for addons.
+//!
+//! Timers run on the proxy's tokio runtime. A recurring timer waits for its
+//! interval plus a random jitter, runs the callback to completion and then
+//! schedules the next run, so runs of the same timer never overlap. A panic
+//! in a callback is logged and does not stop a recurring timer. Timers are
+//! cancelled explicitly, when their addon is removed, or on shutdown.
+
+use futures_util::FutureExt;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tracing::{debug, error};
+
+/// Description of a scheduled timer
+#[derive(Debug, Clone, Serialize)]
+pub struct TimerInfo {
+    pub id: u64,
+    /// Name of the addon that registered the timer
+    pub owner: String,
+    /// Delay before the first run
+    pub delay_ms: u64,
+    /// Interval between runs, `None` for one-shot timers
+    pub interval_ms: Option<u64>,
+    pub jitter_ms: u64,
+    pub runs: u64,
+}
+
+#[derive(Debug)]
+struct Timer {
+    info: TimerInfo,
+    abort: AbortHandle,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    timers: BTreeMap<u64, Timer>,
+}
+
+/// Registry of scheduled callbacks; cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct Timers {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// Handle to a scheduled timer. Dropping it does not cancel the timer.
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    id: u64,
+    timers: Timers,
+}
+
+impl TimerHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Cancel the timer, returning false if it already finished
+    pub fn cancel(&self) -> bool {
+        self.timers.cancel(self.id)
+    }
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `callback` once after `delay`. Must be called within the proxy
+    /// runtime.
+    pub fn once<F, Fut>(&self, owner: &str, delay: Duration, callback: F) -> TimerHandle
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut callback = Some(callback);
+        self.schedule(owner, delay, None, Duration::ZERO, move || {
+            let callback = callback.take().expect("one-shot timer runs once");
+            callback()
+        })
+    }
+
+    /// Run `callback` every `interval`, each run delayed by a random amount
+    /// of up to `jitter`. Must be called within the proxy runtime.
+    pub fn every<F, Fut>(&self, owner: &str, interval: Duration, jitter: Duration, callback: F) -> TimerHandle
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.schedule(owner, interval, Some(interval), jitter, callback)
+    }
+
+    fn schedule<F, Fut>(
+        &self,
+        owner: &str,
+        delay: Duration,
+        interval: Option<Duration>,
+        jitter: Duration,
+        mut callback: F,
+    ) -> TimerHandle
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+
+        let timers = self.clone();
+        let task = tokio::spawn(async move {
+            let mut wait = delay;
+            loop {
+                tokio::time::sleep(wait + random_jitter(jitter)).await;
+                if let Err(panic) = AssertUnwindSafe(callback()).catch_unwind().await {
+                    error!("Timer {} panicked: {}", id, panic_message(&panic));
+                }
+
+                let mut inner = timers.inner.lock().unwrap();
+                match interval {
+                    Some(interval) => {
+                        if let Some(timer) = inner.timers.get_mut(&id) {
+                            timer.info.runs += 1;
+                        }
+                        wait = interval;
+                    }
+                    None => {
+                        inner.timers.remove(&id);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let info = TimerInfo {
+            id,
+            owner: owner.to_string(),
+            delay_ms: delay.as_millis() as u64,
+            interval_ms: interval.map(|i| i.as_millis() as u64),
+            jitter_ms: jitter.as_millis() as u64,
+            runs: 0,
+        };
+        debug!("Scheduled timer {} for {}", id, owner);
+        inner.timers.insert(id, Timer { info, abort: task.abort_handle() });
+        TimerHandle { id, timers: self.clone() }
+    }
+
+    /// Cancel a timer by id
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.inner.lock().unwrap().timers.remove(&id) {
+            Some(timer) => {
+                timer.abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancel all timers registered by an addon, returning how many there were
+    pub fn cancel_owner(&self, owner: &str) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let ids: Vec<u64> = inner.timers.values().filter(|t| t.info.owner == owner).map(|t| t.info.id).collect();
+        for id in &ids {
+            if let Some(timer) = inner.timers.remove(id) {
+                timer.abort.abort();
+            }
+        }
+        ids.len()
+    }
+
+    pub fn cancel_all(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        for (_, timer) in std::mem::take(&mut inner.timers) {
+            timer.abort.abort();
+        }
+    }
+
+    /// Pending timers, in registration order
+    pub fn list(&self) -> Vec<TimerInfo> {
+        self.inner.lock().unwrap().timers.values().map(|t| t.info.clone()).collect()
+    }
+}
+
+fn random_jitter(jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return Duration::ZERO;
+    }
+    rand::thread_rng().gen_range(Duration::ZERO..=jitter)
+}
+
+fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counter() -> (Arc<AtomicUsize>, impl FnMut() -> futures_util::future::Ready<()> + Send + 'static) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counted = count.clone();
+        (count, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            futures_util::future::ready(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_once_runs_and_is_removed() {
+        let timers = Timers::new();
+        let (count, mut callback) = counter();
+        timers.once("test", Duration::from_millis(5), move || callback());
+        assert_eq!(timers.list().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(timers.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_every_with_cancellation() {
+        let timers = Timers::new();
+        let (count, callback) = counter();
+        let handle = timers.every("refresh", Duration::from_millis(5), Duration::from_millis(2), callback);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(handle.cancel());
+        let runs = count.load(Ordering::SeqCst);
+        assert!(runs >= 2, "ran {} times", runs);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(count.load(Ordering::SeqCst), runs);
+        assert!(!handle.cancel());
+    }
+
+    #[tokio::test]
+    async fn test_panicking_callback_keeps_running() {
+        let timers = Timers::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counted = count.clone();
+        timers.every("flaky", Duration::from_millis(5), Duration::ZERO, move || {
+            let run = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run == 0 {
+                    panic!("first run fails");
+                }
+            }
+        });
+
+        // Reporting the panic may be slow, so allow plenty of time
+        for _ in 0..200 {
+            if count.load(Ordering::SeqCst) >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(count.load(Ordering::SeqCst) >= 2);
+        assert_eq!(timers.list()[0].owner, "flaky");
+        assert_eq!(timers.cancel_owner("flaky"), 1);
+        assert_eq!(timers.cancel_owner("flaky"), 0);
+    }
+}
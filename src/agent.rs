@@ -0,0 +1,452 @@
+//! Reverse tunnel agents for capturing remote hosts.
+//!
+//! `mitmproxy-rs agent` runs next to the applications to capture. It
+//! accepts their proxy connections on a local port and carries them to the
+//! central proxy over a single outbound TLS connection, so the remote
+//! machine needs no inbound firewall rules. Agents authenticate with a
+//! shared token. On the central side each tunneled connection is handed to
+//! the proxy listener and goes through the same layer stack as a directly
+//! connected client.
+//!
+//! Connections are multiplexed with a small framing protocol: each frame
+//! carries a stream id, a kind and a length-prefixed payload. There is no
+//! per-stream flow control, so a slow connection stalls the others, which
+//! is acceptable for the handful of clients an agent serves.
+
+use bytes::Bytes;
+use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::certs::CertificateAuthority;
+use crate::{Error, Result};
+
+/// Stream id, kind and payload length
+const HEADER_LEN: usize = 9;
+
+const MAX_PAYLOAD: usize = 16 * 1024;
+
+/// Time an agent has to authenticate after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before an agent reconnects a failed tunnel
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Central side options as configured in the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgentListenerOptions {
+    /// Port of the proxy host agents connect to; disabled if unset
+    pub port: Option<u16>,
+    /// Token agents must present
+    pub token: Option<String>,
+    /// Name on the certificate presented to agents; the proxy host if unset
+    pub hostname: Option<String>,
+}
+
+/// Agent options, as given on the command line
+#[derive(Debug, Clone)]
+pub struct AgentOptions {
+    /// Tunnel address of the central proxy, `host:port`
+    pub server: String,
+    pub token: String,
+    /// Local address applications use as their proxy
+    pub listen: String,
+    /// Name the agent reports to the central proxy
+    pub name: String,
+    /// PEM file of the CA the central proxy's certificate is checked
+    /// against; the system roots if unset
+    pub ca_cert: Option<String>,
+    /// Accept any certificate from the central proxy
+    pub insecure: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Hello {
+    token: String,
+    name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    /// Agent authentication, on stream 0
+    Hello,
+    /// Authentication accepted, on stream 0
+    Welcome,
+    /// The agent accepted a connection
+    Open,
+    Data,
+    /// The sender will not write to the stream anymore
+    Close,
+}
+
+impl Kind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        [Kind::Hello, Kind::Welcome, Kind::Open, Kind::Data, Kind::Close].get(kind as usize).copied()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Frame {
+    stream: u32,
+    kind: Kind,
+    payload: Bytes,
+}
+
+impl Frame {
+    fn new(stream: u32, kind: Kind, payload: impl Into<Bytes>) -> Self {
+        Self { stream, kind, payload: payload.into() }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.extend_from_slice(&self.stream.to_be_bytes());
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+}
+
+/// The next frame, or `None` if the tunnel was closed between frames
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_LEN];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let stream = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let kind = Kind::from_u8(header[4]).ok_or_else(|| Error::Proxy(format!("Unknown tunnel frame kind {}", header[4])))?;
+    let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(Error::Proxy(format!("Tunnel frame of {} bytes exceeds the limit", len)));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(Frame::new(stream, kind, payload)))
+}
+
+/// One end of a tunnel
+#[derive(Clone)]
+struct Mux {
+    frames: mpsc::Sender<Frame>,
+    streams: Arc<std::sync::Mutex<HashMap<u32, mpsc::Sender<Bytes>>>>,
+}
+
+impl Mux {
+    /// Start writing frames to `writer`
+    fn new<W: AsyncWrite + Unpin + Send + 'static>(mut writer: W) -> Self {
+        let (frames, mut outgoing) = mpsc::channel::<Frame>(64);
+        tokio::spawn(async move {
+            while let Some(frame) = outgoing.recv().await {
+                if writer.write_all(&frame.encode()).await.is_err() {
+                    return;
+                }
+                if outgoing.is_empty() && writer.flush().await.is_err() {
+                    return;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+        Self { frames, streams: Arc::default() }
+    }
+
+    /// Accept data for stream `id`. Registered before the stream's
+    /// connection is ready, so that no data is lost meanwhile.
+    fn register(&self, id: u32) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel(16);
+        self.streams.lock().unwrap().insert(id, tx);
+        rx
+    }
+
+    async fn close(&self, id: u32) {
+        self.streams.lock().unwrap().remove(&id);
+        let _ = self.frames.send(Frame::new(id, Kind::Close, Bytes::new())).await;
+    }
+
+    /// Copy data between stream `id` and `conn` until both directions are
+    /// closed
+    fn pipe(&self, id: u32, conn: TcpStream, mut incoming: mpsc::Receiver<Bytes>) {
+        let (mut read_half, mut write_half) = conn.into_split();
+        let frames = self.frames.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_PAYLOAD];
+            while let Ok(n @ 1..) = read_half.read(&mut buf).await {
+                if frames.send(Frame::new(id, Kind::Data, Bytes::copy_from_slice(&buf[..n]))).await.is_err() {
+                    return;
+                }
+            }
+            let _ = frames.send(Frame::new(id, Kind::Close, Bytes::new())).await;
+        });
+        tokio::spawn(async move {
+            while let Some(data) = incoming.recv().await {
+                if write_half.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            let _ = write_half.shutdown().await;
+        });
+    }
+
+    /// Route frames from `reader` to their streams until the tunnel is
+    /// closed. Streams opened by the peer are passed to `on_open`.
+    async fn dispatch<R: AsyncRead + Unpin>(&self, mut reader: R, on_open: impl Fn(&Mux, u32)) -> Result<()> {
+        let result = async {
+            while let Some(frame) = read_frame(&mut reader).await? {
+                match frame.kind {
+                    Kind::Open => on_open(self, frame.stream),
+                    Kind::Data => {
+                        let stream = self.streams.lock().unwrap().get(&frame.stream).cloned();
+                        if let Some(stream) = stream {
+                            let _ = stream.send(frame.payload).await;
+                        }
+                    }
+                    Kind::Close => {
+                        self.streams.lock().unwrap().remove(&frame.stream);
+                    }
+                    Kind::Hello | Kind::Welcome => return Err(Error::Proxy("Unexpected handshake frame in tunnel".to_string())),
+                }
+            }
+            Ok(())
+        }
+        .await;
+        // Closes the local end of every stream
+        self.streams.lock().unwrap().clear();
+        result
+    }
+}
+
+/// Serve an authenticated agent tunnel on `io`, connecting each tunneled
+/// stream to `target`. Returns when the tunnel is closed.
+pub async fn serve_tunnel<S>(io: S, peer: SocketAddr, token: &str, target: SocketAddr) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(io);
+    let hello = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(&mut reader)).await {
+        Ok(Ok(Some(Frame { kind: Kind::Hello, payload, .. }))) => serde_json::from_slice::<Hello>(&payload)?,
+        Ok(Err(e)) => return Err(e),
+        Ok(_) => return Err(Error::auth(format!("Agent at {} did not authenticate", peer))),
+        Err(_) => return Err(Error::auth(format!("Agent at {} timed out authenticating", peer))),
+    };
+    if !tokens_match(&hello.token, token) {
+        let _ = writer.write_all(&Frame::new(0, Kind::Close, "invalid token").encode()).await;
+        return Err(Error::auth(format!("Agent {} at {} presented an invalid token", hello.name, peer)));
+    }
+    writer.write_all(&Frame::new(0, Kind::Welcome, Bytes::new()).encode()).await?;
+    writer.flush().await?;
+    info!("Agent {} connected from {}", hello.name, peer);
+
+    let mux = Mux::new(writer);
+    let result = mux
+        .dispatch(reader, |mux, id| {
+            let incoming = mux.register(id);
+            let mux = mux.clone();
+            tokio::spawn(async move {
+                match TcpStream::connect(target).await {
+                    Ok(conn) => mux.pipe(id, conn, incoming),
+                    Err(e) => {
+                        warn!("Cannot hand tunneled connection to the proxy at {}: {}", target, e);
+                        mux.close(id).await;
+                    }
+                }
+            });
+        })
+        .await;
+    info!("Agent {} disconnected", hello.name);
+    result
+}
+
+/// Authenticate on `io` and carry connections accepted on `listener`
+/// through it until the tunnel is closed
+pub async fn serve_agent<S>(io: S, listener: &TcpListener, token: &str, name: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(io);
+    let hello = serde_json::to_vec(&Hello { token: token.to_string(), name: name.to_string() })?;
+    writer.write_all(&Frame::new(0, Kind::Hello, hello).encode()).await?;
+    writer.flush().await?;
+    match read_frame(&mut reader).await? {
+        Some(Frame { kind: Kind::Welcome, .. }) => {}
+        Some(Frame { kind: Kind::Close, payload, .. }) => {
+            return Err(Error::auth(format!("Tunnel rejected: {}", String::from_utf8_lossy(&payload))));
+        }
+        _ => return Err(Error::auth("Tunnel closed during authentication")),
+    }
+
+    let mux = Mux::new(writer);
+    let accept = async {
+        let mut next_id = 1u32;
+        loop {
+            let (conn, addr) = listener.accept().await?;
+            debug!("Tunneling connection from {} as stream {}", addr, next_id);
+            let incoming = mux.register(next_id);
+            if mux.frames.send(Frame::new(next_id, Kind::Open, Bytes::new())).await.is_err() {
+                return Ok(());
+            }
+            mux.pipe(next_id, conn, incoming);
+            next_id = next_id.wrapping_add(1).max(1);
+        }
+    };
+    // The central proxy never opens streams itself
+    let dispatch = mux.dispatch(reader, |_, id| debug!("Ignoring stream {} opened by the proxy", id));
+    tokio::select! {
+        result = accept => result,
+        result = dispatch => result,
+    }
+}
+
+/// Run an agent until it is stopped, reconnecting the tunnel when it fails
+pub async fn run_agent(options: &AgentOptions) -> Result<()> {
+    let listener = TcpListener::bind(&options.listen).await?;
+    info!("Agent listening on {}, tunneling to {}", options.listen, options.server);
+
+    let mut connector = SslConnector::builder(SslMethod::tls())?;
+    if let Some(ca_cert) = &options.ca_cert {
+        connector.set_ca_file(ca_cert)?;
+    }
+    if options.insecure {
+        connector.set_verify(SslVerifyMode::NONE);
+    }
+    let connector = connector.build();
+    let host = options.server.rsplit_once(':').map_or(options.server.as_str(), |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    loop {
+        let tunnel = async {
+            let stream = TcpStream::connect(&options.server).await?;
+            let ssl = connector.configure()?.verify_hostname(!options.insecure).into_ssl(host)?;
+            let mut tls = tokio_openssl::SslStream::new(ssl, stream)?;
+            Pin::new(&mut tls)
+                .connect()
+                .await
+                .map_err(|e| Error::Proxy(format!("TLS handshake with {} failed: {}", options.server, e)))?;
+            info!("Tunnel to {} established", options.server);
+            serve_agent(tls, &listener, &options.token, &options.name).await
+        };
+        match tunnel.await {
+            Ok(()) => warn!("Tunnel to {} closed", options.server),
+            Err(e @ Error::Auth(_)) => return Err(e),
+            Err(e) => warn!("Tunnel to {} failed: {}", options.server, e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Accept agent tunnels on `listener`, presenting a certificate for
+/// `hostname` signed by `ca`, and hand their connections to `target`
+pub async fn run_listener(
+    listener: TcpListener,
+    ca: &CertificateAuthority,
+    hostname: &str,
+    token: String,
+    target: SocketAddr,
+) -> Result<()> {
+    let (cert, key) = ca.get_cert_for_host(hostname).await?;
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    acceptor.set_certificate(&cert)?;
+    acceptor.set_private_key(&key)?;
+    acceptor.add_extra_chain_cert(openssl::x509::X509::from_der(&ca.ca_cert_der()?)?)?;
+    let acceptor = Arc::new(acceptor.build());
+    let token = Arc::new(token);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = Arc::clone(&acceptor);
+        let token = Arc::clone(&token);
+        tokio::spawn(async move {
+            let tunnel = async {
+                let ssl = openssl::ssl::Ssl::new(acceptor.context())?;
+                let mut tls = tokio_openssl::SslStream::new(ssl, stream)?;
+                Pin::new(&mut tls).accept().await.map_err(|e| Error::Proxy(format!("TLS handshake failed: {}", e)))?;
+                serve_tunnel(tls, peer, &token, target).await
+            };
+            if let Err(e) = tunnel.await {
+                warn!("Agent tunnel from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Compare tokens in time independent of where they differ
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = conn.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_frame_encoding() {
+        let frame = Frame::new(7, Kind::Data, &b"abc"[..]);
+        assert_eq!(frame.encode(), [0, 0, 0, 7, 3, 0, 0, 0, 3, b'a', b'b', b'c']);
+        let decoded = tokio_test::block_on(read_frame(&mut &frame.encode()[..])).unwrap();
+        assert_eq!(decoded, Some(frame));
+        assert!(tokio_test::block_on(read_frame(&mut &[0u8, 0, 0, 1, 9, 0, 0, 0, 0][..])).is_err());
+        assert!(tokio_test::block_on(read_frame(&mut &[][..])).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_round_trip() {
+        let target = echo_server().await;
+        let (agent_io, central_io) = tokio::io::duplex(64 * 1024);
+        let peer: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        tokio::spawn(async move { serve_tunnel(central_io, peer, "secret", target).await });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(async move { serve_agent(agent_io, &listener, "secret", "test").await });
+
+        let mut clients = Vec::new();
+        for i in 0..3 {
+            clients.push(tokio::spawn(async move {
+                let mut conn = TcpStream::connect(local).await.unwrap();
+                let message = format!("hello {}", i).repeat(10_000);
+                conn.write_all(message.as_bytes()).await.unwrap();
+                conn.shutdown().await.unwrap();
+                let mut echoed = String::new();
+                conn.read_to_string(&mut echoed).await.unwrap();
+                assert_eq!(echoed, message);
+            }));
+        }
+        for client in clients {
+            client.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token() {
+        let (agent_io, central_io) = tokio::io::duplex(1024);
+        let peer: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let central = tokio::spawn(async move { serve_tunnel(central_io, peer, "secret", peer).await });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = serve_agent(agent_io, &listener, "guess", "test").await;
+        assert!(matches!(agent, Err(Error::Auth(message)) if message.contains("invalid token")));
+        assert!(matches!(central.await.unwrap(), Err(Error::Auth(_))));
+        assert!(tokens_match("secret", "secret") && !tokens_match("secret", "secreT") && !tokens_match("s", "secret"));
+    }
+}
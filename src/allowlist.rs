@@ -0,0 +1,225 @@
+//! Allowlist-only mode: no connections outside an explicit list of hosts.
+//!
+//! Meant for lab setups where a capture box must never reach production.
+//! When enabled, requests to any destination not on the list are answered
+//! with 403 and never forwarded, whichever way they reach the proxy; replays
+//! and other requests the proxy sends itself, such as fetching the rest of
+//! a lazy body, are refused too. Connections to infrastructure the operator
+//! configures directly (upstream proxies, the DNS resolver, adaptation
+//! services and agent servers) are not checked. Entries are host names
+//! (`api.lab.example`), domains with their subdomains (`*.lab.example`),
+//! addresses or CIDR ranges (`10.0.0.0/8`), each optionally limited to one
+//! port (`api.lab.example:8443`). An empty list allows nothing.
+//!
+//! The list can be changed at runtime through the API. Every change is
+//! kept in an audit trail with the settings before and after it.
+
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::capture_profiles::{in_range, parse_range};
+use crate::flow::HTTPResponse;
+use crate::{Error, Result};
+
+/// Audit entries kept before the oldest are dropped
+pub const MAX_AUDIT_ENTRIES: usize = 1000;
+
+/// Allowlist-only mode as configured in the config file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AllowlistOptions {
+    /// Refuse every destination not in `hosts`
+    pub enabled: bool,
+    pub hosts: Vec<String>,
+}
+
+/// A change of the allowlist settings
+#[derive(Debug, Clone, Serialize)]
+pub struct AllowlistChange {
+    pub timestamp: f64,
+    /// Who made the change, such as `config` or `api`
+    pub source: String,
+    pub before: AllowlistOptions,
+    pub after: AllowlistOptions,
+}
+
+#[derive(Debug, Clone)]
+enum HostPattern {
+    Name(String),
+    /// A domain and its subdomains
+    Domain(String),
+    Range(IpAddr, u8),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    host: HostPattern,
+    port: Option<u16>,
+}
+
+impl Entry {
+    fn parse(spec: &str) -> Result<Self> {
+        let invalid = || Error::invalid_request(format!("Invalid allowlist entry: {}", spec));
+        let spec = spec.trim();
+        // `[v6]:port`, `host:port`, or a bare host, address or range
+        let (host, port) = match spec.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+            Some((host, rest)) => (host, rest.strip_prefix(':')),
+            None => match spec.rsplit_once(':') {
+                Some((host, port)) if !host.contains(':') => (host, Some(port)),
+                _ => (spec, None),
+            },
+        };
+        let port = port.map(|port| port.parse::<u16>().map_err(|_| invalid())).transpose()?;
+        let host = if let Some(domain) = host.strip_prefix("*.") {
+            HostPattern::Domain(domain.to_ascii_lowercase())
+        } else if host.parse::<IpAddr>().is_ok() || host.contains('/') {
+            let (address, prefix) = parse_range(host).map_err(|_| invalid())?;
+            HostPattern::Range(address, prefix)
+        } else {
+            HostPattern::Name(host.to_ascii_lowercase())
+        };
+        if matches!(&host, HostPattern::Name(name) | HostPattern::Domain(name) if name.is_empty() || name.contains(['*', '/'])) {
+            return Err(invalid());
+        }
+        Ok(Self { host, port })
+    }
+
+    fn matches(&self, host: &str, port: u16) -> bool {
+        if self.port.is_some_and(|allowed| allowed != port) {
+            return false;
+        }
+        let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase();
+        match &self.host {
+            HostPattern::Name(name) => host == *name,
+            HostPattern::Domain(domain) => host == *domain || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.')),
+            HostPattern::Range(address, prefix) => host.parse().is_ok_and(|ip| in_range(ip, *address, *prefix)),
+        }
+    }
+}
+
+/// The allowlist in effect and the changes made to it
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    options: AllowlistOptions,
+    entries: Vec<Entry>,
+    audit: Vec<AllowlistChange>,
+}
+
+impl Allowlist {
+    pub fn new(options: &AllowlistOptions) -> Result<Self> {
+        let mut allowlist = Self::default();
+        allowlist.update(options.clone(), "config")?;
+        Ok(allowlist)
+    }
+
+    pub fn options(&self) -> &AllowlistOptions {
+        &self.options
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.options.enabled
+    }
+
+    /// Changes made so far, oldest first
+    pub fn audit(&self) -> &[AllowlistChange] {
+        &self.audit
+    }
+
+    /// Replace the settings, noting the change made by `source` in the
+    /// audit trail. Invalid entries leave the settings unchanged.
+    pub fn update(&mut self, options: AllowlistOptions, source: &str) -> Result<AllowlistChange> {
+        let entries = options.hosts.iter().map(|spec| Entry::parse(spec)).collect::<Result<_>>()?;
+        let change = AllowlistChange {
+            timestamp: crate::clock::now(),
+            source: source.to_string(),
+            before: std::mem::replace(&mut self.options, options.clone()),
+            after: options,
+        };
+        self.entries = entries;
+        if self.audit.len() >= MAX_AUDIT_ENTRIES {
+            self.audit.remove(0);
+        }
+        self.audit.push(change.clone());
+        Ok(change)
+    }
+
+    /// Whether the proxy may connect to `host:port`
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        !self.options.enabled || self.entries.iter().any(|entry| entry.matches(host, port))
+    }
+
+    /// The error for a connection to `host:port` the allowlist refuses, if
+    /// it does
+    pub fn check(&self, host: &str, port: u16) -> Result<()> {
+        if self.allows(host, port) {
+            Ok(())
+        } else {
+            Err(Error::Proxy(format!("{}:{} is not on the allowlist", host, port)))
+        }
+    }
+}
+
+/// Answer to a request for a destination not on the allowlist
+pub fn refusal(host: &str, port: u16) -> HTTPResponse {
+    let body = format!(
+        "Refused by mitmproxy-rs: the proxy is in allowlist-only mode and {}:{} is not on the allowlist.\n",
+        host, port
+    );
+    let mut response = HTTPResponse::new(403, "Forbidden".to_string());
+    response.headers = vec![
+        ("Content-Type".to_string(), "text/plain; charset=utf-8".to_string()),
+        ("Content-Length".to_string(), body.len().to_string()),
+    ];
+    response.timestamp_start = Some(crate::clock::now());
+    response.set_content(body.into_bytes());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(enabled: bool, hosts: &[&str]) -> AllowlistOptions {
+        AllowlistOptions { enabled, hosts: hosts.iter().map(|h| h.to_string()).collect() }
+    }
+
+    #[test]
+    fn test_allows() {
+        let allowlist =
+            Allowlist::new(&options(true, &["api.lab.example", "*.staging.example:8443", "10.1.0.0/16", "[::1]:8080"])).unwrap();
+        assert!(allowlist.allows("API.lab.example", 443));
+        assert!(!allowlist.allows("lab.example", 443));
+        assert!(!allowlist.allows("api.lab.example.evil.com", 443));
+        assert!(allowlist.allows("staging.example", 8443));
+        assert!(allowlist.allows("a.b.staging.example", 8443));
+        assert!(!allowlist.allows("a.staging.example", 443));
+        assert!(!allowlist.allows("notstaging.example", 8443));
+        assert!(allowlist.allows("10.1.200.3", 80));
+        assert!(!allowlist.allows("10.2.0.1", 80));
+        assert!(allowlist.allows("::1", 8080) && allowlist.allows("[::1]", 8080));
+        assert!(allowlist.check("example.com", 443).is_err());
+
+        assert!(Allowlist::new(&options(true, &[])).unwrap().check("api.lab.example", 443).is_err());
+        assert!(Allowlist::new(&options(false, &[])).unwrap().allows("example.com", 443));
+
+        let refusal = refusal("example.com", 443);
+        assert_eq!(refusal.status_code, 403);
+        assert!(String::from_utf8(refusal.content.unwrap()).unwrap().contains("example.com:443 is not on the allowlist"));
+    }
+
+    #[test]
+    fn test_updates_are_audited() {
+        let mut allowlist = Allowlist::new(&options(false, &[])).unwrap();
+        let change = allowlist.update(options(true, &["lab.example"]), "api").unwrap();
+        assert_eq!((change.source.as_str(), change.before.enabled, change.after.enabled), ("api", false, true));
+        assert!(allowlist.allows("lab.example", 80) && !allowlist.allows("example.com", 80));
+
+        for invalid in ["", "*.", "a/b", "10.0.0.0/33", "host:port", "*.a*.example"] {
+            assert!(allowlist.update(options(false, &[invalid]), "api").is_err(), "{}", invalid);
+        }
+        assert!(allowlist.is_enabled());
+        assert_eq!(allowlist.options().hosts, ["lab.example"]);
+        let sources: Vec<&str> = allowlist.audit().iter().map(|change| change.source.as_str()).collect();
+        assert_eq!(sources, ["config", "api"]);
+    }
+}
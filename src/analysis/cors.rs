@@ -0,0 +1,379 @@
+//! CORS preflight analysis.
+//!
+//! Pairs `OPTIONS` preflights with the cross-origin requests that follow
+//! them and validates that the `Access-Control-*` headers of the preflight
+//! response actually permit the observed request: origin, method, request
+//! headers and credentials. The response of the actual request must allow
+//! the origin as well, or the browser hides it from the page.
+//!
+//! [`CorsTracker`] applies the same checks to flows as they complete and
+//! flags mismatching requests in their metadata under [`METADATA_KEY`].
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use indexmap::IndexMap;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::flow::{HTTPFlow, HTTPResponse};
+
+/// Flow metadata key of the CORS problems found for a request
+pub const METADATA_KEY: &str = "cors";
+
+/// Preflights remembered by the tracker for pairing
+const MAX_PREFLIGHTS: usize = 1000;
+
+/// Methods that never need to be listed in `Access-Control-Allow-Methods`
+const SAFELISTED_METHODS: &[&str] = &["GET", "HEAD", "POST"];
+
+/// Outcome of a preflight/request pair
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CorsStatus {
+    /// The headers permit the request
+    Allowed,
+    /// The headers do not permit the request
+    Mismatch,
+    /// No request followed the preflight
+    Unanswered,
+}
+
+/// A preflight and the request it was sent for
+#[derive(Debug, Clone, Serialize)]
+pub struct CorsExchange {
+    pub preflight_id: String,
+    pub request_id: Option<String>,
+    pub origin: String,
+    pub url: String,
+    pub method: String,
+    pub status: CorsStatus,
+    pub problems: Vec<String>,
+}
+
+/// The parts of a preflight needed to validate the request that follows it
+#[derive(Debug, Clone)]
+struct Preflight {
+    flow_id: String,
+    origin: String,
+    url: String,
+    method: String,
+    headers: Vec<String>,
+    response: Option<HTTPResponse>,
+}
+
+impl Preflight {
+    fn parse(flow: &HTTPFlow) -> Option<Self> {
+        if !flow.request.method.eq_ignore_ascii_case("OPTIONS") {
+            return None;
+        }
+        let origin = flow.request.get_header("origin")?;
+        let method = flow.request.get_header("access-control-request-method")?;
+        Some(Preflight {
+            flow_id: flow.flow.id.clone(),
+            origin: origin.trim().to_string(),
+            url: flow.request.url(),
+            method: method.trim().to_string(),
+            headers: flow
+                .request
+                .get_header("access-control-request-headers")
+                .map(|value| header_list(value))
+                .unwrap_or_default(),
+            response: flow.response.clone(),
+        })
+    }
+
+    fn key(&self) -> (String, String, String) {
+        (self.origin.clone(), self.url.clone(), self.method.to_ascii_uppercase())
+    }
+
+    /// Problems that make the browser reject the preflight for a request
+    /// with the given credentials mode
+    fn problems(&self, credentials: bool) -> Vec<String> {
+        let Some(response) = &self.response else {
+            return vec!["Preflight received no response".to_string()];
+        };
+        let mut problems = Vec::new();
+        if !(200..300).contains(&response.status_code) {
+            problems.push(format!("Preflight answered with status {}", response.status_code));
+        }
+        problems.extend(origin_problems("Preflight", response, &self.origin, credentials));
+
+        let wildcard = |list: &[String]| !credentials && list.iter().any(|item| item == "*");
+        let methods = response
+            .get_header("access-control-allow-methods")
+            .map(|value| header_list(value))
+            .unwrap_or_default();
+        let method_safelisted = SAFELISTED_METHODS.iter().any(|m| m.eq_ignore_ascii_case(&self.method));
+        if !method_safelisted && !wildcard(&methods) && !methods.iter().any(|m| m.eq_ignore_ascii_case(&self.method)) {
+            problems.push(format!("Method {} is not in Access-Control-Allow-Methods", self.method));
+        }
+
+        let allowed = response
+            .get_header("access-control-allow-headers")
+            .map(|value| header_list(value))
+            .unwrap_or_default();
+        for header in &self.headers {
+            // A wildcard never covers Authorization
+            let covered = allowed.iter().any(|a| a.eq_ignore_ascii_case(header))
+                || (wildcard(&allowed) && !header.eq_ignore_ascii_case("authorization"));
+            if !covered {
+                problems.push(format!("Header {} is not in Access-Control-Allow-Headers", header));
+            }
+        }
+        problems
+    }
+
+    /// Problems with the request that followed this preflight
+    fn request_problems(&self, flow: &HTTPFlow) -> Vec<String> {
+        let credentials = sends_credentials(flow);
+        let mut problems = self.problems(credentials);
+        match &flow.response {
+            Some(response) => problems.extend(origin_problems("Response", response, &self.origin, credentials)),
+            None => problems.push("Request received no response".to_string()),
+        }
+        problems
+    }
+
+    fn exchange(&self, request: Option<&HTTPFlow>) -> CorsExchange {
+        let (request_id, problems, status) = match request {
+            Some(flow) => {
+                let problems = self.request_problems(flow);
+                let status = if problems.is_empty() { CorsStatus::Allowed } else { CorsStatus::Mismatch };
+                (Some(flow.flow.id.clone()), problems, status)
+            }
+            None => (None, self.problems(false), CorsStatus::Unanswered),
+        };
+        CorsExchange {
+            preflight_id: self.flow_id.clone(),
+            request_id,
+            origin: self.origin.clone(),
+            url: self.url.clone(),
+            method: self.method.clone(),
+            status,
+            problems,
+        }
+    }
+}
+
+/// Key of the preflight a cross-origin request would have been sent after
+fn request_key(flow: &HTTPFlow) -> Option<(String, String, String)> {
+    if flow.request.method.eq_ignore_ascii_case("OPTIONS") {
+        return None;
+    }
+    let origin = flow.request.get_header("origin")?;
+    Some((origin.trim().to_string(), flow.request.url(), flow.request.method.to_ascii_uppercase()))
+}
+
+/// Pair preflights with the requests that follow them and validate each pair.
+/// Preflights followed by several requests are reported once per request.
+pub fn analyze(flows: &[HTTPFlow]) -> Vec<CorsExchange> {
+    let mut ordered: Vec<&HTTPFlow> = flows.iter().collect();
+    ordered.sort_by(|a, b| flow_timestamp(a).total_cmp(&flow_timestamp(b)));
+
+    let mut preflights: HashMap<(String, String, String), (Preflight, bool)> = HashMap::new();
+    let mut order = Vec::new();
+    let mut exchanges = Vec::new();
+    for flow in ordered {
+        if let Some(preflight) = Preflight::parse(flow) {
+            let key = preflight.key();
+            if let Some((previous, false)) = preflights.remove(&key) {
+                exchanges.push(previous.exchange(None));
+            }
+            order.push(key.clone());
+            preflights.insert(key, (preflight, false));
+        } else if let Some((preflight, answered)) = request_key(flow).and_then(|key| preflights.get_mut(&key)) {
+            *answered = true;
+            exchanges.push(preflight.exchange(Some(flow)));
+        }
+    }
+    for key in order {
+        if let Some((preflight, false)) = preflights.remove(&key) {
+            exchanges.push(preflight.exchange(None));
+        }
+    }
+    exchanges
+}
+
+/// Pairs preflights with requests as flows complete and flags mismatches
+#[derive(Debug, Default)]
+pub struct CorsTracker {
+    preflights: Mutex<IndexMap<(String, String, String), Preflight>>,
+}
+
+impl CorsTracker {
+    /// Remember a preflight, or validate a request against the preflight sent
+    /// for it. Returns the problems flagged on the flow.
+    pub fn check(&self, flow: &mut HTTPFlow) -> Vec<String> {
+        let mut preflights = self.preflights.lock().unwrap();
+        if let Some(preflight) = Preflight::parse(flow) {
+            let key = preflight.key();
+            preflights.shift_remove(&key);
+            preflights.insert(key, preflight);
+            let excess = preflights.len().saturating_sub(MAX_PREFLIGHTS);
+            preflights.drain(..excess);
+            return Vec::new();
+        }
+        let Some(preflight) = request_key(flow).and_then(|key| preflights.get(&key).cloned()) else {
+            return Vec::new();
+        };
+        drop(preflights);
+        let problems = preflight.request_problems(flow);
+        if !problems.is_empty() {
+            flow.flow.metadata.insert(
+                METADATA_KEY.to_string(),
+                json!({ "preflight": preflight.flow_id, "problems": problems }),
+            );
+        }
+        problems
+    }
+}
+
+fn origin_problems(what: &str, response: &HTTPResponse, origin: &str, credentials: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+    match response.get_header("access-control-allow-origin").map(|v| v.trim()) {
+        None => problems.push(format!("{} has no Access-Control-Allow-Origin", what)),
+        Some("*") if credentials => {
+            problems.push(format!("{} allows origin * for a request with credentials", what))
+        }
+        Some("*") => {}
+        Some(allowed) if allowed == origin => {}
+        Some(allowed) => problems.push(format!("{} allows origin {}, not {}", what, allowed, origin)),
+    }
+    if credentials
+        && response
+            .get_header("access-control-allow-credentials")
+            .is_none_or(|value| value.trim() != "true")
+    {
+        problems.push(format!("{} does not set Access-Control-Allow-Credentials: true", what));
+    }
+    problems
+}
+
+/// Whether the browser sent the request with credentials. Only cookies are
+/// visible on the wire.
+fn sends_credentials(flow: &HTTPFlow) -> bool {
+    flow.request.get_header("cookie").is_some()
+}
+
+fn header_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn flow_timestamp(flow: &HTTPFlow) -> f64 {
+    flow.request.timestamp_start.unwrap_or(flow.flow.timestamp_created)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    const ORIGIN: &str = "https://app.example.com";
+
+    fn make_flow(method: &str, headers: &[(&str, &str)], response: &[(&str, &str)], ts: f64) -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            method.to_string(),
+            "https".to_string(),
+            "api.example.com".to_string(),
+            443,
+            "/items".to_string(),
+        );
+        request.timestamp_start = Some(ts);
+        request.headers = headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let mut flow = HTTPFlow::new(request);
+        let mut answer = HTTPResponse::new(200, "OK".to_string());
+        answer.headers = response.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        flow.response = Some(answer);
+        flow
+    }
+
+    fn preflight(allow: &[(&str, &str)], ts: f64) -> HTTPFlow {
+        make_flow(
+            "OPTIONS",
+            &[
+                ("Origin", ORIGIN),
+                ("Access-Control-Request-Method", "PUT"),
+                ("Access-Control-Request-Headers", "content-type, x-token"),
+            ],
+            allow,
+            ts,
+        )
+    }
+
+    #[test]
+    fn test_allowed_and_mismatched_preflights() {
+        let good = preflight(
+            &[
+                ("Access-Control-Allow-Origin", ORIGIN),
+                ("Access-Control-Allow-Methods", "GET, PUT"),
+                ("Access-Control-Allow-Headers", "Content-Type, X-Token"),
+            ],
+            1.0,
+        );
+        let request = make_flow("PUT", &[("Origin", ORIGIN)], &[("Access-Control-Allow-Origin", "*")], 2.0);
+        let exchanges = analyze(&[request.clone(), good.clone()]);
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].status, CorsStatus::Allowed);
+        assert_eq!(exchanges[0].preflight_id, good.flow.id);
+        assert_eq!(exchanges[0].request_id.as_deref(), Some(request.flow.id.as_str()));
+
+        let bad = preflight(
+            &[
+                ("Access-Control-Allow-Origin", "*"),
+                ("Access-Control-Allow-Methods", "GET, POST"),
+                ("Access-Control-Allow-Headers", "content-type"),
+            ],
+            3.0,
+        );
+        let credentialed =
+            make_flow("PUT", &[("Origin", ORIGIN), ("Cookie", "session=1")], &[("Access-Control-Allow-Origin", ORIGIN)], 4.0);
+        let exchanges = analyze(&[bad, credentialed]);
+        assert_eq!(exchanges[0].status, CorsStatus::Mismatch);
+        let problems = &exchanges[0].problems;
+        assert!(problems.iter().any(|p| p.contains("origin * for a request with credentials")));
+        assert!(problems.iter().any(|p| p.contains("Method PUT")));
+        assert!(problems.iter().any(|p| p.contains("Header x-token")));
+        assert!(problems.iter().any(|p| p.starts_with("Response does not set Access-Control-Allow-Credentials")));
+    }
+
+    #[test]
+    fn test_unanswered_preflight() {
+        let blocked = preflight(&[("Access-Control-Allow-Origin", "https://other.example.com")], 1.0);
+        let exchanges = analyze(&[blocked]);
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].status, CorsStatus::Unanswered);
+        assert!(exchanges[0].request_id.is_none());
+        assert!(exchanges[0].problems.iter().any(|p| p.contains("not https://app.example.com")));
+    }
+
+    #[test]
+    fn test_tracker_flags_request() {
+        let tracker = CorsTracker::default();
+        let mut pre = preflight(
+            &[
+                ("Access-Control-Allow-Origin", ORIGIN),
+                ("Access-Control-Allow-Methods", "PUT"),
+                ("Access-Control-Allow-Headers", "*"),
+            ],
+            1.0,
+        );
+        assert!(tracker.check(&mut pre).is_empty());
+
+        let mut allowed = make_flow("PUT", &[("Origin", ORIGIN)], &[("Access-Control-Allow-Origin", ORIGIN)], 2.0);
+        assert!(tracker.check(&mut allowed).is_empty());
+        assert!(!allowed.flow.metadata.contains_key(METADATA_KEY));
+
+        let mut hidden = make_flow("PUT", &[("Origin", ORIGIN)], &[], 3.0);
+        assert_eq!(tracker.check(&mut hidden).len(), 1);
+        assert_eq!(hidden.flow.metadata[METADATA_KEY]["preflight"], json!(pre.flow.id));
+
+        // Requests without a preflight are left alone
+        let mut simple = make_flow("GET", &[("Origin", ORIGIN)], &[], 4.0);
+        assert!(tracker.check(&mut simple).is_empty());
+    }
+}
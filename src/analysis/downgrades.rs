@@ -0,0 +1,174 @@
+//! Reporting of HTTP version downgrades between client and server.
+//!
+//! A request the client sent over HTTP/2 or HTTP/3 may reach the server
+//! over an older version, because the proxy converts it or because the
+//! server does not speak the newer one. The conversion is invisible to
+//! both ends and skews latency comparisons, so completed flows are noted
+//! under [`METADATA_KEY`] in their metadata and the notes are counted by
+//! versions and reason.
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::flow::HTTPFlow;
+
+/// Flow metadata key of the downgrade a flow went through
+pub const METADATA_KEY: &str = "downgrade";
+
+/// The server connection negotiated HTTP/1 through ALPN
+pub const UPSTREAM_HTTP1: &str = "upstream_http1";
+/// The server connection is cleartext, where HTTP/2 is not attempted
+pub const CLEARTEXT_UPSTREAM: &str = "cleartext_upstream";
+/// The proxy forwarded the request over an older version by itself
+pub const PROXY_CONVERSION: &str = "proxy_conversion";
+
+/// An HTTP version conversion between the client and server side of a flow
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Downgrade {
+    pub from_version: String,
+    pub to_version: String,
+    pub reason: String,
+}
+
+/// Downgrades with the same versions and reason
+#[derive(Debug, Clone, Serialize)]
+pub struct DowngradeGroup {
+    pub from_version: String,
+    pub to_version: String,
+    pub reason: String,
+    pub count: usize,
+    pub hosts: Vec<String>,
+}
+
+/// The downgrade between the request version of `flow` and the version
+/// its response came in, if the response came in an older one
+pub fn detect(flow: &HTTPFlow) -> Option<Downgrade> {
+    let response = flow.response.as_ref()?;
+    let (from, to) = (rank(&flow.request.http_version)?, rank(&response.http_version)?);
+    if to >= from {
+        return None;
+    }
+    let server_alpn = flow.flow.server_conn.as_ref().and_then(|conn| conn.alpn.as_deref());
+    let reason = if server_alpn.is_some_and(|alpn| alpn.starts_with("http/1")) {
+        UPSTREAM_HTTP1
+    } else if to == 1 && flow.request.scheme == "http" {
+        CLEARTEXT_UPSTREAM
+    } else {
+        PROXY_CONVERSION
+    };
+    Some(Downgrade {
+        from_version: flow.request.http_version.clone(),
+        to_version: response.http_version.clone(),
+        reason: reason.to_string(),
+    })
+}
+
+/// Note the downgrade `flow` went through in its metadata
+pub fn record(flow: &mut HTTPFlow) -> Option<Downgrade> {
+    let downgrade = detect(flow)?;
+    if let Ok(note) = serde_json::to_value(&downgrade) {
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), note);
+    }
+    Some(downgrade)
+}
+
+/// Count the downgrades noted on `flows`, most frequent first
+pub fn summarize(flows: &[HTTPFlow]) -> Vec<DowngradeGroup> {
+    let mut groups: IndexMap<(String, String, String), DowngradeGroup> = IndexMap::new();
+    for flow in flows {
+        let noted = flow.flow.metadata.get(METADATA_KEY).and_then(|note| serde_json::from_value(note.clone()).ok());
+        let Some(downgrade) = noted.or_else(|| detect(flow)) else {
+            continue;
+        };
+        let Downgrade { from_version, to_version, reason } = downgrade;
+        let group = groups
+            .entry((from_version.clone(), to_version.clone(), reason.clone()))
+            .or_insert_with(|| DowngradeGroup { from_version, to_version, reason, count: 0, hosts: Vec::new() });
+        group.count += 1;
+        if !group.hosts.contains(&flow.request.host) {
+            group.hosts.push(flow.request.host.clone());
+        }
+    }
+    let mut groups: Vec<DowngradeGroup> = groups.into_values().collect();
+    groups.sort_by_key(|group| std::cmp::Reverse(group.count));
+    groups
+}
+
+/// Major version of an `HTTP/x` version string
+fn rank(version: &str) -> Option<u8> {
+    match version.strip_prefix("HTTP/")? {
+        "3" | "3.0" => Some(3),
+        "2" | "2.0" => Some(2),
+        "1.1" | "1.0" | "0.9" => Some(1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{Connection, HTTPRequest, HTTPResponse};
+
+    fn flow(scheme: &str, host: &str, request_version: &str, response_version: &str) -> HTTPFlow {
+        let mut request = HTTPRequest::new("GET".to_string(), scheme.to_string(), host.to_string(), 443, "/".to_string());
+        request.http_version = request_version.to_string();
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.http_version = response_version.to_string();
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(&flow("https", "a.com", "HTTP/1.1", "HTTP/1.1")), None);
+        assert_eq!(detect(&flow("https", "a.com", "HTTP/1.1", "HTTP/2")), None);
+        assert_eq!(detect(&HTTPFlow::new(flow("https", "a.com", "HTTP/2", "HTTP/1.1").request)), None);
+
+        let converted = detect(&flow("https", "a.com", "HTTP/2", "HTTP/1.1")).unwrap();
+        assert_eq!(
+            converted,
+            Downgrade { from_version: "HTTP/2".to_string(), to_version: "HTTP/1.1".to_string(), reason: PROXY_CONVERSION.to_string() }
+        );
+        assert_eq!(detect(&flow("http", "a.com", "HTTP/2", "HTTP/1.1")).unwrap().reason, CLEARTEXT_UPSTREAM);
+        assert_eq!(detect(&flow("https", "a.com", "HTTP/3", "HTTP/2")).unwrap().reason, PROXY_CONVERSION);
+
+        let mut negotiated = flow("https", "a.com", "HTTP/2", "HTTP/1.1");
+        negotiated.flow.server_conn = Some(Connection {
+            id: "server".to_string(),
+            peername: None,
+            sockname: None,
+            address: None,
+            tls_established: true,
+            cert: None,
+            sni: None,
+            cipher: None,
+            alpn: Some("http/1.1".to_string()),
+            tls_version: None,
+            timestamp_start: None,
+            timestamp_tcp_setup: None,
+            timestamp_tls_setup: None,
+            timestamp_end: None,
+        });
+        assert_eq!(detect(&negotiated).unwrap().reason, UPSTREAM_HTTP1);
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut flows = vec![
+            flow("https", "a.com", "HTTP/2", "HTTP/1.1"),
+            flow("https", "b.com", "HTTP/2", "HTTP/1.1"),
+            flow("http", "a.com", "HTTP/2", "HTTP/1.1"),
+            flow("https", "a.com", "HTTP/2", "HTTP/1.1"),
+            flow("https", "a.com", "HTTP/2", "HTTP/2"),
+        ];
+        assert!(record(&mut flows[0]).is_some());
+        assert_eq!(flows[0].flow.metadata[METADATA_KEY]["reason"], PROXY_CONVERSION);
+        assert!(record(&mut flows[4]).is_none());
+        assert!(!flows[4].flow.metadata.contains_key(METADATA_KEY));
+
+        let groups = summarize(&flows);
+        assert_eq!(groups.len(), 2);
+        assert_eq!((groups[0].reason.as_str(), groups[0].count), (PROXY_CONVERSION, 3));
+        assert_eq!(groups[0].hosts, ["a.com", "b.com"]);
+        assert_eq!((groups[1].reason.as_str(), groups[1].count), (CLEARTEXT_UPSTREAM, 1));
+    }
+}
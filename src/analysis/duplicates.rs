@@ -0,0 +1,143 @@
+//! Detection of identical bodies served or sent under different URLs.
+//!
+//! Bodies are grouped by their SHA-256 hash. A group is reported when the
+//! same body appears at more than one URL, which points at assets duplicated
+//! across CDN hosts, cache keys that differ only in query parameters, or a
+//! secret pasted into the requests of several services.
+
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::flow::HTTPFlow;
+
+/// A single flow message carrying a duplicated body
+#[derive(Debug, Clone, Serialize)]
+pub struct BodyOccurrence {
+    pub flow_id: String,
+    /// `request` or `response`
+    pub message: &'static str,
+    pub url: String,
+    pub host: String,
+}
+
+/// A body seen at more than one URL
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateBody {
+    pub hash: String,
+    pub size: usize,
+    pub content_type: Option<String>,
+    pub urls: Vec<String>,
+    pub hosts: Vec<String>,
+    pub occurrences: Vec<BodyOccurrence>,
+}
+
+/// Find bodies of at least `min_size` bytes appearing at several URLs.
+/// Request bodies are only considered if `include_requests` is set. Groups
+/// spanning the most hosts come first.
+pub fn find_duplicates(flows: &[HTTPFlow], include_requests: bool, min_size: usize) -> Vec<DuplicateBody> {
+    let mut groups: IndexMap<String, DuplicateBody> = IndexMap::new();
+    let mut ordered: Vec<&HTTPFlow> = flows.iter().collect();
+    ordered.sort_by(|a, b| {
+        let a = a.request.timestamp_start.unwrap_or(a.flow.timestamp_created);
+        let b = b.request.timestamp_start.unwrap_or(b.flow.timestamp_created);
+        a.total_cmp(&b)
+    });
+
+    for flow in ordered {
+        let url = flow.request.url();
+        let request = include_requests.then(|| {
+            let body = &flow.request;
+            ("request", body.body_hash(), body.content.as_ref().map_or(0, Vec::len), body.get_header("content-type"))
+        });
+        let response = flow.response.as_ref().map(|body| {
+            ("response", body.body_hash(), body.content.as_ref().map_or(0, Vec::len), body.get_header("content-type"))
+        });
+
+        for (message, hash, size, content_type) in request.into_iter().chain(response) {
+            let Some(hash) = hash.filter(|_| size >= min_size.max(1)) else {
+                continue;
+            };
+            let group = groups.entry(hash.clone()).or_insert_with(|| DuplicateBody {
+                hash,
+                size,
+                content_type: content_type.cloned(),
+                urls: Vec::new(),
+                hosts: Vec::new(),
+                occurrences: Vec::new(),
+            });
+            if !group.urls.contains(&url) {
+                group.urls.push(url.clone());
+            }
+            if !group.hosts.contains(&flow.request.host) {
+                group.hosts.push(flow.request.host.clone());
+            }
+            group.occurrences.push(BodyOccurrence {
+                flow_id: flow.flow.id.clone(),
+                message,
+                url: url.clone(),
+                host: flow.request.host.clone(),
+            });
+        }
+    }
+
+    let mut duplicates: Vec<DuplicateBody> = groups.into_values().filter(|g| g.urls.len() > 1).collect();
+    duplicates.sort_by(|a, b| {
+        b.hosts
+            .len()
+            .cmp(&a.hosts.len())
+            .then(b.occurrences.len().cmp(&a.occurrences.len()))
+    });
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow(host: &str, path: &str, request_body: &[u8], response_body: &[u8]) -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            "POST".to_string(),
+            "https".to_string(),
+            host.to_string(),
+            443,
+            path.to_string(),
+        );
+        request.set_content(request_body.to_vec());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_header("Content-Type".to_string(), "image/png".to_string());
+        response.set_content(response_body.to_vec());
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_identical_responses_across_hosts() {
+        let flows = vec![
+            flow("cdn1.example.com", "/logo.png", b"", b"PNGDATA"),
+            flow("cdn2.example.com", "/logo.png", b"", b"PNGDATA"),
+            flow("cdn1.example.com", "/logo.png", b"", b"PNGDATA"),
+            flow("cdn1.example.com", "/other.png", b"", b"OTHER"),
+        ];
+        let duplicates = find_duplicates(&flows, false, 1);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].hosts, ["cdn1.example.com", "cdn2.example.com"]);
+        assert_eq!(duplicates[0].urls.len(), 2);
+        assert_eq!(duplicates[0].occurrences.len(), 3);
+        assert_eq!(duplicates[0].content_type.as_deref(), Some("image/png"));
+
+        assert!(find_duplicates(&flows, false, 100).is_empty());
+    }
+
+    #[test]
+    fn test_request_bodies_are_opt_in() {
+        let flows = vec![
+            flow("billing.internal", "/charge", b"api_key=s3cret", b"a"),
+            flow("search.internal", "/query", b"api_key=s3cret", b"b"),
+        ];
+        assert!(find_duplicates(&flows, false, 1).is_empty());
+
+        let duplicates = find_duplicates(&flows, true, 1);
+        assert_eq!(duplicates.len(), 1);
+        assert!(duplicates[0].occurrences.iter().all(|o| o.message == "request"));
+    }
+}
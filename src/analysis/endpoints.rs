@@ -0,0 +1,295 @@
+//! Grouping of flows by endpoint template.
+//!
+//! Paths are normalized by replacing segments that look like parameters
+//! with placeholders, so `/users/123/posts/7` and `/users/456/posts/9` are
+//! both counted under `/users/{id}/posts/{id}`. Numbers, UUIDs, hashes,
+//! dates, e-mail addresses and opaque tokens are recognized by default.
+//! When the heuristics get an API wrong, configured templates take
+//! precedence, segments can be marked as never being parameters, and more
+//! parameter patterns can be added.
+
+use indexmap::IndexMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::OnceLock;
+
+use crate::flow::HTTPFlow;
+use crate::Result;
+
+/// Number of distinct example URLs kept per endpoint
+const MAX_EXAMPLES: usize = 3;
+
+/// Endpoint grouping options as configured in the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EndpointOptions {
+    /// Path templates tried before the heuristics, e.g.
+    /// `/repos/{owner}/{repo}`; a `{name}` segment matches any one segment
+    pub templates: Vec<String>,
+    /// Path segments that are never replaced, e.g. `v2` or `me`
+    pub literal_segments: Vec<String>,
+    /// Additional segment patterns treated as parameters
+    pub parameters: Vec<ParameterPattern>,
+}
+
+/// A segment pattern and the placeholder it is replaced with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParameterPattern {
+    pub name: String,
+    /// Regex the whole segment must match
+    pub pattern: String,
+}
+
+/// Turns request paths into endpoint templates
+#[derive(Debug, Default)]
+pub struct EndpointTemplater {
+    templates: Vec<Vec<String>>,
+    literal_segments: HashSet<String>,
+    parameters: Vec<(String, Regex)>,
+}
+
+impl EndpointTemplater {
+    pub fn new(options: &EndpointOptions) -> Result<Self> {
+        let parameters = options
+            .parameters
+            .iter()
+            .map(|p| Ok((p.name.clone(), Regex::new(&format!("^(?:{})$", p.pattern))?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            templates: options.templates.iter().map(|t| segments(t).map(str::to_string).collect()).collect(),
+            literal_segments: options.literal_segments.iter().cloned().collect(),
+            parameters,
+        })
+    }
+
+    /// The endpoint template of a request path. The query string is ignored.
+    pub fn template(&self, path: &str) -> String {
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let parts: Vec<&str> = segments(path).collect();
+
+        let configured = self.templates.iter().find(|template| {
+            template.len() == parts.len()
+                && template.iter().zip(&parts).all(|(t, p)| is_placeholder(t) || t == p)
+        });
+        let templated: Vec<String> = match configured {
+            Some(template) => template.clone(),
+            None => parts.iter().map(|segment| self.segment(segment)).collect(),
+        };
+
+        let mut template = format!("/{}", templated.join("/"));
+        if path.len() > 1 && path.ends_with('/') {
+            template.push('/');
+        }
+        template
+    }
+
+    fn segment(&self, segment: &str) -> String {
+        if self.literal_segments.contains(segment) {
+            return segment.to_string();
+        }
+        if let Some((name, _)) = self.parameters.iter().find(|(_, regex)| regex.is_match(segment)) {
+            return format!("{{{}}}", name);
+        }
+        match builtin_parameter(segment) {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        }
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+fn is_placeholder(segment: &str) -> bool {
+    segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}')
+}
+
+/// Placeholder name of a segment the built-in heuristics consider a parameter
+fn builtin_parameter(segment: &str) -> Option<&'static str> {
+    static PATTERNS: OnceLock<Vec<(&str, Regex)>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        [
+            ("id", r"^\d+$"),
+            ("uuid", r"^(?i)[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}$"),
+            ("hash", r"^(?i)[0-9a-f]{16,}$"),
+            ("date", r"^\d{4}-\d{2}-\d{2}$"),
+            ("email", r"^[^@\s]+@[^@\s]+\.[^@\s]+$"),
+        ]
+        .into_iter()
+        .map(|(name, pattern)| (name, Regex::new(pattern).expect("valid built-in pattern")))
+        .collect()
+    });
+    if let Some((name, _)) = patterns.iter().find(|(_, regex)| regex.is_match(segment)) {
+        return Some(name);
+    }
+    // Long segments mixing letters and digits, like base64 ids or slugs
+    // with an id suffix, are opaque tokens
+    let is_token = segment.len() >= 20
+        && segment.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.~=".contains(&b))
+        && segment.bytes().any(|b| b.is_ascii_digit())
+        && segment.bytes().any(|b| b.is_ascii_alphabetic());
+    is_token.then_some("token")
+}
+
+/// Latency distribution of an endpoint, in milliseconds
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyStats {
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+}
+
+impl LatencyStats {
+    fn from_samples(mut samples: Vec<f64>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_by(f64::total_cmp);
+        let quantile = |q: f64| samples[((q * samples.len() as f64).ceil() as usize).clamp(1, samples.len()) - 1];
+        Some(Self {
+            min_ms: samples[0],
+            mean_ms: samples.iter().sum::<f64>() / samples.len() as f64,
+            p50_ms: quantile(0.5),
+            p95_ms: quantile(0.95),
+            max_ms: samples[samples.len() - 1],
+        })
+    }
+}
+
+/// Flows sharing method, host and endpoint template
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointGroup {
+    pub method: String,
+    pub host: String,
+    pub template: String,
+    pub count: usize,
+    /// Responses by status code
+    pub status_codes: BTreeMap<u16, usize>,
+    /// Flows that failed without a response
+    pub errors: usize,
+    pub latency: Option<LatencyStats>,
+    pub examples: Vec<String>,
+}
+
+/// Group flows by endpoint. The most requested endpoints come first.
+pub fn group_endpoints(flows: &[HTTPFlow], templater: &EndpointTemplater) -> Vec<EndpointGroup> {
+    let mut groups: IndexMap<(String, String, String), (EndpointGroup, Vec<f64>)> = IndexMap::new();
+    for flow in flows {
+        let request = &flow.request;
+        let template = templater.template(&request.path);
+        let method = request.method.to_ascii_uppercase();
+        let (group, latencies) = groups
+            .entry((method.clone(), request.host.clone(), template.clone()))
+            .or_insert_with(|| {
+                let group = EndpointGroup {
+                    method,
+                    host: request.host.clone(),
+                    template,
+                    count: 0,
+                    status_codes: BTreeMap::new(),
+                    errors: 0,
+                    latency: None,
+                    examples: Vec::new(),
+                };
+                (group, Vec::new())
+            });
+
+        group.count += 1;
+        match &flow.response {
+            Some(response) => {
+                *group.status_codes.entry(response.status_code).or_default() += 1;
+                if let (Some(start), Some(end)) = (request.timestamp_start, response.timestamp_end) {
+                    latencies.push(((end - start) * 1000.0).max(0.0));
+                }
+            }
+            None if flow.flow.error.is_some() => group.errors += 1,
+            None => {}
+        }
+        let url = request.url();
+        if group.examples.len() < MAX_EXAMPLES && !group.examples.contains(&url) {
+            group.examples.push(url);
+        }
+    }
+
+    let mut endpoints: Vec<EndpointGroup> = groups
+        .into_values()
+        .map(|(mut group, latencies)| {
+            group.latency = LatencyStats::from_samples(latencies);
+            group
+        })
+        .collect();
+    endpoints.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.template.cmp(&b.template)));
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow(method: &str, path: &str, latency: f64) -> HTTPFlow {
+        let mut request = HTTPRequest::new(method.to_string(), "https".to_string(), "api.example.com".to_string(), 443, path.to_string());
+        request.timestamp_start = Some(1000.0);
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.timestamp_end = Some(1000.0 + latency);
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_heuristics() {
+        let templater = EndpointTemplater::default();
+        assert_eq!(templater.template("/users/123/posts/7?page=2"), "/users/{id}/posts/{id}");
+        assert_eq!(templater.template("/orders/3f2b8c1e-9d4a-4b7e-8f6a-1c2d3e4f5a6b"), "/orders/{uuid}");
+        assert_eq!(templater.template("/blobs/9f86d081884c7d659a2feaa0c55ad015"), "/blobs/{hash}");
+        assert_eq!(templater.template("/reports/2024-05-01/"), "/reports/{date}/");
+        assert_eq!(templater.template("/share/aGVsbG8gd29ybGQgMTIzNDU2"), "/share/{token}");
+        assert_eq!(templater.template("/api/v2/search"), "/api/v2/search");
+        assert_eq!(templater.template(""), "/");
+    }
+
+    #[test]
+    fn test_configured_rules() {
+        let templater = EndpointTemplater::new(&EndpointOptions {
+            templates: vec!["/repos/{owner}/{repo}".to_string()],
+            literal_segments: vec!["2024".to_string()],
+            parameters: vec![ParameterPattern { name: "sku".to_string(), pattern: "[A-Z]{3}-\\d+".to_string() }],
+        })
+        .unwrap();
+        assert_eq!(templater.template("/repos/rust-lang/cargo"), "/repos/{owner}/{repo}");
+        assert_eq!(templater.template("/repos/rust-lang/cargo/issues"), "/repos/rust-lang/cargo/issues");
+        assert_eq!(templater.template("/archive/2024/12"), "/archive/2024/{id}");
+        assert_eq!(templater.template("/products/ABC-42"), "/products/{sku}");
+
+        let invalid = EndpointOptions {
+            parameters: vec![ParameterPattern { name: "x".to_string(), pattern: "(".to_string() }],
+            ..Default::default()
+        };
+        assert!(EndpointTemplater::new(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_group_endpoints() {
+        let flows = vec![
+            flow("GET", "/users/1", 0.010),
+            flow("GET", "/users/2", 0.030),
+            flow("get", "/users/3", 0.020),
+            flow("DELETE", "/users/1", 0.005),
+            flow("GET", "/health", 0.001),
+        ];
+        let groups = group_endpoints(&flows, &EndpointTemplater::default());
+        assert_eq!(groups.len(), 3);
+        let users = &groups[0];
+        assert_eq!((users.method.as_str(), users.template.as_str(), users.count), ("GET", "/users/{id}", 3));
+        assert_eq!(users.status_codes[&200], 3);
+        assert_eq!(users.examples.len(), 3);
+        let latency = users.latency.as_ref().unwrap();
+        assert!((latency.min_ms - 10.0).abs() < 1e-6 && (latency.max_ms - 30.0).abs() < 1e-6);
+        assert!((latency.p50_ms - 20.0).abs() < 1e-6 && (latency.mean_ms - 20.0).abs() < 1e-6);
+        assert_eq!(groups[1].template, "/health");
+        assert_eq!(groups[2].method, "DELETE");
+    }
+}
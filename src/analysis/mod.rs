@@ -0,0 +1,12 @@
+//! Offline analyzers that correlate captured flows into higher-level views.
+//!
+//! Analyzers operate on a snapshot of the flow store and never modify flows;
+//! flags that should be visible on flows are set as flows complete.
+//! Each analyzer is exposed through an `/analysis/...` route in the web API.
+
+pub mod cors;
+pub mod downgrades;
+pub mod duplicates;
+pub mod endpoints;
+pub mod oauth;
+pub mod unparsable;
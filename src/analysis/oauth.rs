@@ -0,0 +1,595 @@
+//! OAuth2 / OpenID Connect flow correlation.
+//!
+//! Links the requests of an authorization dance (authorize → redirect →
+//! token → userinfo) across flows into logical sessions. Sessions are
+//! correlated through the `state` parameter, the authorization code, and the
+//! issued access/refresh tokens, and are annotated with the grant type, PKCE
+//! usage and token lifetimes.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::flow::HTTPFlow;
+
+/// Kind of a single step within an OAuth session
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthStepKind {
+    Authorize,
+    Redirect,
+    Callback,
+    Token,
+    UserInfo,
+}
+
+/// A flow that participates in an OAuth session
+#[derive(Debug, Clone, Serialize)]
+pub struct OAuthStep {
+    pub kind: OAuthStepKind,
+    pub flow_id: String,
+    pub method: String,
+    pub url: String,
+    pub status_code: Option<u16>,
+    pub timestamp: f64,
+}
+
+/// PKCE (RFC 7636) usage observed in a session
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PkceInfo {
+    pub used: bool,
+    pub method: Option<String>,
+    /// Whether the code_verifier sent to the token endpoint matches the
+    /// code_challenge of the authorize request. `None` if either is missing.
+    pub verified: Option<bool>,
+}
+
+/// Lifetimes of the tokens issued in a session
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TokenLifetimes {
+    pub token_type: Option<String>,
+    pub access_token_expires_in: Option<i64>,
+    pub refresh_token_issued: bool,
+    pub id_token_issued_at: Option<i64>,
+    pub id_token_expires_at: Option<i64>,
+    pub id_token_lifetime: Option<i64>,
+}
+
+/// A logical OAuth2/OIDC session spanning multiple flows
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OAuthSession {
+    pub client_id: Option<String>,
+    pub grant_type: Option<String>,
+    pub response_type: Option<String>,
+    pub scope: Option<String>,
+    pub openid: bool,
+    pub pkce: PkceInfo,
+    pub tokens: TokenLifetimes,
+    pub steps: Vec<OAuthStep>,
+    pub issues: Vec<String>,
+    #[serde(skip)]
+    state: Option<String>,
+    #[serde(skip)]
+    redirect_uri: Option<String>,
+    #[serde(skip)]
+    code: Option<String>,
+    #[serde(skip)]
+    code_challenge: Option<String>,
+    #[serde(skip)]
+    access_token: Option<String>,
+    #[serde(skip)]
+    refresh_token: Option<String>,
+}
+
+impl OAuthSession {
+    fn push_step(&mut self, kind: OAuthStepKind, flow: &HTTPFlow) {
+        self.steps.push(OAuthStep {
+            kind,
+            flow_id: flow.flow.id.clone(),
+            method: flow.request.method.clone(),
+            url: flow.request.url(),
+            status_code: flow.response.as_ref().map(|r| r.status_code),
+            timestamp: flow_timestamp(flow),
+        });
+    }
+}
+
+/// Correlate OAuth2/OIDC requests across the given flows into sessions
+pub fn correlate(flows: &[HTTPFlow]) -> Vec<OAuthSession> {
+    let mut ordered: Vec<&HTTPFlow> = flows.iter().collect();
+    ordered.sort_by(|a, b| flow_timestamp(a).total_cmp(&flow_timestamp(b)));
+
+    let mut sessions: Vec<OAuthSession> = Vec::new();
+
+    for flow in ordered {
+        let query = query_params(&flow.request.path);
+
+        if has_param(&query, "response_type") && has_param(&query, "client_id") {
+            handle_authorize(&mut sessions, flow, &query);
+            continue;
+        }
+
+        if let Some(location) = redirect_location(flow) {
+            let location_query = query_params(&location);
+            if has_param(&location_query, "code") || has_param(&location_query, "error") {
+                handle_callback(&mut sessions, flow, &location, &location_query, OAuthStepKind::Redirect);
+                continue;
+            }
+        }
+
+        if (has_param(&query, "code") || has_param(&query, "error")) && has_param(&query, "state") {
+            let target = flow.request.url();
+            handle_callback(&mut sessions, flow, &target, &query, OAuthStepKind::Callback);
+            continue;
+        }
+
+        if flow.request.method.eq_ignore_ascii_case("POST") {
+            let form = form_params(flow);
+            if has_param(&form, "grant_type") {
+                handle_token(&mut sessions, flow, &form);
+                continue;
+            }
+        }
+
+        handle_userinfo(&mut sessions, flow);
+    }
+
+    sessions
+}
+
+fn handle_authorize(sessions: &mut Vec<OAuthSession>, flow: &HTTPFlow, query: &[(String, String)]) {
+    let mut session = OAuthSession {
+        client_id: param(query, "client_id"),
+        response_type: param(query, "response_type"),
+        scope: param(query, "scope"),
+        state: param(query, "state"),
+        redirect_uri: param(query, "redirect_uri"),
+        code_challenge: param(query, "code_challenge"),
+        ..Default::default()
+    };
+
+    session.openid = session
+        .scope
+        .as_deref()
+        .map(|s| s.split_whitespace().any(|scope| scope == "openid"))
+        .unwrap_or(false);
+
+    if session.response_type.as_deref() == Some("code") {
+        session.grant_type = Some("authorization_code".to_string());
+    } else if session.response_type.as_deref().is_some_and(|rt| rt.contains("token")) {
+        session.grant_type = Some("implicit".to_string());
+        session.issues.push("Implicit grant in use; tokens are exposed in the redirect URL".to_string());
+    }
+
+    if session.code_challenge.is_some() {
+        session.pkce.used = true;
+        session.pkce.method = Some(param(query, "code_challenge_method").unwrap_or_else(|| "plain".to_string()));
+    }
+
+    if session.state.is_none() {
+        session.issues.push("Authorize request carries no state parameter".to_string());
+    }
+
+    session.push_step(OAuthStepKind::Authorize, flow);
+    sessions.push(session);
+}
+
+fn handle_callback(
+    sessions: &mut Vec<OAuthSession>,
+    flow: &HTTPFlow,
+    target: &str,
+    query: &[(String, String)],
+    kind: OAuthStepKind,
+) {
+    let state = param(query, "state");
+    let code = param(query, "code");
+
+    let index = state
+        .as_ref()
+        .and_then(|state| sessions.iter().rposition(|s| s.state.as_ref() == Some(state)))
+        .or_else(|| {
+            code.as_ref()
+                .and_then(|code| sessions.iter().rposition(|s| s.code.as_ref() == Some(code)))
+        })
+        .or_else(|| {
+            let target = target.split('?').next().unwrap_or(target);
+            sessions
+                .iter()
+                .rposition(|s| s.code.is_none() && s.redirect_uri.as_deref() == Some(target))
+        });
+
+    let session = match index {
+        Some(index) => &mut sessions[index],
+        None => {
+            sessions.push(OAuthSession {
+                grant_type: Some("authorization_code".to_string()),
+                state: state.clone(),
+                issues: vec!["Redirect without a matching authorize request".to_string()],
+                ..Default::default()
+            });
+            sessions.last_mut().unwrap()
+        }
+    };
+
+    if let Some(error) = param(query, "error") {
+        session.issues.push(format!("Authorization server returned error: {}", error));
+    }
+    if code.is_some() {
+        session.code = code;
+    }
+    session.push_step(kind, flow);
+}
+
+fn handle_token(sessions: &mut Vec<OAuthSession>, flow: &HTTPFlow, form: &[(String, String)]) {
+    let grant_type = param(form, "grant_type").unwrap_or_default();
+
+    let index = match grant_type.as_str() {
+        "authorization_code" => param(form, "code")
+            .and_then(|code| sessions.iter().rposition(|s| s.code.as_deref() == Some(code.as_str()))),
+        "refresh_token" => param(form, "refresh_token").and_then(|token| {
+            sessions
+                .iter()
+                .rposition(|s| s.refresh_token.as_deref() == Some(token.as_str()))
+        }),
+        _ => None,
+    };
+
+    let session = match index {
+        Some(index) => &mut sessions[index],
+        None => {
+            let mut session = OAuthSession {
+                grant_type: Some(grant_type.clone()),
+                scope: param(form, "scope"),
+                ..Default::default()
+            };
+            if grant_type == "authorization_code" {
+                session
+                    .issues
+                    .push("Token request for an authorization code that was not observed".to_string());
+            }
+            sessions.push(session);
+            sessions.last_mut().unwrap()
+        }
+    };
+
+    if session.client_id.is_none() {
+        session.client_id = param(form, "client_id");
+    }
+
+    if grant_type == "authorization_code" {
+        match (param(form, "code_verifier"), session.code_challenge.clone()) {
+            (Some(verifier), Some(challenge)) => {
+                let verified = pkce_matches(
+                    &verifier,
+                    &challenge,
+                    session.pkce.method.as_deref().unwrap_or("plain"),
+                );
+                session.pkce.verified = Some(verified);
+                if !verified {
+                    session.issues.push("PKCE code_verifier does not match code_challenge".to_string());
+                }
+            }
+            (Some(_), None) => {
+                session.pkce.used = true;
+            }
+            (None, Some(_)) => {
+                session.pkce.verified = Some(false);
+                session.issues.push("PKCE challenge sent but token request has no code_verifier".to_string());
+            }
+            (None, None) => {
+                session.issues.push("Authorization code grant without PKCE".to_string());
+            }
+        }
+    }
+
+    if let Some(response) = &flow.response {
+        let body: Option<serde_json::Value> = response
+            .content
+            .as_deref()
+            .and_then(|content| serde_json::from_slice(content).ok());
+
+        if let Some(body) = body {
+            if response.status_code >= 400 {
+                let error = body["error"].as_str().unwrap_or("unknown_error");
+                session.issues.push(format!("Token endpoint returned error: {}", error));
+            } else {
+                record_tokens(session, &body);
+            }
+        } else if response.status_code >= 400 {
+            session.issues.push(format!("Token endpoint returned status {}", response.status_code));
+        }
+    }
+
+    session.push_step(OAuthStepKind::Token, flow);
+}
+
+fn handle_userinfo(sessions: &mut [OAuthSession], flow: &HTTPFlow) {
+    let token = match flow
+        .request
+        .get_header("authorization")
+        .and_then(|value| bearer_token(value))
+    {
+        Some(token) => token,
+        None => return,
+    };
+
+    if let Some(session) = sessions
+        .iter_mut()
+        .rev()
+        .find(|s| s.access_token.as_deref() == Some(token))
+    {
+        session.push_step(OAuthStepKind::UserInfo, flow);
+    }
+}
+
+fn record_tokens(session: &mut OAuthSession, body: &serde_json::Value) {
+    if let Some(access_token) = body["access_token"].as_str() {
+        session.access_token = Some(access_token.to_string());
+    }
+    if let Some(refresh_token) = body["refresh_token"].as_str() {
+        session.refresh_token = Some(refresh_token.to_string());
+        session.tokens.refresh_token_issued = true;
+    }
+    if let Some(token_type) = body["token_type"].as_str() {
+        session.tokens.token_type = Some(token_type.to_string());
+    }
+    if let Some(scope) = body["scope"].as_str() {
+        session.scope = Some(scope.to_string());
+    }
+    session.tokens.access_token_expires_in = body["expires_in"]
+        .as_i64()
+        .or_else(|| body["expires_in"].as_str().and_then(|s| s.parse().ok()));
+
+    if let Some(claims) = body["id_token"].as_str().and_then(jwt_claims) {
+        session.openid = true;
+        session.tokens.id_token_issued_at = claims["iat"].as_i64();
+        session.tokens.id_token_expires_at = claims["exp"].as_i64();
+        if let (Some(iat), Some(exp)) = (claims["iat"].as_i64(), claims["exp"].as_i64()) {
+            session.tokens.id_token_lifetime = Some(exp - iat);
+        }
+    }
+}
+
+/// Check a PKCE code_verifier against its code_challenge
+fn pkce_matches(verifier: &str, challenge: &str, method: &str) -> bool {
+    if method.eq_ignore_ascii_case("S256") {
+        let digest = Sha256::digest(verifier.as_bytes());
+        URL_SAFE_NO_PAD.encode(digest) == challenge
+    } else {
+        verifier == challenge
+    }
+}
+
+/// Decode the (unverified) claims section of a JWT
+fn jwt_claims(token: &str) -> Option<serde_json::Value> {
+    let payload = token.split('.').nth(1)?;
+    let decoded = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    serde_json::from_slice(&decoded).ok()
+}
+
+fn bearer_token(value: &str) -> Option<&str> {
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+fn redirect_location(flow: &HTTPFlow) -> Option<String> {
+    let response = flow.response.as_ref()?;
+    if !(300..400).contains(&response.status_code) {
+        return None;
+    }
+    response.get_header("location").cloned()
+}
+
+fn flow_timestamp(flow: &HTTPFlow) -> f64 {
+    flow.request.timestamp_start.unwrap_or(flow.flow.timestamp_created)
+}
+
+fn query_params(path_or_url: &str) -> Vec<(String, String)> {
+    match path_or_url.split_once('?') {
+        Some((_, query)) => {
+            let query = query.split('#').next().unwrap_or("");
+            url::form_urlencoded::parse(query.as_bytes()).into_owned().collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+fn form_params(flow: &HTTPFlow) -> Vec<(String, String)> {
+    let is_form = flow
+        .request
+        .get_header("content-type")
+        .map(|ct| ct.to_ascii_lowercase().contains("application/x-www-form-urlencoded"))
+        .unwrap_or(true);
+
+    match (&flow.request.content, is_form) {
+        (Some(content), true) => url::form_urlencoded::parse(content).into_owned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn param(params: &[(String, String)], name: &str) -> Option<String> {
+    params.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+}
+
+fn has_param(params: &[(String, String)], name: &str) -> bool {
+    params.iter().any(|(k, _)| k == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn make_flow(method: &str, host: &str, path: &str, ts: f64) -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            method.to_string(),
+            "https".to_string(),
+            host.to_string(),
+            443,
+            path.to_string(),
+        );
+        request.timestamp_start = Some(ts);
+        HTTPFlow::new(request)
+    }
+
+    fn with_response(mut flow: HTTPFlow, status: u16, headers: Vec<(&str, &str)>, body: &str) -> HTTPFlow {
+        let mut response = HTTPResponse::new(status, String::new());
+        response.headers = headers
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        response.set_content(body.as_bytes().to_vec());
+        flow.response = Some(response);
+        flow
+    }
+
+    fn jwt(claims: &str) -> String {
+        format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims))
+    }
+
+    #[test]
+    fn test_authorization_code_with_pkce() {
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        let authorize = with_response(
+            make_flow(
+                "GET",
+                "idp.example.com",
+                &format!(
+                    "/authorize?response_type=code&client_id=app&scope=openid%20profile&state=xyz\
+                     &redirect_uri=https%3A%2F%2Fapp.example.com%2Fcb&code_challenge={}&code_challenge_method=S256",
+                    challenge
+                ),
+                1.0,
+            ),
+            302,
+            vec![("Location", "https://app.example.com/cb?code=abc&state=xyz")],
+            "",
+        );
+
+        let callback = make_flow("GET", "app.example.com", "/cb?code=abc&state=xyz", 2.0);
+
+        let mut token = make_flow("POST", "idp.example.com", "/token", 3.0);
+        token.request.set_header(
+            "Content-Type".to_string(),
+            "application/x-www-form-urlencoded".to_string(),
+        );
+        token.request.set_content(
+            format!("grant_type=authorization_code&code=abc&code_verifier={}", verifier).into_bytes(),
+        );
+        let token = with_response(
+            token,
+            200,
+            vec![("Content-Type", "application/json")],
+            &format!(
+                r#"{{"access_token":"at-1","token_type":"Bearer","expires_in":3600,"refresh_token":"rt-1","id_token":"{}"}}"#,
+                jwt(r#"{"iat":1000,"exp":4600}"#)
+            ),
+        );
+
+        let mut userinfo = make_flow("GET", "idp.example.com", "/userinfo", 4.0);
+        userinfo
+            .request
+            .set_header("Authorization".to_string(), "Bearer at-1".to_string());
+
+        let sessions = correlate(&[userinfo, token, callback, authorize]);
+        assert_eq!(sessions.len(), 1);
+
+        let session = &sessions[0];
+        assert_eq!(session.client_id.as_deref(), Some("app"));
+        assert_eq!(session.grant_type.as_deref(), Some("authorization_code"));
+        assert!(session.openid);
+        assert!(session.pkce.used);
+        assert_eq!(session.pkce.method.as_deref(), Some("S256"));
+        assert_eq!(session.pkce.verified, Some(true));
+        assert_eq!(session.tokens.access_token_expires_in, Some(3600));
+        assert!(session.tokens.refresh_token_issued);
+        assert_eq!(session.tokens.id_token_lifetime, Some(3600));
+        assert!(session.issues.is_empty());
+
+        let kinds: Vec<OAuthStepKind> = session.steps.iter().map(|s| s.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                OAuthStepKind::Authorize,
+                OAuthStepKind::Callback,
+                OAuthStepKind::Token,
+                OAuthStepKind::UserInfo,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pkce_mismatch_and_missing_pkce() {
+        let authorize = make_flow(
+            "GET",
+            "idp.example.com",
+            "/authorize?response_type=code&client_id=app&state=s1&code_challenge=expected",
+            1.0,
+        );
+        let callback = make_flow("GET", "app.example.com", "/cb?code=c1&state=s1", 2.0);
+        let mut token = make_flow("POST", "idp.example.com", "/token", 3.0);
+        token
+            .request
+            .set_content(b"grant_type=authorization_code&code=c1&code_verifier=other".to_vec());
+
+        let sessions = correlate(&[authorize, callback, token]);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].pkce.verified, Some(false));
+        assert!(sessions[0].issues.iter().any(|i| i.contains("does not match")));
+
+        let authorize = make_flow(
+            "GET",
+            "idp.example.com",
+            "/authorize?response_type=code&client_id=app&state=s2",
+            1.0,
+        );
+        let callback = make_flow("GET", "app.example.com", "/cb?code=c2&state=s2", 2.0);
+        let mut token = make_flow("POST", "idp.example.com", "/token", 3.0);
+        token.request.set_content(b"grant_type=authorization_code&code=c2".to_vec());
+
+        let sessions = correlate(&[authorize, callback, token]);
+        assert!(!sessions[0].pkce.used);
+        assert!(sessions[0].issues.iter().any(|i| i.contains("without PKCE")));
+    }
+
+    #[test]
+    fn test_client_credentials_and_refresh() {
+        let mut token = make_flow("POST", "idp.example.com", "/token", 1.0);
+        token
+            .request
+            .set_content(b"grant_type=client_credentials&client_id=svc".to_vec());
+        let token = with_response(
+            token,
+            200,
+            vec![],
+            r#"{"access_token":"at","expires_in":"60","refresh_token":"rt"}"#,
+        );
+
+        let mut refresh = make_flow("POST", "idp.example.com", "/token", 2.0);
+        refresh
+            .request
+            .set_content(b"grant_type=refresh_token&refresh_token=rt".to_vec());
+        let refresh = with_response(refresh, 400, vec![], r#"{"error":"invalid_grant"}"#);
+
+        let sessions = correlate(&[token, refresh]);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].grant_type.as_deref(), Some("client_credentials"));
+        assert_eq!(sessions[0].client_id.as_deref(), Some("svc"));
+        assert_eq!(sessions[0].tokens.access_token_expires_in, Some(60));
+        assert_eq!(sessions[0].steps.len(), 2);
+        assert!(sessions[0].issues.iter().any(|i| i.contains("invalid_grant")));
+    }
+
+    #[test]
+    fn test_unrelated_flows_are_ignored() {
+        let flow = make_flow("GET", "example.com", "/index.html?q=1", 1.0);
+        assert!(correlate(&[flow]).is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+//! Responses the proxy could not parse, counted per server.
+//!
+//! When a server answers with something that is not an HTTP response, the
+//! flow is noted under [`METADATA_KEY`] with the parse error and the first
+//! [`MAX_RAW_BYTES`] bytes received, so the answer can be inspected later.
+//! The notes are counted per host, which makes flaky origins stand out.
+//! Anonymized flows do not keep them, as the bytes cannot be redacted.
+
+use base64::Engine;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::flow::{HTTPFlow, HTTPResponse};
+
+/// Flow metadata key of the unparsable response a flow received
+pub const METADATA_KEY: &str = "unparsable_response";
+/// Bytes of an unparsable response kept on the flow
+pub const MAX_RAW_BYTES: usize = 4096;
+/// Bytes of an unparsable response shown in the 502 answer
+const PREVIEW_BYTES: usize = 512;
+
+/// What a server sent instead of an HTTP response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnparsableResponse {
+    pub error: String,
+    /// The bytes received, up to [`MAX_RAW_BYTES`], base64 encoded
+    pub raw: String,
+    /// Bytes received in all
+    pub size: usize,
+    pub truncated: bool,
+}
+
+impl UnparsableResponse {
+    pub fn new(error: &str, data: &[u8], size: usize) -> Self {
+        let kept = &data[..data.len().min(MAX_RAW_BYTES)];
+        Self {
+            error: error.to_string(),
+            raw: base64::engine::general_purpose::STANDARD.encode(kept),
+            size,
+            truncated: kept.len() < size,
+        }
+    }
+}
+
+/// Hosts whose responses could not be parsed
+#[derive(Debug, Clone, Serialize)]
+pub struct UnparsableGroup {
+    pub host: String,
+    pub count: usize,
+    pub last_error: String,
+    pub last_seen: f64,
+}
+
+/// Note the unparsable response `flow` received in its metadata
+pub fn record(flow: &mut HTTPFlow, error: &str, data: &[u8], size: usize) {
+    if let Ok(note) = serde_json::to_value(UnparsableResponse::new(error, data, size)) {
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), note);
+    }
+}
+
+/// Count the unparsable responses noted on `flows` per host, most frequent
+/// first
+pub fn summarize(flows: &[HTTPFlow]) -> Vec<UnparsableGroup> {
+    let mut groups: IndexMap<String, UnparsableGroup> = IndexMap::new();
+    for flow in flows {
+        let noted: Option<UnparsableResponse> =
+            flow.flow.metadata.get(METADATA_KEY).and_then(|note| serde_json::from_value(note.clone()).ok());
+        let Some(noted) = noted else {
+            continue;
+        };
+        let host = format!("{}:{}", flow.request.host, flow.request.port);
+        let group = groups.entry(host.clone()).or_insert_with(|| UnparsableGroup {
+            host,
+            count: 0,
+            last_error: String::new(),
+            last_seen: 0.0,
+        });
+        group.count += 1;
+        if flow.flow.timestamp_created >= group.last_seen {
+            group.last_seen = flow.flow.timestamp_created;
+            group.last_error = noted.error;
+        }
+    }
+    let mut groups: Vec<UnparsableGroup> = groups.into_values().collect();
+    groups.sort_by_key(|group| std::cmp::Reverse(group.count));
+    groups
+}
+
+/// 502 answer to a request whose response could not be parsed, describing
+/// what the server sent
+pub fn bad_gateway(host: &str, port: u16, error: &str, data: &[u8], size: usize) -> HTTPResponse {
+    let preview = &data[..data.len().min(PREVIEW_BYTES)];
+    let body = format!(
+        "Bad Gateway: mitmproxy-rs could not parse the response of {}:{}.\n\nError: {}\n\nFirst {} of {} bytes received:\n{}\n",
+        host,
+        port,
+        error,
+        preview.len(),
+        size,
+        preview.escape_ascii()
+    );
+    let mut response = HTTPResponse::new(502, "Bad Gateway".to_string());
+    response.headers = vec![
+        ("Content-Type".to_string(), "text/plain; charset=utf-8".to_string()),
+        ("Content-Length".to_string(), body.len().to_string()),
+    ];
+    response.timestamp_start = Some(crate::clock::now());
+    response.set_content(body.into_bytes());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    fn flow(host: &str, created: f64) -> HTTPFlow {
+        let mut flow = HTTPFlow::new(HTTPRequest::new("GET".to_string(), "http".to_string(), host.to_string(), 80, "/".to_string()));
+        flow.flow.timestamp_created = created;
+        flow
+    }
+
+    #[test]
+    fn test_record_and_summarize() {
+        let mut flows = vec![flow("flaky.example", 1.0), flow("flaky.example", 3.0), flow("other.example", 2.0), flow("ok.example", 4.0)];
+        record(&mut flows[1], "Invalid status line", b"garbage\r\n\r\n", 11);
+        record(&mut flows[0], "Invalid status code", b"HTTP/1.1 abc\r\n\r\n", 17);
+        let large = vec![b'x'; MAX_RAW_BYTES + 10];
+        record(&mut flows[2], "Invalid status line", &large, large.len() + 100);
+
+        let noted: UnparsableResponse = serde_json::from_value(flows[2].flow.metadata[METADATA_KEY].clone()).unwrap();
+        assert!(noted.truncated && noted.size == MAX_RAW_BYTES + 110);
+        assert_eq!(base64::engine::general_purpose::STANDARD.decode(noted.raw).unwrap().len(), MAX_RAW_BYTES);
+
+        let groups = summarize(&flows);
+        assert_eq!(groups.len(), 2);
+        assert_eq!((groups[0].host.as_str(), groups[0].count), ("flaky.example:80", 2));
+        assert_eq!(groups[0].last_error, "Invalid status line");
+        assert_eq!((groups[1].host.as_str(), groups[1].count), ("other.example:80", 1));
+    }
+
+    #[test]
+    fn test_bad_gateway() {
+        let response = bad_gateway("flaky.example", 80, "Invalid status line", b"\x00garbage", 8);
+        assert_eq!(response.status_code, 502);
+        let body = String::from_utf8(response.content.unwrap()).unwrap();
+        assert!(body.contains("flaky.example:80") && body.contains("Error: Invalid status line"));
+        assert!(body.contains("First 8 of 8 bytes received:\n\\x00garbage"));
+    }
+}
@@ -26,7 +26,7 @@ pub async fn auth_middleware(
         return Ok(next.run(request).await);
     }
 
-    let auth_context = validate_auth(&headers, &config)?;
+    let auth_context = validate_auth(&headers, request.uri().query(), &config)?;
 
     // Add auth context to request extensions
     request.extensions_mut().insert(auth_context);
@@ -34,7 +34,7 @@ pub async fn auth_middleware(
     Ok(next.run(request).await)
 }
 
-fn validate_auth(headers: &HeaderMap, config: &Config) -> Result<AuthContext, StatusCode> {
+fn validate_auth(headers: &HeaderMap, query: Option<&str>, config: &Config) -> Result<AuthContext, StatusCode> {
     // Check Authorization header
     if let Some(auth_header) = headers.get("authorization") {
         if let Ok(auth_str) = auth_header.to_str() {
@@ -49,9 +49,19 @@ fn validate_auth(headers: &HeaderMap, config: &Config) -> Result<AuthContext, St
         }
     }
 
-    // Check query parameter
-    // Note: In a real implementation, you'd extract query params from the request
-    // This is simplified for now
+    // Check query parameter, as in the web UI URL printed on startup
+    let query_token = query
+        .into_iter()
+        .flat_map(|query| url::form_urlencoded::parse(query.as_bytes()))
+        .find(|(name, _)| name == "token");
+    if let Some((_, token)) = query_token {
+        if is_valid_token(&token, config) {
+            return Ok(AuthContext {
+                authenticated: true,
+                token: Some(token.into_owned()),
+            });
+        }
+    }
 
     // Check cookie (if present)
     if let Some(cookie_header) = headers.get("cookie") {
@@ -70,6 +80,21 @@ fn validate_auth(headers: &HeaderMap, config: &Config) -> Result<AuthContext, St
     Err(StatusCode::UNAUTHORIZED)
 }
 
+/// Whether the request carries `expected` as a bearer token or `token`
+/// query parameter. Unlike the web UI login, cookies are not accepted.
+pub fn presents_token(headers: &HeaderMap, query: Option<&str>, expected: &str) -> bool {
+    let bearer = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query_token = query
+        .into_iter()
+        .flat_map(|query| url::form_urlencoded::parse(query.as_bytes()))
+        .find(|(name, _)| name == "token")
+        .map(|(_, token)| token.into_owned());
+    !expected.is_empty() && (bearer == Some(expected) || query_token.as_deref() == Some(expected))
+}
+
 fn is_valid_token(token: &str, config: &Config) -> bool {
     if let Some(expected_token) = &config.auth_token {
         token == expected_token
@@ -143,7 +168,7 @@ mod tests {
             HeaderValue::from_static("Bearer test-token"),
         );
 
-        let result = validate_auth(&headers, &config);
+        let result = validate_auth(&headers, None, &config);
         assert!(result.is_ok());
 
         let auth_context = result.unwrap();
@@ -163,8 +188,32 @@ mod tests {
             HeaderValue::from_static("Bearer wrong-token"),
         );
 
-        let result = validate_auth(&headers, &config);
+        let result = validate_auth(&headers, None, &config);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), StatusCode::UNAUTHORIZED);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_auth_validation_query_token() {
+        let mut config = Config::default();
+        config.auth_enabled = true;
+        config.auth_token = Some("test-token".to_string());
+
+        let headers = HeaderMap::new();
+        assert!(validate_auth(&headers, Some("view=flows&token=test-token"), &config).is_ok());
+        assert!(validate_auth(&headers, Some("token=wrong-token"), &config).is_err());
+        assert!(validate_auth(&headers, Some("view=flows"), &config).is_err());
+    }
+
+    #[test]
+    fn test_presents_token() {
+        let mut headers = HeaderMap::new();
+        assert!(presents_token(&headers, Some("seconds=5&token=secret"), "secret"));
+        assert!(!presents_token(&headers, Some("token=other"), "secret"));
+        assert!(!presents_token(&headers, Some("token="), ""));
+        headers.insert("cookie", HeaderValue::from_static("mitmproxy_auth=1"));
+        assert!(!presents_token(&headers, None, "secret"));
+        headers.insert("authorization", HeaderValue::from_static("Bearer secret"));
+        assert!(presents_token(&headers, None, "secret"));
+    }
+}
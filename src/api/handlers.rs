@@ -1,12 +1,20 @@
 use axum::{
     extract::{Path, Query, State, WebSocketUpgrade},
-    http::StatusCode,
-    response::{Json, Response},
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
 };
+use indexmap::IndexMap;
 use serde::Deserialize;
 use serde_json::{json, Value};
+use std::convert::Infallible;
 use std::sync::Arc;
+use tokio::sync::broadcast;
 
+use crate::expectations::ExpectationSpec;
+use crate::flow::HTTPFlow;
 use crate::proxy::ProxyServer;
 
 // Index handler
@@ -16,34 +24,15 @@ pub async fn index() -> &'static str {
 
 // Filter help
 pub async fn filter_help() -> Json<Value> {
-    Json(json!({
-        "commands": {
-            "~a": "Asset content-type",
-            "~b": "Body",
-            "~bq": "Body request",
-            "~bs": "Body response",
-            "~c": "Code",
-            "~d": "Domain",
-            "~dst": "Destination address",
-            "~e": "Error",
-            "~h": "Header",
-            "~hq": "Header request",
-            "~hs": "Header response",
-            "~http": "HTTP flow",
-            "~m": "Method",
-            "~marked": "Marked flow",
-            "~q": "Request",
-            "~s": "Response",
-            "~src": "Source address",
-            "~t": "Content-type",
-            "~tcp": "TCP flow",
-            "~tq": "Content-type request",
-            "~ts": "Content-type response",
-            "~u": "URL",
-            "~udp": "UDP flow",
-            "~websocket": "WebSocket flow"
-        }
-    }))
+    let mut commands: serde_json::Map<String, Value> = crate::filter::get_filter_help()
+        .into_iter()
+        .map(|(name, help)| (name.to_string(), json!(help)))
+        .collect();
+    // Filters registered by addons
+    for custom in crate::filter::list_custom_filters() {
+        commands.insert(format!("~{}", custom.name), json!(custom.help));
+    }
+    Json(json!({ "commands": commands }))
 }
 
 // WebSocket handler
@@ -97,13 +86,25 @@ pub struct ExecuteCommandRequest {
 
 pub async fn execute_command(
     Path(cmd): Path<String>,
-    State(_proxy): State<Arc<ProxyServer>>,
-    Json(_req): Json<ExecuteCommandRequest>,
+    State(proxy): State<Arc<ProxyServer>>,
+    Json(req): Json<ExecuteCommandRequest>,
 ) -> Json<Value> {
     match cmd.as_str() {
         "replay.client" => {
-            // TODO: Implement replay functionality
-            Json(json!({"value": null}))
+            let mut flows = Vec::new();
+            for spec in &req.arguments {
+                match resolve_flows(&proxy, spec).await {
+                    Ok(resolved) => flows.extend(resolved),
+                    Err(e) => return Json(json!({"error": e})),
+                }
+            }
+            let options = proxy.config().replay.clone();
+            let mut replayed = Vec::new();
+            for flow in &flows {
+                let outcome = proxy.replay(flow, &options).await;
+                replayed.extend(outcome.flows.into_iter().map(|flow| flow.flow.id));
+            }
+            Json(json!({"value": replayed}))
         }
         "set" => {
             // TODO: Implement option setting
@@ -113,15 +114,44 @@ pub async fn execute_command(
     }
 }
 
+/// The HTTP flows a command argument names: a flow id, `@all`, `@marked`,
+/// `@unmarked` or a filter expression
+async fn resolve_flows(proxy: &ProxyServer, spec: &str) -> std::result::Result<Vec<HTTPFlow>, String> {
+    if let Some(flow) = proxy.get_flow(spec).await {
+        return Ok(vec![flow]);
+    }
+    let flows = proxy.get_flows().await.into_iter().filter(|flow| !flow.is_tcp() && !flow.is_dns());
+    Ok(match spec {
+        "@all" => flows.collect(),
+        "@marked" => flows.filter(|flow| !flow.flow.marked.is_empty()).collect(),
+        "@unmarked" => flows.filter(|flow| flow.flow.marked.is_empty()).collect(),
+        _ => {
+            let filter = crate::filter::Filter::new("command".to_string(), spec.to_string()).map_err(|e| e.to_string())?;
+            flows.filter(|flow| filter.matches(flow)).collect()
+        }
+    })
+}
+
 // Events
-pub async fn get_events(State(_proxy): State<Arc<ProxyServer>>) -> Json<Vec<Value>> {
-    // TODO: Implement event logging
-    Json(vec![])
+pub async fn get_events(State(proxy): State<Arc<ProxyServer>>) -> Json<Vec<crate::eventlog::LogEntry>> {
+    Json(proxy.get_events())
 }
 
 // Flows
-pub async fn get_flows(State(proxy): State<Arc<ProxyServer>>) -> Json<Vec<Value>> {
-    let flows = proxy.get_flows().await;
+#[derive(Deserialize)]
+pub struct FlowsQuery {
+    /// Only return flows stored after the one with this sequence number
+    since_seq: Option<u64>,
+}
+
+pub async fn get_flows(
+    Query(query): Query<FlowsQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> Json<Vec<Value>> {
+    let flows = match query.since_seq {
+        Some(seq) => proxy.get_flows_since(seq).await,
+        None => proxy.get_flows().await,
+    };
     let json_flows: Vec<Value> = flows
         .iter()
         .map(|flow| flow.to_json())
@@ -130,42 +160,359 @@ pub async fn get_flows(State(proxy): State<Arc<ProxyServer>>) -> Json<Vec<Value>
 }
 
 #[derive(Deserialize)]
-#[allow(dead_code)]
 pub struct DumpQuery {
     filter: Option<String>,
+    /// `har` for a HAR document, `k6` or `locust` for a load test script,
+    /// `reqwest` or `pytest` for a test per flow, otherwise the native format
+    format: Option<String>,
+    /// Rejected: anonymized dumps are taken with POST, see
+    /// [`dump_anonymized_flows`]
+    #[serde(default)]
+    anonymize: bool,
+    /// Keep the pseudonym mapping of an anonymized dump in the confdir to
+    /// reverse the process
+    #[serde(default)]
+    mapping: bool,
 }
 
 pub async fn dump_flows(
-    Query(_query): Query<DumpQuery>,
+    Query(query): Query<DumpQuery>,
     State(proxy): State<Arc<ProxyServer>>,
 ) -> std::result::Result<Vec<u8>, StatusCode> {
-    let flows = proxy.get_flows().await;
+    // Anonymizing saves the pseudonym mapping, which a GET must not do
+    if query.anonymize {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let flows = dump_selection(&proxy, query.filter).await?;
+    serialize_dump(&flows, query.format.as_deref())
+}
 
-    // TODO: Apply filter if provided
-    // TODO: Implement proper binary flow serialization
-    let serialized = serde_json::to_vec(&flows).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(serialized)
+/// Dump flows with identifying data replaced by pseudonyms. The mapping
+/// back to the original values never leaves the proxy host; it is saved in
+/// the confdir, readable by the owner only, if `mapping` is set.
+pub async fn dump_anonymized_flows(
+    Query(query): Query<DumpQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Vec<u8>, StatusCode> {
+    let flows = dump_selection(&proxy, query.filter).await?;
+    let mut redactor = crate::redact::Redactor::new(uuid::Uuid::new_v4().to_string());
+    let flows: Vec<_> = flows.iter().map(|flow| redactor.anonymize(flow)).collect();
+    if query.mapping {
+        let mapping_path = proxy.save_anonymization_mapping(redactor.mapping()).map_err(|e| {
+            tracing::error!("Failed to save anonymization mapping: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        tracing::info!("Saved anonymization mapping to {}", mapping_path.display());
+    }
+    serialize_dump(&flows, query.format.as_deref())
+}
+
+/// Flows to dump, those matching `filter` if it is set
+async fn dump_selection(proxy: &ProxyServer, filter: Option<String>) -> std::result::Result<Vec<crate::flow::HTTPFlow>, StatusCode> {
+    let mut flows = proxy.get_flows().await;
+    if let Some(expr) = filter.filter(|expr| !expr.is_empty()) {
+        let filter = crate::filter::Filter::new("dump".to_string(), expr).map_err(|_| StatusCode::BAD_REQUEST)?;
+        flows.retain(|flow| filter.matches(flow));
+    }
+    Ok(flows)
+}
+
+fn serialize_dump(flows: &[crate::flow::HTTPFlow], format: Option<&str>) -> std::result::Result<Vec<u8>, StatusCode> {
+    let serialized = match format {
+        Some("har") => crate::io::write_har(flows),
+        Some("k6") => crate::io::write_k6(flows),
+        Some("locust") => crate::io::write_locust(flows),
+        Some("reqwest") => crate::io::write_reqwest_tests(flows),
+        Some("pytest") => crate::io::write_pytest_tests(flows),
+        _ => crate::io::write_flows(flows),
+    };
+    serialized.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+/// Replace all flows with those of an uploaded dump or HAR file
 pub async fn load_flows(
     State(proxy): State<Arc<ProxyServer>>,
-    _body: axum::body::Bytes,
-) -> std::result::Result<(), StatusCode> {
-    // TODO: Implement flow loading from binary format
+    body: axum::body::Bytes,
+) -> std::result::Result<(), (StatusCode, String)> {
+    let flows = crate::io::read_flows(&body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
     proxy.clear_flows().await;
+    for flow in flows {
+        proxy.add_flow(flow).await;
+    }
     Ok(())
 }
 
-pub async fn resume_flows(State(_proxy): State<Arc<ProxyServer>>) -> StatusCode {
-    // TODO: Resume all intercepted flows
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    filter: Option<String>,
+}
+
+/// One-line description of a completed flow for live tails
+fn flow_summary(flow: &crate::flow::HTTPFlow) -> Value {
+    let response = flow.response.as_ref();
+    let duration_ms = flow
+        .request
+        .timestamp_start
+        .zip(response.and_then(|r| r.timestamp_end))
+        .map(|(start, end)| ((end - start) * 1000.0).round());
+    json!({
+        "id": flow.flow.id,
+        "seq": flow.flow.seq,
+        "method": flow.request.method,
+        "url": flow.request.url(),
+        "status_code": response.map(|r| r.status_code),
+        "size": response.and_then(|r| r.content.as_ref().map(Vec::len)),
+        "duration_ms": duration_ms,
+        "error": flow.flow.error.as_ref().map(|e| e.msg.clone()),
+    })
+}
+
+/// Server-sent events stream of flow summaries as flows complete
+pub async fn stream_flows(
+    Query(query): Query<StreamQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Sse<impl futures_util::Stream<Item = std::result::Result<Event, Infallible>>>, (StatusCode, String)> {
+    let filter = query
+        .filter
+        .filter(|expr| !expr.is_empty())
+        .map(|expr| crate::filter::Filter::new("stream".to_string(), expr))
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let receiver = proxy.subscribe_completed();
+    let stream = futures_util::stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(flow) if filter.as_ref().is_some_and(|f| !f.matches(&flow)) => continue,
+                Ok(flow) => Event::default().event("flow").id(flow.flow.seq.to_string()).json_data(flow_summary(&flow)),
+                // A slow reader is told how many flows it missed rather than dropped
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    Event::default().event("lagged").json_data(json!({ "skipped": skipped }))
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            return Some((Ok(event.unwrap_or_default()), (receiver, filter)));
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize)]
+pub struct MergeQuery {
+    filter: Option<String>,
+}
+
+/// Merge an uploaded dump into the flow store, skipping flows that are
+/// already present
+pub async fn merge_flows(
+    Query(query): Query<MergeQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+    body: axum::body::Bytes,
+) -> std::result::Result<Json<Value>, (StatusCode, String)> {
+    let bad_request = |e: crate::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    let flows = crate::io::read_flows(&body).map_err(bad_request)?;
+    let filter = query
+        .filter
+        .filter(|expr| !expr.is_empty())
+        .map(|expr| crate::filter::Filter::new("merge".to_string(), expr))
+        .transpose()
+        .map_err(bad_request)?;
+
+    let mut merger = crate::io::FlowMerger::new();
+    for flow in proxy.get_flows().await {
+        merger.mark_seen(&flow);
+    }
+
+    let mut filtered = 0;
+    for flow in flows {
+        if filter.as_ref().is_some_and(|f| !f.matches(&flow)) {
+            filtered += 1;
+            continue;
+        }
+        merger.add(flow);
+    }
+
+    let duplicates = merger.duplicates;
+    let added = merger.into_flows();
+    let count = added.len();
+    for flow in added {
+        proxy.add_flow(flow).await;
+    }
+
+    Ok(Json(json!({
+        "added": count,
+        "duplicates": duplicates,
+        "filtered": filtered
+    })))
+}
+
+/// Resume all intercepted flows
+pub async fn resume_flows(State(proxy): State<Arc<ProxyServer>>) -> StatusCode {
+    let Ok(all) = crate::filter::Filter::new("resume".to_string(), String::new()) else {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    };
+    let resume = |flow: &mut HTTPFlow| {
+        let intercepted = flow.flow.intercepted;
+        flow.flow.resume();
+        intercepted
+    };
+    proxy.update_matching(&all, resume).await;
+    proxy.release_intercepted().await;
     StatusCode::OK
 }
 
-pub async fn kill_flows(State(_proxy): State<Arc<ProxyServer>>) -> StatusCode {
-    // TODO: Kill all killable flows
+/// Kill all killable flows, dropping the held ones
+pub async fn kill_flows(State(proxy): State<Arc<ProxyServer>>) -> StatusCode {
+    let Ok(all) = crate::filter::Filter::new("kill".to_string(), String::new()) else {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    };
+    let kill = |flow: &mut HTTPFlow| {
+        let killable = flow.flow.killable();
+        if killable {
+            flow.flow.kill();
+        }
+        killable
+    };
+    proxy.update_matching(&all, kill).await;
+    proxy.release_intercepted().await;
     StatusCode::OK
 }
 
+#[derive(Deserialize)]
+pub struct FlowActionsRequest {
+    /// Filter expression selecting the flows; an empty one selects all
+    filter: String,
+    #[serde(flatten)]
+    action: FlowAction,
+    /// Only count the matching flows
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum FlowAction {
+    Delete,
+    /// Set the marker; an empty one unmarks
+    Mark {
+        #[serde(default = "default_marker")]
+        marker: String,
+    },
+    /// Attach a tag, or detach it with `remove`
+    Tag {
+        tag: String,
+        #[serde(default)]
+        remove: bool,
+    },
+    /// Download the flows, as HAR with `format: "har"`, as a load test
+    /// script with `format: "k6"` or `"locust"`, or as tests with
+    /// `format: "reqwest"` or `"pytest"`
+    Export { format: Option<String> },
+    Resume,
+    Kill,
+}
+
+fn default_marker() -> String {
+    ":default:".to_string()
+}
+
+/// Apply an action to every flow matching a filter in one call
+pub async fn flow_actions(
+    State(proxy): State<Arc<ProxyServer>>,
+    Json(request): Json<FlowActionsRequest>,
+) -> std::result::Result<Response, (StatusCode, String)> {
+    let filter = crate::filter::Filter::new("actions".to_string(), request.filter)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if let FlowAction::Tag { tag, .. } = &request.action {
+        if tag.trim().is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "Tags must not be empty".to_string()));
+        }
+    }
+    let name = match &request.action {
+        FlowAction::Delete => "delete",
+        FlowAction::Mark { .. } => "mark",
+        FlowAction::Tag { .. } => "tag",
+        FlowAction::Export { .. } => "export",
+        FlowAction::Resume => "resume",
+        FlowAction::Kill => "kill",
+    };
+
+    if request.dry_run {
+        let matched = proxy.get_flows().await.iter().filter(|flow| filter.matches(flow)).count();
+        return Ok(Json(json!({ "action": name, "dry_run": true, "matched": matched })).into_response());
+    }
+    if proxy.is_read_only() && !matches!(request.action, FlowAction::Export { .. }) {
+        return Err((StatusCode::FORBIDDEN, "The flows of a read-only capture cannot be changed".to_string()));
+    }
+
+    let (matched, affected) = match request.action {
+        FlowAction::Delete => {
+            let removed = proxy.remove_matching(&filter).await;
+            (removed, removed)
+        }
+        FlowAction::Mark { marker } => {
+            let mark = |flow: &mut HTTPFlow| {
+                let changed = flow.flow.marked != marker;
+                flow.flow.marked = marker.clone();
+                changed
+            };
+            proxy.update_matching(&filter, |flow| record_change(flow, mark)).await
+        }
+        FlowAction::Tag { tag, remove } => {
+            let tag = |flow: &mut HTTPFlow| if remove { flow.flow.remove_tag(&tag) } else { flow.flow.add_tag(&tag) };
+            proxy.update_matching(&filter, |flow| record_change(flow, tag)).await
+        }
+        FlowAction::Export { format } => {
+            let mut flows = proxy.get_flows().await;
+            flows.retain(|flow| filter.matches(flow));
+            let serialized = match format.as_deref() {
+                Some("har") => crate::io::write_har(&flows),
+                Some("k6") => crate::io::write_k6(&flows),
+                Some("locust") => crate::io::write_locust(&flows),
+                Some("reqwest") => crate::io::write_reqwest_tests(&flows),
+                Some("pytest") => crate::io::write_pytest_tests(&flows),
+                _ => crate::io::write_flows(&flows),
+            };
+            let body = serialized.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            return Ok(body.into_response());
+        }
+        FlowAction::Resume => {
+            let resume = |flow: &mut HTTPFlow| {
+                let intercepted = flow.flow.intercepted;
+                flow.flow.resume();
+                intercepted
+            };
+            let counts = proxy.update_matching(&filter, |flow| record_change(flow, resume)).await;
+            proxy.release_intercepted().await;
+            counts
+        }
+        FlowAction::Kill => {
+            let kill = |flow: &mut HTTPFlow| {
+                let killable = flow.flow.killable();
+                if killable {
+                    flow.flow.kill();
+                }
+                killable
+            };
+            let counts = proxy.update_matching(&filter, |flow| record_change(flow, kill)).await;
+            proxy.release_intercepted().await;
+            counts
+        }
+    };
+    Ok(Json(json!({ "action": name, "dry_run": false, "matched": matched, "affected": affected })).into_response())
+}
+
+/// Apply `apply` to a flow, recording the change in its history like
+/// single edits do. Returns whether the flow changed.
+fn record_change(flow: &mut HTTPFlow, apply: impl Fn(&mut HTTPFlow) -> bool) -> bool {
+    let original = flow.clone();
+    let changed = apply(flow);
+    if changed {
+        crate::changelog::record(flow, &original, "api", None);
+    }
+    changed
+}
+
 // Individual flow operations
 pub async fn get_flow(
     Path(flow_id): Path<String>,
@@ -220,6 +567,7 @@ pub async fn update_flow(
         .await
         .ok_or(StatusCode::NOT_FOUND)?;
 
+    let original = flow.clone();
     flow.backup();
 
     // Update request
@@ -273,6 +621,8 @@ pub async fn update_flow(
         flow.flow.comment = comment;
     }
 
+    crate::changelog::record(&mut flow, &original, "api", None);
+
     if proxy.update_flow(flow).await {
         Ok(StatusCode::OK)
     } else {
@@ -280,6 +630,17 @@ pub async fn update_flow(
     }
 }
 
+pub async fn get_flow_changes(
+    Path(flow_id): Path<String>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Json<Value>, StatusCode> {
+    let flow = proxy.get_flow(&flow_id).await.ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(json!({
+        "flow_id": flow.flow.id,
+        "changes": flow.flow.changes
+    })))
+}
+
 pub async fn delete_flow(
     Path(flow_id): Path<String>,
     State(proxy): State<Arc<ProxyServer>>,
@@ -298,6 +659,7 @@ pub async fn resume_flow(
     if let Some(mut flow) = proxy.get_flow(&flow_id).await {
         flow.flow.resume();
         proxy.update_flow(flow).await;
+        proxy.release_intercepted().await;
         StatusCode::OK
     } else {
         StatusCode::NOT_FOUND
@@ -312,6 +674,7 @@ pub async fn kill_flow(
         if flow.flow.killable() {
             flow.flow.kill();
             proxy.update_flow(flow).await;
+            proxy.release_intercepted().await;
         }
         StatusCode::OK
     } else {
@@ -334,16 +697,27 @@ pub async fn duplicate_flow(
     }
 }
 
+#[derive(Deserialize)]
+pub struct ReplayQuery {
+    /// Override the configured `replay.follow_redirects`
+    follow_redirects: Option<bool>,
+    /// Override the configured `replay.max_redirects`
+    max_redirects: Option<usize>,
+}
+
 pub async fn replay_flow(
     Path(flow_id): Path<String>,
+    Query(query): Query<ReplayQuery>,
     State(proxy): State<Arc<ProxyServer>>,
-) -> StatusCode {
-    if let Some(_flow) = proxy.get_flow(&flow_id).await {
-        // TODO: Implement replay functionality
-        StatusCode::OK
-    } else {
-        StatusCode::NOT_FOUND
-    }
+) -> std::result::Result<Json<Value>, StatusCode> {
+    let flow = proxy.get_flow(&flow_id).await.ok_or(StatusCode::NOT_FOUND)?;
+    let mut options = proxy.config().replay.clone();
+    options.follow_redirects = query.follow_redirects.unwrap_or(options.follow_redirects);
+    options.max_redirects = query.max_redirects.unwrap_or(options.max_redirects);
+
+    let outcome = proxy.replay(&flow, &options).await;
+    let flows: Vec<&str> = outcome.flows.iter().map(|flow| flow.flow.id.as_str()).collect();
+    Ok(Json(json!({ "flows": flows, "stopped": outcome.stopped })))
 }
 
 pub async fn revert_flow(
@@ -362,23 +736,114 @@ pub async fn revert_flow(
 }
 
 // Flow content operations
+
+/// Bytes of a body returned when the client does not ask for a length
+pub const DEFAULT_CONTENT_LENGTH: usize = 1024 * 1024;
+
+/// Size of the chunks a body download is streamed in
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ContentQuery {
+    /// First byte to return
+    #[serde(default)]
+    pub offset: usize,
+    /// Bytes to return; `DEFAULT_CONTENT_LENGTH` if unset
+    pub length: Option<usize>,
+    /// Shortest string the hex view lists, `0` for none
+    pub strings: Option<usize>,
+}
+
+/// The part of a body a content request asked for
+struct ContentPage<'a> {
+    data: &'a [u8],
+    offset: usize,
+    total_size: usize,
+    truncated: bool,
+}
+
+impl ContentQuery {
+    fn page<'a>(&self, content: &'a [u8]) -> ContentPage<'a> {
+        let offset = self.offset.min(content.len());
+        let length = self.length.unwrap_or(DEFAULT_CONTENT_LENGTH);
+        let end = offset.saturating_add(length).min(content.len());
+        ContentPage {
+            data: &content[offset..end],
+            offset,
+            total_size: content.len(),
+            truncated: end < content.len(),
+        }
+    }
+}
+
+/// Body and content type of a flow's request or response, or of the data a
+/// TCP flow's client or server sent
+fn message_content(flow: crate::flow::HTTPFlow, message: &str) -> std::result::Result<(Vec<u8>, Option<String>), StatusCode> {
+    match message {
+        "request" => {
+            let content_type = flow.request.get_header("content-type").cloned();
+            Ok((flow.request.content.unwrap_or_default(), content_type))
+        }
+        "response" => {
+            let response = flow.response.ok_or(StatusCode::NOT_FOUND)?;
+            let content_type = response.get_header("content-type").cloned();
+            Ok((response.content.unwrap_or_default(), content_type))
+        }
+        "client" | "server" => {
+            let from_client = message == "client";
+            let content = flow
+                .tcp_messages
+                .into_iter()
+                .filter(|m| m.from_client == from_client)
+                .flat_map(|m| m.content)
+                .collect();
+            Ok((content, None))
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// A range of the body, `DEFAULT_CONTENT_LENGTH` bytes from the start
+/// unless `offset` and `length` say otherwise. The `X-Total-Size` header
+/// carries the size of the whole body, and `X-Truncated` is set when bytes
+/// follow the returned range.
 pub async fn get_flow_content(
     Path((flow_id, message)): Path<(String, String)>,
+    Query(query): Query<ContentQuery>,
     State(proxy): State<Arc<ProxyServer>>,
-) -> std::result::Result<Vec<u8>, StatusCode> {
+) -> std::result::Result<Response, StatusCode> {
     let flow = proxy.get_flow(&flow_id).await.ok_or(StatusCode::NOT_FOUND)?;
+    let flow = proxy.with_full_body(flow).await;
+    let (content, _) = message_content(flow, &message)?;
 
-    match message.as_str() {
-        "request" => Ok(flow.request.content.unwrap_or_default()),
-        "response" => {
-            if let Some(response) = flow.response {
-                Ok(response.content.unwrap_or_default())
-            } else {
-                Err(StatusCode::NOT_FOUND)
-            }
-        }
-        _ => Err(StatusCode::BAD_REQUEST),
+    let page = query.page(&content);
+    let mut response = page.data.to_vec().into_response();
+    let headers = response.headers_mut();
+    headers.insert("x-total-size", page.total_size.into());
+    if page.truncated {
+        headers.insert("x-truncated", header::HeaderValue::from_static("true"));
     }
+    Ok(response)
+}
+
+/// The whole body as an attachment, streamed with chunked transfer encoding
+pub async fn download_flow_content(
+    Path((flow_id, message)): Path<(String, String)>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Response, StatusCode> {
+    let flow = proxy.get_flow(&flow_id).await.ok_or(StatusCode::NOT_FOUND)?;
+    let flow = proxy.with_full_body(flow).await;
+    let (content, content_type) = message_content(flow, &message)?;
+
+    let content = bytes::Bytes::from(content);
+    let chunks = (0..content.len())
+        .step_by(DOWNLOAD_CHUNK_SIZE)
+        .map(move |start| Ok::<_, Infallible>(content.slice(start..(start + DOWNLOAD_CHUNK_SIZE).min(content.len()))));
+    let body = axum::body::Body::from_stream(futures_util::stream::iter(chunks));
+
+    let content_type = content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+    let disposition = format!("attachment; filename=\"{}-{}.bin\"", flow_id, message);
+    Ok(([(header::CONTENT_TYPE, content_type), (header::CONTENT_DISPOSITION, disposition)], body).into_response())
 }
 
 pub async fn set_flow_content(
@@ -409,32 +874,454 @@ pub async fn set_flow_content(
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    /// Reference file on the proxy host
+    pub file: String,
+    /// Comma-separated normalizations, e.g. `json,whitespace`
+    #[serde(default)]
+    pub normalize: String,
+}
+
+pub async fn get_flow_body_diff(
+    Path((flow_id, message)): Path<(String, String)>,
+    Query(query): Query<DiffQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Json<crate::bodydiff::BodyDiff>, (StatusCode, String)> {
+    let flow = proxy
+        .get_flow(&flow_id)
+        .await
+        .ok_or((StatusCode::NOT_FOUND, "Flow not found".to_string()))?;
+    let normalization =
+        crate::bodydiff::Normalization::parse(&query.normalize).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let expected = proxy.read_diff_reference(&query.file).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    crate::bodydiff::diff_message(&flow, &message, &expected, normalization)
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+pub async fn get_request_form(
+    Path(flow_id): Path<String>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Json<crate::forms::Form>, (StatusCode, String)> {
+    let flow = proxy
+        .get_flow(&flow_id)
+        .await
+        .ok_or((StatusCode::NOT_FOUND, "Flow not found".to_string()))?;
+    crate::forms::decode(&flow.request)
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FormUpdate {
+    /// Convert the body to this form type; defaults to the current one
+    #[serde(rename = "type")]
+    pub kind: Option<crate::forms::FormKind>,
+    pub fields: Vec<crate::forms::FormField>,
+}
+
+pub async fn set_request_form(
+    Path(flow_id): Path<String>,
+    State(proxy): State<Arc<ProxyServer>>,
+    Json(update): Json<FormUpdate>,
+) -> std::result::Result<Json<crate::forms::Form>, (StatusCode, String)> {
+    let mut flow = proxy
+        .get_flow(&flow_id)
+        .await
+        .ok_or((StatusCode::NOT_FOUND, "Flow not found".to_string()))?;
+
+    let original = flow.clone();
+    flow.backup();
+    crate::forms::encode(&mut flow.request, update.kind, &update.fields)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    crate::changelog::record(&mut flow, &original, "api", None);
+
+    let form = crate::forms::decode(&flow.request).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    proxy.update_flow(flow).await;
+    Ok(Json(form))
+}
+
+/// Views that render any range of a body, and so are given only the range
+/// asked for
+const BYTE_PAGED_VIEWS: &[&str] = &["hex", "text"];
+
+/// A content view of a range of the body, selected like for `content.data`.
+/// The hex view numbers lines by their offset in the whole body and names
+/// the file type found at its start. Views that decode structure, such as
+/// `json`, render the whole body and the range applies to the rendered text
+/// instead.
 pub async fn get_flow_content_view(
     Path((flow_id, message, content_view)): Path<(String, String, String)>,
+    Query(query): Query<ContentQuery>,
     State(proxy): State<Arc<ProxyServer>>,
 ) -> std::result::Result<Json<Value>, StatusCode> {
     let flow = proxy.get_flow(&flow_id).await.ok_or(StatusCode::NOT_FOUND)?;
+    let flow = proxy.with_full_body(flow).await;
+    let (content, content_type) = message_content(flow, &message)?;
+    let transformed = crate::transforms::apply(content_type.as_deref(), &content);
 
-    let content = match message.as_str() {
-        "request" => flow.request.content.unwrap_or_default(),
-        "response" => {
-            if let Some(response) = flow.response {
-                response.content.unwrap_or_default()
-            } else {
-                return Err(StatusCode::NOT_FOUND);
+    let registry = crate::contentviews::registry();
+    let content_view = content_view.strip_suffix(".json").unwrap_or(&content_view);
+    let view = match content_view {
+        "auto" => registry.auto_view(&transformed.data, content_type.as_deref()).name(),
+        name => name,
+    };
+    if !BYTE_PAGED_VIEWS.contains(&view) {
+        let mut rendered = registry
+            .render(content_view, &transformed.data, content_type.as_deref())
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let text = std::mem::take(&mut rendered.text);
+        let page = query.page(text.as_bytes());
+        let boundary = |mut i: usize| {
+            while !text.is_char_boundary(i) {
+                i -= 1;
             }
-        }
-        _ => return Err(StatusCode::BAD_REQUEST),
+            i
+        };
+        let (start, end) = (boundary(page.offset), boundary(page.offset + page.data.len()));
+        rendered.text = text[start..end].to_string();
+        let mut rendered = json!(rendered);
+        rendered["offset"] = json!(start);
+        rendered["total_size"] = json!(text.len());
+        rendered["truncated"] = json!(end < text.len());
+        rendered["transform"] = json!(transformed.transform);
+        return Ok(Json(rendered));
+    }
+
+    let page = query.page(&transformed.data);
+    let mut rendered = registry
+        .render(content_view, page.data, content_type.as_deref())
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let file_type = (rendered.view_name == "hex").then(|| crate::contentviews::hex::file_type(&transformed.data));
+    if let Some(file_type) = file_type {
+        let min_string_length = query.strings.unwrap_or(crate::contentviews::hex::DEFAULT_MIN_STRING_LENGTH);
+        rendered.text = crate::contentviews::hex::render(page.data, page.offset, file_type, min_string_length);
+    }
+    let mut rendered = json!(rendered);
+    if let Some(file_type) = file_type {
+        rendered["file_type"] = json!(file_type);
+    }
+    rendered["offset"] = json!(page.offset);
+    rendered["total_size"] = json!(page.total_size);
+    rendered["truncated"] = json!(page.truncated);
+    rendered["transform"] = json!(transformed.transform);
+    Ok(Json(rendered))
+}
+
+#[derive(Deserialize)]
+pub struct PreviewQuery {
+    size: Option<u32>,
+}
+
+pub async fn get_flow_response_preview(
+    Path(flow_id): Path<String>,
+    Query(query): Query<PreviewQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<impl IntoResponse, StatusCode> {
+    let flow = proxy.get_flow(&flow_id).await.ok_or(StatusCode::NOT_FOUND)?;
+    let content = flow
+        .response
+        .and_then(|response| response.content)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let size = query.size.unwrap_or(crate::contentviews::image::DEFAULT_PREVIEW_SIZE);
+    let preview = crate::contentviews::image::preview(&content, size)
+        .map_err(|_| StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+    Ok(([(header::CONTENT_TYPE, "image/png")], preview))
+}
+
+// Recording
+pub async fn get_recording_segments(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    match proxy.save_stream_segments().await {
+        Some(segments) => Json(json!({ "enabled": true, "segments": segments })),
+        None => Json(json!({ "enabled": false, "segments": [] })),
+    }
+}
+
+// Expectations
+pub async fn get_expectations(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    Json(json!(proxy.get_expectations().await))
+}
+
+pub async fn add_expectation(
+    State(proxy): State<Arc<ProxyServer>>,
+    Json(spec): Json<ExpectationSpec>,
+) -> std::result::Result<StatusCode, (StatusCode, String)> {
+    proxy
+        .add_expectation(spec)
+        .await
+        .map(|_| StatusCode::CREATED)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+pub async fn delete_expectation(
+    Path(name): Path<String>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> StatusCode {
+    if proxy.remove_expectation(&name).await {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+pub async fn get_expectation_results(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    let results = proxy.expectation_results().await;
+    let passed: usize = results.iter().map(|r| r.passed).sum();
+    let failed: usize = results.iter().map(|r| r.failed).sum();
+    Json(json!({
+        "passed": passed,
+        "failed": failed,
+        "expectations": results
+    }))
+}
+
+pub async fn reset_expectation_results(State(proxy): State<Arc<ProxyServer>>) -> StatusCode {
+    proxy.reset_expectation_results().await;
+    StatusCode::OK
+}
+
+// Addons
+pub async fn get_addons(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    Json(json!(proxy.get_addons().await))
+}
+
+// Header profiles
+pub async fn get_header_profiles(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    let (profiles, active) = proxy.get_header_profiles().await;
+    Json(json!({
+        "active": active,
+        "profiles": profiles
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct HeaderProfileSelection {
+    pub name: Option<String>,
+}
+
+pub async fn set_header_profile(
+    State(proxy): State<Arc<ProxyServer>>,
+    Json(selection): Json<HeaderProfileSelection>,
+) -> std::result::Result<StatusCode, (StatusCode, String)> {
+    proxy
+        .set_header_profile(selection.name.as_deref())
+        .await
+        .map(|_| StatusCode::OK)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+// CA certificate
+/// Download the CA certificate as `pem`, `cer` or `mobileconfig`
+pub async fn get_ca_cert(
+    Path(format): Path<String>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Response, (StatusCode, String)> {
+    let format = crate::onboarding::CertFormat::from_name(&format)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown certificate format: {}", format)))?;
+    let ca = proxy.ca().ok_or_else(|| (StatusCode::SERVICE_UNAVAILABLE, "The proxy has no CA".to_string()))?;
+    let data = crate::onboarding::cert_download(ca, format).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let disposition = format!("attachment; filename=\"{}\"", format.file_name());
+    Ok(([(header::CONTENT_TYPE, format.content_type().to_string()), (header::CONTENT_DISPOSITION, disposition)], data)
+        .into_response())
+}
+
+// Pinning tests
+pub async fn get_pinning_tests(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    let accepted: Vec<Value> = proxy
+        .get_flows()
+        .await
+        .iter()
+        .filter_map(|flow| {
+            let result = flow.flow.metadata.get(crate::pinning::METADATA_KEY)?;
+            Some(json!({
+                "flow_id": flow.flow.id,
+                "host": flow.request.host,
+                "mode": result["mode"],
+            }))
+        })
+        .collect();
+    Json(json!({ "rules": proxy.get_pinning_tests(), "accepted": accepted }))
+}
+
+// Adaptation services
+pub async fn get_adaptation_services(
+    State(proxy): State<Arc<ProxyServer>>,
+) -> Json<Vec<crate::adaptation::AdaptationService>> {
+    Json(proxy.get_adaptation_services())
+}
+
+pub async fn get_upstream_proxies(
+    State(proxy): State<Arc<ProxyServer>>,
+) -> Json<Vec<crate::proxychain::UpstreamProxyStatus>> {
+    Json(proxy.upstream_proxies())
+}
+
+pub async fn get_server_replay(
+    State(proxy): State<Arc<ProxyServer>>,
+) -> Json<crate::serverreplay::ServerReplayStatus> {
+    Json(proxy.server_replay())
+}
+
+// Analysis
+pub async fn get_oauth_analysis(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    let flows = proxy.get_flows().await;
+    let sessions = crate::analysis::oauth::correlate(&flows);
+    Json(json!({ "sessions": sessions }))
+}
+
+#[derive(Deserialize)]
+pub struct CorsQuery {
+    filter: Option<String>,
+}
+
+pub async fn get_cors_analysis(
+    Query(query): Query<CorsQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Json<Value>, StatusCode> {
+    let mut flows = proxy.get_flows().await;
+    if let Some(expr) = query.filter.filter(|expr| !expr.is_empty()) {
+        let filter = crate::filter::Filter::new("cors".to_string(), expr).map_err(|_| StatusCode::BAD_REQUEST)?;
+        flows.retain(|flow| filter.matches(flow));
+    }
+    let exchanges = crate::analysis::cors::analyze(&flows);
+    Ok(Json(json!({ "exchanges": exchanges })))
+}
+
+#[derive(Deserialize)]
+pub struct DowngradesQuery {
+    filter: Option<String>,
+}
+
+pub async fn get_downgrades_analysis(
+    Query(query): Query<DowngradesQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Json<Value>, StatusCode> {
+    let mut flows = proxy.get_flows().await;
+    if let Some(expr) = query.filter.filter(|expr| !expr.is_empty()) {
+        let filter = crate::filter::Filter::new("downgrades".to_string(), expr).map_err(|_| StatusCode::BAD_REQUEST)?;
+        flows.retain(|flow| filter.matches(flow));
+    }
+    let downgrades = crate::analysis::downgrades::summarize(&flows);
+    let total: usize = downgrades.iter().map(|group| group.count).sum();
+    Ok(Json(json!({ "total": total, "downgrades": downgrades })))
+}
+
+#[derive(Deserialize)]
+pub struct UnparsableQuery {
+    filter: Option<String>,
+}
+
+/// Responses that could not be parsed, counted per host
+pub async fn get_unparsable_analysis(
+    Query(query): Query<UnparsableQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Json<Value>, StatusCode> {
+    let mut flows = proxy.get_flows().await;
+    if let Some(expr) = query.filter.filter(|expr| !expr.is_empty()) {
+        let filter = crate::filter::Filter::new("unparsable".to_string(), expr).map_err(|_| StatusCode::BAD_REQUEST)?;
+        flows.retain(|flow| filter.matches(flow));
+    }
+    let hosts = crate::analysis::unparsable::summarize(&flows);
+    let total: usize = hosts.iter().map(|group| group.count).sum();
+    Ok(Json(json!({ "total": total, "hosts": hosts })))
+}
+
+#[derive(Deserialize)]
+pub struct DuplicatesQuery {
+    filter: Option<String>,
+    /// Also compare request bodies
+    #[serde(default)]
+    requests: bool,
+    #[serde(default)]
+    min_size: usize,
+}
+
+pub async fn get_duplicates_analysis(
+    Query(query): Query<DuplicatesQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Json<Value>, StatusCode> {
+    let mut flows = proxy.get_flows().await;
+    if let Some(expr) = query.filter.filter(|expr| !expr.is_empty()) {
+        let filter = crate::filter::Filter::new("duplicates".to_string(), expr).map_err(|_| StatusCode::BAD_REQUEST)?;
+        flows.retain(|flow| filter.matches(flow));
+    }
+    let duplicates = crate::analysis::duplicates::find_duplicates(&flows, query.requests, query.min_size);
+    Ok(Json(json!({ "duplicates": duplicates })))
+}
+
+#[derive(Deserialize)]
+pub struct EndpointsQuery {
+    filter: Option<String>,
+}
+
+pub async fn get_endpoints_analysis(
+    Query(query): Query<EndpointsQuery>,
+    State(proxy): State<Arc<ProxyServer>>,
+) -> std::result::Result<Json<Value>, StatusCode> {
+    let mut flows = proxy.get_flows().await;
+    if let Some(expr) = query.filter.filter(|expr| !expr.is_empty()) {
+        let filter = crate::filter::Filter::new("endpoints".to_string(), expr).map_err(|_| StatusCode::BAD_REQUEST)?;
+        flows.retain(|flow| filter.matches(flow));
+    }
+    let endpoints = crate::analysis::endpoints::group_endpoints(&flows, proxy.endpoint_templater());
+    Ok(Json(json!({ "endpoints": endpoints })))
+}
+
+pub async fn get_traffic_analysis(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    let summary = proxy.metrics_summary();
+    let describe = |stats: &crate::metrics::HostStats| {
+        json!({
+            "requests": stats.requests,
+            "errors": stats.errors,
+            "server_errors": stats.server_errors,
+            "error_rate": stats.error_rate(),
+            "status_classes": stats.status_classes,
+            "latency_p50_ms": stats.latency_quantile_ms(0.5),
+            "latency_p95_ms": stats.latency_quantile_ms(0.95),
+        })
     };
+    let hosts: serde_json::Map<String, Value> =
+        summary.hosts.iter().map(|(host, stats)| (host.clone(), describe(stats))).collect();
+    Json(json!({
+        "window_secs": summary.window_secs,
+        "total": describe(&summary.total),
+        "hosts": hosts,
+    }))
+}
 
-    // Simple content view implementation
-    let text = String::from_utf8_lossy(&content);
-    Ok(Json(json!({
-        "text": text,
-        "view_name": content_view,
-        "syntax_highlight": false,
-        "description": format!("{} content", message)
-    })))
+pub async fn get_dns_cache(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    let (lookups, connect_failures) = proxy.dns_cache().snapshot();
+    Json(json!({ "lookups": lookups, "connect_failures": connect_failures }))
+}
+
+pub async fn clear_dns_cache(State(proxy): State<Arc<ProxyServer>>) -> StatusCode {
+    proxy.dns_cache().clear();
+    StatusCode::OK
+}
+
+pub async fn get_tls_sessions(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    Json(json!(proxy.tls_sessions().stats()))
+}
+
+pub async fn clear_tls_sessions(State(proxy): State<Arc<ProxyServer>>) -> StatusCode {
+    proxy.tls_sessions().clear();
+    StatusCode::OK
+}
+
+pub async fn get_csp_reports(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    Json(json!({ "reports": proxy.csp_reports() }))
+}
+
+pub async fn clear_csp_reports(State(proxy): State<Arc<ProxyServer>>) -> StatusCode {
+    proxy.clear_csp_reports();
+    StatusCode::OK
+}
+
+// Metrics
+pub async fn get_metrics(State(proxy): State<Arc<ProxyServer>>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], proxy.metrics_prometheus())
 }
 
 // Clear all
@@ -443,18 +1330,55 @@ pub async fn clear_all(State(proxy): State<Arc<ProxyServer>>) -> StatusCode {
     StatusCode::OK
 }
 
+// Allowlist-only mode
+fn allowlist_state(proxy: &ProxyServer) -> Value {
+    let (options, audit) = proxy.allowlist();
+    json!({
+        "enabled": options.enabled,
+        "hosts": options.hosts,
+        "audit": audit,
+    })
+}
+
+pub async fn get_allowlist(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    Json(allowlist_state(&proxy))
+}
+
+/// Body holds the new `enabled` flag and `hosts`; the change is audited
+pub async fn set_allowlist(
+    State(proxy): State<Arc<ProxyServer>>,
+    Json(options): Json<crate::allowlist::AllowlistOptions>,
+) -> std::result::Result<Json<Value>, (StatusCode, String)> {
+    proxy.set_allowlist(options, "api").map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(Json(allowlist_state(&proxy)))
+}
+
 // Options
-pub async fn get_options(State(_proxy): State<Arc<ProxyServer>>) -> Json<Value> {
-    // TODO: Return actual options
-    Json(json!({}))
+pub async fn get_options(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    Json(json!({
+        "modify_headers": proxy.modify_headers().await,
+        "modify_body": proxy.modify_body().await,
+    }))
+}
+
+/// Options that can be changed at runtime; others are left as they are
+#[derive(Deserialize)]
+pub struct OptionsUpdate {
+    modify_headers: Option<Vec<String>>,
+    modify_body: Option<Vec<String>>,
 }
 
 pub async fn set_options(
-    State(_proxy): State<Arc<ProxyServer>>,
-    Json(_options): Json<Value>,
-) -> StatusCode {
-    // TODO: Update options
-    StatusCode::OK
+    State(proxy): State<Arc<ProxyServer>>,
+    Json(options): Json<OptionsUpdate>,
+) -> std::result::Result<StatusCode, (StatusCode, String)> {
+    if let Some(specs) = options.modify_headers {
+        proxy.set_modify_headers(&specs).await.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    }
+    if let Some(specs) = options.modify_body {
+        proxy.set_modify_body(&specs).await.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    }
+    Ok(StatusCode::OK)
 }
 
 pub async fn save_options(State(_proxy): State<Arc<ProxyServer>>) -> StatusCode {
@@ -462,16 +1386,97 @@ pub async fn save_options(State(_proxy): State<Arc<ProxyServer>>) -> StatusCode
     StatusCode::OK
 }
 
+pub async fn get_log_levels(State(_proxy): State<Arc<ProxyServer>>) -> Result<Json<crate::logging::LogLevels>, (StatusCode, String)> {
+    crate::logging::levels()
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Logging has not been initialized".to_string()))
+}
+
+/// Body maps subsystems (or `default`) to a level; `null` resets a subsystem
+pub async fn set_log_levels(
+    State(_proxy): State<Arc<ProxyServer>>,
+    Json(levels): Json<IndexMap<String, Option<String>>>,
+) -> Result<Json<crate::logging::LogLevels>, (StatusCode, String)> {
+    crate::logging::set_levels(levels.iter().map(|(name, level)| (name.as_str(), level.as_deref())))
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+// Recording
+#[derive(Deserialize)]
+pub struct RecordingUpdate {
+    recording: bool,
+}
+
+fn recording_state(proxy: &ProxyServer) -> Value {
+    json!({
+        "recording": proxy.is_recording(),
+        "unrecorded": proxy.unrecorded_flows(),
+        "aggregate_only": proxy.is_aggregate_only(),
+    })
+}
+
+pub async fn get_recording(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+    Json(recording_state(&proxy))
+}
+
+pub async fn set_recording(
+    State(proxy): State<Arc<ProxyServer>>,
+    Json(update): Json<RecordingUpdate>,
+) -> Json<Value> {
+    proxy.set_recording(update.recording);
+    Json(recording_state(&proxy))
+}
+
 // State
-pub async fn get_state(State(_proxy): State<Arc<ProxyServer>>) -> Json<Value> {
+pub async fn get_state(State(proxy): State<Arc<ProxyServer>>) -> Json<Value> {
     Json(json!({
-        "version": "0.1.0",
-        "contentViews": ["auto", "text", "json", "xml", "html"],
+        "version": crate::build_info::VERSION,
+        "contentViews": crate::contentviews::registry().names(),
+        "bodyTransforms": crate::transforms::list_body_transforms(),
         "servers": {},
-        "platform": std::env::consts::OS
+        "platform": std::env::consts::OS,
+        "recording": recording_state(&proxy),
+        "last_seq": proxy.last_seq(),
+        "pruning": proxy.prune_stats(),
+        "ca": proxy.ca_status()
     }))
 }
 
+pub async fn get_build_info(State(proxy): State<Arc<ProxyServer>>) -> Json<crate::build_info::BuildInfo> {
+    Json(proxy.build_info())
+}
+
+// Self-profiling
+#[derive(Deserialize)]
+pub struct ProfileQuery {
+    seconds: Option<u64>,
+    frequency: Option<i32>,
+    format: Option<String>,
+}
+
+/// CPU profile of the proxy over `seconds`, as pprof protobuf or a
+/// flamegraph SVG
+pub async fn get_cpu_profile(
+    Query(query): Query<ProfileQuery>,
+) -> std::result::Result<impl IntoResponse, (StatusCode, String)> {
+    use crate::profiling::{cpu_profile, ProfileRequest};
+
+    let request = ProfileRequest::from_query(query.seconds, query.frequency, query.format.as_deref())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let content_type = request.format.content_type();
+    let profile = tokio::task::spawn_blocking(move || cpu_profile(&request))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?;
+    Ok(([(header::CONTENT_TYPE, content_type)], profile))
+}
+
+pub async fn get_heap_snapshot(State(proxy): State<Arc<ProxyServer>>) -> Json<crate::profiling::HeapSnapshot> {
+    let gauges = proxy.gauge_snapshot(&mut crate::gauges::Throughput::default(), crate::clock::now()).await;
+    Json(crate::profiling::HeapSnapshot::new(&gauges))
+}
+
 // Process information
 pub async fn get_processes(State(_proxy): State<Arc<ProxyServer>>) -> Json<Vec<Value>> {
     // TODO: Return process list
@@ -497,4 +1502,414 @@ pub async fn get_executable_icon(
         0x02, 0x00, 0x01, 0xFC, 0xA8, 0x51, 0x0D, 0x68, 0x00, 0x00, 0x00, 0x00,
         0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
     ]
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_page() {
+        let content = vec![b'x'; DEFAULT_CONTENT_LENGTH + 10];
+        let page = ContentQuery::default().page(&content);
+        assert_eq!((page.data.len(), page.total_size, page.truncated), (DEFAULT_CONTENT_LENGTH, content.len(), true));
+
+        let page = ContentQuery { offset: DEFAULT_CONTENT_LENGTH, ..Default::default() }.page(&content);
+        assert_eq!((page.offset, page.data.len(), page.truncated), (DEFAULT_CONTENT_LENGTH, 10, false));
+
+        let page = ContentQuery { offset: usize::MAX, length: Some(usize::MAX), ..Default::default() }.page(b"abc");
+        assert_eq!((page.offset, page.data, page.truncated), (3, &b""[..], false));
+    }
+
+    #[tokio::test]
+    async fn test_structured_view_of_large_body() {
+        use crate::flow::{HTTPRequest, HTTPResponse};
+
+        let proxy = Arc::new(ProxyServer::new(Arc::new(crate::config::Config::default())));
+        let items: Vec<Value> = (0..DEFAULT_CONTENT_LENGTH / 8).map(|i| json!({ "id": i })).collect();
+        let body = serde_json::to_vec(&items).unwrap();
+        assert!(body.len() > DEFAULT_CONTENT_LENGTH);
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_header("Content-Type".to_string(), "application/json".to_string());
+        response.set_content(body);
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "api.example".to_string(), 443, "/".to_string());
+        let flow = HTTPFlow::new(request).with_response(response);
+        let id = flow.flow.id.clone();
+        proxy.add_flow(flow).await;
+        let view = |name: &str, offset| {
+            let path = Path((id.clone(), "response".to_string(), name.to_string()));
+            let query = Query(ContentQuery { offset, length: Some(100), ..Default::default() });
+            get_flow_content_view(path, query, State(Arc::clone(&proxy)))
+        };
+
+        let Json(first) = view("json", 0).await.unwrap();
+        assert!(first["text"].as_str().unwrap().starts_with("[\n  {\n    \"id\": 0"));
+        assert_eq!(first["truncated"], true);
+        let total = first["total_size"].as_u64().unwrap() as usize;
+        let Json(last) = view("auto", total - 10).await.unwrap();
+        assert_eq!((last["view_name"].as_str(), last["truncated"].as_bool()), (Some("json"), Some(false)));
+        assert!(last["text"].as_str().unwrap().ends_with("}\n]"));
+
+        let Json(hex) = view("hex", 16).await.unwrap();
+        assert_eq!(hex["offset"], 16);
+        assert!(hex["text"].as_str().unwrap().starts_with("00000010"));
+    }
+
+    #[tokio::test]
+    async fn test_flow_actions() {
+        use crate::flow::HTTPRequest;
+
+        let proxy = Arc::new(ProxyServer::new(Arc::new(crate::config::Config::default())));
+        for host in ["a.example.com", "a.example.com", "b.example.com"] {
+            let request = HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, "/".to_string());
+            proxy.add_flow(HTTPFlow::new(request)).await;
+        }
+        let act = |body: Value| {
+            let proxy = Arc::clone(&proxy);
+            async move {
+                let request = serde_json::from_value(body).unwrap();
+                let response = flow_actions(State(proxy), Json(request)).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<Value>(&body).unwrap()
+            }
+        };
+
+        let counted = act(json!({ "filter": "~d a.example", "action": "delete", "dry_run": true })).await;
+        assert_eq!(counted["matched"], 2);
+        assert_eq!(proxy.get_flows().await.len(), 3);
+
+        let tagged = act(json!({ "filter": "~d a.example", "action": "tag", "tag": "noise" })).await;
+        assert_eq!((tagged["matched"].as_u64(), tagged["affected"].as_u64()), (Some(2), Some(2)));
+        let again = act(json!({ "filter": "", "action": "tag", "tag": "noise" })).await;
+        assert_eq!((again["matched"].as_u64(), again["affected"].as_u64()), (Some(3), Some(1)));
+        act(json!({ "filter": "~d b.example", "action": "mark" })).await;
+        let flows = proxy.get_flows().await;
+        assert_eq!(flows[2].flow.tags(), ["noise"]);
+        assert_eq!(flows[2].flow.marked, ":default:");
+        assert!(!flows[2].flow.changes.is_empty());
+
+        let deleted = act(json!({ "filter": "~d a.example", "action": "delete" })).await;
+        assert_eq!(deleted["affected"], 2);
+        assert_eq!(proxy.get_flows().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replay_client_command() {
+        use crate::flow::HTTPRequest;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !received.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+        });
+
+        let proxy = Arc::new(ProxyServer::new(Arc::new(crate::config::Config::default())));
+        let request = HTTPRequest::new("GET".to_string(), "http".to_string(), "127.0.0.1".to_string(), port, "/".to_string());
+        let original = HTTPFlow::new(request);
+        proxy.add_flow(original.clone()).await;
+
+        let run = |arguments: &[&str]| {
+            let request = ExecuteCommandRequest { arguments: arguments.iter().map(|a| a.to_string()).collect() };
+            execute_command(Path("replay.client".to_string()), State(Arc::clone(&proxy)), Json(request))
+        };
+        let Json(result) = run(&[&original.flow.id]).await;
+        let replayed = result["value"].as_array().unwrap();
+        assert_eq!(replayed.len(), 1);
+        let flow = proxy.get_flow(replayed[0].as_str().unwrap()).await.unwrap();
+        assert!(flow.flow.is_replay && flow.flow.id != original.flow.id);
+        assert_eq!(flow.response.as_ref().unwrap().status_code, 201);
+        assert_eq!(flow.to_json()["is_replay"], "request");
+
+        let Json(result) = run(&["~d nothing.example"]).await;
+        assert_eq!(result["value"], json!([]));
+        let Json(result) = run(&["~d ["]).await;
+        assert!(result["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_resume_and_kill_intercepted() {
+        use crate::flow::HTTPRequest;
+
+        let config = crate::config::Config { intercept: Some("~d held.example".to_string()), ..Default::default() };
+        let proxy = Arc::new(ProxyServer::new(Arc::new(config)));
+        let hold = |path: &str| {
+            let proxy = Arc::clone(&proxy);
+            let mut flow = HTTPFlow::new(HTTPRequest::new("GET".to_string(), "https".to_string(), "held.example".to_string(), 443, path.to_string()));
+            let id = flow.flow.id.clone();
+            let task = tokio::spawn(async move {
+                proxy.request_hook(crate::listeners::HTTP3, &mut flow).await;
+                flow
+            });
+            (id, task)
+        };
+        let held = |id: String| {
+            let proxy = Arc::clone(&proxy);
+            async move {
+                for _ in 0..100 {
+                    if proxy.get_flow(&id).await.is_some_and(|flow| flow.flow.intercepted) {
+                        return;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                panic!("flow {} was not held", id);
+            }
+        };
+
+        // Edits made while the request is held are kept
+        let (id, task) = hold("/resumed");
+        held(id.clone()).await;
+        let mut edited = proxy.get_flow(&id).await.unwrap();
+        edited.request.path = "/edited".to_string();
+        proxy.update_flow(edited).await;
+        assert!(!task.is_finished());
+        assert_eq!(resume_flow(Path(id.clone()), State(Arc::clone(&proxy))).await, StatusCode::OK);
+        let flow = task.await.unwrap();
+        assert!(!flow.flow.intercepted && flow.flow.error.is_none());
+        assert_eq!(flow.request.path, "/edited");
+
+        let (id, task) = hold("/killed");
+        held(id.clone()).await;
+        assert_eq!(kill_flow(Path(id), State(Arc::clone(&proxy))).await, StatusCode::OK);
+        assert_eq!(task.await.unwrap().flow.error.unwrap().msg, "Connection killed.");
+
+        let (id, task) = hold("/all");
+        held(id).await;
+        assert_eq!(resume_flows(State(Arc::clone(&proxy))).await, StatusCode::OK);
+        assert!(task.await.unwrap().flow.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_flow_held_twice_is_stored_once() {
+        use crate::flow::{HTTPRequest, HTTPResponse};
+
+        let config = crate::config::Config { intercept: Some("~d held.example".to_string()), ..Default::default() };
+        let proxy = Arc::new(ProxyServer::new(Arc::new(config)));
+        let mut flow = HTTPFlow::new(HTTPRequest::new("GET".to_string(), "https".to_string(), "held.example".to_string(), 443, "/".to_string()));
+        let id = flow.flow.id.clone();
+        let task = {
+            let proxy = Arc::clone(&proxy);
+            tokio::spawn(async move {
+                proxy.request_hook(crate::listeners::HTTP3, &mut flow).await;
+                flow.response = Some(HTTPResponse::new(200, "OK".to_string()));
+                proxy.response_hook(crate::listeners::HTTP3, &mut flow).await;
+                flow
+            })
+        };
+        let held = |response: bool| {
+            let proxy = Arc::clone(&proxy);
+            let id = id.clone();
+            async move {
+                for _ in 0..100 {
+                    let flow = proxy.get_flow(&id).await;
+                    if flow.is_some_and(|flow| flow.flow.intercepted && flow.response.is_some() == response) {
+                        return;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                panic!("flow {} was not held", id);
+            }
+        };
+
+        held(false).await;
+        let seq = proxy.get_flow(&id).await.unwrap().flow.seq;
+        assert_eq!(resume_flow(Path(id.clone()), State(Arc::clone(&proxy))).await, StatusCode::OK);
+        held(true).await;
+        assert_eq!(proxy.get_flow(&id).await.unwrap().flow.seq, seq);
+        assert_eq!(proxy.get_flows().await.len(), 1);
+        assert_eq!(resume_flow(Path(id), State(Arc::clone(&proxy))).await, StatusCode::OK);
+        assert!(task.await.unwrap().flow.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_held_flows_not_recorded_are_dropped_on_release() {
+        use crate::flow::{HTTPRequest, HTTPResponse};
+
+        for aggregate_only in [true, false] {
+            let dir = tempfile::TempDir::new().unwrap();
+            let config = crate::config::Config {
+                intercept: Some("~d held.example".to_string()),
+                aggregate_only,
+                save_stream_file: Some(dir.path().join("flows.jsonl").display().to_string()),
+                ..Default::default()
+            };
+            let proxy = Arc::new(ProxyServer::new(Arc::new(config)));
+            proxy.set_recording(aggregate_only);
+            let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "held.example".to_string(), 443, "/".to_string());
+            let mut flow = HTTPFlow::new(request).with_response(HTTPResponse::new(200, "OK".to_string()));
+            let id = flow.flow.id.clone();
+            let task = {
+                let proxy = Arc::clone(&proxy);
+                tokio::spawn(async move {
+                    proxy.response_hook(crate::listeners::HTTP3, &mut flow).await;
+                    flow
+                })
+            };
+            for _ in 0..100 {
+                if proxy.get_flow(&id).await.is_some() {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+
+            // The held flow can be found and edited, but is not saved
+            let mut edited = proxy.get_flow(&id).await.expect("flow is held");
+            edited.flow.marked = ":edit:".to_string();
+            proxy.update_flow(edited).await;
+            assert_eq!(resume_flow(Path(id.clone()), State(Arc::clone(&proxy))).await, StatusCode::OK);
+            assert_eq!(task.await.unwrap().flow.marked, ":edit:");
+            assert!(proxy.get_flows().await.is_empty());
+            assert_eq!(proxy.save_stream_segments().await.map(|segments| segments.len()), Some(0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_anonymized_dump_needs_post() {
+        use crate::flow::HTTPRequest;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = crate::config::Config { confdir: dir.path().display().to_string(), ..Default::default() };
+        let proxy = Arc::new(ProxyServer::new(Arc::new(config)));
+        proxy.add_flow(HTTPFlow::new(HTTPRequest::new("GET".to_string(), "https".to_string(), "api.corp.com".to_string(), 443, "/".to_string()))).await;
+        let query = |anonymize, mapping| Query(DumpQuery { filter: None, format: None, anonymize, mapping });
+
+        assert_eq!(dump_flows(query(true, false), State(Arc::clone(&proxy))).await, Err(StatusCode::METHOD_NOT_ALLOWED));
+        assert!(!dir.path().join("anonymization").exists());
+
+        // The mapping is only kept when asked for
+        let dump = dump_anonymized_flows(query(false, false), State(Arc::clone(&proxy))).await.unwrap();
+        assert!(!String::from_utf8_lossy(&dump).contains("corp.com"));
+        assert!(!dir.path().join("anonymization").exists());
+
+        dump_anonymized_flows(query(false, true), State(Arc::clone(&proxy))).await.unwrap();
+        let saved: Vec<_> = std::fs::read_dir(dir.path().join("anonymization")).unwrap().collect();
+        assert_eq!(saved.len(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = saved[0].as_ref().unwrap().metadata().unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_body_fetch_respects_allowlist() {
+        use crate::flow::{HTTPRequest, HTTPResponse};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = crate::config::Config {
+            lazy_body: crate::lazybody::LazyBodyOptions { enabled: true, threshold_bytes: 50, preview_bytes: 10, filter: String::new() },
+            allowlist: crate::allowlist::AllowlistOptions { enabled: true, hosts: vec!["*.lab.example".to_string()] },
+            ..Default::default()
+        };
+        let proxy = Arc::new(ProxyServer::new(Arc::new(config)));
+        let request = HTTPRequest::new("GET".to_string(), "http".to_string(), "127.0.0.1".to_string(), port, "/".to_string());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_content(vec![b'x'; 100]);
+        let flow = HTTPFlow::new(request).with_response(response);
+        let id = flow.flow.id.clone();
+        proxy.add_flow(flow).await;
+
+        let query = Query(ContentQuery::default());
+        let response = get_flow_content(Path((id, "response".to_string())), query, State(Arc::clone(&proxy))).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), 10);
+        let accepted = tokio::time::timeout(std::time::Duration::from_millis(100), listener.accept()).await;
+        assert!(accepted.is_err(), "the origin was contacted");
+    }
+
+    #[tokio::test]
+    async fn test_full_body_fetched_once() {
+        use crate::flow::{HTTPRequest, HTTPResponse};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let fetches = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = Arc::clone(&fetches);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let mut received = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !received.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n").await.unwrap();
+                stream.write_all(&[b'x'; 100]).await.unwrap();
+            }
+        });
+
+        let server = |read_only| {
+            let config = crate::config::Config {
+                lazy_body: crate::lazybody::LazyBodyOptions { enabled: true, threshold_bytes: 50, preview_bytes: 10, filter: String::new() },
+                ..Default::default()
+            };
+            Arc::new(ProxyServer::new(Arc::new(config)).with_read_only(read_only))
+        };
+        let request = HTTPRequest::new("GET".to_string(), "http".to_string(), "127.0.0.1".to_string(), port, "/".to_string());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_content(vec![b'x'; 100]);
+        let flow = HTTPFlow::new(request).with_response(response);
+        let id = flow.flow.id.clone();
+        let page = |proxy: &Arc<ProxyServer>, offset| {
+            let query = Query(ContentQuery { offset, length: Some(40), ..Default::default() });
+            let proxy = Arc::clone(proxy);
+            let id = id.clone();
+            async move {
+                let response = get_flow_content(Path((id, "response".to_string())), query, State(proxy)).await.unwrap();
+                axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().len()
+            }
+        };
+
+        let proxy = server(false);
+        proxy.add_flow(flow.clone()).await;
+        assert_eq!(page(&proxy, 0).await, 40);
+        assert_eq!(page(&proxy, 40).await, 40);
+        assert_eq!(page(&proxy, 80).await, 20);
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // A viewer of a loaded dump keeps whole bodies and does not send requests
+        let viewer = server(true);
+        viewer.add_flow(flow).await;
+        assert_eq!(page(&viewer, 80).await, 20);
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_removed_flows_leave_expectation_results() {
+        use crate::flow::{HTTPRequest, HTTPResponse};
+
+        let spec = serde_json::from_value(json!({"name": "ok", "assert": "status", "equals": 200})).unwrap();
+        let config = crate::config::Config { expectations: vec![spec], ..Default::default() };
+        let proxy = Arc::new(ProxyServer::new(Arc::new(config)));
+        let mut ids = Vec::new();
+        for status in [500, 200, 200] {
+            let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "api.example".to_string(), 443, "/".to_string());
+            let flow = HTTPFlow::new(request).with_response(HTTPResponse::new(status, "OK".to_string()));
+            ids.push(flow.flow.id.clone());
+            proxy.add_flow(flow).await;
+        }
+        let outcomes = || async {
+            let results = proxy.expectation_results().await;
+            (results[0].passed, results[0].failed)
+        };
+
+        assert_eq!(outcomes().await, (2, 1));
+        assert_eq!(delete_flow(Path(ids[0].clone()), State(Arc::clone(&proxy))).await, StatusCode::OK);
+        assert_eq!(outcomes().await, (2, 0));
+        let filter = crate::filter::Filter::new("test".to_string(), "~d api.example".to_string()).unwrap();
+        assert_eq!(proxy.remove_matching(&filter).await, 2);
+        assert_eq!(outcomes().await, (0, 0));
+    }
+}
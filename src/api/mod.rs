@@ -3,7 +3,11 @@ pub mod handlers;
 pub mod websocket;
 
 use axum::{
-    routing::{get, post},
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, get, post, put},
     Router,
 };
 use std::sync::Arc;
@@ -34,8 +38,12 @@ pub fn create_router(proxy: Arc<ProxyServer>) -> Router {
         .route("/flows", get(handlers::get_flows))
         .route("/flows.json", get(handlers::get_flows))
         .route("/flows/dump", get(handlers::dump_flows).post(handlers::load_flows))
+        .route("/flows/dump/anonymized", post(handlers::dump_anonymized_flows))
+        .route("/flows/merge", post(handlers::merge_flows))
+        .route("/flows/stream", get(handlers::stream_flows))
         .route("/flows/resume", post(handlers::resume_flows))
         .route("/flows/kill", post(handlers::kill_flows))
+        .route("/flows/actions", post(handlers::flow_actions))
 
         // Individual flow operations
         .route("/flows/:flow_id",
@@ -47,15 +55,72 @@ pub fn create_router(proxy: Arc<ProxyServer>) -> Router {
         .route("/flows/:flow_id/duplicate", post(handlers::duplicate_flow))
         .route("/flows/:flow_id/replay", post(handlers::replay_flow))
         .route("/flows/:flow_id/revert", post(handlers::revert_flow))
+        .route("/flows/:flow_id/changes", get(handlers::get_flow_changes))
 
         // Flow content
         .route("/flows/:flow_id/:message/content.data",
                get(handlers::get_flow_content)
                .post(handlers::set_flow_content))
+        .route("/flows/:flow_id/:message/content.download",
+               get(handlers::download_flow_content))
+        // Also serves `:content_view.json`, which cannot be a separate route
         .route("/flows/:flow_id/:message/content/:content_view",
                get(handlers::get_flow_content_view))
-        .route("/flows/:flow_id/:message/content/:content_view.json",
-               get(handlers::get_flow_content_view))
+        .route("/flows/:flow_id/:message/diff",
+               get(handlers::get_flow_body_diff))
+        .route("/flows/:flow_id/response/preview",
+               get(handlers::get_flow_response_preview))
+        .route("/flows/:flow_id/request/form",
+               get(handlers::get_request_form)
+               .put(handlers::set_request_form))
+
+        // Recording
+        .route("/recording/segments", get(handlers::get_recording_segments))
+
+        // Expectations
+        .route("/expectations", get(handlers::get_expectations).post(handlers::add_expectation))
+        .route("/expectations/results",
+               get(handlers::get_expectation_results)
+               .delete(handlers::reset_expectation_results))
+        .route("/expectations/:name", delete(handlers::delete_expectation))
+
+        // Addons
+        .route("/addons", get(handlers::get_addons))
+
+        // Header profiles
+        .route("/header-profiles", get(handlers::get_header_profiles))
+        .route("/header-profiles/active", put(handlers::set_header_profile))
+
+        // CA certificate
+        .route("/cert/:format", get(handlers::get_ca_cert))
+
+        // Pinning tests
+        .route("/pinning-tests", get(handlers::get_pinning_tests))
+
+        // Allowlist-only mode
+        .route("/allowlist", get(handlers::get_allowlist).put(handlers::set_allowlist))
+
+        // Adaptation services
+        .route("/adaptation-services", get(handlers::get_adaptation_services))
+
+        // Upstream proxies of upstream mode
+        .route("/upstream-proxies", get(handlers::get_upstream_proxies))
+
+        // Server-side replay of recorded flows
+        .route("/server-replay", get(handlers::get_server_replay))
+
+        // Analysis
+        .route("/analysis/oauth", get(handlers::get_oauth_analysis))
+        .route("/analysis/cors", get(handlers::get_cors_analysis))
+        .route("/analysis/downgrades", get(handlers::get_downgrades_analysis))
+        .route("/analysis/duplicates", get(handlers::get_duplicates_analysis))
+        .route("/analysis/endpoints", get(handlers::get_endpoints_analysis))
+        .route("/analysis/traffic", get(handlers::get_traffic_analysis))
+        .route("/analysis/unparsable", get(handlers::get_unparsable_analysis))
+        .route("/analysis/dns-cache", get(handlers::get_dns_cache).delete(handlers::clear_dns_cache))
+        .route("/analysis/tls-sessions", get(handlers::get_tls_sessions).delete(handlers::clear_tls_sessions))
+        .route("/analysis/csp-reports", get(handlers::get_csp_reports).delete(handlers::clear_csp_reports))
+        .route("/metrics", get(handlers::get_metrics))
 
         // Clear all
         .route("/clear", post(handlers::clear_all))
@@ -64,15 +129,98 @@ pub fn create_router(proxy: Arc<ProxyServer>) -> Router {
         .route("/options", get(handlers::get_options).put(handlers::set_options))
         .route("/options.json", get(handlers::get_options))
         .route("/options/save", post(handlers::save_options))
+        .route("/options/log_levels", get(handlers::get_log_levels).put(handlers::set_log_levels))
 
         // State
+        .route("/recording", get(handlers::get_recording).put(handlers::set_recording))
         .route("/state", get(handlers::get_state))
         .route("/state.json", get(handlers::get_state))
+        .route("/build-info", get(handlers::get_build_info))
 
         // Process information
         .route("/processes", get(handlers::get_processes))
         .route("/executable-icon", get(handlers::get_executable_icon))
 
+        .merge(profiling_router(proxy.clone()))
+
+        .layer(middleware::from_fn_with_state(proxy.clone(), read_only_middleware))
         .layer(CorsLayer::permissive())
         .with_state(proxy)
-}
\ No newline at end of file
+}
+
+/// Profiling endpoints, guarded by `profiling_middleware`
+fn profiling_router(proxy: Arc<ProxyServer>) -> Router<Arc<ProxyServer>> {
+    Router::new()
+        .route("/debug/pprof/profile", get(handlers::get_cpu_profile))
+        .route("/debug/pprof/heap", get(handlers::get_heap_snapshot))
+        .route_layer(middleware::from_fn_with_state(proxy, profiling_middleware))
+}
+
+/// Hide the profiling endpoints unless profiling is enabled, and serve
+/// them only to clients presenting the configured auth token
+async fn profiling_middleware(
+    State(proxy): State<Arc<ProxyServer>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let config = proxy.config();
+    if !config.profiling {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let Some(token) = config.auth_token.as_deref() else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+    if !auth::presents_token(request.headers(), request.uri().query(), token) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(next.run(request).await)
+}
+
+/// Routes posted to that export flows without changing them. The actions
+/// route refuses its other actions itself.
+const EXPORT_ROUTES: &[&str] = &["/flows/dump/anonymized", "/flows/actions"];
+
+/// Reject modifying requests when serving a read-only capture
+async fn read_only_middleware(
+    State(proxy): State<Arc<ProxyServer>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let safe = matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || EXPORT_ROUTES.contains(&request.uri().path());
+    if proxy.is_read_only() && !safe {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(request).await)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPFlow, HTTPRequest};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_viewer_exports() {
+        let proxy = Arc::new(ProxyServer::new(Arc::new(crate::config::Config::default())).with_read_only(true));
+        proxy.add_flow(HTTPFlow::new(HTTPRequest::new("GET".to_string(), "https".to_string(), "api.corp.com".to_string(), 443, "/".to_string()))).await;
+        let router = create_router(Arc::clone(&proxy));
+        let post = |uri: &str, body: &str| {
+            let request = Request::post(uri).header("content-type", "application/json").body(axum::body::Body::from(body.to_string())).unwrap();
+            router.clone().oneshot(request)
+        };
+
+        let response = post("/flows/dump/anonymized", "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let dump = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(crate::io::read_flows(&dump).unwrap().len(), 1);
+
+        let export = r#"{"filter": "~d corp.com", "action": "export", "format": "har"}"#;
+        assert_eq!(post("/flows/actions", export).await.unwrap().status(), StatusCode::OK);
+
+        // The flows themselves cannot be changed
+        let delete = r#"{"filter": "~d corp.com", "action": "delete"}"#;
+        assert_eq!(post("/flows/actions", delete).await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(post("/flows/kill", "").await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(proxy.get_flows().await.len(), 1);
+    }
+}
@@ -3,10 +3,12 @@ use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::broadcast;
 use tracing::{debug, error, warn};
 
 use crate::flow::HTTPFlow;
+use crate::gauges::Throughput;
 use crate::proxy::ProxyServer;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,6 +44,51 @@ pub async fn handle_socket(socket: WebSocket, proxy: Arc<ProxyServer>) {
         }
     });
 
+    // Forward flows as they change and complete
+    let forward_task = {
+        let tx = tx.clone();
+        let mut updates = proxy.subscribe_updates();
+        let mut completed = proxy.subscribe_completed();
+        tokio::spawn(async move {
+            loop {
+                let (received, update_type) = tokio::select! {
+                    received = updates.recv() => (received, "flows/update"),
+                    received = completed.recv() => (received, "flows/add"),
+                };
+                match received {
+                    Ok(flow) => broadcast_flow_update(&flow, update_type, &tx).await,
+                    // A slow client misses updates rather than all of them
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    };
+
+    // Push live gauges periodically
+    let state_task = {
+        let tx = tx.clone();
+        let proxy = proxy.clone();
+        let interval = proxy.config().gauges.interval_secs;
+        tokio::spawn(async move {
+            if interval == 0 {
+                return;
+            }
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval));
+            let mut throughput = Throughput::default();
+            loop {
+                ticker.tick().await;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+                let gauges = proxy.gauge_snapshot(&mut throughput, now).await;
+                let msg = WebSocketMessage {
+                    msg_type: "state/update".to_string(),
+                    payload: json!({ "gauges": gauges }),
+                };
+                let _ = tx.send(msg);
+            }
+        })
+    };
+
     // Handle incoming messages from client
     while let Some(msg) = receiver.next().await {
         match msg {
@@ -62,6 +109,8 @@ pub async fn handle_socket(socket: WebSocket, proxy: Arc<ProxyServer>) {
         }
     }
 
+    forward_task.abort();
+    state_task.abort();
     send_task.abort();
 }
 
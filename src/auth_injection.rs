@@ -0,0 +1,446 @@
+//! Injecting credentials into outgoing requests.
+//!
+//! Each `auth_injection` rule gives requests matching its filter fresh
+//! credentials before they are forwarded or replayed, so traffic recorded
+//! without them, or from clients that have none, can be sent to secured
+//! environments. A rule either sets a static bearer token, signs the request
+//! with AWS Signature Version 4 using configured credentials, or sets a
+//! bearer token returned by a script:
+//!
+//! ```text
+//! fn token() {
+//!     if this.request.host.ends_with(".staging.example") { "staging-token" } else { "test-token" }
+//! }
+//! ```
+//!
+//! Script tokens are kept for `refresh_secs` before the script is asked
+//! again, and need the scripting feature. Credentials already on a request are replaced. The first matching
+//! rule wins, and requests it applied to note its filter and type under
+//! [`METADATA_KEY`], without the credentials themselves.
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+#[cfg(feature = "scripting")]
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::filter::Filter;
+use crate::flow::{content_hash, HTTPFlow};
+use crate::sandbox::Sandbox;
+#[cfg(feature = "scripting")]
+use crate::scripting::ScriptAddon;
+use crate::upstream::percent_decode;
+use crate::{Error, Result};
+
+/// Flow metadata key of the rule that injected credentials into a request
+pub const METADATA_KEY: &str = "auth_injection";
+
+/// Credentials given to matching requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthInjectionRule {
+    pub filter: String,
+    #[serde(flatten)]
+    pub credentials: Credentials,
+}
+
+/// Where the credentials of a rule come from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credentials {
+    /// `Authorization: Bearer <token>`
+    Bearer { token: String },
+    /// AWS Signature Version 4
+    AwsSigv4 {
+        access_key_id: String,
+        secret_access_key: String,
+        #[serde(default)]
+        session_token: Option<String>,
+        region: String,
+        service: String,
+    },
+    /// Bearer token returned by `function` of the script at `path`
+    Script {
+        path: String,
+        #[serde(default = "default_function")]
+        function: String,
+        #[serde(default = "default_refresh_secs")]
+        refresh_secs: u64,
+    },
+}
+
+fn default_function() -> String {
+    "token".to_string()
+}
+
+fn default_refresh_secs() -> u64 {
+    300
+}
+
+impl Credentials {
+    fn name(&self) -> &'static str {
+        match self {
+            Credentials::Bearer { .. } => "bearer",
+            Credentials::AwsSigv4 { .. } => "aws_sigv4",
+            Credentials::Script { .. } => "script",
+        }
+    }
+}
+
+/// A script token and when it was fetched
+#[cfg(feature = "scripting")]
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: String,
+    fetched: f64,
+}
+
+#[derive(Debug)]
+struct Injector {
+    rule: AuthInjectionRule,
+    filter: Filter,
+    #[cfg(feature = "scripting")]
+    script: Option<ScriptAddon>,
+    #[cfg(feature = "scripting")]
+    token: Mutex<Option<CachedToken>>,
+}
+
+impl Injector {
+    fn new(rule: &AuthInjectionRule, sandbox: &Sandbox) -> Result<Self> {
+        let filter = Filter::new("auth_injection".to_string(), rule.filter.clone())?;
+        #[cfg(feature = "scripting")]
+        let script = match &rule.credentials {
+            Credentials::Script { path, function, .. } => {
+                Some(ScriptAddon::load_functions(path, sandbox, &[function.as_str()])?)
+            }
+            _ => None,
+        };
+        #[cfg(not(feature = "scripting"))]
+        if let Credentials::Script { path, .. } = &rule.credentials {
+            let _ = sandbox;
+            return Err(Error::Proxy(format!("Cannot load auth_injection script {}: built without the scripting feature", path)));
+        }
+        Ok(Self {
+            rule: rule.clone(),
+            filter,
+            #[cfg(feature = "scripting")]
+            script,
+            #[cfg(feature = "scripting")]
+            token: Mutex::new(None),
+        })
+    }
+
+    /// The token of a script rule, fetched again once it is older than
+    /// `refresh_secs`
+    #[cfg(feature = "scripting")]
+    fn script_token(&self, script: &ScriptAddon, function: &str, refresh_secs: u64, flow: &HTTPFlow) -> Result<String> {
+        let now = crate::clock::now();
+        let mut cached = self.token.lock().unwrap();
+        if let Some(token) = cached.as_ref().filter(|token| now - token.fetched < refresh_secs as f64) {
+            return Ok(token.token.clone());
+        }
+        if let Err(e) = script.reload_if_changed() {
+            warn!("Keeping the previous version of the auth_injection script: {}", e);
+        }
+        let token = script.call_value(function, flow)?;
+        if token.is_empty() || token.contains(['\r', '\n']) {
+            return Err(Error::Proxy(format!("auth_injection script returned an invalid token from {}()", function)));
+        }
+        *cached = Some(CachedToken { token: token.clone(), fetched: now });
+        Ok(token)
+    }
+
+    fn inject(&self, flow: &mut HTTPFlow) -> Result<()> {
+        match &self.rule.credentials {
+            Credentials::Bearer { token } => {
+                flow.request.set_header("Authorization".to_string(), format!("Bearer {}", token));
+            }
+            #[cfg(feature = "scripting")]
+            Credentials::Script { function, refresh_secs, .. } => {
+                let script = self.script.as_ref().ok_or_else(|| Error::Proxy("auth_injection script not loaded".to_string()))?;
+                let token = self.script_token(script, function, *refresh_secs, flow)?;
+                flow.request.set_header("Authorization".to_string(), format!("Bearer {}", token));
+            }
+            #[cfg(not(feature = "scripting"))]
+            Credentials::Script { .. } => return Err(Error::Proxy("auth_injection script not loaded".to_string())),
+            Credentials::AwsSigv4 { access_key_id, secret_access_key, session_token, region, service } => {
+                let key = SigningKey {
+                    access_key_id,
+                    secret_access_key,
+                    session_token: session_token.as_deref(),
+                    region,
+                    service,
+                };
+                sign_sigv4(flow, &key, crate::clock::now())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The configured `auth_injection` rules
+#[derive(Debug, Default)]
+pub struct AuthInjection {
+    injectors: Vec<Injector>,
+}
+
+impl AuthInjection {
+    /// Compile the rules, loading script rules within `sandbox`
+    pub fn new(rules: &[AuthInjectionRule], sandbox: &Sandbox) -> Result<Self> {
+        Ok(Self { injectors: rules.iter().map(|rule| Injector::new(rule, sandbox)).collect::<Result<_>>()? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.injectors.is_empty()
+    }
+
+    /// Give the request of `flow` the credentials of the first rule
+    /// matching it. Returns whether it got any; a rule that fails leaves the
+    /// request unchanged.
+    pub fn apply(&self, flow: &mut HTTPFlow) -> bool {
+        let Some(injector) = self.injectors.iter().find(|injector| injector.filter.matches(flow)) else {
+            return false;
+        };
+        let mut injected = flow.clone();
+        if let Err(e) = injector.inject(&mut injected) {
+            warn!("auth_injection rule {} failed: {}", injector.rule.filter, e);
+            return false;
+        }
+        flow.request = injected.request;
+        let note = json!({ "filter": injector.rule.filter, "type": injector.rule.credentials.name() });
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), note);
+        true
+    }
+}
+
+/// AWS credentials and the scope requests are signed for
+#[derive(Debug, Clone, Copy)]
+pub struct SigningKey<'a> {
+    pub access_key_id: &'a str,
+    pub secret_access_key: &'a str,
+    pub session_token: Option<&'a str>,
+    pub region: &'a str,
+    pub service: &'a str,
+}
+
+/// Headers the signature replaces; other `X-Amz-*` headers the client sent
+/// are kept and signed
+const SIGV4_HEADERS: &[&str] = &["authorization", "x-amz-date", "x-amz-security-token", "x-amz-content-sha256"];
+
+/// Sign the request of `flow` with AWS Signature Version 4 as of `now`, a
+/// UNIX timestamp. The host and `X-Amz-*` headers are signed.
+pub fn sign_sigv4(flow: &mut HTTPFlow, key: &SigningKey, now: f64) -> Result<()> {
+    let time = chrono::DateTime::from_timestamp(now as i64, 0)
+        .ok_or_else(|| Error::Proxy(format!("Cannot sign a request at timestamp {}", now)))?;
+    let amz_date = time.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = time.format("%Y%m%d").to_string();
+
+    let request = &mut flow.request;
+    request.headers.retain(|(name, _)| !SIGV4_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+    let payload_hash = content_hash(request.content.as_deref().unwrap_or_default());
+    request.headers.push(("X-Amz-Date".to_string(), amz_date.clone()));
+    if let Some(token) = key.session_token {
+        request.headers.push(("X-Amz-Security-Token".to_string(), token.to_string()));
+    }
+    // S3 refuses requests without it; other services do not need it
+    if key.service == "s3" {
+        request.headers.push(("X-Amz-Content-Sha256".to_string(), payload_hash.clone()));
+    }
+
+    let host = request.get_header("host").cloned().unwrap_or_else(|| request.pretty_host.clone());
+    let mut headers: Vec<(String, String)> = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.to_ascii_lowercase().starts_with("x-amz-"))
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.split_whitespace().collect::<Vec<_>>().join(" ")))
+        .chain(std::iter::once(("host".to_string(), host.trim().to_string())))
+        .collect();
+    headers.sort();
+    let signed_headers = headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";");
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    let path = path.split('#').next().unwrap_or_default();
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request.method,
+        canonical_uri(path, key.service == "s3"),
+        canonical_query(query),
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, key.region, key.service);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, content_hash(canonical_request.as_bytes()));
+    let mut signing_key = hmac(format!("AWS4{}", key.secret_access_key).as_bytes(), date.as_bytes())?;
+    for part in [key.region, key.service, "aws4_request"] {
+        signing_key = hmac(&signing_key, part.as_bytes())?;
+    }
+    let signature: String = hmac(&signing_key, string_to_sign.as_bytes())?.iter().map(|b| format!("{:02x}", b)).collect();
+    request.headers.push((
+        "Authorization".to_string(),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            key.access_key_id, scope, signed_headers, signature
+        ),
+    ));
+    Ok(())
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Percent-encode everything but unreserved characters, as SigV4 requires
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The path with each segment encoded: once for S3, and on top of the
+/// encoding it was sent with for other services
+fn canonical_uri(path: &str, s3: bool) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.split('/')
+        .map(|segment| if s3 { uri_encode(&percent_decode(segment)) } else { uri_encode(segment) })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Query parameters encoded and sorted by name, then value
+fn canonical_query(query: &str) -> String {
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (uri_encode(&percent_decode(name)), uri_encode(&percent_decode(value)))
+        })
+        .collect();
+    params.sort();
+    params.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    fn flow(method: &str, host: &str, path: &str) -> HTTPFlow {
+        let mut request = HTTPRequest::new(method.to_string(), "https".to_string(), host.to_string(), 443, path.to_string());
+        request.headers = vec![("Host".to_string(), host.to_string())];
+        HTTPFlow::new(request)
+    }
+
+    #[test]
+    fn test_sigv4() {
+        // The get-vanilla and post-vanilla cases of the AWS SigV4 test suite
+        let key = SigningKey {
+            access_key_id: "AKIDEXAMPLE",
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            session_token: None,
+            region: "us-east-1",
+            service: "service",
+        };
+        let now = chrono::DateTime::parse_from_rfc3339("2015-08-30T12:36:00Z").unwrap().timestamp() as f64;
+        let mut get = flow("GET", "example.amazonaws.com", "/");
+        get.request.headers.push(("Authorization".to_string(), "Bearer stale".to_string()));
+        sign_sigv4(&mut get, &key, now).unwrap();
+        assert_eq!(get.request.get_header("x-amz-date").unwrap(), "20150830T123600Z");
+        assert_eq!(
+            get.request.get_header("authorization").unwrap(),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+        let mut post = flow("POST", "example.amazonaws.com", "/");
+        sign_sigv4(&mut post, &key, now).unwrap();
+        assert!(post.request.get_header("authorization").unwrap().ends_with("5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"));
+
+        // Other X-Amz-* headers of the client are kept and signed
+        let mut target = flow("POST", "example.amazonaws.com", "/");
+        target.request.headers.push(("X-Amz-Target".to_string(), "DynamoDB_20120810.GetItem".to_string()));
+        target.request.headers.push(("X-Amz-Date".to_string(), "20000101T000000Z".to_string()));
+        sign_sigv4(&mut target, &key, now).unwrap();
+        assert_eq!(target.request.get_header("x-amz-target").unwrap(), "DynamoDB_20120810.GetItem");
+        let dates: Vec<_> = target.request.headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("x-amz-date")).collect();
+        assert_eq!(dates.len(), 1);
+        assert!(target.request.get_header("authorization").unwrap().contains("SignedHeaders=host;x-amz-date;x-amz-target,"));
+
+        assert_eq!(canonical_query("b=2&a=x%20y&a=1&c"), "a=1&a=x%20y&b=2&c=");
+        assert_eq!(canonical_uri("/documents and settings/a%20b", false), "/documents%20and%20settings/a%2520b");
+        assert_eq!(canonical_uri("/a%20b", true), "/a%20b");
+    }
+
+    #[test]
+    fn test_auth_injection() {
+        let rules: Vec<AuthInjectionRule> = serde_json::from_value(json!([
+            { "filter": "~d api.example", "type": "bearer", "token": "static" },
+            {
+                "filter": "~d amazonaws.com",
+                "type": "aws_sigv4",
+                "access_key_id": "AKIDEXAMPLE",
+                "secret_access_key": "secret",
+                "session_token": "session",
+                "region": "eu-west-1",
+                "service": "s3"
+            }
+        ]))
+        .unwrap();
+        let injection = AuthInjection::new(&rules, &Sandbox::default()).unwrap();
+
+        let mut request = flow("GET", "api.example", "/");
+        request.request.headers.push(("authorization".to_string(), "Bearer expired".to_string()));
+        assert!(injection.apply(&mut request));
+        assert_eq!(request.request.get_header("authorization").unwrap(), "Bearer static");
+        assert_eq!(request.request.headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("authorization")).count(), 1);
+        assert_eq!(request.flow.metadata[METADATA_KEY], json!({ "filter": "~d api.example", "type": "bearer" }));
+
+        let mut request = flow("PUT", "bucket.s3.amazonaws.com", "/key");
+        request.request.set_content(b"data".to_vec());
+        assert!(injection.apply(&mut request));
+        assert!(request.request.get_header("authorization").unwrap().contains("/eu-west-1/s3/aws4_request"));
+        assert_eq!(request.request.get_header("x-amz-security-token").unwrap(), "session");
+        assert_eq!(request.request.get_header("x-amz-content-sha256").unwrap(), &content_hash(b"data"));
+
+        assert!(!injection.apply(&mut flow("GET", "other.example", "/")));
+
+        let missing = vec![AuthInjectionRule {
+            filter: "~d x".to_string(),
+            credentials: Credentials::Script { path: "missing.rhai".to_string(), function: default_function(), refresh_secs: 0 },
+        }];
+        assert!(AuthInjection::new(&missing, &Sandbox::default()).is_err());
+    }
+
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn test_script_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("token.rhai");
+        std::fs::write(&script, r#"fn token() { "token-for-" + this.request.host }"#).unwrap();
+        let rules: Vec<AuthInjectionRule> =
+            serde_json::from_value(json!([{ "filter": "~d scripted.example", "type": "script", "path": script }])).unwrap();
+        let injection = AuthInjection::new(&rules, &Sandbox::default()).unwrap();
+
+        let mut request = flow("GET", "scripted.example", "/");
+        assert!(injection.apply(&mut request));
+        assert_eq!(request.request.get_header("authorization").unwrap(), "Bearer token-for-scripted.example");
+        // The token is kept until it is due for a refresh
+        std::fs::write(&script, r#"fn token() { "changed" }"#).unwrap();
+        let mut request = flow("GET", "scripted.example", "/");
+        injection.apply(&mut request);
+        assert_eq!(request.request.get_header("authorization").unwrap(), "Bearer token-for-scripted.example");
+    }
+}
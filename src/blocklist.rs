@@ -0,0 +1,113 @@
+//! Answering requests with a fixed status code.
+//!
+//! Each `block_list` entry is written `/flow-filter/status`, where the first
+//! character is the separator: `:~d ads.example:404` answers every request
+//! to `ads.example` with an empty 404. Matching requests are answered
+//! before a server connection is opened, so they never leave the proxy.
+//! Status 444 is answered with nothing: the stream is killed as nginx does
+//! for the same code. The first matching rule wins.
+
+use crate::filter::Filter;
+use crate::flow::{HTTPFlow, HTTPResponse};
+use crate::{Error, Result};
+
+/// Status code answered by killing the stream without a response
+pub const NO_RESPONSE: u16 = 444;
+
+/// Requests matching `filter` are answered with `status`
+#[derive(Debug, Clone)]
+pub struct BlockListRule {
+    /// The entry the rule was parsed from
+    pub spec: String,
+    pub filter: Filter,
+    pub status: u16,
+}
+
+impl BlockListRule {
+    /// Parse a `/flow-filter/status` entry. The status is everything after
+    /// the last separator, so the filter may use the separator itself.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::invalid_request(format!("Invalid block_list entry {}: {}", spec, reason));
+        let separator = spec.chars().next().ok_or_else(|| invalid("expected /flow-filter/status"))?;
+        let (filter, status) = spec[separator.len_utf8()..]
+            .rsplit_once(separator)
+            .ok_or_else(|| invalid("expected /flow-filter/status"))?;
+        if filter.trim().is_empty() {
+            return Err(invalid("no filter"));
+        }
+        let status = status
+            .trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|status| (100..=999).contains(status))
+            .ok_or_else(|| invalid("invalid status code"))?;
+        Ok(Self { spec: spec.to_string(), filter: Filter::new("block_list".to_string(), filter.to_string())?, status })
+    }
+}
+
+/// The configured `block_list` rules
+#[derive(Debug, Clone, Default)]
+pub struct BlockList {
+    rules: Vec<BlockListRule>,
+}
+
+impl BlockList {
+    pub fn from_specs(specs: &[String]) -> Result<Self> {
+        Ok(Self { rules: specs.iter().map(|spec| BlockListRule::parse(spec)).collect::<Result<_>>()? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The status of the first rule matching `flow`, if any
+    pub fn check(&self, flow: &HTTPFlow) -> Option<u16> {
+        self.rules.iter().find(|rule| rule.filter.matches(flow)).map(|rule| rule.status)
+    }
+}
+
+/// Empty answer with `status` to a blocked request
+pub fn response(status: u16) -> HTTPResponse {
+    let reason = http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("")
+        .to_string();
+    let mut response = HTTPResponse::new(status, reason);
+    response.headers = vec![("Content-Length".to_string(), "0".to_string())];
+    response.timestamp_start = Some(crate::clock::now());
+    response.set_content(Vec::new());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    fn flow(host: &str, path: &str) -> HTTPFlow {
+        HTTPFlow::new(HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, path.to_string()))
+    }
+
+    #[test]
+    fn test_block_list() {
+        let block = BlockList::from_specs(&[
+            ":~d ads.example:404".to_string(),
+            "/~d tracker.example & ~u /collect/444".to_string(),
+            "|~d ads.example|403".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(block.check(&flow("ads.example", "/banner")), Some(404));
+        assert_eq!(block.check(&flow("tracker.example", "/collect")), Some(NO_RESPONSE));
+        assert_eq!(block.check(&flow("tracker.example", "/")), None);
+        assert_eq!(block.check(&flow("example.com", "/")), None);
+
+        let blocked = response(404);
+        assert_eq!((blocked.status_code, blocked.reason.as_str()), (404, "Not Found"));
+        assert_eq!(blocked.get_header("content-length").unwrap(), "0");
+
+        for invalid in ["", "/404", "//404", "/~d a.example/", "/~d a.example/ok", "/~d a.example/99", "/~d [/404"] {
+            assert!(BlockListRule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! Diffing message bodies against reference payloads.
+//!
+//! The decoded body of a request or response is compared with a golden
+//! payload, typically a file on the proxy host. JSON bodies can be compared
+//! structurally, which ignores key order and formatting and reports changes
+//! by JSON path; everything else is compared line by line, optionally
+//! ignoring whitespace. [`BodyDiffAddon`] runs the same comparison on live
+//! traffic and records the outcome in the flow's metadata.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+use crate::addons::Addon;
+use crate::compression;
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::{Error, Result};
+
+/// Flow metadata key under which [`BodyDiffAddon`] records its result
+pub const METADATA_KEY: &str = "body_diff";
+
+/// Bodies longer than this many lines in their differing part are not
+/// aligned line by line
+const MAX_ALIGNED_LINES: usize = 2000;
+
+/// How bodies are normalized before comparing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Normalization {
+    /// Compare as JSON values, ignoring key order and formatting
+    pub json: bool,
+    /// Ignore leading, trailing and repeated whitespace and blank lines
+    pub whitespace: bool,
+}
+
+impl Normalization {
+    /// Parse a comma-separated list such as `json,whitespace`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut normalization = Self::default();
+        for option in spec.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            match option {
+                "json" => normalization.json = true,
+                "whitespace" => normalization.whitespace = true,
+                other => return Err(Error::invalid_request(format!("Unknown normalization: {}", other))),
+            }
+        }
+        Ok(normalization)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// A difference between two JSON documents
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JsonChange {
+    /// Location of the value, e.g. `$.items[2].id`
+    pub path: String,
+    pub kind: ChangeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<Value>,
+}
+
+/// A line only present in one of two texts
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LineChange {
+    /// `added` lines are only in the actual body, `removed` ones only in
+    /// the expected one
+    pub kind: ChangeKind,
+    /// 1-based line number in the expected body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_line: Option<usize>,
+    /// 1-based line number in the actual body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual_line: Option<usize>,
+    pub text: String,
+}
+
+/// Result of comparing a body with a reference
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum BodyDiff {
+    Json { equal: bool, changes: Vec<JsonChange> },
+    Text { equal: bool, changes: Vec<LineChange> },
+}
+
+impl BodyDiff {
+    pub fn is_equal(&self) -> bool {
+        match self {
+            BodyDiff::Json { equal, .. } | BodyDiff::Text { equal, .. } => *equal,
+        }
+    }
+}
+
+/// Compare an actual body with the expected one
+pub fn diff(expected: &[u8], actual: &[u8], normalization: Normalization) -> Result<BodyDiff> {
+    if normalization.json {
+        let parse = |body: &[u8], which: &str| {
+            serde_json::from_slice::<Value>(body)
+                .map_err(|e| Error::invalid_request(format!("{} body is not JSON: {}", which, e)))
+        };
+        let mut changes = Vec::new();
+        diff_json("$".to_string(), &parse(expected, "Expected")?, &parse(actual, "Actual")?, &mut changes);
+        return Ok(BodyDiff::Json { equal: changes.is_empty(), changes });
+    }
+
+    let expected = lines(&String::from_utf8_lossy(expected), normalization.whitespace);
+    let actual = lines(&String::from_utf8_lossy(actual), normalization.whitespace);
+    let changes = diff_lines(&expected, &actual);
+    Ok(BodyDiff::Text { equal: changes.is_empty(), changes })
+}
+
+/// Compare the decoded body of a flow's `request` or `response` with the
+/// expected one
+pub fn diff_message(flow: &HTTPFlow, message: &str, expected: &[u8], normalization: Normalization) -> Result<BodyDiff> {
+    let (content, encoding) = match message {
+        "request" => (&flow.request.content, flow.request.get_header("content-encoding")),
+        "response" => {
+            let response = flow.response.as_ref().ok_or_else(|| Error::invalid_request("Flow has no response"))?;
+            (&response.content, response.get_header("content-encoding"))
+        }
+        other => return Err(Error::invalid_request(format!("Unknown message: {}", other))),
+    };
+    let content = content.as_deref().unwrap_or_default();
+    let actual = match encoding {
+        Some(encoding) => compression::decode(encoding, content)
+            .map_err(|e| Error::invalid_request(format!("Cannot decode {} body: {}", message, e)))?,
+        None => content.to_vec(),
+    };
+    diff(expected, &actual, normalization)
+}
+
+/// Read a reference file. With a `root`, the file must be inside it and
+/// relative paths are resolved against it.
+pub fn read_reference(path: &str, root: Option<&Path>) -> Result<Vec<u8>> {
+    let path = match root {
+        Some(root) => {
+            let root = root.canonicalize()?;
+            let path = root.join(path).canonicalize()?;
+            if !path.starts_with(&root) {
+                return Err(Error::invalid_request(format!(
+                    "Reference file {} is outside {}",
+                    path.display(),
+                    root.display()
+                )));
+            }
+            path
+        }
+        None => PathBuf::from(path),
+    };
+    Ok(std::fs::read(path)?)
+}
+
+fn diff_json(path: String, expected: &Value, actual: &Value, changes: &mut Vec<JsonChange>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            for (key, value) in expected {
+                match actual.get(key) {
+                    Some(other) => diff_json(child_path(&path, key), value, other, changes),
+                    None => changes.push(JsonChange {
+                        path: child_path(&path, key),
+                        kind: ChangeKind::Removed,
+                        expected: Some(value.clone()),
+                        actual: None,
+                    }),
+                }
+            }
+            for (key, value) in actual.iter().filter(|(key, _)| !expected.contains_key(*key)) {
+                changes.push(JsonChange {
+                    path: child_path(&path, key),
+                    kind: ChangeKind::Added,
+                    expected: None,
+                    actual: Some(value.clone()),
+                });
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) => {
+            for i in 0..expected.len().max(actual.len()) {
+                let path = format!("{}[{}]", path, i);
+                match (expected.get(i), actual.get(i)) {
+                    (Some(e), Some(a)) => diff_json(path, e, a, changes),
+                    (Some(e), None) => {
+                        changes.push(JsonChange { path, kind: ChangeKind::Removed, expected: Some(e.clone()), actual: None })
+                    }
+                    (None, Some(a)) => {
+                        changes.push(JsonChange { path, kind: ChangeKind::Added, expected: None, actual: Some(a.clone()) })
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+        _ if expected != actual => changes.push(JsonChange {
+            path,
+            kind: ChangeKind::Changed,
+            expected: Some(expected.clone()),
+            actual: Some(actual.clone()),
+        }),
+        _ => {}
+    }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    let plain = !key.is_empty()
+        && !key.starts_with(|c: char| c.is_ascii_digit())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        format!("{}.{}", path, key)
+    } else {
+        format!("{}[{}]", path, json!(key))
+    }
+}
+
+/// Lines with their 1-based numbers, normalized for comparison
+fn lines(text: &str, whitespace: bool) -> Vec<(usize, String)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let line = if whitespace { line.split_whitespace().collect::<Vec<_>>().join(" ") } else { line.to_string() };
+            (i + 1, line)
+        })
+        .filter(|(_, line)| !whitespace || !line.is_empty())
+        .collect()
+}
+
+/// Lines to remove from `expected` and add to get `actual`, based on their
+/// longest common subsequence
+fn diff_lines(expected: &[(usize, String)], actual: &[(usize, String)]) -> Vec<LineChange> {
+    let prefix = expected.iter().zip(actual).take_while(|(e, a)| e.1 == a.1).count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(e, a)| e.1 == a.1)
+        .count();
+    let expected = &expected[prefix..expected.len() - suffix];
+    let actual = &actual[prefix..actual.len() - suffix];
+
+    let removed = |(line, text): &(usize, String)| LineChange {
+        kind: ChangeKind::Removed,
+        expected_line: Some(*line),
+        actual_line: None,
+        text: text.clone(),
+    };
+    let added = |(line, text): &(usize, String)| LineChange {
+        kind: ChangeKind::Added,
+        expected_line: None,
+        actual_line: Some(*line),
+        text: text.clone(),
+    };
+    if expected.len() > MAX_ALIGNED_LINES || actual.len() > MAX_ALIGNED_LINES {
+        return expected.iter().map(removed).chain(actual.iter().map(added)).collect();
+    }
+
+    // common[i][j]: length of the common subsequence of expected[i..] and actual[j..]
+    let mut common = vec![vec![0u32; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i].1 == actual[j].1 {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i].1 == actual[j].1 {
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1]) {
+            changes.push(removed(&expected[i]));
+            i += 1;
+        } else {
+            changes.push(added(&actual[j]));
+            j += 1;
+        }
+    }
+    changes
+}
+
+/// Addon diffing the bodies of matching flows against a reference payload.
+/// The result is recorded in the flow's metadata under `body_diff`.
+pub struct BodyDiffAddon {
+    name: String,
+    filter: Filter,
+    message: String,
+    expected: Vec<u8>,
+    normalization: Normalization,
+}
+
+impl BodyDiffAddon {
+    /// Diff the `request` or `response` body of flows matching `filter`
+    /// against the contents of `file`
+    pub fn new(filter: &str, message: &str, file: &Path, normalization: Normalization) -> Result<Self> {
+        if !matches!(message, "request" | "response") {
+            return Err(Error::invalid_request(format!("Unknown message: {}", message)));
+        }
+        Ok(Self {
+            name: format!("body-diff:{}", file.display()),
+            filter: Filter::new("body-diff".to_string(), filter.to_string())?,
+            message: message.to_string(),
+            expected: std::fs::read(file)?,
+            normalization,
+        })
+    }
+
+    fn check(&self, flow: &mut HTTPFlow) {
+        if !self.filter.matches(flow) {
+            return;
+        }
+        let result = match diff_message(flow, &self.message, &self.expected, self.normalization) {
+            Ok(diff) => serde_json::to_value(diff).unwrap_or(Value::Null),
+            Err(e) => json!({ "error": e.to_string() }),
+        };
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), result);
+    }
+}
+
+impl Addon for BodyDiffAddon {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn request(&self, flow: &mut HTTPFlow) {
+        if self.message == "request" {
+            self.check(flow);
+        }
+    }
+
+    fn response(&self, flow: &mut HTTPFlow) {
+        if self.message == "response" {
+            self.check(flow);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Encoding;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    #[test]
+    fn test_json_diff() {
+        let expected = br#"{"id": 1, "tags": ["a", "b"], "meta": {"version": "1.0", "build date": "x"}}"#;
+        let actual = br#"{"meta": {"version": "1.1", "build date": "x"}, "tags": ["a"], "id": 1, "extra": true}"#;
+        let BodyDiff::Json { equal, changes } = diff(expected, actual, Normalization::parse("json").unwrap()).unwrap() else { panic!("expected a JSON diff") };
+        assert!(!equal);
+        let summary: Vec<(&str, ChangeKind)> = changes.iter().map(|c| (c.path.as_str(), c.kind)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("$.meta.version", ChangeKind::Changed),
+                ("$.tags[1]", ChangeKind::Removed),
+                ("$.extra", ChangeKind::Added),
+            ]
+        );
+
+        let reordered = br#"{ "b": 2,
+            "a": [1, 2] }"#;
+        assert!(diff(br#"{"a":[1,2],"b":2}"#, reordered, Normalization { json: true, whitespace: false })
+            .unwrap()
+            .is_equal());
+        assert!(diff(b"{", b"{}", Normalization { json: true, whitespace: false }).is_err());
+    }
+
+    #[test]
+    fn test_text_diff() {
+        let expected = b"one\ntwo\nthree\nfour\n";
+        let actual = b"one\n  two  \nthree and a half\nfour\nfive\n";
+        let BodyDiff::Text { changes, .. } = diff(expected, actual, Normalization::default()).unwrap() else {
+            panic!("expected a text diff")
+        };
+        assert_eq!(changes.len(), 5);
+        assert_eq!(changes[0].kind, ChangeKind::Removed);
+        assert_eq!(changes[0].expected_line, Some(2));
+
+        let ws = Normalization::parse("whitespace").unwrap();
+        let BodyDiff::Text { changes, .. } = diff(expected, actual, ws).unwrap() else { panic!("expected a text diff") };
+        let summary: Vec<(ChangeKind, &str)> = changes.iter().map(|c| (c.kind, c.text.as_str())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (ChangeKind::Removed, "three"),
+                (ChangeKind::Added, "three and a half"),
+                (ChangeKind::Added, "five"),
+            ]
+        );
+        assert!(diff(b"a  b\n\n", b"a b", ws).unwrap().is_equal());
+        assert!(Normalization::parse("json,case").is_err());
+    }
+
+    #[test]
+    fn test_diff_message_and_addon() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("expected.json");
+        std::fs::write(&file, br#"{"status": "ok"}"#).unwrap();
+
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "api.example".to_string(), 443, "/health".to_string());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_header("Content-Encoding".to_string(), "gzip".to_string());
+        response.set_content(Encoding::Gzip.encode(br#"{"status":"degraded"}"#).unwrap());
+        let mut flow = HTTPFlow::new(request).with_response(response);
+
+        let expected = read_reference("expected.json", Some(dir.path())).unwrap();
+        let result = diff_message(&flow, "response", &expected, Normalization::parse("json").unwrap()).unwrap();
+        assert!(!result.is_equal());
+        assert!(read_reference("../../etc/passwd", Some(dir.path())).is_err());
+
+        let addon = BodyDiffAddon::new("~d api.example", "response", &file, Normalization::parse("json").unwrap()).unwrap();
+        addon.response(&mut flow);
+        let recorded = &flow.flow.metadata[METADATA_KEY];
+        assert_eq!(recorded["format"], "json");
+        assert_eq!(recorded["changes"][0]["path"], "$.status");
+        assert_eq!(recorded["changes"][0]["actual"], "degraded");
+    }
+}
@@ -0,0 +1,106 @@
+//! What a proxy binary was built from and which capabilities it has.
+//!
+//! Compile-time facts are recorded by `build.rs`; the runtime part lists the
+//! optional subsystems the current config switches on. Both are reported by
+//! `/build-info`, so bug reports and automation can check a binary without
+//! guessing from its version number.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Cargo features the binary was compiled with
+pub fn features() -> Vec<&'static str> {
+    let mut features: Vec<&str> = env!("MITMPROXY_BUILD_FEATURES").split(',').filter(|f| !f.is_empty()).collect();
+    features.sort_unstable();
+    features
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub features: Vec<&'static str>,
+    /// Commit the binary was built from, if built from a git checkout
+    pub git_commit: Option<&'static str>,
+    /// Whether tracked files had uncommitted changes
+    pub git_dirty: bool,
+    pub rustc: &'static str,
+    pub target: &'static str,
+    pub profile: &'static str,
+    /// Optional subsystems and whether the config enables them
+    pub runtime: BTreeMap<&'static str, bool>,
+}
+
+impl BuildInfo {
+    pub fn new(config: &Config) -> Self {
+        let commit = env!("MITMPROXY_BUILD_GIT_COMMIT");
+        Self {
+            version: VERSION,
+            features: features(),
+            git_commit: (!commit.is_empty()).then_some(commit),
+            git_dirty: env!("MITMPROXY_BUILD_GIT_DIRTY") == "true",
+            rustc: env!("MITMPROXY_BUILD_RUSTC"),
+            target: env!("MITMPROXY_BUILD_TARGET"),
+            profile: env!("MITMPROXY_BUILD_PROFILE"),
+            runtime: runtime_options(config),
+        }
+    }
+}
+
+fn runtime_options(config: &Config) -> BTreeMap<&'static str, bool> {
+    BTreeMap::from([
+        ("adaptation", !config.adaptation_services.is_empty()),
+        ("aggregate_only", config.aggregate_only),
+        ("allowlist", config.allowlist.enabled),
+        ("anticache", config.anticache),
+        ("anticomp", config.anticomp),
+        ("auth", config.auth_enabled),
+        ("auth_injection", !config.auth_injection.is_empty()),
+        ("block_list", !config.block_list.is_empty()),
+        ("csp", !config.csp.rules.is_empty() || config.csp.capture_reports),
+        ("dns_cache", config.dns_cache.enabled),
+        ("expectations", !config.expectations.is_empty()),
+        ("intercept", config.intercept.is_some()),
+        ("map_local", !config.map_local.is_empty()),
+        ("map_remote", !config.map_remote.is_empty()),
+        ("modify_body", !config.modify_body.is_empty()),
+        ("modify_headers", !config.modify_headers.is_empty()),
+        ("pinning_tests", !config.pinning_tests.is_empty()),
+        ("profiling", config.profiling),
+        ("proxy_debug", config.proxy_debug),
+        ("record", config.record),
+        ("request_coalescing", config.request_coalescing.enabled),
+        ("scripts", !config.scripts.is_empty()),
+        ("server_replay", !config.server_replay.files.is_empty()),
+        ("save_stream", config.save_stream_file.is_some()),
+        ("shaping", !config.shaping_rules.is_empty()),
+        ("socket_tuning", config.sockets.client.is_set() || config.sockets.server.is_set()),
+        ("socks_upstream", config.socks_upstream.is_some() || !config.socks_upstream_rules.is_empty()),
+        ("stickycookie", config.stickycookie.is_some()),
+        ("strip_range", !config.strip_range.is_empty()),
+        ("substitute_body", !config.substitute_body.is_empty()),
+        ("tls_session_cache", config.tls_session_cache.enabled),
+        ("upstream_failover", !config.upstream_proxies.fallback.is_empty()),
+        ("warm_start", config.warm_start.enabled),
+        ("wasm_plugins", !config.wasm_plugins.is_empty()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let config = Config { dns_cache: crate::dns::DnsCacheOptions { enabled: false, ..Default::default() }, ..Default::default() };
+        let info = BuildInfo::new(&config);
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(info.rustc.starts_with("rustc "));
+        assert!(!info.target.is_empty());
+        assert_eq!(info.runtime["dns_cache"], false);
+        assert_eq!(info.runtime["tls_session_cache"], true);
+    }
+}
@@ -0,0 +1,288 @@
+//! Capture behavior scoped to client identities.
+//!
+//! `capture_profiles` maps a client identity to how its flows are captured
+//! and which of its requests are intercepted. An identity is a client
+//! address (`10.0.0.5`), an address range (`10.0.0.0/8`), a proxy user
+//! (`user:alice`, from the `Proxy-Authorization` Basic credentials) or `*`
+//! for every other client. A user profile takes precedence over address
+//! profiles, and the narrowest matching range wins. Clients no profile
+//! applies to are captured in full.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::{Error, Result};
+
+/// Flow metadata key naming the profile a flow was captured with
+pub const METADATA_KEY: &str = "capture_profile";
+
+/// How much of a client's traffic is stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureLevel {
+    /// Store flows with their bodies
+    #[default]
+    Full,
+    /// Store flows without request, response and message bodies
+    Headers,
+    /// Do not store flows; they are still counted in the metrics
+    None,
+}
+
+/// Capture profile as configured in the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureProfile {
+    pub capture: CaptureLevel,
+    /// Filter expression of the requests intercepted; empty intercepts
+    /// nothing
+    pub intercept: String,
+}
+
+#[derive(Debug)]
+struct CompiledProfile {
+    identity: String,
+    capture: CaptureLevel,
+    intercept: Option<Filter>,
+}
+
+/// Compiled capture profiles
+#[derive(Debug, Default)]
+pub struct CaptureProfiles {
+    users: HashMap<String, CompiledProfile>,
+    /// Address ranges, narrowest first
+    ranges: Vec<(IpAddr, u8, CompiledProfile)>,
+    default: Option<CompiledProfile>,
+}
+
+impl CaptureProfiles {
+    pub fn new(profiles: &BTreeMap<String, CaptureProfile>) -> Result<Self> {
+        let mut compiled = Self::default();
+        for (identity, profile) in profiles {
+            let intercept = (!profile.intercept.is_empty())
+                .then(|| Filter::new(format!("capture_profile:{}", identity), profile.intercept.clone()))
+                .transpose()?;
+            let entry = CompiledProfile { identity: identity.clone(), capture: profile.capture, intercept };
+            if identity == "*" {
+                compiled.default = Some(entry);
+            } else if let Some(user) = identity.strip_prefix("user:") {
+                compiled.users.insert(user.to_string(), entry);
+            } else {
+                let (address, prefix) = parse_range(identity)?;
+                compiled.ranges.push((address, prefix, entry));
+            }
+        }
+        compiled.ranges.sort_by_key(|(_, prefix, _)| std::cmp::Reverse(*prefix));
+        Ok(compiled)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.ranges.is_empty() && self.default.is_none()
+    }
+
+    fn profile_for(&self, flow: &HTTPFlow) -> Option<&CompiledProfile> {
+        if let Some(profile) = proxy_user(flow).and_then(|user| self.users.get(&user)) {
+            return Some(profile);
+        }
+        let client = flow
+            .flow
+            .client_conn
+            .as_ref()
+            .and_then(|conn| conn.peername.as_ref())
+            .and_then(|(host, _)| host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok());
+        client
+            .and_then(|client| {
+                self.ranges
+                    .iter()
+                    .find(|(address, prefix, _)| in_range(client, *address, *prefix))
+                    .map(|(_, _, profile)| profile)
+            })
+            .or(self.default.as_ref())
+    }
+
+    /// Mark the request as intercepted if the client's profile intercepts
+    /// it. Returns whether it was marked.
+    pub fn intercept(&self, flow: &mut HTTPFlow) -> bool {
+        let intercept = self
+            .profile_for(flow)
+            .and_then(|profile| profile.intercept.as_ref())
+            .is_some_and(|filter| filter.matches(flow));
+        if intercept {
+            flow.flow.intercepted = true;
+        }
+        intercept
+    }
+
+    /// Whether the client's capture level lets a flow be stored at all
+    pub fn stores(&self, flow: &HTTPFlow) -> bool {
+        self.profile_for(flow).is_none_or(|profile| profile.capture != CaptureLevel::None)
+    }
+
+    /// Apply the client's capture level to a flow about to be stored.
+    /// Returns false if the flow must not be stored at all.
+    pub fn capture(&self, flow: &mut HTTPFlow) -> bool {
+        let Some(profile) = self.profile_for(flow) else {
+            return true;
+        };
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), serde_json::json!(profile.identity));
+        match profile.capture {
+            CaptureLevel::Full => true,
+            CaptureLevel::Headers => {
+                strip_bodies(flow);
+                true
+            }
+            CaptureLevel::None => false,
+        }
+    }
+}
+
+/// Drop all bodies, keeping their sizes and hashes
+fn strip_bodies(flow: &mut HTTPFlow) {
+    flow.request.content = None;
+    if let Some(response) = flow.response.as_mut() {
+        response.content = None;
+    }
+    if let Some(websocket) = flow.websocket.as_mut() {
+        websocket.messages.iter_mut().for_each(|message| message.content.clear());
+    }
+    flow.tcp_messages.iter_mut().for_each(|message| message.content.clear());
+    flow.sse_events.clear();
+}
+
+/// User name of the request's `Proxy-Authorization` Basic credentials
+fn proxy_user(flow: &HTTPFlow) -> Option<String> {
+    let header = flow.request.get_header("proxy-authorization")?;
+    let (scheme, credentials) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    Some(decoded.split_once(':').map_or(decoded.as_str(), |(user, _)| user).to_string())
+}
+
+/// An address, or a range in CIDR notation
+pub(crate) fn parse_range(identity: &str) -> Result<(IpAddr, u8)> {
+    let invalid = || Error::invalid_request(format!("Invalid capture profile client: {}", identity));
+    let (address, prefix) = match identity.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (identity, None),
+    };
+    let address: IpAddr = address.parse().map_err(|_| invalid())?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(invalid)?,
+        None => max,
+    };
+    Ok((address, prefix))
+}
+
+pub(crate) fn in_range(client: IpAddr, address: IpAddr, prefix: u8) -> bool {
+    let (client, address, bits) = match (client, address) {
+        (IpAddr::V4(c), IpAddr::V4(a)) => (u32::from(c) as u128, u32::from(a) as u128, 32),
+        (IpAddr::V6(c), IpAddr::V6(a)) => (u128::from(c), u128::from(a), 128),
+        // IPv4 clients reported as mapped IPv6 addresses
+        (IpAddr::V6(c), IpAddr::V4(a)) => match c.to_ipv4_mapped() {
+            Some(c) => (u32::from(c) as u128, u32::from(a) as u128, 32),
+            None => return false,
+        },
+        (IpAddr::V4(_), IpAddr::V6(_)) => return false,
+    };
+    let shift = bits - prefix as u32;
+    shift >= bits || client >> shift == address >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{Connection, HTTPRequest, HTTPResponse};
+
+    fn flow(client: &str, user: Option<&str>) -> HTTPFlow {
+        let mut flow = HTTPFlow::new(HTTPRequest::new(
+            "POST".to_string(),
+            "https".to_string(),
+            "api.example.com".to_string(),
+            443,
+            "/orders".to_string(),
+        ));
+        flow.flow.client_conn = Some(Connection {
+            id: "client".to_string(),
+            peername: Some((client.to_string(), 50000)),
+            sockname: None,
+            address: None,
+            tls_established: false,
+            cert: None,
+            sni: None,
+            cipher: None,
+            alpn: None,
+            tls_version: None,
+            timestamp_start: None,
+            timestamp_tcp_setup: None,
+            timestamp_tls_setup: None,
+            timestamp_end: None,
+        });
+        if let Some(user) = user {
+            let credentials = STANDARD.encode(format!("{}:secret", user));
+            flow.request.set_header("Proxy-Authorization".to_string(), format!("Basic {}", credentials));
+        }
+        flow.request.set_content(b"order".to_vec());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_content(b"done".to_vec());
+        flow.response = Some(response);
+        flow
+    }
+
+    fn profiles() -> CaptureProfiles {
+        let profile = |capture, intercept: &str| CaptureProfile { capture, intercept: intercept.to_string() };
+        CaptureProfiles::new(&BTreeMap::from([
+            ("*".to_string(), profile(CaptureLevel::Headers, "")),
+            ("10.0.0.5".to_string(), profile(CaptureLevel::Full, "")),
+            ("10.0.0.0/8".to_string(), profile(CaptureLevel::None, "")),
+            ("user:alice".to_string(), profile(CaptureLevel::Full, "~m POST")),
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_capture_levels() {
+        let profiles = profiles();
+
+        let mut full = flow("10.0.0.5", None);
+        assert!(profiles.capture(&mut full));
+        assert_eq!(full.request.content.as_deref(), Some(&b"order"[..]));
+        assert_eq!(full.flow.metadata[METADATA_KEY], "10.0.0.5");
+
+        assert!(!profiles.capture(&mut flow("10.1.2.3", None)));
+        assert!(!profiles.capture(&mut flow("::ffff:10.1.2.3", None)));
+
+        let mut headers = flow("192.0.2.1", None);
+        assert!(profiles.capture(&mut headers));
+        assert!(headers.request.content.is_none());
+        assert!(headers.response.as_ref().unwrap().content.is_none());
+        assert_eq!(headers.response.as_ref().unwrap().content_length, Some(4));
+
+        // The user's profile applies wherever they connect from
+        let mut alice = flow("10.1.2.3", Some("alice"));
+        assert!(profiles.capture(&mut alice));
+        assert!(alice.request.content.is_some());
+
+        assert!(CaptureProfiles::new(&BTreeMap::from([("10.0.0.0/33".to_string(), CaptureProfile::default())])).is_err());
+        assert!(CaptureProfiles::default().capture(&mut flow("10.1.2.3", None)));
+    }
+
+    #[test]
+    fn test_intercept_rules_per_user() {
+        let profiles = profiles();
+        let mut alice = flow("192.0.2.1", Some("alice"));
+        assert!(profiles.intercept(&mut alice));
+        assert!(alice.flow.intercepted);
+
+        let mut bob = flow("192.0.2.1", Some("bob"));
+        assert!(!profiles.intercept(&mut bob));
+        assert!(!bob.flow.intercepted);
+    }
+}
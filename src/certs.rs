@@ -1,5 +1,6 @@
-use openssl::asn1::Asn1Time;
-use openssl::bn::{BigNum, MsbOption};
+use openssl::asn1::{Asn1Integer, Asn1Time, Asn1TimeRef};
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
 use openssl::pkey::{PKey, Private};
@@ -8,20 +9,188 @@ use openssl::x509::extension::{
     AuthorityKeyIdentifier, BasicConstraints, KeyUsage, SubjectAlternativeName,
     SubjectKeyIdentifier,
 };
-use openssl::x509::{X509NameBuilder, X509, X509Builder};
+use openssl::x509::{X509NameBuilder, X509, X509Builder, X509Ref};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::{debug, warn};
+
+use crate::clock::IdGenerator;
+use crate::filter::Filter;
+use crate::pinning::{PinningTestMode, WRONG_HOST};
+use crate::{Error, Result};
+
+/// Validity windows of generated certificates as configured in the config
+/// file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CertValidityOptions {
+    /// How far notBefore is moved into the past, so that clients whose
+    /// clock runs behind accept freshly generated certificates
+    pub backdate_hours: u32,
+    /// Validity of leaf certificates, counted from now
+    pub leaf_days: u32,
+    /// Validity of a newly generated CA certificate
+    pub ca_days: u32,
+    /// Warn once the CA expires within this many days
+    pub ca_warning_days: u32,
+    /// Leaf validity for selected hosts; the first matching rule wins
+    pub rules: Vec<CertValidityRule>,
+}
+
+impl Default for CertValidityOptions {
+    fn default() -> Self {
+        Self { backdate_hours: 48, leaf_days: 365, ca_days: 365 * 10, ca_warning_days: 30, rules: Vec::new() }
+    }
+}
+
+/// Leaf validity for the hosts a filter selects
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertValidityRule {
+    /// Filter expression selecting hosts, e.g. `~d \.internal$`
+    pub filter: String,
+    pub days: u32,
+}
+
+/// How many hosts a generated leaf certificate covers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MintingStrategy {
+    /// One certificate per hostname
+    #[default]
+    Host,
+    /// One wildcard certificate per parent domain, e.g. `*.example.com`
+    /// for `www.example.com` and `api.example.com`. Hosts below a
+    /// registrable domain get their parent's wildcard, since a wildcard
+    /// covers a single label.
+    Wildcard,
+    /// One certificate per registrable domain listing the recently seen
+    /// hosts below it, reissued when a new host shows up
+    MultiSan,
+}
+
+/// Certificate minting options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CertMintingOptions {
+    pub strategy: MintingStrategy,
+    /// Hosts a multi-SAN certificate lists before the least recently added
+    /// is dropped
+    pub max_sans: usize,
+    /// Key algorithm of leaf certificates
+    pub key: LeafKeyType,
+}
+
+impl Default for CertMintingOptions {
+    fn default() -> Self {
+        Self { strategy: MintingStrategy::Host, max_sans: 16, key: LeafKeyType::default() }
+    }
+}
+
+/// Key algorithm of generated leaf certificates. ECDSA keys are generated
+/// and used in handshakes much faster than RSA keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum LeafKeyType {
+    Rsa {
+        #[serde(default = "default_rsa_bits")]
+        bits: u32,
+    },
+    EcdsaP256,
+}
+
+fn default_rsa_bits() -> u32 {
+    2048
+}
+
+impl Default for LeafKeyType {
+    fn default() -> Self {
+        LeafKeyType::Rsa { bits: default_rsa_bits() }
+    }
+}
+
+impl std::fmt::Display for LeafKeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeafKeyType::Rsa { bits } => write!(f, "rsa{}", bits),
+            LeafKeyType::EcdsaP256 => write!(f, "ecdsa-p256"),
+        }
+    }
+}
+
+impl LeafKeyType {
+    /// RSA keys must be 2048, 3072 or 4096 bits
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            LeafKeyType::Rsa { bits } if ![2048, 3072, 4096].contains(bits) => Err(Error::certificate(format!(
+                "Unsupported RSA key size {}; use 2048, 3072 or 4096",
+                bits
+            ))),
+            _ => Ok(()),
+        }
+    }
 
-use crate::Result;
+    fn generate(&self) -> Result<PKey<Private>> {
+        Ok(match self {
+            LeafKeyType::Rsa { bits } => PKey::from_rsa(Rsa::generate(*bits)?)?,
+            LeafKeyType::EcdsaP256 => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+                PKey::from_ec_key(EcKey::generate(&group)?)?
+            }
+        })
+    }
+}
+
+/// Persistent leaf certificate cache options as configured in the config
+/// file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CertDiskCacheOptions {
+    pub enabled: bool,
+    /// Certificates kept on disk before the least recently used are
+    /// deleted
+    pub max_entries: usize,
+    /// Most recently used certificates loaded into memory at startup
+    pub preload: usize,
+}
+
+impl Default for CertDiskCacheOptions {
+    fn default() -> Self {
+        Self { enabled: true, max_entries: 5000, preload: 500 }
+    }
+}
+
+/// Second-level labels that are public suffixes under two-letter country
+/// code TLDs, e.g. `co.uk`. A small stand-in for the public suffix list.
+const COUNTRY_SECOND_LEVEL: &[&str] = &["ac", "co", "com", "edu", "gov", "ltd", "net", "nhs", "or", "org", "plc", "sch"];
+
+/// Validity of the CA certificate as reported in `/state`
+#[derive(Debug, Clone, Serialize)]
+pub struct CaStatus {
+    pub not_before: i64,
+    pub not_after: i64,
+    /// Whole days until the CA expires, negative once it has
+    pub expires_in_days: i64,
+    /// Set when the CA expires within `ca_warning_days`
+    pub warning: Option<String>,
+}
 
 pub struct CertificateAuthority {
     cert: X509,
     key: PKey<Private>,
     cert_cache: Arc<RwLock<HashMap<String, (X509, PKey<Private>)>>>,
     cert_dir: PathBuf,
+    validity: CertValidityOptions,
+    validity_rules: Vec<(Filter, u32)>,
+    minting: CertMintingOptions,
+    /// Hosts covered by each multi-SAN certificate, oldest first
+    san_hosts: RwLock<HashMap<String, Vec<String>>>,
+    disk_cache: Option<DiskCache>,
+    /// Source of the random serial numbers of leaf certificates
+    ids: Arc<dyn IdGenerator>,
 }
 
 impl std::fmt::Debug for CertificateAuthority {
@@ -34,50 +203,336 @@ impl std::fmt::Debug for CertificateAuthority {
 
 impl CertificateAuthority {
     pub fn new<P: AsRef<Path>>(cert_dir: P) -> Result<Self> {
+        Self::with_validity(cert_dir, &CertValidityOptions::default())
+    }
+
+    /// Create or load the CA in `cert_dir`, generating certificates with the
+    /// given validity windows
+    pub fn with_validity<P: AsRef<Path>>(cert_dir: P, validity: &CertValidityOptions) -> Result<Self> {
         let cert_dir = cert_dir.as_ref().to_path_buf();
         fs::create_dir_all(&cert_dir)?;
 
         let ca_cert_path = cert_dir.join("mitmproxy-ca-cert.pem");
-        let ca_key_path = cert_dir.join("mitmproxy-ca-cert.p12");
+        let ca_key_path = cert_dir.join("mitmproxy-ca.pem");
 
         let (cert, key) = if ca_cert_path.exists() && ca_key_path.exists() {
-            Self::load_ca_cert(&ca_cert_path, &ca_key_path)?
+            Self::load_ca_cert(&ca_cert_path, &ca_key_path, validity)?
         } else {
-            let (cert, key) = Self::generate_ca_cert()?;
+            let (cert, key) = Self::generate_ca_cert(validity)?;
             Self::save_ca_cert(&cert, &key, &ca_cert_path, &ca_key_path)?;
             (cert, key)
         };
+        Self::from_ca(cert_dir, validity, cert, key)
+    }
+
+    /// Use an existing CA instead of the one in `cert_dir`, such as the CA
+    /// of a Python mitmproxy install, so devices that already trust it keep
+    /// working. `ca_file` is a PEM file holding the key and the
+    /// certificate, like `mitmproxy-ca.pem`, a PKCS#12 bundle like
+    /// `mitmproxy-ca.p12`, or a directory with either. `cert_dir` still
+    /// holds the cached leaf certificates.
+    pub fn import<P: AsRef<Path>, Q: AsRef<Path>>(
+        cert_dir: P,
+        ca_file: Q,
+        passphrase: Option<&str>,
+        validity: &CertValidityOptions,
+    ) -> Result<Self> {
+        let cert_dir = cert_dir.as_ref().to_path_buf();
+        fs::create_dir_all(&cert_dir)?;
+        let (cert, key) = read_ca_file(ca_file.as_ref(), passphrase)?;
+        Self::from_ca(cert_dir, validity, cert, key)
+    }
 
+    fn from_ca(cert_dir: PathBuf, validity: &CertValidityOptions, cert: X509, key: PKey<Private>) -> Result<Self> {
+        let validity_rules = validity
+            .rules
+            .iter()
+            .map(|rule| Ok((Filter::new("cert_validity".to_string(), rule.filter.clone())?, rule.days)))
+            .collect::<Result<Vec<_>>>()?;
         Ok(Self {
             cert,
             key,
             cert_cache: Arc::new(RwLock::new(HashMap::new())),
             cert_dir,
+            validity: validity.clone(),
+            validity_rules,
+            minting: CertMintingOptions::default(),
+            san_hosts: RwLock::default(),
+            disk_cache: None,
+            ids: crate::clock::ids(),
         })
     }
 
+    /// Keep generated leaf certificates under `leaves/` in the cert dir.
+    /// At startup, certificates that expire or were issued by another CA
+    /// are deleted and the most recently used are loaded into memory; the
+    /// others are loaded on demand.
+    pub fn with_disk_cache(mut self, options: &CertDiskCacheOptions) -> Self {
+        self.disk_cache = if options.enabled {
+            DiskCache::open(self.cert_dir.join("leaves"), options.max_entries)
+                .inspect_err(|e| warn!("Certificates are not cached on disk: {}", e))
+                .ok()
+        } else {
+            None
+        };
+        if let Some(disk_cache) = &self.disk_cache {
+            let preloaded = disk_cache.sweep(|cert| self.usable(cert), options.preload);
+            debug!("Loaded {} cached certificates from {}", preloaded.len(), disk_cache.dir.display());
+            self.cert_cache.try_write().expect("cache is not shared yet").extend(preloaded);
+        }
+        self
+    }
+
+    /// Draw the serial numbers of leaf certificates from `ids`
+    pub fn with_ids(mut self, ids: Arc<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Cover hosts with leaf certificates according to `minting`
+    pub fn with_minting(mut self, minting: &CertMintingOptions) -> Self {
+        self.minting = minting.clone();
+        if let Err(e) = minting.key.validate() {
+            warn!("Ignoring leaf key type: {}", e);
+            self.minting.key = LeafKeyType::default();
+        }
+        self
+    }
+
+    /// Key under which the certificate for `hostname` is cached. Keys of
+    /// other than the default key type carry it as a suffix, so that
+    /// certificates cached on disk before are still found.
+    pub fn cache_key(&self, hostname: &str) -> String {
+        let name = self.name_key(hostname);
+        match self.minting.key {
+            key if key == LeafKeyType::default() => name,
+            key => format!("{}#{}", name, key),
+        }
+    }
+
+    /// Name the certificate for `hostname` is issued for: the host, a
+    /// wildcard, or `san:` and the domain of a multi-SAN certificate
+    fn name_key(&self, hostname: &str) -> String {
+        if hostname.parse::<std::net::IpAddr>().is_ok() {
+            return hostname.to_string();
+        }
+        match self.minting.strategy {
+            MintingStrategy::Host => hostname.to_string(),
+            MintingStrategy::Wildcard => format!("*.{}", wildcard_base(hostname)),
+            MintingStrategy::MultiSan => format!("san:{}", registrable_domain(hostname)),
+        }
+    }
+
+    /// Validity of the CA certificate, with a warning if it expires soon
+    pub fn status(&self) -> Result<CaStatus> {
+        let not_after = self.cert.not_after();
+        let expires_in = Asn1Time::days_from_now(0)?.diff(not_after)?;
+        let expires_in_days = expires_in.days as i64;
+        let warning = if expires_in.days < 0 || (expires_in.days == 0 && expires_in.secs <= 0) {
+            Some(format!("The mitmproxy CA certificate expired on {}; delete it from {} to generate a new one", not_after, self.cert_dir.display()))
+        } else if expires_in_days < self.validity.ca_warning_days as i64 {
+            Some(format!("The mitmproxy CA certificate expires in {} days, on {}", expires_in_days, not_after))
+        } else {
+            None
+        };
+        Ok(CaStatus {
+            not_before: parse_asn1_time_to_timestamp(self.cert.not_before()),
+            not_after: parse_asn1_time_to_timestamp(not_after),
+            expires_in_days,
+            warning,
+        })
+    }
+
+    /// Days a leaf certificate for `hostname` is valid
+    fn leaf_days(&self, hostname: &str) -> u32 {
+        self.validity_rules
+            .iter()
+            .find(|(filter, _)| filter.matches_address(hostname, 443))
+            .map_or(self.validity.leaf_days, |(_, days)| *days)
+    }
+
     pub async fn get_cert_for_host(&self, hostname: &str) -> Result<(X509, PKey<Private>)> {
+        let name = self.name_key(hostname);
+        let cache_key = self.cache_key(hostname);
+        if let Some(domain) = name.strip_prefix("san:") {
+            return self.get_multi_san_cert(domain, &cache_key, hostname).await;
+        }
+
         // Check cache first
         {
             let cache = self.cert_cache.read().await;
-            if let Some((cert, key)) = cache.get(hostname) {
+            if let Some((cert, key)) = cache.get(&cache_key) {
                 return Ok((cert.clone(), key.clone()));
             }
         }
 
-        // Generate new certificate
-        let (cert, key) = self.generate_host_cert(hostname)?;
+        // Then the disk cache, generating a new certificate if it is missing
+        let (cert, key) = match self.load_leaf(&cache_key) {
+            Some(leaf) => leaf,
+            None => {
+                let leaf = match name.strip_prefix("*.") {
+                    Some(base) => self.generate_cert(base, &host_sans(base))?,
+                    None => self.generate_host_cert(hostname)?,
+                };
+                self.store_leaf(&cache_key, &leaf);
+                leaf
+            }
+        };
 
         // Cache the certificate
         {
             let mut cache = self.cert_cache.write().await;
-            cache.insert(hostname.to_string(), (cert.clone(), key.clone()));
+            cache.insert(cache_key, (cert.clone(), key.clone()));
+        }
+
+        Ok((cert, key))
+    }
+
+    /// Certificate for the registrable domain `domain` that lists
+    /// `hostname`, reissued with `hostname` added if it is new
+    async fn get_multi_san_cert(&self, domain: &str, cache_key: &str, hostname: &str) -> Result<(X509, PKey<Private>)> {
+        let mut san_hosts = self.san_hosts.write().await;
+        if !san_hosts.contains_key(domain) {
+            // Pick up the hosts of a certificate from a previous run
+            if let Some((cert, key)) = self.load_leaf(cache_key) {
+                let hosts = cert.subject_alt_names().into_iter().flatten();
+                san_hosts.insert(domain.to_string(), hosts.filter_map(|name| name.dnsname().map(str::to_string)).collect());
+                self.cert_cache.write().await.insert(cache_key.to_string(), (cert, key));
+            }
+        }
+        let hosts = san_hosts.entry(domain.to_string()).or_default();
+        if hosts.iter().any(|host| host == hostname) {
+            if let Some((cert, key)) = self.cert_cache.read().await.get(cache_key) {
+                return Ok((cert.clone(), key.clone()));
+            }
+        } else {
+            hosts.push(hostname.to_string());
+            if hosts.len() > self.minting.max_sans.max(1) {
+                hosts.remove(0);
+            }
+        }
+
+        let (cert, key) = self.generate_cert(domain, hosts)?;
+        self.store_leaf(cache_key, &(cert.clone(), key.clone()));
+        self.cert_cache.write().await.insert(cache_key.to_string(), (cert.clone(), key.clone()));
+        Ok((cert, key))
+    }
+
+    /// Certificate cached on disk under `cache_key`, if it was issued by
+    /// this CA and is valid for at least another day
+    fn load_leaf(&self, cache_key: &str) -> Option<(X509, PKey<Private>)> {
+        let disk_cache = self.disk_cache.as_ref()?;
+        let (cert, key) = disk_cache.load(cache_key)?;
+        if self.usable(&cert) {
+            Some((cert, key))
+        } else {
+            disk_cache.remove(cache_key);
+            None
+        }
+    }
+
+    /// Whether a cached certificate was issued by this CA and is valid for
+    /// at least another day
+    fn usable(&self, cert: &X509) -> bool {
+        let issued_by_ca = self.cert.public_key().and_then(|ca_key| cert.verify(&ca_key)).unwrap_or(false);
+        let valid = Asn1Time::days_from_now(1)
+            .and_then(|tomorrow| cert.not_after().compare(&tomorrow))
+            .is_ok_and(|ordering| ordering.is_gt());
+        issued_by_ca && valid
+    }
+
+    fn store_leaf(&self, cache_key: &str, (cert, key): &(X509, PKey<Private>)) {
+        if let Some(disk_cache) = &self.disk_cache {
+            if let Err(e) = disk_cache.store(cache_key, cert, key) {
+                warn!("Cannot cache certificate for {} on disk: {}", cache_key, e);
+            }
+        }
+    }
+
+    /// Certificate for `hostname` that clients must reject, for testing
+    /// their certificate validation and pinning. See [`crate::pinning`].
+    pub async fn get_pinning_test_cert(&self, hostname: &str, mode: PinningTestMode) -> Result<(X509, PKey<Private>)> {
+        let cache_key = format!("{}#{}", hostname, mode);
+        if let Some((cert, key)) = self.cert_cache.read().await.get(&cache_key) {
+            return Ok((cert.clone(), key.clone()));
         }
 
+        let (cert, key) = match mode {
+            PinningTestMode::SelfSigned => {
+                let days = self.leaf_days(hostname);
+                let sans = host_sans(hostname);
+                Self::generate_leaf_cert(hostname, &sans, None, self.minting.key, self.validity.backdate_hours, days, &*self.ids)?
+            }
+            PinningTestMode::WrongHost => self.generate_host_cert(WRONG_HOST)?,
+            PinningTestMode::UntrustedCa => {
+                let (ca_cert, ca_key) =
+                    Self::generate_ca_cert_named("mitmproxy pinning test (untrusted)", &self.validity, &*self.ids)?;
+                let days = self.leaf_days(hostname);
+                let sans = host_sans(hostname);
+                let issuer = Some((&*ca_cert, &ca_key));
+                Self::generate_leaf_cert(hostname, &sans, issuer, self.minting.key, self.validity.backdate_hours, days, &*self.ids)?
+            }
+        };
+
+        self.cert_cache.write().await.insert(cache_key, (cert.clone(), key.clone()));
         Ok((cert, key))
     }
 
-    fn generate_ca_cert() -> Result<(X509, PKey<Private>)> {
+    /// Leaf certificate for `hostname` issued with rcgen for the rustls
+    /// layers, as DER certificate and PKCS#8 key. The ECDSA P-256 key is
+    /// cheap enough to create per connection, so nothing is cached.
+    #[cfg(feature = "rustls-backend")]
+    pub fn get_rcgen_cert_for_host(&self, hostname: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+        use rcgen::{
+            BasicConstraints as RcgenBasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+            ExtendedKeyUsagePurpose, IsCa, KeyPair,
+        };
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        // The issuer is rebuilt from the CA's subject and key; leaves carry
+        // no authority key identifier since rcgen derives it differently
+        let mut issuer_name = DistinguishedName::new();
+        for entry in self.cert.subject_name().entries() {
+            let dn_type = match entry.object().nid() {
+                Nid::COMMONNAME => DnType::CommonName,
+                Nid::ORGANIZATIONNAME => DnType::OrganizationName,
+                Nid::ORGANIZATIONALUNITNAME => DnType::OrganizationalUnitName,
+                Nid::COUNTRYNAME => DnType::CountryName,
+                Nid::STATEORPROVINCENAME => DnType::StateOrProvinceName,
+                Nid::LOCALITYNAME => DnType::LocalityName,
+                nid => return Err(Error::certificate(format!("Unsupported CA subject attribute {:?}", nid))),
+            };
+            issuer_name.push(dn_type, entry.data().as_utf8()?.to_string());
+        }
+        let key_pair = KeyPair::from_der(&self.key.private_key_to_pkcs8()?).map_err(Error::certificate)?;
+        let mut issuer_params = CertificateParams::default();
+        issuer_params.alg = key_pair.algorithm();
+        issuer_params.key_pair = Some(key_pair);
+        issuer_params.distinguished_name = issuer_name;
+        issuer_params.is_ca = IsCa::Ca(RcgenBasicConstraints::Unconstrained);
+        let issuer = Certificate::from_params(issuer_params).map_err(Error::certificate)?;
+
+        let mut params = CertificateParams::new(host_sans(hostname));
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, hostname);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let now = rcgen::date_time_ymd(1970, 1, 1) + SystemTime::now().duration_since(UNIX_EPOCH).map_err(Error::internal)?;
+        params.not_before = now - Duration::from_secs(self.validity.backdate_hours as u64 * 3600);
+        params.not_after = now + Duration::from_secs(self.leaf_days(hostname) as u64 * 86400);
+        let leaf = Certificate::from_params(params).map_err(Error::certificate)?;
+        let cert = leaf.serialize_der_with_signer(&issuer).map_err(Error::certificate)?;
+        Ok((cert, leaf.serialize_private_key_der()))
+    }
+
+    fn generate_ca_cert(validity: &CertValidityOptions) -> Result<(X509, PKey<Private>)> {
+        Self::generate_ca_cert_named("mitmproxy", validity, &*crate::clock::ids())
+    }
+
+    fn generate_ca_cert_named(
+        common_name: &str,
+        validity: &CertValidityOptions,
+        ids: &dyn IdGenerator,
+    ) -> Result<(X509, PKey<Private>)> {
         // Generate RSA key pair
         let rsa = Rsa::generate(2048)?;
         let key = PKey::from_rsa(rsa)?;
@@ -87,22 +542,18 @@ impl CertificateAuthority {
         cert_builder.set_version(2)?;
 
         // Set serial number
-        let serial_number = {
-            let mut serial = BigNum::new()?;
-            serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
-            serial.to_asn1_integer()?
-        };
+        let serial_number = serial_number(ids)?;
         cert_builder.set_serial_number(&serial_number)?;
 
-        // Set validity period (10 years)
-        let not_before = Asn1Time::days_from_now(0)?;
-        let not_after = Asn1Time::days_from_now(365 * 10)?;
+        // Set validity period
+        let not_before = backdated_now(validity.backdate_hours)?;
+        let not_after = Asn1Time::days_from_now(validity.ca_days)?;
         cert_builder.set_not_before(&not_before)?;
         cert_builder.set_not_after(&not_after)?;
 
         // Set subject and issuer
         let mut name_builder = X509NameBuilder::new()?;
-        name_builder.append_entry_by_nid(Nid::COMMONNAME, "mitmproxy")?;
+        name_builder.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
         name_builder.append_entry_by_nid(Nid::ORGANIZATIONNAME, "mitmproxy")?;
         let name = name_builder.build();
 
@@ -133,27 +584,49 @@ impl CertificateAuthority {
     }
 
     fn generate_host_cert(&self, hostname: &str) -> Result<(X509, PKey<Private>)> {
-        // Generate RSA key pair
-        let rsa = Rsa::generate(2048)?;
-        let key = PKey::from_rsa(rsa)?;
+        self.generate_cert(hostname, &host_sans(hostname))
+    }
+
+    /// Generate a certificate named `common_name` for the DNS names `sans`,
+    /// signed by the CA
+    fn generate_cert(&self, common_name: &str, sans: &[String]) -> Result<(X509, PKey<Private>)> {
+        let days = self.leaf_days(common_name);
+        let issuer = Some((&*self.cert, &self.key));
+        Self::generate_leaf_cert(common_name, sans, issuer, self.minting.key, self.validity.backdate_hours, days, &*self.ids)
+    }
+
+    /// Generate a certificate named `hostname` for the DNS names `sans`
+    /// with a new key of `key_type`, signed by `issuer`, or self-signed if
+    /// there is none, valid from `backdate_hours` ago for `days` from now
+    fn generate_leaf_cert(
+        hostname: &str,
+        sans: &[String],
+        issuer: Option<(&X509Ref, &PKey<Private>)>,
+        key_type: LeafKeyType,
+        backdate_hours: u32,
+        days: u32,
+        ids: &dyn IdGenerator,
+    ) -> Result<(X509, PKey<Private>)> {
+        let key = key_type.generate()?;
 
         // Create certificate
         let mut cert_builder = X509Builder::new()?;
         cert_builder.set_version(2)?;
 
         // Set serial number
-        let serial_number = {
-            let mut serial = BigNum::new()?;
-            serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
-            serial.to_asn1_integer()?
-        };
+        let serial_number = serial_number(ids)?;
         cert_builder.set_serial_number(&serial_number)?;
 
-        // Set validity period (1 year)
-        let not_before = Asn1Time::days_from_now(0)?;
-        let not_after = Asn1Time::days_from_now(365)?;
+        // Set validity period, never past the issuer's own
+        let not_before = backdated_now(backdate_hours)?;
+        let not_after = Asn1Time::days_from_now(days)?;
         cert_builder.set_not_before(&not_before)?;
-        cert_builder.set_not_after(&not_after)?;
+        match issuer {
+            Some((cert, _)) if cert.not_after().compare(&not_after)?.is_lt() => {
+                cert_builder.set_not_after(cert.not_after())?
+            }
+            _ => cert_builder.set_not_after(&not_after)?,
+        }
 
         // Set subject
         let mut name_builder = X509NameBuilder::new()?;
@@ -162,7 +635,8 @@ impl CertificateAuthority {
         cert_builder.set_subject_name(&subject_name)?;
 
         // Set issuer to CA
-        cert_builder.set_issuer_name(self.cert.subject_name())?;
+        let issuer_cert = issuer.map(|(cert, _)| cert);
+        cert_builder.set_issuer_name(issuer_cert.map_or(&*subject_name, |cert| cert.subject_name()))?;
 
         // Set public key
         cert_builder.set_pubkey(&key)?;
@@ -170,62 +644,71 @@ impl CertificateAuthority {
         // Add extensions
         cert_builder.append_extension(BasicConstraints::new().build()?)?;
 
-        cert_builder.append_extension(
-            KeyUsage::new()
-                .critical()
-                .non_repudiation()
-                .digital_signature()
-                .key_encipherment()
-                .build()?,
-        )?;
+        // Only RSA keys can encipher the key exchange
+        let mut key_usage = KeyUsage::new();
+        key_usage.critical().non_repudiation().digital_signature();
+        if matches!(key_type, LeafKeyType::Rsa { .. }) {
+            key_usage.key_encipherment();
+        }
+        cert_builder.append_extension(key_usage.build()?)?;
 
         let subject_key_identifier =
-            SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(Some(&self.cert), None))?;
+            SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(issuer_cert, None))?;
         cert_builder.append_extension(subject_key_identifier)?;
 
-        let authority_key_identifier = AuthorityKeyIdentifier::new()
-            .keyid(false)
-            .issuer(false)
-            .build(&cert_builder.x509v3_context(Some(&self.cert), None))?;
-        cert_builder.append_extension(authority_key_identifier)?;
+        if issuer_cert.is_some() {
+            let authority_key_identifier = AuthorityKeyIdentifier::new()
+                .keyid(false)
+                .issuer(false)
+                .build(&cert_builder.x509v3_context(issuer_cert, None))?;
+            cert_builder.append_extension(authority_key_identifier)?;
+        }
 
-        // Add Subject Alternative Name
+        // Add Subject Alternative Name; clients ignore the common name
         let mut san_builder = SubjectAlternativeName::new();
-        san_builder.dns(hostname);
-
-        // Also add wildcard version if not already wildcard
-        if !hostname.starts_with("*.") {
-            san_builder.dns(&format!("*.{}", hostname));
+        for san in sans {
+            match san.parse::<std::net::IpAddr>() {
+                Ok(_) => san_builder.ip(san),
+                Err(_) => san_builder.dns(san),
+            };
         }
 
-        let san = san_builder.build(&cert_builder.x509v3_context(Some(&self.cert), None))?;
+        let san = san_builder.build(&cert_builder.x509v3_context(issuer_cert, None))?;
         cert_builder.append_extension(san)?;
 
         // Sign the certificate with CA key
-        cert_builder.sign(&self.key, MessageDigest::sha256())?;
+        cert_builder.sign(issuer.map_or(&key, |(_, key)| key), MessageDigest::sha256())?;
 
         Ok((cert_builder.build(), key))
     }
 
-    fn load_ca_cert(_cert_path: &Path, _key_path: &Path) -> Result<(X509, PKey<Private>)> {
-        // For simplicity, we'll just regenerate if loading fails
-        // In a real implementation, you'd want to properly load the existing CA
-        Self::generate_ca_cert()
+    /// Load the CA saved by a previous run. `key_path` holds the key
+    /// followed by the certificate, as in mitmproxy's `mitmproxy-ca.pem`.
+    fn load_ca_cert(_cert_path: &Path, key_path: &Path, validity: &CertValidityOptions) -> Result<(X509, PKey<Private>)> {
+        let pem = fs::read(key_path)?;
+        match (PKey::private_key_from_pem(&pem), X509::from_pem(&pem)) {
+            (Ok(key), Ok(cert)) => Ok((cert, key)),
+            _ => {
+                warn!("Cannot read the CA from {}, generating a new one", key_path.display());
+                Self::generate_ca_cert(validity)
+            }
+        }
     }
 
     fn save_ca_cert(
         cert: &X509,
-        _key: &PKey<Private>,
+        key: &PKey<Private>,
         cert_path: &Path,
-        _key_path: &Path,
+        key_path: &Path,
     ) -> Result<()> {
         // Save certificate in PEM format
         let cert_pem = cert.to_pem()?;
-        fs::write(cert_path, cert_pem)?;
+        fs::write(cert_path, &cert_pem)?;
 
-        // In a real implementation, you'd save the private key as well
-        // For now, we'll regenerate on each startup
-        Ok(())
+        // The key only goes into the file readable by the owner alone
+        let mut key_pem = key.private_key_to_pem_pkcs8()?;
+        key_pem.extend_from_slice(&cert_pem);
+        write_private(key_path, &key_pem)
     }
 
     pub fn ca_cert_pem(&self) -> Result<Vec<u8>> {
@@ -236,9 +719,20 @@ impl CertificateAuthority {
         Ok(self.cert.to_der()?)
     }
 
+    /// SHA-256 fingerprint of the CA certificate, as colon-separated hex
+    /// like browsers show it
+    pub fn ca_fingerprint(&self) -> Result<String> {
+        let digest = self.cert.digest(MessageDigest::sha256())?;
+        Ok(digest.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":"))
+    }
+
     pub async fn clear_cache(&self) {
         let mut cache = self.cert_cache.write().await;
         cache.clear();
+        self.san_hosts.write().await.clear();
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.clear();
+        }
     }
 
     pub async fn cache_size(&self) -> usize {
@@ -272,6 +766,8 @@ pub fn cert_to_info(cert: &X509) -> Result<crate::flow::Certificate> {
         for san in sans.iter() {
             if let Some(dns) = san.dnsname() {
                 altnames.push(dns.to_string());
+            } else if let Some(ip) = san.ipaddress().and_then(ip_from_bytes) {
+                altnames.push(ip.to_string());
             }
         }
     }
@@ -288,23 +784,270 @@ pub fn cert_to_info(cert: &X509) -> Result<crate::flow::Certificate> {
     })
 }
 
+/// Address of an IP address SAN, given as 4 or 16 bytes
+fn ip_from_bytes(bytes: &[u8]) -> Option<std::net::IpAddr> {
+    match bytes.len() {
+        4 => Some(std::net::IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(std::net::IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
 /// Parse ASN1 time to Unix timestamp
-fn parse_asn1_time_to_timestamp(time: &openssl::asn1::Asn1TimeRef) -> i64 {
-    // ASN1 time format: YYMMDDhhmmssZ or YYYYMMDDhhmmssZ
-    // Use the to_string() method and parse the result
-    let time_str = format!("{}", time);
-
-    // Try to parse the time string - if parsing fails, return 0
-    // In a real implementation, you'd use chrono or time crate for proper parsing
-    if time_str.len() >= 12 {
-        // Very basic timestamp approximation - in production you'd want proper parsing
-        // For now, just return 0 as a placeholder
-        0
+fn parse_asn1_time_to_timestamp(time: &Asn1TimeRef) -> i64 {
+    Asn1Time::from_unix(0)
+        .and_then(|epoch| epoch.diff(time))
+        .map_or(0, |diff| diff.days as i64 * 24 * 60 * 60 + diff.secs as i64)
+}
+
+/// Where a cached certificate is stored and when it was last used
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DiskEntry {
+    file: String,
+    /// UNIX timestamp in microseconds, unique within the index
+    last_used: i64,
+}
+
+/// Leaf certificates and their keys kept as one PEM file per cache key,
+/// with an index recording when each was last used
+#[derive(Debug)]
+struct DiskCache {
+    dir: PathBuf,
+    max_entries: usize,
+    index: std::sync::Mutex<HashMap<String, DiskEntry>>,
+}
+
+impl DiskCache {
+    const INDEX_FILE: &'static str = "index.json";
+
+    fn open(dir: PathBuf, max_entries: usize) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let index = match fs::read(dir.join(Self::INDEX_FILE)) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring corrupt certificate cache index in {}: {}", dir.display(), e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { dir, max_entries, index: std::sync::Mutex::new(index) })
+    }
+
+    fn load(&self, cache_key: &str) -> Option<(X509, PKey<Private>)> {
+        let mut index = self.index.lock().unwrap();
+        match self.read(&index.get(cache_key)?.file) {
+            Some(leaf) => {
+                let stamp = Self::next_stamp(&index);
+                index.get_mut(cache_key).expect("entry exists").last_used = stamp;
+                self.save_index(&index);
+                Some(leaf)
+            }
+            None => {
+                drop(index);
+                self.remove(cache_key);
+                None
+            }
+        }
+    }
+
+    fn read(&self, file: &str) -> Option<(X509, PKey<Private>)> {
+        let pem = fs::read(self.dir.join(file)).ok()?;
+        Some((X509::from_pem(&pem).ok()?, PKey::private_key_from_pem(&pem).ok()?))
+    }
+
+    /// Delete the certificates that are unreadable or not `usable`, and
+    /// return the `preload` most recently used of the others by cache key
+    fn sweep(&self, usable: impl Fn(&X509) -> bool, preload: usize) -> Vec<(String, (X509, PKey<Private>))> {
+        let mut index = self.index.lock().unwrap();
+        let mut entries: Vec<(String, DiskEntry)> = index.iter().map(|(k, e)| (k.clone(), e.clone())).collect();
+        entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.last_used));
+
+        let mut preloaded = Vec::new();
+        let mut removed = 0;
+        for (cache_key, entry) in entries {
+            match self.read(&entry.file).filter(|(cert, _)| usable(cert)) {
+                Some(leaf) => {
+                    if preloaded.len() < preload {
+                        preloaded.push((cache_key, leaf));
+                    }
+                }
+                None => {
+                    index.remove(&cache_key);
+                    fs::remove_file(self.dir.join(entry.file)).ok();
+                    removed += 1;
+                }
+            }
+        }
+        if removed > 0 {
+            debug!("Deleted {} expired or foreign certificates from {}", removed, self.dir.display());
+            self.save_index(&index);
+        }
+        preloaded
+    }
+
+    fn store(&self, cache_key: &str, cert: &X509, key: &PKey<Private>) -> Result<()> {
+        let file = Self::file_name(cache_key);
+        let mut pem = key.private_key_to_pem_pkcs8()?;
+        pem.extend_from_slice(&cert.to_pem()?);
+        write_private(&self.dir.join(&file), &pem)?;
+
+        let mut index = self.index.lock().unwrap();
+        let last_used = Self::next_stamp(&index);
+        index.insert(cache_key.to_string(), DiskEntry { file, last_used });
+        while index.len() > self.max_entries.max(1) {
+            let Some(oldest) = index.iter().min_by_key(|(_, entry)| entry.last_used).map(|(k, _)| k.clone()) else {
+                break;
+            };
+            if let Some(entry) = index.remove(&oldest) {
+                fs::remove_file(self.dir.join(entry.file)).ok();
+            }
+        }
+        self.save_index(&index);
+        Ok(())
+    }
+
+    fn remove(&self, cache_key: &str) {
+        let mut index = self.index.lock().unwrap();
+        if let Some(entry) = index.remove(cache_key) {
+            fs::remove_file(self.dir.join(entry.file)).ok();
+            self.save_index(&index);
+        }
+    }
+
+    fn clear(&self) {
+        let mut index = self.index.lock().unwrap();
+        for (_, entry) in index.drain() {
+            fs::remove_file(self.dir.join(entry.file)).ok();
+        }
+        self.save_index(&index);
+    }
+
+    /// Current time, moved past the latest use so that uses stay ordered
+    fn next_stamp(index: &HashMap<String, DiskEntry>) -> i64 {
+        let latest = index.values().map(|entry| entry.last_used).max().unwrap_or(0);
+        chrono::Utc::now().timestamp_micros().max(latest + 1)
+    }
+
+    fn save_index(&self, index: &HashMap<String, DiskEntry>) {
+        let path = self.dir.join(Self::INDEX_FILE);
+        let tmp = path.with_extension("json.tmp");
+        let written = serde_json::to_vec(index)
+            .map_err(std::io::Error::from)
+            .and_then(|data| fs::write(&tmp, data))
+            .and_then(|()| fs::rename(&tmp, &path));
+        if let Err(e) = written {
+            warn!("Cannot write certificate cache index {}: {}", path.display(), e);
+        }
+    }
+
+    /// Readable file name for a cache key, made unique by a hash since keys
+    /// may contain characters not allowed in file names
+    fn file_name(cache_key: &str) -> String {
+        let readable: String = cache_key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c.to_ascii_lowercase() } else { '_' })
+            .take(64)
+            .collect();
+        let hash = crate::flow::content_hash(cache_key.as_bytes());
+        format!("{}-{}.pem", readable, &hash[..8])
+    }
+}
+
+/// Read a CA key and certificate from a PEM or PKCS#12 file, or from the
+/// `mitmproxy-ca.pem` or `mitmproxy-ca.p12` in a directory
+fn read_ca_file(path: &Path, passphrase: Option<&str>) -> Result<(X509, PKey<Private>)> {
+    if path.is_dir() {
+        let file = ["mitmproxy-ca.pem", "mitmproxy-ca.p12"].iter().map(|name| path.join(name)).find(|file| file.exists());
+        return match file {
+            Some(file) => read_ca_file(&file, passphrase),
+            None => Err(Error::certificate(format!("No mitmproxy-ca.pem or mitmproxy-ca.p12 in {}", path.display()))),
+        };
+    }
+    let data = fs::read(path)?;
+    let invalid = |reason: &str| Error::certificate(format!("Cannot import the CA from {}: {}", path.display(), reason));
+    let (cert, key) = if data.trim_ascii_start().starts_with(b"-----BEGIN") {
+        let key = PKey::private_key_from_pem(&data).map_err(|_| invalid("no private key"))?;
+        let cert = X509::from_pem(&data).map_err(|_| invalid("no certificate"))?;
+        (cert, key)
     } else {
-        0
+        let parsed = openssl::pkcs12::Pkcs12::from_der(&data)
+            .and_then(|pkcs12| pkcs12.parse2(passphrase.unwrap_or("")))
+            .map_err(|e| invalid(&format!("not a readable PKCS#12 bundle ({})", e)))?;
+        (parsed.cert.ok_or_else(|| invalid("no certificate"))?, parsed.pkey.ok_or_else(|| invalid("no private key"))?)
+    };
+    if !cert.public_key()?.public_eq(&key) {
+        return Err(invalid("the private key does not belong to the certificate"));
+    }
+    Ok((cert, key))
+}
+
+/// Write a file holding a private key or other secret, readable by the
+/// owner only
+pub fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    use std::io::Write;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)?;
+    Ok(())
+}
+
+/// Names of a certificate for `hostname` alone: the name itself, the
+/// wildcard covering its siblings and, unless it is a wildcard already, the
+/// wildcard of its subdomains. An IP address is its only name.
+fn host_sans(hostname: &str) -> Vec<String> {
+    let hostname = hostname.trim_start_matches('[').trim_end_matches(']');
+    if hostname.parse::<std::net::IpAddr>().is_ok() || hostname.starts_with("*.") {
+        return vec![hostname.to_string()];
+    }
+    let mut sans = vec![hostname.to_string()];
+    for wildcard in [format!("*.{}", wildcard_base(hostname)), format!("*.{}", hostname)] {
+        if !sans.contains(&wildcard) {
+            sans.push(wildcard);
+        }
+    }
+    sans
+}
+
+/// The domain under which `hostname` was registered, i.e. its eTLD+1,
+/// e.g. `example.co.uk` for `www.example.co.uk`
+fn registrable_domain(hostname: &str) -> &str {
+    let labels: Vec<&str> = hostname.rsplitn(4, '.').collect();
+    let count = match labels.as_slice() {
+        [tld, second, _, ..] if tld.len() == 2 && COUNTRY_SECOND_LEVEL.contains(second) => 3,
+        _ => 2,
+    };
+    match hostname.rmatch_indices('.').nth(count - 1) {
+        Some((i, _)) => &hostname[i + 1..],
+        None => hostname,
     }
 }
 
+/// Domain whose wildcard covers `hostname`: its parent, unless that is a
+/// public suffix
+fn wildcard_base(hostname: &str) -> &str {
+    let registrable = registrable_domain(hostname);
+    match hostname.split_once('.') {
+        Some((_, parent)) if hostname.len() > registrable.len() => parent,
+        _ => hostname,
+    }
+}
+
+/// Random positive 159-bit serial number drawn from `ids`
+fn serial_number(ids: &dyn IdGenerator) -> Result<Asn1Integer> {
+    let mut bytes = [0u8; 20];
+    ids.fill_random(&mut bytes);
+    bytes[0] &= 0x7f;
+    Ok(BigNum::from_slice(&bytes)?.to_asn1_integer()?)
+}
+
+/// The current time moved `hours` into the past
+fn backdated_now(hours: u32) -> Result<Asn1Time> {
+    let now = chrono::Utc::now().timestamp();
+    Ok(Asn1Time::from_unix(now - hours as i64 * 60 * 60)?)
+}
+
 fn extract_name_entries(name: &openssl::x509::X509NameRef) -> indexmap::IndexMap<String, String> {
     let mut entries = indexmap::IndexMap::new();
 
@@ -359,6 +1102,45 @@ mod tests {
         assert_eq!(ca.cache_size().await, 2);
     }
 
+    #[tokio::test]
+    async fn test_serials_from_ids() {
+        use crate::clock::SeededIds;
+        let temp_dir = TempDir::new().unwrap();
+        let seeded = || CertificateAuthority::new(temp_dir.path()).unwrap().with_ids(Arc::new(SeededIds::new(3)));
+        let (first, second) = (seeded(), seeded());
+        let serial = |cert: X509| cert.serial_number().to_bn().unwrap();
+
+        let a = serial(first.get_cert_for_host("example.com").await.unwrap().0);
+        let b = serial(second.get_cert_for_host("example.com").await.unwrap().0);
+        assert_eq!(a, b);
+        assert!(a.num_bits() <= 159);
+        assert_ne!(a, serial(first.get_cert_for_host("example.org").await.unwrap().0));
+    }
+
+    #[tokio::test]
+    async fn test_pinning_test_certs() {
+        let temp_dir = TempDir::new().unwrap();
+        let ca = CertificateAuthority::new(temp_dir.path()).unwrap();
+        let issued_by_ca = |cert: &X509| cert.verify(&ca.cert.public_key().unwrap()).unwrap();
+
+        let (cert, key) = ca.get_pinning_test_cert("app.example", PinningTestMode::SelfSigned).await.unwrap();
+        assert!(cert.verify(&key).unwrap());
+        assert!(!issued_by_ca(&cert));
+        assert_eq!(cert_to_info(&cert).unwrap().altnames[0], "app.example");
+
+        let (cert, _) = ca.get_pinning_test_cert("app.example", PinningTestMode::WrongHost).await.unwrap();
+        assert!(issued_by_ca(&cert));
+        assert_eq!(cert_to_info(&cert).unwrap().altnames[0], WRONG_HOST);
+
+        let (cert, _) = ca.get_pinning_test_cert("app.example", PinningTestMode::UntrustedCa).await.unwrap();
+        assert!(!issued_by_ca(&cert));
+        assert_ne!(cert.issuer_name().to_der().unwrap(), ca.cert.subject_name().to_der().unwrap());
+
+        // Test certificates never replace the regular one
+        let (regular, _) = ca.get_cert_for_host("app.example").await.unwrap();
+        assert!(issued_by_ca(&regular));
+    }
+
     #[test]
     fn test_cert_info_extraction() {
         let temp_dir = TempDir::new().unwrap();
@@ -369,4 +1151,219 @@ mod tests {
         assert!(!cert_info.serial.is_empty());
         assert!(cert_info.subject.contains_key("CN"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_validity_windows() {
+        let temp_dir = TempDir::new().unwrap();
+        let validity = CertValidityOptions {
+            ca_days: 20,
+            rules: vec![CertValidityRule { filter: r"~d \.internal$".to_string(), days: 7 }],
+            ..Default::default()
+        };
+        let ca = CertificateAuthority::with_validity(temp_dir.path(), &validity).unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let days = |n: i64| n * 24 * 60 * 60;
+
+        let (cert, _) = ca.get_cert_for_host("db.internal").await.unwrap();
+        let not_before = parse_asn1_time_to_timestamp(cert.not_before());
+        assert!((not_before - (now - days(2))).abs() < 60);
+        assert!((parse_asn1_time_to_timestamp(cert.not_after()) - (now + days(7))).abs() < 60);
+
+        // Leaf certificates never outlive the CA
+        let (cert, _) = ca.get_cert_for_host("example.com").await.unwrap();
+        assert_eq!(cert.not_after().compare(ca.cert.not_after()).unwrap(), std::cmp::Ordering::Equal);
+
+        let status = ca.status().unwrap();
+        // 19 once the CA is a second old
+        assert!((19..=20).contains(&status.expires_in_days));
+        assert!(status.warning.unwrap().contains(&format!("expires in {} days", status.expires_in_days)));
+        assert!(CertificateAuthority::new(TempDir::new().unwrap().path()).unwrap().status().unwrap().warning.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_minting_strategies() {
+        assert_eq!(registrable_domain("a.b.example.com"), "example.com");
+        assert_eq!(registrable_domain("www.example.co.uk"), "example.co.uk");
+        assert_eq!(registrable_domain("localhost"), "localhost");
+        assert_eq!(wildcard_base("example.com"), "example.com");
+        assert_eq!(wildcard_base("a.b.example.com"), "b.example.com");
+
+        let temp_dir = TempDir::new().unwrap();
+        let minting = |strategy| CertMintingOptions { strategy, max_sans: 2, ..Default::default() };
+        let ca = CertificateAuthority::new(temp_dir.path()).unwrap().with_minting(&minting(MintingStrategy::Wildcard));
+        let (www, _) = ca.get_cert_for_host("www.example.com").await.unwrap();
+        let (api, _) = ca.get_cert_for_host("api.example.com").await.unwrap();
+        assert_eq!(www.to_der().unwrap(), api.to_der().unwrap());
+        assert_eq!(cert_to_info(&www).unwrap().altnames, ["example.com", "*.example.com"]);
+        ca.get_cert_for_host("10.0.0.1").await.unwrap();
+        assert_eq!(ca.cache_size().await, 2);
+
+        let ca = CertificateAuthority::new(temp_dir.path()).unwrap().with_minting(&minting(MintingStrategy::MultiSan));
+        ca.get_cert_for_host("a.example.com").await.unwrap();
+        let (cert, _) = ca.get_cert_for_host("b.example.com").await.unwrap();
+        assert_eq!(cert_to_info(&cert).unwrap().altnames, ["a.example.com", "b.example.com"]);
+        let (again, _) = ca.get_cert_for_host("a.example.com").await.unwrap();
+        assert_eq!(cert.to_der().unwrap(), again.to_der().unwrap());
+        // The least recently added host makes room for a new one
+        let (cert, _) = ca.get_cert_for_host("c.example.com").await.unwrap();
+        assert_eq!(cert_to_info(&cert).unwrap().altnames, ["b.example.com", "c.example.com"]);
+        assert_eq!(ca.cache_size().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = CertDiskCacheOptions { enabled: true, max_entries: 2, preload: 0 };
+        let open = || CertificateAuthority::new(temp_dir.path()).unwrap().with_disk_cache(&options);
+
+        let ca = open();
+        let (cert, _) = ca.get_cert_for_host("example.com").await.unwrap();
+        ca.get_cert_for_host("example.org").await.unwrap();
+
+        // A restart keeps the CA and loads the certificate from disk
+        let ca = open();
+        assert_eq!(ca.ca_cert_der().unwrap(), open().ca_cert_der().unwrap());
+        assert_eq!(ca.cache_size().await, 0);
+        let (loaded, _) = ca.get_cert_for_host("example.com").await.unwrap();
+        assert_eq!(loaded.to_der().unwrap(), cert.to_der().unwrap());
+
+        // The least recently used certificate is evicted
+        ca.get_cert_for_host("example.net").await.unwrap();
+        let index: HashMap<String, DiskEntry> =
+            serde_json::from_slice(&fs::read(temp_dir.path().join("leaves/index.json")).unwrap()).unwrap();
+        let mut keys: Vec<&String> = index.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["example.com", "example.net"]);
+        assert_eq!(fs::read_dir(temp_dir.path().join("leaves")).unwrap().count(), 3);
+
+        // Certificates of another CA are not used
+        fs::remove_file(temp_dir.path().join("mitmproxy-ca.pem")).unwrap();
+        let ca = open();
+        let (regenerated, _) = ca.get_cert_for_host("example.com").await.unwrap();
+        assert_ne!(regenerated.to_der().unwrap(), cert.to_der().unwrap());
+        assert!(regenerated.verify(&ca.cert.public_key().unwrap()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_import_ca() {
+        let source = TempDir::new().unwrap();
+        let original = CertificateAuthority::new(source.path()).unwrap();
+        let validity = CertValidityOptions::default();
+        let temp_dir = TempDir::new().unwrap();
+
+        // mitmproxy's PEM file, directly or found in its directory
+        let pem = CertificateAuthority::import(temp_dir.path(), source.path().join("mitmproxy-ca.pem"), None, &validity).unwrap();
+        assert_eq!(pem.ca_cert_der().unwrap(), original.ca_cert_der().unwrap());
+        let dir = CertificateAuthority::import(temp_dir.path(), source.path(), None, &validity).unwrap();
+        assert_eq!(dir.ca_cert_der().unwrap(), original.ca_cert_der().unwrap());
+        let (leaf, _) = dir.get_cert_for_host("example.com").await.unwrap();
+        assert!(leaf.verify(&original.cert.public_key().unwrap()).unwrap());
+
+        let p12 = openssl::pkcs12::Pkcs12::builder()
+            .name("mitmproxy")
+            .pkey(&original.key)
+            .cert(&original.cert)
+            .build2("secret")
+            .unwrap();
+        let p12_path = temp_dir.path().join("mitmproxy-ca.p12");
+        fs::write(&p12_path, p12.to_der().unwrap()).unwrap();
+        let imported = CertificateAuthority::import(temp_dir.path(), &p12_path, Some("secret"), &validity).unwrap();
+        assert_eq!(imported.ca_cert_der().unwrap(), original.ca_cert_der().unwrap());
+        assert!(CertificateAuthority::import(temp_dir.path(), &p12_path, None, &validity).is_err());
+
+        // A key that does not match the certificate is rejected
+        let other = TempDir::new().unwrap();
+        let other = CertificateAuthority::new(other.path()).unwrap();
+        let mut mismatched = other.key.private_key_to_pem_pkcs8().unwrap();
+        mismatched.extend_from_slice(&original.cert.to_pem().unwrap());
+        let mismatched_path = temp_dir.path().join("mismatched.pem");
+        fs::write(&mismatched_path, mismatched).unwrap();
+        assert!(CertificateAuthority::import(temp_dir.path(), &mismatched_path, None, &validity).is_err());
+        assert!(CertificateAuthority::import(temp_dir.path(), TempDir::new().unwrap().path(), None, &validity).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_startup_sweep() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = CertDiskCacheOptions { enabled: true, max_entries: 10, preload: 1 };
+        let open = || CertificateAuthority::new(temp_dir.path()).unwrap().with_disk_cache(&options);
+        let leaves = temp_dir.path().join("leaves");
+
+        let ca = open();
+        ca.get_cert_for_host("example.com").await.unwrap();
+        ca.get_cert_for_host("example.org").await.unwrap();
+        let (latest, _) = ca.get_cert_for_host("example.net").await.unwrap();
+        fs::write(leaves.join(DiskCache::file_name("example.org")), b"garbage").unwrap();
+
+        // The most recently used certificate is in memory right away, the
+        // unreadable one is gone
+        let ca = open();
+        assert_eq!(ca.cache_size().await, 1);
+        let cached = ca.cert_cache.read().await.get("example.net").map(|(cert, _)| cert.to_der().unwrap());
+        assert_eq!(cached, Some(latest.to_der().unwrap()));
+        assert_eq!(fs::read_dir(&leaves).unwrap().count(), 3);
+
+        // A new CA deletes every certificate of the old one at startup
+        fs::remove_file(temp_dir.path().join("mitmproxy-ca.pem")).unwrap();
+        let ca = open();
+        assert_eq!(ca.cache_size().await, 0);
+        assert_eq!(fs::read_dir(&leaves).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_leaf_key_types() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = CertDiskCacheOptions { enabled: true, max_entries: 10, preload: 0 };
+        let minting = |key| CertMintingOptions { key, ..Default::default() };
+
+        let ca = CertificateAuthority::new(temp_dir.path()).unwrap().with_disk_cache(&options);
+        let (rsa, key) = ca.get_cert_for_host("example.com").await.unwrap();
+        assert_eq!(key.rsa().unwrap().size() * 8, 2048);
+        assert_eq!(ca.cache_key("example.com"), "example.com");
+
+        let ecdsa_ca = CertificateAuthority::new(temp_dir.path())
+            .unwrap()
+            .with_disk_cache(&options)
+            .with_minting(&minting(LeafKeyType::EcdsaP256));
+        assert_eq!(ecdsa_ca.cache_key("example.com"), "example.com#ecdsa-p256");
+        // The RSA certificate cached on disk is not picked up
+        let (ecdsa, key) = ecdsa_ca.get_cert_for_host("example.com").await.unwrap();
+        assert_ne!(ecdsa.to_der().unwrap(), rsa.to_der().unwrap());
+        assert_eq!(key.ec_key().unwrap().group().curve_name(), Some(Nid::X9_62_PRIME256V1));
+        assert!(ecdsa.verify(&ecdsa_ca.cert.public_key().unwrap()).unwrap());
+
+        let ca = CertificateAuthority::new(temp_dir.path())
+            .unwrap()
+            .with_minting(&minting(LeafKeyType::Rsa { bits: 3072 }));
+        assert_eq!(ca.cache_key("example.com"), "example.com#rsa3072");
+        let (_, key) = ca.get_cert_for_host("example.com").await.unwrap();
+        assert_eq!(key.rsa().unwrap().size() * 8, 3072);
+
+        // Unsupported sizes fall back to the default
+        assert!(LeafKeyType::Rsa { bits: 1024 }.validate().is_err());
+        let ca = CertificateAuthority::new(temp_dir.path())
+            .unwrap()
+            .with_minting(&minting(LeafKeyType::Rsa { bits: 1024 }));
+        assert_eq!(ca.cache_key("example.com"), "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_host_cert_sans() {
+        assert_eq!(host_sans("www.example.com"), ["www.example.com", "*.example.com", "*.www.example.com"]);
+        assert_eq!(host_sans("example.co.uk"), ["example.co.uk", "*.example.co.uk"]);
+        assert_eq!(host_sans("[::1]"), ["::1"]);
+
+        let temp_dir = TempDir::new().unwrap();
+        let ca = CertificateAuthority::new(temp_dir.path()).unwrap();
+        let (cert, _) = ca.get_cert_for_host("api.example.com").await.unwrap();
+        assert_eq!(cert_to_info(&cert).unwrap().altnames, ["api.example.com", "*.example.com", "*.api.example.com"]);
+
+        let (cert, _) = ca.get_cert_for_host("10.0.0.1").await.unwrap();
+        let sans = cert.subject_alt_names().unwrap();
+        assert_eq!(sans.len(), 1);
+        assert!(sans.get(0).unwrap().dnsname().is_none());
+        assert_eq!(cert_to_info(&cert).unwrap().altnames, ["10.0.0.1"]);
+        let (cert, _) = ca.get_cert_for_host("2001:db8::1").await.unwrap();
+        assert_eq!(cert_to_info(&cert).unwrap().altnames, ["2001:db8::1"]);
+    }
+}
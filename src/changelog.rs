@@ -0,0 +1,185 @@
+//! Field-level record of proxy-side flow modifications.
+//!
+//! Whenever an addon, a proxy feature or an API edit changes a flow, the
+//! affected fields are compared before and after and each difference is
+//! appended to the flow's changelog together with its source. The original
+//! wire traffic can thus always be told apart from what the proxy changed.
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::flow::{content_hash, HTTPFlow};
+
+/// Bodies up to this size are recorded verbatim if they are text
+const MAX_INLINE_BODY: usize = 1024;
+
+/// A single field changed on a flow
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub timestamp: f64,
+    /// What made the change: `api`, `addon:<name>`, or a proxy feature
+    pub source: String,
+    /// Hook during which the change was made, if any
+    #[serde(default)]
+    pub hook: Option<String>,
+    /// Dotted field path, e.g. `request.headers.user-agent`
+    pub field: String,
+    pub from: Value,
+    pub to: Value,
+}
+
+/// Compare the wire-relevant fields of two versions of a flow
+pub fn diff(before: &HTTPFlow, after: &HTTPFlow) -> Vec<(String, Value, Value)> {
+    let mut changes = Vec::new();
+    let mut compare = |field: &str, from: Value, to: Value| {
+        if from != to {
+            changes.push((field.to_string(), from, to));
+        }
+    };
+
+    let (a, b) = (&before.request, &after.request);
+    compare("request.method", json!(a.method), json!(b.method));
+    compare("request.scheme", json!(a.scheme), json!(b.scheme));
+    compare("request.host", json!(a.host), json!(b.host));
+    compare("request.port", json!(a.port), json!(b.port));
+    compare("request.path", json!(a.path), json!(b.path));
+    compare("request.http_version", json!(a.http_version), json!(b.http_version));
+    for (name, from, to) in header_changes(&a.headers, &b.headers) {
+        compare(&format!("request.headers.{}", name), from, to);
+    }
+    compare("request.content", body_value(a.content.as_deref()), body_value(b.content.as_deref()));
+
+    match (&before.response, &after.response) {
+        (Some(a), Some(b)) => {
+            compare("response.status_code", json!(a.status_code), json!(b.status_code));
+            compare("response.reason", json!(a.reason), json!(b.reason));
+            for (name, from, to) in header_changes(&a.headers, &b.headers) {
+                compare(&format!("response.headers.{}", name), from, to);
+            }
+            compare("response.content", body_value(a.content.as_deref()), body_value(b.content.as_deref()));
+        }
+        (a, b) => compare("response", json!(a.as_ref().map(|r| r.status_code)), json!(b.as_ref().map(|r| r.status_code))),
+    }
+
+    compare("marked", json!(before.flow.marked), json!(after.flow.marked));
+    compare("comment", json!(before.flow.comment), json!(after.flow.comment));
+    changes
+}
+
+/// Append the differences between `before` and `flow` to the flow's
+/// changelog, returning the number of changed fields
+pub fn record(flow: &mut HTTPFlow, before: &HTTPFlow, source: &str, hook: Option<&str>) -> usize {
+    let timestamp = crate::clock::now();
+    let changes = diff(before, flow);
+    let count = changes.len();
+    flow.flow.changes.extend(changes.into_iter().map(|(field, from, to)| Change {
+        timestamp,
+        source: source.to_string(),
+        hook: hook.map(str::to_string),
+        field,
+        from,
+        to,
+    }));
+    count
+}
+
+/// Per-header differences, keyed by lowercase name. Repeated headers are
+/// compared as a list of values.
+fn header_changes(before: &[(String, String)], after: &[(String, String)]) -> Vec<(String, Value, Value)> {
+    let group = |headers: &[(String, String)]| {
+        let mut grouped: IndexMap<String, Vec<String>> = IndexMap::new();
+        for (name, value) in headers {
+            grouped.entry(name.to_ascii_lowercase()).or_default().push(value.clone());
+        }
+        grouped
+    };
+    let (before, after) = (group(before), group(after));
+    let as_value = |values: Option<&Vec<String>>| match values.map(Vec::as_slice) {
+        None => Value::Null,
+        Some([value]) => json!(value),
+        Some(values) => json!(values),
+    };
+
+    let mut names: Vec<&String> = before.keys().collect();
+    names.extend(after.keys().filter(|name| !before.contains_key(*name)));
+    names
+        .into_iter()
+        .filter(|name| before.get(*name) != after.get(*name))
+        .map(|name| (name.clone(), as_value(before.get(name)), as_value(after.get(name))))
+        .collect()
+}
+
+/// Short text bodies verbatim, anything else as size and hash
+fn body_value(content: Option<&[u8]>) -> Value {
+    match content {
+        None => Value::Null,
+        Some(content) => match std::str::from_utf8(content) {
+            Ok(text) if content.len() <= MAX_INLINE_BODY => json!(text),
+            _ => json!({ "size": content.len(), "sha256": content_hash(content) }),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow() -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "example.com".to_string(),
+            443,
+            "/".to_string(),
+        );
+        request.headers = vec![
+            ("User-Agent".to_string(), "curl/8.0".to_string()),
+            ("Cookie".to_string(), "a=1".to_string()),
+        ];
+        HTTPFlow::new(request).with_response(HTTPResponse::new(200, "OK".to_string()))
+    }
+
+    #[test]
+    fn test_record_changes() {
+        let before = flow();
+        let mut after = before.clone();
+        after.request.set_header("user-agent".to_string(), "bot".to_string());
+        after.request.headers.push(("Cookie".to_string(), "b=2".to_string()));
+        after.request.headers.push(("X-Added".to_string(), "1".to_string()));
+        after.response.as_mut().unwrap().status_code = 404;
+
+        assert_eq!(record(&mut after, &before, "addon:rewrite", Some("request")), 4);
+        let fields: Vec<(&str, &Value, &Value)> =
+            after.flow.changes.iter().map(|c| (c.field.as_str(), &c.from, &c.to)).collect();
+        assert_eq!(
+            fields,
+            [
+                ("request.headers.user-agent", &json!("curl/8.0"), &json!("bot")),
+                ("request.headers.cookie", &json!("a=1"), &json!(["a=1", "b=2"])),
+                ("request.headers.x-added", &Value::Null, &json!("1")),
+                ("response.status_code", &json!(200), &json!(404)),
+            ]
+        );
+        assert_eq!(after.flow.changes[0].source, "addon:rewrite");
+        assert_eq!(after.flow.changes[0].hook.as_deref(), Some("request"));
+
+        // Nothing changed since
+        let snapshot = after.clone();
+        assert_eq!(record(&mut after, &snapshot, "api", None), 0);
+    }
+
+    #[test]
+    fn test_body_values() {
+        let before = flow();
+        let mut after = before.clone();
+        after.request.set_content(b"hello".to_vec());
+        after.response.as_mut().unwrap().set_content(vec![0xff; 4]);
+
+        let changes = diff(&before, &after);
+        assert_eq!(changes[0], ("request.content".to_string(), Value::Null, json!("hello")));
+        assert_eq!(changes[1].2["size"], 4);
+        assert_eq!(changes[1].2["sha256"].as_str().unwrap().len(), 64);
+    }
+}
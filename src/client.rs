@@ -0,0 +1,143 @@
+//! Requests the proxy sends upstream on its own behalf.
+//!
+//! Some features need to talk to an origin outside of a proxied connection,
+//! e.g. to fetch the rest of a body of which only a preview was kept. Such
+//! requests go through the same SOCKS routing and DNS cache as proxied
+//! connections and are sent over HTTP/1.1, with TLS for `https` URLs.
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::flow::{HTTPRequest, HTTPResponse};
+use crate::proxychain::ProxyRoute;
+use crate::upstream::UpstreamRouter;
+use crate::{Error, Result};
+
+/// Headers describing the client connection rather than the request
+pub(crate) const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "content-length",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Send `request` to the server it names. Certificates are verified unless
+/// `verify` is false.
+pub async fn send(router: &UpstreamRouter, request: &HTTPRequest, verify: bool) -> Result<HTTPResponse> {
+    Ok(send_via(router, request, verify).await?.0)
+}
+
+/// Send `request` like [`send`], along with the upstream HTTP proxy that
+/// carried it, if any
+pub async fn send_via(router: &UpstreamRouter, request: &HTTPRequest, verify: bool) -> Result<(HTTPResponse, Option<ProxyRoute>)> {
+    let mut builder = hyper::Request::builder().method(request.method.as_str()).uri(request.path.as_str());
+    for (name, value) in &request.headers {
+        if !HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()) {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+    }
+    if request.get_header("host").is_none() {
+        builder = builder.header(hyper::header::HOST, request.pretty_host.as_str());
+    }
+    let outgoing = builder
+        .body(Full::new(Bytes::from(request.content.clone().unwrap_or_default())))
+        .map_err(|e| Error::invalid_request(format!("Cannot send request upstream: {}", e)))?;
+
+    let (stream, route) = router.connect_via(&request.host, request.port).await?;
+    let timestamp_start = now();
+    let (parts, body) = if request.scheme.eq_ignore_ascii_case("https") {
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        if !verify {
+            connector.set_verify(SslVerifyMode::NONE);
+        }
+        connector.set_alpn_protos(b"\x08http/1.1")?;
+        let ssl = connector.build().configure()?.verify_hostname(verify).into_ssl(&request.host)?;
+        let mut tls = tokio_openssl::SslStream::new(ssl, stream)?;
+        Pin::new(&mut tls)
+            .connect()
+            .await
+            .map_err(|e| Error::Proxy(format!("TLS handshake with {} failed: {}", request.host, e)))?;
+        exchange(tls, outgoing).await?
+    } else {
+        exchange(stream, outgoing).await?
+    };
+
+    let mut response = HTTPResponse::new(
+        parts.status.as_u16(),
+        parts.status.canonical_reason().unwrap_or_default().to_string(),
+    );
+    response.http_version = format!("{:?}", parts.version);
+    response.headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+        .collect();
+    response.set_content(body.to_vec());
+    response.timestamp_start = Some(timestamp_start);
+    response.timestamp_end = Some(now());
+    Ok((response, route))
+}
+
+async fn exchange<S>(stream: S, request: hyper::Request<Full<Bytes>>) -> Result<(http::response::Parts, Bytes)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+    let (parts, body) = sender.send_request(request).await?.into_parts();
+    Ok((parts, body.collect().await?.to_bytes()))
+}
+
+fn now() -> f64 {
+    crate::clock::now()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !received.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 2-3/4\r\nContent-Length: 2\r\n\r\nyz")
+                .await
+                .unwrap();
+            String::from_utf8(received).unwrap()
+        });
+
+        let mut request = HTTPRequest::new("GET".to_string(), "http".to_string(), "127.0.0.1".to_string(), port, "/file".to_string());
+        request.set_header("Range".to_string(), "bytes=2-".to_string());
+        request.set_header("Connection".to_string(), "keep-alive".to_string());
+        let response = send(&UpstreamRouter::default(), &request, true).await.unwrap();
+
+        assert_eq!(response.status_code, 206);
+        assert_eq!(response.reason, "Partial Content");
+        assert_eq!(response.get_header("content-range").unwrap(), "bytes 2-3/4");
+        assert_eq!(response.content.as_deref(), Some(&b"yz"[..]));
+        let received = server.await.unwrap().to_ascii_lowercase();
+        assert!(received.starts_with("get /file http/1.1\r\n"));
+        assert!(received.contains(&format!("host: 127.0.0.1:{}\r\n", port)));
+        assert!(received.contains("range: bytes=2-\r\n"));
+        assert!(!received.contains("keep-alive"));
+    }
+}
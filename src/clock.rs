@@ -0,0 +1,303 @@
+//! Time and flow id sources.
+//!
+//! Flow ids and the timestamps recorded on flows come from here instead of
+//! `Uuid::new_v4` and the system time. Normally that is what they are; in
+//! deterministic mode, enabled with the `deterministic` config option,
+//! timestamps come from a [`SteppingClock`] and ids from a [`SeededIds`]
+//! counter, so golden snapshots of the API output are stable across runs.
+//! Tests can enable the mode for the current thread only with [`enter`].
+//!
+//! Proxy layers do not call these functions: their [`Context`] carries the
+//! [`Clock`] and [`IdGenerator`] they read, taken from here when the
+//! context is created. Simulation tests give a layer a [`ManualClock`]
+//! instead and advance virtual time to drive timeouts and keepalives.
+//!
+//! [`Context`]: crate::proxy::context::Context
+
+use rand::{RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+/// Source of the current time as a UNIX timestamp in seconds
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    fn now(&self) -> f64;
+}
+
+/// The system time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        chrono::Utc::now().timestamp_micros() as f64 / 1_000_000.0
+    }
+}
+
+/// A clock starting at a fixed time that advances by `step` seconds each
+/// time it is read
+#[derive(Debug)]
+pub struct SteppingClock {
+    start: f64,
+    step: f64,
+    ticks: AtomicU64,
+}
+
+impl SteppingClock {
+    pub fn new(start: f64, step: f64) -> Self {
+        Self { start, step, ticks: AtomicU64::new(0) }
+    }
+}
+
+impl Clock for SteppingClock {
+    fn now(&self) -> f64 {
+        let ticks = self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.start + ticks as f64 * self.step
+    }
+}
+
+/// A clock that only moves when told to, for tests simulating the passing
+/// of time
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<f64>,
+}
+
+impl ManualClock {
+    pub fn new(start: f64) -> Self {
+        Self { now: Mutex::new(start) }
+    }
+
+    /// Move the clock `secs` seconds forward
+    pub fn advance(&self, secs: f64) {
+        *self.now.lock().unwrap() += secs;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> f64 {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Source of flow ids and of the random bytes in certificate serial numbers
+/// and WebSocket masks
+pub trait IdGenerator: Send + Sync + std::fmt::Debug {
+    fn flow_id(&self) -> String;
+    fn fill_random(&self, buf: &mut [u8]);
+}
+
+/// Random UUIDs and bytes from the thread's random number generator
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+    fn flow_id(&self) -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        rand::thread_rng().fill_bytes(buf);
+    }
+}
+
+/// Flow ids from a seeded counter, formatted like UUIDs, and random bytes
+/// from a generator with the same seed
+#[derive(Debug)]
+pub struct SeededIds {
+    seed: u64,
+    next: AtomicU64,
+    rng: Mutex<rand::rngs::StdRng>,
+}
+
+impl SeededIds {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, next: AtomicU64::new(1), rng: Mutex::new(rand::rngs::StdRng::seed_from_u64(seed)) }
+    }
+
+    pub fn next_id(&self) -> String {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("{:08x}-0000-4000-8000-{:012x}", self.seed & 0xffff_ffff, n & 0xffff_ffff_ffff)
+    }
+}
+
+impl IdGenerator for SeededIds {
+    fn flow_id(&self) -> String {
+        self.next_id()
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        self.rng.lock().unwrap().fill_bytes(buf);
+    }
+}
+
+/// Deterministic mode options as configured in the config file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeterministicOptions {
+    /// Seed embedded in every flow id
+    pub seed: u64,
+    /// UNIX timestamp of the first reading of the clock
+    pub start_time: f64,
+    /// Seconds the clock advances per reading
+    pub step: f64,
+}
+
+impl Default for DeterministicOptions {
+    fn default() -> Self {
+        Self { seed: 0, start_time: 1_700_000_000.0, step: 0.001 }
+    }
+}
+
+#[derive(Debug)]
+struct Deterministic {
+    clock: Arc<SteppingClock>,
+    ids: Arc<SeededIds>,
+}
+
+impl Deterministic {
+    fn new(options: &DeterministicOptions) -> Arc<Self> {
+        Arc::new(Self {
+            clock: Arc::new(SteppingClock::new(options.start_time, options.step)),
+            ids: Arc::new(SeededIds::new(options.seed)),
+        })
+    }
+}
+
+fn global() -> &'static RwLock<Option<Arc<Deterministic>>> {
+    static GLOBAL: OnceLock<RwLock<Option<Arc<Deterministic>>>> = OnceLock::new();
+    GLOBAL.get_or_init(RwLock::default)
+}
+
+thread_local! {
+    static SCOPED: RefCell<Option<Arc<Deterministic>>> = const { RefCell::new(None) };
+}
+
+fn current() -> Option<Arc<Deterministic>> {
+    SCOPED.with(|scoped| scoped.borrow().clone()).or_else(|| global().read().unwrap().clone())
+}
+
+/// Switch the whole process to deterministic mode, restarting the clock
+/// and the id counter
+pub fn install(options: &DeterministicOptions) {
+    *global().write().unwrap() = Some(Deterministic::new(options));
+}
+
+/// Switch the current thread to deterministic mode until the guard is
+/// dropped. Async tests on a current-thread runtime stay in the mode
+/// across awaits.
+pub fn enter(options: &DeterministicOptions) -> DeterministicGuard {
+    let previous = SCOPED.with(|scoped| scoped.replace(Some(Deterministic::new(options))));
+    DeterministicGuard { previous }
+}
+
+/// Deterministic mode of the current thread, see [`enter`]
+#[derive(Debug)]
+pub struct DeterministicGuard {
+    previous: Option<Arc<Deterministic>>,
+}
+
+impl Drop for DeterministicGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        SCOPED.with(|scoped| *scoped.borrow_mut() = previous);
+    }
+}
+
+/// Current UNIX timestamp in seconds
+pub fn now() -> f64 {
+    match current() {
+        Some(deterministic) => deterministic.clock.now(),
+        None => SystemClock.now(),
+    }
+}
+
+/// A new flow id
+pub fn flow_id() -> String {
+    match current() {
+        Some(deterministic) => deterministic.ids.next_id(),
+        None => RandomIds.flow_id(),
+    }
+}
+
+/// The clock [`now`] reads, to hand to code that takes a [`Clock`]
+pub fn clock() -> Arc<dyn Clock> {
+    match current() {
+        Some(deterministic) => deterministic.clock.clone(),
+        None => Arc::new(SystemClock),
+    }
+}
+
+/// The generator [`flow_id`] draws from, to hand to code that takes an
+/// [`IdGenerator`]
+pub fn ids() -> Arc<dyn IdGenerator> {
+    match current() {
+        Some(deterministic) => deterministic.ids.clone(),
+        None => Arc::new(RandomIds),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPFlow, HTTPRequest};
+
+    fn snapshot() -> serde_json::Value {
+        let mut flow = HTTPFlow::new(HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "example.com".to_string(),
+            443,
+            "/".to_string(),
+        ));
+        flow.flow.set_error("connection reset".to_string());
+        let copy = flow.copy();
+        serde_json::json!([flow.to_json(), copy.to_json()])
+    }
+
+    #[test]
+    fn test_deterministic_snapshots() {
+        let options = DeterministicOptions { seed: 42, start_time: 1000.0, step: 0.5 };
+        {
+            let _guard = enter(&options);
+            assert_eq!(flow_id(), "0000002a-0000-4000-8000-000000000001");
+            assert_eq!((now(), now()), (1000.0, 1000.5));
+        }
+        let first = {
+            let _guard = enter(&options);
+            snapshot()
+        };
+        let second = {
+            let _guard = enter(&options);
+            snapshot()
+        };
+        assert_eq!(first, second);
+        assert_eq!(first[0]["id"], "0000002a-0000-4000-8000-000000000001");
+        assert_eq!(first[1]["id"], "0000002a-0000-4000-8000-000000000002");
+
+        // Leaving the mode restores random ids
+        assert_ne!(flow_id(), flow_id());
+        assert_ne!(snapshot(), first);
+    }
+
+    #[test]
+    fn test_injected_sources() {
+        let clock = ManualClock::new(10.0);
+        clock.advance(2.5);
+        assert_eq!((clock.now(), clock.now()), (12.5, 12.5));
+
+        let (a, b) = (SeededIds::new(7), SeededIds::new(7));
+        let (mut x, mut y) = ([0u8; 16], [0u8; 16]);
+        a.fill_random(&mut x);
+        b.fill_random(&mut y);
+        assert_eq!(x, y);
+        assert_eq!(a.flow_id(), b.flow_id());
+
+        // Contexts created in deterministic mode take its sources
+        let _guard = enter(&DeterministicOptions::default());
+        let context = crate::proxy::context::Context::default();
+        assert_eq!(context.ids.flow_id(), "00000000-0000-4000-8000-000000000001");
+        assert_eq!(context.clock.now(), 1_700_000_000.0);
+    }
+}
@@ -0,0 +1,280 @@
+//! Coalescing of identical simultaneous GET requests.
+//!
+//! When enabled, a GET or HEAD request without a body that is identical to
+//! one already in flight, by URL and a configurable set of headers, does not
+//! go upstream itself: it waits for the in-flight request and is answered
+//! with a copy of its response. This reproduces what caching layers do under
+//! a thundering herd. Both sides record their role in the flow's metadata
+//! under `coalescing`: the leader with the number of followers it served,
+//! each follower with the id of its leader.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::filter::Filter;
+use crate::flow::{HTTPFlow, HTTPRequest, HTTPResponse};
+use crate::{Error, Result};
+
+/// Flow metadata key under which the coalescing role is recorded
+pub const METADATA_KEY: &str = "coalescing";
+
+/// Coalescing options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CoalescingOptions {
+    pub enabled: bool,
+    /// Filter expression selecting the requests that may be coalesced;
+    /// empty matches all
+    pub filter: String,
+    /// Request headers that must be equal for requests to be coalesced
+    pub vary_headers: Vec<String>,
+    /// Followers waiting longer than this send their own request
+    pub max_wait_ms: u64,
+}
+
+impl Default for CoalescingOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filter: String::new(),
+            vary_headers: ["accept", "accept-encoding", "accept-language", "authorization", "cookie", "range"]
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+            max_wait_ms: 30_000,
+        }
+    }
+}
+
+type Outcome = std::result::Result<HTTPResponse, String>;
+
+#[derive(Debug)]
+struct InFlight {
+    leader: String,
+    sender: broadcast::Sender<Outcome>,
+}
+
+#[derive(Debug, Default)]
+pub struct Coalescer {
+    options: CoalescingOptions,
+    filter: Option<Filter>,
+    in_flight: Arc<Mutex<HashMap<String, InFlight>>>,
+}
+
+impl Coalescer {
+    pub fn new(options: &CoalescingOptions) -> Result<Self> {
+        let filter = (!options.filter.is_empty())
+            .then(|| Filter::new("coalescing".to_string(), options.filter.clone()))
+            .transpose()?;
+        Ok(Self { options: options.clone(), filter, in_flight: Arc::default() })
+    }
+
+    /// Key identifying requests that may share a response, if the flow can
+    /// be coalesced at all
+    fn key(&self, flow: &HTTPFlow) -> Option<String> {
+        let request = &flow.request;
+        let method = request.method.to_ascii_uppercase();
+        if !self.options.enabled
+            || !matches!(method.as_str(), "GET" | "HEAD")
+            || request.content.as_deref().is_some_and(|c| !c.is_empty())
+            || self.filter.as_ref().is_some_and(|filter| !filter.matches(flow))
+        {
+            return None;
+        }
+        let mut key = format!("{} {}", method, request.url());
+        for name in &self.options.vary_headers {
+            let values: Vec<&str> = request
+                .headers
+                .iter()
+                .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+                .collect();
+            key.push_str(&format!("\n{}: {}", name.to_ascii_lowercase(), values.join(", ")));
+        }
+        Some(key)
+    }
+
+    /// Get the response for a flow, from `upstream` or, if an identical
+    /// request is already in flight, from that request
+    pub async fn fetch<F, Fut>(&self, flow: &mut HTTPFlow, upstream: F) -> Result<()>
+    where
+        F: FnOnce(HTTPRequest) -> Fut,
+        Fut: Future<Output = Result<HTTPResponse>>,
+    {
+        let Some(key) = self.key(flow) else {
+            flow.response = Some(upstream(flow.request.clone()).await?);
+            return Ok(());
+        };
+
+        let waiting = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(leader) => Some((leader.leader.clone(), leader.sender.subscribe())),
+                None => {
+                    let sender = broadcast::channel(1).0;
+                    in_flight.insert(key.clone(), InFlight { leader: flow.flow.id.clone(), sender });
+                    None
+                }
+            }
+        };
+
+        if let Some((leader, mut receiver)) = waiting {
+            let wait = Duration::from_millis(self.options.max_wait_ms);
+            if let Ok(Ok(outcome)) = tokio::time::timeout(wait, receiver.recv()).await {
+                flow.flow.metadata.insert(METADATA_KEY.to_string(), json!({ "role": "follower", "leader": leader }));
+                flow.response = Some(outcome.map_err(Error::Proxy)?);
+                return Ok(());
+            }
+            // The leader went away or took too long
+            flow.response = Some(upstream(flow.request.clone()).await?);
+            return Ok(());
+        }
+
+        let mut guard = LeaderGuard { in_flight: &self.in_flight, key: Some(key) };
+        let outcome = upstream(flow.request.clone()).await.map_err(|e| e.to_string());
+        let followers = guard.finish(outcome.clone());
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), json!({ "role": "leader", "followers": followers }));
+        flow.response = Some(outcome.map_err(Error::Proxy)?);
+        Ok(())
+    }
+
+    /// Number of distinct requests currently in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+/// Removes the leader's entry when it finishes or is dropped, so followers
+/// never wait on a leader that is gone
+struct LeaderGuard<'a> {
+    in_flight: &'a Mutex<HashMap<String, InFlight>>,
+    key: Option<String>,
+}
+
+impl LeaderGuard<'_> {
+    /// Hand the outcome to the followers, returning how many there were
+    fn finish(&mut self, outcome: Outcome) -> usize {
+        let key = self.key.take().expect("finish is called once");
+        // Removing under the lock means later requests cannot subscribe
+        // after the outcome was sent
+        let entry = self.in_flight.lock().unwrap().remove(&key);
+        entry.map_or(0, |entry| entry.sender.send(outcome).unwrap_or(0))
+    }
+}
+
+impl Drop for LeaderGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.in_flight.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn flow(path: &str, authorization: &str) -> HTTPFlow {
+        let mut request = HTTPRequest::new("GET".to_string(), "https".to_string(), "cdn.example".to_string(), 443, path.to_string());
+        request.set_header("Authorization".to_string(), authorization.to_string());
+        HTTPFlow::new(request)
+    }
+
+    fn enabled() -> Arc<Coalescer> {
+        Arc::new(Coalescer::new(&CoalescingOptions { enabled: true, ..Default::default() }).unwrap())
+    }
+
+    async fn fetch_all(coalescer: Arc<Coalescer>, flows: Vec<HTTPFlow>, calls: Arc<AtomicUsize>) -> Vec<HTTPFlow> {
+        let tasks: Vec<_> = flows
+            .into_iter()
+            .map(|mut flow| {
+                let coalescer = coalescer.clone();
+                let calls = calls.clone();
+                tokio::spawn(async move {
+                    coalescer
+                        .fetch(&mut flow, |_| async move {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Ok(HTTPResponse::new(200, "OK".to_string()))
+                        })
+                        .await
+                        .unwrap();
+                    flow
+                })
+            })
+            .collect();
+        let mut flows = Vec::new();
+        for task in tasks {
+            flows.push(task.await.unwrap());
+        }
+        flows
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_coalesced() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let flows = fetch_all(enabled(), (0..5).map(|_| flow("/asset.js", "a")).collect(), calls.clone()).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(flows.iter().all(|f| f.response.as_ref().unwrap().status_code == 200));
+        let leader = flows.iter().find(|f| f.flow.metadata[METADATA_KEY]["role"] == "leader").unwrap();
+        assert_eq!(leader.flow.metadata[METADATA_KEY]["followers"], 4);
+        let followers = flows.iter().filter(|f| f.flow.metadata[METADATA_KEY]["leader"] == leader.flow.id.as_str());
+        assert_eq!(followers.count(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_distinct_requests_not_coalesced() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let flows = vec![flow("/asset.js", "a"), flow("/asset.js", "b"), flow("/other.js", "a")];
+        fetch_all(enabled(), flows, calls.clone()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let mut post = flow("/asset.js", "a");
+        post.request.method = "POST".to_string();
+        assert!(enabled().key(&post).is_none());
+        let disabled = Coalescer::new(&CoalescingOptions::default()).unwrap();
+        assert!(disabled.key(&flow("/asset.js", "a")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_follower_falls_back_when_leader_is_dropped() {
+        let coalescer = enabled();
+        let leader = {
+            let coalescer = coalescer.clone();
+            tokio::spawn(async move {
+                let mut flow = flow("/slow", "a");
+                let _ = coalescer
+                    .fetch(&mut flow, |_| async {
+                        std::future::pending::<()>().await;
+                        unreachable!()
+                    })
+                    .await;
+            })
+        };
+        while coalescer.in_flight() == 0 {
+            tokio::task::yield_now().await;
+        }
+        let follower = {
+            let coalescer = coalescer.clone();
+            tokio::spawn(async move {
+                let mut flow = flow("/slow", "a");
+                coalescer.fetch(&mut flow, |_| async { Ok(HTTPResponse::new(204, String::new())) }).await.unwrap();
+                flow
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        leader.abort();
+
+        let flow = follower.await.unwrap();
+        assert_eq!(flow.response.unwrap().status_code, 204);
+        assert!(!flow.flow.metadata.contains_key(METADATA_KEY));
+        assert_eq!(coalescer.in_flight(), 0);
+    }
+}
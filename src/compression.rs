@@ -0,0 +1,282 @@
+//! Response compression toward the client.
+//!
+//! Upstream servers do not always compress, and bodies the proxy decoded for
+//! inspection go out uncompressed. When enabled, responses are re-compressed
+//! with the best encoding the client accepts before they are sent, provided
+//! the content type is compressible and the body is large enough to benefit.
+//! Only the copy sent to the client is compressed; the stored flow keeps the
+//! body as received, and the outcome is recorded in its metadata under
+//! `compression`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::{Read, Write};
+
+use crate::contentviews::content_type_matches;
+use crate::flow::{HTTPFlow, HTTPResponse};
+
+/// Flow metadata key under which the applied compression is recorded
+pub const METADATA_KEY: &str = "compression";
+
+/// Content encodings the proxy can produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Br,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Br => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Br => {
+                let mut encoded = Vec::new();
+                let params = brotli::enc::BrotliEncoderParams { quality: 5, ..Default::default() };
+                brotli::BrotliCompress(&mut &data[..], &mut encoded, &params)?;
+                Ok(encoded)
+            }
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Response compression options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponseCompression {
+    pub enabled: bool,
+    /// Encodings to use, in order of preference when the client accepts
+    /// several equally
+    pub encodings: Vec<Encoding>,
+    /// Bodies shorter than this are sent as they are
+    pub min_size: usize,
+    /// Content types to compress, matched by substring
+    pub content_types: Vec<String>,
+}
+
+impl Default for ResponseCompression {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            encodings: vec![Encoding::Br, Encoding::Gzip],
+            min_size: 1024,
+            content_types: ["text/", "json", "javascript", "xml", "css", "svg", "wasm"]
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl ResponseCompression {
+    /// Encoding to use for a client sending `accept_encoding`: the one with
+    /// the highest q-value, ties broken by the configured preference
+    pub fn choose_encoding(&self, accept_encoding: &str) -> Option<Encoding> {
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in &self.encodings {
+            let q = accepted_quality(accept_encoding, encoding.as_str());
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((*encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    /// Response to send to the client for a flow, compressed if the options
+    /// and the client allow it. Returns `None` if it should go out unchanged.
+    pub fn compress(&self, flow: &mut HTTPFlow) -> Option<HTTPResponse> {
+        if !self.enabled || flow.request.method.eq_ignore_ascii_case("HEAD") {
+            return None;
+        }
+        let response = flow.response.as_ref()?;
+        let body = response.content.as_deref()?;
+        let already_encoded = response
+            .get_header("content-encoding")
+            .is_some_and(|e| !e.trim().eq_ignore_ascii_case("identity"));
+        let content_type = response.get_header("content-type").map(String::as_str).unwrap_or_default();
+        let patterns: Vec<&str> = self.content_types.iter().map(String::as_str).collect();
+        if already_encoded
+            || body.len() < self.min_size
+            || matches!(response.status_code, 204 | 206 | 304)
+            || !content_type_matches(content_type, &patterns)
+            || response.get_header("cache-control").is_some_and(|c| c.to_ascii_lowercase().contains("no-transform"))
+        {
+            return None;
+        }
+
+        let encoding = self.choose_encoding(flow.request.get_header("accept-encoding")?)?;
+        let compressed = encoding.encode(body).ok().filter(|c| c.len() < body.len())?;
+
+        let mut wire = response.clone();
+        wire.headers.retain(|(name, _)| {
+            !name.eq_ignore_ascii_case("content-encoding") && !name.eq_ignore_ascii_case("transfer-encoding")
+        });
+        wire.set_header("Content-Encoding".to_string(), encoding.as_str().to_string());
+        wire.set_header("Content-Length".to_string(), compressed.len().to_string());
+        add_vary(&mut wire);
+        // A strong validator no longer identifies the representation sent
+        if let Some(etag) = wire.get_header("etag").filter(|e| !e.starts_with("W/")).cloned() {
+            wire.set_header("ETag".to_string(), format!("W/{}", etag));
+        }
+
+        flow.flow.metadata.insert(
+            METADATA_KEY.to_string(),
+            json!({
+                "encoding": encoding,
+                "original_size": body.len(),
+                "compressed_size": compressed.len(),
+            }),
+        );
+        wire.set_content(compressed);
+        Some(wire)
+    }
+}
+
+/// Decode a body sent with the given Content-Encoding. Stacked encodings
+/// are undone in reverse order.
+pub fn decode(content_encoding: &str, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decoded = data.to_vec();
+    for encoding in content_encoding.rsplit(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut out = Vec::new();
+        match encoding.to_ascii_lowercase().as_str() {
+            "identity" => continue,
+            "gzip" | "x-gzip" => {
+                flate2::read::MultiGzDecoder::new(&decoded[..]).read_to_end(&mut out)?;
+            }
+            "deflate" => {
+                // Servers send raw deflate as often as the zlib-wrapped form
+                if flate2::read::ZlibDecoder::new(&decoded[..]).read_to_end(&mut out).is_err() {
+                    out.clear();
+                    flate2::read::DeflateDecoder::new(&decoded[..]).read_to_end(&mut out)?;
+                }
+            }
+            "br" => {
+                brotli::BrotliDecompress(&mut &decoded[..], &mut out)?;
+            }
+            other => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unsupported Content-Encoding {}", other),
+                ))
+            }
+        }
+        decoded = out;
+    }
+    Ok(decoded)
+}
+
+/// q-value the client gives an encoding in an Accept-Encoding header
+fn accepted_quality(accept_encoding: &str, encoding: &str) -> f32 {
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let q = parts
+            .find_map(|p| p.trim().strip_prefix("q=").map(|q| q.trim().parse().unwrap_or(0.0)))
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+fn add_vary(response: &mut HTTPResponse) {
+    match response.get_header("vary").cloned() {
+        Some(vary) if vary.split(',').any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("accept-encoding")) => {}
+        Some(vary) => response.set_header("Vary".to_string(), format!("{}, Accept-Encoding", vary)),
+        None => response.set_header("Vary".to_string(), "Accept-Encoding".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    fn flow(accept_encoding: &str, content_type: &str, body: Vec<u8>) -> HTTPFlow {
+        let mut request = HTTPRequest::new("GET".to_string(), "https".to_string(), "example.com".to_string(), 443, "/".to_string());
+        request.set_header("Accept-Encoding".to_string(), accept_encoding.to_string());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_header("Content-Type".to_string(), content_type.to_string());
+        response.set_header("Content-Length".to_string(), body.len().to_string());
+        response.set_header("ETag".to_string(), "\"v1\"".to_string());
+        response.set_content(body);
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    fn enabled() -> ResponseCompression {
+        ResponseCompression { enabled: true, ..Default::default() }
+    }
+
+    #[test]
+    fn test_choose_encoding() {
+        let options = enabled();
+        assert_eq!(options.choose_encoding("gzip, deflate, br"), Some(Encoding::Br));
+        assert_eq!(options.choose_encoding("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(options.choose_encoding("br;q=0, *"), Some(Encoding::Gzip));
+        assert_eq!(options.choose_encoding("identity"), None);
+    }
+
+    #[test]
+    fn test_compress_response() {
+        let body = "hello world ".repeat(200).into_bytes();
+        let mut flow = flow("gzip", "text/html; charset=utf-8", body.clone());
+        let wire = enabled().compress(&mut flow).unwrap();
+
+        assert_eq!(wire.get_header("content-encoding").map(String::as_str), Some("gzip"));
+        assert_eq!(wire.get_header("vary").map(String::as_str), Some("Accept-Encoding"));
+        assert_eq!(wire.get_header("etag").map(String::as_str), Some("W/\"v1\""));
+        let compressed = wire.content.clone().unwrap();
+        assert_eq!(wire.get_header("content-length"), Some(&compressed.len().to_string()));
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+
+        // The stored flow is unchanged apart from the record
+        assert_eq!(flow.response.as_ref().unwrap().content.as_deref(), Some(&body[..]));
+        assert_eq!(flow.flow.metadata[METADATA_KEY]["encoding"], "gzip");
+    }
+
+    #[test]
+    fn test_compress_skipped() {
+        let body = "x".repeat(4096).into_bytes();
+        assert!(ResponseCompression::default().compress(&mut flow("gzip", "text/plain", body.clone())).is_none());
+        assert!(enabled().compress(&mut flow("identity", "text/plain", body.clone())).is_none());
+        assert!(enabled().compress(&mut flow("gzip", "image/png", body.clone())).is_none());
+        assert!(enabled().compress(&mut flow("gzip", "text/plain", b"short".to_vec())).is_none());
+
+        let mut encoded = flow("gzip", "text/plain", body);
+        encoded.response.as_mut().unwrap().set_header("Content-Encoding".to_string(), "br".to_string());
+        assert!(enabled().compress(&mut encoded).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_response_hook_compresses() {
+        let config = crate::config::Config { response_compression: enabled(), ..Default::default() };
+        let proxy = crate::proxy::ProxyServer::new(std::sync::Arc::new(config));
+        let body = "{\"items\": []} ".repeat(200).into_bytes();
+        let mut flow = flow("br, gzip", "application/json", body.clone());
+        let delivery = proxy.response_hook(crate::listeners::HTTP3, &mut flow).await.unwrap();
+
+        let wire = delivery.compressed.unwrap();
+        assert_eq!(wire.get_header("content-encoding").map(String::as_str), Some("br"));
+        assert!(wire.content.unwrap().len() < body.len());
+        assert_eq!(flow.response.as_ref().unwrap().content.as_deref(), Some(&body[..]));
+        assert_eq!(flow.flow.metadata[METADATA_KEY]["encoding"], "br");
+    }
+}
@@ -1,9 +1,44 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
 
-use crate::Result;
+use crate::adaptation::AdaptationService;
+use crate::allowlist::AllowlistOptions;
+use crate::analysis::endpoints::EndpointOptions;
+use crate::auth_injection::AuthInjectionRule;
+use crate::capture_profiles::CaptureProfile;
+use crate::certs::{CertDiskCacheOptions, CertMintingOptions, CertValidityOptions};
+use crate::clock::DeterministicOptions;
+use crate::coalesce::CoalescingOptions;
+use crate::compression::ResponseCompression;
+use crate::agent::AgentListenerOptions;
+use crate::cookie_policy::CookiePolicyOptions;
+use crate::csp::CspOptions;
+use crate::dns::DnsCacheOptions;
+use crate::expectations::ExpectationSpec;
+use crate::gauges::GaugeOptions;
+use crate::header_profiles::HeaderProfile;
+use crate::janitor::JanitorOptions;
+use crate::lazybody::LazyBodyOptions;
+use crate::listeners::ListenerOptions;
+use crate::metrics::MetricsOptions;
+use crate::pinning::PinningRule;
+use crate::proxychain::UpstreamProxyOptions;
+use crate::replay::ReplayOptions;
+use crate::sandbox::SandboxOptions;
+use crate::serverreplay::ServerReplayOptions;
+use crate::save::WarmStartOptions;
+use crate::shaping::ShapingRule;
+use crate::sockets::SocketTuning;
+use crate::sse::SseMonitorOptions;
+use crate::store::FlowStoreOptions;
+use crate::tls_sessions::TlsSessionCacheOptions;
+use crate::upstream::SocksUpstreamRule;
+use crate::websocket::WebSocketCloseRule;
+use crate::{Error, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub proxy_host: String,
     pub proxy_port: u16,
@@ -11,21 +46,224 @@ pub struct Config {
     pub web_port: u16,
     pub auth_enabled: bool,
     pub auth_token: Option<String>,
+    /// Serve CPU profiles and memory snapshots at `/debug/pprof` to
+    /// clients presenting `auth_token`
+    pub profiling: bool,
+    /// Log every event the protocol layers handle, for debugging the proxy
+    /// itself
+    pub proxy_debug: bool,
     pub cert_store_path: String,
+    /// Existing CA to use instead of generating one: a PEM file with key
+    /// and certificate, a PKCS#12 bundle, or a mitmproxy cert directory
+    pub ca_file: Option<String>,
+    /// Passphrase of a PKCS#12 `ca_file`
+    pub ca_passphrase: Option<String>,
     pub flows_store_path: String,
     pub max_flows: usize,
     pub ssl_insecure: bool,
     pub upstream_cert: bool,
+    /// Remove `If-Modified-Since` and `If-None-Match` from requests, so
+    /// servers always send full responses
     pub anticache: bool,
+    /// Remove `Accept-Encoding` from requests, so servers send uncompressed
+    /// responses
     pub anticomp: bool,
     pub showhost: bool,
+    /// Hold requests and responses matching this filter expression until
+    /// they are resumed or killed
+    pub intercept: Option<String>,
+    /// Answer requests to `onboarding_host` with the CA certificate
+    /// installation page instead of forwarding them
+    pub onboarding: bool,
+    pub onboarding_host: String,
+    /// Answer requests matching a filter with local files, as
+    /// `filter|path`; see `maplocal` for how directories are served
+    pub map_local: Vec<String>,
+    /// Rewrite request URLs before they are sent upstream, as
+    /// `regex|replacement`
+    pub map_remote: Vec<String>,
+    /// Answer requests matching a filter with a fixed status code, as
+    /// `/flow-filter/status`; 444 kills the stream instead
+    pub block_list: Vec<String>,
+    /// Answer requests matching recorded ones with the recorded responses
+    /// instead of forwarding them
+    pub server_replay: ServerReplayOptions,
+    /// Remember cookies set in responses matching this filter expression
+    /// and send them along with later matching requests to the same server
+    pub stickycookie: Option<String>,
+    /// Replace bodies of matching responses with local files, keeping their
+    /// headers, as `|sha256:hash|path` or `|flow-filter|path`
+    pub substitute_body: Vec<String>,
+    /// Give matching requests credentials before they are forwarded or
+    /// replayed: a bearer token, an AWS SigV4 signature, or a script token
+    pub auth_injection: Vec<AuthInjectionRule>,
+    /// Remove `Range` and `If-Range` from requests matching these filter
+    /// expressions, so that full bodies are captured
+    pub strip_range: Vec<String>,
+    /// Set or remove headers of matching messages, as
+    /// `/flow-filter/name/value`; see `modifyheaders` for the syntax
+    pub modify_headers: Vec<String>,
+    /// Replace regex matches in bodies of matching messages, as
+    /// `/flow-filter/regex/replacement`; see `modifybody` for the syntax
+    pub modify_body: Vec<String>,
+    /// Largest body `modify_body` rules apply to, in bytes
+    pub modify_body_max_size: usize,
     pub no_server: bool,
     pub mode: ProxyMode,
+    /// Proxy to chain to in upstream mode, or server all requests are
+    /// forwarded to in reverse mode, as a URL
     pub upstream_server: Option<String>,
+    /// Fallback proxies and health checks for `upstream_server` in
+    /// upstream mode
+    pub upstream_proxies: UpstreamProxyOptions,
+    /// Forward the client's Host header unchanged in reverse mode
+    pub keep_host_header: bool,
     pub listen_host: Option<String>,
     pub listen_port: Option<u16>,
+    /// Also accept SOCKS5 clients on this port of the proxy host
+    pub socks5_port: Option<u16>,
+    /// Also accept HTTP/3 clients on this UDP port of the proxy host.
+    /// Needs the `http3` feature.
+    pub http3_port: Option<u16>,
+    /// Also answer DNS queries on this UDP port of the proxy host
+    pub dns_port: Option<u16>,
+    /// Server DNS queries are forwarded to as `host:port`. If unset,
+    /// address queries are resolved by the proxy itself and others are
+    /// answered as not implemented.
+    pub dns_upstream: Option<String>,
+    /// Listener for reverse tunnels from remote agents
+    pub agents: AgentListenerOptions,
     pub certs_path: String,
     pub confdir: String,
+    pub expectations: Vec<ExpectationSpec>,
+    /// Append completed flows to segment files derived from this path
+    pub save_stream_file: Option<String>,
+    /// Start a new segment once the current one reaches this many bytes
+    pub save_stream_max_size: Option<u64>,
+    /// Start a new segment once the current one is this many seconds old
+    pub save_stream_rotate_secs: Option<u64>,
+    /// Load the most recent flows of the save stream on startup
+    pub warm_start: WarmStartOptions,
+    /// Where captured flows are kept: in memory or in an sqlite database
+    pub flow_store: FlowStoreOptions,
+    /// Delay, throttle or pad responses matching these rules
+    pub shaping_rules: Vec<ShapingRule>,
+    /// Name of the header profile applied to requests before forwarding
+    pub header_profile: Option<String>,
+    /// Custom header profiles in addition to the built-in ones
+    pub header_profiles: Vec<HeaderProfile>,
+    /// Upstream servers spoken to with prior-knowledge HTTP/2 over plain TCP
+    /// (h2c), as `host`, `host:port` or `*.domain`
+    pub h2c_upstream: Vec<String>,
+    /// Seconds an upstream HTTP/2 connection may be idle before a PING is
+    /// sent to keep it open; 0 disables the keepalive
+    pub http2_ping_keepalive: u64,
+    /// Regexes of destinations tunneled without interception or
+    /// recording, searched case-insensitively in the server address and
+    /// SNI as `host:port`
+    pub ignore_hosts: Vec<String>,
+    /// Regexes of the only destinations intercepted and recorded;
+    /// connections to everything else are relayed as they are. Unused if
+    /// `ignore_hosts` is set.
+    pub allow_hosts: Vec<String>,
+    /// Refuse to connect to any destination not on an explicit list
+    pub allowlist: AllowlistOptions,
+    /// Answer HTTP/1.1 requests sent before the previous response completed
+    /// with 400 and close the connection, instead of processing them in order
+    pub reject_pipelining: bool,
+    /// Answer requests whose response cannot be parsed with a 502
+    /// describing what the server sent, instead of closing the connection
+    pub bad_gateway_on_invalid_response: bool,
+    /// Log level per subsystem (proxy, tls, http1, http2, websocket, api),
+    /// or `default` for everything else
+    pub log_levels: BTreeMap<String, String>,
+    /// Serve certificates clients must reject to matching hosts, to test
+    /// certificate pinning. Never enable this for regular interception.
+    pub pinning_tests: Vec<PinningRule>,
+    /// SOCKS5 proxy server connections go through, e.g.
+    /// `socks5h://127.0.0.1:9050` (`socks5h` resolves names on the proxy)
+    pub socks_upstream: Option<String>,
+    /// Per-destination overrides of `socks_upstream`; the first matching
+    /// rule wins and an empty upstream connects directly
+    pub socks_upstream_rules: Vec<SocksUpstreamRule>,
+    /// Store proxied traffic as flows. When off, traffic is still proxied
+    /// but only counted; recording can be resumed at runtime.
+    pub record: bool,
+    /// What to do with clients using Encrypted Client Hello, whose real
+    /// server name cannot be seen
+    pub tls_ech: EchMode,
+    /// Library intercepting TLS; `rustls` needs the `rustls-backend`
+    /// feature
+    pub tls_backend: TlsBackend,
+    /// Close WebSocket connections matching a filter, or rewrite their
+    /// close frames, with a chosen status code; the first matching rule wins
+    pub websocket_close_rules: Vec<WebSocketCloseRule>,
+    /// Re-compress responses toward clients that accept gzip or brotli
+    pub response_compression: ResponseCompression,
+    /// Keep only rolling per-host aggregates of proxied traffic and never
+    /// store individual flows
+    pub aggregate_only: bool,
+    /// Window and reporting threshold of the traffic aggregates
+    pub metrics: MetricsOptions,
+    /// Stall detection for open `text/event-stream` responses
+    pub sse_monitor: SseMonitorOptions,
+    /// Live gauges pushed to `/updates` clients
+    pub gauges: GaugeOptions,
+    /// Redirect handling of replayed requests
+    pub replay: ReplayOptions,
+    /// Caching of upstream DNS lookups and failed connects
+    pub dns_cache: DnsCacheOptions,
+    /// Content-Security-Policy rewriting and violation report capture
+    pub csp: CspOptions,
+    /// Set-Cookie attribute downgrades for testing cookie policies
+    pub cookie_policy: CookiePolicyOptions,
+    /// Directory body diff reference files must be in; any file the proxy
+    /// can read if unset
+    pub diff_reference_dir: Option<String>,
+    /// External ICAP or HTTP callback services adapting requests and
+    /// responses before they are forwarded
+    pub adaptation_services: Vec<AdaptationService>,
+    /// Per-invocation limits and capability grants of user scripts
+    pub script_sandbox: SandboxOptions,
+    /// WebAssembly modules loaded as addons at startup, with the `wasm`
+    /// feature
+    pub wasm_plugins: Vec<String>,
+    /// Rhai scripts loaded as addons at startup and reloaded when they
+    /// change, with the `scripting` feature
+    pub scripts: Vec<String>,
+    /// TCP options of client and server sockets
+    pub sockets: SocketTuning,
+    /// Collapsing of identical simultaneous GET requests into one upstream
+    /// request
+    pub request_coalescing: CoalescingOptions,
+    /// Resumption of upstream TLS sessions
+    pub tls_session_cache: TlsSessionCacheOptions,
+    /// Storing only a preview of very large response bodies, fetching the
+    /// rest from the origin when the full content is viewed
+    pub lazy_body: LazyBodyOptions,
+    /// Pruning of old flows from memory, archiving them first
+    pub janitor: JanitorOptions,
+    /// Validity windows of generated certificates and the CA expiry warning
+    pub cert_validity: CertValidityOptions,
+    /// Whether leaf certificates cover one host, a wildcard domain or
+    /// several sibling hosts
+    pub cert_minting: CertMintingOptions,
+    /// Keeping generated leaf certificates on disk across restarts
+    pub cert_disk_cache: CertDiskCacheOptions,
+    /// Rules refining how `/analysis/endpoints` groups URLs by endpoint
+    pub endpoints: EndpointOptions,
+    /// Directory raw bytes that failed to parse are saved to, seeding the
+    /// fuzz targets; nothing is saved if unset
+    pub fuzz_corpus_dir: Option<String>,
+    /// Options overriding the global ones for one listener, keyed by
+    /// listener id: a mode name such as `reverse`, `http3` or `dns`
+    pub listeners: BTreeMap<String, ListenerOptions>,
+    /// Capture level and intercept rule per client identity: an address,
+    /// a CIDR range, `user:<name>` for a proxy user, or `*`
+    pub capture_profiles: BTreeMap<String, CaptureProfile>,
+    /// Generate flow ids from a seeded counter and timestamps from a fixed,
+    /// stepping clock, for stable snapshots in tests
+    pub deterministic: Option<DeterministicOptions>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +276,77 @@ pub enum ProxyMode {
     Upstream,
 }
 
+impl ProxyMode {
+    /// Name of the mode, which is also the id of listeners running in it
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProxyMode::Regular => "regular",
+            ProxyMode::Transparent => "transparent",
+            ProxyMode::Socks5 => "socks5",
+            ProxyMode::Reverse => "reverse",
+            ProxyMode::Upstream => "upstream",
+        }
+    }
+}
+
+/// Server all requests are forwarded to in reverse proxy mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReverseTarget {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl ReverseTarget {
+    /// Parse `http://host[:port]` or `https://host[:port]`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::invalid_request(format!("Invalid reverse proxy target {}: {}", spec, reason));
+        let url = url::Url::parse(spec).map_err(|e| invalid(&e.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(invalid("scheme must be http or https"));
+        }
+        if !matches!(url.path(), "" | "/") || url.query().is_some() {
+            return Err(invalid("paths are not supported"));
+        }
+        let host = match url.host().ok_or_else(|| invalid("missing host"))? {
+            url::Host::Ipv6(ip) => ip.to_string(),
+            host => host.to_string(),
+        };
+        let port = url.port_or_known_default().ok_or_else(|| invalid("missing port"))?;
+        Ok(Self { scheme: url.scheme().to_string(), host, port })
+    }
+
+    /// `host[:port]` as sent in the Host header, leaving out default ports
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        match (self.scheme.as_str(), self.port) {
+            ("http", 80) | ("https", 443) => host,
+            (_, port) => format!("{}:{}", host, port),
+        }
+    }
+}
+
+/// Handling of TLS connections using Encrypted Client Hello
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EchMode {
+    /// Forward the connection without interception
+    #[default]
+    Passthrough,
+    /// Close the connection
+    Reject,
+}
+
+/// TLS library used by the interception layers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsBackend {
+    #[default]
+    Openssl,
+    /// rustls with rcgen-issued leaf certificates
+    Rustls,
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
@@ -47,7 +356,11 @@ impl Default for Config {
             web_port: 8081,
             auth_enabled: false,
             auth_token: None,
+            profiling: false,
+            proxy_debug: false,
             cert_store_path: "~/.mitmproxy-rs/certs".to_string(),
+            ca_file: None,
+            ca_passphrase: None,
             flows_store_path: "~/.mitmproxy-rs/flows".to_string(),
             max_flows: 10000,
             ssl_insecure: false,
@@ -55,13 +368,85 @@ impl Default for Config {
             anticache: false,
             anticomp: false,
             showhost: false,
+            intercept: None,
+            onboarding: true,
+            onboarding_host: crate::onboarding::DEFAULT_HOST.to_string(),
+            map_local: Vec::new(),
+            block_list: Vec::new(),
+            server_replay: ServerReplayOptions::default(),
+            stickycookie: None,
+            substitute_body: Vec::new(),
+            auth_injection: Vec::new(),
+            map_remote: Vec::new(),
+            strip_range: Vec::new(),
+            modify_headers: Vec::new(),
+            modify_body: Vec::new(),
+            modify_body_max_size: crate::modifybody::DEFAULT_MAX_SIZE,
             no_server: false,
             mode: ProxyMode::Regular,
             upstream_server: None,
+            upstream_proxies: UpstreamProxyOptions::default(),
+            keep_host_header: false,
             listen_host: None,
             listen_port: None,
+            socks5_port: None,
+            http3_port: None,
+            dns_port: None,
+            dns_upstream: None,
+            agents: AgentListenerOptions::default(),
             certs_path: "~/.mitmproxy-rs/certs".to_string(),
             confdir: "~/.mitmproxy-rs".to_string(),
+            expectations: Vec::new(),
+            save_stream_file: None,
+            save_stream_max_size: None,
+            save_stream_rotate_secs: None,
+            warm_start: WarmStartOptions::default(),
+            flow_store: FlowStoreOptions::default(),
+            shaping_rules: Vec::new(),
+            header_profile: None,
+            header_profiles: Vec::new(),
+            h2c_upstream: Vec::new(),
+            http2_ping_keepalive: 58,
+            ignore_hosts: Vec::new(),
+            allow_hosts: Vec::new(),
+            allowlist: AllowlistOptions::default(),
+            reject_pipelining: false,
+            bad_gateway_on_invalid_response: false,
+            log_levels: BTreeMap::new(),
+            pinning_tests: Vec::new(),
+            socks_upstream: None,
+            socks_upstream_rules: Vec::new(),
+            record: true,
+            tls_ech: EchMode::default(),
+            tls_backend: TlsBackend::default(),
+            websocket_close_rules: Vec::new(),
+            response_compression: ResponseCompression::default(),
+            aggregate_only: false,
+            metrics: MetricsOptions::default(),
+            sse_monitor: SseMonitorOptions::default(),
+            gauges: GaugeOptions::default(),
+            replay: ReplayOptions::default(),
+            dns_cache: DnsCacheOptions::default(),
+            csp: CspOptions::default(),
+            cookie_policy: CookiePolicyOptions::default(),
+            diff_reference_dir: None,
+            adaptation_services: Vec::new(),
+            script_sandbox: SandboxOptions::default(),
+            wasm_plugins: Vec::new(),
+            scripts: Vec::new(),
+            sockets: SocketTuning::default(),
+            request_coalescing: CoalescingOptions::default(),
+            tls_session_cache: TlsSessionCacheOptions::default(),
+            lazy_body: LazyBodyOptions::default(),
+            janitor: JanitorOptions::default(),
+            cert_validity: CertValidityOptions::default(),
+            cert_minting: CertMintingOptions::default(),
+            cert_disk_cache: CertDiskCacheOptions::default(),
+            endpoints: EndpointOptions::default(),
+            fuzz_corpus_dir: None,
+            listeners: BTreeMap::new(),
+            capture_profiles: BTreeMap::new(),
+            deterministic: None,
         }
     }
 }
@@ -82,6 +467,43 @@ impl Config {
         Ok(())
     }
 
+    /// Set the mode from a command-line spec: `regular`, `transparent`,
+    /// `socks5`, `upstream:URL` or `reverse:URL`
+    pub fn set_mode(&mut self, spec: &str) -> Result<()> {
+        let (name, server) = match spec.split_once(':') {
+            Some((name, server)) => (name, Some(server)),
+            None => (spec, None),
+        };
+        self.mode = match (name, server) {
+            ("regular", None) => ProxyMode::Regular,
+            ("transparent", None) => ProxyMode::Transparent,
+            ("socks5", None) => ProxyMode::Socks5,
+            ("upstream", Some(_)) => ProxyMode::Upstream,
+            ("reverse", Some(server)) => {
+                ReverseTarget::parse(server)?;
+                ProxyMode::Reverse
+            }
+            ("upstream" | "reverse", None) => {
+                return Err(Error::invalid_request(format!("Mode {} needs a server, e.g. {}:https://example.com", name, name)))
+            }
+            _ => return Err(Error::invalid_request(format!("Invalid mode: {}", spec))),
+        };
+        self.upstream_server = server.map(str::to_string);
+        Ok(())
+    }
+
+    /// The server requests are forwarded to, in reverse mode
+    pub fn reverse_target(&self) -> Result<Option<ReverseTarget>> {
+        if !matches!(self.mode, ProxyMode::Reverse) {
+            return Ok(None);
+        }
+        let server = self
+            .upstream_server
+            .as_deref()
+            .ok_or_else(|| Error::invalid_request("Reverse mode needs upstream_server"))?;
+        ReverseTarget::parse(server).map(Some)
+    }
+
     pub fn proxy_addr(&self) -> String {
         format!("{}:{}", self.proxy_host, self.proxy_port)
     }
@@ -127,6 +549,27 @@ mod tests {
         assert_eq!(config.proxy_addr(), "127.0.0.1:8080");
     }
 
+    #[test]
+    fn test_set_mode() {
+        let mut config = Config::default();
+        config.set_mode("reverse:https://api.example.com").unwrap();
+        assert!(matches!(config.mode, ProxyMode::Reverse));
+        let target = config.reverse_target().unwrap().unwrap();
+        assert_eq!((target.scheme.as_str(), target.host.as_str(), target.port), ("https", "api.example.com", 443));
+        assert_eq!(target.authority(), "api.example.com");
+
+        config.set_mode("reverse:http://[::1]:8000").unwrap();
+        assert_eq!(config.reverse_target().unwrap().unwrap().authority(), "[::1]:8000");
+
+        assert!(config.set_mode("reverse").is_err());
+        assert!(config.set_mode("reverse:https://api.example.com/v1").is_err());
+        assert!(config.set_mode("reverse:ftp://files.example.com").is_err());
+        assert!(config.set_mode("sideways").is_err());
+
+        config.set_mode("regular").unwrap();
+        assert!(config.reverse_target().unwrap().is_none());
+    }
+
     #[test]
     fn test_web_addr() {
         let config = Config::default();
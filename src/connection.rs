@@ -1,6 +1,6 @@
 //! Connection types and states matching mitmproxy's connection model
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::time::SystemTime;
 
 /// Address type matching Python mitmproxy's Address
@@ -68,6 +68,22 @@ pub struct Connection {
     pub cipher: Option<String>,
     pub sni: Option<String>,
     pub alpn: Option<String>,
+    /// How a ClientHello without a usable server name was handled
+    pub sni_fallback: Option<SniFallback>,
+}
+
+/// Decision taken for a client whose ClientHello carries no SNI, or hides
+/// the real one with Encrypted Client Hello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SniFallback {
+    /// Certificate issued for the host the client asked for in CONNECT
+    ConnectHost(String),
+    /// Certificate issued for the address the client connected to
+    DestinationAddress(IpAddr),
+    /// Encrypted ClientHello forwarded to the server without interception
+    EchPassthrough,
+    /// Encrypted ClientHello refused
+    EchRejected,
 }
 
 impl Connection {
@@ -87,6 +103,7 @@ impl Connection {
             cipher: None,
             sni: None,
             alpn: None,
+            sni_fallback: None,
         }
     }
 }
@@ -118,6 +135,9 @@ impl Client {
 pub struct Server {
     pub connection: Connection,
     pub address: Option<SocketAddr>,
+    /// Host and port the client asked to reach, e.g. in a SOCKS5 request;
+    /// the host may be a name that has not been resolved yet
+    pub destination: Option<(String, u16)>,
 }
 
 impl Server {
@@ -125,6 +145,7 @@ impl Server {
         Self {
             connection: Connection::new(transport_protocol),
             address: None,
+            destination: None,
         }
     }
 
@@ -132,6 +153,7 @@ impl Server {
         Self {
             connection: Connection::new(transport_protocol),
             address: Some(address),
+            destination: None,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,516 @@
+//! Action Message Format (AMF0/AMF3) content view.
+//!
+//! Understands both complete AMF remoting packets (`application/x-amf`) and
+//! bare AMF0 or AMF3 values, including the AMF3 string, object and trait
+//! reference tables.
+
+use serde_json::{json, Map, Value};
+
+use super::{binary_value, content_type_matches, float_value, key_string, ContentView, Reader, MAX_DEPTH};
+use crate::{Error, Result};
+
+const CONTENT_TYPES: &[&str] = &["x-amf"];
+
+/// AMF0 marker switching the rest of the value to AMF3
+const AVMPLUS_MARKER: u8 = 0x11;
+
+/// Externalizable classes whose payload is a single AMF3 value
+const PROXY_CLASSES: &[&str] = &[
+    "flex.messaging.io.ArrayCollection",
+    "flex.messaging.io.ObjectProxy",
+    "mx.collections.ArrayCollection",
+    "mx.utils.ObjectProxy",
+];
+
+/// Renders AMF bodies as JSON
+pub struct AmfView;
+
+impl ContentView for AmfView {
+    fn name(&self) -> &'static str {
+        "amf"
+    }
+
+    fn syntax_highlight(&self) -> &'static str {
+        "json"
+    }
+
+    fn prettify(&self, data: &[u8]) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&decode(data)?)?)
+    }
+
+    fn render_priority(&self, _data: &[u8], content_type: Option<&str>) -> f64 {
+        match content_type {
+            Some(ct) if content_type_matches(ct, CONTENT_TYPES) => 1.0,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Decode an AMF remoting packet, or failing that a bare AMF0 or AMF3 value
+pub fn decode(data: &[u8]) -> Result<Value> {
+    decode_packet(data)
+        .or_else(|_| Decoder::new(data).finish(|d| d.amf0(0)))
+        .or_else(|_| Decoder::new(data).finish(|d| d.amf3(0)))
+}
+
+/// Decode an AMF remoting packet with its headers and messages
+pub fn decode_packet(data: &[u8]) -> Result<Value> {
+    let mut decoder = Decoder::new(data);
+    let version = decoder.reader.u16()?;
+    if version != 0 && version != 3 {
+        return Err(Error::invalid_request(format!("Unsupported AMF packet version {}", version)));
+    }
+
+    let mut headers = Vec::new();
+    for _ in 0..decoder.reader.u16()? {
+        let name = decoder.amf0_string()?;
+        let must_understand = decoder.reader.u8()? != 0;
+        decoder.reader.u32()?;
+        decoder.reset();
+        let value = decoder.amf0(0)?;
+        headers.push(json!({ "name": name, "must_understand": must_understand, "value": value }));
+    }
+
+    let mut messages = Vec::new();
+    for _ in 0..decoder.reader.u16()? {
+        let target = decoder.amf0_string()?;
+        let response = decoder.amf0_string()?;
+        decoder.reader.u32()?;
+        decoder.reset();
+        let body = decoder.amf0(0)?;
+        messages.push(json!({ "target": target, "response": response, "body": body }));
+    }
+
+    if !decoder.reader.is_empty() {
+        return Err(Error::invalid_request("Trailing data after AMF packet"));
+    }
+
+    Ok(json!({ "version": version, "headers": headers, "messages": messages }))
+}
+
+struct Traits {
+    class_name: String,
+    dynamic: bool,
+    externalizable: bool,
+    members: Vec<String>,
+}
+
+struct Decoder<'a> {
+    reader: Reader<'a>,
+    amf0_objects: Vec<Value>,
+    strings: Vec<String>,
+    objects: Vec<Value>,
+    traits: Vec<Traits>,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            reader: Reader::new(data),
+            amf0_objects: Vec::new(),
+            strings: Vec::new(),
+            objects: Vec::new(),
+            traits: Vec::new(),
+        }
+    }
+
+    /// Reference tables are scoped to a single packet header or message
+    fn reset(&mut self) {
+        self.amf0_objects.clear();
+        self.strings.clear();
+        self.objects.clear();
+        self.traits.clear();
+    }
+
+    fn finish(mut self, decode: impl FnOnce(&mut Self) -> Result<Value>) -> Result<Value> {
+        let value = decode(&mut self)?;
+        if !self.reader.is_empty() {
+            return Err(Error::invalid_request("Trailing data after AMF value"));
+        }
+        Ok(value)
+    }
+
+    fn check_depth(depth: usize) -> Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(Error::invalid_request("AMF nesting too deep"));
+        }
+        Ok(())
+    }
+
+    fn amf0_string(&mut self) -> Result<String> {
+        let len = self.reader.u16()? as usize;
+        self.reader.utf8(len)
+    }
+
+    fn amf0_long_string(&mut self) -> Result<String> {
+        let len = self.reader.u32()? as usize;
+        self.reader.utf8(len)
+    }
+
+    fn amf0(&mut self, depth: usize) -> Result<Value> {
+        Self::check_depth(depth)?;
+
+        let marker = self.reader.u8()?;
+        match marker {
+            0x00 => Ok(float_value(self.reader.f64()?)),
+            0x01 => Ok(Value::Bool(self.reader.u8()? != 0)),
+            0x02 => Ok(Value::String(self.amf0_string()?)),
+            0x03 => self.amf0_object(None, depth),
+            0x05 | 0x06 => Ok(Value::Null),
+            0x07 => {
+                let index = self.reader.u16()? as usize;
+                self.amf0_objects
+                    .get(index)
+                    .cloned()
+                    .ok_or_else(|| Error::invalid_request("Invalid AMF0 reference"))
+            }
+            0x08 => {
+                // The ECMA array count is advisory; properties run until the end marker
+                self.reader.u32()?;
+                self.amf0_object(None, depth)
+            }
+            0x0a => {
+                let index = self.amf0_objects.len();
+                self.amf0_objects.push(Value::Null);
+                let count = self.reader.u32()? as usize;
+                let mut items = Vec::with_capacity(count.min(1024));
+                for _ in 0..count {
+                    items.push(self.amf0(depth + 1)?);
+                }
+                let value = Value::Array(items);
+                self.amf0_objects[index] = value.clone();
+                Ok(value)
+            }
+            0x0b => {
+                let millis = self.reader.f64()?;
+                self.reader.u16()?;
+                Ok(date_value(millis))
+            }
+            0x0c | 0x0f => Ok(Value::String(self.amf0_long_string()?)),
+            0x10 => {
+                let class_name = self.amf0_string()?;
+                self.amf0_object(Some(class_name), depth)
+            }
+            AVMPLUS_MARKER => self.amf3(depth + 1),
+            _ => Err(Error::invalid_request(format!("Unsupported AMF0 marker 0x{:02x}", marker))),
+        }
+    }
+
+    fn amf0_object(&mut self, class_name: Option<String>, depth: usize) -> Result<Value> {
+        let index = self.amf0_objects.len();
+        self.amf0_objects.push(Value::Null);
+
+        let mut map = Map::new();
+        if let Some(class_name) = class_name {
+            map.insert("$class".to_string(), Value::String(class_name));
+        }
+        loop {
+            let key = self.amf0_string()?;
+            if key.is_empty() && self.reader.peek() == Some(0x09) {
+                self.reader.u8()?;
+                break;
+            }
+            let value = self.amf0(depth + 1)?;
+            map.insert(key, value);
+        }
+
+        let value = Value::Object(map);
+        self.amf0_objects[index] = value.clone();
+        Ok(value)
+    }
+
+    /// Variable-length 29-bit unsigned integer
+    fn u29(&mut self) -> Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..3 {
+            let byte = self.reader.u8()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Ok((value << 8) | self.reader.u8()? as u32)
+    }
+
+    fn amf3_string(&mut self) -> Result<String> {
+        let header = self.u29()?;
+        if header & 1 == 0 {
+            return self
+                .strings
+                .get((header >> 1) as usize)
+                .cloned()
+                .ok_or_else(|| Error::invalid_request("Invalid AMF3 string reference"));
+        }
+        let value = self.reader.utf8((header >> 1) as usize)?;
+        if !value.is_empty() {
+            self.strings.push(value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Read the header of a referencable AMF3 value. Returns the inline
+    /// length/flags, or the referenced object.
+    fn amf3_header(&mut self) -> Result<std::result::Result<u32, Value>> {
+        let header = self.u29()?;
+        if header & 1 == 0 {
+            return self
+                .objects
+                .get((header >> 1) as usize)
+                .cloned()
+                .map(Err)
+                .ok_or_else(|| Error::invalid_request("Invalid AMF3 object reference"));
+        }
+        Ok(Ok(header >> 1))
+    }
+
+    /// Reserve a slot in the object table before decoding nested values
+    fn reserve_object(&mut self) -> usize {
+        self.objects.push(Value::Null);
+        self.objects.len() - 1
+    }
+
+    fn store_object(&mut self, index: usize, value: Value) -> Value {
+        self.objects[index] = value.clone();
+        value
+    }
+
+    fn amf3(&mut self, depth: usize) -> Result<Value> {
+        Self::check_depth(depth)?;
+
+        let marker = self.reader.u8()?;
+        match marker {
+            0x00 | 0x01 => Ok(Value::Null),
+            0x02 => Ok(Value::Bool(false)),
+            0x03 => Ok(Value::Bool(true)),
+            0x04 => {
+                let value = self.u29()?;
+                // Sign-extend the 29-bit integer
+                Ok(json!(((value << 3) as i32) >> 3))
+            }
+            0x05 => Ok(float_value(self.reader.f64()?)),
+            0x06 => Ok(Value::String(self.amf3_string()?)),
+            0x07 | 0x0b => match self.amf3_header()? {
+                Err(value) => Ok(value),
+                Ok(len) => {
+                    let index = self.reserve_object();
+                    let value = Value::String(self.reader.utf8(len as usize)?);
+                    Ok(self.store_object(index, value))
+                }
+            },
+            0x08 => match self.amf3_header()? {
+                Err(value) => Ok(value),
+                Ok(_) => {
+                    let index = self.reserve_object();
+                    let value = date_value(self.reader.f64()?);
+                    Ok(self.store_object(index, value))
+                }
+            },
+            0x09 => self.amf3_array(depth),
+            0x0a => self.amf3_object(depth),
+            0x0c => match self.amf3_header()? {
+                Err(value) => Ok(value),
+                Ok(len) => {
+                    let index = self.reserve_object();
+                    let value = binary_value(self.reader.take(len as usize)?);
+                    Ok(self.store_object(index, value))
+                }
+            },
+            0x0d..=0x10 => self.amf3_vector(marker, depth),
+            0x11 => match self.amf3_header()? {
+                Err(value) => Ok(value),
+                Ok(count) => {
+                    let index = self.reserve_object();
+                    self.reader.u8()?;
+                    let mut map = Map::new();
+                    for _ in 0..count {
+                        let key = key_string(self.amf3(depth + 1)?);
+                        let value = self.amf3(depth + 1)?;
+                        map.insert(key, value);
+                    }
+                    Ok(self.store_object(index, Value::Object(map)))
+                }
+            },
+            _ => Err(Error::invalid_request(format!("Unsupported AMF3 marker 0x{:02x}", marker))),
+        }
+    }
+
+    fn amf3_array(&mut self, depth: usize) -> Result<Value> {
+        let count = match self.amf3_header()? {
+            Err(value) => return Ok(value),
+            Ok(count) => count as usize,
+        };
+        let index = self.reserve_object();
+
+        let mut assoc = Map::new();
+        loop {
+            let key = self.amf3_string()?;
+            if key.is_empty() {
+                break;
+            }
+            let value = self.amf3(depth + 1)?;
+            assoc.insert(key, value);
+        }
+
+        let mut items = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            items.push(self.amf3(depth + 1)?);
+        }
+
+        // Purely associative arrays are objects; mixed arrays keep dense items
+        // under their indices alongside the named keys.
+        let value = if assoc.is_empty() {
+            Value::Array(items)
+        } else {
+            for (i, item) in items.into_iter().enumerate() {
+                assoc.insert(i.to_string(), item);
+            }
+            Value::Object(assoc)
+        };
+        Ok(self.store_object(index, value))
+    }
+
+    fn amf3_object(&mut self, depth: usize) -> Result<Value> {
+        let header = match self.amf3_header()? {
+            Err(value) => return Ok(value),
+            Ok(header) => header,
+        };
+        let index = self.reserve_object();
+
+        let traits_index = if header & 1 == 0 {
+            let traits_index = (header >> 1) as usize;
+            if traits_index >= self.traits.len() {
+                return Err(Error::invalid_request("Invalid AMF3 traits reference"));
+            }
+            traits_index
+        } else {
+            let externalizable = header & 2 != 0;
+            let dynamic = header & 4 != 0;
+            let member_count = (header >> 3) as usize;
+            let class_name = self.amf3_string()?;
+            let mut members = Vec::with_capacity(member_count.min(1024));
+            for _ in 0..member_count {
+                members.push(self.amf3_string()?);
+            }
+            self.traits.push(Traits { class_name, dynamic, externalizable, members });
+            self.traits.len() - 1
+        };
+
+        let class_name = self.traits[traits_index].class_name.clone();
+
+        if self.traits[traits_index].externalizable {
+            if !PROXY_CLASSES.contains(&class_name.as_str()) {
+                return Err(Error::invalid_request(format!("Unsupported externalizable AMF3 class {}", class_name)));
+            }
+            let value = self.amf3(depth + 1)?;
+            return Ok(self.store_object(index, value));
+        }
+
+        let mut map = Map::new();
+        if !class_name.is_empty() {
+            map.insert("$class".to_string(), Value::String(class_name));
+        }
+        for i in 0..self.traits[traits_index].members.len() {
+            let value = self.amf3(depth + 1)?;
+            map.insert(self.traits[traits_index].members[i].clone(), value);
+        }
+        if self.traits[traits_index].dynamic {
+            loop {
+                let key = self.amf3_string()?;
+                if key.is_empty() {
+                    break;
+                }
+                let value = self.amf3(depth + 1)?;
+                map.insert(key, value);
+            }
+        }
+
+        Ok(self.store_object(index, Value::Object(map)))
+    }
+
+    fn amf3_vector(&mut self, marker: u8, depth: usize) -> Result<Value> {
+        let count = match self.amf3_header()? {
+            Err(value) => return Ok(value),
+            Ok(count) => count as usize,
+        };
+        let index = self.reserve_object();
+        self.reader.u8()?;
+        if marker == 0x10 {
+            self.amf3_string()?;
+        }
+
+        let mut items = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            items.push(match marker {
+                0x0d => json!(self.reader.u32()? as i32),
+                0x0e => json!(self.reader.u32()?),
+                0x0f => float_value(self.reader.f64()?),
+                _ => self.amf3(depth + 1)?,
+            });
+        }
+        Ok(self.store_object(index, Value::Array(items)))
+    }
+}
+
+/// AMF dates are milliseconds since the epoch in UTC
+fn date_value(millis: f64) -> Value {
+    chrono::DateTime::from_timestamp_millis(millis as i64)
+        .map(|date| Value::String(date.to_rfc3339()))
+        .unwrap_or_else(|| float_value(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amf0_string(s: &str) -> Vec<u8> {
+        let mut data = (s.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(s.as_bytes());
+        data
+    }
+
+    #[test]
+    fn test_decode_amf0_packet() {
+        let mut data = vec![0x00, 0x03, 0x00, 0x00, 0x00, 0x01];
+        data.extend(amf0_string("svc.getUser"));
+        data.extend(amf0_string("/1"));
+        data.extend([0xff, 0xff, 0xff, 0xff]);
+        // Strict array [ {id: 7.0, ok: true}, ref 1 ]
+        data.extend([0x0a, 0x00, 0x00, 0x00, 0x02, 0x03]);
+        data.extend(amf0_string("id"));
+        data.extend([0x00, 0x40, 0x1c, 0, 0, 0, 0, 0, 0]);
+        data.extend(amf0_string("ok"));
+        data.extend([0x01, 0x01, 0x00, 0x00, 0x09, 0x07, 0x00, 0x01]);
+
+        let value = decode(&data).unwrap();
+        assert_eq!(value["version"], 3);
+        assert_eq!(value["messages"][0]["target"], "svc.getUser");
+        assert_eq!(value["messages"][0]["body"], json!([{"id": 7.0, "ok": true}, {"id": 7.0, "ok": true}]));
+    }
+
+    #[test]
+    fn test_decode_amf3_object() {
+        // AVM+ switch, typed sealed+dynamic object, string reference
+        let data = [
+            0x11, 0x0a, 0x1b, 0x09, b'U', b's', b'e', b'r', 0x05, b'i', b'd', 0x04, 0xff, 0xff, 0xff, 0xff, 0x09, b'n',
+            b'a', b'm', b'e', 0x06, 0x00, 0x01,
+        ];
+        let value = decode(&data).unwrap();
+        assert_eq!(value, json!({"$class": "User", "id": -1, "name": "User"}));
+    }
+
+    #[test]
+    fn test_decode_amf3_array_collection() {
+        // ArrayCollection wrapping a dense array [1, "a"]
+        let mut data = vec![0x11, 0x0a, 0x07, 0x43];
+        data.extend_from_slice(b"flex.messaging.io.ArrayCollection");
+        data.extend([0x09, 0x05, 0x01, 0x04, 0x01, 0x06, 0x03, b'a']);
+        assert_eq!(decode(&data).unwrap(), json!([1, "a"]));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(decode(&[0x02, 0x00, 0x05, b'a']).is_err());
+        assert!(decode(&[0x07, 0x00, 0x00]).is_err());
+        assert!(decode(&[0x11, 0x0a, 0x07, 0x03, b'X']).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! Content view for `multipart/byteranges` bodies of 206 responses.
+//!
+//! Lists the ranges the parts carry, then the parts coalesced into
+//! contiguous segments of the resource, each shown as text or as a hex dump
+//! at its offset in the resource.
+
+use super::{content_type_matches, hex, ContentView};
+use crate::ranges::{byteranges, coalesce};
+use crate::{Error, Result};
+
+const CONTENT_TYPES: &[&str] = &["multipart/byteranges"];
+
+/// Renders the parts of a multi-range response
+pub struct ByteRangesView;
+
+impl ContentView for ByteRangesView {
+    fn name(&self) -> &'static str {
+        "byteranges"
+    }
+
+    fn prettify(&self, data: &[u8]) -> Result<String> {
+        let parts = byteranges(data, None).ok_or_else(|| Error::invalid_request("Invalid multipart/byteranges body"))?;
+        let mut text = format!("{} parts:\n", parts.len());
+        for part in &parts {
+            let total = part.range.total.map_or("*".to_string(), |total| total.to_string());
+            text.push_str(&format!("  bytes {}-{}/{}", part.range.start, part.range.end, total));
+            if let Some(content_type) = &part.content_type {
+                text.push_str(&format!("  {}", content_type));
+            }
+            if part.data.len() as u64 != part.range.size() {
+                text.push_str(&format!("  ({} of {} bytes)", part.data.len(), part.range.size()));
+            }
+            text.push('\n');
+        }
+        for (start, data) in coalesce(&parts).into_iter().filter(|(_, data)| !data.is_empty()) {
+            text.push_str(&format!("\nbytes {}-{}:\n", start, start + data.len() as u64 - 1));
+            match std::str::from_utf8(&data) {
+                Ok(segment) if !segment.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+                    text.push_str(segment);
+                    text.push('\n');
+                }
+                _ => text.push_str(&hex::hexdump(&data, start as usize)),
+            }
+        }
+        Ok(text)
+    }
+
+    fn render_priority(&self, _data: &[u8], content_type: Option<&str>) -> f64 {
+        if content_type.is_some_and(|ct| content_type_matches(ct, CONTENT_TYPES)) {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contentviews::registry;
+
+    #[test]
+    fn test_byteranges_view() {
+        let body = b"--B\r\nContent-Type: text/plain\r\nContent-Range: bytes 6-10/100\r\n\r\nworld\r\n\
+            --B\r\nContent-Range: bytes 0-5/100\r\n\r\nhello \r\n\
+            --B\r\nContent-Range: bytes 32-35/100\r\n\r\n\x00\x01\x02\x03\r\n--B--\r\n";
+        let rendered = registry().render("auto", body, Some("multipart/byteranges; boundary=B")).unwrap();
+        assert_eq!(rendered.view_name, "byteranges");
+        assert_eq!(
+            rendered.text,
+            "3 parts:\n  bytes 6-10/100  text/plain\n  bytes 0-5/100\n  bytes 32-35/100\n\
+             \nbytes 0-10:\nhello world\n\
+             \nbytes 32-35:\n00000020  00 01 02 03                                       |....|\n"
+        );
+        assert!(registry().render("byteranges", b"not multipart", None).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! CBOR (RFC 8949) content view.
+
+use serde_json::{json, Map, Value};
+
+use super::{binary_value, content_type_matches, float_value, key_string, ContentView, Reader, MAX_DEPTH};
+use crate::{Error, Result};
+
+const CONTENT_TYPES: &[&str] = &["cbor"];
+
+/// Self-described CBOR tag 55799 prefix
+const SELF_DESCRIBE: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// Marker terminating indefinite-length items
+const BREAK: u8 = 0xff;
+
+/// Renders CBOR bodies as JSON
+pub struct CborView;
+
+impl ContentView for CborView {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn syntax_highlight(&self) -> &'static str {
+        "json"
+    }
+
+    fn prettify(&self, data: &[u8]) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&decode(data)?)?)
+    }
+
+    fn render_priority(&self, data: &[u8], content_type: Option<&str>) -> f64 {
+        if content_type.is_some_and(|ct| content_type_matches(ct, CONTENT_TYPES)) || data.starts_with(&SELF_DESCRIBE) {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Decode a complete CBOR document
+pub fn decode(data: &[u8]) -> Result<Value> {
+    let mut reader = Reader::new(data);
+    let value = decode_value(&mut reader, 0)?;
+    if !reader.is_empty() {
+        return Err(Error::invalid_request("Trailing data after CBOR value"));
+    }
+    Ok(value)
+}
+
+fn decode_value(reader: &mut Reader, depth: usize) -> Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(Error::invalid_request("CBOR nesting too deep"));
+    }
+
+    let initial = reader.u8()?;
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+
+    if major == 7 {
+        return decode_simple(reader, info);
+    }
+
+    if info == 31 {
+        return match major {
+            2 => Ok(binary_value(&decode_indefinite_string(reader, 2)?)),
+            3 => String::from_utf8(decode_indefinite_string(reader, 3)?)
+                .map(Value::String)
+                .map_err(|e| Error::invalid_request(format!("Invalid UTF-8 string: {}", e))),
+            4 => {
+                let mut items = Vec::new();
+                while reader.peek() != Some(BREAK) {
+                    items.push(decode_value(reader, depth + 1)?);
+                }
+                reader.u8()?;
+                Ok(Value::Array(items))
+            }
+            5 => {
+                let mut map = Map::new();
+                while reader.peek() != Some(BREAK) {
+                    let key = key_string(decode_value(reader, depth + 1)?);
+                    map.insert(key, decode_value(reader, depth + 1)?);
+                }
+                reader.u8()?;
+                Ok(Value::Object(map))
+            }
+            _ => Err(Error::invalid_request("Invalid indefinite-length CBOR item")),
+        };
+    }
+
+    let arg = read_argument(reader, info)?;
+    match major {
+        0 => Ok(json!(arg)),
+        1 => Ok(match i64::try_from(arg) {
+            Ok(n) => json!(-1 - n),
+            Err(_) => json!(-1.0 - arg as f64),
+        }),
+        2 => Ok(binary_value(reader.take(to_len(arg)?)?)),
+        3 => Ok(Value::String(reader.utf8(to_len(arg)?)?)),
+        4 => {
+            let len = to_len(arg)?;
+            let mut items = Vec::with_capacity(len.min(1024));
+            for _ in 0..len {
+                items.push(decode_value(reader, depth + 1)?);
+            }
+            Ok(Value::Array(items))
+        }
+        5 => {
+            let mut map = Map::new();
+            for _ in 0..to_len(arg)? {
+                let key = key_string(decode_value(reader, depth + 1)?);
+                map.insert(key, decode_value(reader, depth + 1)?);
+            }
+            Ok(Value::Object(map))
+        }
+        _ => {
+            let value = decode_value(reader, depth + 1)?;
+            Ok(match arg {
+                // Date/time strings and epoch timestamps read fine untagged
+                0 | 1 | 55799 => value,
+                tag => json!({ "$tag": tag, "$value": value }),
+            })
+        }
+    }
+}
+
+fn read_argument(reader: &mut Reader, info: u8) -> Result<u64> {
+    match info {
+        0..=23 => Ok(info as u64),
+        24 => Ok(reader.u8()? as u64),
+        25 => Ok(reader.u16()? as u64),
+        26 => Ok(reader.u32()? as u64),
+        27 => reader.u64(),
+        _ => Err(Error::invalid_request(format!("Invalid CBOR additional info {}", info))),
+    }
+}
+
+fn to_len(arg: u64) -> Result<usize> {
+    usize::try_from(arg).map_err(|_| Error::invalid_request("CBOR length too large"))
+}
+
+fn decode_indefinite_string(reader: &mut Reader, major: u8) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let initial = reader.u8()?;
+        if initial == BREAK {
+            return Ok(data);
+        }
+        if initial >> 5 != major {
+            return Err(Error::invalid_request("Invalid chunk in indefinite-length CBOR string"));
+        }
+        let len = to_len(read_argument(reader, initial & 0x1f)?)?;
+        data.extend_from_slice(reader.take(len)?);
+    }
+}
+
+fn decode_simple(reader: &mut Reader, info: u8) -> Result<Value> {
+    match info {
+        20 => Ok(Value::Bool(false)),
+        21 => Ok(Value::Bool(true)),
+        22 | 23 => Ok(Value::Null),
+        24 => Ok(json!({ "$simple": reader.u8()? })),
+        25 => Ok(float_value(half_to_f64(reader.u16()?))),
+        26 => Ok(float_value(reader.f32()? as f64)),
+        27 => Ok(float_value(reader.f64()?)),
+        0..=19 => Ok(json!({ "$simple": info })),
+        _ => Err(Error::invalid_request("Unexpected CBOR break or reserved value")),
+    }
+}
+
+fn half_to_f64(half: u16) -> f64 {
+    let exponent = (half >> 10) & 0x1f;
+    let mantissa = (half & 0x3ff) as f64;
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent as i32 - 25),
+    };
+    if half & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_map() {
+        // {"a": 1, "b": [2, -3], "c": h'0102', "d": 1.5 (half)}
+        let data = [
+            0xa4, 0x61, b'a', 0x01, 0x61, b'b', 0x82, 0x02, 0x22, 0x61, b'c', 0x42, 0x01, 0x02, 0x61, b'd', 0xf9, 0x3e,
+            0x00,
+        ];
+        let value = decode(&data).unwrap();
+        assert_eq!(value, json!({"a": 1, "b": [2, -3], "c": {"$binary": "AQI="}, "d": 1.5}));
+    }
+
+    #[test]
+    fn test_decode_indefinite_and_tags() {
+        // 55799([_ "ab", "c"]) -> indefinite array with an indefinite string
+        let data = [0xd9, 0xd9, 0xf7, 0x9f, 0x7f, 0x61, b'a', 0x61, b'b', 0xff, 0x61, b'c', 0xff];
+        assert_eq!(decode(&data).unwrap(), json!(["ab", "c"]));
+
+        // 32("http://a") keeps the tag
+        let data = [0xd8, 0x20, 0x68, b'h', b't', b't', b'p', b':', b'/', b'/', b'a'];
+        assert_eq!(decode(&data).unwrap(), json!({"$tag": 32, "$value": "http://a"}));
+    }
+
+    #[test]
+    fn test_decode_simple_values() {
+        assert_eq!(decode(&[0x83, 0xf4, 0xf5, 0xf6]).unwrap(), json!([false, true, null]));
+        assert_eq!(decode(&[0xf9, 0xc4, 0x00]).unwrap(), json!(-4.0));
+        assert_eq!(decode(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).unwrap(), json!(-1.0 - u64::MAX as f64));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(decode(&[0x82, 0x01]).is_err());
+        assert!(decode(&[0xff]).is_err());
+        assert!(decode(&[0x01, 0x02]).is_err());
+        assert!(decode(&[0x81; 200]).is_err());
+    }
+
+    #[test]
+    fn test_render_priority() {
+        assert_eq!(CborView.render_priority(&[0xd9, 0xd9, 0xf7, 0x01], None), 1.0);
+        assert_eq!(CborView.render_priority(&[0xa0], Some("application/cbor")), 1.0);
+        assert_eq!(CborView.render_priority(&[0xa0], None), 0.0);
+    }
+}
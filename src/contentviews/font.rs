@@ -0,0 +1,230 @@
+//! Font content view for TrueType, OpenType and WOFF files.
+
+use serde::Serialize;
+
+use super::{content_type_matches, ContentView, Reader};
+use crate::{Error, Result};
+
+const CONTENT_TYPES: &[&str] = &["font/", "application/font", "application/x-font", "application/vnd.ms-fontobject"];
+
+/// Metadata read from a font's table directory
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FontInfo {
+    pub format: String,
+    pub num_tables: u16,
+    pub num_fonts: Option<u32>,
+    pub family_name: Option<String>,
+    pub full_name: Option<String>,
+    pub num_glyphs: Option<u16>,
+}
+
+/// Renders a summary of font metadata
+pub struct FontView;
+
+impl ContentView for FontView {
+    fn name(&self) -> &'static str {
+        "font"
+    }
+
+    fn prettify(&self, data: &[u8]) -> Result<String> {
+        let info = font_info(data)?;
+        let mut lines = vec![format!("Format: {}", info.format)];
+        if let Some(num_fonts) = info.num_fonts {
+            lines.push(format!("Fonts: {}", num_fonts));
+        }
+        lines.push(format!("Tables: {}", info.num_tables));
+        if let Some(family) = &info.family_name {
+            lines.push(format!("Family: {}", family));
+        }
+        if let Some(full_name) = &info.full_name {
+            lines.push(format!("Name: {}", full_name));
+        }
+        if let Some(num_glyphs) = info.num_glyphs {
+            lines.push(format!("Glyphs: {}", num_glyphs));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn render_priority(&self, data: &[u8], content_type: Option<&str>) -> f64 {
+        if font_format(data).is_none() {
+            return 0.0;
+        }
+        match content_type {
+            Some(ct) if content_type_matches(ct, CONTENT_TYPES) => 1.0,
+            _ => 0.8,
+        }
+    }
+}
+
+fn font_format(data: &[u8]) -> Option<&'static str> {
+    match data.get(..4)? {
+        [0x00, 0x01, 0x00, 0x00] | b"true" => Some("TrueType"),
+        b"OTTO" => Some("OpenType (CFF)"),
+        b"ttcf" => Some("TrueType Collection"),
+        b"wOFF" => Some("WOFF"),
+        b"wOF2" => Some("WOFF2"),
+        _ => None,
+    }
+}
+
+/// Read format, names and glyph count from a font file
+pub fn font_info(data: &[u8]) -> Result<FontInfo> {
+    let format = font_format(data).ok_or_else(|| Error::invalid_request("Unrecognized font format"))?;
+    let mut info = FontInfo { format: format.to_string(), ..Default::default() };
+    let mut reader = Reader::new(data);
+    reader.take(4)?;
+
+    match format {
+        "TrueType Collection" => {
+            reader.u32()?;
+            let num_fonts = reader.u32()?;
+            info.num_fonts = Some(num_fonts);
+            // Describe the first font of the collection
+            if num_fonts > 0 {
+                let offset = reader.u32()? as usize;
+                read_sfnt(data, offset, &mut info)?;
+            }
+        }
+        "WOFF" | "WOFF2" => {
+            reader.take(8)?;
+            info.num_tables = reader.u16()?;
+            if format == "WOFF" {
+                read_woff_tables(data, &mut info)?;
+            }
+        }
+        _ => read_sfnt(data, 0, &mut info)?,
+    }
+
+    Ok(info)
+}
+
+/// Read an sfnt table directory at `start`. Table offsets are relative to
+/// the start of the file, also within collections.
+fn read_sfnt(data: &[u8], start: usize, info: &mut FontInfo) -> Result<()> {
+    let mut reader = Reader::new(data);
+    reader.take(start + 4)?;
+    info.num_tables = reader.u16()?;
+    reader.take(6)?;
+    for _ in 0..info.num_tables {
+        let tag = reader.take(4)?;
+        reader.u32()?;
+        let offset = reader.u32()? as usize;
+        let length = reader.u32()? as usize;
+        if let Some(table) = data.get(offset..offset.saturating_add(length)) {
+            read_table(tag, table, info);
+        }
+    }
+    Ok(())
+}
+
+/// WOFF tables may be zlib-compressed; only uncompressed ones are inspected
+fn read_woff_tables(data: &[u8], info: &mut FontInfo) -> Result<()> {
+    let mut reader = Reader::new(data);
+    reader.take(44)?;
+    for _ in 0..info.num_tables {
+        let tag = reader.take(4)?;
+        let offset = reader.u32()? as usize;
+        let compressed_length = reader.u32()? as usize;
+        let original_length = reader.u32()? as usize;
+        reader.u32()?;
+        if compressed_length == original_length {
+            if let Some(table) = data.get(offset..offset.saturating_add(original_length)) {
+                read_table(tag, table, info);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_table(tag: &[u8], table: &[u8], info: &mut FontInfo) {
+    match tag {
+        b"maxp" if table.len() >= 6 => {
+            info.num_glyphs = Some(u16::from_be_bytes([table[4], table[5]]));
+        }
+        b"name" => {
+            info.family_name = name_record(table, 1);
+            info.full_name = name_record(table, 4);
+        }
+        _ => {}
+    }
+}
+
+/// Look up a name record, preferring Windows Unicode over Macintosh Roman
+fn name_record(table: &[u8], name_id: u16) -> Option<String> {
+    let mut reader = Reader::new(table);
+    reader.u16().ok()?;
+    let count = reader.u16().ok()?;
+    let storage = reader.u16().ok()? as usize;
+
+    let mut fallback = None;
+    for _ in 0..count {
+        let platform = reader.u16().ok()?;
+        reader.u16().ok()?;
+        reader.u16().ok()?;
+        let id = reader.u16().ok()?;
+        let length = reader.u16().ok()? as usize;
+        let offset = reader.u16().ok()? as usize;
+        if id != name_id {
+            continue;
+        }
+
+        let start = storage + offset;
+        let Some(bytes) = table.get(start..start + length) else {
+            continue;
+        };
+        match platform {
+            0 | 3 => {
+                let units: Vec<u16> = bytes.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+                return Some(String::from_utf16_lossy(&units));
+            }
+            1 if fallback.is_none() => {
+                fallback = Some(bytes.iter().map(|&b| b as char).collect());
+            }
+            _ => {}
+        }
+    }
+    fallback
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal TrueType file with `name` and `maxp` tables
+    fn ttf() -> Vec<u8> {
+        let family: Vec<u8> = "Demo".encode_utf16().flat_map(|u| u.to_be_bytes()).collect();
+        let mut name = vec![0, 0, 0, 1, 0, 18, 0, 3, 0, 1, 0x04, 0x09, 0, 1, 0, family.len() as u8, 0, 0];
+        name.extend(&family);
+        let maxp = vec![0, 0, 0x50, 0, 0x01, 0x2c];
+
+        let mut data = vec![0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+        let name_offset = 12 + 32;
+        let maxp_offset = name_offset + name.len();
+        for (tag, offset, len) in [(b"name", name_offset, name.len()), (b"maxp", maxp_offset, maxp.len())] {
+            data.extend(tag);
+            data.extend([0; 4]);
+            data.extend((offset as u32).to_be_bytes());
+            data.extend((len as u32).to_be_bytes());
+        }
+        data.extend(name);
+        data.extend(maxp);
+        data
+    }
+
+    #[test]
+    fn test_truetype_info() {
+        let info = font_info(&ttf()).unwrap();
+        assert_eq!(info.format, "TrueType");
+        assert_eq!(info.num_tables, 2);
+        assert_eq!(info.family_name.as_deref(), Some("Demo"));
+        assert_eq!(info.num_glyphs, Some(300));
+    }
+
+    #[test]
+    fn test_render_priority() {
+        assert_eq!(FontView.render_priority(&ttf(), Some("font/ttf")), 1.0);
+        assert_eq!(FontView.render_priority(b"wOF2\0\0\0\0", None), 0.8);
+        assert_eq!(FontView.render_priority(b"hello", Some("font/ttf")), 0.0);
+        assert!(font_info(b"wOFF").is_err());
+    }
+}
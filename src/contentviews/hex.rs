@@ -0,0 +1,203 @@
+//! Hex dump content view for binary bodies of unknown format.
+//!
+//! Renders the classic offset/hex/ASCII dump, followed by the printable
+//! strings found in the data and preceded by the file type its magic bytes
+//! identify. The content view API renders a range of the body with its
+//! real offsets, so large bodies can be paged through.
+
+use super::ContentView;
+use crate::Result;
+
+/// Bytes shown per dump line
+const BYTES_PER_LINE: usize = 16;
+
+/// Shortest run of printable characters listed as a string, by default
+pub const DEFAULT_MIN_STRING_LENGTH: usize = 4;
+
+/// Magic bytes at a fixed offset and the file type they identify
+const MAGIC: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "PNG image"),
+    (0, b"\xff\xd8\xff", "JPEG image"),
+    (0, b"GIF87a", "GIF image"),
+    (0, b"GIF89a", "GIF image"),
+    (0, b"BM", "BMP image"),
+    (0, b"\x00\x00\x01\x00", "ICO image"),
+    (0, b"II*\x00", "TIFF image"),
+    (0, b"MM\x00*", "TIFF image"),
+    (8, b"WEBP", "WebP image"),
+    (8, b"WAVE", "WAV audio"),
+    (8, b"AVI ", "AVI video"),
+    (4, b"ftyp", "MP4/QuickTime media"),
+    (0, b"OggS", "Ogg media"),
+    (0, b"ID3", "MP3 audio"),
+    (0, b"fLaC", "FLAC audio"),
+    (0, b"%PDF-", "PDF document"),
+    (0, b"PK\x03\x04", "ZIP archive"),
+    (0, b"PK\x05\x06", "ZIP archive (empty)"),
+    (0, b"\x1f\x8b", "gzip data"),
+    (0, b"\x28\xb5\x2f\xfd", "Zstandard data"),
+    (0, b"BZh", "bzip2 data"),
+    (0, b"\xfd7zXZ\x00", "xz data"),
+    (0, b"7z\xbc\xaf\x27\x1c", "7-Zip archive"),
+    (0, b"Rar!\x1a\x07", "RAR archive"),
+    (257, b"ustar", "tar archive"),
+    (0, b"\x7fELF", "ELF executable"),
+    (0, b"MZ", "Windows executable"),
+    (0, b"\xcf\xfa\xed\xfe", "Mach-O executable"),
+    (0, b"\xca\xfe\xba\xbe", "Java class or Mach-O universal binary"),
+    (0, b"dex\n", "Android DEX"),
+    (0, b"\x00asm", "WebAssembly module"),
+    (0, b"SQLite format 3\x00", "SQLite database"),
+    (0, b"wOFF", "WOFF font"),
+    (0, b"wOF2", "WOFF2 font"),
+    (0, b"OTTO", "OpenType font"),
+    (0, b"\x00\x01\x00\x00\x00", "TrueType font"),
+    (0, b"-----BEGIN ", "PEM data"),
+    (0, b"\x30\x82", "DER data (certificate or key)"),
+    (0, b"bplist", "Binary property list"),
+];
+
+/// Renders a hex dump, strings and the detected file type
+pub struct HexView;
+
+impl ContentView for HexView {
+    fn name(&self) -> &'static str {
+        "hex"
+    }
+
+    fn prettify(&self, data: &[u8]) -> Result<String> {
+        Ok(render(data, 0, file_type(data), DEFAULT_MIN_STRING_LENGTH))
+    }
+
+    fn render_priority(&self, data: &[u8], _content_type: Option<&str>) -> f64 {
+        // Better than garbled text for anything that is not text
+        if is_binary(data) {
+            0.1
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Dump of `data`, which starts at `offset` in the body, followed by its
+/// strings of at least `min_string_length` characters; `0` leaves them out
+pub fn render(data: &[u8], offset: usize, file_type: Option<&str>, min_string_length: usize) -> String {
+    let mut text = String::new();
+    if let Some(file_type) = file_type {
+        text.push_str(&format!("Type: {}\n\n", file_type));
+    }
+    text.push_str(&hexdump(data, offset));
+    if min_string_length > 0 {
+        let found = strings(data, min_string_length);
+        text.push_str(&format!("\nStrings ({}, at least {} characters):\n", found.len(), min_string_length));
+        for (at, string) in found {
+            text.push_str(&format!("{:08x}  {}\n", offset + at, string));
+        }
+    }
+    text
+}
+
+/// Offset, hex and ASCII columns, 16 bytes a line
+pub fn hexdump(data: &[u8], offset: usize) -> String {
+    let mut dump = String::with_capacity(data.len().div_ceil(BYTES_PER_LINE) * 78);
+    for (line, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
+        dump.push_str(&format!("{:08x}  ", offset + line * BYTES_PER_LINE));
+        for i in 0..BYTES_PER_LINE {
+            match chunk.get(i) {
+                Some(byte) => dump.push_str(&format!("{:02x} ", byte)),
+                None => dump.push_str("   "),
+            }
+            if i == BYTES_PER_LINE / 2 - 1 {
+                dump.push(' ');
+            }
+        }
+        dump.push_str(" |");
+        dump.extend(chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+/// Runs of at least `min_length` printable ASCII characters, with their
+/// offsets in `data`
+pub fn strings(data: &[u8], min_length: usize) -> Vec<(usize, String)> {
+    let mut found = Vec::new();
+    let mut start = None;
+    for (i, &byte) in data.iter().chain(std::iter::once(&0)).enumerate() {
+        let printable = byte.is_ascii_graphic() || byte == b' ' || byte == b'\t';
+        match (printable, start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                if i - s >= min_length {
+                    found.push((s, String::from_utf8_lossy(&data[s..i]).into_owned()));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+/// File type identified by the magic bytes at the start of `data`
+pub fn file_type(data: &[u8]) -> Option<&'static str> {
+    MAGIC
+        .iter()
+        .find(|(offset, magic, _)| data.get(*offset..offset + magic.len()) == Some(*magic))
+        .map(|(_, _, name)| *name)
+}
+
+/// Whether `data` is not text: not UTF-8, or holding control characters
+fn is_binary(data: &[u8]) -> bool {
+    match std::str::from_utf8(data) {
+        Ok(text) => text.chars().any(|c| c.is_control() && !c.is_whitespace()),
+        Err(e) => e.error_len().is_some() || e.valid_up_to() == 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contentviews::registry;
+
+    #[test]
+    fn test_hexdump() {
+        let dump = hexdump(b"\x00\x01GET / HTTP/1.1\r\nHost", 0x20);
+        assert_eq!(
+            dump,
+            "00000020  00 01 47 45 54 20 2f 20  48 54 54 50 2f 31 2e 31  |..GET / HTTP/1.1|\n\
+             00000030  0d 0a 48 6f 73 74                                 |..Host|\n"
+        );
+        assert_eq!(hexdump(b"", 0), "");
+    }
+
+    #[test]
+    fn test_strings_and_file_type() {
+        let data = b"\x7fELF\x02\x01\x00\x00libc.so.6\x00ab\x00GLIBC_2.34";
+        assert_eq!(file_type(data), Some("ELF executable"));
+        assert_eq!(file_type(b"hello"), None);
+        assert_eq!(
+            strings(data, 4),
+            [(8, "libc.so.6".to_string()), (21, "GLIBC_2.34".to_string())]
+        );
+        assert_eq!(strings(data, 3)[0], (1, "ELF".to_string()));
+        assert_eq!(strings(data, 2).len(), 4);
+
+        let rendered = render(data, 0x100, file_type(data), 4);
+        assert!(rendered.starts_with("Type: ELF executable\n\n00000100  7f 45 4c 46"));
+        assert!(rendered.ends_with("Strings (2, at least 4 characters):\n00000108  libc.so.6\n00000115  GLIBC_2.34\n"));
+        assert!(!render(data, 0, None, 0).contains("Strings"));
+    }
+
+    #[test]
+    fn test_auto_picks_hex_for_binary() {
+        let binary = b"\x00\x00\x00\x2aproto\xff\xfe";
+        assert_eq!(registry().render("auto", binary, Some("application/octet-stream")).unwrap().view_name, "hex");
+        assert_eq!(registry().render("auto", "héllo\n".as_bytes(), None).unwrap().view_name, "text");
+        // A PNG is still shown by the image view
+        let png = image::RgbaImage::new(1, 1);
+        let mut encoded = std::io::Cursor::new(Vec::new());
+        png.write_to(&mut encoded, image::ImageFormat::Png).unwrap();
+        assert_eq!(registry().render("auto", encoded.get_ref(), None).unwrap().view_name, "image");
+    }
+}
@@ -0,0 +1,169 @@
+//! Image content view and thumbnail previews.
+
+use image::{ImageDecoder, ImageFormat, ImageReader, Limits};
+use serde::Serialize;
+use std::io::Cursor;
+
+use super::{content_type_matches, ContentView};
+use crate::{Error, Result};
+
+/// Default edge length of generated previews, in pixels
+pub const DEFAULT_PREVIEW_SIZE: u32 = 128;
+
+/// Largest preview edge length that may be requested
+pub const MAX_PREVIEW_SIZE: u32 = 1024;
+
+/// Decoding limits guarding against decompression bombs
+const MAX_DIMENSION: u32 = 16384;
+const MAX_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// Metadata extracted from an image without decoding its pixels
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageInfo {
+    pub format: String,
+    pub mime_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub color_type: String,
+    pub bits_per_pixel: u16,
+    pub has_alpha: bool,
+    pub has_exif: bool,
+    pub exif_length: Option<usize>,
+}
+
+/// Renders a summary of image metadata
+pub struct ImageView;
+
+impl ContentView for ImageView {
+    fn name(&self) -> &'static str {
+        "image"
+    }
+
+    fn prettify(&self, data: &[u8]) -> Result<String> {
+        let info = image_info(data)?;
+        let mut lines = vec![
+            format!("Format: {} ({})", info.format, info.mime_type),
+            format!("Size: {} x {} px", info.width, info.height),
+            format!("Color: {}, {} bits per pixel", info.color_type, info.bits_per_pixel),
+            format!("Alpha: {}", if info.has_alpha { "yes" } else { "no" }),
+        ];
+        lines.push(match info.exif_length {
+            Some(len) => format!("EXIF: present ({} bytes)", len),
+            None => "EXIF: none".to_string(),
+        });
+        Ok(lines.join("\n"))
+    }
+
+    fn render_priority(&self, data: &[u8], content_type: Option<&str>) -> f64 {
+        if image::guess_format(data).is_err() {
+            return 0.0;
+        }
+        match content_type {
+            Some(ct) if content_type_matches(ct, &["image/"]) => 1.0,
+            _ => 0.8,
+        }
+    }
+}
+
+fn reader(data: &[u8]) -> Result<ImageReader<Cursor<&[u8]>>> {
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    reader.limits(limits);
+    Ok(reader)
+}
+
+fn image_error(e: image::ImageError) -> Error {
+    Error::invalid_request(format!("Cannot decode image: {}", e))
+}
+
+/// Read format, dimensions, color depth and EXIF presence from image headers
+pub fn image_info(data: &[u8]) -> Result<ImageInfo> {
+    let reader = reader(data)?;
+    let format = reader
+        .format()
+        .ok_or_else(|| Error::invalid_request("Unrecognized image format"))?;
+    let mut decoder = reader.into_decoder().map_err(image_error)?;
+
+    let (width, height) = decoder.dimensions();
+    let color_type = decoder.original_color_type();
+    let exif = decoder.exif_metadata().map_err(image_error)?;
+
+    Ok(ImageInfo {
+        format: format!("{:?}", format).to_uppercase(),
+        mime_type: format.to_mime_type().to_string(),
+        width,
+        height,
+        color_type: format!("{:?}", color_type),
+        bits_per_pixel: color_type.bits_per_pixel(),
+        has_alpha: decoder.color_type().has_alpha(),
+        has_exif: exif.is_some(),
+        exif_length: exif.map(|exif| exif.len()),
+    })
+}
+
+/// Downscale an image to fit within `size` x `size` pixels, encoded as PNG
+pub fn preview(data: &[u8], size: u32) -> Result<Vec<u8>> {
+    let size = size.clamp(1, MAX_PREVIEW_SIZE);
+    let image = reader(data)?.decode().map_err(image_error)?;
+
+    // Never upscale images that are already small enough
+    let thumbnail = if image.width() <= size && image.height() <= size {
+        image
+    } else {
+        image.thumbnail(size, size)
+    };
+
+    let mut output = Cursor::new(Vec::new());
+    thumbnail
+        .write_to(&mut output, ImageFormat::Png)
+        .map_err(|e| Error::internal(format!("Cannot encode preview: {}", e)))?;
+    Ok(output.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbaImage::from_pixel(width, height, Rgba([255, 0, 0, 128]));
+        let mut output = Cursor::new(Vec::new());
+        image.write_to(&mut output, ImageFormat::Png).unwrap();
+        output.into_inner()
+    }
+
+    #[test]
+    fn test_image_info() {
+        let info = image_info(&png(40, 20)).unwrap();
+        assert_eq!(info.format, "PNG");
+        assert_eq!(info.mime_type, "image/png");
+        assert_eq!((info.width, info.height), (40, 20));
+        assert_eq!(info.bits_per_pixel, 32);
+        assert!(info.has_alpha);
+        assert!(!info.has_exif);
+
+        assert!(image_info(b"not an image").is_err());
+    }
+
+    #[test]
+    fn test_preview_downscales() {
+        let thumbnail = preview(&png(400, 200), 100).unwrap();
+        let info = image_info(&thumbnail).unwrap();
+        assert_eq!((info.width, info.height), (100, 50));
+
+        let unchanged = preview(&png(10, 10), 100).unwrap();
+        assert_eq!(image_info(&unchanged).unwrap().width, 10);
+    }
+
+    #[test]
+    fn test_render() {
+        let view = ImageView;
+        let data = png(2, 3);
+        assert_eq!(view.render_priority(&data, Some("image/png")), 1.0);
+        assert_eq!(view.render_priority(b"<svg/>", Some("image/svg+xml")), 0.0);
+        assert!(view.prettify(&data).unwrap().contains("Size: 2 x 3 px"));
+    }
+}
@@ -0,0 +1,334 @@
+//! Content views render message bodies into a human-readable form.
+//!
+//! Each view decodes a body and pretty-prints it. When the `auto` view is
+//! requested, the view with the highest render priority for the given body
+//! and content type is used, falling back to plain text.
+
+pub mod amf;
+pub mod byteranges;
+pub mod cbor;
+pub mod font;
+pub mod hex;
+pub mod image;
+pub mod msgpack;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+
+use crate::{Error, Result};
+
+/// Maximum nesting depth accepted by the binary decoders
+pub(crate) const MAX_DEPTH: usize = 64;
+
+/// A decoder that turns a message body into readable text
+pub trait ContentView: Send + Sync {
+    /// Name used to select the view through the API
+    fn name(&self) -> &'static str;
+
+    /// Syntax highlighting hint for the rendered text
+    fn syntax_highlight(&self) -> &'static str {
+        "none"
+    }
+
+    /// Render the body, failing if it cannot be decoded by this view
+    fn prettify(&self, data: &[u8]) -> Result<String>;
+
+    /// How well this view fits the body. Zero means the view does not apply.
+    fn render_priority(&self, data: &[u8], content_type: Option<&str>) -> f64;
+}
+
+/// Output of a content view
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderedContent {
+    pub text: String,
+    pub view_name: String,
+    pub syntax_highlight: String,
+    pub description: String,
+}
+
+/// Collection of available content views
+pub struct ContentViewRegistry {
+    views: Vec<Box<dyn ContentView>>,
+}
+
+impl Default for ContentViewRegistry {
+    fn default() -> Self {
+        let mut registry = Self { views: Vec::new() };
+        registry.add(Box::new(TextView::new("text", "none", &["text/plain"])));
+        registry.add(Box::new(JsonView));
+        registry.add(Box::new(TextView::new("xml", "xml", &["xml"])));
+        registry.add(Box::new(TextView::new("html", "html", &["text/html"])));
+        registry.add(Box::new(msgpack::MsgPackView));
+        registry.add(Box::new(cbor::CborView));
+        registry.add(Box::new(amf::AmfView));
+        registry.add(Box::new(image::ImageView));
+        registry.add(Box::new(font::FontView));
+        registry.add(Box::new(byteranges::ByteRangesView));
+        registry.add(Box::new(hex::HexView));
+        registry
+    }
+}
+
+impl ContentViewRegistry {
+    /// Register a view, replacing any existing view with the same name
+    pub fn add(&mut self, view: Box<dyn ContentView>) {
+        self.views.retain(|v| v.name() != view.name());
+        self.views.push(view);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn ContentView> {
+        self.views.iter().find(|v| v.name() == name).map(|v| v.as_ref())
+    }
+
+    /// Names of all views, including the `auto` pseudo-view
+    pub fn names(&self) -> Vec<&'static str> {
+        std::iter::once("auto")
+            .chain(self.views.iter().map(|v| v.name()))
+            .collect()
+    }
+
+    /// Pick the best view for a body
+    pub fn auto_view(&self, data: &[u8], content_type: Option<&str>) -> &dyn ContentView {
+        let mut best: Option<(f64, &dyn ContentView)> = None;
+        for view in &self.views {
+            let priority = view.render_priority(data, content_type);
+            if priority > best.map_or(0.0, |(p, _)| p) {
+                best = Some((priority, view.as_ref()));
+            }
+        }
+
+        best.map(|(_, view)| view)
+            .or_else(|| self.get("text"))
+            .expect("text view is always registered")
+    }
+
+    /// Render a body with the named view, or the best matching view for `auto`
+    pub fn render(&self, name: &str, data: &[u8], content_type: Option<&str>) -> Result<RenderedContent> {
+        let view = if name == "auto" {
+            self.auto_view(data, content_type)
+        } else {
+            self.get(name)
+                .ok_or_else(|| Error::invalid_request(format!("Unknown content view: {}", name)))?
+        };
+
+        // An explicitly selected view that cannot decode the body is an error;
+        // the auto view falls back to text instead.
+        let text = match view.prettify(data) {
+            Ok(text) => text,
+            Err(e) if name != "auto" => return Err(e),
+            Err(_) => return self.render("text", data, content_type),
+        };
+
+        let description = match name {
+            "auto" => format!("[auto] {}", view.name()),
+            _ => view.name().to_string(),
+        };
+
+        Ok(RenderedContent {
+            text,
+            view_name: view.name().to_string(),
+            syntax_highlight: view.syntax_highlight().to_string(),
+            description,
+        })
+    }
+}
+
+/// Shared registry of the built-in content views
+pub fn registry() -> &'static ContentViewRegistry {
+    static REGISTRY: OnceLock<ContentViewRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(ContentViewRegistry::default)
+}
+
+/// Plain text rendering, selected by content type
+struct TextView {
+    name: &'static str,
+    highlight: &'static str,
+    content_types: &'static [&'static str],
+}
+
+impl TextView {
+    fn new(name: &'static str, highlight: &'static str, content_types: &'static [&'static str]) -> Self {
+        Self { name, highlight, content_types }
+    }
+}
+
+impl ContentView for TextView {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn syntax_highlight(&self) -> &'static str {
+        self.highlight
+    }
+
+    fn prettify(&self, data: &[u8]) -> Result<String> {
+        Ok(String::from_utf8_lossy(data).into_owned())
+    }
+
+    fn render_priority(&self, _data: &[u8], content_type: Option<&str>) -> f64 {
+        match content_type {
+            Some(ct) if content_type_matches(ct, self.content_types) => 0.5,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Pretty-printed JSON
+struct JsonView;
+
+impl ContentView for JsonView {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn syntax_highlight(&self) -> &'static str {
+        "json"
+    }
+
+    fn prettify(&self, data: &[u8]) -> Result<String> {
+        let value: Value = serde_json::from_slice(data)?;
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+
+    fn render_priority(&self, data: &[u8], content_type: Option<&str>) -> f64 {
+        match content_type {
+            Some(ct) if content_type_matches(ct, &["json"]) => 1.0,
+            _ if serde_json::from_slice::<Value>(data).is_ok_and(|v| v.is_object() || v.is_array()) => 0.3,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Whether a Content-Type header value contains any of the given fragments
+pub(crate) fn content_type_matches(content_type: &str, candidates: &[&str]) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    candidates.iter().any(|c| mime.contains(c))
+}
+
+/// JSON representation of binary data embedded in a decoded document
+pub(crate) fn binary_value(data: &[u8]) -> Value {
+    json!({ "$binary": STANDARD.encode(data) })
+}
+
+/// JSON object key for a decoded map key of arbitrary type
+pub(crate) fn key_string(key: Value) -> String {
+    match key {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+/// Big-endian cursor over a binary body
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub(crate) fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| Error::invalid_request("Unexpected end of data"))?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub(crate) fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    pub(crate) fn utf8(&mut self, len: usize) -> Result<String> {
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| Error::invalid_request(format!("Invalid UTF-8 string: {}", e)))
+    }
+}
+
+/// JSON number for a float, or null for NaN and infinities
+pub(crate) fn float_value(value: f64) -> Value {
+    serde_json::Number::from_f64(value).map(Value::Number).unwrap_or(Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_include_auto() {
+        let names = registry().names();
+        assert_eq!(names[0], "auto");
+        assert!(names.contains(&"msgpack"));
+        assert!(names.contains(&"cbor"));
+        assert!(names.contains(&"amf"));
+        assert!(names.contains(&"hex"));
+        assert!(names.contains(&"byteranges"));
+    }
+
+    #[test]
+    fn test_auto_prefers_json_content_type() {
+        let rendered = registry()
+            .render("auto", br#"{"a":1}"#, Some("application/json; charset=utf-8"))
+            .unwrap();
+        assert_eq!(rendered.view_name, "json");
+        assert_eq!(rendered.text, "{\n  \"a\": 1\n}");
+    }
+
+    #[test]
+    fn test_auto_falls_back_to_text() {
+        let rendered = registry().render("auto", b"hello", None).unwrap();
+        assert_eq!(rendered.view_name, "text");
+        assert_eq!(rendered.text, "hello");
+    }
+
+    #[test]
+    fn test_explicit_view_errors() {
+        assert!(registry().render("json", b"not json", None).is_err());
+        assert!(registry().render("nope", b"", None).is_err());
+    }
+
+    #[test]
+    fn test_reader_bounds() {
+        let mut reader = Reader::new(&[0x01, 0x02, 0x03]);
+        assert_eq!(reader.u16().unwrap(), 0x0102);
+        assert!(reader.u16().is_err());
+        assert_eq!(reader.u8().unwrap(), 0x03);
+        assert!(reader.is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+//! MessagePack content view.
+
+use serde_json::{json, Map, Value};
+
+use super::{binary_value, content_type_matches, float_value, key_string, ContentView, Reader, MAX_DEPTH};
+use crate::{Error, Result};
+
+const CONTENT_TYPES: &[&str] = &["msgpack"];
+
+/// Renders MessagePack bodies as JSON
+pub struct MsgPackView;
+
+impl ContentView for MsgPackView {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn syntax_highlight(&self) -> &'static str {
+        "json"
+    }
+
+    fn prettify(&self, data: &[u8]) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&decode(data)?)?)
+    }
+
+    fn render_priority(&self, data: &[u8], content_type: Option<&str>) -> f64 {
+        if content_type.is_some_and(|ct| content_type_matches(ct, CONTENT_TYPES)) {
+            return 1.0;
+        }
+        // Without a content type, only claim bodies that start with a map or
+        // array, are not text, and decode completely.
+        let container = matches!(data.first(), Some(0x80..=0x9f | 0xdc..=0xdf));
+        if container && std::str::from_utf8(data).is_err() && decode(data).is_ok() {
+            0.4
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Decode a complete MessagePack document
+pub fn decode(data: &[u8]) -> Result<Value> {
+    let mut reader = Reader::new(data);
+    let value = decode_value(&mut reader, 0)?;
+    if !reader.is_empty() {
+        return Err(Error::invalid_request("Trailing data after MessagePack value"));
+    }
+    Ok(value)
+}
+
+fn decode_value(reader: &mut Reader, depth: usize) -> Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(Error::invalid_request("MessagePack nesting too deep"));
+    }
+
+    let marker = reader.u8()?;
+    let value = match marker {
+        0x00..=0x7f => json!(marker),
+        0x80..=0x8f => decode_map(reader, (marker & 0x0f) as usize, depth)?,
+        0x90..=0x9f => decode_array(reader, (marker & 0x0f) as usize, depth)?,
+        0xa0..=0xbf => Value::String(reader.utf8((marker & 0x1f) as usize)?),
+        0xc0 => Value::Null,
+        0xc2 => Value::Bool(false),
+        0xc3 => Value::Bool(true),
+        0xc4 => {
+            let len = reader.u8()? as usize;
+            binary_value(reader.take(len)?)
+        }
+        0xc5 => {
+            let len = reader.u16()? as usize;
+            binary_value(reader.take(len)?)
+        }
+        0xc6 => {
+            let len = reader.u32()? as usize;
+            binary_value(reader.take(len)?)
+        }
+        0xc7 => {
+            let len = reader.u8()? as usize;
+            decode_ext(reader, len)?
+        }
+        0xc8 => {
+            let len = reader.u16()? as usize;
+            decode_ext(reader, len)?
+        }
+        0xc9 => {
+            let len = reader.u32()? as usize;
+            decode_ext(reader, len)?
+        }
+        0xca => float_value(reader.f32()? as f64),
+        0xcb => float_value(reader.f64()?),
+        0xcc => json!(reader.u8()?),
+        0xcd => json!(reader.u16()?),
+        0xce => json!(reader.u32()?),
+        0xcf => json!(reader.u64()?),
+        0xd0 => json!(reader.u8()? as i8),
+        0xd1 => json!(reader.u16()? as i16),
+        0xd2 => json!(reader.u32()? as i32),
+        0xd3 => json!(reader.u64()? as i64),
+        0xd4 => decode_ext(reader, 1)?,
+        0xd5 => decode_ext(reader, 2)?,
+        0xd6 => decode_ext(reader, 4)?,
+        0xd7 => decode_ext(reader, 8)?,
+        0xd8 => decode_ext(reader, 16)?,
+        0xd9 => {
+            let len = reader.u8()? as usize;
+            Value::String(reader.utf8(len)?)
+        }
+        0xda => {
+            let len = reader.u16()? as usize;
+            Value::String(reader.utf8(len)?)
+        }
+        0xdb => {
+            let len = reader.u32()? as usize;
+            Value::String(reader.utf8(len)?)
+        }
+        0xdc => {
+            let len = reader.u16()? as usize;
+            decode_array(reader, len, depth)?
+        }
+        0xdd => {
+            let len = reader.u32()? as usize;
+            decode_array(reader, len, depth)?
+        }
+        0xde => {
+            let len = reader.u16()? as usize;
+            decode_map(reader, len, depth)?
+        }
+        0xdf => {
+            let len = reader.u32()? as usize;
+            decode_map(reader, len, depth)?
+        }
+        0xe0..=0xff => json!(marker as i8),
+        0xc1 => return Err(Error::invalid_request("Invalid MessagePack marker 0xc1")),
+    };
+    Ok(value)
+}
+
+fn decode_array(reader: &mut Reader, len: usize, depth: usize) -> Result<Value> {
+    // Do not trust the declared length for preallocation
+    let mut items = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        items.push(decode_value(reader, depth + 1)?);
+    }
+    Ok(Value::Array(items))
+}
+
+fn decode_map(reader: &mut Reader, len: usize, depth: usize) -> Result<Value> {
+    let mut map = Map::new();
+    for _ in 0..len {
+        let key = key_string(decode_value(reader, depth + 1)?);
+        let value = decode_value(reader, depth + 1)?;
+        map.insert(key, value);
+    }
+    Ok(Value::Object(map))
+}
+
+fn decode_ext(reader: &mut Reader, len: usize) -> Result<Value> {
+    let ext_type = reader.u8()? as i8;
+    let data = reader.take(len)?;
+
+    // The timestamp extension (type -1) is rendered as seconds/nanoseconds
+    if ext_type == -1 {
+        let timestamp = match data.len() {
+            4 => Some((u32::from_be_bytes(data.try_into().unwrap()) as i64, 0)),
+            8 => {
+                let raw = u64::from_be_bytes(data.try_into().unwrap());
+                Some(((raw & 0x3_ffff_ffff) as i64, (raw >> 34) as u32))
+            }
+            12 => Some((
+                i64::from_be_bytes(data[4..].try_into().unwrap()),
+                u32::from_be_bytes(data[..4].try_into().unwrap()),
+            )),
+            _ => None,
+        };
+        if let Some((seconds, nanoseconds)) = timestamp {
+            return Ok(json!({ "$timestamp": { "seconds": seconds, "nanoseconds": nanoseconds } }));
+        }
+    }
+
+    let mut value = binary_value(data);
+    value["$ext"] = json!(ext_type);
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_map() {
+        // {"compact": true, "schema": 0, "list": [1, -2, "x"], "f": 1.5}
+        let data = [
+            0x84, 0xa7, b'c', b'o', b'm', b'p', b'a', b'c', b't', 0xc3, 0xa6, b's', b'c', b'h', b'e', b'm', b'a', 0x00,
+            0xa4, b'l', b'i', b's', b't', 0x93, 0x01, 0xfe, 0xa1, b'x', 0xa1, b'f', 0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0,
+        ];
+        let value = decode(&data).unwrap();
+        assert_eq!(value, json!({"compact": true, "schema": 0, "list": [1, -2, "x"], "f": 1.5}));
+    }
+
+    #[test]
+    fn test_decode_binary_and_ext() {
+        let value = decode(&[0x92, 0xc4, 0x02, 0xde, 0xad, 0xd4, 0x05, 0xff]).unwrap();
+        assert_eq!(value[0], json!({"$binary": "3q0="}));
+        assert_eq!(value[1], json!({"$binary": "/w==", "$ext": 5}));
+
+        let value = decode(&[0xd6, 0xff, 0x00, 0x00, 0x00, 0x2a]).unwrap();
+        assert_eq!(value, json!({"$timestamp": {"seconds": 42, "nanoseconds": 0}}));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(decode(&[0x92, 0x01]).is_err());
+        assert!(decode(&[0x01, 0x02]).is_err());
+        assert!(decode(&[0xc1]).is_err());
+        assert!(decode(&[0xdd, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(decode(&[0x91; 200]).is_err());
+    }
+
+    #[test]
+    fn test_render_priority() {
+        let view = MsgPackView;
+        assert_eq!(view.render_priority(b"", Some("application/x-msgpack")), 1.0);
+        assert!(view.render_priority(&[0x81, 0xa1, b'a', 0xc4, 0x01, 0xff], None) > 0.0);
+        assert_eq!(view.render_priority(br#"{"a": 1}"#, None), 0.0);
+    }
+}
@@ -0,0 +1,221 @@
+//! Cookie attribute downgrades for testing cookie policies.
+//!
+//! Rules select responses by filter expression and rewrite the attributes
+//! of their `Set-Cookie` headers: drop `Secure`, change `SameSite` or
+//! shorten the lifetime with `Max-Age`. This shows how an app behaves
+//! under stricter or looser cookie policies without changing its backend.
+//! Every matching rule is applied in order. The names and original
+//! attributes of the changed cookies are kept in the flow's `cookie_policy`
+//! metadata, without their values so that anonymized exports do not leak
+//! them, and the changes are recorded in its changelog under the
+//! `cookie_policy` source.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::changelog;
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::Result;
+
+/// Flow metadata key holding the original attributes of changed cookies
+pub const METADATA_KEY: &str = "cookie_policy";
+
+/// Values of the `SameSite` attribute
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// How a cookie's attributes are changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum CookieAction {
+    /// Remove the `Secure` attribute
+    DropSecure,
+    /// Replace the `SameSite` attribute
+    SameSite { value: SameSite },
+    /// Expire the cookie after at most this many seconds. Cookies without
+    /// an expiry get one.
+    MaxAge { seconds: u64 },
+}
+
+/// A cookie rule as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CookieRule {
+    /// Filter expression selecting responses, e.g. `~d app.example.com`
+    pub filter: String,
+    /// Names of the cookies changed; all cookies if empty
+    #[serde(default)]
+    pub cookies: Vec<String>,
+    #[serde(flatten)]
+    pub action: CookieAction,
+}
+
+/// Cookie policy options as configured in the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CookiePolicyOptions {
+    pub rules: Vec<CookieRule>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CookiePolicy {
+    rules: Vec<(Filter, CookieRule)>,
+}
+
+impl CookiePolicy {
+    pub fn new(options: &CookiePolicyOptions) -> Result<Self> {
+        let rules = options
+            .rules
+            .iter()
+            .map(|rule| Ok((Filter::new("cookie_policy".to_string(), rule.filter.clone())?, rule.clone())))
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Apply the matching rules to the response's cookies. Returns whether
+    /// the response changed.
+    pub fn rewrite(&self, flow: &mut HTTPFlow) -> bool {
+        if flow.response.is_none() {
+            return false;
+        }
+        let rules: Vec<&CookieRule> =
+            self.rules.iter().filter(|(filter, _)| filter.matches(flow)).map(|(_, rule)| rule).collect();
+        if rules.is_empty() {
+            return false;
+        }
+
+        let before = flow.clone();
+        let response = flow.response.as_mut().expect("response checked above");
+        let mut originals = Vec::new();
+        for (_, value) in response.headers.iter_mut().filter(|(name, _)| name.eq_ignore_ascii_case("set-cookie")) {
+            let name = cookie_name(value).to_string();
+            let rewritten = rules
+                .iter()
+                .filter(|rule| rule.cookies.is_empty() || rule.cookies.contains(&name))
+                .fold(value.clone(), |cookie, rule| apply(&cookie, &rule.action));
+            if rewritten != *value {
+                originals.push(json!({
+                    "name": name,
+                    "original": attributes(value),
+                    "rewritten": attributes(&rewritten),
+                }));
+                *value = rewritten;
+            }
+        }
+        if originals.is_empty() {
+            return false;
+        }
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), Value::Array(originals));
+        changelog::record(flow, &before, "cookie_policy", None) > 0
+    }
+}
+
+fn cookie_name(set_cookie: &str) -> &str {
+    let pair = set_cookie.split(';').next().unwrap_or_default();
+    pair.split('=').next().unwrap_or_default().trim()
+}
+
+/// The attributes of a `Set-Cookie` value, without the cookie itself
+fn attributes(set_cookie: &str) -> &str {
+    set_cookie.split_once(';').map_or("", |(_, attributes)| attributes.trim())
+}
+
+/// Lowercase name of a cookie attribute such as `Max-Age=60`
+fn attribute_name(attribute: &str) -> String {
+    attribute.split('=').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+/// A `Set-Cookie` value with `action` applied to its attributes
+fn apply(set_cookie: &str, action: &CookieAction) -> String {
+    let mut parts = set_cookie.split(';').map(str::trim).filter(|part| !part.is_empty());
+    let pair = parts.next().unwrap_or_default();
+    let mut attributes: Vec<String> = parts.map(str::to_string).collect();
+    match action {
+        CookieAction::DropSecure => attributes.retain(|a| attribute_name(a) != "secure"),
+        CookieAction::SameSite { value } => {
+            attributes.retain(|a| attribute_name(a) != "samesite");
+            attributes.push(format!("SameSite={:?}", value));
+        }
+        CookieAction::MaxAge { seconds } => {
+            let current = attributes
+                .iter()
+                .find(|a| attribute_name(a) == "max-age")
+                .and_then(|a| a.split_once('='))
+                .and_then(|(_, v)| v.trim().parse::<i64>().ok());
+            if current.is_none_or(|current| current > *seconds as i64) {
+                attributes.retain(|a| attribute_name(a) != "max-age");
+                attributes.push(format!("Max-Age={}", seconds));
+            }
+        }
+    }
+    std::iter::once(pair.to_string()).chain(attributes).collect::<Vec<_>>().join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow(host: &str) -> HTTPFlow {
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, "/".to_string());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.headers.push(("Set-Cookie".to_string(), "sid=abc; Path=/; Secure; HttpOnly; SameSite=Strict; Max-Age=86400".to_string()));
+        response.headers.push(("Set-Cookie".to_string(), "theme=dark; Secure".to_string()));
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    fn rule(cookies: &[&str], action: CookieAction) -> CookieRule {
+        CookieRule {
+            filter: "~d app.example".to_string(),
+            cookies: cookies.iter().map(|c| c.to_string()).collect(),
+            action,
+        }
+    }
+
+    #[test]
+    fn test_apply_actions() {
+        let cookie = "sid=abc; Path=/; Secure; SameSite=Strict; max-age=600";
+        assert_eq!(apply(cookie, &CookieAction::DropSecure), "sid=abc; Path=/; SameSite=Strict; max-age=600");
+        assert_eq!(
+            apply(cookie, &CookieAction::SameSite { value: SameSite::None }),
+            "sid=abc; Path=/; Secure; max-age=600; SameSite=None"
+        );
+        assert_eq!(apply(cookie, &CookieAction::MaxAge { seconds: 60 }), "sid=abc; Path=/; Secure; SameSite=Strict; Max-Age=60");
+        assert_eq!(apply(cookie, &CookieAction::MaxAge { seconds: 3600 }), cookie);
+        assert_eq!(apply("a=1", &CookieAction::MaxAge { seconds: 5 }), "a=1; Max-Age=5");
+    }
+
+    #[test]
+    fn test_rewrite_response() {
+        let policy = CookiePolicy::new(&CookiePolicyOptions {
+            rules: vec![
+                rule(&[], CookieAction::DropSecure),
+                rule(&["sid"], CookieAction::SameSite { value: SameSite::Lax }),
+            ],
+        })
+        .unwrap();
+
+        let mut downgraded = flow("app.example");
+        assert!(policy.rewrite(&mut downgraded));
+        let cookies: Vec<&str> = downgraded.response.as_ref().unwrap().headers.iter().map(|(_, v)| v.as_str()).collect();
+        assert_eq!(cookies, ["sid=abc; Path=/; HttpOnly; Max-Age=86400; SameSite=Lax", "theme=dark"]);
+        let originals = downgraded.flow.metadata[METADATA_KEY].as_array().unwrap();
+        assert_eq!(originals.len(), 2);
+        assert_eq!(originals[0]["original"], "Path=/; Secure; HttpOnly; SameSite=Strict; Max-Age=86400");
+        assert_eq!(originals[0]["rewritten"], "Path=/; HttpOnly; Max-Age=86400; SameSite=Lax");
+        assert_eq!(originals[1]["name"], "theme");
+        assert!(!downgraded.flow.metadata[METADATA_KEY].to_string().contains("abc"));
+        assert_eq!(downgraded.flow.changes[0].source, "cookie_policy");
+
+        let mut untouched = flow("other.example");
+        assert!(!policy.rewrite(&mut untouched));
+        assert!(untouched.flow.metadata.is_empty());
+
+        let invalid = CookieRule { filter: "~q(".to_string(), ..rule(&[], CookieAction::DropSecure) };
+        assert!(CookiePolicy::new(&CookiePolicyOptions { rules: vec![invalid] }).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Capture of malformed traffic for fuzzing.
+//!
+//! When a parser rejects what a peer sent, the raw bytes are written to the
+//! fuzz corpus directory, in a subdirectory per parser named after the
+//! cargo-fuzz target that exercises it. Files are named by the SHA-256 of
+//! their content, so the same input is stored only once. Running
+//! `cargo fuzz run http1 <dir>/http1` seeds the fuzzer with what real
+//! clients and servers sent.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::flow::content_hash;
+use crate::Result;
+
+/// Inputs are cut to this size; fuzzers rarely need more
+pub const MAX_INPUT_SIZE: usize = 64 * 1024;
+
+/// Parsers malformed input is collected for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Parser {
+    /// HTTP/1 requests and responses as read from the connection
+    Http1,
+    /// The body of a message with chunked transfer encoding
+    Chunked,
+    /// The TLS records carrying a ClientHello
+    ClientHello,
+    /// A `text/event-stream` response body
+    Sse,
+}
+
+impl Parser {
+    /// Name of the corpus subdirectory and of the fuzz target
+    pub fn name(&self) -> &'static str {
+        match self {
+            Parser::Http1 => "http1",
+            Parser::Chunked => "chunked",
+            Parser::ClientHello => "client_hello",
+            Parser::Sse => "sse",
+        }
+    }
+}
+
+/// Directory malformed inputs are saved to
+#[derive(Debug, Clone)]
+pub struct FuzzCorpus {
+    dir: PathBuf,
+}
+
+impl FuzzCorpus {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Save an input that `parser` rejected. Returns the path of the new
+    /// file, or `None` if the input is empty or was saved before.
+    pub fn record(&self, parser: Parser, data: &[u8]) -> Result<Option<PathBuf>> {
+        let data = &data[..data.len().min(MAX_INPUT_SIZE)];
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let dir = self.dir.join(parser.name());
+        let path = dir.join(content_hash(data));
+        if path.exists() {
+            return Ok(None);
+        }
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(&path, data)?;
+        Ok(Some(path))
+    }
+}
+
+/// Append received bytes to the copy kept of a message in case it turns
+/// out to be malformed, up to the size a corpus input is cut to
+pub fn keep_input(buf: &mut Vec<u8>, data: &[u8]) {
+    let room = MAX_INPUT_SIZE.saturating_sub(buf.len());
+    buf.extend_from_slice(&data[..data.len().min(room)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_deduplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = FuzzCorpus::new(dir.path().join("corpus")).unwrap();
+
+        let path = corpus.record(Parser::Http1, b"GET / HTTP/9\r\n\r\n").unwrap().unwrap();
+        assert_eq!(path.parent().unwrap(), dir.path().join("corpus/http1"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"GET / HTTP/9\r\n\r\n");
+        assert!(corpus.record(Parser::Http1, b"GET / HTTP/9\r\n\r\n").unwrap().is_none());
+        assert!(corpus.record(Parser::Chunked, b"").unwrap().is_none());
+
+        let mut kept = vec![0; MAX_INPUT_SIZE - 1];
+        keep_input(&mut kept, b"abc");
+        assert_eq!(kept.len(), MAX_INPUT_SIZE);
+    }
+}
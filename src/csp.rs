@@ -0,0 +1,324 @@
+//! Content-Security-Policy rewriting and violation report capture.
+//!
+//! Injecting debugging scripts through rewrite rules only works if the page's
+//! CSP lets them run. Rules select hosts by filter expression and strip the
+//! policy, downgrade it to report-only, or add sources to its script and
+//! connect directives. Changes are recorded in the flow's changelog under the
+//! `csp` source.
+//!
+//! Browsers post violation reports to the policy's `report-uri` or
+//! `report-to` endpoint, either as `application/csp-report` or as Reporting
+//! API batches. With `capture_reports`, such requests are parsed into a list
+//! of reports, and with `intercept_reports` they are answered by the proxy
+//! so they never reach the site.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::changelog;
+use crate::filter::Filter;
+use crate::flow::{HTTPFlow, HTTPResponse};
+use crate::Result;
+
+/// Response headers carrying an enforced policy
+const POLICY_HEADERS: &[&str] = &["content-security-policy", "x-content-security-policy", "x-webkit-csp"];
+
+const REPORT_ONLY_HEADER: &str = "Content-Security-Policy-Report-Only";
+
+/// Directives extended by `allow-sources`
+const EXTENDED_DIRECTIVES: &[&str] = &["script-src", "script-src-elem", "connect-src", "default-src"];
+
+/// Reports kept in memory; older ones are dropped
+pub const MAX_REPORTS: usize = 1000;
+
+/// How a matching response's policy is changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum CspAction {
+    /// Remove the policy
+    Strip,
+    /// Keep the policy but only report violations
+    ReportOnly,
+    /// Add sources, e.g. `'unsafe-inline'` or a debug host, to the script
+    /// and connect directives
+    AllowSources { sources: Vec<String> },
+}
+
+/// A CSP rule as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CspRule {
+    /// Filter expression selecting responses, e.g. `~d app.example.com`
+    pub filter: String,
+    #[serde(flatten)]
+    pub action: CspAction,
+}
+
+/// CSP options as configured in the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CspOptions {
+    pub rules: Vec<CspRule>,
+    /// Collect violation reports sent by browsers
+    pub capture_reports: bool,
+    /// Answer captured reports with 204 instead of forwarding them
+    pub intercept_reports: bool,
+}
+
+/// A captured violation report
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CspReport {
+    pub flow_id: String,
+    pub timestamp: f64,
+    pub document_uri: Option<String>,
+    pub effective_directive: Option<String>,
+    pub violated_directive: Option<String>,
+    pub blocked_uri: Option<String>,
+    pub source_file: Option<String>,
+    pub line_number: Option<u64>,
+    pub disposition: Option<String>,
+    pub original_policy: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Csp {
+    options: CspOptions,
+    rules: Vec<(Filter, CspAction)>,
+}
+
+impl Csp {
+    pub fn new(options: &CspOptions) -> Result<Self> {
+        let mut csp = Self { options: options.clone(), rules: Vec::new() };
+        for rule in &options.rules {
+            csp.rules.push((Filter::new("csp".to_string(), rule.filter.clone())?, rule.action.clone()));
+        }
+        Ok(csp)
+    }
+
+    /// Apply the first matching rule to the response's policy. Returns
+    /// whether the response changed.
+    pub fn rewrite(&self, flow: &mut HTTPFlow) -> bool {
+        let Some((_, action)) = self.rules.iter().find(|(filter, _)| filter.matches(flow)) else {
+            return false;
+        };
+        let Some(response) = flow.response.as_ref() else {
+            return false;
+        };
+        if !has_policy(response) {
+            return false;
+        }
+
+        let before = flow.clone();
+        let response = flow.response.as_mut().expect("response checked above");
+        let policies: Vec<String> = response
+            .headers
+            .iter()
+            .filter(|(name, _)| is_policy_header(name))
+            .map(|(_, value)| value.clone())
+            .collect();
+        response.headers.retain(|(name, _)| !is_policy_header(name));
+        match action {
+            CspAction::Strip => {}
+            CspAction::ReportOnly => {
+                for policy in policies {
+                    response.headers.push((REPORT_ONLY_HEADER.to_string(), policy));
+                }
+            }
+            CspAction::AllowSources { sources } => {
+                for policy in policies {
+                    response.headers.push(("Content-Security-Policy".to_string(), allow_sources(&policy, sources)));
+                }
+            }
+        }
+        changelog::record(flow, &before, "csp", None) > 0
+    }
+
+    /// Parse a flow's request as a violation report. If reports are
+    /// intercepted, the flow gets a local 204 response.
+    pub fn capture(&self, flow: &mut HTTPFlow) -> Vec<CspReport> {
+        if !self.options.capture_reports || !flow.request.method.eq_ignore_ascii_case("POST") {
+            return Vec::new();
+        }
+        let content_type = flow.request.get_header("content-type").map(|ct| ct.to_ascii_lowercase()).unwrap_or_default();
+        let Some(body) = flow.request.content.as_deref().and_then(|body| serde_json::from_slice::<Value>(body).ok()) else {
+            return Vec::new();
+        };
+
+        let reports: Vec<&Value> = if content_type.starts_with("application/csp-report") {
+            body.get("csp-report").into_iter().collect()
+        } else if content_type.starts_with("application/reports+json") {
+            body.as_array()
+                .into_iter()
+                .flatten()
+                .filter(|report| report["type"] == "csp-violation")
+                .map(|report| &report["body"])
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let reports: Vec<CspReport> = reports.into_iter().map(|report| parse_report(flow, report)).collect();
+
+        if !reports.is_empty() && self.options.intercept_reports && flow.response.is_none() {
+            flow.response = Some(HTTPResponse::new(204, "No Content".to_string()));
+        }
+        reports
+    }
+}
+
+fn is_policy_header(name: &str) -> bool {
+    POLICY_HEADERS.iter().any(|header| name.eq_ignore_ascii_case(header))
+}
+
+fn has_policy(response: &HTTPResponse) -> bool {
+    response.headers.iter().any(|(name, _)| is_policy_header(name))
+}
+
+/// Add sources to the directives scripts and their connections are checked
+/// against. Directives that would not allow them anyway (`'none'`) are
+/// replaced rather than extended.
+fn allow_sources(policy: &str, sources: &[String]) -> String {
+    let mut directives: Vec<String> = Vec::new();
+    let mut extended = false;
+    for directive in policy.split(';').map(str::trim).filter(|d| !d.is_empty()) {
+        let mut parts = directive.split_whitespace();
+        let name = parts.next().unwrap_or_default();
+        if !EXTENDED_DIRECTIVES.iter().any(|d| name.eq_ignore_ascii_case(d)) {
+            directives.push(directive.to_string());
+            continue;
+        }
+        extended = true;
+        let mut values: Vec<&str> = parts.filter(|v| !v.eq_ignore_ascii_case("'none'")).collect();
+        // A nonce or hash makes browsers ignore 'unsafe-inline'
+        if sources.iter().any(|s| s == "'unsafe-inline'") {
+            values.retain(|v| !v.starts_with("'nonce-") && !v.starts_with("'sha") && *v != "'strict-dynamic'");
+        }
+        for source in sources {
+            if !values.contains(&source.as_str()) {
+                values.push(source);
+            }
+        }
+        directives.push(format!("{} {}", name, values.join(" ")));
+    }
+    if !extended {
+        // Without these directives, everything is allowed already
+        return policy.to_string();
+    }
+    directives.join("; ")
+}
+
+fn parse_report(flow: &HTTPFlow, report: &Value) -> CspReport {
+    // report-uri reports use kebab-case, Reporting API ones camelCase
+    let field = |kebab: &str, camel: &str| {
+        report
+            .get(kebab)
+            .or_else(|| report.get(camel))
+            .and_then(|v| v.as_str().map(str::to_string).or_else(|| (!v.is_null()).then(|| v.to_string())))
+    };
+    CspReport {
+        flow_id: flow.flow.id.clone(),
+        timestamp: flow.request.timestamp_start.unwrap_or(flow.flow.timestamp_created),
+        document_uri: field("document-uri", "documentURL"),
+        effective_directive: field("effective-directive", "effectiveDirective"),
+        violated_directive: field("violated-directive", "violatedDirective"),
+        blocked_uri: field("blocked-uri", "blockedURL"),
+        source_file: field("source-file", "sourceFile"),
+        line_number: report.get("line-number").or_else(|| report.get("lineNumber")).and_then(Value::as_u64),
+        disposition: field("disposition", "disposition"),
+        original_policy: field("original-policy", "originalPolicy"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    const POLICY: &str = "default-src 'self'; script-src 'self' 'nonce-abc'; img-src *";
+
+    fn flow(host: &str) -> HTTPFlow {
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, "/".to_string());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_header("Content-Security-Policy".to_string(), POLICY.to_string());
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    fn csp(action: CspAction) -> Csp {
+        let options = CspOptions {
+            rules: vec![CspRule { filter: "~d app.example".to_string(), action }],
+            ..Default::default()
+        };
+        Csp::new(&options).unwrap()
+    }
+
+    #[test]
+    fn test_rewrite_policy() {
+        let mut stripped = flow("app.example");
+        assert!(csp(CspAction::Strip).rewrite(&mut stripped));
+        assert!(!has_policy(stripped.response.as_ref().unwrap()));
+        assert_eq!(stripped.flow.changes[0].source, "csp");
+
+        let mut report_only = flow("app.example");
+        assert!(csp(CspAction::ReportOnly).rewrite(&mut report_only));
+        assert_eq!(report_only.response.as_ref().unwrap().get_header(REPORT_ONLY_HEADER).map(String::as_str), Some(POLICY));
+
+        let mut untouched = flow("other.example");
+        assert!(!csp(CspAction::Strip).rewrite(&mut untouched));
+    }
+
+    #[tokio::test]
+    async fn test_response_hook_rewrites_policy() {
+        let options = CspOptions {
+            rules: vec![CspRule { filter: "~d app.example".to_string(), action: CspAction::ReportOnly }],
+            ..Default::default()
+        };
+        let config = crate::config::Config { csp: options, ..Default::default() };
+        let proxy = crate::proxy::ProxyServer::new(std::sync::Arc::new(config));
+        let mut flow = flow("app.example");
+        proxy.response_hook(crate::listeners::HTTP3, &mut flow).await.unwrap();
+        let response = flow.response.as_ref().unwrap();
+        assert!(!has_policy(response));
+        assert_eq!(response.get_header(REPORT_ONLY_HEADER).map(String::as_str), Some(POLICY));
+    }
+
+    #[test]
+    fn test_allow_sources() {
+        let sources = vec!["'unsafe-inline'".to_string(), "https://debug.local".to_string()];
+        assert_eq!(
+            allow_sources(POLICY, &sources),
+            "default-src 'self' 'unsafe-inline' https://debug.local; \
+             script-src 'self' 'unsafe-inline' https://debug.local; img-src *"
+        );
+        assert_eq!(
+            allow_sources("script-src 'none'", &sources[1..]),
+            "script-src https://debug.local"
+        );
+        assert_eq!(allow_sources("img-src 'self'", &sources), "img-src 'self'");
+    }
+
+    #[test]
+    fn test_capture_reports() {
+        let options = CspOptions { capture_reports: true, intercept_reports: true, ..Default::default() };
+        let csp = Csp::new(&options).unwrap();
+
+        let mut request = HTTPRequest::new("POST".to_string(), "https".to_string(), "app.example".to_string(), 443, "/csp".to_string());
+        request.set_header("Content-Type".to_string(), "application/csp-report".to_string());
+        request.set_content(
+            br#"{"csp-report": {"document-uri": "https://app.example/", "violated-directive": "script-src", "blocked-uri": "inline", "line-number": 12}}"#.to_vec(),
+        );
+        let mut flow = HTTPFlow::new(request);
+        let reports = csp.capture(&mut flow);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].blocked_uri.as_deref(), Some("inline"));
+        assert_eq!(reports[0].line_number, Some(12));
+        assert_eq!(flow.response.as_ref().unwrap().status_code, 204);
+
+        let mut request = HTTPRequest::new("POST".to_string(), "https".to_string(), "app.example".to_string(), 443, "/r".to_string());
+        request.set_header("Content-Type".to_string(), "application/reports+json".to_string());
+        request.set_content(
+            br#"[{"type": "csp-violation", "body": {"documentURL": "https://app.example/", "effectiveDirective": "connect-src"}},
+                 {"type": "deprecation", "body": {}}]"#.to_vec(),
+        );
+        let reports = csp.capture(&mut HTTPFlow::new(request));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].effective_directive.as_deref(), Some("connect-src"));
+    }
+}
@@ -0,0 +1,307 @@
+//! Caching of upstream DNS lookups and connect results.
+//!
+//! Successful lookups are kept for `ttl_secs`, so repeated requests to a host
+//! do not pay resolution latency again. Failed lookups and failed connects
+//! are kept for `negative_ttl_secs`: requests to a host that is down fail
+//! fast instead of each waiting for a timeout, and the failure count shows
+//! which hosts keep flapping.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::{Error, Result};
+
+/// DNS cache options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DnsCacheOptions {
+    pub enabled: bool,
+    /// How long successful lookups are reused
+    pub ttl_secs: u64,
+    /// How long failed lookups and connects are remembered
+    pub negative_ttl_secs: u64,
+    /// Entries kept before the oldest are evicted
+    pub max_entries: usize,
+}
+
+impl Default for DnsCacheOptions {
+    fn default() -> Self {
+        Self { enabled: true, ttl_secs: 60, negative_ttl_secs: 10, max_entries: 4096 }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Lookup {
+    Resolved(Vec<IpAddr>),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    lookup: Lookup,
+    created: Instant,
+    expires: Instant,
+    hits: u64,
+}
+
+#[derive(Debug, Clone)]
+struct ConnectFailure {
+    error: String,
+    expires: Instant,
+    /// Consecutive failures, kept across expiry until a connect succeeds
+    failures: u64,
+}
+
+/// A cached lookup as reported by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsCacheEntry {
+    pub host: String,
+    pub addresses: Vec<IpAddr>,
+    pub error: Option<String>,
+    pub expires_in_secs: u64,
+    pub hits: u64,
+}
+
+/// A destination connects to which recently failed
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectFailureEntry {
+    pub address: String,
+    pub error: String,
+    pub failures: u64,
+    /// Whether new connects are currently refused without trying
+    pub active: bool,
+    pub expires_in_secs: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    lookups: HashMap<String, Entry>,
+    connect_failures: HashMap<String, ConnectFailure>,
+}
+
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    options: DnsCacheOptions,
+    state: Mutex<State>,
+}
+
+impl DnsCache {
+    pub fn new(options: DnsCacheOptions) -> Self {
+        Self { options, state: Mutex::default() }
+    }
+
+    /// Addresses of `host`, from the cache if possible
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        if self.options.enabled {
+            if let Some(lookup) = self.cached(host, Instant::now()) {
+                return match lookup {
+                    Lookup::Resolved(addrs) => Ok(addrs),
+                    Lookup::Failed(error) => Err(Error::Proxy(format!("Cannot resolve {} (cached): {}", host, error))),
+                };
+            }
+        }
+
+        let lookup = match tokio::net::lookup_host((host, 0)).await {
+            Ok(addrs) => {
+                let mut ips: Vec<IpAddr> = Vec::new();
+                for addr in addrs {
+                    if !ips.contains(&addr.ip()) {
+                        ips.push(addr.ip());
+                    }
+                }
+                if ips.is_empty() {
+                    Lookup::Failed("no addresses".to_string())
+                } else {
+                    Lookup::Resolved(ips)
+                }
+            }
+            Err(e) => Lookup::Failed(e.to_string()),
+        };
+        if self.options.enabled {
+            self.store(host, lookup.clone(), Instant::now());
+        }
+        match lookup {
+            Lookup::Resolved(addrs) => Ok(addrs),
+            Lookup::Failed(error) => Err(Error::Proxy(format!("Cannot resolve {}: {}", host, error))),
+        }
+    }
+
+    /// Connect to `host:port`, failing fast if it failed recently
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let key = format!("{}:{}", host, port);
+        if self.options.enabled {
+            if let Some(error) = self.recent_failure(&key, Instant::now()) {
+                return Err(Error::Proxy(format!("Cannot connect to {} (cached): {}", key, error)));
+            }
+        }
+
+        let mut last_error = None;
+        for ip in self.resolve(host).await? {
+            match TcpStream::connect(SocketAddr::new(ip, port)).await {
+                Ok(stream) => {
+                    self.state.lock().unwrap().connect_failures.remove(&key);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    debug!("Connecting to {} ({}) failed: {}", key, ip, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        let error = last_error.map(|e| e.to_string()).unwrap_or_else(|| "no addresses".to_string());
+        if self.options.enabled {
+            self.record_failure(&key, &error, Instant::now());
+        }
+        Err(Error::Proxy(format!("Cannot connect to {}: {}", key, error)))
+    }
+
+    fn cached(&self, host: &str, now: Instant) -> Option<Lookup> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.lookups.get_mut(&host.to_ascii_lowercase())?;
+        if entry.expires <= now {
+            return None;
+        }
+        entry.hits += 1;
+        Some(entry.lookup.clone())
+    }
+
+    fn store(&self, host: &str, lookup: Lookup, now: Instant) {
+        let ttl = match lookup {
+            Lookup::Resolved(_) => self.options.ttl_secs,
+            Lookup::Failed(_) => self.options.negative_ttl_secs,
+        };
+        let mut state = self.state.lock().unwrap();
+        state.lookups.retain(|_, entry| entry.expires > now);
+        if state.lookups.len() >= self.options.max_entries {
+            let oldest = state.lookups.iter().min_by_key(|(_, entry)| entry.created).map(|(host, _)| host.clone());
+            if let Some(oldest) = oldest {
+                state.lookups.remove(&oldest);
+            }
+        }
+        let entry = Entry { lookup, created: now, expires: now + Duration::from_secs(ttl), hits: 0 };
+        state.lookups.insert(host.to_ascii_lowercase(), entry);
+    }
+
+    fn recent_failure(&self, key: &str, now: Instant) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.connect_failures.get(key).filter(|failure| failure.expires > now).map(|failure| failure.error.clone())
+    }
+
+    fn record_failure(&self, key: &str, error: &str, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let expires = now + Duration::from_secs(self.options.negative_ttl_secs);
+        let failure = state.connect_failures.entry(key.to_string()).or_insert(ConnectFailure {
+            error: String::new(),
+            expires,
+            failures: 0,
+        });
+        failure.error = error.to_string();
+        failure.expires = expires;
+        failure.failures += 1;
+    }
+
+    /// Cached lookups and connect failures, most failing destinations first
+    pub fn snapshot(&self) -> (Vec<DnsCacheEntry>, Vec<ConnectFailureEntry>) {
+        let now = Instant::now();
+        let remaining = |expires: Instant| expires.saturating_duration_since(now).as_secs();
+        let state = self.state.lock().unwrap();
+
+        let mut lookups: Vec<DnsCacheEntry> = state
+            .lookups
+            .iter()
+            .filter(|(_, entry)| entry.expires > now)
+            .map(|(host, entry)| {
+                let (addresses, error) = match &entry.lookup {
+                    Lookup::Resolved(addrs) => (addrs.clone(), None),
+                    Lookup::Failed(error) => (Vec::new(), Some(error.clone())),
+                };
+                DnsCacheEntry { host: host.clone(), addresses, error, expires_in_secs: remaining(entry.expires), hits: entry.hits }
+            })
+            .collect();
+        lookups.sort_by(|a, b| a.host.cmp(&b.host));
+
+        let mut failures: Vec<ConnectFailureEntry> = state
+            .connect_failures
+            .iter()
+            .map(|(address, failure)| ConnectFailureEntry {
+                address: address.clone(),
+                error: failure.error.clone(),
+                failures: failure.failures,
+                active: failure.expires > now,
+                expires_in_secs: remaining(failure.expires),
+            })
+            .collect();
+        failures.sort_by(|a, b| b.failures.cmp(&a.failures).then_with(|| a.address.cmp(&b.address)));
+        (lookups, failures)
+    }
+
+    pub fn clear(&self) {
+        *self.state.lock().unwrap() = State::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> DnsCache {
+        DnsCache::new(DnsCacheOptions { enabled: true, ttl_secs: 60, negative_ttl_secs: 10, max_entries: 2 })
+    }
+
+    #[test]
+    fn test_lookup_expiry_and_eviction() {
+        let cache = cache();
+        let now = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        cache.store("Example.com", Lookup::Resolved(vec![ip]), now);
+        cache.store("down.example", Lookup::Failed("timeout".to_string()), now + Duration::from_secs(1));
+
+        assert!(matches!(cache.cached("example.com", now), Some(Lookup::Resolved(addrs)) if addrs == vec![ip]));
+        assert!(matches!(cache.cached("down.example", now + Duration::from_secs(5)), Some(Lookup::Failed(_))));
+        // Negative entries expire sooner
+        assert!(cache.cached("down.example", now + Duration::from_secs(12)).is_none());
+        assert!(cache.cached("example.com", now + Duration::from_secs(61)).is_none());
+
+        cache.store("third.example", Lookup::Resolved(vec![ip]), now + Duration::from_secs(2));
+        let (lookups, _) = cache.snapshot();
+        assert_eq!(lookups.len(), 2);
+        assert!(!lookups.iter().any(|entry| entry.host == "example.com"));
+    }
+
+    #[test]
+    fn test_connect_failures() {
+        let cache = cache();
+        let now = Instant::now();
+        cache.record_failure("flaky.example:443", "connection refused", now);
+        cache.record_failure("flaky.example:443", "connection refused", now);
+        assert_eq!(cache.recent_failure("flaky.example:443", now).as_deref(), Some("connection refused"));
+        assert!(cache.recent_failure("flaky.example:443", now + Duration::from_secs(11)).is_none());
+
+        let (_, failures) = cache.snapshot();
+        assert_eq!(failures[0].failures, 2);
+        cache.clear();
+        assert!(cache.snapshot().1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_fast_after_failure() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let cache = cache();
+        let err = cache.connect("127.0.0.1", port).await.unwrap_err();
+        assert!(!err.to_string().contains("cached"));
+        let err = cache.connect("127.0.0.1", port).await.unwrap_err();
+        assert!(err.to_string().contains("cached"));
+    }
+}
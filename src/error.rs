@@ -46,6 +46,10 @@ pub enum Error {
     #[error("Regex error: {0}")]
     Regex(#[from] regex::Error),
 
+    #[cfg(feature = "sqlite")]
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
     #[error("Internal error: {0}")]
     Internal(String),
 
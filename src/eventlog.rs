@@ -0,0 +1,57 @@
+//! Event log shown to API clients.
+//!
+//! Entries mirror mitmproxy's web event log: an id, a level and a message.
+//! Only the most recent entries are kept.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Entries kept before the oldest are dropped
+pub const MAX_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogEntry {
+    pub id: String,
+    pub level: LogLevel,
+    pub message: String,
+    pub timestamp: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct EventLog {
+    entries: VecDeque<LogEntry>,
+    next_id: u64,
+}
+
+impl EventLog {
+    pub fn push(&mut self, level: LogLevel, message: impl Into<String>) {
+        self.next_id += 1;
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(LogEntry {
+            id: self.next_id.to_string(),
+            level,
+            message: message.into(),
+            timestamp: chrono::Utc::now().timestamp_millis() as f64 / 1000.0,
+        });
+    }
+
+    /// Entries, oldest first
+    pub fn entries(&self) -> Vec<LogEntry> {
+        self.entries.iter().cloned().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,314 @@
+//! Declarative assertions evaluated against live traffic.
+//!
+//! An expectation pairs a flow filter with an assertion. Every completed flow
+//! matching the filter is checked, and pass/fail outcomes are recorded per
+//! flow so the proxy can act as a lightweight contract-testing gate.
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::{Error, Result};
+
+/// An expectation as configured through the API or config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpectationSpec {
+    pub name: String,
+    /// Filter expression selecting the flows to check; empty matches all
+    #[serde(default)]
+    pub filter: String,
+    #[serde(flatten)]
+    pub assertion: Assertion,
+}
+
+/// Predicate checked against a matching flow
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "assert", rename_all = "snake_case")]
+pub enum Assertion {
+    /// Response status code equals the given value
+    Status { equals: u16 },
+    /// Response carries the header, optionally with an exact value
+    Header {
+        header: String,
+        #[serde(default)]
+        equals: Option<String>,
+    },
+    /// Value at a JSON path of the response body equals the given value
+    JsonPath { path: String, equals: Value },
+    /// Time from request start to response end is below the limit
+    Latency { max_ms: f64 },
+}
+
+impl Assertion {
+    /// Check a completed flow, returning the failure reason if it does not hold
+    fn check(&self, flow: &HTTPFlow) -> std::result::Result<(), String> {
+        let response = flow.response.as_ref().ok_or_else(|| match &flow.flow.error {
+            Some(error) => format!("no response: {}", error.msg),
+            None => "no response".to_string(),
+        })?;
+
+        match self {
+            Assertion::Status { equals } => {
+                if response.status_code == *equals {
+                    Ok(())
+                } else {
+                    Err(format!("status {} != {}", response.status_code, equals))
+                }
+            }
+            Assertion::Header { header, equals } => match (response.get_header(header), equals) {
+                (None, _) => Err(format!("header {} missing", header)),
+                (Some(actual), Some(expected)) if actual != expected => {
+                    Err(format!("header {} is {:?}, expected {:?}", header, actual, expected))
+                }
+                _ => Ok(()),
+            },
+            Assertion::JsonPath { path, equals } => {
+                let body: Value = response
+                    .content
+                    .as_deref()
+                    .and_then(|content| serde_json::from_slice(content).ok())
+                    .ok_or_else(|| "response body is not JSON".to_string())?;
+                match body.pointer(&json_pointer(path)) {
+                    Some(actual) if actual == equals => Ok(()),
+                    Some(actual) => Err(format!("{} is {}, expected {}", path, actual, equals)),
+                    None => Err(format!("{} not found", path)),
+                }
+            }
+            Assertion::Latency { max_ms } => {
+                let start = flow.request.timestamp_start.ok_or("request start time unknown")?;
+                let end = response
+                    .timestamp_end
+                    .or(response.timestamp_start)
+                    .ok_or("response time unknown")?;
+                let latency = (end - start) * 1000.0;
+                if latency < *max_ms {
+                    Ok(())
+                } else {
+                    Err(format!("latency {:.1}ms >= {}ms", latency, max_ms))
+                }
+            }
+        }
+    }
+}
+
+/// Convert a dotted path like `$.data.items[0].id` into a JSON pointer
+fn json_pointer(path: &str) -> String {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    let mut pointer = String::new();
+    for segment in path.split(['.', '[']).filter(|s| !s.is_empty()) {
+        let segment = segment.strip_suffix(']').unwrap_or(segment);
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    }
+    pointer
+}
+
+/// A registered expectation and its outcomes
+#[derive(Debug, Clone)]
+struct Expectation {
+    spec: ExpectationSpec,
+    filter: Filter,
+    /// Failure reason per checked flow, `None` if the flow passed
+    outcomes: IndexMap<String, Option<String>>,
+}
+
+/// A flow that failed an expectation
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedFlow {
+    pub flow_id: String,
+    pub reason: String,
+}
+
+/// Outcome summary for a single expectation
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpectationResult {
+    #[serde(flatten)]
+    pub spec: ExpectationSpec,
+    pub passed: usize,
+    pub failed: usize,
+    pub failing_flows: Vec<FailedFlow>,
+}
+
+/// Set of expectations evaluated by the proxy
+#[derive(Debug, Clone, Default)]
+pub struct Expectations {
+    entries: Vec<Expectation>,
+}
+
+impl Expectations {
+    /// Build the set from configured specs
+    pub fn from_specs(specs: &[ExpectationSpec]) -> Result<Self> {
+        let mut expectations = Self::default();
+        for spec in specs {
+            expectations.add(spec.clone())?;
+        }
+        Ok(expectations)
+    }
+
+    /// Register an expectation, replacing any existing one with the same name
+    pub fn add(&mut self, spec: ExpectationSpec) -> Result<()> {
+        if spec.name.is_empty() {
+            return Err(Error::invalid_request("Expectation name must not be empty"));
+        }
+        let filter = Filter::new(spec.name.clone(), spec.filter.clone())?;
+        self.remove(&spec.name);
+        self.entries.push(Expectation { spec, filter, outcomes: IndexMap::new() });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.spec.name != name);
+        self.entries.len() != before
+    }
+
+    pub fn specs(&self) -> Vec<ExpectationSpec> {
+        self.entries.iter().map(|e| e.spec.clone()).collect()
+    }
+
+    /// Check a flow against all matching expectations. Flows that are still
+    /// waiting for a response are skipped; re-evaluating a flow replaces its
+    /// previous outcome.
+    pub fn evaluate(&mut self, flow: &HTTPFlow) {
+        if flow.response.is_none() && flow.flow.error.is_none() {
+            return;
+        }
+        for entry in &mut self.entries {
+            if entry.filter.matches(flow) {
+                let outcome = entry.spec.assertion.check(flow).err();
+                entry.outcomes.insert(flow.flow.id.clone(), outcome);
+            }
+        }
+    }
+
+    /// Forget the outcomes of the flow with `flow_id`, once it is removed
+    pub fn forget(&mut self, flow_id: &str) {
+        for entry in &mut self.entries {
+            entry.outcomes.shift_remove(flow_id);
+        }
+    }
+
+    /// Forget all recorded outcomes
+    pub fn reset(&mut self) {
+        for entry in &mut self.entries {
+            entry.outcomes.clear();
+        }
+    }
+
+    pub fn results(&self) -> Vec<ExpectationResult> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let failing_flows: Vec<FailedFlow> = entry
+                    .outcomes
+                    .iter()
+                    .filter_map(|(flow_id, reason)| {
+                        reason.as_ref().map(|reason| FailedFlow { flow_id: flow_id.clone(), reason: reason.clone() })
+                    })
+                    .collect();
+                ExpectationResult {
+                    spec: entry.spec.clone(),
+                    passed: entry.outcomes.len() - failing_flows.len(),
+                    failed: failing_flows.len(),
+                    failing_flows,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+    use serde_json::json;
+
+    fn flow(path: &str, status: u16, body: &str) -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "api.example.com".to_string(),
+            443,
+            path.to_string(),
+        );
+        request.timestamp_start = Some(100.0);
+        let mut response = HTTPResponse::new(status, "OK".to_string());
+        response.set_header("Content-Type".to_string(), "application/json".to_string());
+        response.set_content(body.as_bytes().to_vec());
+        response.timestamp_end = Some(100.25);
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    fn spec(value: Value) -> ExpectationSpec {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_assertions() {
+        let mut expectations = Expectations::from_specs(&[
+            spec(json!({"name": "ok", "filter": "~u /users", "assert": "status", "equals": 200})),
+            spec(json!({"name": "ct", "assert": "header", "header": "content-type"})),
+            spec(json!({"name": "id", "filter": "~u /users", "assert": "json_path", "path": "$.items[0].id", "equals": 7})),
+            spec(json!({"name": "fast", "assert": "latency", "max_ms": 200})),
+        ])
+        .unwrap();
+
+        let good = flow("/users", 200, r#"{"items": [{"id": 7}]}"#);
+        let bad = flow("/users", 500, r#"{"items": []}"#);
+        let other = flow("/other", 404, "{}");
+        expectations.evaluate(&good);
+        expectations.evaluate(&bad);
+        expectations.evaluate(&other);
+
+        let results = expectations.results();
+        assert_eq!((results[0].passed, results[0].failed), (1, 1));
+        assert_eq!(results[0].failing_flows[0].flow_id, bad.flow.id);
+        assert_eq!((results[1].passed, results[1].failed), (3, 0));
+        assert_eq!((results[2].passed, results[2].failed), (1, 1));
+        assert_eq!(results[2].failing_flows[0].reason, "$.items[0].id not found");
+        assert_eq!((results[3].passed, results[3].failed), (0, 3));
+    }
+
+    #[test]
+    fn test_reevaluation_and_pending_flows() {
+        let mut expectations =
+            Expectations::from_specs(&[spec(json!({"name": "ok", "assert": "status", "equals": 200}))]).unwrap();
+
+        let mut f = flow("/", 500, "");
+        expectations.evaluate(&f);
+        f.response.as_mut().unwrap().status_code = 200;
+        expectations.evaluate(&f);
+
+        let pending = HTTPFlow::new(f.request.clone());
+        expectations.evaluate(&pending);
+
+        let results = expectations.results();
+        assert_eq!((results[0].passed, results[0].failed), (1, 0));
+
+        expectations.forget(&f.flow.id);
+        assert_eq!(expectations.results()[0].passed, 0);
+        expectations.evaluate(&f);
+        expectations.reset();
+        assert_eq!(expectations.results()[0].passed, 0);
+    }
+
+    #[test]
+    fn test_add_replaces_and_validates() {
+        let mut expectations = Expectations::default();
+        expectations.add(spec(json!({"name": "a", "assert": "status", "equals": 200}))).unwrap();
+        expectations.add(spec(json!({"name": "a", "assert": "status", "equals": 201}))).unwrap();
+        assert_eq!(expectations.specs().len(), 1);
+        assert!(expectations.add(spec(json!({"name": "b", "filter": "(", "assert": "status", "equals": 1}))).is_err());
+        assert!(expectations.remove("a"));
+        assert!(!expectations.remove("a"));
+    }
+
+    #[test]
+    fn test_json_pointer() {
+        assert_eq!(json_pointer("$.data.items[0].id"), "/data/items/0/id");
+        assert_eq!(json_pointer("a/b.c"), "/a~1b/c");
+        assert_eq!(json_pointer("$"), "");
+    }
+}
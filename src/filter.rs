@@ -1,7 +1,11 @@
 use regex::Regex;
 use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use tracing::error;
 
 use crate::flow::{HTTPFlow, FlowType};
+use crate::panics;
+use crate::transforms;
 use crate::{Error, Result};
 
 #[derive(Debug, Clone)]
@@ -19,16 +23,25 @@ pub enum CompiledFilter {
     Host(Regex),
     Path(Regex),
     Body(Regex),
+    /// Lowercase hex prefix of a request or response body hash
+    BodyHash(String),
     Header { name: String, pattern: Regex },
     StatusCode(u16),
     ContentType(Regex),
     Url(Regex),
     Error,
     Marked,
+    /// Flow without a response yet
+    Request,
+    /// Flow with a response
+    Response,
+    Dns,
     Http,
     Tcp,
     Udp,
     WebSocket,
+    /// Registered filter and the argument written after its name
+    Custom(CustomFilter, Option<String>),
     And(Box<CompiledFilter>, Box<CompiledFilter>),
     Or(Box<CompiledFilter>, Box<CompiledFilter>),
     Not(Box<CompiledFilter>),
@@ -48,6 +61,20 @@ impl Filter {
         self.compiled.matches(flow)
     }
 
+    /// Match against a destination only, for decisions made before any
+    /// request is seen. Only address-based expressions such as `~d` are
+    /// meaningful here.
+    pub fn matches_address(&self, host: &str, port: u16) -> bool {
+        let probe = HTTPFlow::new(crate::flow::HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            host.to_string(),
+            port,
+            "/".to_string(),
+        ));
+        self.compiled.matches(&probe)
+    }
+
     fn compile(expr: &str) -> Result<CompiledFilter> {
         let expr = expr.trim();
 
@@ -97,6 +124,14 @@ impl Filter {
             return Ok(CompiledFilter::Url(regex));
         }
 
+        if let Some(hash) = expr.strip_prefix("~bhash ") {
+            let hash = hash.trim().to_ascii_lowercase();
+            if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::filter(format!("Invalid body hash: {}", hash)));
+            }
+            return Ok(CompiledFilter::BodyHash(hash));
+        }
+
         if expr.starts_with("~b ") {
             let pattern = expr[3..].trim();
             let regex = Regex::new(pattern).map_err(|e| Error::filter(format!("Invalid regex: {}", e)))?;
@@ -129,10 +164,23 @@ impl Filter {
             return Ok(CompiledFilter::ContentType(regex));
         }
 
+        if let Some(rest) = expr.strip_prefix('~') {
+            let (name, argument) = match rest.split_once(char::is_whitespace) {
+                Some((name, argument)) => (name, Some(argument.trim().to_string())),
+                None => (rest, None),
+            };
+            if let Some(custom) = custom_filters().read().unwrap().get(name) {
+                return Ok(CompiledFilter::Custom(custom.clone(), argument.filter(|a| !a.is_empty())));
+            }
+        }
+
         // Handle simple keywords
         match expr {
             "~e" => Ok(CompiledFilter::Error),
             "~marked" => Ok(CompiledFilter::Marked),
+            "~q" => Ok(CompiledFilter::Request),
+            "~s" => Ok(CompiledFilter::Response),
+            "~dns" => Ok(CompiledFilter::Dns),
             "~http" => Ok(CompiledFilter::Http),
             "~tcp" => Ok(CompiledFilter::Tcp),
             "~udp" => Ok(CompiledFilter::Udp),
@@ -165,23 +213,34 @@ impl CompiledFilter {
             }
 
             CompiledFilter::Body(regex) => {
+                // Bodies are matched as transformed for inspection
+                let request_type = flow.request.get_header("content-type").map(String::as_str);
                 if let Some(content) = &flow.request.content {
-                    if let Ok(text) = String::from_utf8(content.clone()) {
-                        if regex.is_match(&text) {
+                    if let Ok(text) = std::str::from_utf8(&transforms::apply(request_type, content).data) {
+                        if regex.is_match(text) {
                             return true;
                         }
                     }
                 }
                 if let Some(response) = &flow.response {
+                    let response_type = response.get_header("content-type").map(String::as_str);
                     if let Some(content) = &response.content {
-                        if let Ok(text) = String::from_utf8(content.clone()) {
-                            return regex.is_match(&text);
+                        if let Ok(text) = std::str::from_utf8(&transforms::apply(response_type, content).data) {
+                            return regex.is_match(text);
                         }
                     }
                 }
                 false
             }
 
+            CompiledFilter::BodyHash(prefix) => {
+                let response_hash = flow.response.as_ref().and_then(|r| r.body_hash());
+                [flow.request.body_hash(), response_hash]
+                    .into_iter()
+                    .flatten()
+                    .any(|hash| hash.starts_with(prefix.as_str()))
+            }
+
             CompiledFilter::Header { name, pattern } => {
                 // Check request headers
                 for (header_name, header_value) in &flow.request.headers {
@@ -230,11 +289,17 @@ impl CompiledFilter {
 
             CompiledFilter::Marked => !flow.flow.marked.is_empty(),
 
+            CompiledFilter::Request => flow.response.is_none(),
+            CompiledFilter::Response => flow.response.is_some(),
+
+            CompiledFilter::Dns => matches!(flow.flow.flow_type, FlowType::Dns),
             CompiledFilter::Http => matches!(flow.flow.flow_type, FlowType::Http),
             CompiledFilter::Tcp => matches!(flow.flow.flow_type, FlowType::Tcp),
             CompiledFilter::Udp => matches!(flow.flow.flow_type, FlowType::Udp),
             CompiledFilter::WebSocket => flow.websocket.is_some(),
 
+            CompiledFilter::Custom(custom, argument) => custom.matches(flow, argument.as_deref()),
+
             CompiledFilter::And(left, right) => {
                 left.matches(flow) && right.matches(flow)
             }
@@ -248,6 +313,78 @@ impl CompiledFilter {
     }
 }
 
+/// Predicate behind a custom filter, called with the flow and the argument
+/// written after the filter name, if any
+pub type FilterPredicate = Arc<dyn Fn(&HTTPFlow, Option<&str>) -> bool + Send + Sync>;
+
+/// Filter implemented in code and registered at runtime as `~name`
+#[derive(Clone)]
+pub struct CustomFilter {
+    pub name: String,
+    pub help: String,
+    /// Addon that registered the filter
+    pub owner: String,
+    predicate: FilterPredicate,
+}
+
+impl std::fmt::Debug for CustomFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CustomFilter")
+            .field("name", &self.name)
+            .field("owner", &self.owner)
+            .finish()
+    }
+}
+
+impl CustomFilter {
+    /// A panicking predicate does not match
+    fn matches(&self, flow: &HTTPFlow, argument: Option<&str>) -> bool {
+        panics::catch(|| (self.predicate)(flow, argument)).unwrap_or_else(|report| {
+            error!("Filter ~{} of addon {} {}", self.name, self.owner, report);
+            false
+        })
+    }
+}
+
+fn custom_filters() -> &'static RwLock<HashMap<String, CustomFilter>> {
+    static FILTERS: OnceLock<RwLock<HashMap<String, CustomFilter>>> = OnceLock::new();
+    FILTERS.get_or_init(RwLock::default)
+}
+
+/// Make `~name` available in filter expressions. Expressions compiled
+/// before the registration treat it as a URL regex, as before. Names are
+/// alphanumeric and may not shadow a built-in filter or another owner's.
+pub fn register_custom_filter(owner: &str, name: &str, help: &str, predicate: FilterPredicate) -> Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(Error::filter(format!("Invalid filter name: {}", name)));
+    }
+    if get_filter_help().contains_key(format!("~{}", name).as_str()) {
+        return Err(Error::filter(format!("~{} is a built-in filter", name)));
+    }
+    let mut filters = custom_filters().write().unwrap();
+    if let Some(existing) = filters.get(name).filter(|f| f.owner != owner) {
+        return Err(Error::filter(format!("~{} is already registered by {}", name, existing.owner)));
+    }
+    filters.insert(
+        name.to_string(),
+        CustomFilter { name: name.to_string(), help: help.to_string(), owner: owner.to_string(), predicate },
+    );
+    Ok(())
+}
+
+/// Remove the filters registered by `owner`. Compiled expressions using
+/// them keep working.
+pub fn unregister_custom_filters(owner: &str) {
+    custom_filters().write().unwrap().retain(|_, f| f.owner != owner);
+}
+
+/// Registered custom filters, sorted by name
+pub fn list_custom_filters() -> Vec<CustomFilter> {
+    let mut filters: Vec<_> = custom_filters().read().unwrap().values().cloned().collect();
+    filters.sort_by(|a, b| a.name.cmp(&b.name));
+    filters
+}
+
 // Helper function to find logical operators at the top level (not inside parentheses)
 fn find_operator(expr: &str, op: &str) -> Option<usize> {
     let mut depth = 0;
@@ -274,10 +411,12 @@ pub fn get_filter_help() -> HashMap<&'static str, &'static str> {
 
     help.insert("~a", "Asset content-type");
     help.insert("~b", "Body");
+    help.insert("~bhash", "Body SHA-256 hash (prefix)");
     help.insert("~bq", "Body request");
     help.insert("~bs", "Body response");
     help.insert("~c", "Code");
     help.insert("~d", "Domain");
+    help.insert("~dns", "DNS flow");
     help.insert("~dst", "Destination address");
     help.insert("~e", "Error");
     help.insert("~h", "Header");
@@ -378,6 +517,17 @@ mod tests {
         assert!(!filter.matches(&flow));
     }
 
+    #[test]
+    fn test_request_response_filters() {
+        let mut flow = create_test_flow();
+        let request = Filter::new("test".to_string(), "~q".to_string()).unwrap();
+        let response = Filter::new("test".to_string(), "~s".to_string()).unwrap();
+        assert!(request.matches(&flow) && !response.matches(&flow));
+
+        flow.response = Some(HTTPResponse::new(200, "OK".to_string()));
+        assert!(!request.matches(&flow) && response.matches(&flow));
+    }
+
     #[test]
     fn test_error_filter() {
         let mut flow = create_test_flow();
@@ -401,4 +551,60 @@ mod tests {
         let flow_unmarked = create_test_flow();
         assert!(!filter.matches(&flow_unmarked));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_body_hash_filter() {
+        let mut flow = create_test_flow();
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_content(b"hello".to_vec());
+        flow.response = Some(response);
+
+        // sha256("hello")
+        let filter = Filter::new("test".to_string(), "~bhash 2CF24DBA5F".to_string()).unwrap();
+        assert!(filter.matches(&flow));
+
+        let filter = Filter::new("test".to_string(), "~bhash 2cf25".to_string()).unwrap();
+        assert!(!filter.matches(&flow));
+
+        assert!(Filter::new("test".to_string(), "~bhash xyz".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_custom_filter() {
+        let predicate: FilterPredicate = Arc::new(|flow, argument| {
+            let limit = argument.and_then(|a| a.parse().ok()).unwrap_or(0);
+            flow.request.path.len() > limit
+        });
+        register_custom_filter("fraud", "longpath", "Path longer than N", predicate.clone()).unwrap();
+        assert!(register_custom_filter("fraud", "m", "", predicate.clone()).is_err());
+        assert!(register_custom_filter("fraud", "bad name", "", predicate.clone()).is_err());
+        assert!(register_custom_filter("other", "longpath", "", predicate).is_err());
+        assert!(list_custom_filters().iter().any(|f| f.name == "longpath" && f.owner == "fraud"));
+
+        let flow = create_test_flow();
+        assert!(Filter::new("t".to_string(), "~longpath".to_string()).unwrap().matches(&flow));
+        let filter = Filter::new("t".to_string(), "~m GET & !~longpath 100".to_string()).unwrap();
+        assert!(filter.matches(&flow));
+        assert!(!Filter::new("t".to_string(), "~longpath 100".to_string()).unwrap().matches(&flow));
+
+        unregister_custom_filters("fraud");
+        assert!(!list_custom_filters().iter().any(|f| f.name == "longpath"));
+        // Compiled expressions keep the predicate
+        assert!(filter.matches(&flow));
+    }
+
+    #[test]
+    fn test_body_filter_matches_transformed_body() {
+        let reverse: transforms::BodyTransformFn = Arc::new(|body| Ok(body.iter().rev().copied().collect()));
+        transforms::register_body_transform("filter-test", "reverse", "x-filter-test/reversed", reverse).unwrap();
+
+        let mut flow = create_test_flow();
+        flow.request.set_header("Content-Type".to_string(), "x-filter-test/reversed".to_string());
+        flow.request.set_content(b"terces".to_vec());
+        let filter = Filter::new("test".to_string(), "~b secret".to_string()).unwrap();
+        assert!(filter.matches(&flow));
+
+        transforms::unregister_body_transforms("filter-test");
+        assert!(!filter.matches(&flow));
+    }
+}
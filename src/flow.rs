@@ -1,10 +1,17 @@
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+
+use crate::changelog::Change;
+use crate::clock::{Clock, IdGenerator};
+use crate::sse::{SseEvent, SseParser, SseStreamStats};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Flow {
     pub id: String,
+    /// Position in the order flows were stored, starting at 1; 0 until the
+    /// flow is stored
+    #[serde(default)]
+    pub seq: u64,
     pub flow_type: FlowType,
     pub intercepted: bool,
     pub is_replay: bool,
@@ -15,8 +22,17 @@ pub struct Flow {
     pub client_conn: Option<Connection>,
     pub server_conn: Option<Connection>,
     pub error: Option<FlowError>,
+    /// Arbitrary data attached by addons and proxy features
+    #[serde(default)]
+    pub metadata: IndexMap<String, serde_json::Value>,
+    /// Modifications made by the proxy, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<Change>,
 }
 
+/// Flow metadata key of the tags attached through the API
+pub const TAGS_KEY: &str = "tags";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum FlowType {
@@ -33,6 +49,19 @@ pub struct HTTPFlow {
     pub request: HTTPRequest,
     pub response: Option<HTTPResponse>,
     pub websocket: Option<WebSocketFlow>,
+    /// Events received on a `text/event-stream` response
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sse_events: Vec<SseMessage>,
+    /// Duration, event and stall metrics of a `text/event-stream` response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sse_stream: Option<SseStreamStats>,
+    /// Data exchanged on a connection that was not HTTP, in a flow of
+    /// type `tcp`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tcp_messages: Vec<TCPMessage>,
+    /// Query and answer of a flow of type `dns`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<DNSFlow>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,7 +95,7 @@ pub struct HTTPResponse {
     pub trailers: Option<Vec<(String, String)>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct WebSocketFlow {
     pub messages_meta: WebSocketMessagesMeta,
     pub closed_by_client: Option<bool>,
@@ -76,7 +105,7 @@ pub struct WebSocketFlow {
     pub messages: Vec<WebSocketMessage>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct WebSocketMessagesMeta {
     pub content_length: usize,
     pub count: usize,
@@ -101,6 +130,68 @@ pub enum WebSocketMessageType {
     Close,
 }
 
+/// A chunk of data received on a raw TCP connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TCPMessage {
+    pub from_client: bool,
+    pub content: Vec<u8>,
+    pub timestamp: f64,
+}
+
+/// A DNS query and the answer to it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DNSFlow {
+    pub request: DNSMessage,
+    pub response: Option<DNSMessage>,
+}
+
+/// A DNS message as defined by RFC 1035
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DNSMessage {
+    pub id: u16,
+    /// Whether the message is a query rather than a response
+    pub query: bool,
+    pub op_code: u8,
+    pub authoritative_answer: bool,
+    pub truncation: bool,
+    pub recursion_desired: bool,
+    pub recursion_available: bool,
+    pub reserved: u8,
+    pub response_code: u8,
+    pub questions: Vec<DNSQuestion>,
+    pub answers: Vec<DNSResourceRecord>,
+    pub authorities: Vec<DNSResourceRecord>,
+    pub additionals: Vec<DNSResourceRecord>,
+    pub timestamp: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DNSQuestion {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: u16,
+    pub class: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DNSResourceRecord {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: u16,
+    pub class: u16,
+    pub ttl: u32,
+    /// Record data in wire format, with any names in it uncompressed
+    pub data: Vec<u8>,
+}
+
+/// A server-sent event with the time it was received
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SseMessage {
+    #[serde(flatten)]
+    pub event: SseEvent,
+    pub timestamp: f64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Connection {
     pub id: String,
@@ -137,20 +228,40 @@ pub struct FlowError {
     pub timestamp: f64,
 }
 
+impl FlowType {
+    /// Name of the type as used in the API
+    pub fn name(&self) -> &'static str {
+        match self {
+            FlowType::Http => "http",
+            FlowType::Tcp => "tcp",
+            FlowType::Udp => "udp",
+            FlowType::Dns => "dns",
+        }
+    }
+}
+
 impl Flow {
     pub fn new(flow_type: FlowType) -> Self {
+        Self::with_sources(flow_type, &*crate::clock::clock(), &*crate::clock::ids())
+    }
+
+    /// A flow created at the time of `clock` with an id from `ids`
+    pub fn with_sources(flow_type: FlowType, clock: &dyn Clock, ids: &dyn IdGenerator) -> Self {
         Self {
-            id: Uuid::new_v4().to_string(),
+            id: ids.flow_id(),
+            seq: 0,
             flow_type,
             intercepted: false,
             is_replay: false,
             modified: false,
             marked: String::new(),
             comment: String::new(),
-            timestamp_created: chrono::Utc::now().timestamp() as f64,
+            timestamp_created: clock.now(),
             client_conn: None,
             server_conn: None,
             error: None,
+            metadata: IndexMap::new(),
+            changes: Vec::new(),
         }
     }
 
@@ -161,7 +272,7 @@ impl Flow {
     pub fn set_error(&mut self, msg: String) {
         self.error = Some(FlowError {
             msg,
-            timestamp: chrono::Utc::now().timestamp() as f64,
+            timestamp: crate::clock::now(),
         });
     }
 
@@ -176,18 +287,90 @@ impl Flow {
     pub fn killable(&self) -> bool {
         !self.is_replay && self.error.is_none()
     }
+
+    /// Tags attached to the flow, kept in its metadata
+    pub fn tags(&self) -> Vec<&str> {
+        self.metadata
+            .get(TAGS_KEY)
+            .and_then(|tags| tags.as_array())
+            .map(|tags| tags.iter().filter_map(|tag| tag.as_str()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Attach `tag`. Returns false if the flow already had it.
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        if self.tags().contains(&tag) {
+            return false;
+        }
+        let mut tags: Vec<String> = self.tags().into_iter().map(str::to_string).collect();
+        tags.push(tag.to_string());
+        self.metadata.insert(TAGS_KEY.to_string(), serde_json::json!(tags));
+        true
+    }
+
+    /// Detach `tag`. Returns false if the flow did not have it.
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        let tags = self.tags();
+        if !tags.contains(&tag) {
+            return false;
+        }
+        let tags: Vec<String> = tags.into_iter().filter(|t| *t != tag).map(str::to_string).collect();
+        if tags.is_empty() {
+            self.metadata.shift_remove(TAGS_KEY);
+        } else {
+            self.metadata.insert(TAGS_KEY.to_string(), serde_json::json!(tags));
+        }
+        true
+    }
 }
 
 impl HTTPFlow {
     pub fn new(request: HTTPRequest) -> Self {
+        Self::with_sources(request, &*crate::clock::clock(), &*crate::clock::ids())
+    }
+
+    /// An HTTP flow created at the time of `clock` with an id from `ids`
+    pub fn with_sources(request: HTTPRequest, clock: &dyn Clock, ids: &dyn IdGenerator) -> Self {
         Self {
-            flow: Flow::new(FlowType::Http),
+            flow: Flow::with_sources(FlowType::Http, clock, ids),
             request,
             response: None,
             websocket: None,
+            sse_events: Vec::new(),
+            sse_stream: None,
+            tcp_messages: Vec::new(),
+            dns: None,
+        }
+    }
+
+    /// A flow of type `tcp` for a raw connection to `host:port`. TCP flows
+    /// are stored alongside HTTP flows; their request only names the
+    /// server and the data is kept in `tcp_messages`.
+    pub fn new_tcp(host: String, port: u16) -> Self {
+        let request = HTTPRequest::new(String::new(), "tcp".to_string(), host, port, String::new());
+        Self { flow: Flow::new(FlowType::Tcp), ..Self::new(request) }
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        matches!(self.flow.flow_type, FlowType::Tcp)
+    }
+
+    /// A flow of type `dns` for a query. Its request names the first
+    /// question, so that domain filters match DNS flows.
+    pub fn new_dns(query: DNSMessage) -> Self {
+        let name = query.questions.first().map(|q| q.name.clone()).unwrap_or_default();
+        let request = HTTPRequest::new(String::new(), "dns".to_string(), name, 53, String::new());
+        Self {
+            flow: Flow::new(FlowType::Dns),
+            dns: Some(DNSFlow { request: query, response: None }),
+            ..Self::new(request)
         }
     }
 
+    pub fn is_dns(&self) -> bool {
+        matches!(self.flow.flow_type, FlowType::Dns)
+    }
+
     pub fn with_response(mut self, response: HTTPResponse) -> Self {
         self.response = Some(response);
         self
@@ -198,6 +381,44 @@ impl HTTPFlow {
         self
     }
 
+    /// Whether the response is a server-sent event stream
+    pub fn is_event_stream(&self) -> bool {
+        self.response
+            .as_ref()
+            .and_then(|r| r.get_header("content-type"))
+            .is_some_and(|ct| ct.to_ascii_lowercase().starts_with("text/event-stream"))
+    }
+
+    /// Parse the events of a completed event-stream response into
+    /// `sse_events`, unless they were captured while streaming. All events
+    /// get the response end time, and the stream gets metrics unless it was
+    /// monitored while open. Returns whether events were added.
+    pub fn capture_sse_events(&mut self) -> bool {
+        if !self.sse_events.is_empty() || !self.is_event_stream() {
+            return false;
+        }
+        let Some(response) = &self.response else {
+            return false;
+        };
+        let Some(content) = response.content.as_deref() else {
+            return false;
+        };
+
+        let timestamp = response
+            .timestamp_end
+            .or(response.timestamp_start)
+            .unwrap_or(self.flow.timestamp_created);
+        let start = response.timestamp_start.unwrap_or(timestamp);
+        let mut parser = SseParser::new();
+        let mut events = parser.parse_chunk(content);
+        events.extend(parser.flush());
+        self.sse_events = events.into_iter().map(|event| SseMessage { event, timestamp }).collect();
+        if self.sse_stream.is_none() {
+            self.sse_stream = Some(SseStreamStats::completed(start, timestamp, self.sse_events.len()));
+        }
+        !self.sse_events.is_empty()
+    }
+
     pub fn backup(&mut self) {
         // In the original mitmproxy, this creates a backup for revert functionality
         // For now, we'll mark it as modified
@@ -211,7 +432,8 @@ impl HTTPFlow {
 
     pub fn copy(&self) -> Self {
         let mut new_flow = self.clone();
-        new_flow.flow.id = Uuid::new_v4().to_string();
+        new_flow.flow.id = crate::clock::flow_id();
+        new_flow.flow.seq = 0;
         new_flow.flow.is_replay = true;
         new_flow
     }
@@ -220,9 +442,15 @@ impl HTTPFlow {
         // Convert to the same JSON format as mitmproxy
         let mut json = serde_json::json!({
             "id": self.flow.id,
+            "seq": self.flow.seq,
             "intercepted": self.flow.intercepted,
-            "is_replay": self.flow.is_replay,
-            "type": "http",
+            // mitmproxy tells replayed requests from replayed responses
+            "is_replay": if self.flow.is_replay {
+                Some("request")
+            } else {
+                self.flow.metadata.contains_key(crate::serverreplay::METADATA_KEY).then_some("response")
+            },
+            "type": self.flow.flow_type.name(),
             "modified": self.flow.modified,
             "marked": self.flow.marked,
             "comment": self.flow.comment,
@@ -241,6 +469,24 @@ impl HTTPFlow {
             json["error"] = serde_json::to_value(error).unwrap();
         }
 
+        if !self.flow.metadata.is_empty() {
+            json["metadata"] = serde_json::to_value(&self.flow.metadata).unwrap();
+        }
+
+        if self.is_tcp() {
+            json["server_address"] = serde_json::json!([self.request.host, self.request.port]);
+            json["messages"] = serde_json::to_value(&self.tcp_messages).unwrap();
+            return json;
+        }
+
+        if let Some(dns) = &self.dns {
+            json["request"] = dns.request.to_json();
+            if let Some(response) = &dns.response {
+                json["response"] = response.to_json();
+            }
+            return json;
+        }
+
         json["request"] = serde_json::to_value(&self.request).unwrap();
 
         if let Some(response) = &self.response {
@@ -251,10 +497,190 @@ impl HTTPFlow {
             json["websocket"] = serde_json::to_value(websocket).unwrap();
         }
 
+        if let Some(sse_stream) = &self.sse_stream {
+            json["sse_stream"] = serde_json::to_value(sse_stream).unwrap();
+        }
+
         json
     }
 }
 
+impl DNSMessage {
+    /// A response to this query with `answers` and no error
+    pub fn succeed(&self, answers: Vec<DNSResourceRecord>) -> Self {
+        Self {
+            query: false,
+            recursion_available: true,
+            response_code: dns_response_code::NOERROR,
+            answers,
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            timestamp: crate::clock::now(),
+            ..self.clone()
+        }
+    }
+
+    /// A response to this query failing with `response_code`
+    pub fn fail(&self, response_code: u8) -> Self {
+        Self { response_code, ..self.succeed(Vec::new()) }
+    }
+
+    /// The message in the JSON format of mitmproxy, with type names and
+    /// record data as text
+    pub fn to_json(&self) -> serde_json::Value {
+        let records = |records: &[DNSResourceRecord]| -> Vec<serde_json::Value> {
+            records
+                .iter()
+                .map(|r| {
+                    serde_json::json!({
+                        "name": r.name,
+                        "type": dns_type_name(r.record_type),
+                        "class": dns_class_name(r.class),
+                        "ttl": r.ttl,
+                        "data": r.data_text(),
+                    })
+                })
+                .collect()
+        };
+        let questions: Vec<serde_json::Value> = self
+            .questions
+            .iter()
+            .map(|q| serde_json::json!({ "name": q.name, "type": dns_type_name(q.record_type), "class": dns_class_name(q.class) }))
+            .collect();
+        serde_json::json!({
+            "id": self.id,
+            "query": self.query,
+            "op_code": self.op_code,
+            "authoritative_answer": self.authoritative_answer,
+            "truncation": self.truncation,
+            "recursion_desired": self.recursion_desired,
+            "recursion_available": self.recursion_available,
+            "response_code": dns_response_code::name(self.response_code),
+            "questions": questions,
+            "answers": records(&self.answers),
+            "authorities": records(&self.authorities),
+            "additionals": records(&self.additionals),
+            "timestamp": self.timestamp,
+        })
+    }
+}
+
+impl DNSResourceRecord {
+    /// An A or AAAA record for `ip`
+    pub fn address(name: String, ip: std::net::IpAddr, ttl: u32) -> Self {
+        let (record_type, data) = match ip {
+            std::net::IpAddr::V4(ip) => (dns_type::A, ip.octets().to_vec()),
+            std::net::IpAddr::V6(ip) => (dns_type::AAAA, ip.octets().to_vec()),
+        };
+        Self { name, record_type, class: dns_class::IN, ttl, data }
+    }
+
+    /// The record data as text: addresses and names as such, TXT strings
+    /// joined, anything else hex encoded
+    pub fn data_text(&self) -> String {
+        match self.record_type {
+            dns_type::A if self.data.len() == 4 => {
+                std::net::Ipv4Addr::from(<[u8; 4]>::try_from(self.data.as_slice()).unwrap()).to_string()
+            }
+            dns_type::AAAA if self.data.len() == 16 => {
+                std::net::Ipv6Addr::from(<[u8; 16]>::try_from(self.data.as_slice()).unwrap()).to_string()
+            }
+            dns_type::CNAME | dns_type::NS | dns_type::PTR => {
+                let mut labels = Vec::new();
+                let mut rest = self.data.as_slice();
+                while let Some((&len, tail)) = rest.split_first() {
+                    if len == 0 || tail.len() < len as usize {
+                        break;
+                    }
+                    labels.push(String::from_utf8_lossy(&tail[..len as usize]).into_owned());
+                    rest = &tail[len as usize..];
+                }
+                labels.join(".")
+            }
+            dns_type::TXT => {
+                let mut text = String::new();
+                let mut rest = self.data.as_slice();
+                while let Some((&len, tail)) = rest.split_first() {
+                    let len = (len as usize).min(tail.len());
+                    text.push_str(&String::from_utf8_lossy(&tail[..len]));
+                    rest = &tail[len..];
+                }
+                text
+            }
+            _ => self.data.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
+/// DNS record types
+pub mod dns_type {
+    pub const A: u16 = 1;
+    pub const NS: u16 = 2;
+    pub const CNAME: u16 = 5;
+    pub const SOA: u16 = 6;
+    pub const PTR: u16 = 12;
+    pub const MX: u16 = 15;
+    pub const TXT: u16 = 16;
+    pub const AAAA: u16 = 28;
+    pub const SRV: u16 = 33;
+    pub const OPT: u16 = 41;
+    pub const HTTPS: u16 = 65;
+    pub const ANY: u16 = 255;
+}
+
+/// DNS classes
+pub mod dns_class {
+    pub const IN: u16 = 1;
+}
+
+/// DNS response codes
+pub mod dns_response_code {
+    pub const NOERROR: u8 = 0;
+    pub const FORMERR: u8 = 1;
+    pub const SERVFAIL: u8 = 2;
+    pub const NXDOMAIN: u8 = 3;
+    pub const NOTIMP: u8 = 4;
+    pub const REFUSED: u8 = 5;
+
+    pub fn name(code: u8) -> String {
+        match code {
+            NOERROR => "NOERROR".to_string(),
+            FORMERR => "FORMERR".to_string(),
+            SERVFAIL => "SERVFAIL".to_string(),
+            NXDOMAIN => "NXDOMAIN".to_string(),
+            NOTIMP => "NOTIMP".to_string(),
+            REFUSED => "REFUSED".to_string(),
+            code => format!("RCODE({})", code),
+        }
+    }
+}
+
+/// Name of a DNS record type, e.g. `AAAA`
+pub fn dns_type_name(record_type: u16) -> String {
+    match record_type {
+        dns_type::A => "A".to_string(),
+        dns_type::NS => "NS".to_string(),
+        dns_type::CNAME => "CNAME".to_string(),
+        dns_type::SOA => "SOA".to_string(),
+        dns_type::PTR => "PTR".to_string(),
+        dns_type::MX => "MX".to_string(),
+        dns_type::TXT => "TXT".to_string(),
+        dns_type::AAAA => "AAAA".to_string(),
+        dns_type::SRV => "SRV".to_string(),
+        dns_type::OPT => "OPT".to_string(),
+        dns_type::HTTPS => "HTTPS".to_string(),
+        dns_type::ANY => "ANY".to_string(),
+        record_type => format!("TYPE({})", record_type),
+    }
+}
+
+fn dns_class_name(class: u16) -> String {
+    match class {
+        dns_class::IN => "IN".to_string(),
+        class => format!("CLASS({})", class),
+    }
+}
+
 impl HTTPRequest {
     pub fn new(
         method: String,
@@ -292,15 +718,16 @@ impl HTTPRequest {
 
     pub fn set_content(&mut self, content: Vec<u8>) {
         self.content_length = Some(content.len());
-        if !content.is_empty() {
-            use sha2::{Digest, Sha256};
-            let mut hasher = Sha256::new();
-            hasher.update(&content);
-            self.content_hash = Some(format!("{:x}", hasher.finalize()));
-        }
+        self.content_hash = (!content.is_empty()).then(|| content_hash(&content));
         self.content = Some(content);
     }
 
+    /// SHA-256 of the body, computed if it was not stored with the content
+    pub fn body_hash(&self) -> Option<String> {
+        let content = self.content.as_deref().filter(|c| !c.is_empty())?;
+        Some(self.content_hash.clone().unwrap_or_else(|| content_hash(content)))
+    }
+
     pub fn get_header(&self, name: &str) -> Option<&String> {
         self.headers.iter()
             .find(|(k, _)| k.eq_ignore_ascii_case(name))
@@ -311,6 +738,24 @@ impl HTTPRequest {
         self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(&name));
         self.headers.push((name, value));
     }
+
+    /// Remove the headers that let a server answer 304 Not Modified, so it
+    /// sends the full response. Returns whether any were removed.
+    pub fn anticache(&mut self) -> bool {
+        self.remove_headers(&["if-modified-since", "if-none-match"])
+    }
+
+    /// Remove `Accept-Encoding`, so the server sends an uncompressed
+    /// response. Returns whether it was removed.
+    pub fn anticomp(&mut self) -> bool {
+        self.remove_headers(&["accept-encoding"])
+    }
+
+    fn remove_headers(&mut self, names: &[&str]) -> bool {
+        let before = self.headers.len();
+        self.headers.retain(|(k, _)| !names.iter().any(|name| k.eq_ignore_ascii_case(name)));
+        self.headers.len() != before
+    }
 }
 
 impl HTTPResponse {
@@ -331,15 +776,16 @@ impl HTTPResponse {
 
     pub fn set_content(&mut self, content: Vec<u8>) {
         self.content_length = Some(content.len());
-        if !content.is_empty() {
-            use sha2::{Digest, Sha256};
-            let mut hasher = Sha256::new();
-            hasher.update(&content);
-            self.content_hash = Some(format!("{:x}", hasher.finalize()));
-        }
+        self.content_hash = (!content.is_empty()).then(|| content_hash(&content));
         self.content = Some(content);
     }
 
+    /// SHA-256 of the body, computed if it was not stored with the content
+    pub fn body_hash(&self) -> Option<String> {
+        let content = self.content.as_deref().filter(|c| !c.is_empty())?;
+        Some(self.content_hash.clone().unwrap_or_else(|| content_hash(content)))
+    }
+
     pub fn get_header(&self, name: &str) -> Option<&String> {
         self.headers.iter()
             .find(|(k, _)| k.eq_ignore_ascii_case(name))
@@ -352,6 +798,12 @@ impl HTTPResponse {
     }
 }
 
+/// Hex-encoded SHA-256 of a message body
+pub fn content_hash(content: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(content))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -365,6 +817,26 @@ mod tests {
         assert!(!flow.modified);
     }
 
+    #[test]
+    fn test_flow_seq() {
+        let mut flow = HTTPFlow::new(HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "example.com".to_string(),
+            443,
+            "/".to_string(),
+        ));
+        flow.flow.seq = 7;
+        assert_eq!(flow.to_json()["seq"], 7);
+        // Duplicates are numbered when stored
+        assert_eq!(flow.copy().flow.seq, 0);
+
+        // Dumps written before flows had sequence numbers still load
+        let mut value = serde_json::to_value(&flow).unwrap();
+        value.as_object_mut().unwrap().remove("seq");
+        assert_eq!(serde_json::from_value::<HTTPFlow>(value).unwrap().flow.seq, 0);
+    }
+
     #[test]
     fn test_http_request_url() {
         let request = HTTPRequest::new(
@@ -397,4 +869,26 @@ mod tests {
         );
         assert_eq!(request.pretty_host, "example.com:8443");
     }
+
+    #[test]
+    fn test_http_request_anticache_anticomp() {
+        let mut request = HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "example.com".to_string(),
+            443,
+            "/".to_string(),
+        );
+        request.headers = vec![
+            ("If-None-Match".to_string(), "\"abc\"".to_string()),
+            ("if-modified-since".to_string(), "Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+            ("Accept-Encoding".to_string(), "gzip, br".to_string()),
+            ("Accept".to_string(), "*/*".to_string()),
+        ];
+        assert!(request.anticache());
+        assert!(!request.anticache());
+        assert!(request.anticomp());
+        assert!(!request.anticomp());
+        assert_eq!(request.headers, vec![("Accept".to_string(), "*/*".to_string())]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,332 @@
+//! Structured access to form request bodies.
+//!
+//! Decodes `application/x-www-form-urlencoded` and `multipart/form-data`
+//! bodies into ordered fields and encodes edited fields back, keeping the
+//! multipart boundary where possible and updating the framing headers so
+//! the edited request can be forwarded or replayed as is.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::flow::HTTPRequest;
+use crate::{Error, Result};
+
+const URLENCODED: &str = "application/x-www-form-urlencoded";
+const MULTIPART: &str = "multipart/form-data";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FormKind {
+    Urlencoded,
+    Multipart,
+}
+
+/// A single form field. Multipart parts may carry a file name and content
+/// type; binary values are base64-encoded with `encoding` set to `base64`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FormField {
+    pub name: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+impl FormField {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            filename: None,
+            content_type: None,
+            encoding: None,
+        }
+    }
+
+    fn bytes(&self) -> Result<Vec<u8>> {
+        match self.encoding.as_deref() {
+            None => Ok(self.value.clone().into_bytes()),
+            Some("base64") => STANDARD
+                .decode(&self.value)
+                .map_err(|e| Error::invalid_request(format!("Invalid base64 in field {}: {}", self.name, e))),
+            Some(other) => Err(Error::invalid_request(format!("Unsupported field encoding: {}", other))),
+        }
+    }
+}
+
+/// A decoded form body
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Form {
+    #[serde(rename = "type")]
+    pub kind: FormKind,
+    pub fields: Vec<FormField>,
+}
+
+/// Form kind declared by a request's Content-Type
+pub fn form_kind(request: &HTTPRequest) -> Option<FormKind> {
+    let content_type = request.get_header("content-type")?.to_ascii_lowercase();
+    if content_type.starts_with(URLENCODED) {
+        Some(FormKind::Urlencoded)
+    } else if content_type.starts_with(MULTIPART) {
+        Some(FormKind::Multipart)
+    } else {
+        None
+    }
+}
+
+/// Decode the form body of a request
+pub fn decode(request: &HTTPRequest) -> Result<Form> {
+    let kind = form_kind(request).ok_or_else(|| Error::invalid_request("Request body is not a form"))?;
+    if let Some(encoding) = request.get_header("content-encoding").filter(|e| !e.eq_ignore_ascii_case("identity")) {
+        return Err(Error::invalid_request(format!("Cannot edit a form with Content-Encoding {}", encoding)));
+    }
+    let body = request.content.as_deref().unwrap_or_default();
+
+    let fields = match kind {
+        FormKind::Urlencoded => url::form_urlencoded::parse(body)
+            .map(|(name, value)| FormField::new(&name, &value))
+            .collect(),
+        FormKind::Multipart => {
+            let boundary = boundary(request).ok_or_else(|| Error::invalid_request("Multipart body without boundary"))?;
+            decode_multipart(body, &boundary)?
+        }
+    };
+    Ok(Form { kind, fields })
+}
+
+/// Replace the body of a request with the encoded fields. `kind` defaults
+/// to the request's current form type; a different kind converts the body
+/// and updates Content-Type. Content-Length is updated, and any transfer
+/// or content encoding dropped since the new body is sent as is.
+pub fn encode(request: &mut HTTPRequest, kind: Option<FormKind>, fields: &[FormField]) -> Result<()> {
+    let current = form_kind(request);
+    let kind = kind
+        .or(current)
+        .ok_or_else(|| Error::invalid_request("Request body is not a form; specify the form type"))?;
+
+    let body = match kind {
+        FormKind::Urlencoded => {
+            let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+            for field in fields {
+                let value = field.bytes()?;
+                let value = String::from_utf8(value)
+                    .map_err(|_| Error::invalid_request(format!("Field {} is not text", field.name)))?;
+                serializer.append_pair(&field.name, &value);
+            }
+            if current != Some(kind) {
+                replace_header(request, "Content-Type", URLENCODED.to_string());
+            }
+            serializer.finish().into_bytes()
+        }
+        FormKind::Multipart => {
+            let parts = fields
+                .iter()
+                .map(|field| Ok((field, field.bytes()?)))
+                .collect::<Result<Vec<_>>>()?;
+            // Keep the boundary unless the new content contains it
+            let boundary = boundary(request)
+                .filter(|_| current == Some(kind))
+                .filter(|b| !parts.iter().any(|(_, value)| contains(value, b.as_bytes())))
+                .unwrap_or_else(|| {
+                    let boundary = new_boundary();
+                    replace_header(request, "Content-Type", format!("{}; boundary={}", MULTIPART, boundary));
+                    boundary
+                });
+            encode_multipart(&parts, &boundary)
+        }
+    };
+
+    request.headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("transfer-encoding") && !name.eq_ignore_ascii_case("content-encoding")
+    });
+    replace_header(request, "Content-Length", body.len().to_string());
+    request.set_content(body);
+    Ok(())
+}
+
+/// Set a header, keeping its position if it is already present
+fn replace_header(request: &mut HTTPRequest, name: &str, value: String) {
+    match request.headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(name)) {
+        Some((_, existing)) => *existing = value,
+        None => request.headers.push((name.to_string(), value)),
+    }
+}
+
+fn boundary(request: &HTTPRequest) -> Option<String> {
+    let content_type = request.get_header("content-type")?;
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+            .filter(|b| !b.is_empty())
+    })
+}
+
+fn new_boundary() -> String {
+    format!("----mitmproxy{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack.get(from..)?.windows(needle.len()).position(|window| window == needle).map(|i| i + from)
+}
+
+fn decode_multipart(body: &[u8], boundary: &str) -> Result<Vec<FormField>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut fields = Vec::new();
+    let mut pos = find(body, &delimiter, 0).ok_or_else(|| Error::invalid_request("Multipart boundary not found"))?;
+
+    loop {
+        pos += delimiter.len();
+        if body[pos..].starts_with(b"--") {
+            break;
+        }
+        let start = pos + if body[pos..].starts_with(b"\r\n") { 2 } else { 0 };
+        let end = find(body, &delimiter, start).ok_or_else(|| Error::invalid_request("Unterminated multipart body"))?;
+        // The line break before the next delimiter belongs to it
+        let part = &body[start..end];
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        fields.push(decode_part(part)?);
+        pos = end;
+    }
+    Ok(fields)
+}
+
+fn decode_part(part: &[u8]) -> Result<FormField> {
+    let split = find(part, b"\r\n\r\n", 0).ok_or_else(|| Error::invalid_request("Multipart part without headers"))?;
+    let head = String::from_utf8_lossy(&part[..split]);
+    let content = &part[split + 4..];
+
+    let mut field = FormField::new("", "");
+    for line in head.split("\r\n") {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        if name.trim().eq_ignore_ascii_case("content-type") {
+            field.content_type = Some(value.trim().to_string());
+        } else if name.trim().eq_ignore_ascii_case("content-disposition") {
+            for param in value.split(';').skip(1) {
+                let Some((key, value)) = param.split_once('=') else { continue };
+                let value = value.trim().trim_matches('"').to_string();
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "name" => field.name = value,
+                    "filename" => field.filename = Some(value),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    match std::str::from_utf8(content) {
+        Ok(text) => field.value = text.to_string(),
+        Err(_) => {
+            field.value = STANDARD.encode(content);
+            field.encoding = Some("base64".to_string());
+        }
+    }
+    Ok(field)
+}
+
+fn encode_multipart(parts: &[(&FormField, Vec<u8>)], boundary: &str) -> Vec<u8> {
+    let quote = |s: &str| s.replace('"', "%22").replace(['\r', '\n'], " ");
+    let mut body = Vec::new();
+    for (field, value) in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        let mut disposition = format!("Content-Disposition: form-data; name=\"{}\"", quote(&field.name));
+        if let Some(filename) = &field.filename {
+            disposition.push_str(&format!("; filename=\"{}\"", quote(filename)));
+        }
+        body.extend_from_slice(disposition.as_bytes());
+        body.extend_from_slice(b"\r\n");
+        if let Some(content_type) = &field.content_type {
+            body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        body.extend_from_slice(value);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(content_type: &str, body: &[u8]) -> HTTPRequest {
+        let mut request = HTTPRequest::new(
+            "POST".to_string(),
+            "https".to_string(),
+            "example.com".to_string(),
+            443,
+            "/submit".to_string(),
+        );
+        request.headers = vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ];
+        request.set_content(body.to_vec());
+        request
+    }
+
+    #[test]
+    fn test_urlencoded_roundtrip() {
+        let mut req = request(URLENCODED, b"user=alice&note=hello+world%21&tag=a&tag=b");
+        let mut form = decode(&req).unwrap();
+        assert_eq!(form.kind, FormKind::Urlencoded);
+        assert_eq!(form.fields[1], FormField::new("note", "hello world!"));
+        assert_eq!(form.fields.len(), 4);
+
+        form.fields[0].value = "bob & co".to_string();
+        encode(&mut req, None, &form.fields).unwrap();
+        assert_eq!(req.content.as_deref().unwrap(), b"user=bob+%26+co&note=hello+world%21&tag=a&tag=b");
+        assert_eq!(req.get_header("content-length").unwrap(), "47");
+        assert_eq!(req.headers[1].0, "Content-Length");
+    }
+
+    #[test]
+    fn test_multipart_roundtrip() {
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n\
+            --XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\xff\x00\x01\r\n--XyZ--\r\n";
+        let mut req = request("multipart/form-data; boundary=\"XyZ\"", body);
+        let mut form = decode(&req).unwrap();
+        assert_eq!(form.fields[0], FormField::new("title", "Hello"));
+        assert_eq!(form.fields[1].filename.as_deref(), Some("a.bin"));
+        assert_eq!(form.fields[1].encoding.as_deref(), Some("base64"));
+        assert_eq!(form.fields[1].value, "/wAB");
+
+        // Unchanged fields encode to the original body
+        encode(&mut req, None, &form.fields).unwrap();
+        assert_eq!(req.content.as_deref().unwrap(), &body[..]);
+
+        // A value containing the boundary forces a new one
+        form.fields[0].value = "--XyZ".to_string();
+        encode(&mut req, None, &form.fields).unwrap();
+        let new_boundary = boundary(&req).unwrap();
+        assert_ne!(new_boundary, "XyZ");
+        assert_eq!(decode(&req).unwrap().fields, form.fields);
+    }
+
+    #[test]
+    fn test_convert_and_reject() {
+        let mut req = request("application/json", b"{}");
+        assert!(decode(&req).is_err());
+        assert!(encode(&mut req, None, &[]).is_err());
+
+        encode(&mut req, Some(FormKind::Multipart), &[FormField::new("a", "1")]).unwrap();
+        assert!(req.get_header("content-type").unwrap().starts_with("multipart/form-data; boundary="));
+        assert_eq!(decode(&req).unwrap().fields, [FormField::new("a", "1")]);
+
+        let mut gzipped = request(URLENCODED, b"\x1f\x8b");
+        gzipped.headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+        assert!(decode(&gzipped).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Live gauges of proxy activity.
+//!
+//! Open connections and transferred body bytes are counted as they change;
+//! the remaining gauges are read from the flow store when a snapshot is
+//! taken. Clients of `/updates` receive a snapshot every `interval_secs`
+//! as a `state/update` message, so the web UI can show an activity header
+//! without polling the REST API.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::flow::HTTPFlow;
+
+/// Gauge push options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GaugeOptions {
+    /// Time between two `state/update` messages; 0 disables them
+    pub interval_secs: u64,
+}
+
+impl Default for GaugeOptions {
+    fn default() -> Self {
+        Self { interval_secs: 2 }
+    }
+}
+
+/// Counters updated while traffic flows
+#[derive(Debug, Default)]
+pub struct Gauges {
+    client_connections: AtomicU64,
+    server_connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl Gauges {
+    /// Count an open client connection until the guard is dropped
+    pub fn client_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.client_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { gauges: self.clone(), server: false }
+    }
+
+    /// Count an open server connection until the guard is dropped
+    pub fn server_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.server_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { gauges: self.clone(), server: true }
+    }
+
+    /// Count the bodies of a completed flow: request bodies as received
+    /// from clients, response bodies as sent to them
+    pub fn record_flow(&self, flow: &HTTPFlow) {
+        let request = flow.request.content.as_ref().map_or(0, Vec::len);
+        let response = flow.response.as_ref().and_then(|r| r.content.as_ref()).map_or(0, Vec::len);
+        self.bytes_in.fetch_add(request as u64, Ordering::Relaxed);
+        self.bytes_out.fetch_add(response as u64, Ordering::Relaxed);
+    }
+
+    pub fn client_connections(&self) -> u64 {
+        self.client_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn server_connections(&self) -> u64 {
+        self.server_connections.load(Ordering::Relaxed)
+    }
+
+    /// Body bytes received and sent since the proxy started
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.bytes_in.load(Ordering::Relaxed), self.bytes_out.load(Ordering::Relaxed))
+    }
+}
+
+/// Open connection counted in [`Gauges`]
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    gauges: Arc<Gauges>,
+    server: bool,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let counter = if self.server { &self.gauges.server_connections } else { &self.gauges.client_connections };
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Gauges pushed in a `state/update` message
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GaugeSnapshot {
+    pub client_connections: u64,
+    pub server_connections: u64,
+    /// Intercepted flows waiting to be resumed
+    pub paused_flows: usize,
+    pub stored_flows: usize,
+    /// Body bytes held by the stored flows
+    pub stored_bytes: u64,
+    /// Flows pruned from the store by the janitor
+    pub evicted_flows: u64,
+    pub bytes_in_per_sec: f64,
+    pub bytes_out_per_sec: f64,
+}
+
+/// Byte rates between consecutive snapshots of one subscriber
+#[derive(Debug, Default)]
+pub struct Throughput {
+    last: Option<(f64, u64, u64)>,
+}
+
+impl Throughput {
+    /// Bytes per second received and sent since the previous call, given
+    /// the totals at `now` in seconds. The first call yields zero rates.
+    pub fn rate(&mut self, now: f64, bytes_in: u64, bytes_out: u64) -> (f64, f64) {
+        let rate = match self.last {
+            Some((then, last_in, last_out)) if now > then => {
+                let elapsed = now - then;
+                (
+                    bytes_in.saturating_sub(last_in) as f64 / elapsed,
+                    bytes_out.saturating_sub(last_out) as f64 / elapsed,
+                )
+            }
+            _ => (0.0, 0.0),
+        };
+        self.last = Some((now, bytes_in, bytes_out));
+        rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_guards_and_rates() {
+        let gauges = Arc::new(Gauges::default());
+        let client = gauges.client_connection();
+        let server = gauges.server_connection();
+        let other = gauges.client_connection();
+        assert_eq!((gauges.client_connections(), gauges.server_connections()), (2, 1));
+        drop(client);
+        drop(server);
+        assert_eq!((gauges.client_connections(), gauges.server_connections()), (1, 0));
+        drop(other);
+
+        let mut throughput = Throughput::default();
+        assert_eq!(throughput.rate(10.0, 100, 1000), (0.0, 0.0));
+        assert_eq!(throughput.rate(12.0, 300, 5000), (100.0, 2000.0));
+        // A clock that did not advance yields no rate instead of dividing by zero
+        assert_eq!(throughput.rate(12.0, 400, 6000), (0.0, 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_of_store() {
+        use crate::config::Config;
+        use crate::flow::{HTTPRequest, HTTPResponse};
+        use crate::proxy::ProxyServer;
+
+        let proxy = ProxyServer::new(Arc::new(Config::default()));
+        let mut flow = HTTPFlow::new(HTTPRequest::new(
+            "POST".to_string(),
+            "https".to_string(),
+            "api.example.com".to_string(),
+            443,
+            "/upload".to_string(),
+        ));
+        flow.request.set_content(vec![0; 100]);
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_content(vec![0; 400]);
+        flow.response = Some(response);
+        let mut paused = flow.clone();
+        paused.flow.id = "paused".to_string();
+        paused.flow.intercepted = true;
+
+        let mut throughput = Throughput::default();
+        proxy.gauge_snapshot(&mut throughput, 1.0).await;
+        proxy.record_flow(flow).await;
+        proxy.add_flow(paused).await;
+        let _connection = proxy.gauges().client_connection();
+
+        let snapshot = proxy.gauge_snapshot(&mut throughput, 3.0).await;
+        assert_eq!(snapshot.client_connections, 1);
+        assert_eq!(snapshot.stored_flows, 2);
+        assert_eq!(snapshot.paused_flows, 1);
+        assert_eq!(snapshot.stored_bytes, 1000);
+        // Only flows seen on the wire count as traffic
+        assert_eq!((snapshot.bytes_in_per_sec, snapshot.bytes_out_per_sec), (50.0, 200.0));
+    }
+}
@@ -0,0 +1,479 @@
+//! HAR 1.2 export and import.
+//!
+//! Besides the standard request/response data, entries carry the flow's
+//! non-HTTP traffic in custom fields so sessions survive a round trip:
+//! WebSocket messages use the `_webSocketMessages` extension written by
+//! Chrome DevTools, server-sent events go into `_eventSourceMessages`, and
+//! the WebSocket close handshake into `_webSocketClose`.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+
+use crate::flow::{
+    HTTPFlow, HTTPRequest, HTTPResponse, SseMessage, WebSocketFlow, WebSocketMessage, WebSocketMessageType,
+    WebSocketMessagesMeta,
+};
+use crate::sse::SseEvent;
+use crate::{Error, Result};
+
+/// Serialize flows as a HAR document
+pub fn to_har(flows: &[HTTPFlow]) -> Value {
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": {
+                "name": "mitmproxy-rs",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "pages": [],
+            "entries": flows.iter().map(entry).collect::<Vec<_>>(),
+        }
+    })
+}
+
+/// Parse flows from a HAR document
+pub fn from_har(har: &Value) -> Result<Vec<HTTPFlow>> {
+    let entries = har
+        .pointer("/log/entries")
+        .and_then(Value::as_array)
+        .ok_or_else(|| Error::invalid_request("HAR document has no log.entries"))?;
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            parse_entry(entry).map_err(|e| Error::invalid_request(format!("Invalid HAR entry {}: {}", i, e)))
+        })
+        .collect()
+}
+
+fn entry(flow: &HTTPFlow) -> Value {
+    let request = &flow.request;
+    let started = request.timestamp_start.unwrap_or(flow.flow.timestamp_created);
+    let request_end = request.timestamp_end.unwrap_or(started);
+    let response_start = flow.response.as_ref().and_then(|r| r.timestamp_start).unwrap_or(request_end);
+    let response_end = flow.response.as_ref().and_then(|r| r.timestamp_end).unwrap_or(response_start);
+    let millis = |from: f64, to: f64| ((to - from) * 1000.0).max(0.0);
+    let timings = json!({
+        "send": millis(started, request_end),
+        "wait": millis(request_end, response_start),
+        "receive": millis(response_start, response_end),
+    });
+
+    let mut entry = json!({
+        "startedDateTime": iso_time(started),
+        "time": millis(started, response_end),
+        "request": har_request(request),
+        "response": flow.response.as_ref().map_or_else(empty_response, har_response),
+        "cache": {},
+        "timings": timings,
+        "_flowId": flow.flow.id,
+    });
+    let object = entry.as_object_mut().expect("entry is an object");
+
+    if let Some((address, _)) = flow.flow.server_conn.as_ref().and_then(|c| c.peername.as_ref()) {
+        object.insert("serverIPAddress".to_string(), json!(address));
+    }
+    if !flow.flow.comment.is_empty() {
+        object.insert("comment".to_string(), json!(flow.flow.comment));
+    }
+    if !flow.flow.marked.is_empty() {
+        object.insert("_marked".to_string(), json!(flow.flow.marked));
+    }
+    if let Some(error) = &flow.flow.error {
+        object.insert("_error".to_string(), json!(error.msg));
+    }
+
+    if let Some(websocket) = &flow.websocket {
+        object.insert("_resourceType".to_string(), json!("websocket"));
+        let messages: Vec<Value> = websocket.messages.iter().map(websocket_message).collect();
+        object.insert("_webSocketMessages".to_string(), json!(messages));
+        if websocket.close_code.is_some() || websocket.closed_by_client.is_some() {
+            object.insert(
+                "_webSocketClose".to_string(),
+                json!({
+                    "code": websocket.close_code,
+                    "reason": websocket.close_reason,
+                    "closedByClient": websocket.closed_by_client,
+                    "time": websocket.timestamp_end,
+                }),
+            );
+        }
+    } else if !flow.sse_events.is_empty() {
+        object.insert("_resourceType".to_string(), json!("eventsource"));
+        let messages: Vec<Value> = flow
+            .sse_events
+            .iter()
+            .map(|message| {
+                json!({
+                    "time": message.timestamp,
+                    "eventName": message.event.event_type,
+                    "eventId": message.event.id,
+                    "data": message.event.data,
+                    "retry": message.event.retry,
+                })
+            })
+            .collect();
+        object.insert("_eventSourceMessages".to_string(), json!(messages));
+    }
+
+    entry
+}
+
+fn har_request(request: &HTTPRequest) -> Value {
+    let query: Vec<Value> = match request.path.split_once('?') {
+        Some((_, query)) => url::form_urlencoded::parse(query.as_bytes())
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut value = json!({
+        "method": request.method,
+        "url": request.url(),
+        "httpVersion": request.http_version,
+        "headers": har_headers(&request.headers),
+        "queryString": query,
+        "cookies": [],
+        "headersSize": -1,
+        "bodySize": request.content.as_ref().map_or(0, Vec::len),
+    });
+    if let Some(content) = request.content.as_deref().filter(|c| !c.is_empty()) {
+        let mut post_data = body_text(content);
+        post_data.insert(
+            "mimeType".to_string(),
+            json!(request.get_header("content-type").map_or("", String::as_str)),
+        );
+        value["postData"] = Value::Object(post_data);
+    }
+    value
+}
+
+fn har_response(response: &HTTPResponse) -> Value {
+    let content = response.content.as_deref().unwrap_or_default();
+    let mut body = body_text(content);
+    body.insert("size".to_string(), json!(content.len()));
+    body.insert(
+        "mimeType".to_string(),
+        json!(response.get_header("content-type").map_or("", String::as_str)),
+    );
+    json!({
+        "status": response.status_code,
+        "statusText": response.reason,
+        "httpVersion": response.http_version,
+        "headers": har_headers(&response.headers),
+        "cookies": [],
+        "content": body,
+        "redirectURL": response.get_header("location").map_or("", String::as_str),
+        "headersSize": -1,
+        "bodySize": content.len(),
+    })
+}
+
+/// Response placeholder for flows that never got one
+fn empty_response() -> Value {
+    json!({
+        "status": 0,
+        "statusText": "",
+        "httpVersion": "",
+        "headers": [],
+        "cookies": [],
+        "content": { "size": 0, "mimeType": "x-unknown" },
+        "redirectURL": "",
+        "headersSize": -1,
+        "bodySize": -1,
+    })
+}
+
+fn har_headers(headers: &[(String, String)]) -> Vec<Value> {
+    headers.iter().map(|(name, value)| json!({ "name": name, "value": value })).collect()
+}
+
+/// Text fields of a body, base64-encoded if it is not UTF-8
+fn body_text(content: &[u8]) -> Map<String, Value> {
+    let mut fields = Map::new();
+    match std::str::from_utf8(content) {
+        Ok(text) => {
+            fields.insert("text".to_string(), json!(text));
+        }
+        Err(_) => {
+            fields.insert("text".to_string(), json!(STANDARD.encode(content)));
+            fields.insert("encoding".to_string(), json!("base64"));
+        }
+    }
+    fields
+}
+
+fn websocket_message(message: &WebSocketMessage) -> Value {
+    let opcode = match message.message_type {
+        WebSocketMessageType::Text => 1,
+        WebSocketMessageType::Binary => 2,
+        WebSocketMessageType::Close => 8,
+        WebSocketMessageType::Ping => 9,
+        WebSocketMessageType::Pong => 10,
+    };
+    // Chrome stores text frames verbatim and everything else as base64
+    let data = match message.message_type {
+        WebSocketMessageType::Text => String::from_utf8_lossy(&message.content).into_owned(),
+        _ => STANDARD.encode(&message.content),
+    };
+    json!({
+        "type": if message.from_client { "send" } else { "receive" },
+        "time": message.timestamp,
+        "opcode": opcode,
+        "data": data,
+    })
+}
+
+fn iso_time(timestamp: f64) -> String {
+    DateTime::<Utc>::from_timestamp_millis((timestamp * 1000.0) as i64)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn parse_entry(entry: &Value) -> std::result::Result<HTTPFlow, String> {
+    let har_request = entry.get("request").ok_or("missing request")?;
+    let url = url::Url::parse(str_field(har_request, "url")?).map_err(|e| e.to_string())?;
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path = format!("{}?{}", path, query);
+    }
+
+    let mut request = HTTPRequest::new(
+        str_field(har_request, "method")?.to_string(),
+        url.scheme().to_string(),
+        url.host_str().ok_or("URL has no host")?.to_string(),
+        url.port_or_known_default().unwrap_or(80),
+        path,
+    );
+    request.http_version = str_field(har_request, "httpVersion").unwrap_or("HTTP/1.1").to_string();
+    request.headers = parse_headers(har_request);
+    if let Some(post_data) = har_request.get("postData") {
+        request.set_content(parse_body(post_data)?);
+    }
+
+    let started = entry
+        .get("startedDateTime")
+        .and_then(Value::as_str)
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+        .map(|t| t.timestamp_millis() as f64 / 1000.0)
+        .unwrap_or_default();
+    let timing = |name: &str| entry.pointer(&format!("/timings/{}", name)).and_then(Value::as_f64).unwrap_or(0.0).max(0.0) / 1000.0;
+    let request_end = started + timing("send");
+    let response_start = request_end + timing("wait");
+    request.timestamp_start = Some(started);
+    request.timestamp_end = Some(request_end);
+
+    let mut flow = HTTPFlow::new(request);
+    flow.flow.timestamp_created = started;
+    if let Some(id) = entry.get("_flowId").and_then(Value::as_str) {
+        flow.flow.id = id.to_string();
+    }
+    if let Some(comment) = entry.get("comment").and_then(Value::as_str) {
+        flow.flow.comment = comment.to_string();
+    }
+    if let Some(marked) = entry.get("_marked").and_then(Value::as_str) {
+        flow.flow.marked = marked.to_string();
+    }
+    if let Some(error) = entry.get("_error").and_then(Value::as_str) {
+        flow.flow.set_error(error.to_string());
+    }
+
+    let har_response = entry.get("response").ok_or("missing response")?;
+    let status = har_response.get("status").and_then(Value::as_u64).unwrap_or(0);
+    if status > 0 {
+        let mut response = HTTPResponse::new(status as u16, str_field(har_response, "statusText").unwrap_or("").to_string());
+        response.http_version = str_field(har_response, "httpVersion").unwrap_or("HTTP/1.1").to_string();
+        response.headers = parse_headers(har_response);
+        if let Some(content) = har_response.get("content").filter(|c| c.get("text").is_some()) {
+            response.set_content(parse_body(content)?);
+        }
+        response.timestamp_start = Some(response_start);
+        response.timestamp_end = Some(response_start + timing("receive"));
+        flow.response = Some(response);
+    }
+
+    if let Some(messages) = entry.get("_webSocketMessages").and_then(Value::as_array) {
+        flow.websocket = Some(parse_websocket(entry, messages)?);
+    }
+    if let Some(messages) = entry.get("_eventSourceMessages").and_then(Value::as_array) {
+        flow.sse_events = messages
+            .iter()
+            .map(|message| SseMessage {
+                event: SseEvent {
+                    event_type: str_field(message, "eventName").unwrap_or("message").to_string(),
+                    data: str_field(message, "data").unwrap_or("").to_string(),
+                    id: message.get("eventId").and_then(Value::as_str).map(str::to_string),
+                    retry: message.get("retry").and_then(Value::as_u64),
+                },
+                timestamp: message.get("time").and_then(Value::as_f64).unwrap_or(response_start),
+            })
+            .collect();
+    }
+
+    Ok(flow)
+}
+
+fn parse_websocket(entry: &Value, messages: &[Value]) -> std::result::Result<WebSocketFlow, String> {
+    let messages = messages
+        .iter()
+        .map(|message| {
+            let data = str_field(message, "data").unwrap_or("");
+            let message_type = match message.get("opcode").and_then(Value::as_u64).unwrap_or(1) {
+                1 => WebSocketMessageType::Text,
+                2 => WebSocketMessageType::Binary,
+                8 => WebSocketMessageType::Close,
+                9 => WebSocketMessageType::Ping,
+                10 => WebSocketMessageType::Pong,
+                opcode => return Err(format!("unknown WebSocket opcode {}", opcode)),
+            };
+            let content = match message_type {
+                WebSocketMessageType::Text => data.as_bytes().to_vec(),
+                _ => STANDARD.decode(data).map_err(|e| e.to_string())?,
+            };
+            Ok(WebSocketMessage {
+                content,
+                from_client: str_field(message, "type") == Ok("send"),
+                timestamp: message.get("time").and_then(Value::as_f64).unwrap_or_default(),
+                message_type,
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, String>>()?;
+
+    let close = entry.get("_webSocketClose");
+    let close_field = |name: &str| close.and_then(|c| c.get(name));
+    Ok(WebSocketFlow {
+        messages_meta: WebSocketMessagesMeta {
+            content_length: messages.iter().map(|m| m.content.len()).sum(),
+            count: messages.len(),
+            timestamp_last: messages.last().map(|m| m.timestamp),
+        },
+        closed_by_client: close_field("closedByClient").and_then(Value::as_bool),
+        close_code: close_field("code").and_then(Value::as_u64).map(|c| c as u16),
+        close_reason: close_field("reason").and_then(Value::as_str).map(str::to_string),
+        timestamp_end: close_field("time").and_then(Value::as_f64),
+        messages,
+    })
+}
+
+fn parse_headers(message: &Value) -> Vec<(String, String)> {
+    message
+        .get("headers")
+        .and_then(Value::as_array)
+        .map(|headers| {
+            headers
+                .iter()
+                .filter_map(|h| Some((str_field(h, "name").ok()?.to_string(), str_field(h, "value").ok()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_body(body: &Value) -> std::result::Result<Vec<u8>, String> {
+    let text = str_field(body, "text").unwrap_or("");
+    match body.get("encoding").and_then(Value::as_str) {
+        Some("base64") => STANDARD.decode(text).map_err(|e| e.to_string()),
+        _ => Ok(text.as_bytes().to_vec()),
+    }
+}
+
+fn str_field<'a>(value: &'a Value, name: &str) -> std::result::Result<&'a str, String> {
+    value.get(name).and_then(Value::as_str).ok_or_else(|| format!("missing {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(path: &str) -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "example.com".to_string(),
+            443,
+            path.to_string(),
+        );
+        request.timestamp_start = Some(1_700_000_000.0);
+        request.timestamp_end = Some(1_700_000_000.1);
+        let mut response = HTTPResponse::new(101, "Switching Protocols".to_string());
+        response.timestamp_start = Some(1_700_000_000.25);
+        response.timestamp_end = Some(1_700_000_000.25);
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_websocket_roundtrip() {
+        let message = |content: &[u8], from_client, message_type| WebSocketMessage {
+            content: content.to_vec(),
+            from_client,
+            timestamp: 1_700_000_001.0,
+            message_type,
+        };
+        let messages = vec![
+            message(b"hello", true, WebSocketMessageType::Text),
+            message(&[0, 1, 2], false, WebSocketMessageType::Binary),
+        ];
+        let original = flow("/socket?room=1").with_websocket(WebSocketFlow {
+            messages_meta: WebSocketMessagesMeta { content_length: 8, count: 2, timestamp_last: Some(1_700_000_001.0) },
+            closed_by_client: Some(true),
+            close_code: Some(1000),
+            close_reason: Some("bye".to_string()),
+            timestamp_end: Some(1_700_000_002.0),
+            messages,
+        });
+
+        let har = to_har(std::slice::from_ref(&original));
+        let entry = &har["log"]["entries"][0];
+        assert_eq!(entry["_resourceType"], "websocket");
+        assert_eq!(entry["_webSocketMessages"][1]["data"], "AAEC");
+        assert_eq!(entry["request"]["queryString"][0]["name"], "room");
+        assert_eq!(entry["timings"]["wait"].as_f64().unwrap().round(), 150.0);
+
+        let loaded = from_har(&har).unwrap();
+        assert_eq!(loaded[0].flow.id, original.flow.id);
+        assert_eq!(loaded[0].request.path, "/socket?room=1");
+        let websocket = loaded[0].websocket.as_ref().unwrap();
+        assert_eq!(websocket.messages[0].content, b"hello");
+        assert!(websocket.messages[0].from_client);
+        assert_eq!(websocket.messages[1].content, [0, 1, 2]);
+        assert_eq!(websocket.close_code, Some(1000));
+        assert_eq!(websocket.messages_meta.count, 2);
+    }
+
+    #[test]
+    fn test_sse_roundtrip() {
+        let mut original = flow("/events");
+        let response = original.response.as_mut().unwrap();
+        response.status_code = 200;
+        response.set_header("Content-Type".to_string(), "text/event-stream".to_string());
+        response.set_content(b"event: ping\nid: 1\ndata: a\n\ndata: b\n\n".to_vec());
+        assert!(original.capture_sse_events());
+
+        let har = to_har(std::slice::from_ref(&original));
+        assert_eq!(har["log"]["entries"][0]["_eventSourceMessages"][0]["eventName"], "ping");
+
+        let loaded = from_har(&har).unwrap();
+        assert_eq!(loaded[0].sse_events, original.sse_events);
+        assert_eq!(loaded[0].response.as_ref().unwrap().content, original.response.unwrap().content);
+    }
+
+    #[test]
+    fn test_binary_bodies_and_missing_response() {
+        let mut original = flow("/upload");
+        original.request.method = "POST".to_string();
+        original.request.set_content(vec![0xff, 0xfe]);
+        original.response = None;
+        original.flow.set_error("connection reset".to_string());
+
+        let har = to_har(&[original]);
+        assert_eq!(har["log"]["entries"][0]["request"]["postData"]["encoding"], "base64");
+        assert_eq!(har["log"]["entries"][0]["response"]["status"], 0);
+
+        let loaded = from_har(&har).unwrap();
+        assert_eq!(loaded[0].request.content.as_deref(), Some(&[0xff, 0xfe][..]));
+        assert!(loaded[0].response.is_none());
+        assert_eq!(loaded[0].flow.error.as_ref().unwrap().msg, "connection reset");
+
+        assert!(from_har(&json!({"log": {}})).is_err());
+    }
+}
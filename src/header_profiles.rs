@@ -0,0 +1,244 @@
+//! Request header normalization by named browser profiles.
+//!
+//! A profile describes the fingerprint-relevant headers a client sends, such
+//! as `User-Agent`, `Accept-Language` and the `sec-ch-*` client hints. When a
+//! profile is active, these headers are rewritten on every request before it
+//! is forwarded, so traffic through the proxy looks like it comes from one
+//! consistent client regardless of the real one.
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::flow::HTTPFlow;
+use crate::{Error, Result};
+
+/// Flow metadata key recording the profile applied to a request
+pub const METADATA_KEY: &str = "header_profile";
+
+/// A named set of header rewrites
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderProfile {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Headers to remove; a trailing `*` matches by prefix (`sec-ch-*`)
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Headers to set, replacing any value sent by the client
+    #[serde(default)]
+    pub headers: IndexMap<String, String>,
+}
+
+impl HeaderProfile {
+    fn new(name: &str, description: &str, headers: &[(&str, &str)]) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            remove: vec!["sec-ch-*".to_string()],
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+
+    /// Rewrite the request headers of a flow, returning whether any changed
+    pub fn apply(&self, flow: &mut HTTPFlow) -> bool {
+        let request = &mut flow.request;
+        let before = request.headers.clone();
+
+        request.headers.retain(|(name, _)| !self.remove.iter().any(|pattern| header_matches(pattern, name)));
+        for (name, value) in &self.headers {
+            match request.headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(name)) {
+                // Keep the client's header position, only the value is replaced
+                Some((_, existing)) => *existing = value.clone(),
+                None => request.headers.push((name.clone(), value.clone())),
+            }
+        }
+
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), self.name.clone().into());
+        request.headers != before
+    }
+}
+
+fn header_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.len() >= prefix.len() && name[..prefix.len()].eq_ignore_ascii_case(prefix),
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Profiles shipped with the proxy
+pub fn builtin_profiles() -> Vec<HeaderProfile> {
+    vec![
+        HeaderProfile::new(
+            "chrome-120-windows",
+            "Chrome 120 on Windows",
+            &[
+                (
+                    "User-Agent",
+                    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+                ),
+                ("Accept-Language", "en-US,en;q=0.9"),
+                ("sec-ch-ua", "\"Not_A Brand\";v=\"8\", \"Chromium\";v=\"120\", \"Google Chrome\";v=\"120\""),
+                ("sec-ch-ua-mobile", "?0"),
+                ("sec-ch-ua-platform", "\"Windows\""),
+            ],
+        ),
+        HeaderProfile::new(
+            "chrome-120-android",
+            "Chrome 120 on Android",
+            &[
+                (
+                    "User-Agent",
+                    "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36",
+                ),
+                ("Accept-Language", "en-US,en;q=0.9"),
+                ("sec-ch-ua", "\"Not_A Brand\";v=\"8\", \"Chromium\";v=\"120\", \"Google Chrome\";v=\"120\""),
+                ("sec-ch-ua-mobile", "?1"),
+                ("sec-ch-ua-platform", "\"Android\""),
+            ],
+        ),
+        // Firefox and Safari do not send client hints
+        HeaderProfile::new(
+            "firefox-121-linux",
+            "Firefox 121 on Linux",
+            &[
+                ("User-Agent", "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0"),
+                ("Accept-Language", "en-US,en;q=0.5"),
+            ],
+        ),
+        HeaderProfile::new(
+            "safari-17-macos",
+            "Safari 17 on macOS",
+            &[
+                (
+                    "User-Agent",
+                    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Safari/605.1.15",
+                ),
+                ("Accept-Language", "en-US,en;q=0.9"),
+            ],
+        ),
+    ]
+}
+
+/// Available profiles and the one currently applied to requests
+#[derive(Debug, Clone)]
+pub struct HeaderProfiles {
+    profiles: IndexMap<String, HeaderProfile>,
+    active: Option<String>,
+}
+
+impl Default for HeaderProfiles {
+    fn default() -> Self {
+        Self {
+            profiles: builtin_profiles().into_iter().map(|p| (p.name.clone(), p)).collect(),
+            active: None,
+        }
+    }
+}
+
+impl HeaderProfiles {
+    /// Built-in profiles extended by custom ones, which replace built-ins of
+    /// the same name
+    pub fn new(custom: &[HeaderProfile], active: Option<&str>) -> Result<Self> {
+        let mut profiles = Self::default();
+        for profile in custom {
+            if profile.name.is_empty() {
+                return Err(Error::invalid_request("Header profile name must not be empty"));
+            }
+            profiles.profiles.insert(profile.name.clone(), profile.clone());
+        }
+        profiles.set_active(active)?;
+        Ok(profiles)
+    }
+
+    pub fn list(&self) -> Vec<HeaderProfile> {
+        self.profiles.values().cloned().collect()
+    }
+
+    pub fn active(&self) -> Option<&HeaderProfile> {
+        self.active.as_ref().and_then(|name| self.profiles.get(name))
+    }
+
+    /// Select the profile applied to requests, or disable normalization
+    pub fn set_active(&mut self, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            if !self.profiles.contains_key(name) {
+                return Err(Error::invalid_request(format!("Unknown header profile: {}", name)));
+            }
+        }
+        self.active = name.map(str::to_string);
+        Ok(())
+    }
+
+    /// Apply the active profile to a flow's request
+    pub fn apply(&self, flow: &mut HTTPFlow) -> bool {
+        self.active().is_some_and(|profile| profile.apply(flow))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    fn flow(headers: &[(&str, &str)]) -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "example.com".to_string(),
+            443,
+            "/".to_string(),
+        );
+        request.headers = headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        HTTPFlow::new(request)
+    }
+
+    #[test]
+    fn test_firefox_profile_strips_client_hints() {
+        let profiles = HeaderProfiles::new(&[], Some("firefox-121-linux")).unwrap();
+        let mut f = flow(&[
+            ("Host", "example.com"),
+            ("user-agent", "curl/8.0"),
+            ("Sec-CH-UA-Platform", "\"Linux\""),
+            ("Accept", "*/*"),
+        ]);
+        assert!(profiles.apply(&mut f));
+
+        let names: Vec<&str> = f.request.headers.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(names, ["Host", "user-agent", "Accept", "Accept-Language"]);
+        assert!(f.request.get_header("user-agent").unwrap().contains("Firefox/121.0"));
+        assert_eq!(f.flow.metadata[METADATA_KEY], "firefox-121-linux");
+    }
+
+    #[test]
+    fn test_chrome_profile_sets_client_hints() {
+        let profiles = HeaderProfiles::new(&[], Some("chrome-120-android")).unwrap();
+        let mut f = flow(&[("sec-ch-ua-arch", "\"x86\"")]);
+        profiles.apply(&mut f);
+        assert_eq!(f.request.get_header("sec-ch-ua-mobile").map(String::as_str), Some("?1"));
+        assert!(f.request.get_header("sec-ch-ua-arch").is_none());
+
+        // Already normalized requests are left unchanged
+        assert!(!profiles.apply(&mut f));
+    }
+
+    #[test]
+    fn test_custom_profiles_and_selection() {
+        let custom: HeaderProfile = serde_json::from_value(serde_json::json!({
+            "name": "crawler",
+            "remove": ["cookie"],
+            "headers": {"User-Agent": "ExampleBot/1.0"}
+        }))
+        .unwrap();
+        let mut profiles = HeaderProfiles::new(&[custom], None).unwrap();
+        assert_eq!(profiles.list().len(), builtin_profiles().len() + 1);
+
+        let mut f = flow(&[("Cookie", "a=1")]);
+        assert!(!profiles.apply(&mut f));
+        profiles.set_active(Some("crawler")).unwrap();
+        assert!(profiles.apply(&mut f));
+        assert_eq!(f.request.headers, [("User-Agent".to_string(), "ExampleBot/1.0".to_string())]);
+
+        assert!(profiles.set_active(Some("unknown")).is_err());
+        assert_eq!(profiles.active().unwrap().name, "crawler");
+    }
+}
@@ -0,0 +1,93 @@
+//! Holding intercepted flows until they are resumed or killed.
+//!
+//! With `intercept` set to a filter expression, requests and responses
+//! matching it are intercepted: the flow is marked `intercepted`, stored so
+//! that it can be inspected and edited, and its traffic waits until the
+//! flow is resumed or killed with `/flows/:id/resume`, `/flows/:id/kill` or
+//! their bulk counterparts. A resumed flow continues as it was left, with
+//! any edits made while it was held; a killed one is dropped. Requests the
+//! intercept rule of a capture profile marks are held the same way.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::Result;
+
+/// The `intercept` rule and the flows held by it
+#[derive(Debug, Default)]
+pub struct Intercept {
+    filter: Option<Filter>,
+    /// Wakers of the held flows, by flow id
+    held: Mutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl Intercept {
+    /// Intercept flows matching `filter`, or none
+    pub fn new(filter: Option<&str>) -> Result<Self> {
+        let filter = filter.map(|filter| Filter::new("intercept".to_string(), filter.to_string())).transpose()?;
+        Ok(Self { filter, held: Mutex::default() })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    /// Mark `flow` as intercepted if it matches the rule. Returns whether
+    /// it was marked.
+    pub fn intercept(&self, flow: &mut HTTPFlow) -> bool {
+        let intercept = self.filter.as_ref().is_some_and(|filter| filter.matches(flow));
+        if intercept {
+            flow.flow.intercepted = true;
+        }
+        intercept
+    }
+
+    /// Register `id` as held. The flow waits on the returned waker until
+    /// it is released.
+    pub fn hold(&self, id: &str) -> Arc<Notify> {
+        self.held.lock().unwrap().entry(id.to_string()).or_default().clone()
+    }
+
+    /// Ids of the flows held
+    pub fn held(&self) -> Vec<String> {
+        self.held.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Let the flow with `id` continue. Returns whether it was held.
+    pub fn release(&self, id: &str) -> bool {
+        let Some(waker) = self.held.lock().unwrap().remove(id) else {
+            return false;
+        };
+        // Stores a permit if the flow is not waiting yet
+        waker.notify_one();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    #[tokio::test]
+    async fn test_hold_and_release() {
+        let intercept = Intercept::new(Some("~d held.example")).unwrap();
+        let mut flow = HTTPFlow::new(HTTPRequest::new("GET".to_string(), "https".to_string(), "held.example".to_string(), 443, "/".to_string()));
+        assert!(intercept.intercept(&mut flow) && flow.flow.intercepted);
+        let mut other = HTTPFlow::new(HTTPRequest::new("GET".to_string(), "https".to_string(), "other.example".to_string(), 443, "/".to_string()));
+        assert!(!intercept.intercept(&mut other) && !other.flow.intercepted);
+
+        // Released before it waits, the flow does not wait at all
+        let waker = intercept.hold(&flow.flow.id);
+        assert_eq!(intercept.held(), vec![flow.flow.id.clone()]);
+        assert!(intercept.release(&flow.flow.id));
+        tokio::time::timeout(std::time::Duration::from_secs(1), waker.notified()).await.unwrap();
+        assert!(!intercept.release(&flow.flow.id) && intercept.held().is_empty());
+
+        assert!(!Intercept::default().is_enabled());
+        assert!(Intercept::new(Some("~d [")).is_err());
+    }
+}
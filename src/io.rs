@@ -0,0 +1,217 @@
+//! Reading, writing and merging flow dump files.
+//!
+//! Three formats are understood: a JSON array of flows, as produced by
+//! `/flows/dump`, JSON lines with one flow per line, as written by the save
+//! stream, and HAR documents.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::flow::HTTPFlow;
+use crate::{Error, Result};
+
+/// Parse flows from a dump in either supported format
+pub fn read_flows(data: &[u8]) -> Result<Vec<HTTPFlow>> {
+    let text = std::str::from_utf8(data).map_err(|e| Error::invalid_request(format!("Dump is not UTF-8: {}", e)))?;
+    let trimmed = text.trim_start();
+
+    if trimmed.is_empty() {
+        return Ok(Vec::new());
+    }
+    if trimmed.starts_with('[') {
+        return Ok(serde_json::from_str(trimmed)?);
+    }
+    if trimmed.starts_with('{') {
+        // A single object is either a HAR document or a one-line dump
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(trimmed) {
+            if value.get("log").is_some() {
+                return crate::har::from_har(&value);
+            }
+        }
+    }
+
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| Error::invalid_request(format!("Invalid flow on line {}: {}", i + 1, e)))
+        })
+        .collect()
+}
+
+/// Read flows from a dump file
+pub fn read_flows_file<P: AsRef<Path>>(path: P) -> Result<Vec<HTTPFlow>> {
+    read_flows(&std::fs::read(path)?)
+}
+
+/// Serialize flows as a JSON array dump
+pub fn write_flows(flows: &[HTTPFlow]) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(flows)?)
+}
+
+/// Serialize flows as a HAR document
+pub fn write_har(flows: &[HTTPFlow]) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&crate::har::to_har(flows))?)
+}
+
+/// Serialize flows as a k6 load test script
+pub fn write_k6(flows: &[HTTPFlow]) -> Result<Vec<u8>> {
+    Ok(crate::loadtest::to_k6(flows).into_bytes())
+}
+
+/// Serialize flows as a Locust load test file
+pub fn write_locust(flows: &[HTTPFlow]) -> Result<Vec<u8>> {
+    Ok(crate::loadtest::to_locust(flows).into_bytes())
+}
+
+/// Serialize flows as Rust tests using reqwest
+pub fn write_reqwest_tests(flows: &[HTTPFlow]) -> Result<Vec<u8>> {
+    Ok(crate::testgen::to_reqwest(flows).into_bytes())
+}
+
+/// Serialize flows as pytest tests using httpx
+pub fn write_pytest_tests(flows: &[HTTPFlow]) -> Result<Vec<u8>> {
+    Ok(crate::testgen::to_pytest(flows).into_bytes())
+}
+
+/// Combines flows from several captures, dropping duplicates. A flow is a
+/// duplicate if its id was seen before, or if an earlier flow has an
+/// identical request with the same timestamps (the same exchange recorded by
+/// two proxy instances).
+#[derive(Debug, Default)]
+pub struct FlowMerger {
+    ids: HashSet<String>,
+    fingerprints: HashSet<String>,
+    flows: Vec<HTTPFlow>,
+    pub duplicates: usize,
+}
+
+impl FlowMerger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember a flow for deduplication without including it in the output
+    pub fn mark_seen(&mut self, flow: &HTTPFlow) {
+        self.ids.insert(flow.flow.id.clone());
+        self.fingerprints.insert(fingerprint(flow));
+    }
+
+    /// Add a flow, returning false if it duplicates an earlier one
+    pub fn add(&mut self, flow: HTTPFlow) -> bool {
+        let fingerprint = fingerprint(&flow);
+        if self.ids.contains(&flow.flow.id) || self.fingerprints.contains(&fingerprint) {
+            self.duplicates += 1;
+            return false;
+        }
+        self.ids.insert(flow.flow.id.clone());
+        self.fingerprints.insert(fingerprint);
+        self.flows.push(flow);
+        true
+    }
+
+    /// The merged flows, ordered by creation time
+    pub fn into_flows(self) -> Vec<HTTPFlow> {
+        let mut flows = self.flows;
+        flows.sort_by(|a, b| {
+            let a = a.request.timestamp_start.unwrap_or(a.flow.timestamp_created);
+            let b = b.request.timestamp_start.unwrap_or(b.flow.timestamp_created);
+            a.total_cmp(&b)
+        });
+        flows
+    }
+}
+
+/// Hash of the request and the exchange timestamps
+fn fingerprint(flow: &HTTPFlow) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(flow.request.method.as_bytes());
+    hasher.update(flow.request.url().as_bytes());
+    for (name, value) in &flow.request.headers {
+        hasher.update(name.to_ascii_lowercase().as_bytes());
+        hasher.update(b":");
+        hasher.update(value.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.update(flow.request.content.as_deref().unwrap_or_default());
+    hasher.update(flow.request.timestamp_start.unwrap_or_default().to_be_bytes());
+    hasher.update(flow.request.timestamp_end.unwrap_or_default().to_be_bytes());
+    let response_end = flow.response.as_ref().and_then(|r| r.timestamp_end);
+    hasher.update(response_end.unwrap_or_default().to_be_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    fn flow(path: &str) -> HTTPFlow {
+        HTTPFlow::new(HTTPRequest::new(
+            "GET".to_string(),
+            "http".to_string(),
+            "example.com".to_string(),
+            80,
+            path.to_string(),
+        ))
+    }
+
+    #[test]
+    fn test_roundtrip_json_array() {
+        let flows = vec![flow("/a"), flow("/b")];
+        let loaded = read_flows(&write_flows(&flows).unwrap()).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].request.path, "/b");
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let mut data = Vec::new();
+        for f in [flow("/a"), flow("/b")] {
+            data.extend(serde_json::to_vec(&f).unwrap());
+            data.extend(b"\n\n");
+        }
+        let loaded = read_flows(&data).unwrap();
+        assert_eq!(loaded.len(), 2);
+
+        assert!(read_flows(b"").unwrap().is_empty());
+        let err = read_flows(b"{}\n").unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+    }
+
+    #[test]
+    fn test_har_detection() {
+        let flows = vec![flow("/a")];
+        let loaded = read_flows(&write_har(&flows).unwrap()).unwrap();
+        assert_eq!(loaded[0].flow.id, flows[0].flow.id);
+        assert_eq!(loaded[0].request.path, "/a");
+    }
+
+    #[test]
+    fn test_merge_deduplicates() {
+        let mut a = flow("/a");
+        a.request.timestamp_start = Some(2.0);
+        let mut b = flow("/b");
+        b.request.timestamp_start = Some(1.0);
+
+        // Same exchange captured by another instance under a different id
+        let mut a_elsewhere = a.clone();
+        a_elsewhere.flow.id = "other".to_string();
+        // Same request at a different time is a distinct exchange
+        let mut a_later = a.clone();
+        a_later.flow.id = "later".to_string();
+        a_later.request.timestamp_start = Some(3.0);
+
+        let mut merger = FlowMerger::new();
+        assert!(merger.add(a.clone()));
+        assert!(merger.add(b));
+        assert!(!merger.add(a.clone()));
+        assert!(!merger.add(a_elsewhere));
+        assert!(merger.add(a_later));
+        assert_eq!(merger.duplicates, 2);
+
+        let paths: Vec<String> = merger.into_flows().into_iter().map(|f| f.request.path).collect();
+        assert_eq!(paths, ["/b", "/a", "/a"]);
+    }
+}
@@ -0,0 +1,110 @@
+//! Time-based pruning of the flow store.
+//!
+//! When enabled, a background job periodically removes flows older than
+//! `max_age_secs` from memory, so that a long-running proxy does not grow
+//! without bound. Before a flow is removed it is archived: appended to the
+//! `archive_file` save stream if one is configured, and handed to the
+//! `archive` hook of every addon, which can push it to an export sink. A
+//! flow that cannot be written to the archive file is kept and retried on
+//! the next run. Intercepted flows are never pruned.
+
+use serde::{Deserialize, Serialize};
+
+use crate::flow::HTTPFlow;
+
+/// Janitor options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JanitorOptions {
+    pub enabled: bool,
+    /// Flows created longer ago than this are pruned
+    pub max_age_secs: u64,
+    /// Time between two runs
+    pub interval_secs: u64,
+    /// Save stream pruned flows are appended to, segmented like the main
+    /// save stream
+    pub archive_file: Option<String>,
+}
+
+impl Default for JanitorOptions {
+    fn default() -> Self {
+        Self { enabled: false, max_age_secs: 24 * 60 * 60, interval_secs: 60, archive_file: None }
+    }
+}
+
+/// Pruning statistics as reported in `/state`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PruneStats {
+    pub runs: u64,
+    pub pruned: u64,
+    pub archived: u64,
+    /// Flows kept because writing them to the archive file failed
+    pub archive_failures: u64,
+    /// Start of the last run, as a UNIX timestamp
+    pub last_run: Option<f64>,
+    pub last_pruned: u64,
+}
+
+/// Whether a flow is due for pruning at `cutoff`, a UNIX timestamp
+pub fn is_expired(flow: &HTTPFlow, cutoff: f64) -> bool {
+    !flow.flow.intercepted && flow.flow.timestamp_created < cutoff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addons::Addon;
+    use crate::config::Config;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+    use crate::proxy::ProxyServer;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Sink(Mutex<Vec<String>>);
+
+    impl Addon for Sink {
+        fn name(&self) -> &str {
+            "sink"
+        }
+
+        fn archive(&self, flow: &HTTPFlow) {
+            self.0.lock().unwrap().push(flow.request.path.clone());
+        }
+    }
+
+    fn flow(path: &str, created: f64) -> HTTPFlow {
+        let mut flow = HTTPFlow::new(HTTPRequest::new("GET".to_string(), "http".to_string(), "example.com".to_string(), 80, path.to_string()));
+        flow.flow.timestamp_created = created;
+        flow.response = Some(HTTPResponse::new(200, "OK".to_string()));
+        flow
+    }
+
+    #[tokio::test]
+    async fn test_prune_flows() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("archive.jsonl");
+        let config = Config {
+            janitor: JanitorOptions { enabled: true, max_age_secs: 100, archive_file: Some(archive.display().to_string()), ..Default::default() },
+            ..Default::default()
+        };
+        let proxy = ProxyServer::new(Arc::new(config));
+        let sink = Arc::new(Sink::default());
+        proxy.add_addon(sink.clone()).await.unwrap();
+
+        proxy.add_flow(flow("/old", 1000.0)).await;
+        proxy.add_flow(flow("/new", 1950.0)).await;
+        let mut intercepted = flow("/held", 1000.0);
+        intercepted.flow.intercepted = true;
+        proxy.add_flow(intercepted).await;
+
+        assert_eq!(proxy.prune_flows(2000.0).await, 1);
+        let remaining: Vec<String> = proxy.get_flows().await.into_iter().map(|f| f.request.path).collect();
+        assert_eq!(remaining, ["/new", "/held"]);
+        assert_eq!(*sink.0.lock().unwrap(), ["/old"]);
+        let archived = crate::save::load_recent(&archive, &Default::default()).unwrap();
+        assert_eq!(archived[0].request.path, "/old");
+
+        let stats = proxy.prune_stats();
+        assert_eq!((stats.runs, stats.pruned, stats.archived, stats.last_run), (1, 1, 1, Some(2000.0)));
+    }
+}
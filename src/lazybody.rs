@@ -0,0 +1,345 @@
+//! Keeping only a preview of very large response bodies.
+//!
+//! When enabled, responses to GET requests with bodies above a threshold
+//! are stored with only their first `preview_bytes`, which keeps captures of
+//! downloads and media small. The flow records under `lazy_body` that its
+//! body is partial, along with the full size and the validator needed to
+//! fetch the rest. When the full content is asked for, the remainder is
+//! requested from the origin again, with a Range request if the server
+//! advertised byte ranges, and is only used if it matches what was captured.
+//! Fetched bodies are kept in memory, up to [`CACHE_BYTES`], so that paging
+//! through a body does not fetch it again for every page.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::filter::Filter;
+use crate::flow::{HTTPFlow, HTTPRequest, HTTPResponse};
+use crate::ranges::ContentRange;
+use crate::{Error, Result};
+
+/// Flow metadata key under which partial bodies are described
+pub const METADATA_KEY: &str = "lazy_body";
+
+/// Bytes of fetched bodies kept before the least recently used are dropped
+pub const CACHE_BYTES: usize = 128 * 1024 * 1024;
+
+/// Request headers that could make the origin answer with anything but
+/// the body
+const CONDITIONAL_HEADERS: &[&str] = &["if-match", "if-none-match", "if-modified-since", "if-unmodified-since", "if-range"];
+
+/// Lazy body options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LazyBodyOptions {
+    pub enabled: bool,
+    /// Bodies larger than this are cut down to their preview
+    pub threshold_bytes: usize,
+    /// Bytes of the body kept
+    pub preview_bytes: usize,
+    /// Filter expression selecting the flows affected; empty matches all
+    pub filter: String,
+}
+
+impl Default for LazyBodyOptions {
+    fn default() -> Self {
+        Self { enabled: false, threshold_bytes: 4 * 1024 * 1024, preview_bytes: 64 * 1024, filter: String::new() }
+    }
+}
+
+/// A body fetched for a flow
+#[derive(Debug)]
+struct FetchedBody {
+    flow_id: String,
+    /// Hash of the full body, as stored with the preview
+    hash: Option<String>,
+    content: Vec<u8>,
+    /// How it was fetched, `range` or `full`
+    method: String,
+}
+
+#[derive(Debug, Default)]
+pub struct LazyBody {
+    options: LazyBodyOptions,
+    filter: Option<Filter>,
+    /// Fetched bodies, most recently used last
+    fetched: Mutex<VecDeque<FetchedBody>>,
+}
+
+impl LazyBody {
+    pub fn new(options: &LazyBodyOptions) -> Result<Self> {
+        let filter = (!options.filter.is_empty())
+            .then(|| Filter::new("lazy_body".to_string(), options.filter.clone()))
+            .transpose()?;
+        Ok(Self { options: options.clone(), filter, fetched: Mutex::default() })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.options.enabled
+    }
+
+    /// Complete a partial body with the body fetched for it before, if it
+    /// is still cached. Returns whether it was.
+    pub fn complete_cached(&self, flow: &mut HTTPFlow) -> bool {
+        let hash = flow.response.as_ref().and_then(|response| response.content_hash.clone());
+        let mut fetched = self.fetched.lock().unwrap();
+        let Some(index) = fetched.iter().position(|body| body.flow_id == flow.flow.id && body.hash == hash) else {
+            return false;
+        };
+        let body = fetched.remove(index).expect("index was found");
+        fill(flow, body.content.clone(), &body.method);
+        fetched.push_back(body);
+        true
+    }
+
+    /// Keep the body of a flow completed by [`complete`]
+    pub fn cache(&self, flow: &HTTPFlow) {
+        let Some(response) = flow.response.as_ref() else {
+            return;
+        };
+        let content = response.content.clone().unwrap_or_default();
+        if content.len() > CACHE_BYTES {
+            return;
+        }
+        let Some(method) = flow.flow.metadata.get(METADATA_KEY).and_then(|meta| meta["fetched"].as_str()) else {
+            return;
+        };
+        let method = method.to_string();
+        let mut fetched = self.fetched.lock().unwrap();
+        fetched.retain(|body| body.flow_id != flow.flow.id);
+        let mut size = fetched.iter().map(|body| body.content.len()).sum::<usize>() + content.len();
+        while size > CACHE_BYTES {
+            size -= fetched.pop_front().map_or(0, |body| body.content.len());
+        }
+        let flow_id = flow.flow.id.clone();
+        fetched.push_back(FetchedBody { flow_id, hash: response.content_hash.clone(), content, method });
+    }
+
+    /// Cut a large response body down to its preview. Returns whether the
+    /// body was cut.
+    pub fn truncate(&self, flow: &mut HTTPFlow) -> bool {
+        if !self.options.enabled
+            || !flow.request.method.eq_ignore_ascii_case("GET")
+            || self.filter.as_ref().is_some_and(|filter| !filter.matches(flow))
+        {
+            return false;
+        }
+        let Some(response) = flow.response.as_mut() else {
+            return false;
+        };
+        let size = response.content.as_ref().map_or(0, Vec::len);
+        if response.status_code != 200 || size <= self.options.threshold_bytes {
+            return false;
+        }
+
+        let ranges = response.get_header("accept-ranges").is_some_and(|v| v.trim().eq_ignore_ascii_case("bytes"));
+        // Weak ETags cannot be used in If-Range
+        let validator = response
+            .get_header("etag")
+            .filter(|etag| !etag.starts_with("W/"))
+            .or_else(|| response.get_header("last-modified"))
+            .cloned();
+        let hash = response.body_hash();
+        let content = response.content.as_mut().expect("size is above the threshold");
+        content.truncate(self.options.preview_bytes);
+        let stored = content.len();
+        // The length and hash keep describing the full body
+        response.content_length = Some(size);
+        response.content_hash = hash;
+
+        flow.flow.metadata.insert(
+            METADATA_KEY.to_string(),
+            json!({ "partial": true, "size": size, "stored": stored, "ranges": ranges, "validator": validator }),
+        );
+        true
+    }
+}
+
+/// Whether only a preview of the flow's response body is stored
+pub fn is_partial(flow: &HTTPFlow) -> bool {
+    flow.flow.metadata.get(METADATA_KEY).is_some_and(|m| m["partial"] == true)
+}
+
+/// The request fetching the rest of a partial body from the origin
+pub fn remainder_request(flow: &HTTPFlow) -> Option<HTTPRequest> {
+    let meta = flow.flow.metadata.get(METADATA_KEY).filter(|_| is_partial(flow))?;
+    let mut request = flow.request.clone();
+    request.headers.retain(|(name, _)| !CONDITIONAL_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+    request.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("range"));
+    if meta["ranges"] == true {
+        request.set_header("Range".to_string(), format!("bytes={}-", meta["stored"]));
+        if let Some(validator) = meta["validator"].as_str() {
+            request.set_header("If-Range".to_string(), validator.to_string());
+        }
+    }
+    Some(request)
+}
+
+/// Complete a partial body with the origin's answer to
+/// [`remainder_request`]. Fails, leaving the flow unchanged, unless the
+/// answer continues or repeats the captured body.
+pub fn complete(flow: &mut HTTPFlow, answer: HTTPResponse) -> Result<()> {
+    let meta = flow
+        .flow
+        .metadata
+        .get(METADATA_KEY)
+        .filter(|_| is_partial(flow))
+        .cloned()
+        .ok_or_else(|| Error::invalid_request("Flow body is not partial"))?;
+    let size = meta["size"].as_u64().unwrap_or_default() as usize;
+    let response = flow.response.as_mut().ok_or_else(|| Error::invalid_request("Flow has no response"))?;
+    let preview = response.content.clone().unwrap_or_default();
+    let body = answer.content.clone().unwrap_or_default();
+
+    let (full, method) = match answer.status_code {
+        206 => {
+            let start = answer.get_header("content-range").and_then(|range| content_range(range, size));
+            if start != Some(preview.len()) {
+                return Err(changed("the returned range does not continue the preview"));
+            }
+            ([preview.as_slice(), body.as_slice()].concat(), "range")
+        }
+        200 => {
+            if !body.starts_with(&preview) {
+                return Err(changed("the body differs from the preview"));
+            }
+            (body, "full")
+        }
+        status => return Err(Error::Proxy(format!("Origin answered {} when fetching the full body", status))),
+    };
+    if full.len() != size {
+        return Err(changed(format!("the body is {} bytes instead of {}", full.len(), size)));
+    }
+    if response.content_hash.as_ref().is_some_and(|hash| *hash != crate::flow::content_hash(&full)) {
+        return Err(changed("the body hash differs"));
+    }
+
+    fill(flow, full, method);
+    Ok(())
+}
+
+/// Replace the preview of a partial body with the full body
+fn fill(flow: &mut HTTPFlow, full: Vec<u8>, method: &str) {
+    if let Some(response) = flow.response.as_mut() {
+        response.set_content(full);
+    }
+    if let Some(meta) = flow.flow.metadata.get_mut(METADATA_KEY) {
+        meta["partial"] = Value::Bool(false);
+        meta["fetched"] = json!(method);
+    }
+}
+
+/// Start offset of a `bytes start-end/size` Content-Range for a body of
+/// `size` bytes
+fn content_range(value: &str, size: usize) -> Option<usize> {
+    let range = ContentRange::parse(value)?;
+    (range.total.is_none_or(|total| total == size as u64) && range.end + 1 == size as u64).then_some(range.start as usize)
+}
+
+fn changed(reason: impl std::fmt::Display) -> Error {
+    Error::Proxy(format!("Body changed upstream since it was captured: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large_flow(ranges: bool) -> HTTPFlow {
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "cdn.example".to_string(), 443, "/video.mp4".to_string());
+        let mut flow = HTTPFlow::new(request);
+        flow.request.set_header("If-None-Match".to_string(), "\"old\"".to_string());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        if ranges {
+            response.set_header("Accept-Ranges".to_string(), "bytes".to_string());
+        }
+        response.set_header("ETag".to_string(), "\"v1\"".to_string());
+        response.set_content((0..100u8).collect());
+        flow.response = Some(response);
+        flow
+    }
+
+    fn lazy() -> LazyBody {
+        LazyBody::new(&LazyBodyOptions { enabled: true, threshold_bytes: 50, preview_bytes: 10, filter: String::new() }).unwrap()
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut flow = large_flow(true);
+        assert!(lazy().truncate(&mut flow));
+        let response = flow.response.as_ref().unwrap();
+        assert_eq!(response.content.as_ref().unwrap().len(), 10);
+        assert_eq!(response.content_length, Some(100));
+        assert!(is_partial(&flow));
+        assert_eq!(flow.flow.metadata[METADATA_KEY]["validator"], "\"v1\"");
+
+        let mut small = large_flow(true);
+        small.response.as_mut().unwrap().set_content(vec![0; 50]);
+        assert!(!lazy().truncate(&mut small));
+        assert!(!LazyBody::new(&LazyBodyOptions::default()).unwrap().truncate(&mut large_flow(true)));
+    }
+
+    #[test]
+    fn test_complete_with_range() {
+        let mut flow = large_flow(true);
+        lazy().truncate(&mut flow);
+        let request = remainder_request(&flow).unwrap();
+        assert_eq!(request.get_header("range").unwrap(), "bytes=10-");
+        assert_eq!(request.get_header("if-range").unwrap(), "\"v1\"");
+        assert!(request.get_header("if-none-match").is_none());
+
+        let mut wrong = HTTPResponse::new(206, "Partial Content".to_string());
+        wrong.set_header("Content-Range".to_string(), "bytes 20-99/100".to_string());
+        wrong.set_content((20..100u8).collect());
+        assert!(complete(&mut flow, wrong).is_err());
+        assert!(is_partial(&flow));
+
+        let mut answer = HTTPResponse::new(206, "Partial Content".to_string());
+        answer.set_header("Content-Range".to_string(), "bytes 10-99/100".to_string());
+        answer.set_content((10..100u8).collect());
+        complete(&mut flow, answer).unwrap();
+        assert_eq!(flow.response.unwrap().content.unwrap(), (0..100u8).collect::<Vec<_>>());
+        assert_eq!(flow.flow.metadata[METADATA_KEY]["fetched"], "range");
+    }
+
+    #[test]
+    fn test_cache() {
+        let lazy = lazy();
+        let mut flow = large_flow(false);
+        lazy.truncate(&mut flow);
+        let preview = flow.clone();
+        assert!(!lazy.complete_cached(&mut flow.clone()));
+
+        let mut answer = HTTPResponse::new(200, "OK".to_string());
+        answer.set_content((0..100u8).collect());
+        complete(&mut flow, answer).unwrap();
+        lazy.cache(&flow);
+        let mut cached = preview.clone();
+        assert!(lazy.complete_cached(&mut cached));
+        assert_eq!(cached.response.unwrap().content.unwrap(), (0..100u8).collect::<Vec<_>>());
+        assert_eq!(cached.flow.metadata[METADATA_KEY]["fetched"], "full");
+
+        // An edited preview is not completed with the old body
+        let mut edited = preview;
+        edited.response.as_mut().unwrap().set_content(vec![1; 10]);
+        assert!(!lazy.complete_cached(&mut edited));
+    }
+
+    #[test]
+    fn test_complete_without_range() {
+        let mut flow = large_flow(false);
+        lazy().truncate(&mut flow);
+        assert!(remainder_request(&flow).unwrap().get_header("range").is_none());
+
+        let mut changed = HTTPResponse::new(200, "OK".to_string());
+        changed.set_content((1..101u8).collect());
+        assert!(complete(&mut flow, changed).is_err());
+
+        let mut answer = HTTPResponse::new(200, "OK".to_string());
+        answer.set_content((0..100u8).collect());
+        complete(&mut flow, answer).unwrap();
+        assert!(!is_partial(&flow));
+        assert_eq!(flow.flow.metadata[METADATA_KEY]["fetched"], "full");
+    }
+}
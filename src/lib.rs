@@ -1,14 +1,78 @@
+pub mod adaptation;
+pub mod addons;
+pub mod agent;
+pub mod allowlist;
+pub mod analysis;
 pub mod api;
 pub mod auth;
+pub mod auth_injection;
+pub mod banner;
+pub mod blocklist;
+pub mod bodydiff;
+pub mod build_info;
+pub mod capture_profiles;
 pub mod certs;
+pub mod client;
+pub mod clock;
+pub mod changelog;
+pub mod coalesce;
+pub mod compression;
 pub mod config;
+pub mod contentviews;
+pub mod cookie_policy;
+pub mod corpus;
+pub mod csp;
+pub mod dns;
 pub mod connection;
 pub mod error;
+pub mod eventlog;
+pub mod expectations;
 pub mod filter;
 pub mod flow;
+pub mod forms;
+pub mod gauges;
+pub mod har;
+pub mod header_profiles;
+pub mod intercept;
+pub mod io;
+pub mod janitor;
+pub mod lazybody;
+pub mod listeners;
+pub mod loadtest;
+pub mod logging;
+pub mod maplocal;
+pub mod mapremote;
+pub mod metrics;
+pub mod modifybody;
+pub mod modifyheaders;
+pub mod onboarding;
+pub mod panics;
+pub mod pinning;
+pub mod profiling;
 pub mod proxy;
+pub mod proxychain;
+pub mod ranges;
+pub mod redact;
+pub mod replay;
+pub mod rewrites;
+pub mod sandbox;
+pub mod save;
+#[cfg(feature = "scripting")]
+pub mod scripting;
 pub mod server;
+pub mod serverreplay;
+pub mod shaping;
+pub mod sockets;
 pub mod sse;
+pub mod stickycookie;
+pub mod store;
+pub mod substitute;
+pub mod testgen;
+pub mod tls_sessions;
+pub mod transforms;
+pub mod upstream;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 pub mod websocket;
 
 pub use error::{Error, Result};
@@ -0,0 +1,165 @@
+//! Options scoped to a single listener.
+//!
+//! Most options apply to all traffic. A listener scope overrides some of
+//! them for the connections one listener accepts, so that the reverse
+//! proxy, for example, does not run the addons or shaping rules set up for
+//! the regular proxy. Scopes are keyed by listener id: the mode a listener
+//! runs in (`regular`, `reverse`, `socks5`, ...), `http3` for the HTTP/3
+//! listener or `dns` for the DNS listener. Options a scope leaves unset fall back to the global ones.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::config::ProxyMode;
+use crate::cookie_policy::{CookiePolicy, CookiePolicyOptions};
+use crate::shaping::{Shaper, ShapingRule};
+use crate::{Error, Result};
+
+/// Id of the HTTP/3 listener
+pub const HTTP3: &str = "http3";
+
+/// Id of the DNS listener
+pub const DNS: &str = "dns";
+
+/// Options of one listener as configured in the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListenerOptions {
+    /// Names of the addons run on this listener's flows; all if unset
+    pub addons: Option<Vec<String>>,
+    /// Shaping rules replacing the global ones; an empty list turns
+    /// throttling off
+    pub shaping_rules: Option<Vec<ShapingRule>>,
+    /// Cookie rules replacing the global ones
+    pub cookie_policy: Option<CookiePolicyOptions>,
+}
+
+/// The compiled options of one listener
+#[derive(Debug, Default)]
+pub struct ListenerScope {
+    addons: Option<HashSet<String>>,
+    shaper: Option<Shaper>,
+    cookie_policy: Option<CookiePolicy>,
+}
+
+impl ListenerScope {
+    pub fn new(options: &ListenerOptions) -> Result<Self> {
+        Ok(Self {
+            addons: options.addons.as_ref().map(|names| names.iter().cloned().collect()),
+            shaper: options.shaping_rules.as_deref().map(Shaper::from_rules).transpose()?,
+            cookie_policy: options.cookie_policy.as_ref().map(CookiePolicy::new).transpose()?,
+        })
+    }
+
+    /// Whether the addon runs on this listener's flows
+    pub fn runs_addon(&self, name: &str) -> bool {
+        self.addons.as_ref().is_none_or(|names| names.contains(name))
+    }
+
+    /// The listener's shaping rules, if they replace the global ones
+    pub fn shaper(&self) -> Option<&Shaper> {
+        self.shaper.as_ref()
+    }
+
+    /// The listener's cookie rules, if they replace the global ones
+    pub fn cookie_policy(&self) -> Option<&CookiePolicy> {
+        self.cookie_policy.as_ref()
+    }
+}
+
+/// Scopes of all configured listeners
+#[derive(Debug, Default)]
+pub struct ListenerScopes {
+    scopes: HashMap<String, ListenerScope>,
+    /// Used for listeners without a scope
+    global: ListenerScope,
+}
+
+impl ListenerScopes {
+    pub fn new(options: &BTreeMap<String, ListenerOptions>) -> Result<Self> {
+        let scopes = options
+            .iter()
+            .map(|(id, options)| {
+                let scope = ListenerScope::new(options)
+                    .map_err(|e| Error::invalid_request(format!("Listener {}: {}", id, e)))?;
+                Ok((id.clone(), scope))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { scopes, global: ListenerScope::default() })
+    }
+
+    /// The scope of a listener; one overriding nothing if it has none
+    pub fn get(&self, listener: &str) -> &ListenerScope {
+        self.scopes.get(listener).unwrap_or(&self.global)
+    }
+
+    /// Configured listener ids that no listener uses
+    pub fn unknown_ids(&self) -> Vec<&str> {
+        let known = [
+            ProxyMode::Regular,
+            ProxyMode::Transparent,
+            ProxyMode::Socks5,
+            ProxyMode::Reverse,
+            ProxyMode::Upstream,
+        ]
+        .map(|mode| mode.name());
+        let mut unknown: Vec<&str> = self
+            .scopes
+            .keys()
+            .map(String::as_str)
+            .filter(|id| *id != HTTP3 && *id != DNS && !known.contains(id))
+            .collect();
+        unknown.sort_unstable();
+        unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPFlow, HTTPRequest, HTTPResponse};
+
+    #[test]
+    fn test_scoped_options() {
+        let mut options = BTreeMap::new();
+        options.insert(
+            "reverse".to_string(),
+            ListenerOptions { addons: Some(vec!["logger".to_string()]), shaping_rules: Some(Vec::new()), cookie_policy: None },
+        );
+        options.insert("staging".to_string(), ListenerOptions::default());
+        let scopes = ListenerScopes::new(&options).unwrap();
+
+        let reverse = scopes.get("reverse");
+        assert!(reverse.runs_addon("logger"));
+        assert!(!reverse.runs_addon("breakpoints"));
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "example.com".to_string(), 443, "/".to_string());
+        let mut flow = HTTPFlow::new(request).with_response(HTTPResponse::new(200, "OK".to_string()));
+        assert!(reverse.shaper().unwrap().shape(&mut flow).is_none());
+        assert!(reverse.cookie_policy().is_none());
+
+        let regular = scopes.get("regular");
+        assert!(regular.runs_addon("breakpoints"));
+        assert!(regular.shaper().is_none());
+        assert_eq!(scopes.unknown_ids(), ["staging"]);
+    }
+
+    #[test]
+    fn test_invalid_scope() {
+        let rule = ShapingRule {
+            name: "slow".to_string(),
+            content_types: Vec::new(),
+            filter: "~q(".to_string(),
+            chunk_size: None,
+            chunk_delay_ms: 100,
+            bandwidth: None,
+            pad_bytes: 0,
+            pad_to: None,
+        };
+        let options = BTreeMap::from([(
+            HTTP3.to_string(),
+            ListenerOptions { shaping_rules: Some(vec![rule]), ..Default::default() },
+        )]);
+        let error = ListenerScopes::new(&options).unwrap_err().to_string();
+        assert!(error.contains("Listener http3"), "{}", error);
+    }
+}
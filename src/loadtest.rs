@@ -0,0 +1,572 @@
+//! Load test scripts generated from captured flows.
+//!
+//! The flows are turned into one scripted user session, either a k6
+//! JavaScript test or a Locust Python file. Requests are sent in the order
+//! they were recorded, grouped into runs of consecutive requests to the
+//! same host, and separated by the think times between one response and
+//! the next request.
+//!
+//! Tokens issued by the server are correlated: a string value of a JSON
+//! response body or a `Set-Cookie` value that a later request sends back is
+//! extracted into a variable when the response arrives, and the later
+//! requests use the variable instead of the recorded value. To keep
+//! ordinary words out, only values of at least eight characters without
+//! whitespace and with a digit are considered.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::Value;
+use std::collections::HashSet;
+
+use crate::flow::HTTPFlow;
+
+/// Longest think time kept; idle periods of a capture are cut to this
+const MAX_THINK_TIME: f64 = 30.0;
+
+/// Shortest think time worth a sleep
+const MIN_THINK_TIME: f64 = 0.01;
+
+const MIN_TOKEN_LEN: usize = 8;
+
+/// Request headers the HTTP client sets itself
+pub(crate) const SKIPPED_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A piece of a generated string: recorded text or a captured variable
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Var(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// Where a variable is read from in the response that issues it
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Json(Vec<PathSegment>),
+    Cookie(String),
+}
+
+#[derive(Debug)]
+struct Variable {
+    name: String,
+    value: String,
+    /// Step whose response issues the value
+    producer: usize,
+    source: Source,
+}
+
+#[derive(Debug)]
+enum Body {
+    Text(Vec<Part>),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Step {
+    host: String,
+    /// Seconds to wait before the request
+    think_time: Option<f64>,
+    method: String,
+    url: Vec<Part>,
+    headers: Vec<(String, Vec<Part>)>,
+    body: Option<Body>,
+}
+
+#[derive(Debug, Default)]
+struct Plan {
+    /// Scheme and host of the first request
+    origin: Option<String>,
+    steps: Vec<Step>,
+    variables: Vec<Variable>,
+}
+
+impl Plan {
+    fn new(flows: &[HTTPFlow]) -> Self {
+        let mut flows: Vec<&HTTPFlow> = flows
+            .iter()
+            .filter(|flow| !flow.is_tcp() && !flow.is_dns() && flow.websocket.is_none())
+            .collect();
+        flows.sort_by(|a, b| start(a).total_cmp(&start(b)));
+
+        let texts: Vec<String> = flows.iter().map(|flow| request_text(flow)).collect();
+        let mut variables: Vec<Variable> = Vec::new();
+        let mut correlated = HashSet::new();
+        for (i, flow) in flows.iter().enumerate() {
+            for (value, source) in response_tokens(flow) {
+                let issued = !texts[..=i].iter().any(|text| text.contains(&value));
+                let returned = texts[i + 1..].iter().any(|text| text.contains(&value));
+                if !issued || !returned || !correlated.insert(value.clone()) {
+                    continue;
+                }
+                let raw = match &source {
+                    Source::Json(path) => path.iter().rev().find_map(|segment| match segment {
+                        PathSegment::Key(key) => Some(key.as_str()),
+                        PathSegment::Index(_) => None,
+                    }),
+                    Source::Cookie(name) => Some(name.as_str()),
+                };
+                let name = variable_name(raw.unwrap_or("token"), &variables);
+                variables.push(Variable { name, value, producer: i, source });
+            }
+        }
+
+        let mut steps = Vec::new();
+        let mut previous_end: Option<f64> = None;
+        for (i, flow) in flows.iter().enumerate() {
+            let mut tokens: Vec<(&str, usize)> = variables
+                .iter()
+                .enumerate()
+                .filter(|(_, variable)| variable.producer < i)
+                .map(|(index, variable)| (variable.value.as_str(), index))
+                .collect();
+            tokens.sort_by_key(|(value, _)| std::cmp::Reverse(value.len()));
+
+            let request = &flow.request;
+            let mut headers: Vec<(String, Vec<Part>)> = Vec::new();
+            for (name, value) in &request.headers {
+                let lower = name.to_ascii_lowercase();
+                if lower.starts_with(':') || SKIPPED_HEADERS.contains(&lower.as_str()) {
+                    continue;
+                }
+                let value = substitute(value, &tokens);
+                match headers.iter_mut().find(|(existing, _)| existing.eq_ignore_ascii_case(name)) {
+                    Some((_, parts)) => {
+                        let separator = if lower == "cookie" { "; " } else { ", " };
+                        parts.push(Part::Text(separator.to_string()));
+                        parts.extend(value);
+                    }
+                    None => headers.push((name.clone(), value)),
+                }
+            }
+            let body = request.content.as_ref().filter(|content| !content.is_empty()).map(|content| {
+                match std::str::from_utf8(content) {
+                    Ok(text) => Body::Text(substitute(text, &tokens)),
+                    Err(_) => Body::Binary(content.clone()),
+                }
+            });
+
+            let think_time = previous_end
+                .map(|end| start(flow) - end)
+                .filter(|gap| *gap >= MIN_THINK_TIME)
+                .map(|gap| gap.min(MAX_THINK_TIME));
+            previous_end = Some(end(flow));
+            steps.push(Step {
+                host: request.pretty_host.clone(),
+                think_time,
+                method: request.method.clone(),
+                url: substitute(&request.url(), &tokens),
+                headers,
+                body,
+            });
+        }
+        let origin = flows.first().map(|flow| format!("{}://{}", flow.request.scheme, flow.request.pretty_host));
+        Self { origin, steps, variables }
+    }
+
+    fn has_binary_body(&self) -> bool {
+        self.steps.iter().any(|step| matches!(step.body, Some(Body::Binary(_))))
+    }
+
+    /// Steps split into runs of consecutive requests to one host
+    fn groups(&self) -> Vec<(&str, Vec<(usize, &Step)>)> {
+        let mut groups: Vec<(&str, Vec<(usize, &Step)>)> = Vec::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            match groups.last_mut() {
+                Some((host, steps)) if *host == step.host => steps.push((i, step)),
+                _ => groups.push((&step.host, vec![(i, step)])),
+            }
+        }
+        groups
+    }
+
+    fn extracted_by(&self, step: usize) -> impl Iterator<Item = &Variable> {
+        self.variables.iter().filter(move |variable| variable.producer == step)
+    }
+
+    /// A string expression concatenating literals and captured variables,
+    /// valid in both JavaScript and Python
+    fn expression(&self, parts: &[Part]) -> String {
+        if parts.is_empty() {
+            return literal("");
+        }
+        parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => literal(text),
+                Part::Var(index) => format!("captured[{}]", literal(&self.variables[*index].name)),
+            })
+            .collect::<Vec<_>>()
+            .join(" + ")
+    }
+
+    fn headers(&self, step: &Step) -> String {
+        let entries: Vec<String> = step
+            .headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}", literal(name), self.expression(value)))
+            .collect();
+        format!("{{{}}}", entries.join(", "))
+    }
+}
+
+/// Generate a k6 script replaying the flows as one virtual user iteration
+pub fn to_k6(flows: &[HTTPFlow]) -> String {
+    let plan = Plan::new(flows);
+    let mut script = format!("// k6 load test generated by mitmproxy-rs from {} flows\n", plan.steps.len());
+    script.push_str("import http from \"k6/http\";\n");
+    script.push_str("import { group, sleep } from \"k6\";\n");
+    if plan.has_binary_body() {
+        script.push_str("import encoding from \"k6/encoding\";\n");
+    }
+    script.push_str("\nexport default function () {\n");
+    script.push_str("  const captured = {};\n");
+    script.push_str("  let res;\n");
+    for (host, steps) in plan.groups() {
+        script.push_str(&format!("\n  group({}, function () {{\n", literal(host)));
+        for (i, step) in steps {
+            if let Some(seconds) = step.think_time {
+                script.push_str(&format!("    sleep({:.2});\n", seconds));
+            }
+            let body = match &step.body {
+                Some(Body::Text(parts)) => plan.expression(parts),
+                Some(Body::Binary(data)) => format!("encoding.b64decode({}, \"std\", \"b\")", literal(&STANDARD.encode(data))),
+                None => "null".to_string(),
+            };
+            script.push_str(&format!(
+                "    res = http.request({}, {}, {}, {{ headers: {}, redirects: 0 }});\n",
+                literal(&step.method),
+                plan.expression(&step.url),
+                body,
+                plan.headers(step),
+            ));
+            for variable in plan.extracted_by(i) {
+                let value = match &variable.source {
+                    Source::Json(path) => format!("res.json({})", literal(&gjson_path(path))),
+                    Source::Cookie(name) => format!("res.cookies[{}][0].value", literal(name)),
+                };
+                script.push_str(&format!("    captured[{}] = {};\n", literal(&variable.name), value));
+            }
+        }
+        script.push_str("  });\n");
+    }
+    script.push_str("}\n");
+    script
+}
+
+/// Generate a Locust file whose user replays the flows in one task
+pub fn to_locust(flows: &[HTTPFlow]) -> String {
+    let plan = Plan::new(flows);
+    let mut script = format!("# Locust load test generated by mitmproxy-rs from {} flows\n", plan.steps.len());
+    if plan.has_binary_body() {
+        script.push_str("import base64\n");
+    }
+    script.push_str("import time\n\n");
+    script.push_str("from locust import HttpUser, constant, task\n\n\n");
+    script.push_str("class RecordedUser(HttpUser):\n");
+    script.push_str(&format!("    host = {}\n", literal(plan.origin.as_deref().unwrap_or("http://localhost"))));
+    script.push_str("    wait_time = constant(0)\n\n");
+    script.push_str("    @task\n");
+    script.push_str("    def recorded_session(self):\n");
+    if plan.steps.is_empty() {
+        script.push_str("        pass\n");
+        return script;
+    }
+    script.push_str("        captured = {}\n");
+    for (host, steps) in plan.groups() {
+        script.push_str(&format!("\n        # {}\n", host));
+        for (i, step) in steps {
+            if let Some(seconds) = step.think_time {
+                script.push_str(&format!("        time.sleep({:.2})\n", seconds));
+            }
+            script.push_str("        res = self.client.request(\n");
+            script.push_str(&format!("            {},\n", literal(&step.method)));
+            script.push_str(&format!("            {},\n", plan.expression(&step.url)));
+            script.push_str(&format!("            headers={},\n", plan.headers(step)));
+            match &step.body {
+                Some(Body::Text(parts)) => {
+                    script.push_str(&format!("            data=({}).encode(),\n", plan.expression(parts)))
+                }
+                Some(Body::Binary(data)) => script.push_str(&format!(
+                    "            data=base64.b64decode({}),\n",
+                    literal(&STANDARD.encode(data))
+                )),
+                None => {}
+            }
+            script.push_str("            allow_redirects=False,\n");
+            script.push_str("        )\n");
+            for variable in plan.extracted_by(i) {
+                let value = match &variable.source {
+                    Source::Json(path) => {
+                        let path: String = path
+                            .iter()
+                            .map(|segment| match segment {
+                                PathSegment::Key(key) => format!("[{}]", literal(key)),
+                                PathSegment::Index(index) => format!("[{}]", index),
+                            })
+                            .collect();
+                        format!("res.json(){}", path)
+                    }
+                    Source::Cookie(name) => format!("res.cookies.get({})", literal(name)),
+                };
+                script.push_str(&format!("        captured[{}] = {}\n", literal(&variable.name), value));
+            }
+        }
+    }
+    script
+}
+
+/// A double-quoted string literal; JSON string syntax is valid JavaScript
+/// and Python alike
+fn literal(text: &str) -> String {
+    serde_json::to_string(text).unwrap_or_else(|_| "\"\"".to_string())
+}
+
+fn start(flow: &HTTPFlow) -> f64 {
+    flow.request.timestamp_start.unwrap_or(flow.flow.timestamp_created)
+}
+
+fn end(flow: &HTTPFlow) -> f64 {
+    flow.response
+        .as_ref()
+        .and_then(|response| response.timestamp_end.or(response.timestamp_start))
+        .or(flow.request.timestamp_end)
+        .unwrap_or_else(|| start(flow))
+}
+
+/// Everything of a request a token can be sent back in
+fn request_text(flow: &HTTPFlow) -> String {
+    let request = &flow.request;
+    let mut text = request.url();
+    for (name, value) in &request.headers {
+        text.push_str(&format!("\n{}: {}", name, value));
+    }
+    if let Some(content) = &request.content {
+        text.push('\n');
+        text.push_str(&String::from_utf8_lossy(content));
+    }
+    text
+}
+
+fn is_token(value: &str) -> bool {
+    value.len() >= MIN_TOKEN_LEN
+        && !value.chars().any(char::is_whitespace)
+        && value.chars().any(|c| c.is_ascii_digit())
+}
+
+/// Token candidates issued by the response of a flow
+fn response_tokens(flow: &HTTPFlow) -> Vec<(String, Source)> {
+    let Some(response) = &flow.response else {
+        return Vec::new();
+    };
+    let mut tokens = Vec::new();
+    for (name, value) in &response.headers {
+        if !name.eq_ignore_ascii_case("set-cookie") {
+            continue;
+        }
+        let pair = value.split(';').next().unwrap_or("");
+        if let Some((name, value)) = pair.split_once('=') {
+            let value = value.trim().trim_matches('"');
+            if is_token(value) {
+                tokens.push((value.to_string(), Source::Cookie(name.trim().to_string())));
+            }
+        }
+    }
+    if let Some(json) = response.content.as_deref().and_then(|content| serde_json::from_slice::<Value>(content).ok()) {
+        json_tokens(&json, &mut Vec::new(), &mut tokens);
+    }
+    tokens
+}
+
+fn json_tokens(value: &Value, path: &mut Vec<PathSegment>, tokens: &mut Vec<(String, Source)>) {
+    match value {
+        Value::String(text) if is_token(text) => tokens.push((text.clone(), Source::Json(path.clone()))),
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                path.push(PathSegment::Index(index));
+                json_tokens(item, path, tokens);
+                path.pop();
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map {
+                path.push(PathSegment::Key(key.clone()));
+                json_tokens(item, path, tokens);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Selector for k6's `Response.json()`, which uses GJSON syntax
+fn gjson_path(path: &[PathSegment]) -> String {
+    path.iter()
+        .map(|segment| match segment {
+            PathSegment::Key(key) => key
+                .chars()
+                .flat_map(|c| {
+                    let escape = matches!(c, '.' | '*' | '?' | '|' | '#' | '@' | '\\' | '!' | '=' | '<' | '>' | '%');
+                    escape.then_some('\\').into_iter().chain(std::iter::once(c))
+                })
+                .collect(),
+            PathSegment::Index(index) => index.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+/// A readable, unique variable name derived from a JSON key or cookie name
+fn variable_name(raw: &str, taken: &[Variable]) -> String {
+    let mut base = String::new();
+    for c in raw.chars() {
+        if c.is_ascii_alphanumeric() {
+            base.push(c.to_ascii_lowercase());
+        } else if !base.ends_with('_') {
+            base.push('_');
+        }
+    }
+    let base = match base.trim_matches('_') {
+        "" => "token".to_string(),
+        trimmed => trimmed.to_string(),
+    };
+    let mut name = base.clone();
+    let mut n = 1;
+    while taken.iter().any(|variable| variable.name == name) {
+        n += 1;
+        name = format!("{}_{}", base, n);
+    }
+    name
+}
+
+/// Split text into literal parts and the captured tokens it contains.
+/// `tokens` must be ordered longest first so overlapping values resolve to
+/// the most specific one.
+fn substitute(text: &str, tokens: &[(&str, usize)]) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut pending = String::new();
+    let mut rest = text;
+    'scan: while let Some(c) = rest.chars().next() {
+        for (value, index) in tokens {
+            if rest.starts_with(value) {
+                if !pending.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut pending)));
+                }
+                parts.push(Part::Var(*index));
+                rest = &rest[value.len()..];
+                continue 'scan;
+            }
+        }
+        pending.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    if !pending.is_empty() {
+        parts.push(Part::Text(pending));
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow(method: &str, host: &str, path: &str, start: f64, end: f64) -> HTTPFlow {
+        let mut request =
+            HTTPRequest::new(method.to_string(), "https".to_string(), host.to_string(), 443, path.to_string());
+        request.headers = vec![
+            ("Host".to_string(), host.to_string()),
+            ("Accept".to_string(), "application/json".to_string()),
+        ];
+        request.timestamp_start = Some(start);
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.timestamp_end = Some(end);
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    /// A login issuing a token and a cookie, then two requests using them
+    fn session() -> Vec<HTTPFlow> {
+        let mut login = flow("POST", "auth.example.com", "/login", 100.0, 100.5);
+        login.request.set_content(br#"{"user":"alice"}"#.to_vec());
+        let response = login.response.as_mut().unwrap();
+        response.set_content(br#"{"data":{"access_token":"tok3n-abc123","user":"alice"}}"#.to_vec());
+        response.headers.push(("Set-Cookie".to_string(), "sid=s3ssion99; Path=/; HttpOnly".to_string()));
+
+        let mut profile = flow("GET", "api.example.com", "/me?session=s3ssion99", 102.0, 102.2);
+        profile.request.headers.push(("Authorization".to_string(), "Bearer tok3n-abc123".to_string()));
+        let mut orders = flow("GET", "api.example.com", "/orders", 102.2, 102.4);
+        orders.request.headers.push(("Cookie".to_string(), "sid=s3ssion99".to_string()));
+        // Out of order on purpose; the export follows the timestamps
+        vec![orders, login, profile]
+    }
+
+    #[test]
+    fn test_correlated_tokens() {
+        let plan = Plan::new(&session());
+        let names: Vec<&str> = plan.variables.iter().map(|variable| variable.name.as_str()).collect();
+        assert_eq!(names, ["sid", "access_token"]);
+        assert_eq!(plan.variables[0].source, Source::Cookie("sid".to_string()));
+        assert_eq!(
+            plan.variables[1].source,
+            Source::Json(vec![PathSegment::Key("data".to_string()), PathSegment::Key("access_token".to_string())])
+        );
+        // "alice" is too short to be a token, and the login sent it first
+        assert_eq!(plan.steps[0].host, "auth.example.com");
+        assert_eq!(plan.steps[1].think_time, Some(1.5));
+        assert_eq!(plan.steps[2].think_time, None);
+        assert_eq!(
+            plan.steps[1].url,
+            vec![Part::Text("https://api.example.com/me?session=".to_string()), Part::Var(0)]
+        );
+
+        assert_eq!(substitute("a-xy-b", &[("xyz", 0), ("xy", 1)]), vec![
+            Part::Text("a-".to_string()),
+            Part::Var(1),
+            Part::Text("-b".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_k6_script() {
+        let script = to_k6(&session());
+        assert!(script.contains("group(\"auth.example.com\", function () {"));
+        assert!(script.contains("captured[\"access_token\"] = res.json(\"data.access_token\");"));
+        assert!(script.contains("captured[\"sid\"] = res.cookies[\"sid\"][0].value;"));
+        assert!(script.contains("\"Authorization\": \"Bearer \" + captured[\"access_token\"]"));
+        assert!(script.contains("sleep(1.50);"));
+        assert!(script.contains("\"{\\\"user\\\":\\\"alice\\\"}\""));
+        assert!(!script.contains("\"Host\""));
+        assert_eq!(script.matches("group(").count(), 2);
+        assert!(!script.contains("k6/encoding"));
+    }
+
+    #[test]
+    fn test_locust_script() {
+        let mut flows = session();
+        flows[0].request.set_content(vec![0xff, 0x00]);
+        let script = to_locust(&flows);
+        assert!(script.contains("    host = \"https://auth.example.com\"\n"));
+        assert!(script.contains("        captured[\"access_token\"] = res.json()[\"data\"][\"access_token\"]\n"));
+        assert!(script.contains("        captured[\"sid\"] = res.cookies.get(\"sid\")\n"));
+        assert!(script.contains("headers={\"Accept\": \"application/json\", \"Cookie\": \"sid=\" + captured[\"sid\"]}"));
+        assert!(script.contains("        time.sleep(1.50)\n"));
+        assert!(script.contains("data=base64.b64decode(\"/wA=\"),"));
+        assert!(script.starts_with("# Locust load test generated by mitmproxy-rs from 3 flows\nimport base64\n"));
+
+        assert!(to_locust(&[]).ends_with("    def recorded_session(self):\n        pass\n"));
+    }
+}
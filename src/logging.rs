@@ -0,0 +1,161 @@
+//! Per-subsystem log levels.
+//!
+//! Log output is filtered by subsystem rather than by a single verbosity
+//! switch, so that e.g. TLS handshakes can be traced while the rest of the
+//! proxy stays at `info`. Each subsystem covers a set of tracing targets,
+//! including those of the libraries doing the work for it. Levels come from
+//! the configuration and can be changed at runtime through the API.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+
+use serde::Serialize;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, Registry};
+
+use crate::{Error, Result};
+
+/// Target of HTTP/1 connection logs
+pub const HTTP1: &str = "mitmproxy_rs::http1";
+/// Target of HTTP/2 connection logs
+pub const HTTP2: &str = "mitmproxy_rs::http2";
+
+/// Subsystems and the tracing targets they cover
+pub const SUBSYSTEMS: &[(&str, &[&str])] = &[
+    ("proxy", &["mitmproxy_rs::proxy", "mitmproxy_rs::server", "mitmproxy_rs::addons"]),
+    ("tls", &["mitmproxy_rs::proxy::layers::tls", "mitmproxy_rs::certs", "openssl"]),
+    ("http1", &[HTTP1, "hyper"]),
+    ("http2", &[HTTP2, "h2"]),
+    (
+        "websocket",
+        &["mitmproxy_rs::proxy::layers::websocket", "mitmproxy_rs::websocket", "tokio_tungstenite", "tungstenite"],
+    ),
+    ("api", &["mitmproxy_rs::api", "axum", "tower_http"]),
+];
+
+/// Current log levels
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogLevels {
+    /// Level of everything not covered by a subsystem override
+    pub default: String,
+    /// Effective level of every subsystem
+    pub subsystems: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+struct State {
+    default: LevelFilter,
+    overrides: BTreeMap<String, LevelFilter>,
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+static STATE: OnceLock<Mutex<State>> = OnceLock::new();
+
+/// Install the global subscriber with `default` as the level of everything
+pub fn init(default: LevelFilter) {
+    let (filter, handle) = reload::Layer::new(build_filter(default, &BTreeMap::new()));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    let _ = STATE.set(Mutex::new(State { default, overrides: BTreeMap::new(), handle }));
+}
+
+/// Apply level changes. Keys are subsystem names or `default`; a `None`
+/// level resets a subsystem to the default. Nothing is applied if any
+/// entry is invalid.
+pub fn set_levels<'a>(levels: impl IntoIterator<Item = (&'a str, Option<&'a str>)>) -> Result<LogLevels> {
+    let state = STATE
+        .get()
+        .ok_or_else(|| Error::internal("Logging has not been initialized"))?;
+    let mut state = state.lock().unwrap();
+
+    let mut default = state.default;
+    let mut overrides = state.overrides.clone();
+    for (name, level) in levels {
+        match (name, level) {
+            ("default", Some(level)) => default = parse_level(level)?,
+            ("default", None) => return Err(Error::invalid_request("The default log level cannot be reset")),
+            (name, _) if !SUBSYSTEMS.iter().any(|(subsystem, _)| *subsystem == name) => {
+                return Err(Error::invalid_request(format!("Unknown log subsystem: {}", name)));
+            }
+            (name, Some(level)) => {
+                overrides.insert(name.to_string(), parse_level(level)?);
+            }
+            (name, None) => {
+                overrides.remove(name);
+            }
+        }
+    }
+
+    state
+        .handle
+        .reload(build_filter(default, &overrides))
+        .map_err(|e| Error::internal(format!("Cannot update log filter: {}", e)))?;
+    state.default = default;
+    state.overrides = overrides;
+    Ok(describe(default, &state.overrides))
+}
+
+/// Current levels, or `None` if logging has not been initialized
+pub fn levels() -> Option<LogLevels> {
+    let state = STATE.get()?.lock().unwrap();
+    Some(describe(state.default, &state.overrides))
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    level
+        .parse()
+        .map_err(|_| Error::invalid_request(format!("Invalid log level: {} (use off, error, warn, info, debug or trace)", level)))
+}
+
+fn build_filter(default: LevelFilter, overrides: &BTreeMap<String, LevelFilter>) -> EnvFilter {
+    let mut filter = EnvFilter::default().add_directive(default.into());
+    for (subsystem, targets) in SUBSYSTEMS {
+        if let Some(level) = overrides.get(*subsystem) {
+            for target in *targets {
+                let directive = format!("{}={}", target, level).parse().expect("valid target directive");
+                filter = filter.add_directive(directive);
+            }
+        }
+    }
+    filter
+}
+
+fn describe(default: LevelFilter, overrides: &BTreeMap<String, LevelFilter>) -> LogLevels {
+    LogLevels {
+        default: default.to_string(),
+        subsystems: SUBSYSTEMS
+            .iter()
+            .map(|(name, _)| (name.to_string(), overrides.get(*name).unwrap_or(&default).to_string()))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_filter() {
+        let mut overrides = BTreeMap::new();
+        overrides.insert("tls".to_string(), LevelFilter::WARN);
+        overrides.insert("http2".to_string(), LevelFilter::TRACE);
+        let filter = build_filter(LevelFilter::INFO, &overrides).to_string();
+        assert!(filter.contains("mitmproxy_rs::proxy::layers::tls=warn"));
+        assert!(filter.contains("h2=trace"));
+        assert!(!filter.contains("axum"));
+
+        let levels = describe(LevelFilter::INFO, &overrides);
+        assert_eq!(levels.subsystems["tls"], "warn");
+        assert_eq!(levels.subsystems["api"], "info");
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("DEBUG").unwrap(), LevelFilter::DEBUG);
+        assert!(parse_level("loud").is_err());
+    }
+}
@@ -1,7 +1,7 @@
-use clap::Parser;
-use mitmproxy_rs::{config::Config, server::MitmproxyServer, Result};
-use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
+use clap::{Parser, Subcommand, ValueEnum};
+use mitmproxy_rs::{config::Config, filter::Filter, io::FlowMerger, redact::Redactor, server::MitmproxyServer, Result};
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
 
 #[derive(Parser)]
 #[command(name = "mitmproxy-rs")]
@@ -10,7 +10,7 @@ struct Cli {
     #[arg(short, long, default_value = "127.0.0.1")]
     listen_host: String,
 
-    #[arg(short, long, default_value = "8080")]
+    #[arg(short = 'p', long, default_value = "8080")]
     listen_port: u16,
 
     #[arg(short, long, default_value = "8081")]
@@ -22,8 +22,206 @@ struct Cli {
     #[arg(short, long)]
     verbose: bool,
 
+    /// Log level of a subsystem, e.g. `tls=warn` or `http2=trace`
+    #[arg(long = "log", value_name = "SUBSYSTEM=LEVEL")]
+    log_levels: Vec<String>,
+
     #[arg(long)]
     config: Option<String>,
+
+    /// Proxy mode: `regular`, `transparent`, `socks5`, `upstream:URL` or
+    /// `reverse:URL`, e.g. `reverse:https://api.example.com`
+    #[arg(short, long)]
+    mode: Option<String>,
+
+    /// Forward the client's Host header unchanged in reverse mode
+    #[arg(long)]
+    keep_host_header: bool,
+
+    /// Also accept SOCKS5 clients on this port
+    #[arg(long)]
+    socks5_port: Option<u16>,
+
+    /// Also accept HTTP/3 clients on this UDP port (needs the http3 feature)
+    #[arg(long)]
+    http3_port: Option<u16>,
+
+    /// Also answer DNS queries on this UDP port
+    #[arg(long)]
+    dns_port: Option<u16>,
+
+    /// Forward DNS queries to this server (`host:port`) instead of
+    /// resolving them on the proxy
+    #[arg(long, value_name = "ADDR")]
+    dns_upstream: Option<String>,
+
+    /// Tunnel TLS connections to hosts matching this regex without
+    /// interception; may be repeated
+    #[arg(long, value_name = "REGEX")]
+    ignore_hosts: Vec<String>,
+
+    /// Only intercept and record connections to hosts matching this regex,
+    /// passing everything else through; may be repeated
+    #[arg(long, value_name = "REGEX")]
+    allow_hosts: Vec<String>,
+
+    /// Replace regex matches in message bodies, as
+    /// `/flow-filter/regex/replacement` or `/regex/replacement`; may be
+    /// repeated
+    #[arg(long, value_name = "SPEC")]
+    modify_body: Vec<String>,
+
+    /// Start with flow recording paused; traffic is still proxied
+    #[arg(long)]
+    no_record: bool,
+
+    /// Use an existing CA, such as mitmproxy's `mitmproxy-ca.pem` or
+    /// `mitmproxy-ca.p12`, or a directory holding one, e.g. `~/.mitmproxy`
+    #[arg(long, value_name = "PATH")]
+    certs: Option<String>,
+
+    /// Rewrite flows with this Rhai script, reloaded when it changes; may
+    /// be repeated (needs the scripting feature)
+    #[arg(short, long = "script", value_name = "FILE")]
+    scripts: Vec<String>,
+
+    /// Save raw bytes that fail to parse to this directory, for fuzzing
+    #[arg(long, value_name = "DIR")]
+    fuzz_corpus_dir: Option<String>,
+
+    /// Print the effective configuration, after the config file and the
+    /// other options were applied, and exit
+    #[arg(long, value_name = "FORMAT")]
+    print_config: Option<ConfigFormat>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ConfigFormat {
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the web API over a dump file without starting the proxy
+    View {
+        /// Dump file to load
+        file: String,
+    },
+    /// Capture this machine's traffic through a central proxy: accept
+    /// proxy connections locally and tunnel them to the proxy's agent port
+    Agent {
+        /// Agent port of the central proxy, `host:port`
+        #[arg(long)]
+        server: String,
+
+        /// Token configured on the central proxy
+        #[arg(long)]
+        token: String,
+
+        /// Local address applications use as their proxy
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// Name reported to the central proxy
+        #[arg(long)]
+        name: Option<String>,
+
+        /// CA certificate (PEM) to check the central proxy's certificate
+        /// against, e.g. its mitmproxy-ca-cert.pem
+        #[arg(long)]
+        ca_cert: Option<String>,
+
+        /// Do not verify the central proxy's certificate
+        #[arg(long)]
+        insecure: bool,
+    },
+    /// Work with dump files
+    Flows {
+        #[command(subcommand)]
+        command: FlowsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum FlowsCommand {
+    /// Combine dump files, dropping duplicate flows
+    Merge {
+        /// Dump files to merge
+        #[arg(required = true)]
+        inputs: Vec<String>,
+
+        /// File to write the merged dump to
+        #[arg(short, long)]
+        output: String,
+
+        /// Only keep flows matching this filter expression
+        #[arg(short, long)]
+        filter: Option<String>,
+    },
+    /// Write a copy of a dump with hosts, addresses and credentials replaced
+    Anonymize {
+        /// Dump file to anonymize
+        input: String,
+
+        /// File to write the anonymized dump to
+        #[arg(short, long)]
+        output: String,
+
+        /// Keep the pseudonym mapping in this file to reverse the process
+        #[arg(short, long)]
+        mapping: Option<String>,
+    },
+}
+
+fn anonymize_dump(input: &str, output: &str, mapping: Option<&str>) -> Result<()> {
+    let mut redactor = Redactor::new(uuid::Uuid::new_v4().to_string());
+    let flows: Vec<_> = mitmproxy_rs::io::read_flows_file(input)?
+        .iter()
+        .map(|flow| redactor.anonymize(flow))
+        .collect();
+
+    std::fs::write(output, mitmproxy_rs::io::write_flows(&flows)?)?;
+    info!("Wrote {} anonymized flows to {}", flows.len(), output);
+
+    if let Some(mapping) = mapping {
+        // The mapping reverses the anonymization, so it is kept private
+        mitmproxy_rs::certs::write_private(std::path::Path::new(mapping), &serde_json::to_vec_pretty(redactor.mapping())?)?;
+        info!("Wrote pseudonym mapping to {}", mapping);
+    }
+    Ok(())
+}
+
+fn merge_dumps(inputs: &[String], output: &str, filter: Option<&str>) -> Result<()> {
+    let filter = filter
+        .map(|expr| Filter::new("merge".to_string(), expr.to_string()))
+        .transpose()?;
+
+    let mut merger = FlowMerger::new();
+    let mut filtered = 0;
+    for input in inputs {
+        for flow in mitmproxy_rs::io::read_flows_file(input)? {
+            if filter.as_ref().is_some_and(|f| !f.matches(&flow)) {
+                filtered += 1;
+                continue;
+            }
+            merger.add(flow);
+        }
+    }
+
+    let duplicates = merger.duplicates;
+    let flows = merger.into_flows();
+    std::fs::write(output, mitmproxy_rs::io::write_flows(&flows)?)?;
+    info!(
+        "Wrote {} flows to {} ({} duplicates, {} filtered)",
+        flows.len(),
+        output,
+        duplicates,
+        filtered
+    );
+    Ok(())
 }
 
 #[tokio::main]
@@ -31,12 +229,26 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     // Initialize logging
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(if cli.verbose { Level::DEBUG } else { Level::INFO })
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    mitmproxy_rs::logging::init(if cli.verbose { LevelFilter::DEBUG } else { LevelFilter::INFO });
 
-    info!("Starting mitmproxy-rs");
+    if let Some(Command::Flows { command }) = &cli.command {
+        return match command {
+            FlowsCommand::Merge { inputs, output, filter } => merge_dumps(inputs, output, filter.as_deref()),
+            FlowsCommand::Anonymize { input, output, mapping } => anonymize_dump(input, output, mapping.as_deref()),
+        };
+    }
+
+    if let Some(Command::Agent { server, token, listen, name, ca_cert, insecure }) = &cli.command {
+        let options = mitmproxy_rs::agent::AgentOptions {
+            server: server.clone(),
+            token: token.clone(),
+            listen: listen.clone(),
+            name: name.clone().or_else(|| std::env::var("HOSTNAME").ok()).unwrap_or_else(|| "agent".to_string()),
+            ca_cert: ca_cert.clone(),
+            insecure: *insecure,
+        };
+        return mitmproxy_rs::agent::run_agent(&options).await;
+    }
 
     // Load configuration
     let config = if let Some(config_path) = cli.config {
@@ -45,6 +257,21 @@ async fn main() -> Result<()> {
         Config::default()
     };
 
+    // Subsystem levels from the command line take precedence
+    let cli_levels = cli.log_levels.iter().filter_map(|entry| match entry.split_once('=') {
+        Some((subsystem, level)) => Some((subsystem, Some(level))),
+        None => {
+            warn!("Ignoring --log {}: expected SUBSYSTEM=LEVEL", entry);
+            None
+        }
+    });
+    let config_levels = config.log_levels.iter().map(|(subsystem, level)| (subsystem.as_str(), Some(level.as_str())));
+    for (subsystem, level) in config_levels.chain(cli_levels) {
+        if let Err(e) = mitmproxy_rs::logging::set_levels([(subsystem, level)]) {
+            warn!("Ignoring log level for {}: {}", subsystem, e);
+        }
+    }
+
     let mut server_config = config;
     server_config.proxy_host = cli.listen_host;
     server_config.proxy_port = cli.listen_port;
@@ -52,9 +279,57 @@ async fn main() -> Result<()> {
     if let Some(web_host) = cli.web_host {
         server_config.web_host = web_host;
     }
+    if cli.no_record {
+        server_config.record = false;
+    }
+    if let Some(mode) = &cli.mode {
+        server_config.set_mode(mode)?;
+    }
+    if cli.keep_host_header {
+        server_config.keep_host_header = true;
+    }
+    if let Some(port) = cli.socks5_port {
+        server_config.socks5_port = Some(port);
+    }
+    if let Some(port) = cli.http3_port {
+        server_config.http3_port = Some(port);
+    }
+    if let Some(port) = cli.dns_port {
+        server_config.dns_port = Some(port);
+    }
+    if let Some(upstream) = cli.dns_upstream {
+        server_config.dns_upstream = Some(upstream);
+    }
+    if !cli.ignore_hosts.is_empty() {
+        server_config.ignore_hosts = cli.ignore_hosts;
+    }
+    if !cli.allow_hosts.is_empty() {
+        server_config.allow_hosts = cli.allow_hosts;
+    }
+    if !cli.modify_body.is_empty() {
+        server_config.modify_body = cli.modify_body;
+    }
+    if let Some(certs) = cli.certs {
+        server_config.ca_file = Some(certs);
+    }
+    if !cli.scripts.is_empty() {
+        server_config.scripts = cli.scripts;
+    }
+    if let Some(dir) = cli.fuzz_corpus_dir {
+        server_config.fuzz_corpus_dir = Some(dir);
+    }
+
+    if let Some(ConfigFormat::Json) = cli.print_config {
+        println!("{}", serde_json::to_string_pretty(&mitmproxy_rs::banner::effective_config(&server_config)?)?);
+        return Ok(());
+    }
 
     // Create and start the server
-    let server = MitmproxyServer::new(server_config).await?;
+    info!("Starting mitmproxy-rs");
+    let server = match cli.command {
+        Some(Command::View { file }) => MitmproxyServer::view(server_config, file).await?,
+        _ => MitmproxyServer::new(server_config).await?,
+    };
     server.run().await?;
 
     Ok(())
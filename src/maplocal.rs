@@ -0,0 +1,203 @@
+//! Serving local files instead of server responses.
+//!
+//! Each `map_local` entry is written `filter|path`: requests matching the
+//! filter expression are answered with the file at `path` and never reach
+//! a server. If `path` is a directory, the request path is looked up in it,
+//! with `index.html` standing in for directories, so `~d example.com|./dist`
+//! serves `./dist/js/app.js` for `http://example.com/js/app.js`. Files that
+//! do not exist are answered with 404. The first matching rule wins.
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::filter::Filter;
+use crate::flow::{HTTPFlow, HTTPResponse};
+use crate::upstream::percent_decode;
+use crate::{Error, Result};
+
+/// File served in place of a directory
+const INDEX_FILE: &str = "index.html";
+
+/// Content types by file extension, for files served
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// Requests matching `filter` are answered from `path`
+#[derive(Debug, Clone)]
+pub struct MapLocalRule {
+    pub filter: Filter,
+    pub path: PathBuf,
+}
+
+impl MapLocalRule {
+    /// Parse a `filter|path` entry. The path is everything after the last
+    /// `|`, so the filter may use `|` itself.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (filter, path) = spec
+            .rsplit_once('|')
+            .ok_or_else(|| Error::invalid_request(format!("Invalid map_local entry {}: expected filter|path", spec)))?;
+        let path = path.trim();
+        if path.is_empty() {
+            return Err(Error::invalid_request(format!("Invalid map_local entry {}: no path", spec)));
+        }
+        Ok(Self {
+            filter: Filter::new("map_local".to_string(), filter.to_string())?,
+            path: match (path.strip_prefix("~/"), dirs::home_dir()) {
+                (Some(rest), Some(home)) => home.join(rest),
+                _ => PathBuf::from(path),
+            },
+        })
+    }
+
+    /// The file served for a request to `request_path`, if the request may
+    /// have one: paths escaping a mapped directory have none
+    fn file(&self, request_path: &str) -> Option<PathBuf> {
+        if !self.path.is_dir() {
+            return Some(self.path.clone());
+        }
+        let request_path = request_path.split(['?', '#']).next().unwrap_or("/");
+        let mut file = self.path.clone();
+        for segment in request_path.split('/').map(percent_decode) {
+            let mut components = Path::new(&segment).components();
+            match (components.next(), components.next()) {
+                (None, _) | (Some(Component::CurDir), None) => {}
+                (Some(Component::Normal(name)), None) => file.push(name),
+                _ => return None,
+            }
+        }
+        if file.is_dir() {
+            file.push(INDEX_FILE);
+        }
+        Some(file)
+    }
+}
+
+/// The configured `map_local` rules
+#[derive(Debug, Clone, Default)]
+pub struct MapLocal {
+    rules: Vec<MapLocalRule>,
+}
+
+impl MapLocal {
+    pub fn from_specs(specs: &[String]) -> Result<Self> {
+        Ok(Self { rules: specs.iter().map(|spec| MapLocalRule::parse(spec)).collect::<Result<_>>()? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Local response to the request of `flow`, if a rule matches it
+    pub fn respond(&self, flow: &HTTPFlow) -> Option<HTTPResponse> {
+        let rule = self.rules.iter().find(|rule| rule.filter.matches(flow))?;
+        let file = rule.file(&flow.request.path);
+        Some(match file.as_deref().map(|file| (file, std::fs::read(file))) {
+            Some((file, Ok(data))) => response(200, content_type(file), data),
+            _ => response(404, "text/plain; charset=utf-8", b"File not found\n".to_vec()),
+        })
+    }
+}
+
+/// Content type of the file at `path`, guessed from its extension
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    CONTENT_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map_or("application/octet-stream", |(_, content_type)| content_type)
+}
+
+fn response(status: u16, content_type: &str, body: Vec<u8>) -> HTTPResponse {
+    let reason = http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("")
+        .to_string();
+    let mut response = HTTPResponse::new(status, reason);
+    response.headers = vec![
+        ("Content-Type".to_string(), content_type.to_string()),
+        ("Content-Length".to_string(), body.len().to_string()),
+    ];
+    response.timestamp_start = Some(crate::clock::now());
+    response.set_content(body);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    fn flow(host: &str, path: &str) -> HTTPFlow {
+        HTTPFlow::new(HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, path.to_string()))
+    }
+
+    #[test]
+    fn test_map_local() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("js")).unwrap();
+        std::fs::write(dir.path().join("js/app.js"), "console.log(1)").unwrap();
+        std::fs::write(dir.path().join("index.html"), "<h1>local</h1>").unwrap();
+        std::fs::write(dir.path().join("config.json"), "{}").unwrap();
+        let specs = [
+            format!("~d api.example.com & ~u /config|{}", dir.path().join("config.json").display()),
+            format!("~d example.com|{}", dir.path().display()),
+        ];
+        let map_local = MapLocal::from_specs(&specs).unwrap();
+
+        let response = map_local.respond(&flow("example.com", "/js/app.js?v=2")).unwrap();
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.get_header("content-type").unwrap(), "text/javascript; charset=utf-8");
+        assert_eq!(response.content.unwrap(), b"console.log(1)");
+        assert_eq!(map_local.respond(&flow("example.com", "/")).unwrap().content.unwrap(), b"<h1>local</h1>");
+        // A file rule serves the file whatever the path
+        let response = map_local.respond(&flow("api.example.com", "/config/v2")).unwrap();
+        assert_eq!(response.get_header("content-type").unwrap(), "application/json");
+
+        assert_eq!(map_local.respond(&flow("example.com", "/missing.css")).unwrap().status_code, 404);
+        assert_eq!(map_local.respond(&flow("example.com", "/js/%2e%2e/%2e%2e/etc/passwd")).unwrap().status_code, 404);
+        assert!(map_local.respond(&flow("example.org", "/js/app.js")).is_none());
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = MapLocalRule::parse("~d a.com | ~d b.com|/srv/static").unwrap();
+        assert_eq!(rule.path, PathBuf::from("/srv/static"));
+        assert!(rule.filter.matches(&flow("b.com", "/")));
+        assert!(MapLocalRule::parse("~d a.com").is_err());
+        assert!(MapLocalRule::parse("~d a.com|").is_err());
+        assert!(MapLocalRule::parse("~u [|/srv").is_err());
+        assert_eq!(content_type(Path::new("font.WOFF2")), "font/woff2");
+        assert_eq!(content_type(Path::new("blob")), "application/octet-stream");
+    }
+}
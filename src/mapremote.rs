@@ -0,0 +1,139 @@
+//! Rewriting request URLs before they are sent upstream.
+//!
+//! Each `map_remote` entry is written `regex|replacement`. Every match of
+//! the regex in a request's URL is replaced, with `$1` or `${name}`
+//! referring to capture groups, and the request goes to the resulting URL
+//! instead: `^https://staging\.example\.com/|https://example.com/` sends
+//! staging traffic to production. The Host header follows the new host if
+//! the request has one. All rules apply in order, each to the URL the
+//! previous one produced.
+
+use regex::Regex;
+
+use crate::flow::HTTPRequest;
+use crate::{Error, Result};
+
+/// Replaces matches of `regex` in request URLs with `replacement`
+#[derive(Debug, Clone)]
+pub struct MapRemoteRule {
+    pub regex: Regex,
+    pub replacement: String,
+}
+
+impl MapRemoteRule {
+    /// Parse a `regex|replacement` entry. The replacement is everything
+    /// after the last `|`, so the regex may use alternations.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::invalid_request(format!("Invalid map_remote entry {}: {}", spec, reason));
+        let (regex, replacement) = spec.rsplit_once('|').ok_or_else(|| invalid("expected regex|replacement"))?;
+        Ok(Self {
+            regex: Regex::new(regex).map_err(|e| invalid(&e.to_string()))?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+/// The configured `map_remote` rules
+#[derive(Debug, Clone, Default)]
+pub struct MapRemote {
+    rules: Vec<MapRemoteRule>,
+}
+
+impl MapRemote {
+    pub fn from_specs(specs: &[String]) -> Result<Self> {
+        Ok(Self { rules: specs.iter().map(|spec| MapRemoteRule::parse(spec)).collect::<Result<_>>()? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Point `request` at its rewritten URL. Returns whether it changed;
+    /// a rewrite producing an invalid URL leaves the request as it was.
+    pub fn rewrite(&self, request: &mut HTTPRequest) -> Result<bool> {
+        let original = request.url();
+        let mut url = original.clone();
+        for rule in &self.rules {
+            url = rule.regex.replace_all(&url, rule.replacement.as_str()).into_owned();
+        }
+        if url == original {
+            return Ok(false);
+        }
+
+        let invalid = |reason: &str| Error::invalid_request(format!("map_remote rewrote {} to {}: {}", original, url, reason));
+        let target = url::Url::parse(&url).map_err(|e| invalid(&e.to_string()))?;
+        if !matches!(target.scheme(), "http" | "https") {
+            return Err(invalid("scheme must be http or https"));
+        }
+        let host = match target.host().ok_or_else(|| invalid("missing host"))? {
+            url::Host::Ipv6(ip) => ip.to_string(),
+            host => host.to_string(),
+        };
+        let port = target.port_or_known_default().ok_or_else(|| invalid("missing port"))?;
+        let path = match target.query() {
+            Some(query) => format!("{}?{}", target.path(), query),
+            None => target.path().to_string(),
+        };
+
+        let rewritten = HTTPRequest::new(request.method.clone(), target.scheme().to_string(), host, port, path);
+        request.scheme = rewritten.scheme;
+        request.host = rewritten.host;
+        request.port = rewritten.port;
+        request.path = rewritten.path;
+        request.pretty_host = rewritten.pretty_host;
+        // Keep the header where the client put it
+        if let Some((_, value)) = request.headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("host")) {
+            *value = request.pretty_host.clone();
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(scheme: &str, host: &str, port: u16, path: &str) -> HTTPRequest {
+        let mut request = HTTPRequest::new("GET".to_string(), scheme.to_string(), host.to_string(), port, path.to_string());
+        request.headers = vec![("Host".to_string(), request.pretty_host.clone()), ("Accept".to_string(), "*/*".to_string())];
+        request
+    }
+
+    #[test]
+    fn test_map_remote() {
+        let map_remote = MapRemote::from_specs(&[
+            r"^https://staging\.example\.com/|https://example.com/".to_string(),
+            r"^(https?)://example\.com/v1/(\w+)|http://localhost:8080/api/$2?from=$1".to_string(),
+        ])
+        .unwrap();
+
+        let mut staging = request("https", "staging.example.com", 443, "/home?q=1");
+        assert!(map_remote.rewrite(&mut staging).unwrap());
+        assert_eq!(staging.url(), "https://example.com/home?q=1");
+        assert_eq!(staging.headers[0], ("Host".to_string(), "example.com".to_string()));
+
+        // Rules apply to the URL the previous rule produced
+        let mut api = request("https", "staging.example.com", 443, "/v1/users");
+        assert!(map_remote.rewrite(&mut api).unwrap());
+        assert_eq!(api.url(), "http://localhost:8080/api/users?from=https");
+        assert_eq!((api.host.as_str(), api.port), ("localhost", 8080));
+        assert_eq!(api.headers[0].1, "localhost:8080");
+
+        let mut other = request("http", "example.org", 80, "/");
+        assert!(!map_remote.rewrite(&mut other).unwrap());
+        assert_eq!(other.url(), "http://example.org/");
+    }
+
+    #[test]
+    fn test_invalid_rules_and_rewrites() {
+        assert!(MapRemoteRule::parse("example.com").is_err());
+        assert!(MapRemoteRule::parse("(example|example.org").is_err());
+        let rule = MapRemoteRule::parse("a|b|c").unwrap();
+        assert_eq!((rule.regex.as_str(), rule.replacement.as_str()), ("a|b", "c"));
+
+        let map_remote = MapRemote::from_specs(&["^https://|ftp://".to_string()]).unwrap();
+        let mut request = request("https", "example.com", 443, "/");
+        assert!(map_remote.rewrite(&mut request).is_err());
+        assert_eq!(request.url(), "https://example.com/");
+    }
+}
@@ -0,0 +1,287 @@
+//! Rolling traffic aggregates.
+//!
+//! Completed flows are folded into per-minute buckets holding, per host,
+//! request and error counts and a latency histogram. Only the host is kept:
+//! no paths, headers, bodies or client addresses. Buckets older than the
+//! window are dropped, and hosts seen fewer than `min_host_count` times in
+//! the window are reported together as `other`, so rarely visited sites
+//! cannot be singled out. Combined with `aggregate_only`, which stops flows
+//! from being stored at all, this gives shared deployments observability
+//! without a record of individual browsing.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::flow::HTTPFlow;
+
+/// Upper bounds of the latency histogram buckets, in milliseconds
+pub const LATENCY_BUCKETS_MS: &[f64] = &[10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0];
+
+/// Name under which hosts below the reporting threshold are grouped
+pub const OTHER_HOSTS: &str = "other";
+
+/// Metrics options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsOptions {
+    /// Length of the rolling window aggregates cover
+    pub window_secs: u64,
+    /// Hosts with fewer requests in the window are reported as `other`
+    pub min_host_count: u64,
+}
+
+impl Default for MetricsOptions {
+    fn default() -> Self {
+        Self { window_secs: 3600, min_host_count: 5 }
+    }
+}
+
+/// Aggregated statistics of one host, or of all traffic
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct HostStats {
+    pub requests: u64,
+    /// Flows that failed without a response
+    pub errors: u64,
+    /// Responses with a 5xx status
+    pub server_errors: u64,
+    /// Responses per status class, e.g. `2xx`
+    pub status_classes: BTreeMap<String, u64>,
+    /// Count per latency bucket; the last one counts slower responses
+    pub latency_buckets: Vec<u64>,
+    pub latency_sum_ms: f64,
+    pub latency_count: u64,
+}
+
+impl HostStats {
+    fn record(&mut self, flow: &HTTPFlow) {
+        self.requests += 1;
+        if flow.flow.error.is_some() {
+            self.errors += 1;
+        }
+        if let Some(response) = &flow.response {
+            if response.status_code >= 500 {
+                self.server_errors += 1;
+            }
+            *self.status_classes.entry(format!("{}xx", response.status_code / 100)).or_default() += 1;
+        }
+        if let Some(latency) = latency_ms(flow) {
+            if self.latency_buckets.is_empty() {
+                self.latency_buckets = vec![0; LATENCY_BUCKETS_MS.len() + 1];
+            }
+            let bucket = LATENCY_BUCKETS_MS.iter().position(|bound| latency <= *bound).unwrap_or(LATENCY_BUCKETS_MS.len());
+            self.latency_buckets[bucket] += 1;
+            self.latency_sum_ms += latency;
+            self.latency_count += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &HostStats) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.server_errors += other.server_errors;
+        for (class, count) in &other.status_classes {
+            *self.status_classes.entry(class.clone()).or_default() += count;
+        }
+        if !other.latency_buckets.is_empty() {
+            self.latency_buckets.resize(LATENCY_BUCKETS_MS.len() + 1, 0);
+            for (total, count) in self.latency_buckets.iter_mut().zip(&other.latency_buckets) {
+                *total += count;
+            }
+        }
+        self.latency_sum_ms += other.latency_sum_ms;
+        self.latency_count += other.latency_count;
+    }
+
+    /// Share of requests that failed or got a 5xx response
+    pub fn error_rate(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        (self.errors + self.server_errors) as f64 / self.requests as f64
+    }
+
+    /// Upper bound of the bucket holding the given quantile of latencies
+    pub fn latency_quantile_ms(&self, quantile: f64) -> Option<f64> {
+        if self.latency_count == 0 {
+            return None;
+        }
+        let rank = (quantile * self.latency_count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.latency_buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(LATENCY_BUCKETS_MS.get(i).copied().unwrap_or(f64::INFINITY));
+            }
+        }
+        None
+    }
+}
+
+/// Aggregates over the current window
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MetricsSummary {
+    pub window_secs: u64,
+    pub total: HostStats,
+    pub hosts: BTreeMap<String, HostStats>,
+}
+
+/// Per-minute buckets of host statistics
+#[derive(Debug, Default)]
+pub struct Metrics {
+    options: MetricsOptions,
+    buckets: VecDeque<(u64, BTreeMap<String, HostStats>)>,
+}
+
+impl Metrics {
+    pub fn new(options: MetricsOptions) -> Self {
+        Self { options, buckets: VecDeque::new() }
+    }
+
+    pub fn record(&mut self, flow: &HTTPFlow) {
+        self.record_at(flow, now());
+    }
+
+    fn record_at(&mut self, flow: &HTTPFlow, now: u64) {
+        let minute = now / 60;
+        if self.buckets.back().is_none_or(|(m, _)| *m != minute) {
+            self.buckets.push_back((minute, BTreeMap::new()));
+        }
+        self.expire(now);
+        let (_, hosts) = self.buckets.back_mut().expect("current bucket");
+        hosts.entry(flow.request.host.to_ascii_lowercase()).or_default().record(flow);
+    }
+
+    fn expire(&mut self, now: u64) {
+        let oldest = now.saturating_sub(self.options.window_secs) / 60;
+        while self.buckets.front().is_some_and(|(minute, _)| *minute < oldest) {
+            self.buckets.pop_front();
+        }
+    }
+
+    pub fn summary(&mut self) -> MetricsSummary {
+        self.summary_at(now())
+    }
+
+    fn summary_at(&mut self, now: u64) -> MetricsSummary {
+        self.expire(now);
+        let mut hosts: BTreeMap<String, HostStats> = BTreeMap::new();
+        for (_, bucket) in &self.buckets {
+            for (host, stats) in bucket {
+                hosts.entry(host.clone()).or_default().merge(stats);
+            }
+        }
+
+        let mut summary = MetricsSummary { window_secs: self.options.window_secs, ..Default::default() };
+        for (host, stats) in hosts {
+            summary.total.merge(&stats);
+            let name = if stats.requests < self.options.min_host_count { OTHER_HOSTS.to_string() } else { host };
+            summary.hosts.entry(name).or_default().merge(&stats);
+        }
+        summary
+    }
+
+    /// Aggregates in the Prometheus text exposition format
+    pub fn prometheus(&mut self) -> String {
+        let summary = self.summary();
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP mitmproxy_requests_total Requests in the last {}s.", summary.window_secs);
+        let _ = writeln!(out, "# TYPE mitmproxy_requests_total gauge");
+        for (host, stats) in &summary.hosts {
+            let _ = writeln!(out, "mitmproxy_requests_total{{host=\"{}\"}} {}", escape(host), stats.requests);
+        }
+        let _ = writeln!(out, "# HELP mitmproxy_errors_total Failed requests and 5xx responses in the last {}s.", summary.window_secs);
+        let _ = writeln!(out, "# TYPE mitmproxy_errors_total gauge");
+        for (host, stats) in &summary.hosts {
+            let _ = writeln!(out, "mitmproxy_errors_total{{host=\"{}\"}} {}", escape(host), stats.errors + stats.server_errors);
+        }
+        let _ = writeln!(out, "# HELP mitmproxy_latency_ms Response latency in the last {}s.", summary.window_secs);
+        let _ = writeln!(out, "# TYPE mitmproxy_latency_ms histogram");
+        for (host, stats) in &summary.hosts {
+            if stats.latency_count == 0 {
+                continue;
+            }
+            let host = escape(host);
+            let mut cumulative = 0;
+            for (i, count) in stats.latency_buckets.iter().enumerate() {
+                cumulative += count;
+                let le = LATENCY_BUCKETS_MS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+                let _ = writeln!(out, "mitmproxy_latency_ms_bucket{{host=\"{}\",le=\"{}\"}} {}", host, le, cumulative);
+            }
+            let _ = writeln!(out, "mitmproxy_latency_ms_sum{{host=\"{}\"}} {}", host, stats.latency_sum_ms);
+            let _ = writeln!(out, "mitmproxy_latency_ms_count{{host=\"{}\"}} {}", host, stats.latency_count);
+        }
+        out
+    }
+}
+
+fn latency_ms(flow: &HTTPFlow) -> Option<f64> {
+    let start = flow.request.timestamp_start?;
+    let end = flow.response.as_ref()?.timestamp_end?;
+    (end >= start).then_some((end - start) * 1000.0)
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow(host: &str, status: u16, latency_ms: f64) -> HTTPFlow {
+        let mut request = HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, "/private".to_string());
+        request.timestamp_start = Some(100.0);
+        let mut response = HTTPResponse::new(status, String::new());
+        response.timestamp_end = Some(100.0 + latency_ms / 1000.0);
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_aggregates_and_threshold() {
+        let mut metrics = Metrics::new(MetricsOptions { window_secs: 3600, min_host_count: 2 });
+        metrics.record_at(&flow("api.example.com", 200, 40.0), 1000);
+        metrics.record_at(&flow("API.example.com", 503, 700.0), 1010);
+        metrics.record_at(&flow("rare.example", 200, 5.0), 1020);
+
+        let summary = metrics.summary_at(1030);
+        assert_eq!(summary.total.requests, 3);
+        let api = &summary.hosts["api.example.com"];
+        assert_eq!((api.requests, api.server_errors), (2, 1));
+        assert_eq!(api.error_rate(), 0.5);
+        assert_eq!(api.status_classes["5xx"], 1);
+        assert_eq!(api.latency_quantile_ms(0.5), Some(50.0));
+        assert_eq!(api.latency_quantile_ms(0.99), Some(1000.0));
+        // Below the threshold, the host is not named
+        assert!(!summary.hosts.contains_key("rare.example"));
+        assert_eq!(summary.hosts[OTHER_HOSTS].requests, 1);
+    }
+
+    #[test]
+    fn test_window_expiry() {
+        let mut metrics = Metrics::new(MetricsOptions { window_secs: 120, min_host_count: 1 });
+        metrics.record_at(&flow("example.com", 200, 10.0), 0);
+        metrics.record_at(&flow("example.com", 200, 10.0), 150);
+        assert_eq!(metrics.summary_at(170).total.requests, 2);
+        assert_eq!(metrics.summary_at(250).total.requests, 1);
+        assert_eq!(metrics.summary_at(500).total.requests, 0);
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let mut metrics = Metrics::new(MetricsOptions { window_secs: 3600, min_host_count: 1 });
+        metrics.record(&flow("example.com", 200, 30.0));
+        let text = metrics.prometheus();
+        assert!(text.contains("mitmproxy_requests_total{host=\"example.com\"} 1"));
+        assert!(text.contains("mitmproxy_latency_ms_bucket{host=\"example.com\",le=\"25\"} 0"));
+        assert!(text.contains("mitmproxy_latency_ms_bucket{host=\"example.com\",le=\"50\"} 1"));
+        assert!(text.contains("mitmproxy_latency_ms_bucket{host=\"example.com\",le=\"+Inf\"} 1"));
+        assert!(!text.contains("/private"));
+    }
+}
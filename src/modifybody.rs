@@ -0,0 +1,238 @@
+//! Regex replacements in message bodies.
+//!
+//! Each `modify_body` entry is written `/flow-filter/regex/replacement`,
+//! with the same separator and filter rules as `modify_headers`: every
+//! match of the regex in the body of a matching message is replaced, with
+//! `$1` or `${name}` referring to capture groups. A replacement starting
+//! with `@` is the content of the file it names, inserted as it is.
+//! Bodies are matched as bytes, so binary bodies pass through intact where
+//! nothing matches. Compressed bodies are decoded first and sent decoded if
+//! a rule changed them. Bodies larger than the size limit, before or after
+//! the replacements, are left alone.
+
+use regex::bytes::{NoExpand, Regex};
+use tracing::debug;
+
+use crate::flow::HTTPFlow;
+use crate::modifyheaders::split_spec;
+use crate::{Error, Result};
+
+/// Largest body rules apply to, by default
+pub const DEFAULT_MAX_SIZE: usize = 10 * 1024 * 1024;
+
+/// Replaces matches of `regex` in bodies of flows matching `filter`
+#[derive(Debug, Clone)]
+pub struct ModifyBodyRule {
+    /// The entry the rule was parsed from
+    pub spec: String,
+    /// Matches every flow when left out
+    pub filter: Option<crate::filter::Filter>,
+    pub regex: Regex,
+    pub replacement: Vec<u8>,
+    /// Whether the replacement was read from a file, and is inserted
+    /// without expanding capture group references
+    pub literal: bool,
+}
+
+impl ModifyBodyRule {
+    /// Parse a `[/flow-filter]/regex/replacement` entry
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::invalid_request(format!("Invalid modify_body entry {}: {}", spec, reason));
+        let (filter, regex, replacement) = split_spec("modify_body", spec, "regex/replacement")?;
+        if regex.is_empty() {
+            return Err(invalid("empty regex"));
+        }
+        let regex = Regex::new(regex).map_err(|e| invalid(&e.to_string()))?;
+        let (replacement, literal) = match replacement.strip_prefix('@') {
+            Some(path) => (std::fs::read(path).map_err(|e| invalid(&format!("cannot read {}: {}", path, e)))?, true),
+            None => (replacement.as_bytes().to_vec(), false),
+        };
+        Ok(Self { spec: spec.to_string(), filter, regex, replacement, literal })
+    }
+
+    fn matches(&self, flow: &HTTPFlow) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.matches(flow))
+    }
+
+    fn replace(&self, body: &[u8]) -> Vec<u8> {
+        if self.literal {
+            self.regex.replace_all(body, NoExpand(&self.replacement)).into_owned()
+        } else {
+            self.regex.replace_all(body, self.replacement.as_slice()).into_owned()
+        }
+    }
+}
+
+/// The configured `modify_body` rules
+#[derive(Debug, Clone)]
+pub struct ModifyBody {
+    rules: Vec<ModifyBodyRule>,
+    max_size: usize,
+}
+
+impl Default for ModifyBody {
+    fn default() -> Self {
+        Self { rules: Vec::new(), max_size: DEFAULT_MAX_SIZE }
+    }
+}
+
+impl ModifyBody {
+    pub fn new(specs: &[String], max_size: usize) -> Result<Self> {
+        let rules = specs.iter().map(|spec| ModifyBodyRule::parse(spec)).collect::<Result<_>>()?;
+        Ok(Self { rules, max_size })
+    }
+
+    /// The entries the rules were parsed from
+    pub fn specs(&self) -> Vec<String> {
+        self.rules.iter().map(|rule| rule.spec.clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Apply the rules to the request body of `flow`. Returns whether it
+    /// changed.
+    pub fn apply_request(&self, flow: &mut HTTPFlow) -> bool {
+        let request = &flow.request;
+        let Some(body) = self.rewrite(flow, &request.headers, request.content.as_deref()) else {
+            return false;
+        };
+        let request = &mut flow.request;
+        mark_decoded(&mut request.headers, body.len());
+        request.set_content(body);
+        true
+    }
+
+    /// Apply the rules to the response body of `flow`. Returns whether it
+    /// changed.
+    pub fn apply_response(&self, flow: &mut HTTPFlow) -> bool {
+        let Some(response) = flow.response.as_ref() else {
+            return false;
+        };
+        let Some(body) = self.rewrite(flow, &response.headers, response.content.as_deref()) else {
+            return false;
+        };
+        let Some(response) = flow.response.as_mut() else {
+            return false;
+        };
+        mark_decoded(&mut response.headers, body.len());
+        response.set_content(body);
+        true
+    }
+
+    /// The body with the replacements of the rules matching `flow`, if any
+    /// changed it
+    fn rewrite(&self, flow: &HTTPFlow, headers: &[(String, String)], body: Option<&[u8]>) -> Option<Vec<u8>> {
+        let body = body.filter(|body| !body.is_empty())?;
+        let rules: Vec<&ModifyBodyRule> = self.rules.iter().filter(|rule| rule.matches(flow)).collect();
+        if rules.is_empty() {
+            return None;
+        }
+        if body.len() > self.max_size {
+            debug!("modify_body skips a {} byte body, over the {} byte limit", body.len(), self.max_size);
+            return None;
+        }
+        let encoding = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("content-encoding"));
+        let decoded = match encoding {
+            Some((_, encoding)) => match crate::compression::decode(encoding, body) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    debug!("modify_body skips a body it cannot decode: {}", e);
+                    return None;
+                }
+            },
+            None => body.to_vec(),
+        };
+        let mut modified = decoded.clone();
+        for rule in rules {
+            modified = rule.replace(&modified);
+            if modified.len() > self.max_size {
+                debug!("modify_body leaves a body unchanged that would grow over {} bytes", self.max_size);
+                return None;
+            }
+        }
+        (modified != decoded).then_some(modified)
+    }
+}
+
+/// Update the headers of a message whose body is now sent decoded and
+/// `length` bytes long
+fn mark_decoded(headers: &mut Vec<(String, String)>, length: usize) {
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-encoding"));
+    if let Some((_, value)) = headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("content-length")) {
+        *value = length.to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+    use std::io::Write;
+
+    fn flow(host: &str, request_body: &[u8], response_body: &[u8]) -> HTTPFlow {
+        let mut request = HTTPRequest::new("POST".to_string(), "https".to_string(), host.to_string(), 443, "/".to_string());
+        request.headers = vec![("Content-Length".to_string(), request_body.len().to_string())];
+        request.set_content(request_body.to_vec());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_content(response_body.to_vec());
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_modify_body() {
+        let modify = ModifyBody::new(
+            &[
+                r"/~q/(\w+)@example\.com/$1@test.invalid".to_string(),
+                r"|~d api.example.com & ~s|\x00secret|public".to_string(),
+            ],
+            DEFAULT_MAX_SIZE,
+        )
+        .unwrap();
+
+        let mut request = flow("api.example.com", b"{\"to\":\"alice@example.com\"}", b"\xff\x00secret\xfe");
+        request.response = None;
+        assert!(modify.apply_request(&mut request));
+        assert_eq!(request.request.content.as_deref().unwrap(), b"{\"to\":\"alice@test.invalid\"}");
+        assert_eq!(request.request.get_header("content-length").unwrap(), "27");
+
+        // Binary bodies keep their other bytes
+        let mut response = flow("api.example.com", b"", b"\xff\x00secret\xfe");
+        assert!(modify.apply_response(&mut response));
+        assert_eq!(response.response.unwrap().content.unwrap(), b"\xffpublic\xfe");
+
+        let mut other = flow("example.com", b"bob@example.org", b"\x00secret");
+        assert!(!modify.apply_request(&mut other));
+        assert!(!modify.apply_response(&mut other));
+        assert_eq!(modify.specs().len(), 2);
+    }
+
+    #[test]
+    fn test_encoded_bodies_and_limits() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let replacement = dir.path().join("replacement");
+        std::fs::write(&replacement, "$1 stays").unwrap();
+        let modify = ModifyBody::new(&[format!("|(hello)|@{}", replacement.display())], 32).unwrap();
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(b"hello world").unwrap();
+        let mut encoded = flow("example.com", b"", &gzip.finish().unwrap());
+        encoded.response.as_mut().unwrap().headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+        assert!(modify.apply_response(&mut encoded));
+        let response = encoded.response.unwrap();
+        assert_eq!(response.content.as_deref().unwrap(), b"$1 stays world");
+        assert!(response.get_header("content-encoding").is_none());
+
+        let mut large = flow("example.com", &[b'x'; 33], b"hello");
+        large.request.content.as_mut().unwrap()[0] = b'h';
+        assert!(!modify.apply_request(&mut large));
+        let mut growing = flow("example.com", b"hello hello hello hello", b"");
+        assert!(!modify.apply_request(&mut growing));
+        assert_eq!(growing.request.content.unwrap(), b"hello hello hello hello");
+
+        for invalid in ["/x", "//y", "/(x/y", "/~q/x/@/nonexistent/file"] {
+            assert!(ModifyBodyRule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
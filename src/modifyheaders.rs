@@ -0,0 +1,173 @@
+//! Declarative header rewriting.
+//!
+//! Each `modify_headers` entry is written `/flow-filter/name/value`, where
+//! the first character is the separator and the filter may be left out:
+//! `/User-Agent/curl` sets the header on every message. An empty value
+//! removes the header, and a value starting with `@` is read from the file
+//! it names. Rules apply to requests before they are forwarded and to
+//! responses before they reach the client; `~q` or `~s` in the filter
+//! restricts a rule to one of them. All headers named by matching rules
+//! are removed first, then the non-empty values are added in rule order.
+
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::{Error, Result};
+
+/// Sets or removes the header `name` in messages of flows matching `filter`
+#[derive(Debug, Clone)]
+pub struct ModifyHeadersRule {
+    /// The entry the rule was parsed from
+    pub spec: String,
+    /// Matches every flow when left out
+    pub filter: Option<Filter>,
+    pub name: String,
+    /// Empty to remove the header
+    pub value: String,
+}
+
+impl ModifyHeadersRule {
+    /// Parse a `[/flow-filter]/name/value` entry. Only a filter allows the
+    /// value to contain the separator.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::invalid_request(format!("Invalid modify_headers entry {}: {}", spec, reason));
+        let (filter, name, value) = split_spec("modify_headers", spec, "name/value")?;
+        if name.is_empty() || http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(invalid("invalid header name"));
+        }
+        let value = match value.strip_prefix('@') {
+            Some(path) => {
+                let data = std::fs::read_to_string(path).map_err(|e| invalid(&format!("cannot read {}: {}", path, e)))?;
+                data.trim_end_matches(['\r', '\n']).to_string()
+            }
+            None => value.to_string(),
+        };
+        Ok(Self { spec: spec.to_string(), filter, name: name.to_string(), value })
+    }
+
+    fn matches(&self, flow: &HTTPFlow) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.matches(flow))
+    }
+}
+
+/// Split a `[/flow-filter]/subject/value` entry of the `option` rules,
+/// whose first character is the separator, and compile its filter
+pub(crate) fn split_spec<'a>(option: &str, spec: &'a str, parts: &str) -> Result<(Option<Filter>, &'a str, &'a str)> {
+    let invalid = || Error::invalid_request(format!("Invalid {} entry {}: expected /flow-filter/{} or /{}", option, spec, parts, parts));
+    let separator = spec.chars().next().ok_or_else(invalid)?;
+    match spec[separator.len_utf8()..].splitn(3, separator).collect::<Vec<_>>()[..] {
+        [subject, value] => Ok((None, subject, value)),
+        [filter, subject, value] => Ok((Some(Filter::new(option.to_string(), filter.to_string())?), subject, value)),
+        _ => Err(invalid()),
+    }
+}
+
+/// The configured `modify_headers` rules
+#[derive(Debug, Clone, Default)]
+pub struct ModifyHeaders {
+    rules: Vec<ModifyHeadersRule>,
+}
+
+impl ModifyHeaders {
+    pub fn from_specs(specs: &[String]) -> Result<Self> {
+        Ok(Self { rules: specs.iter().map(|spec| ModifyHeadersRule::parse(spec)).collect::<Result<_>>()? })
+    }
+
+    /// The entries the rules were parsed from
+    pub fn specs(&self) -> Vec<String> {
+        self.rules.iter().map(|rule| rule.spec.clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Rewrite the request headers of `flow`. Returns whether a rule
+    /// matched.
+    pub fn apply_request(&self, flow: &mut HTTPFlow) -> bool {
+        let rules = self.matching(flow);
+        modify(&mut flow.request.headers, &rules)
+    }
+
+    /// Rewrite the response headers of `flow`. Returns whether a rule
+    /// matched.
+    pub fn apply_response(&self, flow: &mut HTTPFlow) -> bool {
+        let rules = self.matching(flow);
+        match flow.response.as_mut() {
+            Some(response) => modify(&mut response.headers, &rules),
+            None => false,
+        }
+    }
+
+    fn matching(&self, flow: &HTTPFlow) -> Vec<&ModifyHeadersRule> {
+        self.rules.iter().filter(|rule| rule.matches(flow)).collect()
+    }
+}
+
+fn modify(headers: &mut Vec<(String, String)>, rules: &[&ModifyHeadersRule]) -> bool {
+    headers.retain(|(name, _)| !rules.iter().any(|rule| rule.name.eq_ignore_ascii_case(name)));
+    headers.extend(
+        rules
+            .iter()
+            .filter(|rule| !rule.value.is_empty())
+            .map(|rule| (rule.name.clone(), rule.value.clone())),
+    );
+    !rules.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow(host: &str) -> HTTPFlow {
+        let mut request = HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, "/".to_string());
+        request.headers = vec![
+            ("User-Agent".to_string(), "browser".to_string()),
+            ("Cookie".to_string(), "a=1".to_string()),
+        ];
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.headers = vec![("Server".to_string(), "nginx".to_string())];
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_modify_headers() {
+        let modify = ModifyHeaders::from_specs(&[
+            "/User-Agent/curl".to_string(),
+            "|~d example.com & ~q|Cookie|".to_string(),
+            "/~s/X-Debug/1/2".to_string(),
+            "/~s/Server/".to_string(),
+        ])
+        .unwrap();
+
+        let mut matching = flow("example.com");
+        matching.response = None;
+        assert!(modify.apply_request(&mut matching));
+        assert_eq!(matching.request.headers, [("User-Agent".to_string(), "curl".to_string())]);
+
+        let mut other = flow("example.org");
+        assert!(modify.apply_request(&mut other));
+        assert_eq!(other.request.get_header("cookie").unwrap(), "a=1");
+        assert!(modify.apply_response(&mut other));
+        let response = other.response.unwrap();
+        assert_eq!(
+            response.headers,
+            [("User-Agent".to_string(), "curl".to_string()), ("X-Debug".to_string(), "1/2".to_string())]
+        );
+        assert_eq!(modify.specs()[1], "|~d example.com & ~q|Cookie|");
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let token = dir.path().join("token");
+        std::fs::write(&token, "Bearer abc\n").unwrap();
+        let rule = ModifyHeadersRule::parse(&format!("/~d api.example.com/Authorization/@{}", token.display())).unwrap();
+        assert_eq!((rule.name.as_str(), rule.value.as_str()), ("Authorization", "Bearer abc"));
+        assert!(rule.matches(&flow("api.example.com")) && !rule.matches(&flow("example.com")));
+
+        for invalid in ["", "/User-Agent", "//value", "/Bad Name/x", "/~u [/X/y", "/~q/X/@/nonexistent/file"] {
+            assert!(ModifyHeadersRule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! CA certificate installation, served on the magic `mitm.it` host.
+//!
+//! Clients browsing through the proxy to `http://mitm.it/` get a page
+//! linking the CA certificate in the formats platforms install: PEM for
+//! Linux, Firefox and Android, DER (`.cer`) for Windows, and a
+//! configuration profile for iOS and macOS. The proxy answers these
+//! requests itself; they never reach a server. The web API serves the same
+//! downloads under `/cert/`.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::certs::CertificateAuthority;
+use crate::flow::{HTTPRequest, HTTPResponse};
+use crate::Result;
+
+/// Host answered by the proxy unless `onboarding_host` says otherwise
+pub const DEFAULT_HOST: &str = "mitm.it";
+
+/// Format the CA certificate is downloaded in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertFormat {
+    Pem,
+    /// DER, with the `.cer` extension Windows expects
+    Cer,
+    /// Apple configuration profile installing the certificate as a root
+    MobileConfig,
+}
+
+impl CertFormat {
+    pub const ALL: [CertFormat; 3] = [CertFormat::Pem, CertFormat::Cer, CertFormat::MobileConfig];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CertFormat::Pem => "pem",
+            CertFormat::Cer => "cer",
+            CertFormat::MobileConfig => "mobileconfig",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            CertFormat::Pem => "application/x-x509-ca-cert",
+            CertFormat::Cer => "application/pkix-cert",
+            CertFormat::MobileConfig => "application/x-apple-aspen-config",
+        }
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("mitmproxy-ca-cert.{}", self.name())
+    }
+
+    fn platforms(&self) -> &'static str {
+        match self {
+            CertFormat::Pem => "Linux, Android, Firefox",
+            CertFormat::Cer => "Windows",
+            CertFormat::MobileConfig => "iOS, macOS",
+        }
+    }
+}
+
+/// The CA certificate in `format`
+pub fn cert_download(ca: &CertificateAuthority, format: CertFormat) -> Result<Vec<u8>> {
+    Ok(match format {
+        CertFormat::Pem => ca.ca_cert_pem()?,
+        CertFormat::Cer => ca.ca_cert_der()?,
+        CertFormat::MobileConfig => mobileconfig(&ca.ca_cert_der()?).into_bytes(),
+    })
+}
+
+/// Configuration profile installing the DER certificate `der` as a trusted
+/// root. Its UUIDs derive from the certificate, so the profile of one CA is
+/// always the same and installing it again replaces the earlier one.
+pub fn mobileconfig(der: &[u8]) -> String {
+    let digest = Sha256::digest(der);
+    let uuid = |salt: u8| {
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        bytes[0] ^= salt;
+        uuid::Builder::from_random_bytes(bytes).into_uuid().to_string().to_uppercase()
+    };
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>PayloadContent</key>
+    <array>
+        <dict>
+            <key>PayloadCertificateFileName</key>
+            <string>mitmproxy-ca-cert.cer</string>
+            <key>PayloadContent</key>
+            <data>{}</data>
+            <key>PayloadDescription</key>
+            <string>Adds the mitmproxy CA certificate</string>
+            <key>PayloadDisplayName</key>
+            <string>mitmproxy</string>
+            <key>PayloadIdentifier</key>
+            <string>org.mitmproxy.rs.ca.{}</string>
+            <key>PayloadType</key>
+            <string>com.apple.security.root</string>
+            <key>PayloadUUID</key>
+            <string>{}</string>
+            <key>PayloadVersion</key>
+            <integer>1</integer>
+        </dict>
+    </array>
+    <key>PayloadDisplayName</key>
+    <string>mitmproxy CA</string>
+    <key>PayloadIdentifier</key>
+    <string>org.mitmproxy.rs.{}</string>
+    <key>PayloadRemovalDisallowed</key>
+    <false/>
+    <key>PayloadType</key>
+    <string>Configuration</string>
+    <key>PayloadUUID</key>
+    <string>{}</string>
+    <key>PayloadVersion</key>
+    <integer>1</integer>
+</dict>
+</plist>
+"#,
+        STANDARD.encode(der),
+        uuid(1),
+        uuid(1),
+        uuid(0),
+        uuid(0),
+    )
+}
+
+/// Whether `request` goes to the onboarding host and is answered by
+/// [`respond`]
+pub fn is_onboarding_request(request: &HTTPRequest, onboarding_host: &str) -> bool {
+    !request.method.eq_ignore_ascii_case("CONNECT") && request.host.eq_ignore_ascii_case(onboarding_host)
+}
+
+/// Answer a request to the onboarding host: the index page at `/`, the
+/// certificate at `/cert/<format>`
+pub fn respond(request: &HTTPRequest, ca: Option<&CertificateAuthority>) -> HTTPResponse {
+    let path = request.path.split(['?', '#']).next().unwrap_or("/");
+    if path == "/" {
+        return response(200, "text/html; charset=utf-8", index_page().into_bytes());
+    }
+    let Some(format) = path.strip_prefix("/cert/").and_then(CertFormat::from_name) else {
+        return response(404, "text/plain; charset=utf-8", b"Not found\n".to_vec());
+    };
+    let Some(ca) = ca else {
+        return response(503, "text/plain; charset=utf-8", b"The proxy has no CA certificate\n".to_vec());
+    };
+    match cert_download(ca, format) {
+        Ok(data) => {
+            let mut response = response(200, format.content_type(), data);
+            response
+                .headers
+                .push(("Content-Disposition".to_string(), format!("attachment; filename=\"{}\"", format.file_name())));
+            response
+        }
+        Err(e) => response(500, "text/plain; charset=utf-8", format!("{}\n", e).into_bytes()),
+    }
+}
+
+fn response(status: u16, content_type: &str, body: Vec<u8>) -> HTTPResponse {
+    let reason = http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("")
+        .to_string();
+    let mut response = HTTPResponse::new(status, reason);
+    response.headers = vec![
+        ("Content-Type".to_string(), content_type.to_string()),
+        ("Content-Length".to_string(), body.len().to_string()),
+        ("Cache-Control".to_string(), "no-store".to_string()),
+    ];
+    response.timestamp_start = Some(crate::clock::now());
+    response.set_content(body);
+    response
+}
+
+fn index_page() -> String {
+    let links: String = CertFormat::ALL
+        .iter()
+        .map(|format| {
+            format!(
+                "<li><a href=\"/cert/{}\">{}</a> ({})</li>\n",
+                format.name(),
+                format.file_name(),
+                format.platforms()
+            )
+        })
+        .collect();
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>mitmproxy-rs certificate</title></head>\n<body>\n\
+         <h1>Install the mitmproxy-rs CA certificate</h1>\n<ul>\n{}</ul>\n\
+         <p>Only install the certificate of a proxy you run yourself: it can intercept all encrypted traffic.</p>\n\
+         </body>\n</html>\n",
+        links
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn request(path: &str) -> HTTPRequest {
+        HTTPRequest::new("GET".to_string(), "http".to_string(), "mitm.it".to_string(), 80, path.to_string())
+    }
+
+    #[test]
+    fn test_onboarding_responses() {
+        let temp_dir = TempDir::new().unwrap();
+        let ca = CertificateAuthority::new(temp_dir.path()).unwrap();
+
+        assert!(is_onboarding_request(&request("/"), "MITM.it"));
+        let mut connect = request("mitm.it:443");
+        connect.method = "CONNECT".to_string();
+        assert!(!is_onboarding_request(&connect, DEFAULT_HOST));
+
+        let index = respond(&request("/"), Some(&ca));
+        assert_eq!(index.status_code, 200);
+        assert!(String::from_utf8(index.content.unwrap()).unwrap().contains("href=\"/cert/mobileconfig\""));
+
+        let pem = respond(&request("/cert/pem?download=1"), Some(&ca));
+        assert_eq!(pem.content.as_deref(), Some(ca.ca_cert_pem().unwrap().as_slice()));
+        assert_eq!(pem.get_header("content-disposition").unwrap(), "attachment; filename=\"mitmproxy-ca-cert.pem\"");
+        let cer = respond(&request("/cert/cer"), Some(&ca));
+        assert_eq!(cer.content.as_deref(), Some(ca.ca_cert_der().unwrap().as_slice()));
+
+        let profile = String::from_utf8(respond(&request("/cert/mobileconfig"), Some(&ca)).content.unwrap()).unwrap();
+        assert!(profile.contains(&STANDARD.encode(ca.ca_cert_der().unwrap())));
+        assert_eq!(profile, mobileconfig(&ca.ca_cert_der().unwrap()));
+
+        assert_eq!(respond(&request("/cert/p7b"), Some(&ca)).status_code, 404);
+        assert_eq!(respond(&request("/cert/pem"), None).status_code, 503);
+    }
+}
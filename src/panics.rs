@@ -0,0 +1,176 @@
+//! Containing panics.
+//!
+//! Connection tasks and addon hooks run under [`catch`] or [`catch_async`],
+//! so a bug triggered by one malformed connection or one faulty addon fails
+//! that connection or flow instead of the whole process. While such a guard
+//! is active, the panic hook captures the message, location and a backtrace
+//! into a [`PanicReport`] rather than printing them to stderr; the caller
+//! decides where the report goes.
+
+use serde::Serialize;
+use serde_json::json;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Once;
+use std::task::{Context, Poll};
+
+use crate::flow::HTTPFlow;
+use crate::Error;
+
+/// Flow metadata key under which the details of a panic are recorded
+pub const METADATA_KEY: &str = "panic";
+
+thread_local! {
+    /// Number of active guards on this thread
+    static GUARDS: Cell<usize> = const { Cell::new(0) };
+    /// Report of the last panic caught on this thread
+    static LAST_REPORT: RefCell<Option<PanicReport>> = const { RefCell::new(None) };
+}
+
+/// A caught panic
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PanicReport {
+    pub message: String,
+    /// Source location, e.g. `src/proxy/layers/http.rs:120:9`
+    pub location: Option<String>,
+    pub backtrace: String,
+}
+
+impl PanicReport {
+    /// Fail a flow with this panic as its error
+    pub fn apply(&self, flow: &mut HTTPFlow) {
+        flow.flow.set_error(format!("Internal error: {}", self.message));
+        flow.flow.metadata.insert(
+            METADATA_KEY.to_string(),
+            json!({ "location": self.location, "backtrace": self.backtrace }),
+        );
+    }
+}
+
+impl fmt::Display for PanicReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "panicked at {}: {}", location, self.message),
+            None => write!(f, "panicked: {}", self.message),
+        }
+    }
+}
+
+impl From<PanicReport> for Error {
+    fn from(report: PanicReport) -> Self {
+        Error::internal(report)
+    }
+}
+
+/// Install the panic hook capturing reports for guarded code. Panics
+/// outside of guards still go to the previous hook. Idempotent.
+pub fn install_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if GUARDS.with(Cell::get) == 0 {
+                return previous(info);
+            }
+            let report = PanicReport {
+                message: payload_message(info.payload()),
+                location: info.location().map(|l| l.to_string()),
+                backtrace: Backtrace::force_capture().to_string(),
+            };
+            LAST_REPORT.with(|last| *last.borrow_mut() = Some(report));
+        }));
+    });
+}
+
+/// Run `f`, returning a report instead of unwinding if it panics
+pub fn catch<R>(f: impl FnOnce() -> R) -> Result<R, PanicReport> {
+    install_hook();
+    GUARDS.with(|guards| guards.set(guards.get() + 1));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    GUARDS.with(|guards| guards.set(guards.get() - 1));
+    result.map_err(|payload| {
+        LAST_REPORT.with(|last| last.borrow_mut().take()).unwrap_or_else(|| PanicReport {
+            message: payload_message(payload.as_ref()),
+            location: None,
+            backtrace: String::new(),
+        })
+    })
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string())
+}
+
+/// Drive `future`, returning a report instead of unwinding if any poll of
+/// it panics
+pub async fn catch_async<F: Future>(future: F) -> Result<F::Output, PanicReport> {
+    CatchPanic { future: Box::pin(future) }.await
+}
+
+struct CatchPanic<F> {
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for CatchPanic<F> {
+    type Output = Result<F::Output, PanicReport>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.future.as_mut();
+        match catch(move || future.poll(cx)) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(report) => Poll::Ready(Err(report)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    #[test]
+    fn test_catch() {
+        assert_eq!(catch(|| 42), Ok(42));
+
+        let report = catch(|| -> u32 { panic!("bad frame length {}", 7) }).unwrap_err();
+        assert_eq!(report.message, "bad frame length 7");
+        assert!(report.location.as_deref().is_some_and(|l| l.starts_with("src/panics.rs")));
+        assert!(!report.backtrace.is_empty());
+
+        let mut flow = HTTPFlow::new(HTTPRequest::new(
+            "GET".to_string(),
+            "http".to_string(),
+            "example.com".to_string(),
+            80,
+            "/".to_string(),
+        ));
+        report.apply(&mut flow);
+        assert_eq!(flow.flow.error.as_ref().unwrap().msg, "Internal error: bad frame length 7");
+        assert!(flow.flow.metadata.contains_key(METADATA_KEY));
+    }
+
+    #[tokio::test]
+    async fn test_catch_async() {
+        let report = catch_async(async {
+            tokio::task::yield_now().await;
+            let headers: Vec<u8> = Vec::new();
+            headers[3]
+        })
+        .await
+        .unwrap_err();
+        assert!(report.message.contains("index out of bounds"));
+
+        // A panicking task does not affect others
+        assert_eq!(catch_async(async { "still running" }).await, Ok("still running"));
+    }
+}
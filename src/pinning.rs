@@ -0,0 +1,151 @@
+//! Certificate pinning test mode.
+//!
+//! Normally the proxy presents certificates signed by its own CA, which test
+//! devices are set up to trust. In pinning test mode, clients connecting to
+//! selected hosts instead get a certificate they must reject: a self-signed
+//! one, one for a different host, or one signed by a CA nobody trusts. An app
+//! that validates or pins certificates correctly aborts the handshake; if a
+//! flow for such a host shows up anyway, the client accepted the certificate
+//! and the flow is marked as a failed pinning test.
+//!
+//! The certificate is chosen during the TLS handshake, before any request is
+//! seen, so rule filters are evaluated against the host and port only.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::{Error, Result};
+
+/// Flow metadata key marking flows whose client accepted a test certificate
+pub const METADATA_KEY: &str = "pinning_test";
+
+/// Host name used for wrong-host certificates
+pub const WRONG_HOST: &str = "pinning-test.invalid";
+
+/// Kind of invalid certificate presented to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PinningTestMode {
+    /// Certificate for the right host, signed by itself
+    SelfSigned,
+    /// Certificate signed by the proxy CA, but for another host
+    WrongHost,
+    /// Certificate for the right host, signed by a throwaway CA
+    UntrustedCa,
+}
+
+impl std::fmt::Display for PinningTestMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PinningTestMode::SelfSigned => "self-signed",
+            PinningTestMode::WrongHost => "wrong-host",
+            PinningTestMode::UntrustedCa => "untrusted-ca",
+        })
+    }
+}
+
+/// A pinning test rule as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinningRule {
+    /// Filter expression selecting hosts, e.g. `~d api.example.com`
+    pub filter: String,
+    pub mode: PinningTestMode,
+}
+
+/// The configured pinning test rules, checked in order
+#[derive(Debug, Clone, Default)]
+pub struct PinningTests {
+    rules: Vec<(PinningRule, Filter)>,
+}
+
+impl PinningTests {
+    pub fn from_rules(rules: &[PinningRule]) -> Result<Self> {
+        let mut tests = Self::default();
+        for rule in rules {
+            if rule.filter.trim().is_empty() {
+                return Err(Error::invalid_request("Pinning test rules need a filter"));
+            }
+            let filter = Filter::new(format!("pinning-{}", rule.mode), rule.filter.clone())?;
+            tests.rules.push((rule.clone(), filter));
+        }
+        Ok(tests)
+    }
+
+    pub fn rules(&self) -> Vec<PinningRule> {
+        self.rules.iter().map(|(rule, _)| rule.clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Certificate to present to clients connecting to `host:port`, if the
+    /// host is under test
+    pub fn mode_for(&self, host: &str, port: u16) -> Option<PinningTestMode> {
+        self.rules.iter().find(|(_, filter)| filter.matches_address(host, port)).map(|(rule, _)| rule.mode)
+    }
+
+    /// Mark a flow whose client completed the handshake with a test
+    /// certificate. Returns the mode the client accepted.
+    pub fn check(&self, flow: &mut HTTPFlow) -> Option<PinningTestMode> {
+        if flow.request.scheme != "https" || flow.flow.metadata.contains_key(METADATA_KEY) {
+            return None;
+        }
+        let mode = self.mode_for(&flow.request.host, flow.request.port)?;
+        warn!(
+            "Pinning test FAILED: client accepted a {} certificate for {}",
+            mode, flow.request.host
+        );
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), json!({ "mode": mode, "accepted": true }));
+        Some(mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    fn tests() -> PinningTests {
+        PinningTests::from_rules(&[
+            PinningRule { filter: "~d api.bank.example".to_string(), mode: PinningTestMode::SelfSigned },
+            PinningRule { filter: "~d bank.example".to_string(), mode: PinningTestMode::WrongHost },
+        ])
+        .unwrap()
+    }
+
+    fn flow(scheme: &str, host: &str) -> HTTPFlow {
+        HTTPFlow::new(HTTPRequest::new(
+            "GET".to_string(),
+            scheme.to_string(),
+            host.to_string(),
+            443,
+            "/".to_string(),
+        ))
+    }
+
+    #[test]
+    fn test_mode_for_host() {
+        let tests = tests();
+        assert_eq!(tests.mode_for("api.bank.example", 443), Some(PinningTestMode::SelfSigned));
+        assert_eq!(tests.mode_for("www.bank.example", 443), Some(PinningTestMode::WrongHost));
+        assert_eq!(tests.mode_for("example.com", 443), None);
+        assert!(PinningTests::from_rules(&[PinningRule { filter: " ".to_string(), mode: PinningTestMode::UntrustedCa }]).is_err());
+    }
+
+    #[test]
+    fn test_accepted_certificate_is_flagged() {
+        let tests = tests();
+        let mut accepted = flow("https", "api.bank.example");
+        assert_eq!(tests.check(&mut accepted), Some(PinningTestMode::SelfSigned));
+        assert_eq!(accepted.flow.metadata[METADATA_KEY]["mode"], "self-signed");
+        // Flagged only once
+        assert_eq!(tests.check(&mut accepted), None);
+
+        assert_eq!(tests.check(&mut flow("http", "api.bank.example")), None);
+        assert_eq!(tests.check(&mut flow("https", "example.com")), None);
+    }
+}
@@ -0,0 +1,252 @@
+//! Self-profiling of the running proxy.
+//!
+//! With `profiling` set in the config, `/debug/pprof/profile` samples the
+//! proxy's own threads for a while and returns the CPU profile, either as
+//! a gzipped pprof protobuf for `go tool pprof` or as a flamegraph SVG, and
+//! `/debug/pprof/heap` returns a snapshot of the process memory and the
+//! flow store. CPU profiles need a build with the `profiling` feature. The
+//! endpoints are only served to clients presenting the configured
+//! `auth_token`, since profiles reveal what the proxy is working on.
+
+use serde::Serialize;
+
+use crate::gauges::GaugeSnapshot;
+use crate::{Error, Result};
+
+/// Profiling time when a request does not name one
+pub const DEFAULT_SECONDS: u64 = 30;
+/// Longest profiling time a request may ask for
+pub const MAX_SECONDS: u64 = 300;
+/// Samples per second when a request does not name a rate
+pub const DEFAULT_FREQUENCY: i32 = 99;
+
+/// Output of a CPU profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileFormat {
+    /// Gzipped pprof protobuf
+    Pprof,
+    /// Flamegraph SVG
+    Flamegraph,
+}
+
+impl ProfileFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ProfileFormat::Pprof => "application/octet-stream",
+            ProfileFormat::Flamegraph => "image/svg+xml",
+        }
+    }
+}
+
+/// What a CPU profile request asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileRequest {
+    pub seconds: u64,
+    pub frequency: i32,
+    pub format: ProfileFormat,
+}
+
+impl ProfileRequest {
+    /// Read `seconds`, `frequency` and `format` query parameters, with the
+    /// defaults for those left out
+    pub fn from_query(seconds: Option<u64>, frequency: Option<i32>, format: Option<&str>) -> Result<Self> {
+        let seconds = seconds.unwrap_or(DEFAULT_SECONDS);
+        if seconds == 0 || seconds > MAX_SECONDS {
+            return Err(Error::invalid_request(format!("seconds must be between 1 and {}", MAX_SECONDS)));
+        }
+        let frequency = frequency.unwrap_or(DEFAULT_FREQUENCY);
+        if !(1..=1000).contains(&frequency) {
+            return Err(Error::invalid_request("frequency must be between 1 and 1000"));
+        }
+        let format = match format.unwrap_or("pprof") {
+            "pprof" | "proto" => ProfileFormat::Pprof,
+            "flamegraph" | "svg" => ProfileFormat::Flamegraph,
+            other => return Err(Error::invalid_request(format!("Unknown profile format: {}", other))),
+        };
+        Ok(Self { seconds, frequency, format })
+    }
+}
+
+/// Sample the proxy for the requested time and render the profile. Blocks
+/// the calling thread meanwhile; only one profile is taken at a time.
+#[cfg(feature = "profiling")]
+pub fn cpu_profile(request: &ProfileRequest) -> Result<Vec<u8>> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(Error::Proxy("A profile is already being taken".to_string()));
+    }
+    let result = sample(request);
+    RUNNING.store(false, Ordering::SeqCst);
+    result
+}
+
+#[cfg(feature = "profiling")]
+fn profiling_failed(e: pprof::Error) -> Error {
+    Error::Proxy(format!("Profiling failed: {}", e))
+}
+
+#[cfg(feature = "profiling")]
+fn sample(request: &ProfileRequest) -> Result<Vec<u8>> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(request.frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(profiling_failed)?;
+    std::thread::sleep(std::time::Duration::from_secs(request.seconds));
+    let report = guard.report().build().map_err(profiling_failed)?;
+    render(&report, request.format)
+}
+
+/// Render a report in `format`. A report without samples is an error
+/// rather than an empty profile: it usually means SIGPROF never reached
+/// the proxy's threads.
+#[cfg(feature = "profiling")]
+fn render(report: &pprof::Report, format: ProfileFormat) -> Result<Vec<u8>> {
+    use pprof::protos::Message;
+    use std::io::Write;
+
+    if report.data.is_empty() {
+        return Err(Error::Proxy("Profiling failed: no samples were taken".to_string()));
+    }
+    match format {
+        ProfileFormat::Flamegraph => {
+            let mut svg = Vec::new();
+            report.flamegraph(&mut svg).map_err(profiling_failed)?;
+            Ok(svg)
+        }
+        ProfileFormat::Pprof => {
+            let profile = report.pprof().map_err(profiling_failed)?;
+            let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            gzip.write_all(&profile.encode_to_vec())?;
+            Ok(gzip.finish()?)
+        }
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn cpu_profile(_request: &ProfileRequest) -> Result<Vec<u8>> {
+    Err(Error::Proxy("CPU profiles need a build with the profiling feature".to_string()))
+}
+
+/// Memory of the proxy process, in bytes, as far as the platform tells
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProcessMemory {
+    pub resident: Option<u64>,
+    pub peak_resident: Option<u64>,
+    pub virtual_size: Option<u64>,
+    /// Heap and other private data
+    pub data: Option<u64>,
+}
+
+impl ProcessMemory {
+    pub fn current() -> Self {
+        std::fs::read_to_string("/proc/self/status").map(|status| Self::parse(&status)).unwrap_or_default()
+    }
+
+    /// Read the `Vm*` lines of a `/proc/<pid>/status` file
+    fn parse(status: &str) -> Self {
+        let field = |name: &str| {
+            status.lines().find_map(|line| {
+                let value = line.strip_prefix(name)?.strip_prefix(':')?.trim();
+                let kilobytes = value.strip_suffix("kB").unwrap_or(value).trim();
+                kilobytes.parse::<u64>().ok().map(|kb| kb * 1024)
+            })
+        };
+        Self {
+            resident: field("VmRSS"),
+            peak_resident: field("VmHWM"),
+            virtual_size: field("VmSize"),
+            data: field("VmData"),
+        }
+    }
+}
+
+/// Where the memory of the proxy goes, at one point in time
+#[derive(Debug, Clone, Serialize)]
+pub struct HeapSnapshot {
+    pub timestamp: f64,
+    pub process: ProcessMemory,
+    pub stored_flows: usize,
+    /// Bodies of stored flows
+    pub stored_bytes: u64,
+    pub evicted_flows: u64,
+}
+
+impl HeapSnapshot {
+    pub fn new(gauges: &GaugeSnapshot) -> Self {
+        Self {
+            timestamp: crate::clock::now(),
+            process: ProcessMemory::current(),
+            stored_flows: gauges.stored_flows,
+            stored_bytes: gauges.stored_bytes,
+            evicted_flows: gauges.evicted_flows,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_request() {
+        let request = ProfileRequest::from_query(None, None, None).unwrap();
+        assert_eq!((request.seconds, request.frequency, request.format), (DEFAULT_SECONDS, DEFAULT_FREQUENCY, ProfileFormat::Pprof));
+        let request = ProfileRequest::from_query(Some(5), Some(250), Some("flamegraph")).unwrap();
+        assert_eq!((request.seconds, request.format.content_type()), (5, "image/svg+xml"));
+
+        assert!(ProfileRequest::from_query(Some(0), None, None).is_err());
+        assert!(ProfileRequest::from_query(Some(MAX_SECONDS + 1), None, None).is_err());
+        assert!(ProfileRequest::from_query(None, Some(0), None).is_err());
+        assert!(ProfileRequest::from_query(None, None, Some("text")).is_err());
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn test_render() {
+        let mut report = pprof::Report { data: std::collections::HashMap::new(), timing: Default::default() };
+        assert!(render(&report, ProfileFormat::Flamegraph).is_err());
+
+        let symbol = pprof::Symbol { name: Some(b"handle_connection".to_vec()), addr: None, lineno: None, filename: None };
+        let frames = pprof::Frames {
+            frames: vec![vec![symbol]],
+            thread_name: "worker".to_string(),
+            thread_id: 1,
+            sample_timestamp: std::time::SystemTime::now(),
+        };
+        report.data.insert(frames, 3);
+        let svg = render(&report, ProfileFormat::Flamegraph).unwrap();
+        assert!(String::from_utf8_lossy(&svg).contains("<svg"));
+        assert_eq!(render(&report, ProfileFormat::Pprof).unwrap()[..2], [0x1f, 0x8b]);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    #[ignore = "needs SIGPROF delivered to the test threads, which sandboxes and some CI runners prevent"]
+    fn test_cpu_profile() {
+        let request = ProfileRequest::from_query(Some(1), Some(200), Some("flamegraph")).unwrap();
+        // Something to sample, as flamegraphs of idle threads are empty
+        let until = std::time::Instant::now() + std::time::Duration::from_millis(1200);
+        let busy = std::thread::spawn(move || while std::time::Instant::now() < until {});
+        let svg = cpu_profile(&request).unwrap();
+        busy.join().unwrap();
+        assert!(String::from_utf8_lossy(&svg).contains("<svg"));
+
+        let request = ProfileRequest::from_query(Some(1), None, None).unwrap();
+        let profile = cpu_profile(&request).unwrap();
+        assert_eq!(profile[..2], [0x1f, 0x8b]);
+    }
+
+    #[test]
+    fn test_process_memory() {
+        let status = "Name:\tmitmproxy-rs\nVmPeak:\t  300000 kB\nVmSize:\t  200000 kB\nVmHWM:\t   40000 kB\nVmRSS:\t   30000 kB\nVmData:\t   25000 kB\n";
+        let memory = ProcessMemory::parse(status);
+        assert_eq!(memory.resident, Some(30000 * 1024));
+        assert_eq!(memory.peak_resident, Some(40000 * 1024));
+        assert_eq!(memory.virtual_size, Some(200000 * 1024));
+        assert_eq!(memory.data, Some(25000 * 1024));
+        assert_eq!(ProcessMemory::parse("Name:\tx\n"), ProcessMemory::default());
+    }
+}
@@ -162,6 +162,23 @@ impl Command for Log {
     }
 }
 
+/// Save input a parser rejected to the fuzz corpus
+#[derive(Debug, Clone)]
+pub struct CaptureMalformed {
+    pub parser: crate::corpus::Parser,
+    pub data: Vec<u8>,
+}
+
+impl Command for CaptureMalformed {
+    fn command_name(&self) -> &'static str {
+        "CaptureMalformed"
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
 // TLS-related data structures
 /// TLS client hello data
 #[derive(Debug, Clone)]
@@ -170,6 +187,8 @@ pub struct ClientHelloData {
     pub alpn_protocols: Vec<String>,
     pub ignore_connection: bool,
     pub establish_server_tls_first: bool,
+    /// The ClientHello is encrypted (ECH or ESNI); `sni` is the public name
+    pub ech: bool,
 }
 
 /// TLS connection data
@@ -338,7 +357,7 @@ impl StartHook for TlsFailedServerHook {
 /// WebSocket connection start hook
 #[derive(Debug)]
 pub struct WebsocketStartHook {
-    pub flow: crate::flow::Flow,
+    pub flow: crate::flow::HTTPFlow,
 }
 
 impl Command for WebsocketStartHook {
@@ -360,7 +379,7 @@ impl StartHook for WebsocketStartHook {
 /// WebSocket message hook
 #[derive(Debug)]
 pub struct WebsocketMessageHook {
-    pub flow: crate::flow::Flow,
+    pub flow: crate::flow::HTTPFlow,
 }
 
 impl Command for WebsocketMessageHook {
@@ -382,7 +401,7 @@ impl StartHook for WebsocketMessageHook {
 /// WebSocket connection end hook
 #[derive(Debug)]
 pub struct WebsocketEndHook {
-    pub flow: crate::flow::Flow,
+    pub flow: crate::flow::HTTPFlow,
 }
 
 impl Command for WebsocketEndHook {
@@ -400,3 +419,192 @@ impl StartHook for WebsocketEndHook {
         "websocket_end"
     }
 }
+
+// SSE Hook Commands
+/// Events arrived on an open `text/event-stream` response
+#[derive(Debug)]
+pub struct SseEventHook {
+    pub flow: crate::flow::HTTPFlow,
+}
+
+impl Command for SseEventHook {
+    fn command_name(&self) -> &'static str {
+        "SseEventHook"
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl StartHook for SseEventHook {
+    fn hook_name(&self) -> &'static str {
+        "sse_event"
+    }
+}
+
+// Intercept Hook Commands
+/// A flow matched the `intercept` rule and is held. The hook completes
+/// once the flow is resumed or killed, with the flow as it was left.
+#[derive(Debug)]
+pub struct InterceptHook {
+    pub flow: crate::flow::HTTPFlow,
+}
+
+impl Command for InterceptHook {
+    fn command_name(&self) -> &'static str {
+        "InterceptHook"
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl StartHook for InterceptHook {
+    fn hook_name(&self) -> &'static str {
+        "intercept"
+    }
+
+    fn is_blocking_hook(&self) -> bool {
+        true
+    }
+}
+
+// TCP Hook Commands
+/// TCP connection start hook
+#[derive(Debug)]
+pub struct TcpStartHook {
+    pub flow: crate::flow::HTTPFlow,
+}
+
+impl Command for TcpStartHook {
+    fn command_name(&self) -> &'static str {
+        "TcpStartHook"
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl StartHook for TcpStartHook {
+    fn hook_name(&self) -> &'static str {
+        "tcp_start"
+    }
+}
+
+/// TCP message hook
+#[derive(Debug)]
+pub struct TcpMessageHook {
+    pub flow: crate::flow::HTTPFlow,
+}
+
+impl Command for TcpMessageHook {
+    fn command_name(&self) -> &'static str {
+        "TcpMessageHook"
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl StartHook for TcpMessageHook {
+    fn hook_name(&self) -> &'static str {
+        "tcp_message"
+    }
+}
+
+/// TCP connection end hook
+#[derive(Debug)]
+pub struct TcpEndHook {
+    pub flow: crate::flow::HTTPFlow,
+}
+
+impl Command for TcpEndHook {
+    fn command_name(&self) -> &'static str {
+        "TcpEndHook"
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl StartHook for TcpEndHook {
+    fn hook_name(&self) -> &'static str {
+        "tcp_end"
+    }
+}
+
+// DNS Hook Commands
+/// DNS query hook; an answer set here is sent without asking the server
+#[derive(Debug)]
+pub struct DnsRequestHook {
+    pub flow: crate::flow::HTTPFlow,
+}
+
+impl Command for DnsRequestHook {
+    fn command_name(&self) -> &'static str {
+        "DnsRequestHook"
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl StartHook for DnsRequestHook {
+    fn hook_name(&self) -> &'static str {
+        "dns_request"
+    }
+}
+
+/// DNS answer hook, run before the answer is sent to the client
+#[derive(Debug)]
+pub struct DnsResponseHook {
+    pub flow: crate::flow::HTTPFlow,
+}
+
+impl Command for DnsResponseHook {
+    fn command_name(&self) -> &'static str {
+        "DnsResponseHook"
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl StartHook for DnsResponseHook {
+    fn hook_name(&self) -> &'static str {
+        "dns_response"
+    }
+}
+
+/// DNS error hook, run when a query cannot be answered
+#[derive(Debug)]
+pub struct DnsErrorHook {
+    pub flow: crate::flow::HTTPFlow,
+}
+
+impl Command for DnsErrorHook {
+    fn command_name(&self) -> &'static str {
+        "DnsErrorHook"
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl StartHook for DnsErrorHook {
+    fn hook_name(&self) -> &'static str {
+        "dns_error"
+    }
+}
@@ -1,9 +1,21 @@
 //! Context that layers operate within
 //! This mirrors the Python Context class in mitmproxy/proxy/context.py
 
-use crate::config::Config;
+use crate::allowlist::Allowlist;
+use crate::blocklist::BlockList;
+use crate::certs::CertificateAuthority;
+use crate::clock::{Clock, IdGenerator};
+use crate::config::{Config, EchMode, ReverseTarget, TlsBackend};
 use crate::connection::{Client, Server, Connection};
-use std::sync::Arc;
+use crate::intercept::Intercept;
+use crate::maplocal::MapLocal;
+use crate::mapremote::MapRemote;
+use crate::pinning::PinningTests;
+use crate::rewrites::Rewrites;
+use crate::serverreplay::ServerReplay;
+use crate::tls_sessions::TlsSessionCache;
+use crate::websocket::WebSocketCloseRule;
+use std::sync::{Arc, RwLock};
 
 /// Context provided to each layer containing connection and configuration state.
 /// This mirrors the Python Context class behavior.
@@ -17,6 +29,10 @@ pub struct Context {
     pub options: ContextOptions,
     /// Stack of layers for debugging and context tracking
     pub layers: Vec<LayerRef>,
+    /// Time read by the layers for flow and message timestamps
+    pub clock: Arc<dyn Clock>,
+    /// Flow ids and random bytes used by the layers
+    pub ids: Arc<dyn IdGenerator>,
 }
 
 /// Options available to the context - mirrors Python options
@@ -36,12 +52,62 @@ pub struct ContextOptions {
     pub connection_strategy: String,
     /// Keep host header in reverse proxy mode
     pub keep_host_header: bool,
+    /// Server all requests are forwarded to in reverse proxy mode
+    pub reverse_target: Option<ReverseTarget>,
     /// Enable WebSocket support
     pub websocket: bool,
     /// Enable raw TCP mode
     pub rawtcp: bool,
     /// Normalize outbound HTTP/2 headers
     pub normalize_outbound_headers: bool,
+    /// Upstream servers that speak HTTP/2 without TLS (prior knowledge)
+    pub h2c_upstream: Vec<String>,
+    /// Idle seconds before a PING is sent on upstream HTTP/2 connections;
+    /// 0 disables the keepalive
+    pub http2_ping_keepalive: u64,
+    /// Destinations tunneled without TLS interception
+    pub ignore_hosts: Vec<String>,
+    /// The only destinations intercepted, if set
+    pub allow_hosts: Vec<String>,
+    /// Answer pipelined HTTP/1.1 requests with 400 instead of processing them in order
+    pub reject_pipelining: bool,
+    /// Answer requests whose response cannot be parsed with a 502
+    pub bad_gateway_on_invalid_response: bool,
+    /// Pass through or reject clients using Encrypted Client Hello
+    pub tls_ech: EchMode,
+    /// Library intercepting TLS
+    pub tls_backend: TlsBackend,
+    /// Close or rewrite the close frames of matching WebSocket connections
+    pub websocket_close_rules: Vec<WebSocketCloseRule>,
+    /// Sessions offered again on upstream TLS connections; none if the
+    /// cache is disabled
+    pub tls_sessions: Option<Arc<TlsSessionCache>>,
+    /// Host whose requests are answered with the CA certificate
+    /// installation page; none if onboarding is disabled
+    pub onboarding_host: Option<String>,
+    /// CA whose certificate the onboarding page serves
+    pub ca: Option<Arc<CertificateAuthority>>,
+    /// Requests answered with local files; none if no rules are set
+    pub map_local: Option<Arc<MapLocal>>,
+    /// Rewriting of request URLs; none if no rules are set
+    pub map_remote: Option<Arc<MapRemote>>,
+    /// Requests answered with a fixed status code; none if no rules are set
+    pub block_list: Option<Arc<BlockList>>,
+    /// Requests answered with recorded responses; none if no flows are
+    /// replayed
+    pub server_replay: Option<Arc<ServerReplay>>,
+    /// Destinations requests may be forwarded to, changed at runtime for
+    /// open connections too
+    pub allowlist: Option<Arc<RwLock<Allowlist>>>,
+    /// Requests and responses held until resumed or killed; none if the
+    /// `intercept` option is unset
+    pub intercept: Option<Arc<Intercept>>,
+    /// Hosts served invalid certificates to test client pinning; none if
+    /// no rules are set
+    pub pinning_tests: Option<Arc<PinningTests>>,
+    /// Header, body, cookie and credential rewriting of complete requests
+    /// and responses, changed at runtime for open connections too
+    pub rewrites: Option<Arc<Rewrites>>,
 }
 
 /// Reference to a layer in the stack
@@ -61,26 +127,68 @@ impl Default for ContextOptions {
             validate_inbound_headers: true,
             connection_strategy: "eager".to_string(),
             keep_host_header: false,
+            reverse_target: None,
             websocket: true,
             rawtcp: false,
             normalize_outbound_headers: false,
+            h2c_upstream: Vec::new(),
+            http2_ping_keepalive: 58,
+            ignore_hosts: Vec::new(),
+            allow_hosts: Vec::new(),
+            reject_pipelining: false,
+            bad_gateway_on_invalid_response: false,
+            tls_ech: EchMode::default(),
+            tls_backend: TlsBackend::default(),
+            websocket_close_rules: Vec::new(),
+            tls_sessions: None,
+            onboarding_host: None,
+            ca: None,
+            map_local: None,
+            map_remote: None,
+            block_list: None,
+            server_replay: None,
+            allowlist: None,
+            intercept: None,
+            pinning_tests: None,
+            rewrites: None,
         }
     }
 }
 
 impl From<Arc<Config>> for ContextOptions {
-    fn from(_config: Arc<Config>) -> Self {
+    fn from(config: Arc<Config>) -> Self {
         ContextOptions {
-            proxy_debug: false, // TODO: read from config
+            proxy_debug: config.proxy_debug,
             body_size_limit: None,
             stream_large_bodies: None,
             store_streamed_bodies: true,
             validate_inbound_headers: true,
             connection_strategy: "eager".to_string(),
-            keep_host_header: false,
+            keep_host_header: config.keep_host_header,
+            reverse_target: config.reverse_target().ok().flatten(),
             websocket: true,
             rawtcp: false,
             normalize_outbound_headers: false,
+            h2c_upstream: config.h2c_upstream.clone(),
+            http2_ping_keepalive: config.http2_ping_keepalive,
+            ignore_hosts: config.ignore_hosts.clone(),
+            allow_hosts: config.allow_hosts.clone(),
+            reject_pipelining: config.reject_pipelining,
+            bad_gateway_on_invalid_response: config.bad_gateway_on_invalid_response,
+            tls_ech: config.tls_ech,
+            tls_backend: config.tls_backend,
+            websocket_close_rules: config.websocket_close_rules.clone(),
+            tls_sessions: None,
+            onboarding_host: config.onboarding.then(|| config.onboarding_host.clone()),
+            ca: None,
+            map_local: None,
+            map_remote: None,
+            block_list: None,
+            server_replay: None,
+            allowlist: None,
+            intercept: None,
+            pinning_tests: None,
+            rewrites: None,
         }
     }
 }
@@ -96,6 +204,8 @@ impl Default for Context {
             server: None,
             options: ContextOptions::default(),
             layers: Vec::new(),
+            clock: crate::clock::clock(),
+            ids: crate::clock::ids(),
         }
     }
 }
@@ -108,9 +218,19 @@ impl Context {
             server: None,
             options: options.into(),
             layers: Vec::new(),
+            clock: crate::clock::clock(),
+            ids: crate::clock::ids(),
         }
     }
 
+    /// Read time from `clock` and draw ids from `ids` instead of the
+    /// process-wide sources
+    pub fn with_sources(mut self, clock: Arc<dyn Clock>, ids: Arc<dyn IdGenerator>) -> Self {
+        self.clock = clock;
+        self.ids = ids;
+        self
+    }
+
     /// Set the server connection
     pub fn with_server(mut self, server: Server) -> Self {
         self.server = Some(server);
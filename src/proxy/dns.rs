@@ -0,0 +1,299 @@
+//! DNS interception over UDP.
+//!
+//! [`DnsServer`] receives queries on a UDP socket and runs each through a
+//! [`DnsLayer`], carrying out its commands: hooks run the proxy's DNS
+//! hooks, queries are forwarded to the configured upstream server and
+//! answers are sent back to the client. Without an upstream server,
+//! address queries the hooks leave unanswered are resolved by the proxy
+//! itself, like mitmproxy's `dns_resolver` addon does. Flows are recorded
+//! once answered or failed.
+
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+use crate::connection::{Client, Connection, Server, TransportProtocol};
+use crate::flow::{dns_class, dns_response_code, dns_type, DNSResourceRecord, HTTPFlow};
+use crate::listeners;
+use crate::proxy::commands::{Command, DnsErrorHook, DnsRequestHook, DnsResponseHook, Log, LogLevel, SendData};
+use crate::proxy::events::{ConnectionClosed, DataReceived, HookCompleted, Start};
+use crate::proxy::layers::DnsLayer;
+use crate::proxy::{AnyEvent, Context, Layer, ProxyServer};
+use crate::{Error, Result};
+
+/// How long to wait for the upstream server's answer
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TTL of answers resolved by the proxy
+const RESOLVED_TTL: u32 = 60;
+
+/// Largest DNS message over UDP
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Answers DNS queries on a UDP socket
+#[derive(Debug)]
+pub struct DnsServer {
+    proxy: Arc<ProxyServer>,
+    socket: UdpSocket,
+    /// Server queries are forwarded to; the proxy resolves them if unset
+    upstream: Option<SocketAddr>,
+}
+
+impl DnsServer {
+    /// Listen on `addr`. Must be called within the proxy runtime.
+    pub fn bind(proxy: Arc<ProxyServer>, addr: SocketAddr) -> Result<Self> {
+        let upstream = proxy
+            .config()
+            .dns_upstream
+            .as_deref()
+            .map(|spec| {
+                spec.parse::<SocketAddr>()
+                    .map_err(|e| Error::invalid_request(format!("Invalid DNS upstream {}: {}", spec, e)))
+            })
+            .transpose()?;
+        let socket = std::net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { proxy, socket: UdpSocket::from_std(socket)?, upstream })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Answer queries until the task is aborted
+    pub async fn run(self: Arc<Self>) {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buf).await {
+                Ok((len, peer)) => {
+                    let server = Arc::clone(&self);
+                    let data = buf[..len].to_vec();
+                    tokio::spawn(async move { server.handle_query(peer, data).await });
+                }
+                Err(e) => warn!("Error receiving DNS query: {}", e),
+            }
+        }
+    }
+
+    /// Run one query through a DNS layer until it is answered
+    async fn handle_query(&self, peer: SocketAddr, data: Vec<u8>) {
+        let mut client = Client::new(TransportProtocol::Udp);
+        client.connection.peername = Some(peer);
+        client.proxy_mode = Some(listeners::DNS.to_string());
+        let mut context = Context::new(client, Arc::clone(self.proxy.config()));
+        let mut server = Server::new(TransportProtocol::Udp);
+        server.address = self.upstream;
+        context.server = Some(server);
+
+        let client = context.client_conn().clone();
+        let mut layer = DnsLayer::new(context);
+        let mut events = VecDeque::from([
+            AnyEvent::Start(Start),
+            AnyEvent::DataReceived(DataReceived { connection: client.clone(), data }),
+        ]);
+        while let Some(event) = events.pop_front() {
+            let commands: Vec<Box<dyn Command>> = {
+                let mut generator = layer.handle_event(event);
+                std::iter::from_fn(|| generator.next_command()).collect()
+            };
+            for command in commands {
+                events.extend(self.execute(command, &client, peer).await);
+            }
+        }
+    }
+
+    /// Carry out a command of the layer, returning the event it results in
+    async fn execute(&self, command: Box<dyn Command>, client: &Connection, peer: SocketAddr) -> Option<AnyEvent> {
+        let completed = |command: Box<dyn Command>| Some(AnyEvent::HookCompleted(HookCompleted { command }));
+        let any = command.as_any();
+        if let Some(hook) = any.downcast_ref::<DnsRequestHook>() {
+            let mut flow = hook.flow.clone();
+            self.proxy.dns_request_hook(listeners::DNS, &mut flow).await;
+            if self.upstream.is_none() {
+                self.resolve(&mut flow).await;
+            }
+            completed(Box::new(DnsRequestHook { flow }))
+        } else if let Some(hook) = any.downcast_ref::<DnsResponseHook>() {
+            let mut flow = hook.flow.clone();
+            self.proxy.dns_response_hook(listeners::DNS, &mut flow).await;
+            self.proxy.record_flow(flow.clone()).await;
+            completed(Box::new(DnsResponseHook { flow }))
+        } else if let Some(hook) = any.downcast_ref::<DnsErrorHook>() {
+            self.proxy.record_flow(hook.flow.clone()).await;
+            None
+        } else if let Some(send) = any.downcast_ref::<SendData>() {
+            if send.connection == *client {
+                if let Err(e) = self.socket.send_to(&send.data, peer).await {
+                    debug!("Cannot send DNS answer to {}: {}", peer, e);
+                }
+                return None;
+            }
+            let event = match self.forward(&send.data).await {
+                Ok(data) => AnyEvent::DataReceived(DataReceived { connection: send.connection.clone(), data }),
+                Err(e) => {
+                    warn!("DNS query to upstream failed: {}", e);
+                    AnyEvent::ConnectionClosed(ConnectionClosed { connection: send.connection.clone() })
+                }
+            };
+            Some(event)
+        } else {
+            match any.downcast_ref::<Log>() {
+                Some(log) if matches!(log.level, LogLevel::Warning | LogLevel::Error) => warn!("{}", log.message),
+                Some(log) => debug!("{}", log.message),
+                None => {}
+            }
+            None
+        }
+    }
+
+    /// Send a query to the upstream server and wait for its answer
+    async fn forward(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let upstream = self.upstream.ok_or_else(|| Error::Proxy("No upstream DNS server".to_string()))?;
+        let local: SocketAddr = if upstream.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(upstream).await?;
+        socket.send(query).await?;
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        let len = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .map_err(|_| Error::Proxy(format!("No answer from {}", upstream)))??;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    /// Answer a query the hooks left unanswered: A and AAAA queries with
+    /// the addresses the proxy resolves the name to, anything else as not
+    /// implemented
+    async fn resolve(&self, flow: &mut HTTPFlow) {
+        let Some(dns) = flow.dns.as_mut().filter(|dns| dns.response.is_none()) else {
+            return;
+        };
+        let request = &dns.request;
+        let question = match request.questions.as_slice() {
+            [question] if request.op_code == 0 && question.class == dns_class::IN => question,
+            _ => {
+                dns.response = Some(request.fail(dns_response_code::NOTIMP));
+                return;
+            }
+        };
+        let want_v4 = match question.record_type {
+            dns_type::A => true,
+            dns_type::AAAA => false,
+            _ => {
+                dns.response = Some(request.fail(dns_response_code::NOTIMP));
+                return;
+            }
+        };
+
+        let response = match self.proxy.dns_cache().resolve(&question.name).await {
+            Ok(ips) => {
+                let answers = ips
+                    .into_iter()
+                    .filter(|ip| matches!(ip, IpAddr::V4(_)) == want_v4)
+                    .map(|ip| DNSResourceRecord::address(question.name.clone(), ip, RESOLVED_TTL))
+                    .collect();
+                request.succeed(answers)
+            }
+            Err(e) => {
+                debug!("Cannot resolve {}: {}", question.name, e);
+                request.fail(dns_response_code::NXDOMAIN)
+            }
+        };
+        dns.response = Some(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addons::Addon;
+    use crate::config::Config;
+    use crate::flow::{DNSMessage, DNSQuestion};
+    use crate::proxy::layers::dns::{pack, unpack};
+
+    /// Points every name at localhost
+    struct Sinkhole;
+
+    impl Addon for Sinkhole {
+        fn name(&self) -> &str {
+            "sinkhole"
+        }
+
+        fn dns_response(&self, flow: &mut HTTPFlow) {
+            let response = flow.dns.as_mut().unwrap().response.as_mut().unwrap();
+            for answer in &mut response.answers {
+                *answer = DNSResourceRecord::address(answer.name.clone(), [127, 0, 0, 1].into(), answer.ttl);
+            }
+        }
+    }
+
+    /// DNS server answering every A query with 192.0.2.1
+    async fn upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_MESSAGE_SIZE];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let query = unpack(&buf[..len]).unwrap();
+                let name = query.questions[0].name.clone();
+                let answer = query.succeed(vec![DNSResourceRecord::address(name, [192, 0, 2, 1].into(), 300)]);
+                socket.send_to(&pack(&answer), peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn ask(server: SocketAddr, name: &str, record_type: u16) -> DNSMessage {
+        let query = DNSMessage {
+            id: 7,
+            query: true,
+            recursion_desired: true,
+            questions: vec![DNSQuestion { name: name.to_string(), record_type, class: dns_class::IN }],
+            ..Default::default()
+        };
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&pack(&query), server).await.unwrap();
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf)).await.unwrap().unwrap();
+        unpack(&buf[..len]).unwrap()
+    }
+
+    async fn dns_server(config: Config) -> (Arc<ProxyServer>, SocketAddr) {
+        let proxy = Arc::new(ProxyServer::new(Arc::new(config)));
+        let server = Arc::new(DnsServer::bind(Arc::clone(&proxy), "127.0.0.1:0".parse().unwrap()).unwrap());
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        (proxy, addr)
+    }
+
+    #[tokio::test]
+    async fn test_forwards_and_rewrites_answers() {
+        let config = Config { dns_upstream: Some(upstream().await.to_string()), ..Default::default() };
+        let (proxy, addr) = dns_server(config).await;
+        proxy.add_addon(Arc::new(Sinkhole)).await.unwrap();
+
+        let answer = ask(addr, "example.com", dns_type::A).await;
+        assert_eq!((answer.id, answer.answers[0].data_text()), (7, "127.0.0.1".to_string()));
+
+        let flows = proxy.get_flows().await;
+        assert_eq!(flows.len(), 1);
+        let json = flows[0].to_json();
+        assert_eq!(json["type"], "dns");
+        assert_eq!(json["request"]["questions"][0]["name"], "example.com");
+        assert_eq!(json["response"]["answers"][0]["data"], "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_resolves_without_upstream() {
+        let (_proxy, addr) = dns_server(Config::default()).await;
+
+        let answer = ask(addr, "127.0.0.1", dns_type::A).await;
+        assert_eq!(answer.response_code, dns_response_code::NOERROR);
+        assert_eq!(answer.answers[0].data_text(), "127.0.0.1");
+        assert!(ask(addr, "127.0.0.1", dns_type::AAAA).await.answers.is_empty());
+        assert_eq!(ask(addr, "example.com", dns_type::MX).await.response_code, dns_response_code::NOTIMP);
+    }
+}
@@ -99,6 +99,23 @@ pub enum AnyEvent {
     WebSocketMessageInjected(WebSocketMessageInjected),
 }
 
+impl AnyEvent {
+    /// The wrapped event, for layers that match on concrete event types
+    pub fn into_event(self) -> Box<dyn Event> {
+        match self {
+            AnyEvent::Start(e) => Box::new(e),
+            AnyEvent::ConnectionEvent(e) => Box::new(e),
+            AnyEvent::DataReceived(e) => Box::new(e),
+            AnyEvent::ConnectionClosed(e) => Box::new(e),
+            AnyEvent::CommandCompleted(e) => Box::new(e),
+            AnyEvent::OpenConnectionCompleted(e) => Box::new(e),
+            AnyEvent::Wakeup(e) => Box::new(e),
+            AnyEvent::HookCompleted(e) => Box::new(e),
+            AnyEvent::WebSocketMessageInjected(e) => Box::new(e),
+        }
+    }
+}
+
 impl Event for AnyEvent {
     fn event_name(&self) -> &'static str {
         match self {
@@ -0,0 +1,552 @@
+//! HTTP/3 interception over QUIC.
+//!
+//! [`Http3Server`] accepts QUIC connections on a UDP socket from clients
+//! that negotiate ALPN `h3`, terminating TLS with a certificate the CA
+//! mints for the SNI of each connection. Requests are forwarded by
+//! [`Http3Client`] over HTTP/3 to the reverse proxy target, or to the SNI
+//! host when clients are directed to the proxy by DNS, and are recorded as
+//! flows with `HTTP/3.0` as their version.
+
+use bytes::{Buf, Bytes};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::certs::CertificateAuthority;
+use crate::client::HOP_BY_HOP;
+use crate::config::ReverseTarget;
+use crate::flow::{HTTPFlow, HTTPRequest, HTTPResponse};
+use crate::listeners;
+use crate::proxy::rustls_config::{provider, NoVerification};
+use crate::proxy::{Delivery, ProxyServer};
+use crate::shaping::ShapingPlan;
+use crate::{Error, Result};
+
+/// ALPN protocol identifier of HTTP/3
+pub const ALPN_H3: &[u8] = b"h3";
+
+/// Version recorded on HTTP/3 requests and responses
+pub const HTTP3_VERSION: &str = "HTTP/3.0";
+
+type ServerStream = h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Intercepts HTTP/3 traffic on a UDP socket
+#[derive(Debug)]
+pub struct Http3Server {
+    proxy: Arc<ProxyServer>,
+    endpoint: quinn::Endpoint,
+    client: Http3Client,
+    /// Where requests go in reverse proxy mode
+    reverse_target: Option<ReverseTarget>,
+}
+
+impl Http3Server {
+    /// Listen on `addr`, serving certificates signed by the proxy's CA
+    pub fn bind(proxy: Arc<ProxyServer>, addr: SocketAddr) -> Result<Self> {
+        let ca = proxy.ca().cloned().ok_or_else(|| Error::certificate("HTTP/3 needs a certificate authority"))?;
+        let reverse_target = proxy.config().reverse_target()?;
+        let resolver = CertResolver {
+            ca,
+            default_host: reverse_target.as_ref().map_or_else(|| "localhost".to_string(), |t| t.host.clone()),
+            keys: Default::default(),
+        };
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(Error::certificate)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        tls.alpn_protocols = vec![ALPN_H3.to_vec()];
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls).map_err(Error::certificate)?;
+        let endpoint = quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr)?;
+        let client = Http3Client::new(!proxy.config().ssl_insecure)?;
+        Ok(Self { proxy, endpoint, client, reverse_target })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// Accept connections until the endpoint is closed
+    pub async fn run(self: Arc<Self>) {
+        while let Some(incoming) = self.endpoint.accept().await {
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                let peer = incoming.remote_address();
+                if let Err(e) = server.handle_connection(incoming).await {
+                    debug!("HTTP/3 connection from {} closed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(self: Arc<Self>, incoming: quinn::Incoming) -> Result<()> {
+        let connection = incoming.await.map_err(h3_error)?;
+        let sni = connection
+            .handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .and_then(|data| data.server_name);
+        let mut h3_conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection))
+            .await
+            .map_err(h3_error)?;
+        while let Some(resolver) = h3_conn.accept().await.map_err(h3_error)? {
+            let server = Arc::clone(&self);
+            let sni = sni.clone();
+            tokio::spawn(async move {
+                match resolver.resolve_request().await {
+                    Ok((request, stream)) => server.handle_request(request, stream, sni.as_deref()).await,
+                    Err(e) => debug!("Cannot read HTTP/3 request: {}", e),
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn handle_request(&self, request: http::Request<()>, mut stream: ServerStream, sni: Option<&str>) {
+        let mut flow = HTTPFlow::new(self.flow_request(&request, sni));
+        match read_body(&mut stream).await {
+            Ok(body) if !body.is_empty() => flow.request.set_content(body),
+            Ok(_) => {}
+            Err(e) => {
+                debug!("Cannot read HTTP/3 request body: {}", e);
+                return;
+            }
+        }
+        flow.request.timestamp_end = Some(now());
+
+        // QUIC cannot be tunneled as it is, so hosts that would be passed
+        // through are forwarded untouched and not recorded
+        let intercept = !self.proxy.passes_through(&flow.request.host, flow.request.port);
+        if intercept && !self.proxy.request_hook(listeners::HTTP3, &mut flow).await {
+            // A request an adaptation service blocked is answered with its
+            // response; one killed or failed is dropped with its stream
+            if let Some(response) = flow.response.clone() {
+                if let Err(e) = send_response(&mut stream, &response, None).await {
+                    debug!("Cannot send HTTP/3 response: {}", e);
+                }
+            }
+            self.proxy.record_flow(flow).await;
+            return;
+        }
+        if self.proxy.check_destination(&flow.request.host, flow.request.port).is_err() {
+            let response = crate::allowlist::refusal(&flow.request.host, flow.request.port);
+            flow.response = Some(response.clone());
+            if let Err(e) = send_response(&mut stream, &response, None).await {
+                debug!("Cannot send HTTP/3 response: {}", e);
+            }
+            self.proxy.record_flow(flow).await;
+            return;
+        }
+        // Identical requests in flight at the same time share one upstream
+        // request if coalescing is enabled
+        let answer = self
+            .proxy
+            .fetch_coalesced(&mut flow, |request| async move {
+                let ips = self.proxy.dns_cache().resolve(&request.host).await?;
+                self.client.send(SocketAddr::new(ips[0], request.port), &request).await
+            })
+            .await;
+        let mut delivery = Delivery::default();
+        let response = match answer {
+            Ok(()) => {
+                if intercept {
+                    let Some(hooked) = self.proxy.response_hook(listeners::HTTP3, &mut flow).await else {
+                        self.proxy.record_flow(flow).await;
+                        return;
+                    };
+                    delivery = hooked;
+                }
+                delivery.compressed.or_else(|| flow.response.clone()).unwrap_or_else(|| bad_gateway("no response"))
+            }
+            Err(e) => {
+                warn!("HTTP/3 request to {} failed: {}", flow.request.url(), e);
+                flow.flow.set_error(e.to_string());
+                bad_gateway(&e.to_string())
+            }
+        };
+
+        if let Err(e) = send_response(&mut stream, &response, delivery.shaping.as_ref()).await {
+            debug!("Cannot send HTTP/3 response: {}", e);
+        }
+        if intercept {
+            self.proxy.record_flow(flow).await;
+        }
+    }
+
+    fn flow_request(&self, request: &http::Request<()>, sni: Option<&str>) -> HTTPRequest {
+        let uri = request.uri();
+        let (host, port) = match &self.reverse_target {
+            Some(target) => (target.host.clone(), target.port),
+            None => (uri.host().or(sni).unwrap_or_default().to_string(), uri.port_u16().unwrap_or(443)),
+        };
+        let path = uri.path_and_query().map_or("/", |p| p.as_str()).to_string();
+        let mut flow_request = HTTPRequest::new(request.method().to_string(), "https".to_string(), host, port, path);
+        flow_request.http_version = HTTP3_VERSION.to_string();
+        flow_request.headers = header_pairs(request.headers());
+        flow_request.timestamp_start = Some(now());
+        flow_request
+    }
+}
+
+/// Sends requests to servers over HTTP/3
+#[derive(Debug)]
+pub struct Http3Client {
+    endpoint: quinn::Endpoint,
+}
+
+impl Http3Client {
+    /// Client verifying server certificates unless `verify` is false
+    pub fn new(verify: bool) -> Result<Self> {
+        let builder = rustls::ClientConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(Error::certificate)?;
+        let mut tls = if verify {
+            let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider())))
+                .with_no_client_auth()
+        };
+        tls.alpn_protocols = vec![ALPN_H3.to_vec()];
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls).map_err(Error::certificate)?;
+
+        // A dual-stack socket reaches both address families, where available
+        let mut endpoint = quinn::Endpoint::client((Ipv6Addr::UNSPECIFIED, 0).into())
+            .or_else(|_| quinn::Endpoint::client((Ipv4Addr::UNSPECIFIED, 0).into()))?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        Ok(Self { endpoint })
+    }
+
+    /// Send `request` to the server at `addr`, which must present a
+    /// certificate for the request's host. Every request is sent on a
+    /// connection of its own.
+    pub async fn send(&self, addr: SocketAddr, request: &HTTPRequest) -> Result<HTTPResponse> {
+        let mut builder = http::Request::builder().method(request.method.as_str()).uri(request.url());
+        for (name, value) in &request.headers {
+            let name_lower = name.to_ascii_lowercase();
+            if name_lower != "host" && !HOP_BY_HOP.contains(&name_lower.as_str()) {
+                builder = builder.header(name.as_str(), value.as_str());
+            }
+        }
+        let outgoing = builder
+            .body(())
+            .map_err(|e| Error::invalid_request(format!("Cannot send request upstream: {}", e)))?;
+
+        let connection = self
+            .endpoint
+            .connect(addr, &request.host)
+            .map_err(h3_error)?
+            .await
+            .map_err(|e| Error::Proxy(format!("QUIC handshake with {} failed: {}", request.host, e)))?;
+        let timestamp_start = now();
+        let (mut driver, mut sender) = h3::client::new(h3_quinn::Connection::new(connection)).await.map_err(h3_error)?;
+        let driver = tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+        let exchange = async {
+            let mut stream = sender.send_request(outgoing).await?;
+            if let Some(content) = request.content.as_ref().filter(|c| !c.is_empty()) {
+                stream.send_data(Bytes::from(content.clone())).await?;
+            }
+            stream.finish().await?;
+            let head = stream.recv_response().await?;
+            let mut body = Vec::new();
+            while let Some(mut chunk) = stream.recv_data().await? {
+                body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+            }
+            Ok::<_, h3::error::StreamError>((head, body))
+        };
+        let result = exchange.await;
+        driver.abort();
+        let (head, body) = result.map_err(h3_error)?;
+
+        let status = head.status();
+        let mut response = HTTPResponse::new(status.as_u16(), status.canonical_reason().unwrap_or_default().to_string());
+        response.http_version = HTTP3_VERSION.to_string();
+        response.headers = header_pairs(head.headers());
+        response.set_content(body);
+        response.timestamp_start = Some(timestamp_start);
+        response.timestamp_end = Some(now());
+        Ok(response)
+    }
+}
+
+/// Serves certificates minted by the CA for the SNI of each connection
+#[derive(Debug)]
+struct CertResolver {
+    ca: Arc<CertificateAuthority>,
+    /// Host the certificate is minted for when the client sends no SNI
+    default_host: String,
+    keys: std::sync::Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    fn certified_key(&self, host: &str) -> Result<Arc<CertifiedKey>> {
+        if let Some(key) = self.keys.lock().unwrap().get(host) {
+            return Ok(key.clone());
+        }
+        // rustls asks for the certificate synchronously from the QUIC
+        // driver, so only this worker thread waits while it is minted
+        let handle = tokio::runtime::Handle::try_current().map_err(Error::internal)?;
+        if handle.runtime_flavor() != tokio::runtime::RuntimeFlavor::MultiThread {
+            return Err(Error::internal("HTTP/3 interception needs the multi-threaded runtime"));
+        }
+        let (cert, key) = tokio::task::block_in_place(|| handle.block_on(self.ca.get_cert_for_host(host)))?;
+
+        let chain = vec![CertificateDer::from(cert.to_der()?), CertificateDer::from(self.ca.ca_cert_der()?)];
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.private_key_to_pkcs8()?));
+        let signing_key = rustls::crypto::ring::sign::any_supported_type(&key).map_err(Error::certificate)?;
+        let certified = Arc::new(CertifiedKey::new(chain, signing_key));
+        self.keys.lock().unwrap().insert(host.to_string(), certified.clone());
+        Ok(certified)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello.server_name().unwrap_or(&self.default_host).to_string();
+        self.certified_key(&host)
+            .inspect_err(|e| warn!("Cannot create HTTP/3 certificate for {}: {}", host, e))
+            .ok()
+    }
+}
+
+async fn read_body(stream: &mut ServerStream) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await.map_err(h3_error)? {
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok(body)
+}
+
+/// Send a response, delivering its body in the chunks and at the pace of
+/// a shaping plan if there is one
+async fn send_response(stream: &mut ServerStream, response: &HTTPResponse, plan: Option<&ShapingPlan>) -> Result<()> {
+    let mut builder = http::Response::builder().status(response.status_code);
+    for (name, value) in &response.headers {
+        if !HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()) {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+    }
+    stream.send_response(builder.body(()).map_err(h3_error)?).await.map_err(h3_error)?;
+    let content = response.content.as_deref().unwrap_or_default();
+    let schedule = match plan {
+        Some(plan) => plan.schedule(content.len()),
+        None if content.is_empty() => Vec::new(),
+        None => vec![(0..content.len(), Duration::ZERO)],
+    };
+    for (range, delay) in schedule {
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        stream.send_data(Bytes::copy_from_slice(&content[range])).await.map_err(h3_error)?;
+    }
+    stream.finish().await.map_err(h3_error)
+}
+
+fn bad_gateway(reason: &str) -> HTTPResponse {
+    let mut response = HTTPResponse::new(502, "Bad Gateway".to_string());
+    response.http_version = HTTP3_VERSION.to_string();
+    response.set_header("content-type".to_string(), "text/plain".to_string());
+    response.set_content(format!("Cannot reach the server: {}\n", reason).into_bytes());
+    response
+}
+
+fn header_pairs(headers: &http::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+        .collect()
+}
+
+fn h3_error(e: impl std::fmt::Display) -> Error {
+    Error::Proxy(format!("HTTP/3: {}", e))
+}
+
+fn now() -> f64 {
+    crate::clock::now()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ProxyMode};
+    use crate::coalesce::{self, CoalescingOptions};
+    use crate::shaping::ShapingRule;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    /// HTTP/3 server answering every request with its method and path
+    async fn origin(ca: &CertificateAuthority) -> SocketAddr {
+        counting_origin(ca, Duration::ZERO).await.0
+    }
+
+    /// [`origin`] answering after `delay`, with the number of requests it
+    /// received
+    async fn counting_origin(ca: &CertificateAuthority, delay: Duration) -> (SocketAddr, Arc<AtomicUsize>) {
+        let (cert, key) = ca.get_cert_for_host("127.0.0.1").await.unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.private_key_to_pkcs8().unwrap()));
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![CertificateDer::from(cert.to_der().unwrap())], key)
+            .unwrap();
+        tls.alpn_protocols = vec![ALPN_H3.to_vec()];
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls).unwrap();
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint = quinn::Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            // The client sends every request on a connection of its own
+            while let Some(incoming) = endpoint.accept().await {
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let connection = incoming.await.unwrap();
+                    let mut h3_conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await.unwrap();
+                    while let Ok(Some(resolver)) = h3_conn.accept().await {
+                        let (request, mut stream) = resolver.resolve_request().await.unwrap();
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let body = read_body(&mut stream).await.unwrap();
+                        tokio::time::sleep(delay).await;
+                        let head = http::Response::builder().status(201).header("x-origin", "h3").body(()).unwrap();
+                        stream.send_response(head).await.unwrap();
+                        let answer = format!("{} {} {}", request.method(), request.uri().path(), String::from_utf8_lossy(&body));
+                        stream.send_data(Bytes::from(answer)).await.unwrap();
+                        stream.finish().await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, requests)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reverse_proxy_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let ca = Arc::new(CertificateAuthority::new(dir.path()).unwrap());
+        let origin = origin(&ca).await;
+
+        let config = Config {
+            mode: ProxyMode::Reverse,
+            upstream_server: Some(format!("https://127.0.0.1:{}", origin.port())),
+            ssl_insecure: true,
+            ..Default::default()
+        };
+        let proxy = Arc::new(ProxyServer::new(Arc::new(config)).with_ca(ca));
+        let server = Arc::new(Http3Server::bind(Arc::clone(&proxy), "127.0.0.1:0".parse().unwrap()).unwrap());
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let mut request = HTTPRequest::new("POST".to_string(), "https".to_string(), "example.test".to_string(), 443, "/echo?x=1".to_string());
+        request.set_content(b"ping".to_vec());
+        let response = Http3Client::new(false).unwrap().send(addr, &request).await.unwrap();
+        assert_eq!(response.status_code, 201);
+        assert_eq!(response.http_version, "HTTP/3.0");
+        assert_eq!(response.get_header("x-origin").map(String::as_str), Some("h3"));
+        assert_eq!(response.content.as_deref(), Some(&b"POST /echo ping"[..]));
+
+        let mut flows = Vec::new();
+        for _ in 0..100 {
+            flows = proxy.get_flows().await;
+            if !flows.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(flows.len(), 1);
+        let flow = &flows[0];
+        assert_eq!(flow.request.http_version, "HTTP/3.0");
+        assert_eq!((flow.request.host.as_str(), flow.request.path.as_str()), ("127.0.0.1", "/echo?x=1"));
+        assert_eq!(flow.response.as_ref().unwrap().status_code, 201);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shaped_response_is_paced() {
+        let dir = TempDir::new().unwrap();
+        let ca = Arc::new(CertificateAuthority::new(dir.path()).unwrap());
+        let origin = origin(&ca).await;
+
+        let rule = ShapingRule {
+            name: "slow".to_string(),
+            content_types: Vec::new(),
+            filter: String::new(),
+            chunk_size: Some(4),
+            chunk_delay_ms: 100,
+            bandwidth: None,
+            pad_bytes: 0,
+            pad_to: None,
+        };
+        let config = Config {
+            mode: ProxyMode::Reverse,
+            upstream_server: Some(format!("https://127.0.0.1:{}", origin.port())),
+            ssl_insecure: true,
+            shaping_rules: vec![rule],
+            ..Default::default()
+        };
+        let proxy = Arc::new(ProxyServer::new(Arc::new(config)).with_ca(ca));
+        let server = Arc::new(Http3Server::bind(proxy, "127.0.0.1:0".parse().unwrap()).unwrap());
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        // "GET /slow " is sent in three chunks, two of them delayed
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "example.test".to_string(), 443, "/slow".to_string());
+        let started = std::time::Instant::now();
+        let response = Http3Client::new(false).unwrap().send(addr, &request).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(response.content.as_deref(), Some(&b"GET /slow "[..]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_identical_requests_share_one_upstream_request() {
+        let dir = TempDir::new().unwrap();
+        let ca = Arc::new(CertificateAuthority::new(dir.path()).unwrap());
+        let (origin, requests) = counting_origin(&ca, Duration::from_millis(300)).await;
+
+        let config = Config {
+            mode: ProxyMode::Reverse,
+            upstream_server: Some(format!("https://127.0.0.1:{}", origin.port())),
+            ssl_insecure: true,
+            request_coalescing: CoalescingOptions { enabled: true, ..Default::default() },
+            ..Default::default()
+        };
+        let proxy = Arc::new(ProxyServer::new(Arc::new(config)).with_ca(ca));
+        let server = Arc::new(Http3Server::bind(Arc::clone(&proxy), "127.0.0.1:0".parse().unwrap()).unwrap());
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let client = Arc::new(Http3Client::new(false).unwrap());
+        let tasks: Vec<_> = (0..3)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "example.test".to_string(), 443, "/asset.js".to_string());
+                    client.send(addr, &request).await.unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap().content.as_deref(), Some(&b"GET /asset.js "[..]));
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let mut flows = Vec::new();
+        for _ in 0..100 {
+            flows = proxy.get_flows().await;
+            if flows.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let roles: Vec<&str> = flows.iter().filter_map(|flow| flow.flow.metadata[coalesce::METADATA_KEY]["role"].as_str()).collect();
+        assert_eq!(roles.iter().filter(|role| **role == "leader").count(), 1);
+        assert_eq!(roles.iter().filter(|role| **role == "follower").count(), 2);
+    }
+}
@@ -408,8 +408,13 @@ impl Layer for NextLayer {
 
             // TODO: Implement proper layer selection logic based on the event type
             // For now, default to TCP layer
-            let tcp_layer = Box::new(crate::proxy::layers::tcp::TcpLayer::new(self.base.context.clone()));
-            self.set_child_layer(tcp_layer);
+            let context = self.base.context.clone();
+            let tcp_layer = if crate::proxy::layers::tls::ignore_connection(&context, None, None) {
+                crate::proxy::layers::tcp::TcpLayer::ignored(context)
+            } else {
+                crate::proxy::layers::tcp::TcpLayer::new(context)
+            };
+            self.set_child_layer(Box::new(tcp_layer));
 
             self.process_buffered_events()
         }
@@ -0,0 +1,471 @@
+//! DNS layer implementation
+//! This mirrors the Python DNS layer in mitmproxy/proxy/layers/dns.py
+//!
+//! Each query a client sends becomes a `dns` flow, reported with the
+//! `dns_request` hook. If the hook answers the query, the answer goes
+//! straight back to the client; otherwise the query is forwarded to the
+//! server. Answers are reported with the `dns_response` hook before they
+//! are sent, so hooks can rewrite them.
+
+use std::collections::HashMap;
+
+use crate::connection::Connection;
+use crate::flow::{dns_type, DNSMessage, DNSQuestion, DNSResourceRecord, HTTPFlow};
+use crate::proxy::{
+    commands::{Command, DnsErrorHook, DnsRequestHook, DnsResponseHook, Log, LogLevel, SendData},
+    context::Context,
+    events::{AnyEvent, Event},
+    layer::{BaseLayer, CommandGenerator, Layer, SimpleCommandGenerator},
+};
+use crate::{Error, Result};
+
+/// Size of the DNS message header
+const HEADER_SIZE: usize = 12;
+
+/// Longest name allowed by RFC 1035, in wire format
+const MAX_NAME_LENGTH: usize = 255;
+
+/// DNS layer answering the queries of one client
+#[derive(Debug)]
+pub struct DnsLayer {
+    base: BaseLayer,
+    /// Flows of queries not answered yet, by message id
+    flows: HashMap<u16, HTTPFlow>,
+}
+
+impl DnsLayer {
+    pub fn new(context: Context) -> Self {
+        let mut context = context;
+        context.add_layer("DNS".to_string());
+        Self { base: BaseLayer::new(context), flows: HashMap::new() }
+    }
+
+    fn server_conn(&self) -> Connection {
+        self.base.context.server_conn().cloned().unwrap_or_default()
+    }
+
+    fn handle_data_received(&mut self, connection: Connection, data: Vec<u8>) -> Box<dyn CommandGenerator<()>> {
+        let from_client = connection == *self.base.context.client_conn();
+        let message = match unpack(&data) {
+            Ok(message) => message,
+            Err(e) => {
+                let side = if from_client { "client" } else { "server" };
+                return warn(format!("Ignoring invalid DNS message from {}: {}", side, e));
+            }
+        };
+
+        if from_client {
+            if !message.query {
+                return warn("Ignoring DNS response from client".to_string());
+            }
+            let flow = HTTPFlow::new_dns(message);
+            let id = flow.dns.as_ref().unwrap().request.id;
+            self.flows.insert(id, flow.clone());
+            return Box::new(SimpleCommandGenerator::new(vec![Box::new(DnsRequestHook { flow })]));
+        }
+
+        if message.query {
+            return warn("Ignoring DNS query from server".to_string());
+        }
+        let Some(flow) = self.flows.get_mut(&message.id) else {
+            return warn(format!("Ignoring DNS response with unknown id {}", message.id));
+        };
+        flow.dns.as_mut().unwrap().response = Some(message);
+        Box::new(SimpleCommandGenerator::new(vec![Box::new(DnsResponseHook { flow: flow.clone() })]))
+    }
+
+    /// Continue with the flow as the hook left it: answer from the
+    /// request hook or forward the query, and send the response hook's
+    /// answer to the client. Killed flows are dropped.
+    fn handle_hook_completed(&mut self, command: Box<dyn Command>) -> Box<dyn CommandGenerator<()>> {
+        let mut commands: Vec<Box<dyn Command>> = Vec::new();
+
+        if let Some(hook) = command.as_any().downcast_ref::<DnsRequestHook>() {
+            let flow = hook.flow.clone();
+            let Some(dns) = flow.dns.as_ref() else {
+                return Box::new(SimpleCommandGenerator::empty());
+            };
+            let id = dns.request.id;
+            if flow.flow.error.is_some() {
+                self.flows.remove(&id);
+            } else if dns.response.is_some() {
+                self.flows.insert(id, flow.clone());
+                commands.push(Box::new(DnsResponseHook { flow }));
+            } else {
+                commands.push(Box::new(SendData { connection: self.server_conn(), data: pack(&dns.request) }));
+                self.flows.insert(id, flow);
+            }
+        } else if let Some(hook) = command.as_any().downcast_ref::<DnsResponseHook>() {
+            let Some(dns) = hook.flow.dns.as_ref() else {
+                return Box::new(SimpleCommandGenerator::empty());
+            };
+            self.flows.remove(&dns.request.id);
+            if let (None, Some(response)) = (&hook.flow.flow.error, &dns.response) {
+                let client = self.base.context.client_conn().clone();
+                commands.push(Box::new(SendData { connection: client, data: pack(response) }));
+            }
+        }
+
+        Box::new(SimpleCommandGenerator::new(commands))
+    }
+
+    /// Queries still waiting for the server fail when it goes away
+    fn handle_connection_closed(&mut self, connection: Connection) -> Box<dyn CommandGenerator<()>> {
+        if connection == *self.base.context.client_conn() {
+            return Box::new(SimpleCommandGenerator::empty());
+        }
+        let commands: Vec<Box<dyn Command>> = self
+            .flows
+            .drain()
+            .map(|(_, mut flow)| {
+                flow.flow.set_error("Connection closed".to_string());
+                Box::new(DnsErrorHook { flow }) as Box<dyn Command>
+            })
+            .collect();
+        Box::new(SimpleCommandGenerator::new(commands))
+    }
+}
+
+impl Layer for DnsLayer {
+    fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
+        if self.base.is_paused() {
+            self.base.queue_event(event);
+            return Box::new(SimpleCommandGenerator::empty());
+        }
+
+        match event {
+            AnyEvent::Start(_) => {
+                let commands: Vec<Box<dyn Command>> = self.base.debug_log("DNS layer started").into_iter().collect();
+                Box::new(SimpleCommandGenerator::new(commands))
+            }
+            AnyEvent::DataReceived(data_event) => self.handle_data_received(data_event.connection, data_event.data),
+            AnyEvent::HookCompleted(completed) => self.handle_hook_completed(completed.command),
+            AnyEvent::ConnectionClosed(closed) => self.handle_connection_closed(closed.connection),
+            event => {
+                let commands: Vec<Box<dyn Command>> = self
+                    .base
+                    .debug_log(&format!("Unknown event: {}", event.event_name()))
+                    .into_iter()
+                    .collect();
+                Box::new(SimpleCommandGenerator::new(commands))
+            }
+        }
+    }
+
+    fn layer_name(&self) -> &'static str {
+        "DNSLayer"
+    }
+
+    fn debug_prefix(&self) -> Option<&str> {
+        self.base.debug.as_deref()
+    }
+}
+
+fn warn(message: String) -> Box<dyn CommandGenerator<()>> {
+    Box::new(SimpleCommandGenerator::new(vec![Box::new(Log { message, level: LogLevel::Warning })]))
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::Proxy(format!("Invalid DNS message: {}", reason))
+}
+
+/// Parse a DNS message from its wire format. Compressed names are
+/// expanded, also inside the data of records holding names.
+pub fn unpack(data: &[u8]) -> Result<DNSMessage> {
+    if data.len() < HEADER_SIZE {
+        return Err(invalid("truncated header"));
+    }
+    let word = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+    let flags = word(2);
+    let mut message = DNSMessage {
+        id: word(0),
+        query: flags & 0x8000 == 0,
+        op_code: ((flags >> 11) & 0xf) as u8,
+        authoritative_answer: flags & 0x0400 != 0,
+        truncation: flags & 0x0200 != 0,
+        recursion_desired: flags & 0x0100 != 0,
+        recursion_available: flags & 0x0080 != 0,
+        reserved: ((flags >> 4) & 0x7) as u8,
+        response_code: (flags & 0xf) as u8,
+        timestamp: crate::clock::now(),
+        ..Default::default()
+    };
+
+    let mut reader = Reader { data, pos: HEADER_SIZE };
+    for _ in 0..word(4) {
+        let name = reader.name()?;
+        message.questions.push(DNSQuestion { name, record_type: reader.u16()?, class: reader.u16()? });
+    }
+    for (count, records) in [
+        (word(6), &mut message.answers),
+        (word(8), &mut message.authorities),
+        (word(10), &mut message.additionals),
+    ] {
+        for _ in 0..count {
+            records.push(reader.record()?);
+        }
+    }
+    if reader.pos != data.len() {
+        return Err(invalid("trailing bytes"));
+    }
+    Ok(message)
+}
+
+/// Encode a DNS message in wire format, without name compression
+pub fn pack(message: &DNSMessage) -> Vec<u8> {
+    let flags = (!message.query as u16) << 15
+        | (message.op_code as u16 & 0xf) << 11
+        | (message.authoritative_answer as u16) << 10
+        | (message.truncation as u16) << 9
+        | (message.recursion_desired as u16) << 8
+        | (message.recursion_available as u16) << 7
+        | (message.reserved as u16 & 0x7) << 4
+        | (message.response_code as u16 & 0xf);
+
+    let mut data = Vec::new();
+    for word in [
+        message.id,
+        flags,
+        message.questions.len() as u16,
+        message.answers.len() as u16,
+        message.authorities.len() as u16,
+        message.additionals.len() as u16,
+    ] {
+        data.extend_from_slice(&word.to_be_bytes());
+    }
+    for question in &message.questions {
+        pack_name(&mut data, &question.name);
+        data.extend_from_slice(&question.record_type.to_be_bytes());
+        data.extend_from_slice(&question.class.to_be_bytes());
+    }
+    for record in message.answers.iter().chain(&message.authorities).chain(&message.additionals) {
+        pack_name(&mut data, &record.name);
+        data.extend_from_slice(&record.record_type.to_be_bytes());
+        data.extend_from_slice(&record.class.to_be_bytes());
+        data.extend_from_slice(&record.ttl.to_be_bytes());
+        data.extend_from_slice(&(record.data.len() as u16).to_be_bytes());
+        data.extend_from_slice(&record.data);
+    }
+    data
+}
+
+/// Append `name` as a sequence of labels
+pub fn pack_name(data: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        data.push(label.len() as u8);
+        data.extend_from_slice(label);
+    }
+    data.push(0);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len()).ok_or_else(|| invalid("truncated"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read a possibly compressed name. Pointers must point backwards,
+    /// which rules out loops.
+    fn name(&mut self) -> Result<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut length = 0;
+        let mut pos = self.pos;
+        let mut resume = None;
+        loop {
+            let len = *self.data.get(pos).ok_or_else(|| invalid("truncated name"))? as usize;
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self.data.get(pos + 1..pos + 1 + len).ok_or_else(|| invalid("truncated name"))?;
+                    length += len + 1;
+                    if length > MAX_NAME_LENGTH {
+                        return Err(invalid("name too long"));
+                    }
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+                0xc0 => {
+                    let low = *self.data.get(pos + 1).ok_or_else(|| invalid("truncated name"))? as usize;
+                    let target = (len & 0x3f) << 8 | low;
+                    if target >= pos {
+                        return Err(invalid("forward name pointer"));
+                    }
+                    resume.get_or_insert(pos + 2);
+                    pos = target;
+                }
+                _ => return Err(invalid("unknown label type")),
+            }
+        }
+        self.pos = resume.unwrap_or(pos);
+        Ok(labels.join("."))
+    }
+
+    fn record(&mut self) -> Result<DNSResourceRecord> {
+        let name = self.name()?;
+        let record_type = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let start = self.pos;
+        let end = start + len;
+        if end > self.data.len() {
+            return Err(invalid("truncated record"));
+        }
+
+        // Names in record data may point anywhere in the message, so they
+        // are expanded for the data to stand on its own
+        let (prefix, names, suffix) = match record_type {
+            dns_type::CNAME | dns_type::NS | dns_type::PTR => (0, 1, 0),
+            dns_type::MX => (2, 1, 0),
+            dns_type::SRV => (6, 1, 0),
+            dns_type::SOA => (0, 2, 20),
+            _ => (0, 0, len),
+        };
+        let data = if names == 0 {
+            self.take(len)?.to_vec()
+        } else {
+            let mut data = self.take(prefix)?.to_vec();
+            for _ in 0..names {
+                let name = self.name()?;
+                pack_name(&mut data, &name);
+            }
+            data.extend_from_slice(self.take(suffix)?);
+            if self.pos != end {
+                return Err(invalid("record data length mismatch"));
+            }
+            data
+        };
+        Ok(DNSResourceRecord { name, record_type, class, ttl, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{Server, TransportProtocol};
+    use crate::filter::Filter;
+    use crate::flow::dns_class;
+    use crate::proxy::events::{DataReceived, HookCompleted, Start};
+
+    fn commands(layer: &mut DnsLayer, event: AnyEvent) -> Vec<Box<dyn Command>> {
+        let mut generator = layer.handle_event(event);
+        std::iter::from_fn(|| generator.next_command()).collect()
+    }
+
+    fn query(name: &str) -> DNSMessage {
+        DNSMessage {
+            id: 0x1234,
+            query: true,
+            recursion_desired: true,
+            questions: vec![DNSQuestion { name: name.to_string(), record_type: dns_type::A, class: dns_class::IN }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_unpack_compressed_response() {
+        // example.com CNAME www.example.com, which points back at the
+        // question name
+        let mut data = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        pack_name(&mut data, "example.com");
+        data.extend_from_slice(&[0, 5, 0, 1]);
+        data.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6, 3, b'w', b'w', b'w', 0xc0, 12]);
+
+        let message = unpack(&data).unwrap();
+        assert!(!message.query);
+        assert!(message.recursion_available);
+        assert_eq!(message.questions[0].name, "example.com");
+        let answer = &message.answers[0];
+        assert_eq!((answer.name.as_str(), answer.ttl), ("example.com", 60));
+        assert_eq!(answer.data_text(), "www.example.com");
+        let repacked = unpack(&pack(&message)).unwrap();
+        assert_eq!((repacked.questions, repacked.answers), (message.questions.clone(), message.answers.clone()));
+
+        // A pointer to itself is rejected
+        let mut looping = data[..HEADER_SIZE].to_vec();
+        looping[5] = 1;
+        looping[7] = 0;
+        looping.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert!(unpack(&looping).is_err());
+        assert!(unpack(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_query_answered_by_server() {
+        let mut context = Context::default();
+        context.server = Some(Server::new(TransportProtocol::Udp));
+        let client = context.client_conn().clone();
+        let server = context.server_conn().cloned().unwrap();
+        let mut layer = DnsLayer::new(context);
+        commands(&mut layer, AnyEvent::Start(Start));
+
+        let request = query("example.com");
+        let received = commands(&mut layer, AnyEvent::DataReceived(DataReceived { connection: client.clone(), data: pack(&request) }));
+        let hook = received[0].as_any().downcast_ref::<DnsRequestHook>().unwrap();
+        assert_eq!(hook.flow.request.host, "example.com");
+        assert!(Filter::new("dns".to_string(), "~dns".to_string()).unwrap().matches(&hook.flow));
+
+        let flow = hook.flow.clone();
+        let forwarded = commands(&mut layer, AnyEvent::HookCompleted(HookCompleted { command: Box::new(DnsRequestHook { flow }) }));
+        let sent = forwarded[0].as_any().downcast_ref::<SendData>().unwrap();
+        assert_eq!((&sent.connection, unpack(&sent.data).unwrap().questions), (&server, request.questions.clone()));
+
+        let answer = request.succeed(vec![DNSResourceRecord::address("example.com".to_string(), [93, 184, 215, 14].into(), 300)]);
+        let received = commands(&mut layer, AnyEvent::DataReceived(DataReceived { connection: server, data: pack(&answer) }));
+        let mut flow = received[0].as_any().downcast_ref::<DnsResponseHook>().unwrap().flow.clone();
+
+        // The response hook rewrites the answer
+        let response = flow.dns.as_mut().unwrap().response.as_mut().unwrap();
+        response.answers[0] = DNSResourceRecord::address("example.com".to_string(), [127, 0, 0, 1].into(), 60);
+        let json = flow.to_json();
+        assert_eq!(json["type"], "dns");
+        assert_eq!(json["response"]["answers"][0]["data"], "127.0.0.1");
+        assert_eq!(json["response"]["answers"][0]["type"], "A");
+
+        let sent = commands(&mut layer, AnyEvent::HookCompleted(HookCompleted { command: Box::new(DnsResponseHook { flow }) }));
+        let sent = sent[0].as_any().downcast_ref::<SendData>().unwrap();
+        assert_eq!(sent.connection, client);
+        let answer = unpack(&sent.data).unwrap();
+        assert_eq!((answer.id, answer.answers[0].data_text()), (0x1234, "127.0.0.1".to_string()));
+        assert!(layer.flows.is_empty());
+    }
+
+    #[test]
+    fn test_query_answered_by_hook() {
+        let context = Context::default();
+        let client = context.client_conn().clone();
+        let mut layer = DnsLayer::new(context);
+
+        let request = query("blocked.example");
+        let received = commands(&mut layer, AnyEvent::DataReceived(DataReceived { connection: client, data: pack(&request) }));
+        let mut flow = received[0].as_any().downcast_ref::<DnsRequestHook>().unwrap().flow.clone();
+        flow.dns.as_mut().unwrap().response = Some(request.fail(crate::flow::dns_response_code::NXDOMAIN));
+
+        let answered = commands(&mut layer, AnyEvent::HookCompleted(HookCompleted { command: Box::new(DnsRequestHook { flow }) }));
+        let flow = answered[0].as_any().downcast_ref::<DnsResponseHook>().unwrap().flow.clone();
+        let sent = commands(&mut layer, AnyEvent::HookCompleted(HookCompleted { command: Box::new(DnsResponseHook { flow }) }));
+        let answer = unpack(&sent[0].as_any().downcast_ref::<SendData>().unwrap().data).unwrap();
+        assert_eq!(answer.response_code, crate::flow::dns_response_code::NXDOMAIN);
+    }
+}
@@ -11,15 +11,21 @@ Key components:
 - Integration with TLS layers for HTTPS support
 */
 
+use crate::config::ReverseTarget;
+use crate::corpus;
 use crate::connection::{Connection, ConnectionState};
-use crate::flow::{HTTPFlow, HTTPRequest, HTTPResponse};
+use crate::flow::{HTTPFlow, HTTPRequest, HTTPResponse, SseMessage};
 use crate::proxy::context::Context;
 use crate::proxy::{commands::*, events::*, layer::*};
 use crate::error::ProxyError;
+use crate::logging;
+use crate::sse::{SseParser, SseStreamStats};
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::task::{Context as TaskContext, Poll, Waker};
 use bytes::Bytes;
 use tracing::{debug, warn, error};
 use http;
@@ -63,6 +69,14 @@ pub enum H2Event {
         error_code: u32,
         last_stream_id: u32,
     },
+    TrailersReceived {
+        stream_id: u32,
+        trailers: http::HeaderMap,
+    },
+    /// The peer ended its side of the stream
+    StreamEnded {
+        stream_id: u32,
+    },
 }
 
 /// Stream ID type matching Python's StreamId
@@ -119,6 +133,8 @@ pub enum HTTPMode {
     Regular,
     Transparent,
     Upstream,
+    /// All requests go to the server configured as the reverse target
+    Reverse,
 }
 
 /// Error codes for HTTP protocol errors, matching Python's ErrorCode enum
@@ -158,6 +174,15 @@ impl ErrorCode {
             | ErrorCode::Cancel => None,
         }
     }
+
+    /// RST_STREAM error code for an HTTP/2 stream given up with this error
+    pub fn h2_reason(&self) -> h2::Reason {
+        match self {
+            ErrorCode::Cancel | ErrorCode::ClientDisconnected | ErrorCode::PassthroughClose => h2::Reason::CANCEL,
+            ErrorCode::Http11Required => h2::Reason::HTTP_1_1_REQUIRED,
+            _ => h2::Reason::INTERNAL_ERROR,
+        }
+    }
 }
 
 /// Base trait for HTTP events, matching Python's HttpEvent
@@ -376,6 +401,43 @@ impl HttpEvent for ResponseProtocolError {
     }
 }
 
+/// The server sent something that is not an HTTP response. Carries the
+/// bytes received, up to `unparsable::MAX_RAW_BYTES`.
+#[derive(Debug, Clone)]
+pub struct InvalidResponse {
+    pub stream_id: StreamId,
+    pub message: String,
+    pub data: Vec<u8>,
+    /// Bytes received in all
+    pub size: usize,
+}
+
+impl InvalidResponse {
+    fn new(stream_id: StreamId, message: String, data: &[u8]) -> Self {
+        let kept = &data[..data.len().min(crate::analysis::unparsable::MAX_RAW_BYTES)];
+        Self { stream_id, message, data: kept.to_vec(), size: data.len() }
+    }
+}
+
+impl Event for InvalidResponse {
+    fn event_name(&self) -> &'static str {
+        "InvalidResponse"
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+impl HttpEvent for InvalidResponse {
+    fn stream_id(&self) -> StreamId {
+        self.stream_id
+    }
+}
+
 /// Base trait for HTTP commands, matching Python's HttpCommand
 pub trait HttpCommand: Command {}
 
@@ -462,13 +524,16 @@ impl ReceiveBuffer {
     }
 
     pub fn maybe_extract_lines(&mut self) -> Option<Vec<Vec<u8>>> {
+        let head = self.maybe_extract_head()?;
+        Some(self.split_lines(&head))
+    }
+
+    /// Take a message head ending with an empty line off the buffer, as
+    /// received
+    pub fn maybe_extract_head(&mut self) -> Option<Vec<u8>> {
         // Look for double CRLF indicating end of headers
-        if let Some(pos) = self.find_double_crlf() {
-            let headers_data = self.buf.drain(..pos + 4).collect::<Vec<u8>>();
-            Some(self.split_lines(&headers_data))
-        } else {
-            None
-        }
+        let pos = self.find_double_crlf()?;
+        Some(self.buf.drain(..pos + 4).collect())
     }
 
     pub fn len(&self) -> usize {
@@ -488,7 +553,7 @@ impl ReceiveBuffer {
             .position(|window| window == b"\r\n\r\n")
     }
 
-    fn split_lines(&self, data: &[u8]) -> Vec<Vec<u8>> {
+    pub fn split_lines(&self, data: &[u8]) -> Vec<Vec<u8>> {
         data.split(|&byte| byte == b'\n')
             .map(|line| {
                 if line.ends_with(&[b'\r']) {
@@ -512,10 +577,20 @@ pub struct HttpStream {
     pub request_body_buf: ReceiveBuffer,
     pub response_body_buf: ReceiveBuffer,
     pub child_layer: Option<Box<dyn Layer>>,
+    /// Parser of an event-stream response, whose events are recorded as
+    /// they arrive
+    sse_parser: Option<SseParser>,
+    /// Server requests are rewritten to in reverse mode
+    reverse_target: Option<ReverseTarget>,
+    keep_host_header: bool,
+    /// Whether the request rewrites ran; a request without a body is
+    /// complete both with its headers and with its end of message
+    request_rewritten: bool,
+    context: Context,
 }
 
 impl HttpStream {
-    pub fn new(_context: Context, stream_id: StreamId) -> Self {
+    pub fn new(context: Context, stream_id: StreamId) -> Self {
         // Create a placeholder request - will be populated when actual request is received
         let request = crate::flow::HTTPRequest::new(
             "GET".to_string(),
@@ -524,7 +599,7 @@ impl HttpStream {
             80,
             "/".to_string(),
         );
-        let flow = HTTPFlow::new(request);
+        let flow = HTTPFlow::with_sources(request, &*context.clock, &*context.ids);
 
         Self {
             stream_id,
@@ -534,6 +609,11 @@ impl HttpStream {
             request_body_buf: ReceiveBuffer::new(),
             response_body_buf: ReceiveBuffer::new(),
             child_layer: None,
+            sse_parser: None,
+            reverse_target: context.options.reverse_target.clone(),
+            keep_host_header: context.options.keep_host_header,
+            request_rewritten: false,
+            context,
         }
     }
 
@@ -545,6 +625,10 @@ impl HttpStream {
             return self.handle_start();
         }
 
+        if self.client_state == "passthrough" {
+            return self.handle_passthrough(event);
+        }
+
         // Handle HTTP events based on current state
         if let Some(req_headers) = event.as_any().downcast_ref::<RequestHeaders>() {
             return self.handle_request_headers(req_headers.clone());
@@ -578,6 +662,14 @@ impl HttpStream {
             return self.handle_protocol_error(resp_error.message.clone());
         }
 
+        if let Some(invalid) = event.as_any().downcast_ref::<InvalidResponse>() {
+            return self.handle_invalid_response(invalid);
+        }
+
+        if let Some(completed) = event.as_any().downcast_ref::<HookCompleted>() {
+            return self.handle_hook_completed(completed.command.as_ref());
+        }
+
         warn!("HttpStream {} received unhandled event: {:?}",
               self.stream_id, std::any::type_name_of_val(&*event));
         Box::new(SimpleCommandGenerator::empty())
@@ -611,13 +703,69 @@ impl HttpStream {
             ]));
         }
 
+        if self.reverse_target.is_none() {
+            let onboarding = self.context.options.onboarding_host.as_deref();
+            if onboarding.is_some_and(|host| crate::onboarding::is_onboarding_request(&event.request, host)) {
+                return self.handle_onboarding();
+            }
+        }
+
         // Handle CONNECT method
         if event.request.method.to_uppercase() == "CONNECT" {
+            if let Some(refused) = self.refuse_unlisted_destination() {
+                return refused;
+            }
             return self.handle_connect();
         }
 
-        // Set appropriate scheme/host/port based on mode
-        // (Implementation would depend on proxy mode configuration)
+        // In reverse mode every request goes to the configured server
+        if let Some(target) = &self.reverse_target {
+            let request = &mut self.flow.request;
+            request.scheme = target.scheme.clone();
+            request.host = target.host.clone();
+            request.port = target.port;
+            request.pretty_host = target.authority();
+            if !self.keep_host_header {
+                // Keep the header where the client put it
+                match request.headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("host")) {
+                    Some((_, value)) => *value = target.authority(),
+                    None => request.headers.insert(0, ("Host".to_string(), target.authority())),
+                }
+            }
+        }
+
+        if let Some(map_remote) = &self.context.options.map_remote {
+            let before = self.flow.request.url();
+            match map_remote.rewrite(&mut self.flow.request) {
+                Ok(true) => debug!("HttpStream {} mapped {} to {}", self.stream_id, before, self.flow.request.url()),
+                Ok(false) => {}
+                Err(e) => warn!("HttpStream {}: {}", self.stream_id, e),
+            }
+        }
+
+        if let Some(status) = self.context.options.block_list.as_ref().and_then(|b| b.check(&self.flow)) {
+            return self.block(status);
+        }
+
+        if let Some(response) = self.context.options.map_local.as_ref().and_then(|m| m.respond(&self.flow)) {
+            debug!("HttpStream {} answered {} from a local file with {}",
+                   self.stream_id, self.flow.request.url(), response.status_code);
+            return self.respond_locally(response);
+        }
+
+        // Requests matched on their body are answered once it is complete
+        let replay = self.context.options.server_replay.clone();
+        if let Some(replay) = replay.filter(|replay| event.end_stream || !replay.needs_body()) {
+            if let Some(response) = replay.respond(&mut self.flow) {
+                debug!("HttpStream {} answered {} from server replay with {}",
+                       self.stream_id, self.flow.request.url(), response.status_code);
+                return self.respond_locally(response);
+            }
+        }
+
+        if let Some(refused) = self.refuse_unlisted_destination() {
+            return refused;
+        }
 
         self.client_state = if event.end_stream {
             "done".to_string()
@@ -626,9 +774,137 @@ impl HttpStream {
         };
         self.server_state = "wait_for_response_headers".to_string();
 
+        if event.end_stream {
+            self.rewrite_request();
+            if let Some(held) = self.intercept("request_intercepted") {
+                return held;
+            }
+        }
+
+        Box::new(SimpleCommandGenerator::empty())
+    }
+
+    /// Apply the header, body, cookie and credential rewrites to the
+    /// complete request, once
+    fn rewrite_request(&mut self) {
+        if std::mem::replace(&mut self.request_rewritten, true) {
+            return;
+        }
+        if let Some(rewrites) = &self.context.options.rewrites {
+            rewrites.request(&mut self.flow);
+        }
+    }
+
+    /// Hold the flow until it is resumed or killed if it matches the
+    /// `intercept` rule, entering `state` in the meantime
+    fn intercept(&mut self, state: &str) -> Option<Box<dyn CommandGenerator<()>>> {
+        let intercept = self.context.options.intercept.clone()?;
+        if !intercept.intercept(&mut self.flow) {
+            return None;
+        }
+        debug!("HttpStream {} intercepted {}", self.stream_id, self.flow.request.url());
+        self.server_state = state.to_string();
+        Some(Box::new(SimpleCommandGenerator::new(vec![Box::new(InterceptHook { flow: self.flow.clone() })])))
+    }
+
+    /// Continue with an intercepted flow as it was left once it is resumed:
+    /// forward the request, or answer it with a response added while it
+    /// was held, or finish the response. Killed flows drop the stream.
+    fn handle_hook_completed(&mut self, command: &dyn Command) -> Box<dyn CommandGenerator<()>> {
+        let Some(hook) = command.as_any().downcast_ref::<InterceptHook>() else {
+            return Box::new(SimpleCommandGenerator::empty());
+        };
+        let state = self.server_state.clone();
+        if state != "request_intercepted" && state != "response_intercepted" {
+            return Box::new(SimpleCommandGenerator::empty());
+        }
+        self.flow = hook.flow.clone();
+        self.flow.flow.intercepted = false;
+
+        if self.flow.flow.error.is_some() {
+            debug!("HttpStream {} dropped killed flow {}", self.stream_id, self.flow.request.url());
+            self.client_state = "done".to_string();
+            self.server_state = "done".to_string();
+            return Box::new(SimpleCommandGenerator::new(vec![Box::new(DropStream { stream_id: self.stream_id })]));
+        }
+        if state == "response_intercepted" {
+            return self.finish_response();
+        }
+        if let Some(response) = self.flow.response.clone() {
+            return self.respond_locally(response);
+        }
+        // The request may have been sent elsewhere while it was held
+        if let Some(status) = self.context.options.block_list.as_ref().and_then(|b| b.check(&self.flow)) {
+            return self.block(status);
+        }
+        if let Some(refused) = self.refuse_unlisted_destination() {
+            return refused;
+        }
+        self.server_state = "wait_for_response_headers".to_string();
         Box::new(SimpleCommandGenerator::empty())
     }
 
+    /// Answer a request to the onboarding host without contacting a server
+    fn handle_onboarding(&mut self) -> Box<dyn CommandGenerator<()>> {
+        let response = crate::onboarding::respond(&self.flow.request, self.context.options.ca.as_deref());
+        debug!("HttpStream {} answered onboarding request with {}", self.stream_id, response.status_code);
+        self.respond_locally(response)
+    }
+
+    /// Answer a request matching a `block_list` rule with `status`, or kill
+    /// the stream for 444
+    fn block(&mut self, status: u16) -> Box<dyn CommandGenerator<()>> {
+        debug!("HttpStream {} blocked {} with {}", self.stream_id, self.flow.request.url(), status);
+        if status != crate::blocklist::NO_RESPONSE {
+            return self.respond_locally(crate::blocklist::response(status));
+        }
+        self.flow.flow.set_error("Connection killed by block_list.".to_string());
+        self.client_state = "done".to_string();
+        self.server_state = "done".to_string();
+        Box::new(SimpleCommandGenerator::new(vec![Box::new(SendHttp {
+            event: Box::new(ResponseProtocolError {
+                stream_id: self.stream_id,
+                message: "Connection killed by block_list".to_string(),
+                code: ErrorCode::Kill,
+            }),
+            connection: self.context.client_conn().clone(),
+        })]))
+    }
+
+    /// Answer the request with 403 if allowlist-only mode is on and its
+    /// destination is not on the allowlist
+    fn refuse_unlisted_destination(&mut self) -> Option<Box<dyn CommandGenerator<()>>> {
+        let request = &self.flow.request;
+        let allowlist = self.context.options.allowlist.as_ref()?;
+        if allowlist.read().unwrap().allows(&request.host, request.port) {
+            return None;
+        }
+        warn!("HttpStream {} refused {}:{}: not on the allowlist", self.stream_id, request.host, request.port);
+        let response = crate::allowlist::refusal(&request.host, request.port);
+        Some(self.respond_locally(response))
+    }
+
+    /// Send `response` to the client as the answer to its request
+    fn respond_locally(&mut self, response: HTTPResponse) -> Box<dyn CommandGenerator<()>> {
+        let data = Bytes::from(response.content.clone().unwrap_or_default());
+        self.flow.response = Some(response.clone());
+        self.client_state = "done".to_string();
+        self.server_state = "done".to_string();
+
+        let connection = self.context.client_conn().clone();
+        let events: Vec<Box<dyn HttpEvent>> = vec![
+            Box::new(ResponseHeaders { stream_id: self.stream_id, response, end_stream: false }),
+            Box::new(ResponseData { stream_id: self.stream_id, data }),
+            Box::new(ResponseEndOfMessage { stream_id: self.stream_id }),
+        ];
+        Box::new(SimpleCommandGenerator::new(
+            events
+                .into_iter()
+                .map(|event| Box::new(SendHttp { event, connection: connection.clone() }) as Box<dyn Command>)
+                .collect(),
+        ))
+    }
+
     fn handle_request_data(&mut self, event: RequestData) -> Box<dyn CommandGenerator<()>> {
         debug!("HttpStream {} received {} bytes of request data", self.stream_id, event.data.len());
         self.request_body_buf.extend(&event.data);
@@ -639,10 +915,25 @@ impl HttpStream {
         debug!("HttpStream {} request complete", self.stream_id);
 
         // Finalize request body
-        self.flow.request.content = Some(self.request_body_buf.buf.clone());
+        self.flow.request.set_content(self.request_body_buf.buf.clone());
         self.request_body_buf.clear();
 
+        let replay = self.context.options.server_replay.clone();
+        if let Some(replay) = replay.filter(|replay| replay.needs_body() && self.server_state != "done") {
+            if let Some(response) = replay.respond(&mut self.flow) {
+                debug!("HttpStream {} answered {} from server replay with {}",
+                       self.stream_id, self.flow.request.url(), response.status_code);
+                return self.respond_locally(response);
+            }
+        }
+
         self.client_state = "done".to_string();
+        if self.server_state != "done" {
+            self.rewrite_request();
+            if let Some(held) = self.intercept("request_intercepted") {
+                return held;
+            }
+        }
 
         // TODO: Trigger request hook and make server connection
 
@@ -654,6 +945,10 @@ impl HttpStream {
                self.stream_id, event.response.status_code, event.response.reason);
 
         self.flow.response = Some(event.response.clone());
+        if self.flow.is_event_stream() {
+            self.sse_parser = Some(SseParser::new());
+            self.flow.sse_stream = Some(SseStreamStats::new(self.context.clock.now()));
+        }
 
         // TODO: Validate response and trigger response headers hook
 
@@ -669,18 +964,54 @@ impl HttpStream {
     fn handle_response_data(&mut self, event: ResponseData) -> Box<dyn CommandGenerator<()>> {
         debug!("HttpStream {} received {} bytes of response data", self.stream_id, event.data.len());
         self.response_body_buf.extend(&event.data);
+        let Some(parser) = self.sse_parser.as_mut() else {
+            return Box::new(SimpleCommandGenerator::empty());
+        };
+        let events = parser.parse_chunk(&event.data);
+        if self.record_sse_events(events) {
+            return Box::new(SimpleCommandGenerator::new(vec![Box::new(SseEventHook { flow: self.flow.clone() })]));
+        }
         Box::new(SimpleCommandGenerator::empty())
     }
 
+    /// Add events received on an event-stream response to the flow.
+    /// Returns whether there were any.
+    fn record_sse_events(&mut self, events: Vec<crate::sse::SseEvent>) -> bool {
+        let timestamp = self.context.clock.now();
+        if let Some(stats) = self.flow.sse_stream.as_mut() {
+            stats.record_data(timestamp, events.len());
+        }
+        let added = !events.is_empty();
+        self.flow.sse_events.extend(events.into_iter().map(|event| SseMessage { event, timestamp }));
+        added
+    }
+
     fn handle_response_end(&mut self, _event: ResponseEndOfMessage) -> Box<dyn CommandGenerator<()>> {
         debug!("HttpStream {} response complete", self.stream_id);
 
         // Finalize response body
         if let Some(ref mut response) = self.flow.response {
-            response.content = Some(self.response_body_buf.buf.clone());
+            response.set_content(self.response_body_buf.buf.clone());
             self.response_body_buf.clear();
         }
+        if let Some(mut parser) = self.sse_parser.take() {
+            self.record_sse_events(parser.flush().into_iter().collect());
+            if let Some(stats) = self.flow.sse_stream.as_mut() {
+                stats.finish(self.context.clock.now());
+            }
+        }
+
+        if let Some(rewrites) = &self.context.options.rewrites {
+            rewrites.response(&mut self.flow);
+        }
+        if let Some(held) = self.intercept("response_intercepted") {
+            return held;
+        }
+        self.finish_response()
+    }
 
+    /// Complete the stream once the response was received
+    fn finish_response(&mut self) -> Box<dyn CommandGenerator<()>> {
         self.server_state = "done".to_string();
         self.flow.flow.modified = true; // Mark as done instead of live flag
 
@@ -709,6 +1040,22 @@ impl HttpStream {
         ]))
     }
 
+    /// Note what the server sent instead of a response on the flow, and
+    /// answer with a 502 describing it if so configured
+    fn handle_invalid_response(&mut self, event: &InvalidResponse) -> Box<dyn CommandGenerator<()>> {
+        use crate::analysis::unparsable;
+
+        unparsable::record(&mut self.flow, &event.message, &event.data, event.size);
+        if !self.context.options.bad_gateway_on_invalid_response || self.client_state == "passthrough" {
+            return self.handle_protocol_error(event.message.clone());
+        }
+        warn!("HttpStream {} answers with 502: {}", self.stream_id, event.message);
+        self.flow.flow.set_error(event.message.clone());
+        let request = &self.flow.request;
+        let response = unparsable::bad_gateway(&request.host, request.port, &event.message, &event.data, event.size);
+        self.respond_locally(response)
+    }
+
     fn handle_connect(&mut self) -> Box<dyn CommandGenerator<()>> {
         debug!("HttpStream {} handling CONNECT request", self.stream_id);
 
@@ -719,12 +1066,104 @@ impl HttpStream {
         Box::new(SimpleCommandGenerator::empty())
     }
 
+    /// Switch to the protocol the server agreed to in its 101 response,
+    /// matching Python's handle_protocol_upgrade. WebSocket connections get
+    /// a `WebSocketLayer` as child layer; other protocols are relayed as is.
     fn handle_protocol_upgrade(&mut self) -> Box<dyn CommandGenerator<()>> {
         debug!("HttpStream {} handling protocol upgrade", self.stream_id);
 
-        // TODO: Create child layer for upgraded protocol (WebSocket, etc.)
+        self.client_state = "passthrough".to_string();
+        self.server_state = "passthrough".to_string();
 
-        Box::new(SimpleCommandGenerator::empty())
+        let is_websocket = self
+            .flow
+            .request
+            .get_header("upgrade")
+            .is_some_and(|upgrade| upgrade.to_ascii_lowercase().contains("websocket"));
+        if !is_websocket || !self.context.options.websocket {
+            return Box::new(SimpleCommandGenerator::empty());
+        }
+
+        let child = super::websocket::WebSocketLayer::new(self.context.clone(), self.flow.clone());
+        self.child_layer = Some(Box::new(child));
+        self.forward_to_child(AnyEvent::Start(Start))
+    }
+
+    /// Relay the data of an upgraded connection, through the child layer if
+    /// there is one
+    fn handle_passthrough(&mut self, event: Box<dyn Event>) -> Box<dyn CommandGenerator<()>> {
+        let client = self.context.client_conn().clone();
+        let server = self.context.server_conn().cloned().unwrap_or_default();
+        let event = event.as_any();
+
+        if let Some(e) = event.downcast_ref::<WebSocketMessageInjected>() {
+            return self.forward_to_child(AnyEvent::WebSocketMessageInjected(e.clone()));
+        }
+        if let Some(AnyEvent::WebSocketMessageInjected(e)) = event.downcast_ref::<AnyEvent>() {
+            return self.forward_to_child(AnyEvent::WebSocketMessageInjected(e.clone()));
+        }
+
+        let (from_client, relayed): (bool, Box<dyn HttpEvent>) = if let Some(e) = event.downcast_ref::<RequestData>() {
+            (true, Box::new(e.clone()))
+        } else if let Some(e) = event.downcast_ref::<ResponseData>() {
+            (false, Box::new(e.clone()))
+        } else if let Some(e) = event.downcast_ref::<RequestEndOfMessage>() {
+            (true, Box::new(e.clone()))
+        } else if let Some(e) = event.downcast_ref::<ResponseEndOfMessage>() {
+            (false, Box::new(e.clone()))
+        } else {
+            return Box::new(SimpleCommandGenerator::empty());
+        };
+        let (source, destination) = if from_client { (client, server) } else { (server, client) };
+
+        if self.child_layer.is_some() {
+            let data = event
+                .downcast_ref::<RequestData>()
+                .map(|e| &e.data)
+                .or_else(|| event.downcast_ref::<ResponseData>().map(|e| &e.data));
+            let child_event = match data {
+                Some(data) => AnyEvent::DataReceived(DataReceived { connection: source, data: data.to_vec() }),
+                None => AnyEvent::ConnectionClosed(ConnectionClosed { connection: source }),
+            };
+            return self.forward_to_child(child_event);
+        }
+        // Without a child layer, data goes to the other side unchanged
+        Box::new(SimpleCommandGenerator::new(vec![Box::new(SendHttp { event: relayed, connection: destination })]))
+    }
+
+    /// Pass an event to the child layer and translate the data it sends
+    /// into HTTP events for the connection's HTTP layer
+    fn forward_to_child(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
+        let Some(child) = self.child_layer.as_mut() else {
+            return Box::new(SimpleCommandGenerator::empty());
+        };
+        let client = self.context.client_conn().clone();
+        let server = self.context.server_conn().cloned().unwrap_or_default();
+        let stream_id = self.stream_id;
+
+        let mut generator = child.handle_event(event);
+        let mut commands: Vec<Box<dyn Command>> = Vec::new();
+        while let Some(cmd) = generator.next_command() {
+            if let Some(send) = cmd.as_any().downcast_ref::<SendData>() {
+                let data = Bytes::from(send.data.clone());
+                let (event, connection): (Box<dyn HttpEvent>, Connection) = if send.connection == client {
+                    (Box::new(ResponseData { stream_id, data }), client.clone())
+                } else {
+                    (Box::new(RequestData { stream_id, data }), server.clone())
+                };
+                commands.push(Box::new(SendHttp { event, connection }));
+            } else if let Some(close) = cmd.as_any().downcast_ref::<CloseConnection>() {
+                let (event, connection): (Box<dyn HttpEvent>, Connection) = if close.connection == client {
+                    (Box::new(ResponseEndOfMessage { stream_id }), client.clone())
+                } else {
+                    (Box::new(RequestEndOfMessage { stream_id }), server.clone())
+                };
+                commands.push(Box::new(SendHttp { event, connection }));
+            } else {
+                commands.push(cmd);
+            }
+        }
+        Box::new(SimpleCommandGenerator::new(commands))
     }
 
     fn validate_request(&self, request: &HTTPRequest) -> Result<(), String> {
@@ -743,7 +1182,10 @@ impl HttpStream {
 impl Layer for HttpStream {
     fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
         // Convert AnyEvent to Box<dyn Event> and delegate to internal handler
-        self.handle_event(Box::new(event) as Box<dyn Event>)
+        match event {
+            AnyEvent::HookCompleted(completed) => self.handle_event(Box::new(completed)),
+            event => self.handle_event(Box::new(event) as Box<dyn Event>),
+        }
     }
 
     fn layer_name(&self) -> &'static str {
@@ -808,6 +1250,27 @@ impl HttpLayer {
             }
         }
 
+        // Intercepted flows continue on the stream holding them
+        if let Some(completed) = event.as_any().downcast_ref::<HookCompleted>() {
+            if let Some(hook) = completed.command.as_any().downcast_ref::<InterceptHook>() {
+                if let Some(stream) = self.streams.values_mut().find(|s| s.flow.flow.id == hook.flow.flow.id) {
+                    return stream.handle_event(event);
+                }
+            }
+        }
+
+        // Injected WebSocket messages go to the upgraded streams
+        if let Some(AnyEvent::WebSocketMessageInjected(injected)) = event.as_any().downcast_ref::<AnyEvent>() {
+            let mut commands: Vec<Box<dyn Command>> = Vec::new();
+            for stream in self.streams.values_mut().filter(|s| s.child_layer.is_some()) {
+                let mut gen = stream.handle_event(Box::new(injected.clone()));
+                while let Some(cmd) = gen.next_command() {
+                    commands.push(cmd);
+                }
+            }
+            return Box::new(SimpleCommandGenerator::new(commands));
+        }
+
         // Route connection events to connection handlers
         // TODO: Implement connection event routing
 
@@ -880,6 +1343,17 @@ pub fn format_error(status_code: u16, message: &str) -> Vec<u8> {
     ).into_bytes()
 }
 
+/// Parse the size on a chunk size line, ignoring chunk extensions. Sizes
+/// no buffer could hold are rejected.
+fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next()?.trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(size, 16).ok().filter(|size| *size <= isize::MAX as usize)
+}
+
 /// HTTP/1.1 connection trait, matching Python's Http1Connection
 pub trait Http1Connection: Layer {
     fn stream_id(&self) -> Option<StreamId>;
@@ -900,6 +1374,8 @@ pub struct Http1Server {
     pub receive_buffer: ReceiveBuffer,
     pub state: Http1ServerState,
     pub context: Context,
+    /// Raw bytes of the request being read, kept for the fuzz corpus
+    received: Vec<u8>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -924,6 +1400,7 @@ impl Http1Server {
             receive_buffer: ReceiveBuffer::new(),
             state: Http1ServerState::Start,
             context,
+            received: Vec::new(),
         }
     }
 
@@ -931,6 +1408,16 @@ impl Http1Server {
     pub fn send_event(&mut self, event: Box<dyn HttpEvent>) -> Box<dyn CommandGenerator<()>> {
         let mut commands: Vec<Box<dyn Command>> = Vec::new();
 
+        if self.state == Http1ServerState::Passthrough {
+            let connection = self.context.client_conn().clone();
+            if let Some(e) = event.as_any().downcast_ref::<ResponseData>() {
+                commands.push(Box::new(SendData { connection, data: e.data.to_vec() }));
+            } else if event.as_any().downcast_ref::<ResponseEndOfMessage>().is_some() {
+                commands.push(Box::new(CloseConnection { connection }));
+            }
+            return Box::new(SimpleCommandGenerator::new(commands));
+        }
+
         match event.as_ref() {
             _ if event.as_any().downcast_ref::<ResponseHeaders>().is_some() => {
                 let resp_headers = event.as_any().downcast_ref::<ResponseHeaders>().unwrap();
@@ -948,7 +1435,7 @@ impl Http1Server {
                 let raw_response = match self.assemble_response_head(&response) {
                     Ok(r) => r,
                     Err(e) => {
-                        error!("Failed to assemble response head: {}", e);
+                        error!(target: logging::HTTP1, "Failed to assemble response head: {}", e);
                         return Box::new(SimpleCommandGenerator::empty());
                     }
                 };
@@ -999,7 +1486,7 @@ impl Http1Server {
                         let error_response = match self.make_error_response(status, &resp_error.message) {
                             Ok(r) => r,
                             Err(e) => {
-                                error!("Failed to make error response: {}", e);
+                                error!(target: logging::HTTP1, "Failed to make error response: {}", e);
                                 return Box::new(SimpleCommandGenerator::empty());
                             }
                         };
@@ -1014,7 +1501,7 @@ impl Http1Server {
                 }) as Box<dyn Command>);
             }
             _ => {
-                error!("Unexpected HTTP event: {:?}", std::any::type_name_of_val(event.as_ref()));
+                error!(target: logging::HTTP1, "Unexpected HTTP event: {:?}", std::any::type_name_of_val(event.as_ref()));
                 return Box::new(SimpleCommandGenerator::empty());
             }
         }
@@ -1034,12 +1521,12 @@ impl Http1Server {
                         let expected_body_size = match self.calculate_expected_body_size(&request) {
                             Ok(size) => size,
                             Err(e) => {
-                                error!("Failed to calculate expected body size: {}", e);
+                                error!(target: logging::HTTP1, "Failed to calculate expected body size: {}", e);
                                 return Box::new(SimpleCommandGenerator::empty());
                             }
                         };
 
-                        let commands: Vec<Box<dyn Command>> = vec![
+                        let mut commands: Vec<Box<dyn Command>> = vec![
                             Box::new(ReceiveHttp {
                                 event: Box::new(RequestHeaders {
                                     stream_id: self.stream_id,
@@ -1050,14 +1537,22 @@ impl Http1Server {
                             }) as Box<dyn Command>
                         ];
 
+                        // The body may already be buffered, continue with it right away
                         self.state = Http1ServerState::ReadBody;
+                        let mut gen = self.read_body(Box::new(DataReceived {
+                            connection: self.context.client_conn().clone(),
+                            data: vec![],
+                        }));
+                        while let Some(cmd) = gen.next_command() {
+                            commands.push(cmd);
+                        }
                         return Box::new(SimpleCommandGenerator::new(commands));
                     }
                     Err(e) => {
                         let error_response = match self.make_error_response(400, &e) {
                             Ok(r) => r,
                             Err(err) => {
-                                error!("Failed to make error response: {}", err);
+                                error!(target: logging::HTTP1, "Failed to make error response: {}", err);
                                 return Box::new(SimpleCommandGenerator::empty());
                             }
                         };
@@ -1076,7 +1571,7 @@ impl Http1Server {
         } else if let Some(_connection_closed) = event.as_any().downcast_ref::<ConnectionClosed>() {
             let buf_content = self.receive_buffer.buf.clone();
             if !buf_content.iter().all(|&b| b.is_ascii_whitespace()) {
-                debug!("Client closed connection before completing request headers: {:?}",
+                debug!(target: logging::HTTP1, "Client closed connection before completing request headers: {:?}",
                        String::from_utf8_lossy(&buf_content));
             }
             return Box::new(SimpleCommandGenerator::new(vec![
@@ -1134,10 +1629,7 @@ impl Http1Server {
         let mut request = crate::flow::HTTPRequest::new(method, scheme, host, port, path);
         request.http_version = version;
         request.headers = parsed_headers;
-        request.timestamp_start = Some(SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f64());
+        request.timestamp_start = Some(self.context.clock.now());
 
         Ok(request)
     }
@@ -1189,6 +1681,19 @@ impl Http1Server {
             self.response = None;
             self.stream_id += 2; // Increment by 2 for next request
             self.state = Http1ServerState::ReadHeaders;
+
+            // Data buffered by now was sent before the response completed.
+            // Blank lines between requests are allowed (RFC 9112, section 2.2).
+            if self.receive_buffer.buf.iter().all(|b| b.is_ascii_whitespace()) {
+                self.receive_buffer.clear();
+            } else if self.context.options.reject_pipelining {
+                return self.reject_pipelined_request();
+            } else {
+                return self.read_headers(Box::new(DataReceived {
+                    connection: self.context.client_conn().clone(),
+                    data: vec![],
+                }));
+            }
         }
 
         if self.request_done && !self.response_done {
@@ -1198,6 +1703,26 @@ impl Http1Server {
         Box::new(SimpleCommandGenerator::empty())
     }
 
+    /// Answer a pipelined request with 400 and close the connection
+    fn reject_pipelined_request(&mut self) -> Box<dyn CommandGenerator<()>> {
+        warn!(target: logging::HTTP1, "Rejecting pipelined HTTP/1.1 request");
+        self.receive_buffer.clear();
+        self.state = Http1ServerState::Done;
+
+        let mut commands: Vec<Box<dyn Command>> = Vec::new();
+        match self.make_error_response(400, "Pipelined requests are not supported by this proxy.") {
+            Ok(response) => commands.push(Box::new(SendData {
+                connection: self.context.client_conn().clone(),
+                data: response,
+            })),
+            Err(e) => error!(target: logging::HTTP1, "Failed to make error response: {}", e),
+        }
+        commands.push(Box::new(CloseConnection {
+            connection: self.context.client_conn().clone(),
+        }));
+        Box::new(SimpleCommandGenerator::new(commands))
+    }
+
     fn should_make_pipe(&self, request: &HTTPRequest, response: &HTTPResponse) -> bool {
         response.status_code == 101 ||
         (response.status_code == 200 && request.method.to_uppercase() == "CONNECT")
@@ -1306,8 +1831,15 @@ impl Http1Connection for Http1Server {
 
 impl Layer for Http1Server {
     fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
-        // Convert AnyEvent to Box<dyn Event> and delegate to sync_handle_event
-        self.sync_handle_event(Box::new(event) as Box<dyn Event>)
+        if let AnyEvent::DataReceived(e) = &event {
+            if self.state != Http1ServerState::Passthrough {
+                corpus::keep_input(&mut self.received, &e.data);
+            }
+        }
+        let mut gen = self.sync_handle_event(event.into_event());
+        let mut commands: Vec<Box<dyn Command>> = std::iter::from_fn(|| gen.next_command()).collect();
+        commands.extend(capture_malformed(&mut self.received, &commands));
+        Box::new(SimpleCommandGenerator::new(commands))
     }
 
     fn layer_name(&self) -> &'static str {
@@ -1318,7 +1850,7 @@ impl Layer for Http1Server {
 impl Http1Server {
     /// Handle event based on current state, matching Python's _handle_event method
     fn sync_handle_event(&mut self, event: Box<dyn Event>) -> Box<dyn CommandGenerator<()>> {
-        debug!("Http1Server handling event in state {:?}: {:?}",
+        debug!(target: logging::HTTP1, "Http1Server handling event in state {:?}: {:?}",
                self.state, std::any::type_name_of_val(&*event));
 
         match self.state {
@@ -1327,7 +1859,7 @@ impl Http1Server {
                     self.state = Http1ServerState::ReadHeaders;
                     Box::new(SimpleCommandGenerator::empty())
                 } else {
-                    error!("Expected Start event");
+                    error!(target: logging::HTTP1, "Expected Start event");
                     Box::new(SimpleCommandGenerator::empty())
                 }
             }
@@ -1341,6 +1873,12 @@ impl Http1Server {
                 // Wait for next request - handle HTTP events from the stream
                 if let Some(http_event) = self.try_extract_http_event(&event) {
                     self.send_event(http_event)
+                } else if let Some(data_received) = event.as_any().downcast_ref::<DataReceived>() {
+                    // A pipelined request, handled once the current response is complete
+                    debug!(target: logging::HTTP1, "Client pipelined data before the response to stream {} completed",
+                           self.stream_id);
+                    self.receive_buffer.extend(&data_received.data);
+                    Box::new(SimpleCommandGenerator::empty())
                 } else {
                     Box::new(SimpleCommandGenerator::empty())
                 }
@@ -1349,12 +1887,20 @@ impl Http1Server {
                 Box::new(SimpleCommandGenerator::empty())
             }
             Http1ServerState::Passthrough => {
-                // Forward data directly
+                // Hand the upgraded connection's data to the stream
                 if let Some(data_received) = event.as_any().downcast_ref::<DataReceived>() {
                     Box::new(SimpleCommandGenerator::new(vec![
-                        Box::new(SendData {
-                            connection: self.context.client_conn().clone(),
-                            data: data_received.data.clone(),
+                        Box::new(ReceiveHttp {
+                            event: Box::new(RequestData {
+                                stream_id: self.stream_id,
+                                data: data_received.data.clone().into(),
+                            }),
+                        }) as Box<dyn Command>
+                    ]))
+                } else if event.as_any().downcast_ref::<ConnectionClosed>().is_some() {
+                    Box::new(SimpleCommandGenerator::new(vec![
+                        Box::new(ReceiveHttp {
+                            event: Box::new(RequestEndOfMessage { stream_id: self.stream_id }),
                         }) as Box<dyn Command>
                     ]))
                 } else {
@@ -1377,7 +1923,7 @@ impl Http1Server {
                 let expected_body_size = match self.calculate_expected_body_size(request) {
                     Ok(size) => size,
                     Err(e) => {
-                        error!("Failed to calculate expected body size: {}", e);
+                        error!(target: logging::HTTP1, "Failed to calculate expected body size: {}", e);
                         return Box::new(SimpleCommandGenerator::empty());
                     }
                 };
@@ -1400,7 +1946,7 @@ impl Http1Server {
                 let expected_body_size = match self.calculate_expected_body_size(request) {
                     Ok(size) => size,
                     Err(e) => {
-                        error!("Failed to calculate expected body size: {}", e);
+                        error!(target: logging::HTTP1, "Failed to calculate expected body size: {}", e);
                         return Box::new(SimpleCommandGenerator::empty());
                     }
                 };
@@ -1439,13 +1985,10 @@ impl Http1Server {
         loop {
             // Try to read chunk size line
             if let Some(line_end) = self.find_line_end() {
-                let chunk_size_line = self.receive_buffer.buf.drain(..line_end + 2).collect::<Vec<u8>>();
-                let chunk_size_str = String::from_utf8_lossy(&chunk_size_line[..chunk_size_line.len() - 2]);
-
                 // Parse chunk size (hex)
-                let chunk_size = match usize::from_str_radix(chunk_size_str.trim(), 16) {
-                    Ok(size) => size,
-                    Err(_) => {
+                let chunk_size = match parse_chunk_size(&self.receive_buffer.buf[..line_end]) {
+                    Some(size) => size,
+                    None => {
                         return Box::new(SimpleCommandGenerator::new(vec![
                             Box::new(ReceiveHttp {
                                 event: Box::new(RequestProtocolError {
@@ -1459,6 +2002,7 @@ impl Http1Server {
                 };
 
                 if chunk_size == 0 {
+                    self.receive_buffer.buf.drain(..line_end + 2);
                     // Last chunk, read trailers (if any) and finish
                     if let Some(trailer_end) = self.find_double_crlf() {
                         self.receive_buffer.buf.drain(..trailer_end + 4);
@@ -1476,8 +2020,10 @@ impl Http1Server {
                     return Box::new(SimpleCommandGenerator::new(commands));
                 }
 
-                // Check if we have the full chunk + CRLF
-                if self.receive_buffer.len() >= chunk_size + 2 {
+                // Check if we have the full chunk + CRLF; the size line
+                // stays buffered until then
+                if self.receive_buffer.len() >= line_end + 2 + chunk_size + 2 {
+                    self.receive_buffer.buf.drain(..line_end + 2);
                     let chunk_data = self.receive_buffer.buf.drain(..chunk_size).collect::<Vec<u8>>();
                     self.receive_buffer.buf.drain(..2); // Remove trailing CRLF
 
@@ -1571,6 +2117,8 @@ pub struct Http1Client {
     pub receive_buffer: ReceiveBuffer,
     pub state: Http1ClientState,
     pub context: Context,
+    /// Raw bytes of the response being read, kept for the fuzz corpus
+    received: Vec<u8>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -1595,6 +2143,7 @@ impl Http1Client {
             receive_buffer: ReceiveBuffer::new(),
             state: Http1ClientState::Start,
             context,
+            received: Vec::new(),
         }
     }
 
@@ -1602,6 +2151,16 @@ impl Http1Client {
     pub fn send_event(&mut self, event: Box<dyn HttpEvent>) -> Box<dyn CommandGenerator<()>> {
         let mut commands: Vec<Box<dyn Command>> = Vec::new();
 
+        if self.state == Http1ClientState::Passthrough {
+            let connection = self.context.server_conn().cloned().unwrap_or_default();
+            if let Some(e) = event.as_any().downcast_ref::<RequestData>() {
+                commands.push(Box::new(SendData { connection, data: e.data.to_vec() }));
+            } else if event.as_any().downcast_ref::<RequestEndOfMessage>().is_some() {
+                commands.push(Box::new(CloseConnection { connection }));
+            }
+            return Box::new(SimpleCommandGenerator::new(commands));
+        }
+
         // Handle RequestProtocolError separately
         if let Some(_req_error) = event.as_any().downcast_ref::<RequestProtocolError>() {
             commands.push(Box::new(CloseConnection {
@@ -1616,14 +2175,14 @@ impl Http1Client {
                 self.stream_id = Some(req_headers.stream_id);
                 self.request = Some(req_headers.request.clone());
             } else {
-                error!("Expected RequestHeaders as first event");
+                error!(target: logging::HTTP1, "Expected RequestHeaders as first event");
                 return Box::new(SimpleCommandGenerator::empty());
             }
         }
 
         // Verify stream ID matches
         if Some(event.stream_id()) != self.stream_id {
-            error!("Stream ID mismatch");
+            error!(target: logging::HTTP1, "Stream ID mismatch");
             return Box::new(SimpleCommandGenerator::empty());
         }
 
@@ -1662,7 +2221,7 @@ impl Http1Client {
                 let raw_request = match self.assemble_request_head(&request) {
                     Ok(r) => r,
                     Err(e) => {
-                        error!("Failed to assemble request head: {}", e);
+                        error!(target: logging::HTTP1, "Failed to assemble request head: {}", e);
                         return Box::new(SimpleCommandGenerator::empty());
                     }
                 };
@@ -1702,7 +2261,7 @@ impl Http1Client {
                             match self.calculate_expected_response_body_size(request, response) {
                                 Ok(size) => size,
                                 Err(e) => {
-                                    error!("Failed to calculate expected body size: {}", e);
+                                    error!(target: logging::HTTP1, "Failed to calculate expected body size: {}", e);
                                     0
                                 }
                             }
@@ -1724,7 +2283,7 @@ impl Http1Client {
                 }
             }
             _ => {
-                error!("Unexpected HTTP event: {:?}", std::any::type_name_of_val(event.as_ref()));
+                error!(target: logging::HTTP1, "Unexpected HTTP event: {:?}", std::any::type_name_of_val(event.as_ref()));
                 return Box::new(SimpleCommandGenerator::empty());
             }
         }
@@ -1737,7 +2296,7 @@ impl Http1Client {
         if let Some(data_received) = event.as_any().downcast_ref::<DataReceived>() {
             if self.request.is_none() {
                 // Unexpected data from server
-                warn!("Unexpected data from server: {:?}", String::from_utf8_lossy(&data_received.data));
+                warn!(target: logging::HTTP1, "Unexpected data from server: {:?}", String::from_utf8_lossy(&data_received.data));
                 return Box::new(SimpleCommandGenerator::new(vec![
                     Box::new(CloseConnection {
                         connection: self.context.server_conn().cloned().unwrap_or_default(),
@@ -1747,7 +2306,8 @@ impl Http1Client {
 
             self.receive_buffer.extend(&data_received.data);
 
-            if let Some(response_lines) = self.receive_buffer.maybe_extract_lines() {
+            if let Some(head) = self.receive_buffer.maybe_extract_head() {
+                let response_lines = self.receive_buffer.split_lines(&head);
                 match self.parse_response_head(&response_lines) {
                     Ok(response) => {
                         self.response = Some(response.clone());
@@ -1756,7 +2316,7 @@ impl Http1Client {
                             match self.calculate_expected_response_body_size(request, &response) {
                                 Ok(size) => size,
                                 Err(e) => {
-                                    error!("Failed to calculate expected body size: {}", e);
+                                    error!(target: logging::HTTP1, "Failed to calculate expected body size: {}", e);
                                     0
                                 }
                             }
@@ -1783,11 +2343,11 @@ impl Http1Client {
                                 connection: self.context.server_conn().cloned().unwrap_or_default(),
                             }),
                             Box::new(ReceiveHttp {
-                                event: Box::new(ResponseProtocolError {
-                                    stream_id: self.stream_id.unwrap(),
-                                    message: format!("Cannot parse HTTP response: {}", e),
-                                    code: ErrorCode::GenericServerError,
-                                }),
+                                event: Box::new(InvalidResponse::new(
+                                    self.stream_id.unwrap(),
+                                    format!("Cannot parse HTTP response: {}", e),
+                                    &head,
+                                )),
                             })
                         ]));
                     }
@@ -1808,12 +2368,11 @@ impl Http1Client {
                 if !self.receive_buffer.is_empty() {
                     return Box::new(SimpleCommandGenerator::new(vec![
                         Box::new(ReceiveHttp {
-                            event: Box::new(ResponseProtocolError {
+                            event: Box::new(InvalidResponse::new(
                                 stream_id,
-                                message: format!("Unexpected server response: {:?}",
-                                    String::from_utf8_lossy(&self.receive_buffer.buf)),
-                                code: ErrorCode::GenericServerError,
-                            }),
+                                "Server closed the connection before completing the response head".to_string(),
+                                &self.receive_buffer.buf,
+                            )),
                         })
                     ]));
                 } else {
@@ -1842,7 +2401,7 @@ impl Http1Client {
                 let expected_body_size = match self.calculate_expected_response_body_size(request, response) {
                     Ok(size) => size,
                     Err(e) => {
-                        error!("Failed to calculate expected body size: {}", e);
+                        error!(target: logging::HTTP1, "Failed to calculate expected body size: {}", e);
                         return Box::new(SimpleCommandGenerator::empty());
                     }
                 };
@@ -1868,7 +2427,7 @@ impl Http1Client {
                 let expected_body_size = match self.calculate_expected_response_body_size(request, response) {
                     Ok(size) => size,
                     Err(e) => {
-                        error!("Failed to calculate expected body size: {}", e);
+                        error!(target: logging::HTTP1, "Failed to calculate expected body size: {}", e);
                         return Box::new(SimpleCommandGenerator::empty());
                     }
                 };
@@ -1913,13 +2472,10 @@ impl Http1Client {
         loop {
             // Try to read chunk size line
             if let Some(line_end) = self.find_line_end() {
-                let chunk_size_line = self.receive_buffer.buf.drain(..line_end + 2).collect::<Vec<u8>>();
-                let chunk_size_str = String::from_utf8_lossy(&chunk_size_line[..chunk_size_line.len() - 2]);
-
                 // Parse chunk size (hex)
-                let chunk_size = match usize::from_str_radix(chunk_size_str.trim(), 16) {
-                    Ok(size) => size,
-                    Err(_) => {
+                let chunk_size = match parse_chunk_size(&self.receive_buffer.buf[..line_end]) {
+                    Some(size) => size,
+                    None => {
                         return Box::new(SimpleCommandGenerator::new(vec![
                             Box::new(CloseConnection {
                                 connection: self.context.server_conn().cloned().unwrap_or_default(),
@@ -1936,6 +2492,7 @@ impl Http1Client {
                 };
 
                 if chunk_size == 0 {
+                    self.receive_buffer.buf.drain(..line_end + 2);
                     // Last chunk, read trailers (if any) and finish
                     if let Some(trailer_end) = self.find_double_crlf() {
                         self.receive_buffer.buf.drain(..trailer_end + 4);
@@ -1953,8 +2510,10 @@ impl Http1Client {
                     return Box::new(SimpleCommandGenerator::new(commands));
                 }
 
-                // Check if we have the full chunk + CRLF
-                if self.receive_buffer.len() >= chunk_size + 2 {
+                // Check if we have the full chunk + CRLF; the size line
+                // stays buffered until then
+                if self.receive_buffer.len() >= line_end + 2 + chunk_size + 2 {
+                    self.receive_buffer.buf.drain(..line_end + 2);
                     let chunk_data = self.receive_buffer.buf.drain(..chunk_size).collect::<Vec<u8>>();
                     self.receive_buffer.buf.drain(..2); // Remove trailing CRLF
 
@@ -2070,10 +2629,7 @@ impl Http1Client {
         let mut response = HTTPResponse::new(status_code, reason);
         response.http_version = version;
         response.headers = headers;
-        response.timestamp_start = Some(SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f64());
+        response.timestamp_start = Some(self.context.clock.now());
         Ok(response)
     }
 
@@ -2107,6 +2663,11 @@ impl Http1Client {
                 .map_err(|_| ProxyError::Proxy("Invalid Content-Length header".to_string()));
         }
 
+        // A single-range 206 without Content-Length is as long as its range
+        if let Some(size) = crate::ranges::expected_body_size(response) {
+            return Ok(size as usize);
+        }
+
         // HTTP/1.0 without Content-Length means read until EOF
         if response.http_version == "HTTP/1.0" {
             Ok(usize::MAX - 1) // Read-until-EOF semantics
@@ -2306,8 +2867,15 @@ impl Http1Connection for Http1Client {
 
 impl Layer for Http1Client {
     fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
-        // Convert AnyEvent to Box<dyn Event> and delegate to sync_handle_event
-        self.sync_handle_event(Box::new(event) as Box<dyn Event>)
+        if let AnyEvent::DataReceived(e) = &event {
+            if self.state != Http1ClientState::Passthrough {
+                corpus::keep_input(&mut self.received, &e.data);
+            }
+        }
+        let mut gen = self.sync_handle_event(event.into_event());
+        let mut commands: Vec<Box<dyn Command>> = std::iter::from_fn(|| gen.next_command()).collect();
+        commands.extend(capture_malformed(&mut self.received, &commands));
+        Box::new(SimpleCommandGenerator::new(commands))
     }
 
     fn layer_name(&self) -> &'static str {
@@ -2325,7 +2893,7 @@ impl Http1Client {
     }
 
     fn sync_handle_event(&mut self, event: Box<dyn Event>) -> Box<dyn CommandGenerator<()>> {
-        debug!("Http1Client handling event in state {:?}: {:?}",
+        debug!(target: logging::HTTP1, "Http1Client handling event in state {:?}: {:?}",
                self.state, std::any::type_name_of_val(&*event));
 
         match self.state {
@@ -2334,7 +2902,7 @@ impl Http1Client {
                     self.state = Http1ClientState::ReadHeaders;
                     Box::new(SimpleCommandGenerator::empty())
                 } else {
-                    error!("Expected Start event");
+                    error!(target: logging::HTTP1, "Expected Start event");
                     Box::new(SimpleCommandGenerator::empty())
                 }
             }
@@ -2366,6 +2934,37 @@ impl Http1Client {
     }
 }
 
+/// Report the kept copy of a message for the fuzz corpus if `commands`
+/// reject it, or drop the copy once the message was read completely. A
+/// rejected chunked message is reported to the chunked decoder's corpus
+/// as well.
+fn capture_malformed(received: &mut Vec<u8>, commands: &[Box<dyn Command>]) -> Vec<Box<dyn Command>> {
+    let events: Vec<&dyn std::any::Any> = commands
+        .iter()
+        .filter_map(|c| c.as_any().downcast_ref::<ReceiveHttp>())
+        .map(|r| r.event.as_any())
+        .collect();
+    if events.iter().any(|e| e.is::<RequestProtocolError>() || e.is::<ResponseProtocolError>() || e.is::<InvalidResponse>()) {
+        let input = std::mem::take(received);
+        let head_end = input.windows(4).position(|w| w == b"\r\n\r\n");
+        let chunked = head_end.filter(|&end| {
+            let head = String::from_utf8_lossy(&input[..end]).to_ascii_lowercase();
+            head.lines().any(|line| line.starts_with("transfer-encoding:") && line.contains("chunked"))
+        });
+
+        let mut captures: Vec<Box<dyn Command>> = Vec::new();
+        if let Some(end) = chunked {
+            captures.push(Box::new(CaptureMalformed { parser: corpus::Parser::Chunked, data: input[end + 4..].to_vec() }));
+        }
+        captures.push(Box::new(CaptureMalformed { parser: corpus::Parser::Http1, data: input }));
+        return captures;
+    }
+    if events.iter().any(|e| e.is::<RequestEndOfMessage>() || e.is::<ResponseEndOfMessage>()) {
+        received.clear();
+    }
+    Vec::new()
+}
+
 /// Command to receive HTTP event, matching Python's ReceiveHttp
 #[derive(Debug)]
 pub struct ReceiveHttp {
@@ -2449,6 +3048,8 @@ impl HttpEvent for ResponseTrailers {
 /// HTTP/2 connection configuration, matching Python's h2_conf_defaults
 #[derive(Debug, Clone)]
 pub struct Http2Config {
+    /// Whether we are the client of the connection, i.e. talk to a server
+    pub client_side: bool,
     pub header_encoding: Option<String>,
     pub validate_outbound_headers: bool,
     pub validate_inbound_headers: bool,
@@ -2459,6 +3060,7 @@ pub struct Http2Config {
 impl Default for Http2Config {
     fn default() -> Self {
         Self {
+            client_side: false,
             header_encoding: None,
             validate_outbound_headers: false,
             validate_inbound_headers: false,
@@ -2468,31 +3070,47 @@ impl Default for Http2Config {
     }
 }
 
-/// Buffered HTTP/2 connection wrapper, matching Python's BufferedH2Connection
-/// This wraps h2 server/client connection and adds internal send buffers
+/// Buffered HTTP/2 connection wrapper, matching Python's BufferedH2Connection.
+///
+/// Frames are parsed and serialized by the h2 crate, which is driven without
+/// a runtime: received bytes are fed to it through an in-memory transport and
+/// everything it writes is collected for `data_to_send`. h2 takes care of
+/// HPACK, flow control and answering SETTINGS and PING; connection-level
+/// frames are additionally scanned here to report them like Python's h2
+/// events do.
 #[derive(Debug)]
 pub struct BufferedH2Connection {
-    // We'll store connection state and handle it manually to match Python's approach
-    stream_buffers: HashMap<u32, VecDeque<SendH2Data>>,
-    #[allow(dead_code)] // TODO: Use for trailer support
-    stream_trailers: HashMap<u32, Vec<(Bytes, Bytes)>>,
+    client_side: bool,
     max_frame_size: u32,
-    #[allow(dead_code)] // TODO: Use for flow control
     initial_window_size: u32,
-    /// Number of outbound streams currently open
-    pub open_outbound_streams: u32,
-    /// Next available stream ID for client-initiated streams
-    next_stream_id: u32,
+    io: H2Io,
+    role: H2Role,
+    streams: HashMap<u32, H2Stream>,
+    /// Stream events produced while sending, reported with the next received data
+    pending_events: Vec<H2Event>,
     /// Remote peer settings
     remote_max_concurrent_streams: u32,
+    /// Received bytes not yet forming a complete frame
+    inbound: Vec<u8>,
+    /// Bytes of the client preface still to be skipped before the first frame
+    preface_remaining: usize,
+    /// Set after sending the client preface until the server's SETTINGS arrive
+    awaiting_remote_settings: bool,
+    /// Handle for keepalive PINGs of the client side
+    ping_pong: Option<h2::PingPong>,
 }
 
-/// Data to be sent on an HTTP/2 stream
-#[derive(Debug, Clone)]
-pub struct SendH2Data {
-    pub data: Bytes,
-    pub end_stream: bool,
-}
+/// Client connection preface (RFC 9113, section 3.4)
+pub const H2_CONNECTION_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const H2_FRAME_HEADER_LEN: usize = 9;
+const H2_FRAME_SETTINGS: u8 = 0x4;
+const H2_FRAME_PING: u8 = 0x6;
+const H2_FRAME_GOAWAY: u8 = 0x7;
+#[cfg(test)]
+const H2_FRAME_WINDOW_UPDATE: u8 = 0x8;
+const H2_FLAG_ACK: u8 = 0x1;
+const H2_SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
 
 /// Remote settings from HTTP/2 peer
 #[derive(Debug, Clone)]
@@ -2500,457 +3118,834 @@ pub struct RemoteSettings {
     pub max_concurrent_streams: u32,
 }
 
-impl BufferedH2Connection {
-    pub fn new() -> Self {
-        Self {
-            stream_buffers: HashMap::new(),
-            stream_trailers: HashMap::new(),
-            max_frame_size: 2_u32.pow(17), // 128KB, matching Python
-            initial_window_size: 2_u32.pow(31) - 1, // Max window size, matching Python
-            open_outbound_streams: 0,
-            next_stream_id: 1, // Client uses odd stream IDs
-            remote_max_concurrent_streams: 100, // Default max concurrent streams
-        }
+/// Bytes exchanged with the h2 state machine
+#[derive(Debug, Default)]
+struct H2Buffers {
+    inbound: bytes::BytesMut,
+    outbound: Vec<u8>,
+}
+
+/// In-memory transport h2 reads received data from and writes frames to
+#[derive(Debug, Clone, Default)]
+struct H2Io(Arc<std::sync::Mutex<H2Buffers>>);
+
+impl H2Io {
+    fn buffers(&self) -> std::sync::MutexGuard<'_, H2Buffers> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
     }
 
-    /// Get remote settings
-    pub fn remote_settings(&self) -> RemoteSettings {
-        RemoteSettings {
-            max_concurrent_streams: self.remote_max_concurrent_streams,
-        }
+    /// Bytes not yet read by h2 and bytes not yet taken from it
+    fn pending(&self) -> (usize, usize) {
+        let buffers = self.buffers();
+        (buffers.inbound.len(), buffers.outbound.len())
     }
+}
 
-    /// Get next available stream ID for a new outbound stream
-    pub fn get_next_available_stream_id(&mut self) -> u32 {
-        let id = self.next_stream_id;
-        self.next_stream_id += 2; // Increment by 2 to maintain odd/even parity
-        self.open_outbound_streams += 1;
-        id
+impl tokio::io::AsyncRead for H2Io {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let mut buffers = self.buffers();
+        if buffers.inbound.is_empty() {
+            // Polled again once the next DataReceived event arrives
+            return Poll::Pending;
+        }
+        let n = buf.remaining().min(buffers.inbound.len());
+        buf.put_slice(&buffers.inbound.split_to(n));
+        Poll::Ready(Ok(()))
     }
+}
 
-    /// Receive data and return events, matching Python's receive_data method
-    /// This converts raw bytes to H2Event enum, avoiding h2::frame usage
-    pub fn receive_data(&mut self, data: &[u8]) -> Result<Vec<H2Event>, ProxyError> {
-        // For now, return a placeholder event indicating we need to implement
-        // proper HTTP/2 frame parsing using the h2 library's non-frame API
-        let mut h2_events = Vec::new();
+impl tokio::io::AsyncWrite for H2Io {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, data: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.buffers().outbound.extend_from_slice(data);
+        Poll::Ready(Ok(data.len()))
+    }
 
-        if !data.is_empty() {
-            h2_events.push(H2Event::ProtocolError {
-                message: "BufferedH2Connection.receive_data not fully implemented - needs h2 integration".to_string(),
-            });
-        }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
 
-        Ok(h2_events)
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
+}
 
-    /// Send data on a stream, with buffering like Python implementation
-    pub fn send_data(&mut self, stream_id: u32, data: Bytes, end_stream: bool) -> Result<(), ProxyError> {
-        let frame_size = data.len();
-
-        // Check frame size limit
-        if frame_size > self.max_frame_size as usize {
-            // Split large frames
-            let max_size = self.max_frame_size as usize;
-            for chunk in data.chunks(max_size) {
-                let is_last_chunk = chunk.as_ptr() == data[data.len() - chunk.len()..].as_ptr();
-                self.send_data(stream_id, Bytes::copy_from_slice(chunk), end_stream && is_last_chunk)?;
-            }
-            return Ok(());
-        }
-
-        // Check if we have buffered data for this stream
-        if self.stream_buffers.contains_key(&stream_id) {
-            // Append to buffer
-            self.stream_buffers
-                .entry(stream_id)
-                .or_insert_with(VecDeque::new)
-                .push_back(SendH2Data { data, end_stream });
-        } else {
-            // For now, always buffer the data until we implement flow control
-            let mut buffer = VecDeque::new();
-            buffer.push_back(SendH2Data { data, end_stream });
-            self.stream_buffers.insert(stream_id, buffer);
+/// The h2 state machine, by side and progress of the connection
+#[derive(Debug)]
+enum H2Role {
+    Idle,
+    /// Server waiting for the client preface
+    ServerHandshake(Pin<Box<h2::server::Handshake<H2Io, Bytes>>>),
+    Server(h2::server::Connection<H2Io, Bytes>),
+    Client(h2::client::SendRequest<Bytes>, h2::client::Connection<H2Io, Bytes>),
+    Closed,
+}
+
+/// h2 handles of an open stream
+#[derive(Debug, Default)]
+struct H2Stream {
+    /// Response headers awaited from the server (client side)
+    response: Option<h2::client::ResponseFuture>,
+    /// Handle to send the response headers with (server side)
+    respond: Option<h2::server::SendResponse<Bytes>>,
+    /// Body of the received message while it is being read
+    recv: Option<h2::RecvStream>,
+    /// Set once the body was read and only the trailers remain
+    body_done: bool,
+    send: Option<h2::SendStream<Bytes>>,
+    /// Whether we ended our side of the stream
+    local_closed: bool,
+}
+
+impl H2Stream {
+    /// Collect the events of the stream. Returns false once the stream is
+    /// closed in both directions or was reset.
+    fn poll(&mut self, stream_id: u32, cx: &mut TaskContext<'_>, events: &mut Vec<H2Event>) -> bool {
+        if let Some(response) = self.response.as_mut() {
+            match Pin::new(response).poll(cx) {
+                Poll::Ready(Ok(response)) => {
+                    self.response = None;
+                    let (parts, body) = response.into_parts();
+                    let end_stream = body.is_end_stream();
+                    let mut headers = vec![(Bytes::from_static(b":status"), Bytes::from(parts.status.as_str().to_string()))];
+                    headers.extend(header_list(&parts.headers));
+                    events.push(H2Event::HeadersReceived { stream_id, headers, end_stream });
+                    if end_stream {
+                        events.push(H2Event::StreamEnded { stream_id });
+                    } else {
+                        self.recv = Some(body);
+                    }
+                }
+                Poll::Ready(Err(e)) => return stream_failed(stream_id, e, events),
+                Poll::Pending => {}
+            }
         }
 
-        Ok(())
-    }
+        while let Some(body) = self.recv.as_mut() {
+            if !self.body_done {
+                match body.poll_data(cx) {
+                    Poll::Ready(Some(Ok(data))) => {
+                        // Hand out the window again right away, like Python's
+                        // acknowledge_received_data
+                        let _ = body.flow_control().release_capacity(data.len());
+                        // Empty frames only carry END_STREAM
+                        if !data.is_empty() {
+                            events.push(H2Event::DataReceived { stream_id, data, end_stream: false });
+                        }
+                        continue;
+                    }
+                    Poll::Ready(Some(Err(e))) => return stream_failed(stream_id, e, events),
+                    Poll::Ready(None) => self.body_done = true,
+                    Poll::Pending => break,
+                }
+            }
+            match body.poll_trailers(cx) {
+                Poll::Ready(Ok(trailers)) => {
+                    if let Some(trailers) = trailers {
+                        events.push(H2Event::TrailersReceived { stream_id, trailers });
+                    }
+                    events.push(H2Event::StreamEnded { stream_id });
+                    self.recv = None;
+                }
+                Poll::Ready(Err(e)) => return stream_failed(stream_id, e, events),
+                Poll::Pending => break,
+            }
+        }
 
-    /// Get data to send to the network
-    pub fn data_to_send(&mut self) -> Option<Bytes> {
-        // TODO: Implement proper data serialization from buffered streams
-        None
+        let remote_closed = self.response.is_none() && self.recv.is_none();
+        if remote_closed && !self.local_closed {
+            // The peer may still reset a stream it is done sending on
+            let reset = match (self.send.as_mut(), self.respond.as_mut()) {
+                (Some(send), _) => send.poll_reset(cx),
+                (None, Some(respond)) => respond.poll_reset(cx),
+                (None, None) => Poll::Pending,
+            };
+            match reset {
+                Poll::Ready(Ok(reason)) => {
+                    events.push(H2Event::StreamReset { stream_id, error_code: reason.into() });
+                    return false;
+                }
+                Poll::Ready(Err(e)) => return stream_failed(stream_id, e, events),
+                Poll::Pending => {}
+            }
+        }
+        !(remote_closed && self.local_closed)
     }
+}
 
-    /// Check if stream has buffered data
-    pub fn has_buffered_data(&self, stream_id: u32) -> bool {
-        self.stream_buffers.get(&stream_id).map_or(false, |buf| !buf.is_empty())
+fn stream_failed(stream_id: u32, e: h2::Error, events: &mut Vec<H2Event>) -> bool {
+    // Streams ended by a GOAWAY are handled with the connection
+    if !e.is_go_away() && !e.is_io() {
+        let error_code = e.reason().unwrap_or(h2::Reason::INTERNAL_ERROR).into();
+        events.push(H2Event::StreamReset { stream_id, error_code });
     }
+    false
+}
 
-    /// Process buffered data for a stream when window updates occur
-    pub fn stream_window_updated(&mut self, _stream_id: u32) -> bool {
-        // TODO: Implement window update processing like Python version
-        false
-    }
+fn header_list(headers: &http::HeaderMap) -> impl Iterator<Item = (Bytes, Bytes)> + '_ {
+    headers
+        .iter()
+        .map(|(name, value)| (Bytes::copy_from_slice(name.as_str().as_bytes()), Bytes::copy_from_slice(value.as_bytes())))
 }
 
-/// HTTP/2 connection base class, matching Python's Http2Connection
-#[derive(Debug)]
-pub struct Http2Connection {
-    pub context: Context,
-    pub conn: Arc<Connection>,
-    pub h2_conn: BufferedH2Connection,
-    pub streams: HashMap<StreamId, Http2StreamState>,
-    pub debug: bool,
-    pub config: Http2Config,
+fn h2_error(e: h2::Error) -> ProxyError {
+    ProxyError::Proxy(format!("HTTP/2 error: {}", e))
 }
 
-impl Http2Connection {
-    pub fn new(context: Context, conn: Arc<Connection>, config: Http2Config) -> Self {
-        // Create the buffered H2 connection wrapper
-        let h2_conn = BufferedH2Connection::new();
+impl Default for BufferedH2Connection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+impl BufferedH2Connection {
+    /// Client side of a connection
+    pub fn new() -> Self {
         Self {
-            context,
-            conn,
-            h2_conn,
+            client_side: true,
+            max_frame_size: 2_u32.pow(17), // 128KB, matching Python
+            initial_window_size: 2_u32.pow(31) - 1, // Max window size, matching Python
+            io: H2Io::default(),
+            role: H2Role::Idle,
             streams: HashMap::new(),
-            debug: false, // TODO: Get from context options
-            config,
+            pending_events: Vec::new(),
+            remote_max_concurrent_streams: 100, // Default max concurrent streams
+            inbound: Vec::new(),
+            preface_remaining: 0,
+            awaiting_remote_settings: false,
+            ping_pong: None,
         }
     }
 
-    /// Check if a stream is closed, matching Python's is_closed method
-    pub fn is_closed(&self, stream_id: StreamId) -> bool {
-        // TODO: Implement proper stream state checking with h2 library
-        !self.streams.contains_key(&stream_id)
+    /// Server side of a connection
+    pub fn server() -> Self {
+        Self { client_side: false, ..Self::new() }
+    }
+
+    /// Start the connection, matching h2's initiate_connection. The client
+    /// sends the magic string, our SETTINGS with server push disabled, and a
+    /// window update raising the connection window to our initial window
+    /// size; the server must answer with its own SETTINGS frame first. The
+    /// server sends its SETTINGS and waits for the client preface.
+    pub fn initiate_connection(&mut self) {
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        if self.client_side {
+            let handshake = h2::client::Builder::new()
+                .enable_push(false)
+                .initial_window_size(self.initial_window_size)
+                .initial_connection_window_size(self.initial_window_size)
+                .max_frame_size(self.max_frame_size)
+                .handshake::<_, Bytes>(self.io.clone());
+            // Writing the preface to memory never blocks
+            match std::pin::pin!(handshake).poll(&mut cx) {
+                Poll::Ready(Ok((send_request, mut connection))) => {
+                    self.ping_pong = connection.ping_pong();
+                    self.role = H2Role::Client(send_request, connection);
+                }
+                Poll::Ready(Err(e)) => self.fail(e),
+                Poll::Pending => self.role = H2Role::Closed,
+            }
+            self.awaiting_remote_settings = true;
+        } else {
+            let handshake = h2::server::Builder::new()
+                .initial_window_size(self.initial_window_size)
+                .initial_connection_window_size(self.initial_window_size)
+                .max_frame_size(self.max_frame_size)
+                .handshake::<_, Bytes>(self.io.clone());
+            self.role = H2Role::ServerHandshake(Box::pin(handshake));
+            self.preface_remaining = H2_CONNECTION_PREFACE.len();
+        }
+        self.drive();
     }
 
-    /// Check if we can write to a stream, matching Python's is_open_for_us method
-    pub fn is_open_for_us(&self, stream_id: StreamId) -> bool {
-        // TODO: Implement proper stream state checking with h2 library
-        self.streams.contains_key(&stream_id) &&
-        self.streams[&stream_id] == Http2StreamState::HeadersReceived
+    /// Get remote settings
+    pub fn remote_settings(&self) -> RemoteSettings {
+        RemoteSettings {
+            max_concurrent_streams: self.remote_max_concurrent_streams,
+        }
     }
 
-    /// Handle HTTP/2 events, matching Python's handle_h2_event method
-    pub fn handle_h2_event(&mut self, event: H2Event) -> Box<dyn crate::proxy::layer::CommandGenerator<()>> {
-        match event {
-            H2Event::DataReceived { stream_id, data, end_stream } => {
-                self.handle_data_received_simple(stream_id, data, end_stream)
-            }
-            H2Event::HeadersReceived { stream_id, headers, end_stream } => {
-                self.handle_headers_received_simple(stream_id, headers, end_stream)
-            }
-            H2Event::StreamReset { stream_id, error_code } => {
-                self.handle_stream_reset(stream_id, error_code)
-            }
-            H2Event::SettingsChanged => {
-                self.handle_settings_changed()
-            }
-            H2Event::GoAway { error_code, last_stream_id } => {
-                self.handle_go_away(error_code, last_stream_id)
-            }
-            H2Event::WindowUpdate { stream_id } => {
-                self.handle_window_update(stream_id)
-            }
-            H2Event::Ping { ack, data } => {
-                self.handle_ping(ack, data)
-            }
-            H2Event::ProtocolError { message } => {
-                self.protocol_error_generator(message)
-            }
-            H2Event::ConnectionTerminated { error_code, last_stream_id } => {
-                self.handle_go_away(error_code, last_stream_id)
-            }
+    /// Number of streams we opened that are not closed yet
+    pub fn open_outbound_streams(&self) -> u32 {
+        if self.client_side {
+            self.streams.len() as u32
+        } else {
+            0
         }
     }
 
-    /// Simple data received handler returning () generator
-    fn handle_data_received_simple(&mut self, _stream_id: u32, _data: Bytes, _end_stream: bool) -> Box<dyn crate::proxy::layer::CommandGenerator<()>> {
-        // TODO: Implement properly
-        Box::new(SimpleCommandGenerator::new(vec![]))
+    /// Whether the stream is closed in both directions, or was never opened
+    pub fn is_closed(&self, stream_id: u32) -> bool {
+        !self.streams.contains_key(&stream_id)
     }
 
-    /// Simple headers received handler returning () generator
-    fn handle_headers_received_simple(&mut self, _stream_id: u32, _headers: Vec<(Bytes, Bytes)>, _end_stream: bool) -> Box<dyn crate::proxy::layer::CommandGenerator<()>> {
-        // TODO: Implement properly
-        Box::new(SimpleCommandGenerator::new(vec![]))
+    /// Whether we can still send on the stream
+    pub fn is_open_for_us(&self, stream_id: u32) -> bool {
+        !matches!(self.role, H2Role::Closed) && self.streams.get(&stream_id).is_some_and(|s| !s.local_closed)
     }
 
-    /// Protocol error handler that returns a CommandGenerator<()>
-    fn protocol_error_generator(&mut self, message: String) -> Box<dyn crate::proxy::layer::CommandGenerator<()>> {
-        warn!("HTTP/2 protocol error: {}", message);
-
-        let commands = vec![
-            Box::new(Log {
-                message: format!("HTTP/2 protocol error: {}", message),
-                level: LogLevel::Error,
-            }) as Box<dyn Command>,
-            Box::new(CloseConnection {
-                connection: (*self.conn).clone(),
-            }) as Box<dyn Command>,
-        ];
+    /// Whether the response headers of the stream were sent (server side)
+    pub fn headers_sent(&self, stream_id: u32) -> bool {
+        self.streams.get(&stream_id).is_none_or(|s| s.respond.is_none())
+    }
 
-        Box::new(crate::proxy::layer::SimpleCommandGenerator::new(commands))
+    /// Receive data and return events, matching Python's receive_data method
+    pub fn receive_data(&mut self, data: &[u8]) -> Result<Vec<H2Event>, ProxyError> {
+        let mut h2_events = self.scan_frames(data)?;
+        self.io.buffers().inbound.extend_from_slice(data);
+        self.drive();
+        h2_events.append(&mut self.pending_events);
+        Ok(h2_events)
     }
 
-    #[allow(dead_code)] // TODO: Use in handle_h2_event for DataReceived
-    fn handle_data_received(&mut self, stream_id: u32, data: Bytes, end_stream: bool) -> Box<dyn crate::proxy::layer::CommandGenerator<()>> {
-        let stream_id = stream_id as StreamId;
+    /// Report connection-level frames. h2 itself answers SETTINGS and PING
+    /// frames; this only tracks what the layers need to know about.
+    fn scan_frames(&mut self, data: &[u8]) -> Result<Vec<H2Event>, ProxyError> {
+        let skipped = self.preface_remaining.min(data.len());
+        self.preface_remaining -= skipped;
+        self.inbound.extend_from_slice(&data[skipped..]);
+        let mut h2_events = Vec::new();
 
-        // Check if stream exists
-        let state = self.streams.get(&stream_id);
-        if state.is_none() {
-            return self.protocol_error_generator(
-                format!("Received data frame for unknown stream {}", stream_id)
-            );
-        }
+        while self.inbound.len() >= H2_FRAME_HEADER_LEN {
+            let header = &self.inbound[..H2_FRAME_HEADER_LEN];
+            let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let (kind, flags) = (header[3], header[4]);
+
+            // A server that does not speak h2c typically answers the preface
+            // with an HTTP/1 error response
+            if self.awaiting_remote_settings && (kind != H2_FRAME_SETTINGS || flags & H2_FLAG_ACK != 0) {
+                return Err(ProxyError::Proxy(
+                    "Server did not answer the HTTP/2 connection preface with SETTINGS; it may not support HTTP/2 without TLS".to_string(),
+                ));
+            }
+            if length > self.max_frame_size as usize {
+                return Err(ProxyError::Proxy(format!("HTTP/2 frame of {} bytes exceeds the maximum frame size", length)));
+            }
+            if self.inbound.len() < H2_FRAME_HEADER_LEN + length {
+                break;
+            }
+            let frame: Vec<u8> = self.inbound.drain(..H2_FRAME_HEADER_LEN + length).skip(H2_FRAME_HEADER_LEN).collect();
 
-        // Check if we're expecting headers instead of data
-        if *state.unwrap() == Http2StreamState::ExpectingHeaders {
-            return self.protocol_error_generator(
-                "Received HTTP/2 data frame, expected headers.".to_string()
-            );
+            match kind {
+                H2_FRAME_SETTINGS if flags & H2_FLAG_ACK != 0 => {}
+                H2_FRAME_SETTINGS => {
+                    if !frame.len().is_multiple_of(6) {
+                        return Err(ProxyError::Proxy("Malformed HTTP/2 SETTINGS frame".to_string()));
+                    }
+                    for setting in frame.chunks(6) {
+                        let id = u16::from_be_bytes([setting[0], setting[1]]);
+                        let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                        if id == H2_SETTINGS_MAX_CONCURRENT_STREAMS {
+                            self.remote_max_concurrent_streams = value;
+                        }
+                    }
+                    self.awaiting_remote_settings = false;
+                    h2_events.push(H2Event::SettingsChanged);
+                }
+                H2_FRAME_PING => {
+                    let data: [u8; 8] = frame
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| ProxyError::Proxy("Malformed HTTP/2 PING frame".to_string()))?;
+                    h2_events.push(H2Event::Ping { ack: flags & H2_FLAG_ACK != 0, data });
+                }
+                H2_FRAME_GOAWAY if frame.len() >= 8 => h2_events.push(H2Event::GoAway {
+                    last_stream_id: u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) & 0x7fff_ffff,
+                    error_code: u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]),
+                }),
+                _ => {}
+            }
         }
 
-        // Handle empty end-of-stream data frames (just flow control)
-        let is_empty_eos_data_frame = end_stream && data.is_empty();
-        if is_empty_eos_data_frame {
-            // TODO: Acknowledge received data for flow control
-            return Box::new(crate::proxy::layer::SimpleCommandGenerator::empty());
+        Ok(h2_events)
+    }
+
+    /// Let h2 process received data and write out pending frames,
+    /// collecting the stream events that result
+    fn drive(&mut self) {
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        loop {
+            let progress = (self.pending_events.len(), self.io.pending());
+            self.poll_connection(&mut cx);
+            if matches!(self.role, H2Role::Closed) {
+                break;
+            }
+            let mut ids: Vec<u32> = self.streams.keys().copied().collect();
+            ids.sort_unstable();
+            for id in ids {
+                let open = self.streams.get_mut(&id).is_some_and(|s| s.poll(id, &mut cx, &mut self.pending_events));
+                if !open {
+                    self.streams.remove(&id);
+                }
+            }
+            if progress == (self.pending_events.len(), self.io.pending()) {
+                break;
+            }
         }
+    }
 
-        // Send data to stream
-        let commands = vec![
-            Box::new(ReceiveHttp {
-                event: Box::new(RequestData {
-                    stream_id,
-                    data,
-                }),
-            }) as Box<dyn Command>
-        ];
+    fn poll_connection(&mut self, cx: &mut TaskContext<'_>) {
+        if let H2Role::ServerHandshake(handshake) = &mut self.role {
+            match handshake.as_mut().poll(cx) {
+                Poll::Ready(Ok(connection)) => self.role = H2Role::Server(connection),
+                Poll::Ready(Err(e)) => return self.fail(e),
+                Poll::Pending => return,
+            }
+        }
 
-        Box::new(crate::proxy::layer::SimpleCommandGenerator::new(commands))
+        let result = match &mut self.role {
+            H2Role::Server(connection) => loop {
+                match connection.poll_accept(cx) {
+                    Poll::Ready(Some(Ok((request, respond)))) => {
+                        let stream_id = respond.stream_id().as_u32();
+                        let (parts, body) = request.into_parts();
+                        let end_stream = body.is_end_stream();
+                        let mut headers = vec![(Bytes::from_static(b":method"), Bytes::from(parts.method.to_string()))];
+                        if let Some(scheme) = parts.uri.scheme_str() {
+                            headers.push((Bytes::from_static(b":scheme"), Bytes::from(scheme.to_string())));
+                        }
+                        if let Some(authority) = parts.uri.authority() {
+                            headers.push((Bytes::from_static(b":authority"), Bytes::from(authority.to_string())));
+                        }
+                        if let Some(path) = parts.uri.path_and_query() {
+                            headers.push((Bytes::from_static(b":path"), Bytes::from(path.to_string())));
+                        }
+                        headers.extend(header_list(&parts.headers));
+                        self.pending_events.push(H2Event::HeadersReceived { stream_id, headers, end_stream });
+                        if end_stream {
+                            self.pending_events.push(H2Event::StreamEnded { stream_id });
+                        }
+                        let recv = (!end_stream).then_some(body);
+                        self.streams.insert(stream_id, H2Stream { respond: Some(respond), recv, ..Default::default() });
+                    }
+                    Poll::Ready(Some(Err(e))) => break Some(Err(e)),
+                    Poll::Ready(None) => break Some(Ok(())),
+                    Poll::Pending => break None,
+                }
+            },
+            H2Role::Client(_, connection) => match Pin::new(connection).poll(cx) {
+                Poll::Ready(result) => Some(result),
+                Poll::Pending => None,
+            },
+            _ => None,
+        };
+        match result {
+            Some(Ok(())) => self.role = H2Role::Closed,
+            Some(Err(e)) => self.fail(e),
+            None => {}
+        }
     }
 
-    #[allow(dead_code)] // TODO: Use in handle_h2_event for HeadersReceived
-    fn handle_headers_received(&mut self, stream_id: u32, headers: Vec<(Bytes, Bytes)>, end_stream: bool) -> Box<dyn crate::proxy::layer::CommandGenerator<()>> {
-        let stream_id = stream_id as StreamId;
+    /// Give up on a connection h2 reported an error for
+    fn fail(&mut self, e: h2::Error) {
+        // A GOAWAY from the peer is reported by scan_frames, and one we sent
+        // was asked for by the layer
+        if !(e.is_go_away() && (e.is_remote() || !e.is_library())) {
+            self.pending_events.push(H2Event::ProtocolError { message: e.to_string() });
+        }
+        self.role = H2Role::Closed;
+        self.streams.clear();
+    }
 
-        // Parse headers into pseudo-headers and regular headers
-        let result = self.parse_h2_headers_from_vec(headers);
-        let (regular_headers, pseudo_headers) = match result {
-            Ok(parsed) => parsed,
-            Err(e) => {
-                return self.protocol_error_generator(format!("Failed to parse headers: {}", e));
-            }
+    /// Open a stream with the given request headers (client side). Returns
+    /// the id of the new stream.
+    pub fn send_request(&mut self, headers: Vec<(Bytes, Bytes)>, end_stream: bool) -> Result<u32, ProxyError> {
+        let H2Role::Client(send_request, _) = &mut self.role else {
+            return Err(ProxyError::Proxy("HTTP/2 connection cannot open a stream".to_string()));
         };
-
-        // Create HTTP request from headers
-        let request = match self.create_request_from_headers(pseudo_headers, regular_headers) {
-            Ok(req) => req,
-            Err(e) => {
-                return self.protocol_error_generator(format!("Failed to create request: {}", e));
-            }
+        let (pseudo_headers, fields) = split_pseudo_headers(headers)?;
+        let pseudo_header = |name: &str| {
+            pseudo_headers
+                .get(name)
+                .ok_or_else(|| ProxyError::Proxy(format!("Required pseudo header is missing: {}", name)))
         };
+        let uri = http::Uri::builder()
+            .scheme(pseudo_header(":scheme")?.as_ref())
+            .authority(pseudo_header(":authority")?.as_ref())
+            .path_and_query(pseudo_header(":path")?.as_ref())
+            .build()
+            .map_err(|e| ProxyError::Proxy(format!("Invalid request target: {}", e)))?;
+        let mut request = http::Request::builder()
+            .method(pseudo_header(":method")?.as_ref())
+            .uri(uri)
+            .body(())
+            .map_err(|e| ProxyError::Proxy(format!("Invalid request: {}", e)))?;
+        *request.headers_mut() = fields;
+
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        match send_request.poll_ready(&mut cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Err(h2_error(e)),
+            Poll::Pending => return Err(ProxyError::Proxy("No HTTP/2 stream available".to_string())),
+        }
+        let (response, send) = send_request.send_request(request, end_stream).map_err(h2_error)?;
+        let stream_id = response.stream_id().as_u32();
+        self.streams.insert(
+            stream_id,
+            H2Stream { response: Some(response), send: Some(send), local_closed: end_stream, ..Default::default() },
+        );
+        self.drive();
+        Ok(stream_id)
+    }
+
+    /// Send the response headers of a stream (server side)
+    pub fn send_headers(&mut self, stream_id: u32, headers: Vec<(Bytes, Bytes)>, end_stream: bool) -> Result<(), ProxyError> {
+        let (status_code, fields) = parse_h2_response_headers(headers)?;
+        let mut response = http::Response::builder()
+            .status(status_code)
+            .body(())
+            .map_err(|e| ProxyError::Proxy(format!("Invalid response: {}", e)))?;
+        *response.headers_mut() = fields;
+
+        let stream = self.open_stream(stream_id)?;
+        let respond = stream
+            .respond
+            .as_mut()
+            .ok_or_else(|| ProxyError::Proxy(format!("Headers already sent on HTTP/2 stream {}", stream_id)))?;
+        stream.send = Some(respond.send_response(response, end_stream).map_err(h2_error)?);
+        stream.respond = None;
+        stream.local_closed = end_stream;
+        self.drive();
+        Ok(())
+    }
 
-        self.streams.insert(stream_id, Http2StreamState::HeadersReceived);
+    /// Send data on a stream. h2 splits it into frames and holds it back
+    /// until the peer's flow-control window allows sending it.
+    pub fn send_data(&mut self, stream_id: u32, data: Bytes, end_stream: bool) -> Result<(), ProxyError> {
+        let stream = self.open_stream(stream_id)?;
+        let send = stream
+            .send
+            .as_mut()
+            .ok_or_else(|| ProxyError::Proxy(format!("Headers not sent yet on HTTP/2 stream {}", stream_id)))?;
+        send.send_data(data, end_stream).map_err(h2_error)?;
+        stream.local_closed = end_stream;
+        self.drive();
+        Ok(())
+    }
 
-        let commands = vec![
-            Box::new(ReceiveHttp {
-                event: Box::new(RequestHeaders {
-                    stream_id,
-                    request,
-                    end_stream,
-                    replay_flow: None,
-                }),
-            }) as Box<dyn Command>
-        ];
+    /// End our side of a stream
+    pub fn end_stream(&mut self, stream_id: u32) -> Result<(), ProxyError> {
+        self.send_data(stream_id, Bytes::new(), true)
+    }
 
-        Box::new(crate::proxy::layer::SimpleCommandGenerator::new(commands))
+    /// Send trailers on a stream, which ends our side of it
+    pub fn send_trailers(&mut self, stream_id: u32, trailers: http::HeaderMap) -> Result<(), ProxyError> {
+        let stream = self.open_stream(stream_id)?;
+        let send = stream
+            .send
+            .as_mut()
+            .ok_or_else(|| ProxyError::Proxy(format!("Headers not sent yet on HTTP/2 stream {}", stream_id)))?;
+        send.send_trailers(trailers).map_err(h2_error)?;
+        stream.local_closed = true;
+        self.drive();
+        Ok(())
     }
 
-    fn handle_stream_reset(&mut self, stream_id: u32, error_code: u32) -> Box<dyn CommandGenerator<()>> {
-        let stream_id = stream_id as StreamId;
+    /// Reset a stream with RST_STREAM
+    pub fn reset_stream(&mut self, stream_id: u32, reason: h2::Reason) {
+        if let Some(mut stream) = self.streams.remove(&stream_id) {
+            match (stream.send.as_mut(), stream.respond.as_mut()) {
+                (Some(send), _) => send.send_reset(reason),
+                (None, Some(respond)) => respond.send_reset(reason),
+                (None, None) => {}
+            }
+            self.drive();
+        }
+    }
 
-        if !self.streams.contains_key(&stream_id) {
-            // We don't track priority frames which could be followed by a stream reset
-            return Box::new(SimpleCommandGenerator::new(vec![]));
+    /// Close the connection with GOAWAY, matching h2's close_connection
+    pub fn close_connection(&mut self, reason: h2::Reason) {
+        match &mut self.role {
+            H2Role::Server(connection) => {
+                connection.abrupt_shutdown(reason);
+                self.drive();
+            }
+            H2Role::Client(..) => {
+                // h2 only sends GOAWAY for clients when it detects an error
+                // itself; we never accept streams from the server
+                let mut payload = 0_u32.to_be_bytes().to_vec();
+                payload.extend_from_slice(&u32::from(reason).to_be_bytes());
+                let mut buffers = self.io.buffers();
+                buffers.outbound.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+                buffers.outbound.extend_from_slice(&[H2_FRAME_GOAWAY, 0, 0, 0, 0, 0]);
+                buffers.outbound.extend_from_slice(&payload);
+            }
+            _ => {}
         }
+        self.role = H2Role::Closed;
+        self.streams.clear();
+    }
 
-        self.streams.remove(&stream_id);
+    fn open_stream(&mut self, stream_id: u32) -> Result<&mut H2Stream, ProxyError> {
+        self.streams
+            .get_mut(&stream_id)
+            .filter(|s| !s.local_closed)
+            .ok_or_else(|| ProxyError::Proxy(format!("HTTP/2 stream {} is not open for sending", stream_id)))
+    }
 
-        let err_code = match error_code {
-            0x8 => ErrorCode::Cancel, // CANCEL
-            0xD => ErrorCode::Http11Required, // HTTP_1_1_REQUIRED
-            _ => ErrorCode::GenericClientError,
+    /// Queue a PING, unless one is still unanswered. Returns false if the
+    /// connection cannot send PINGs anymore.
+    pub fn ping(&mut self) -> bool {
+        let Some(ping_pong) = self.ping_pong.as_mut() else {
+            return false;
         };
-
-        let err_str = format!("0x{:x}", error_code);
-
-        let commands = vec![
-            Box::new(ReceiveHttp {
-                event: Box::new(RequestProtocolError {
-                    stream_id,
-                    message: format!("stream reset by client ({})", err_str),
-                    code: err_code,
-                }),
-            }) as Box<dyn Command>
-        ];
-        Box::new(SimpleCommandGenerator::new(commands))
+        // The pong of the previous ping must be collected first
+        let _ = ping_pong.poll_pong(&mut TaskContext::from_waker(Waker::noop()));
+        match ping_pong.send_ping(h2::Ping::opaque()) {
+            Ok(()) => {
+                self.drive();
+                true
+            }
+            // Only a closed connection fails; otherwise a ping is in flight
+            Err(e) => !e.is_io(),
+        }
     }
 
-    fn handle_settings_changed(&mut self) -> Box<dyn CommandGenerator<()>> {
-        // Settings frames are handled automatically by the h2 library
-        Box::new(SimpleCommandGenerator::new(vec![]))
+    /// Get data to send to the network
+    pub fn data_to_send(&mut self) -> Option<Bytes> {
+        let outbound = std::mem::take(&mut self.io.buffers().outbound);
+        (!outbound.is_empty()).then(|| Bytes::from(outbound))
     }
+}
 
-    fn handle_go_away(&mut self, error_code: u32, last_stream_id: u32) -> Box<dyn CommandGenerator<()>> {
-        // Close all streams >= last_stream_id
-        let streams_to_close: Vec<StreamId> = self.streams.keys()
-            .filter(|&&id| id >= last_stream_id as StreamId)
-            .cloned()
-            .collect();
+/// HTTP/2 connection base class, matching Python's Http2Connection
+#[derive(Debug)]
+pub struct Http2Connection {
+    pub context: Context,
+    pub conn: Arc<Connection>,
+    pub h2_conn: BufferedH2Connection,
+    /// Receive state of each h2 stream
+    pub streams: HashMap<StreamId, Http2StreamState>,
+    /// Stream id of the HTTP layer for h2 streams we opened; other streams
+    /// keep their id
+    pub their_stream_id: HashMap<u32, StreamId>,
+    pub debug: bool,
+    pub config: Http2Config,
+    pub receive_protocol_error: fn(StreamId, String, ErrorCode) -> Box<dyn HttpEvent>,
+    pub receive_data: fn(StreamId, Bytes) -> Box<dyn HttpEvent>,
+    pub receive_trailers: fn(StreamId, http::HeaderMap) -> Box<dyn HttpEvent>,
+    pub receive_end_of_message: fn(StreamId) -> Box<dyn HttpEvent>,
+}
 
-        let mut commands = Vec::new();
-        for stream_id in streams_to_close {
-            self.streams.remove(&stream_id);
-            commands.push(Box::new(ReceiveHttp {
-                event: Box::new(RequestProtocolError {
-                    stream_id,
-                    message: format!("HTTP/2 connection closed: 0x{:x}", error_code),
-                    code: ErrorCode::GenericClientError,
-                }),
-            }) as Box<dyn Command>);
+impl Http2Connection {
+    pub fn new(context: Context, conn: Arc<Connection>, config: Http2Config) -> Self {
+        let h2_conn = if config.client_side {
+            BufferedH2Connection::new()
+        } else {
+            BufferedH2Connection::server()
+        };
+
+        let debug = context.options.proxy_debug;
+        // A client receives responses, a server requests
+        if config.client_side {
+            Self {
+                context,
+                conn,
+                h2_conn,
+                streams: HashMap::new(),
+                their_stream_id: HashMap::new(),
+                debug,
+                config,
+                receive_protocol_error: |stream_id, message, code| Box::new(ResponseProtocolError { stream_id, message, code }),
+                receive_data: |stream_id, data| Box::new(ResponseData { stream_id, data }),
+                receive_trailers: |stream_id, trailers| Box::new(ResponseTrailers { stream_id, trailers }),
+                receive_end_of_message: |stream_id| Box::new(ResponseEndOfMessage { stream_id }),
+            }
+        } else {
+            Self {
+                context,
+                conn,
+                h2_conn,
+                streams: HashMap::new(),
+                their_stream_id: HashMap::new(),
+                debug,
+                config,
+                receive_protocol_error: |stream_id, message, code| Box::new(RequestProtocolError { stream_id, message, code }),
+                receive_data: |stream_id, data| Box::new(RequestData { stream_id, data }),
+                receive_trailers: |stream_id, trailers| Box::new(RequestTrailers { stream_id, trailers }),
+                receive_end_of_message: |stream_id| Box::new(RequestEndOfMessage { stream_id }),
+            }
         }
+    }
 
-        commands.push(Box::new(CloseConnection {
-            connection: (*self.conn).clone(),
-        }) as Box<dyn Command>);
+    /// Stream id of the HTTP layer for an h2 stream
+    pub fn their_stream(&self, stream_id: u32) -> StreamId {
+        self.their_stream_id.get(&stream_id).copied().unwrap_or(stream_id as StreamId)
+    }
 
-        Box::new(SimpleCommandGenerator::new(commands))
+    /// Check if a stream is closed, matching Python's is_closed method
+    pub fn is_closed(&self, stream_id: u32) -> bool {
+        self.h2_conn.is_closed(stream_id)
     }
 
-    fn handle_window_update(&mut self, _stream_id: u32) -> Box<dyn CommandGenerator<()>> {
-        // Window update frames are handled automatically by the h2 library
-        Box::new(SimpleCommandGenerator::new(vec![]))
+    /// Check if we can write to a stream, matching Python's is_open_for_us method
+    pub fn is_open_for_us(&self, stream_id: u32) -> bool {
+        self.h2_conn.is_open_for_us(stream_id)
     }
 
-    fn handle_ping(&mut self, _ack: bool, _data: [u8; 8]) -> Box<dyn CommandGenerator<()>> {
-        // Ping frames are handled automatically by the h2 library
-        Box::new(SimpleCommandGenerator::new(vec![]))
+    /// Error code of protocol errors reported to the HTTP layer
+    fn generic_error(&self) -> ErrorCode {
+        if self.config.client_side {
+            ErrorCode::GenericServerError
+        } else {
+            ErrorCode::GenericClientError
+        }
     }
 
-    #[allow(dead_code)] // TODO: Use in handle_headers_received
-    fn parse_h2_headers_from_vec(&self, headers: Vec<(Bytes, Bytes)>) -> Result<(http::HeaderMap, HashMap<String, String>), ProxyError> {
-        let mut header_map = http::HeaderMap::new();
-        let mut pseudo_headers = HashMap::new();
+    /// Drop the state of a stream h2 considers closed
+    fn forget_if_closed(&mut self, stream_id: u32) {
+        if self.h2_conn.is_closed(stream_id) {
+            self.streams.remove(&(stream_id as StreamId));
+            self.their_stream_id.remove(&stream_id);
+        }
+    }
 
-        for (name_bytes, value_bytes) in headers {
-            let name_str = std::str::from_utf8(&name_bytes)
-                .map_err(|_| ProxyError::Proxy("Invalid header name encoding".to_string()))?;
+    /// Handle HTTP/2 events, matching Python's handle_h2_event method.
+    /// Headers are handled by the server and client layers.
+    pub fn handle_h2_event(&mut self, event: H2Event) -> Box<dyn crate::proxy::layer::CommandGenerator<()>> {
+        if self.debug {
+            let peer = if self.config.client_side { "server" } else { "client" };
+            debug!(target: logging::HTTP2, "{} << {:?}", peer, event);
+        }
+        match event {
+            H2Event::DataReceived { stream_id, data, .. } => {
+                self.handle_data_received(stream_id, data)
+            }
+            H2Event::TrailersReceived { stream_id, trailers } => {
+                self.handle_trailers_received(stream_id, trailers)
+            }
+            H2Event::StreamEnded { stream_id } => {
+                self.handle_stream_ended(stream_id)
+            }
+            H2Event::StreamReset { stream_id, error_code } => {
+                self.handle_stream_reset(stream_id, error_code)
+            }
+            H2Event::GoAway { error_code, last_stream_id }
+            | H2Event::ConnectionTerminated { error_code, last_stream_id } => {
+                self.handle_go_away(error_code, last_stream_id)
+            }
+            H2Event::ProtocolError { message } => {
+                self.protocol_error(message, Some(h2::Reason::PROTOCOL_ERROR))
+            }
+            // SETTINGS, WINDOW_UPDATE and PING frames are handled by h2
+            H2Event::HeadersReceived { .. } | H2Event::SettingsChanged | H2Event::WindowUpdate { .. } | H2Event::Ping { .. } => {
+                Box::new(SimpleCommandGenerator::empty())
+            }
+        }
+    }
 
-            if name_str.starts_with(':') {
-                if pseudo_headers.contains_key(name_str) {
-                    return Err(ProxyError::Proxy(format!("Duplicate HTTP/2 pseudo header: {}", name_str)));
-                }
-                let value_str = std::str::from_utf8(&value_bytes)
-                    .map_err(|_| ProxyError::Proxy("Invalid pseudo header value encoding".to_string()))?;
-                pseudo_headers.insert(name_str.to_string(), value_str.to_string());
-            } else {
-                let name = http::HeaderName::from_bytes(&name_bytes)
-                    .map_err(|_| ProxyError::Proxy(format!("Invalid header name: {}", name_str)))?;
-                let value = http::HeaderValue::from_bytes(&value_bytes)
-                    .map_err(|_| ProxyError::Proxy("Invalid header value".to_string()))?;
-                header_map.insert(name, value);
+    fn handle_data_received(&mut self, stream_id: u32, data: Bytes) -> Box<dyn CommandGenerator<()>> {
+        match self.streams.get(&(stream_id as StreamId)) {
+            Some(Http2StreamState::HeadersReceived) => Box::new(SimpleCommandGenerator::new(vec![
+                Box::new(ReceiveHttp { event: (self.receive_data)(self.their_stream(stream_id), data) }) as Box<dyn Command>,
+            ])),
+            Some(Http2StreamState::ExpectingHeaders) => {
+                self.protocol_error("Received HTTP/2 data frame, expected headers.".to_string(), Some(h2::Reason::PROTOCOL_ERROR))
             }
+            None => Box::new(SimpleCommandGenerator::empty()),
         }
+    }
 
-        Ok((header_map, pseudo_headers))
+    fn handle_trailers_received(&mut self, stream_id: u32, trailers: http::HeaderMap) -> Box<dyn CommandGenerator<()>> {
+        if self.streams.get(&(stream_id as StreamId)) != Some(&Http2StreamState::HeadersReceived) {
+            return Box::new(SimpleCommandGenerator::empty());
+        }
+        Box::new(SimpleCommandGenerator::new(vec![
+            Box::new(ReceiveHttp { event: (self.receive_trailers)(self.their_stream(stream_id), trailers) }) as Box<dyn Command>,
+        ]))
     }
 
-    #[allow(dead_code)] // TODO: Use in handle_headers_received
-    fn create_request_from_headers(&self, pseudo_headers: HashMap<String, String>, headers: http::HeaderMap) -> Result<HTTPRequest, ProxyError> {
-        let method = pseudo_headers.get(":method")
-            .ok_or_else(|| ProxyError::Proxy("Required pseudo header is missing: :method".to_string()))?;
-        let scheme = pseudo_headers.get(":scheme")
-            .ok_or_else(|| ProxyError::Proxy("Required pseudo header is missing: :scheme".to_string()))?;
-        let path = pseudo_headers.get(":path")
-            .ok_or_else(|| ProxyError::Proxy("Required pseudo header is missing: :path".to_string()))?;
-        let authority = pseudo_headers.get(":authority");
+    fn handle_stream_ended(&mut self, stream_id: u32) -> Box<dyn CommandGenerator<()>> {
+        let mut commands = Vec::new();
+        if self.streams.get(&(stream_id as StreamId)) == Some(&Http2StreamState::HeadersReceived) {
+            commands.push(Box::new(ReceiveHttp {
+                event: (self.receive_end_of_message)(self.their_stream(stream_id)),
+            }) as Box<dyn Command>);
+        }
+        self.forget_if_closed(stream_id);
+        Box::new(SimpleCommandGenerator::new(commands))
+    }
 
-        if !pseudo_headers.is_empty() {
-            return Err(ProxyError::Proxy(format!("Unknown pseudo headers: {:?}", pseudo_headers.keys())));
+    fn handle_stream_reset(&mut self, stream_id: u32, error_code: u32) -> Box<dyn CommandGenerator<()>> {
+        if !self.streams.contains_key(&(stream_id as StreamId)) {
+            // We don't track priority frames which could be followed by a stream reset
+            return Box::new(SimpleCommandGenerator::new(vec![]));
         }
 
-        let (host, port) = if let Some(auth) = authority {
-            parse_authority(auth, true)
-                .map_err(|e| ProxyError::Proxy(format!("Invalid authority: {}", e)))?
-        } else {
-            ("".to_string(), 0)
+        let err_code = match h2::Reason::from(error_code) {
+            h2::Reason::CANCEL => ErrorCode::Cancel,
+            h2::Reason::HTTP_1_1_REQUIRED => ErrorCode::Http11Required,
+            _ => self.generic_error(),
         };
+        let peer = if self.config.client_side { "server" } else { "client" };
+        let event = (self.receive_protocol_error)(
+            self.their_stream(stream_id),
+            format!("stream reset by {} ({:?})", peer, h2::Reason::from(error_code)),
+            err_code,
+        );
+        self.streams.remove(&(stream_id as StreamId));
+        self.their_stream_id.remove(&stream_id);
+        Box::new(SimpleCommandGenerator::new(vec![Box::new(ReceiveHttp { event }) as Box<dyn Command>]))
+    }
 
-        // Build URL
-        let url_str = if !host.is_empty() && port != 0 {
-            format!("{}://{}:{}{}", scheme, host, port, path)
-        } else if !host.is_empty() {
-            format!("{}://{}{}", scheme, host, path)
-        } else {
-            format!("{}://{}", scheme, path)
-        };
+    fn handle_go_away(&mut self, error_code: u32, last_stream_id: u32) -> Box<dyn CommandGenerator<()>> {
+        self.close_connection(format!(
+            "HTTP/2 connection closed: GOAWAY ({:?}, last stream {})",
+            h2::Reason::from(error_code),
+            last_stream_id
+        ))
+    }
 
-        let _url = url::Url::parse(&url_str)
-            .map_err(|e| ProxyError::Proxy(format!("Invalid URL: {}", e)))?;
+    /// Send HTTP/2 frame data, matching Python's data_to_send method
+    pub fn data_to_send(&mut self) -> Option<Bytes> {
+        self.h2_conn.data_to_send()
+    }
 
-        // Convert headers
-        let mut header_vec = Vec::new();
-        for (name, value) in headers.iter() {
-            if !name.as_str().starts_with(':') {
-                if let Ok(v) = value.to_str() {
-                    header_vec.push((name.as_str().to_lowercase(), v.to_string()));
-                }
-            }
+    /// Append a SendData command for frames h2 wrote, if any
+    pub fn push_data_to_send(&mut self, commands: &mut Vec<Box<dyn Command>>) {
+        if let Some(data) = self.data_to_send() {
+            commands.push(Box::new(SendData {
+                connection: (*self.conn).clone(),
+                data: data.to_vec(),
+            }));
         }
+    }
 
-        let mut request = HTTPRequest::new(
-            method.clone(),
-            scheme.to_string(),
-            host.clone(),
-            port,
-            path.to_string(),
-        );
-        request.http_version = "HTTP/2.0".to_string();
-        request.headers = header_vec;
-        request.timestamp_start = Some(SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f64());
-        Ok(request)
+    /// Send the frames h2 wrote, if any
+    pub fn flush(&mut self) -> Box<dyn CommandGenerator<()>> {
+        let mut commands = Vec::new();
+        self.push_data_to_send(&mut commands);
+        Box::new(SimpleCommandGenerator::new(commands))
     }
 
-    /// Send HTTP/2 frame data, matching Python's data_to_send method
-    pub fn data_to_send(&mut self) -> Option<Bytes> {
-        // TODO: Implement proper data sending with h2 library
-        None
+    /// Reset a stream we failed to send on and report the failure to the
+    /// HTTP layer
+    pub fn stream_error(&mut self, stream_id: u32, error: ProxyError) -> Box<dyn CommandGenerator<()>> {
+        warn!(target: logging::HTTP2, "HTTP/2 stream {} failed: {}", stream_id, error);
+        self.h2_conn.reset_stream(stream_id, h2::Reason::INTERNAL_ERROR);
+        let mut commands: Vec<Box<dyn Command>> = Vec::new();
+        self.push_data_to_send(&mut commands);
+        if self.streams.remove(&(stream_id as StreamId)).is_some() {
+            commands.push(Box::new(ReceiveHttp {
+                event: (self.receive_protocol_error)(self.their_stream(stream_id), error.to_string(), self.generic_error()),
+            }));
+        }
+        self.their_stream_id.remove(&stream_id);
+        Box::new(SimpleCommandGenerator::new(commands))
     }
 
     /// Close connection with error, matching Python's protocol_error method
-    pub fn protocol_error(&mut self, message: String, _error_code: Option<h2::Reason>) -> Box<dyn CommandGenerator<()>> {
-        warn!("HTTP/2 protocol error: {}", message);
-
-        // Send GOAWAY frame
-        // TODO: Implement GOAWAY sending with h2 library
-
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(Log {
-                message: format!("HTTP/2 protocol error: {}", message),
-                level: LogLevel::Error,
-            }),
-            Box::new(CloseConnection {
-                connection: (*self.conn).clone(),
-            }),
-        ]))
+    pub fn protocol_error(&mut self, message: String, error_code: Option<h2::Reason>) -> Box<dyn CommandGenerator<()>> {
+        warn!(target: logging::HTTP2, "HTTP/2 protocol error: {}", message);
+
+        let mut commands: Vec<Box<dyn Command>> = vec![Box::new(Log {
+            message: format!("HTTP/2 protocol error: {}", message),
+            level: LogLevel::Error,
+        })];
+        self.h2_conn.close_connection(error_code.unwrap_or(h2::Reason::PROTOCOL_ERROR));
+        self.push_data_to_send(&mut commands);
+        let mut close = self.close_connection(message);
+        commands.extend(std::iter::from_fn(|| close.next_command()));
+        Box::new(SimpleCommandGenerator::new(commands))
     }
 
     /// Close connection, matching Python's close_connection method
@@ -2962,17 +3957,16 @@ impl Http2Connection {
         ];
 
         // Send protocol errors for all active streams
-        for stream_id in self.streams.keys().cloned().collect::<Vec<_>>() {
+        let mut stream_ids: Vec<StreamId> = self.streams.keys().copied().collect();
+        stream_ids.sort_unstable();
+        for stream_id in stream_ids {
             commands.push(Box::new(ReceiveHttp {
-                event: Box::new(RequestProtocolError {
-                    stream_id,
-                    message: msg.clone(),
-                    code: ErrorCode::GenericClientError,
-                }),
+                event: (self.receive_protocol_error)(self.their_stream(stream_id as u32), msg.clone(), self.generic_error()),
             }) as Box<dyn Command>);
         }
 
         self.streams.clear();
+        self.their_stream_id.clear();
         Box::new(SimpleCommandGenerator::new(commands))
     }
 }
@@ -2981,32 +3975,30 @@ impl Http2Connection {
 #[derive(Debug)]
 pub struct Http2Server {
     pub base: Http2Connection,
-    pub receive_protocol_error: fn(StreamId, String, ErrorCode) -> Box<dyn HttpEvent>,
-    pub receive_data: fn(StreamId, Bytes) -> Box<dyn HttpEvent>,
-    pub receive_trailers: fn(StreamId, http::HeaderMap) -> Box<dyn HttpEvent>,
-    pub receive_end_of_message: fn(StreamId) -> Box<dyn HttpEvent>,
 }
 
 impl Http2Server {
     pub fn new(context: Context) -> Self {
         let config = Http2Config::default();
-        let base = Http2Connection::new(context, Arc::new(Connection::default()), config);
+        let conn = context.client.connection.clone();
+        let base = Http2Connection::new(context, Arc::new(conn), config);
 
-        Self {
-            base,
-            receive_protocol_error: |stream_id, message, code| Box::new(RequestProtocolError { stream_id, message, code }),
-            receive_data: |stream_id, data| Box::new(RequestData { stream_id, data }),
-            receive_trailers: |stream_id, trailers| Box::new(RequestTrailers { stream_id, trailers }),
-            receive_end_of_message: |stream_id| Box::new(RequestEndOfMessage { stream_id }),
-        }
+        Self { base }
     }
 
     /// Handle HTTP/2 request received event, matching Python's handle_h2_event for RequestReceived
-    pub fn handle_request_received(&mut self, headers: Vec<(Bytes, Bytes)>) -> Box<dyn CommandGenerator<()>> {
+    pub fn handle_request_received(&mut self, stream_id: u32, headers: Vec<(Bytes, Bytes)>, end_stream: bool) -> Box<dyn CommandGenerator<()>> {
         let (host, port, method, scheme, _authority, path, headers) = match parse_h2_request_headers(headers) {
             Ok(result) => result,
             Err(e) => {
-                return self.base.protocol_error(e.to_string(), Some(h2::Reason::PROTOCOL_ERROR));
+                // Only the stream is broken, not the connection
+                self.base.h2_conn.reset_stream(stream_id, h2::Reason::PROTOCOL_ERROR);
+                let mut commands: Vec<Box<dyn Command>> = vec![Box::new(Log {
+                    message: format!("Invalid HTTP/2 request headers: {}", e),
+                    level: LogLevel::Info,
+                })];
+                self.base.push_data_to_send(&mut commands);
+                return Box::new(SimpleCommandGenerator::new(commands));
             }
         };
 
@@ -3024,60 +4016,21 @@ impl Http2Server {
                 request.headers.push((name.to_string(), value_str.to_string()));
             }
         }
-        request.timestamp_start = Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64());
+        request.timestamp_start = Some(self.base.context.clock.now());
 
-        // TODO: Get next available stream ID
-        let stream_id = 1;
-        self.base.streams.insert(stream_id, Http2StreamState::HeadersReceived);
+        self.base.streams.insert(stream_id as StreamId, Http2StreamState::HeadersReceived);
 
         Box::new(SimpleCommandGenerator::new(vec![
             Box::new(ReceiveHttp {
                 event: Box::new(RequestHeaders {
-                    stream_id,
+                    stream_id: stream_id as StreamId,
                     request,
-                    end_stream: false, // TODO: Determine from headers
+                    end_stream,
                     replay_flow: None,
                 }),
             }) as Box<dyn Command>
         ]))
     }
-
-    /// Handle HTTP/2 informational response, matching Python's handle_h2_event for InformationalResponseReceived
-    pub fn handle_informational_response(&mut self, headers: Vec<(Bytes, Bytes)>) -> Box<dyn CommandGenerator<()>> {
-        // HTTP/2 informational responses are swallowed (not forwarded)
-        let (pseudo_headers, _headers_map) = match split_pseudo_headers(headers) {
-            Ok(result) => result,
-            Err(e) => {
-                return self.base.protocol_error(e.to_string(), Some(h2::Reason::PROTOCOL_ERROR));
-            }
-        };
-        let status = pseudo_headers.get(":status")
-            .and_then(|s| String::from_utf8_lossy(s).parse::<u16>().ok())
-            .unwrap_or(0);
-
-        let reason = match status {
-            100 => "Continue",
-            101 => "Switching Protocols",
-            102 => "Processing",
-            103 => "Early Hints",
-            _ => "Unknown",
-        };
-
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(Log {
-                message: format!("Swallowing HTTP/2 informational response: {} {}", status, reason),
-                level: LogLevel::Info,
-            })
-        ]))
-    }
-
-    /// Handle HTTP/2 request from server (protocol error), matching Python's handle_h2_event for RequestReceived on client
-    pub fn handle_request_from_server(&mut self) -> Box<dyn CommandGenerator<()>> {
-        self.base.protocol_error(
-            "HTTP/2 protocol error: received request from server".to_string(),
-            Some(h2::Reason::PROTOCOL_ERROR)
-        )
-    }
 }
 
 impl Layer for Http2Server {
@@ -3094,11 +4047,12 @@ impl Layer for Http2Server {
 impl Http2Server {
     /// Handle event and route to appropriate handler based on event type
     fn sync_handle_event(&mut self, event: Box<dyn Event>) -> Box<dyn CommandGenerator<()>> {
-        debug!("Http2Server handling event: {:?}", std::any::type_name_of_val(&*event));
+        debug!(target: logging::HTTP2, "Http2Server handling event: {:?}", std::any::type_name_of_val(&*event));
 
-        // Handle Start event
+        // Send our SETTINGS; the client preface follows
         if event.as_any().downcast_ref::<Start>().is_some() {
-            return Box::new(SimpleCommandGenerator::empty());
+            self.base.h2_conn.initiate_connection();
+            return self.base.flush();
         }
 
         // Handle DataReceived for H2 frame processing
@@ -3108,13 +4062,18 @@ impl Http2Server {
                 Ok(h2_events) => {
                     let mut all_commands: Vec<Box<dyn Command>> = Vec::new();
                     for h2_event in h2_events {
-                        let gen = self.base.handle_h2_event(h2_event);
-                        // Extract commands from generator
-                        let mut gen = gen;
+                        let mut gen = match h2_event {
+                            H2Event::HeadersReceived { stream_id, headers, end_stream } => {
+                                self.handle_request_received(stream_id, headers, end_stream)
+                            }
+                            other => self.base.handle_h2_event(other),
+                        };
                         while let Some(cmd) = gen.next_command() {
                             all_commands.push(cmd);
                         }
                     }
+                    // Acknowledgements and window updates
+                    self.base.push_data_to_send(&mut all_commands);
                     return Box::new(SimpleCommandGenerator::new(all_commands));
                 }
                 Err(e) => {
@@ -3130,6 +4089,9 @@ impl Http2Server {
         if let Some(resp_data) = event.as_any().downcast_ref::<ResponseData>() {
             return self.handle_response_data(resp_data.clone());
         }
+        if let Some(resp_trailers) = event.as_any().downcast_ref::<ResponseTrailers>() {
+            return self.handle_response_trailers(resp_trailers.clone());
+        }
         if let Some(resp_end) = event.as_any().downcast_ref::<ResponseEndOfMessage>() {
             return self.handle_response_end(resp_end.clone());
         }
@@ -3142,87 +4104,89 @@ impl Http2Server {
             return self.base.close_connection("Connection closed".to_string());
         }
 
-        warn!("Http2Server received unhandled event: {:?}", std::any::type_name_of_val(&*event));
+        warn!(target: logging::HTTP2, "Http2Server received unhandled event: {:?}", std::any::type_name_of_val(&*event));
         Box::new(SimpleCommandGenerator::empty())
     }
 
+    /// Finish sending on a stream, reporting errors to the HTTP layer
+    fn sent(&mut self, stream_id: u32, result: Result<(), ProxyError>) -> Box<dyn CommandGenerator<()>> {
+        if let Err(e) = result {
+            return self.base.stream_error(stream_id, e);
+        }
+        self.base.forget_if_closed(stream_id);
+        self.base.flush()
+    }
+
     fn handle_response_headers(&mut self, event: ResponseHeaders) -> Box<dyn CommandGenerator<()>> {
-        if !self.base.is_open_for_us(event.stream_id) {
+        let stream_id = event.stream_id as u32;
+        if !self.base.is_open_for_us(stream_id) {
             return Box::new(SimpleCommandGenerator::empty());
         }
 
-        let _headers = match format_h2_response_headers(&self.base.context, &event) {
-            Ok(h) => h,
-            Err(e) => return self.base.protocol_error(e.to_string(), Some(h2::Reason::PROTOCOL_ERROR)),
-        };
-        // TODO: Send headers using h2 library
-
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(SendData {
-                connection: (*self.base.conn).clone(),
-                data: self.base.data_to_send().unwrap_or_default().to_vec(),
-            })
-        ]))
+        let result = format_h2_response_headers(&self.base.context, &event)
+            .and_then(|headers| self.base.h2_conn.send_headers(stream_id, headers, event.end_stream));
+        self.sent(stream_id, result)
     }
 
     fn handle_response_data(&mut self, event: ResponseData) -> Box<dyn CommandGenerator<()>> {
-        if !self.base.is_open_for_us(event.stream_id) {
+        let stream_id = event.stream_id as u32;
+        if !self.base.is_open_for_us(stream_id) {
             return Box::new(SimpleCommandGenerator::empty());
         }
 
-        // TODO: Send data using h2 library
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(SendData {
-                connection: (*self.base.conn).clone(),
-                data: self.base.data_to_send().unwrap_or_default().to_vec(),
-            })
-        ]))
+        let result = self.base.h2_conn.send_data(stream_id, event.data, false);
+        self.sent(stream_id, result)
+    }
+
+    fn handle_response_trailers(&mut self, event: ResponseTrailers) -> Box<dyn CommandGenerator<()>> {
+        let stream_id = event.stream_id as u32;
+        if !self.base.is_open_for_us(stream_id) {
+            return Box::new(SimpleCommandGenerator::empty());
+        }
+
+        let result = self.base.h2_conn.send_trailers(stream_id, event.trailers);
+        self.sent(stream_id, result)
     }
 
     fn handle_response_end(&mut self, event: ResponseEndOfMessage) -> Box<dyn CommandGenerator<()>> {
-        if !self.base.is_open_for_us(event.stream_id) {
+        let stream_id = event.stream_id as u32;
+        // Trailers already ended the stream
+        if !self.base.is_open_for_us(stream_id) {
             return Box::new(SimpleCommandGenerator::empty());
         }
 
-        // TODO: End stream using h2 library
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(SendData {
-                connection: (*self.base.conn).clone(),
-                data: self.base.data_to_send().unwrap_or_default().to_vec(),
-            })
-        ]))
+        let result = self.base.h2_conn.end_stream(stream_id);
+        self.sent(stream_id, result)
     }
 
     fn handle_response_error(&mut self, event: ResponseProtocolError) -> Box<dyn CommandGenerator<()>> {
-        if self.base.is_closed(event.stream_id) {
+        let stream_id = event.stream_id as u32;
+        if self.base.is_closed(stream_id) {
             return Box::new(SimpleCommandGenerator::empty());
         }
 
+        // Answer with an error page if the response has not started yet
         let status = event.code.http_status_code();
-        if self.base.is_open_for_us(event.stream_id) && status.is_some() && self.base.streams[&event.stream_id] != Http2StreamState::ExpectingHeaders {
-            // Send error response headers
-            // TODO: Send error headers using h2 library
+        if let Some(status) = status.filter(|_| self.base.is_open_for_us(stream_id) && !self.base.h2_conn.headers_sent(stream_id)) {
+            let headers = vec![
+                (Bytes::from_static(b":status"), Bytes::from(status.to_string())),
+                (Bytes::from_static(b"server"), Bytes::from(format!("mitmproxy-rs {}", env!("CARGO_PKG_VERSION")))),
+                (Bytes::from_static(b"content-type"), Bytes::from_static(b"text/html")),
+            ];
+            let result = self
+                .base
+                .h2_conn
+                .send_headers(stream_id, headers, false)
+                .and_then(|()| self.base.h2_conn.send_data(stream_id, Bytes::from(format_error(status, &event.message)), true));
+            if let Err(e) = result {
+                warn!(target: logging::HTTP2, "Cannot send HTTP/2 error response: {}", e);
+                self.base.h2_conn.reset_stream(stream_id, h2::Reason::INTERNAL_ERROR);
+            }
         } else {
-            let _error_code = match event.code {
-                ErrorCode::Cancel | ErrorCode::ClientDisconnected => h2::Reason::CANCEL,
-                ErrorCode::Kill => h2::Reason::INTERNAL_ERROR,
-                ErrorCode::Http11Required => h2::Reason::HTTP_1_1_REQUIRED,
-                ErrorCode::PassthroughClose => h2::Reason::CANCEL,
-                ErrorCode::GenericClientError | ErrorCode::GenericServerError |
-                ErrorCode::RequestTooLarge | ErrorCode::ResponseTooLarge |
-                ErrorCode::ConnectFailed | ErrorCode::DestinationUnknown |
-                ErrorCode::RequestValidationFailed | ErrorCode::ResponseValidationFailed => h2::Reason::INTERNAL_ERROR,
-            };
-
-            // TODO: Reset stream using h2 library
+            self.base.h2_conn.reset_stream(stream_id, event.code.h2_reason());
         }
-
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(SendData {
-                connection: (*self.base.conn).clone(),
-                data: self.base.data_to_send().unwrap_or_default().to_vec(),
-            })
-        ]))
+        self.base.streams.remove(&event.stream_id);
+        self.base.flush()
     }
 }
 
@@ -3231,40 +4195,30 @@ impl Http2Server {
 pub struct Http2Client {
     pub base: Http2Connection,
     pub our_stream_id: HashMap<StreamId, u32>,
-    pub their_stream_id: HashMap<u32, StreamId>,
-    pub stream_queue: HashMap<StreamId, Vec<Box<dyn Event>>>,
-    pub provisional_max_concurrency: Option<u32>,
-    pub last_activity: f64,
-    pub receive_protocol_error: fn(StreamId, String, ErrorCode) -> Box<dyn HttpEvent>,
-    pub receive_data: fn(StreamId, Bytes) -> Box<dyn HttpEvent>,
-    pub receive_trailers: fn(StreamId, http::HeaderMap) -> Box<dyn HttpEvent>,
-    pub receive_end_of_message: fn(StreamId) -> Box<dyn HttpEvent>,
+    pub stream_queue: HashMap<StreamId, Vec<Box<dyn Event>>>,
+    pub provisional_max_concurrency: Option<u32>,
+    pub last_activity: f64,
 }
 
 impl Http2Client {
     pub fn new(context: Context) -> Self {
-        let config = Http2Config::default();
-        let base = Http2Connection::new(context, Arc::new(Connection::default()), config);
-
-        // Disable HTTP/2 push
-        // TODO: Configure h2 connection to disable push
+        let config = Http2Config { client_side: true, ..Default::default() };
+        let conn = context.server.as_ref().map(|server| server.connection.clone()).unwrap_or_default();
+        let last_activity = context.clock.now();
+        // Server push is disabled in the SETTINGS sent with the preface
+        let base = Http2Connection::new(context, Arc::new(conn), config);
 
         Self {
             base,
             our_stream_id: HashMap::new(),
-            their_stream_id: HashMap::new(),
             stream_queue: HashMap::new(),
             provisional_max_concurrency: Some(10),
-            last_activity: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-            receive_protocol_error: |stream_id, message, code| Box::new(ResponseProtocolError { stream_id, message, code }),
-            receive_data: |stream_id, data| Box::new(ResponseData { stream_id, data }),
-            receive_trailers: |stream_id, trailers| Box::new(ResponseTrailers { stream_id, trailers }),
-            receive_end_of_message: |stream_id| Box::new(ResponseEndOfMessage { stream_id }),
+            last_activity,
         }
     }
 
     /// Handle HTTP/2 response received event, matching Python's handle_h2_event for ResponseReceived
-    pub fn handle_response_received(&mut self, headers: Vec<(Bytes, Bytes)>) -> Box<dyn CommandGenerator<()>> {
+    pub fn handle_response_received(&mut self, stream_id: u32, headers: Vec<(Bytes, Bytes)>, end_stream: bool) -> Box<dyn CommandGenerator<()>> {
         let (status_code, headers) = match parse_h2_response_headers(headers) {
             Ok(result) => result,
             Err(e) => return self.base.protocol_error(e.to_string(), Some(h2::Reason::PROTOCOL_ERROR)),
@@ -3278,68 +4232,52 @@ impl Http2Client {
                 response.headers.push((name.to_string(), value_str.to_string()));
             }
         }
-        response.timestamp_start = Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64());
+        response.timestamp_start = Some(self.base.context.clock.now());
 
-        // TODO: Get stream ID from h2 event
-        let stream_id = 1;
-        if self.base.streams.get(&stream_id) != Some(&Http2StreamState::ExpectingHeaders) {
+        let ours = stream_id as StreamId;
+        if self.base.streams.get(&ours) != Some(&Http2StreamState::ExpectingHeaders) {
             return self.base.protocol_error("Received unexpected HTTP/2 response.".to_string(), Some(h2::Reason::PROTOCOL_ERROR));
         }
 
-        self.base.streams.insert(stream_id, Http2StreamState::HeadersReceived);
+        self.base.streams.insert(ours, Http2StreamState::HeadersReceived);
 
         Box::new(SimpleCommandGenerator::new(vec![
             Box::new(ReceiveHttp {
                 event: Box::new(ResponseHeaders {
-                    stream_id,
+                    stream_id: self.base.their_stream(stream_id),
                     response,
-                    end_stream: false, // TODO: Determine from headers
+                    end_stream,
                 }),
             }) as Box<dyn Command>
         ]))
     }
 
-    /// Handle HTTP/2 informational response, matching Python's handle_h2_event for InformationalResponseReceived
-    pub fn handle_informational_response(&mut self, headers: Vec<(Bytes, Bytes)>) -> Box<dyn CommandGenerator<()>> {
-        // HTTP/2 informational responses are swallowed (not forwarded)
-        let (pseudo_headers, _headers_map) = match split_pseudo_headers(headers) {
-            Ok(result) => result,
-            Err(e) => return self.base.protocol_error(e.to_string(), Some(h2::Reason::PROTOCOL_ERROR)),
-        };
-        let status = pseudo_headers.get(":status")
-            .and_then(|s| String::from_utf8_lossy(s).parse::<u16>().ok())
-            .unwrap_or(0);
-
-        let reason = match status {
-            100 => "Continue",
-            101 => "Switching Protocols",
-            102 => "Processing",
-            103 => "Early Hints",
-            _ => "Unknown",
-        };
-
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(Log {
-                message: format!("Swallowing HTTP/2 informational response: {} {}", status, reason),
-                level: LogLevel::Info,
-            })
-        ]))
-    }
-
-    /// Handle HTTP/2 request from server (protocol error), matching Python's handle_h2_event for RequestReceived on client
-    pub fn handle_request_from_server(&mut self) -> Box<dyn CommandGenerator<()>> {
-        self.base.protocol_error(
-            "HTTP/2 protocol error: received request from server".to_string(),
-            Some(h2::Reason::PROTOCOL_ERROR)
-        )
-    }
-
     /// Handle remote settings changed, matching Python's handle_h2_event for RemoteSettingsChanged
     pub fn handle_remote_settings_changed(&mut self) -> Box<dyn CommandGenerator<()>> {
         // We have received at least one settings from now, can rely on max concurrency in remote_settings
         self.provisional_max_concurrency = None;
         Box::new(SimpleCommandGenerator::empty())
     }
+
+    fn no_free_streams(&self) -> bool {
+        self.base.h2_conn.open_outbound_streams()
+            >= self.provisional_max_concurrency.unwrap_or(self.base.h2_conn.remote_settings().max_concurrent_streams)
+    }
+
+    /// Send queued requests once streams became available, oldest first
+    fn process_stream_queue(&mut self) -> Vec<Box<dyn Command>> {
+        let mut commands = Vec::new();
+        while !self.no_free_streams() {
+            let Some(&stream_id) = self.stream_queue.keys().min() else {
+                break;
+            };
+            for event in self.stream_queue.remove(&stream_id).unwrap_or_default() {
+                let mut gen = self.sync_handle_event(event);
+                commands.extend(std::iter::from_fn(|| gen.next_command()));
+            }
+        }
+        commands
+    }
 }
 
 impl Layer for Http2Client {
@@ -3356,11 +4294,23 @@ impl Layer for Http2Client {
 impl Http2Client {
     /// Handle event and route to appropriate handler based on event type
     fn sync_handle_event(&mut self, event: Box<dyn Event>) -> Box<dyn CommandGenerator<()>> {
-        debug!("Http2Client handling event: {:?}", std::any::type_name_of_val(&*event));
+        debug!(target: logging::HTTP2, "Http2Client handling event: {:?}", std::any::type_name_of_val(&*event));
 
-        // Handle Start event
+        if event.as_any().downcast_ref::<Wakeup>().is_some() {
+            return self.keepalive();
+        }
+        self.last_activity = self.base.context.clock.now();
+
+        // Send the connection preface. Over TLS the server has agreed to h2
+        // via ALPN; in plain TCP (h2c) we rely on prior knowledge.
         if event.as_any().downcast_ref::<Start>().is_some() {
-            return Box::new(SimpleCommandGenerator::empty());
+            self.base.h2_conn.initiate_connection();
+            let mut commands: Vec<Box<dyn Command>> = Vec::new();
+            self.base.push_data_to_send(&mut commands);
+            if self.base.context.options.http2_ping_keepalive > 0 {
+                commands.push(Box::new(RequestWakeup { delay: self.base.context.options.http2_ping_keepalive as f64 }));
+            }
+            return Box::new(SimpleCommandGenerator::new(commands));
         }
 
         // Handle DataReceived for H2 frame processing
@@ -3370,13 +4320,21 @@ impl Http2Client {
                 Ok(h2_events) => {
                     let mut all_commands: Vec<Box<dyn Command>> = Vec::new();
                     for h2_event in h2_events {
-                        let gen = self.base.handle_h2_event(h2_event);
-                        // Extract commands from generator
-                        let mut gen = gen;
+                        let mut gen = match h2_event {
+                            H2Event::HeadersReceived { stream_id, headers, end_stream } => {
+                                self.handle_response_received(stream_id, headers, end_stream)
+                            }
+                            H2Event::SettingsChanged => self.handle_remote_settings_changed(),
+                            other => self.base.handle_h2_event(other),
+                        };
                         while let Some(cmd) = gen.next_command() {
                             all_commands.push(cmd);
                         }
                     }
+                    // Acknowledge SETTINGS and PING frames
+                    self.base.push_data_to_send(&mut all_commands);
+                    self.our_stream_id.retain(|_, ours| !self.base.h2_conn.is_closed(*ours));
+                    all_commands.extend(self.process_stream_queue());
                     return Box::new(SimpleCommandGenerator::new(all_commands));
                 }
                 Err(e) => {
@@ -3385,6 +4343,19 @@ impl Http2Client {
             }
         }
 
+        // Events of queued streams wait for their stream to be opened
+        if let Some(http_event) = request_event(&*event) {
+            let stream_id = http_event.stream_id();
+            if let Some(queue) = self.stream_queue.get_mut(&stream_id) {
+                if event.as_any().downcast_ref::<RequestProtocolError>().is_some() {
+                    self.stream_queue.remove(&stream_id);
+                } else {
+                    queue.push(event);
+                }
+                return Box::new(SimpleCommandGenerator::empty());
+            }
+        }
+
         // Handle HTTP events for request transmission
         if let Some(req_headers) = event.as_any().downcast_ref::<RequestHeaders>() {
             return self.handle_request_headers(req_headers.clone());
@@ -3392,12 +4363,14 @@ impl Http2Client {
         if let Some(req_data) = event.as_any().downcast_ref::<RequestData>() {
             return self.handle_request_data(req_data.clone());
         }
+        if let Some(req_trailers) = event.as_any().downcast_ref::<RequestTrailers>() {
+            return self.handle_request_trailers(req_trailers.clone());
+        }
         if let Some(req_end) = event.as_any().downcast_ref::<RequestEndOfMessage>() {
             return self.handle_request_end(req_end.clone());
         }
         if let Some(req_error) = event.as_any().downcast_ref::<RequestProtocolError>() {
-            // Handle request protocol error - close the stream
-            return self.base.protocol_error(req_error.message.clone(), Some(h2::Reason::CANCEL));
+            return self.handle_request_error(req_error.clone());
         }
 
         // Handle connection events
@@ -3405,75 +4378,135 @@ impl Http2Client {
             return self.base.close_connection("Connection closed".to_string());
         }
 
-        warn!("Http2Client received unhandled event: {:?}", std::any::type_name_of_val(&*event));
+        warn!(target: logging::HTTP2, "Http2Client received unhandled event: {:?}", std::any::type_name_of_val(&*event));
         Box::new(SimpleCommandGenerator::empty())
     }
 
-    fn handle_request_headers(&mut self, event: RequestHeaders) -> Box<dyn CommandGenerator<()>> {
-        // Map stream IDs
-        let ours = if let Some(ours) = self.our_stream_id.get(&event.stream_id) {
-            *ours
-        } else {
-            let no_free_streams = self.base.h2_conn.open_outbound_streams >=
-                self.provisional_max_concurrency.unwrap_or(self.base.h2_conn.remote_settings().max_concurrent_streams as u32);
-
-            if no_free_streams {
-                self.stream_queue.entry(event.stream_id).or_insert_with(Vec::new).push(Box::new(event));
+    /// Send a PING if the connection was idle for `http2_ping_keepalive`
+    /// seconds, and ask to be woken up when it next may be
+    fn keepalive(&mut self) -> Box<dyn CommandGenerator<()>> {
+        let keepalive = self.base.context.options.http2_ping_keepalive as f64;
+        if keepalive <= 0.0 {
+            return Box::new(SimpleCommandGenerator::empty());
+        }
+        let now = self.base.context.clock.now();
+        let idle = now - self.last_activity;
+        let mut commands: Vec<Box<dyn Command>> = Vec::new();
+        if idle >= keepalive {
+            if !self.base.h2_conn.ping() {
                 return Box::new(SimpleCommandGenerator::empty());
             }
+            debug!(target: logging::HTTP2, "Sending keepalive PING after {:.0}s idle", idle);
+            self.last_activity = now;
+            self.base.push_data_to_send(&mut commands);
+            commands.push(Box::new(RequestWakeup { delay: keepalive }));
+        } else {
+            commands.push(Box::new(RequestWakeup { delay: keepalive - idle }));
+        }
+        Box::new(SimpleCommandGenerator::new(commands))
+    }
 
-            let ours = self.base.h2_conn.get_next_available_stream_id();
-            self.our_stream_id.insert(event.stream_id, ours);
-            self.their_stream_id.insert(ours, event.stream_id);
-            ours
-        };
+    /// Finish sending on a stream, reporting errors to the HTTP layer
+    fn sent(&mut self, ours: u32, result: Result<(), ProxyError>) -> Box<dyn CommandGenerator<()>> {
+        if let Err(e) = result {
+            return self.base.stream_error(ours, e);
+        }
+        self.base.forget_if_closed(ours);
+        self.base.flush()
+    }
 
-        let _headers = match format_h2_request_headers(&self.base.context, &event) {
-            Ok(h) => h,
-            Err(e) => return self.base.protocol_error(e.to_string(), Some(h2::Reason::PROTOCOL_ERROR)),
+    fn handle_request_headers(&mut self, event: RequestHeaders) -> Box<dyn CommandGenerator<()>> {
+        if self.no_free_streams() {
+            self.stream_queue.entry(event.stream_id).or_default().push(Box::new(event));
+            return Box::new(SimpleCommandGenerator::empty());
+        }
+
+        let result = format_h2_request_headers(&self.base.context, &event)
+            .and_then(|headers| self.base.h2_conn.send_request(headers, event.end_stream));
+        let ours = match result {
+            Ok(ours) => ours,
+            Err(e) => {
+                warn!(target: logging::HTTP2, "Cannot send HTTP/2 request: {}", e);
+                let mut commands: Vec<Box<dyn Command>> = Vec::new();
+                self.base.push_data_to_send(&mut commands);
+                commands.push(Box::new(ReceiveHttp {
+                    event: Box::new(ResponseProtocolError {
+                        stream_id: event.stream_id,
+                        message: e.to_string(),
+                        code: ErrorCode::GenericServerError,
+                    }),
+                }));
+                return Box::new(SimpleCommandGenerator::new(commands));
+            }
         };
-        // TODO: Send headers using h2 library
+        self.our_stream_id.insert(event.stream_id, ours);
+        self.base.their_stream_id.insert(ours, event.stream_id);
         self.base.streams.insert(ours as StreamId, Http2StreamState::ExpectingHeaders);
-
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(SendData {
-                connection: (*self.base.conn).clone(),
-                data: self.base.data_to_send().unwrap_or_default().to_vec(),
-            })
-        ]))
+        self.base.flush()
     }
 
     fn handle_request_data(&mut self, event: RequestData) -> Box<dyn CommandGenerator<()>> {
-        if !self.base.is_open_for_us(event.stream_id) {
+        let Some(ours) = self.our_stream_id.get(&event.stream_id).copied().filter(|&ours| self.base.is_open_for_us(ours)) else {
             return Box::new(SimpleCommandGenerator::empty());
-        }
+        };
 
-        // TODO: Send data using h2 library
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(SendData {
-                connection: (*self.base.conn).clone(),
-                data: self.base.data_to_send().unwrap_or_default().to_vec(),
-            })
-        ]))
+        let result = self.base.h2_conn.send_data(ours, event.data, false);
+        self.sent(ours, result)
+    }
+
+    fn handle_request_trailers(&mut self, event: RequestTrailers) -> Box<dyn CommandGenerator<()>> {
+        let Some(ours) = self.our_stream_id.get(&event.stream_id).copied().filter(|&ours| self.base.is_open_for_us(ours)) else {
+            return Box::new(SimpleCommandGenerator::empty());
+        };
+
+        let result = self.base.h2_conn.send_trailers(ours, event.trailers);
+        self.sent(ours, result)
     }
 
     fn handle_request_end(&mut self, event: RequestEndOfMessage) -> Box<dyn CommandGenerator<()>> {
-        if !self.base.is_open_for_us(event.stream_id) {
+        // Trailers already ended the stream
+        let Some(ours) = self.our_stream_id.get(&event.stream_id).copied().filter(|&ours| self.base.is_open_for_us(ours)) else {
             return Box::new(SimpleCommandGenerator::empty());
-        }
+        };
 
-        // TODO: End stream using h2 library
-        Box::new(SimpleCommandGenerator::new(vec![
-            Box::new(SendData {
-                connection: (*self.base.conn).clone(),
-                data: self.base.data_to_send().unwrap_or_default().to_vec(),
-            })
-        ]))
+        let result = self.base.h2_conn.end_stream(ours);
+        self.sent(ours, result)
+    }
+
+    fn handle_request_error(&mut self, event: RequestProtocolError) -> Box<dyn CommandGenerator<()>> {
+        let Some(ours) = self.our_stream_id.remove(&event.stream_id) else {
+            return Box::new(SimpleCommandGenerator::empty());
+        };
+
+        // Only the stream is given up, the connection stays usable
+        self.base.h2_conn.reset_stream(ours, event.code.h2_reason());
+        self.base.streams.remove(&(ours as StreamId));
+        self.base.their_stream_id.remove(&ours);
+        self.base.flush()
+    }
+}
+
+/// The event if it is one an HTTP/2 client sends upstream
+fn request_event(event: &dyn Event) -> Option<&dyn HttpEvent> {
+    let any = event.as_any();
+    if let Some(e) = any.downcast_ref::<RequestHeaders>() {
+        Some(e)
+    } else if let Some(e) = any.downcast_ref::<RequestData>() {
+        Some(e)
+    } else if let Some(e) = any.downcast_ref::<RequestTrailers>() {
+        Some(e)
+    } else if let Some(e) = any.downcast_ref::<RequestEndOfMessage>() {
+        Some(e)
+    } else {
+        any.downcast_ref::<RequestProtocolError>().map(|e| e as &dyn HttpEvent)
     }
 }
 
 /// Utility functions for HTTP/2 header parsing and formatting, matching Python implementations
 
+/// Headers describing an HTTP/1 connection (RFC 9113, section 8.2.2)
+const H2_CONNECTION_HEADERS: &[&str] = &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
 /// Normalize HTTP/1.1 headers for HTTP/2, matching Python's normalize_h1_headers
 pub fn normalize_h1_headers(headers: Vec<(Bytes, Bytes)>, _is_client: bool) -> Result<Vec<(Bytes, Bytes)>, ProxyError> {
     // HTTP/1 servers commonly send capitalized headers, which isn't valid HTTP/2
@@ -3484,6 +4517,11 @@ pub fn normalize_h1_headers(headers: Vec<(Bytes, Bytes)>, _is_client: bool) -> R
         if name_str.chars().any(|c| !c.is_ascii()) {
             return Err(ProxyError::Proxy("Header name contains non-ASCII characters".to_string()));
         }
+        // Connection-specific headers are not allowed in HTTP/2
+        let lower = name_str.to_ascii_lowercase();
+        if H2_CONNECTION_HEADERS.contains(&lower.as_str()) || (lower == "te" && !value.eq_ignore_ascii_case(b"trailers")) {
+            continue;
+        }
 
         // Convert to lowercase for HTTP/2
         let normalized_name = name_str.to_lowercase().into_bytes();
@@ -3564,17 +4602,15 @@ pub fn format_h2_response_headers(context: &Context, event: &ResponseHeaders) ->
 
 /// Parse HTTP/2 request headers, matching Python's parse_h2_request_headers
 pub fn parse_h2_request_headers(h2_headers: Vec<(Bytes, Bytes)>) -> Result<(String, u16, Bytes, Bytes, Bytes, Bytes, http::HeaderMap), ProxyError> {
-    let (pseudo_headers, headers) = split_pseudo_headers(h2_headers)?;
+    let (mut pseudo_headers, headers) = split_pseudo_headers(h2_headers)?;
 
-    let method = pseudo_headers.get(":method")
+    let method = pseudo_headers.remove(":method")
         .ok_or_else(|| ProxyError::Proxy("Required pseudo header is missing: :method".to_string()))?;
-    let scheme = pseudo_headers.get(":scheme")
+    let scheme = pseudo_headers.remove(":scheme")
         .ok_or_else(|| ProxyError::Proxy("Required pseudo header is missing: :scheme".to_string()))?;
-    let path = pseudo_headers.get(":path")
+    let path = pseudo_headers.remove(":path")
         .ok_or_else(|| ProxyError::Proxy("Required pseudo header is missing: :path".to_string()))?;
-    let authority = pseudo_headers.get(":authority")
-        .map(|s| s.clone())
-        .unwrap_or_else(|| Bytes::new());
+    let authority = pseudo_headers.remove(":authority").unwrap_or_default();
 
     if !pseudo_headers.is_empty() {
         return Err(ProxyError::Proxy(format!("Unknown pseudo headers: {:?}", pseudo_headers.keys())));
@@ -3587,7 +4623,7 @@ pub fn parse_h2_request_headers(h2_headers: Vec<(Bytes, Bytes)>) -> Result<(Stri
         ("".to_string(), 0)
     };
 
-    Ok((host, port, Bytes::from(method.clone()), Bytes::from(scheme.clone()), authority, Bytes::from(path.clone()), headers))
+    Ok((host, port, method, scheme, authority, path, headers))
 }
 
 /// Parse HTTP/2 response headers, matching Python's parse_h2_response_headers
@@ -3619,13 +4655,60 @@ pub fn split_pseudo_headers(h2_headers: Vec<(Bytes, Bytes)>) -> Result<(HashMap<
                 .map_err(|_| ProxyError::Proxy("Invalid header name".to_string()))?;
             let header_value = http::HeaderValue::from_bytes(&value)
                 .map_err(|_| ProxyError::Proxy("Invalid header value".to_string()))?;
-            headers.insert(header_name, header_value);
+            headers.append(header_name, header_value);
         }
     }
 
     Ok((pseudo_headers, headers))
 }
 
+/// Whether `host:port` is configured as an h2c upstream. Patterns are
+/// `host`, `host:port` or `*.domain`; a wildcard also matches the domain itself.
+pub fn is_h2c_upstream(patterns: &[String], host: &str, port: u16) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        let (pattern_host, pattern_port) = match pattern.rsplit_once(':') {
+            Some((h, p)) if !h.contains(':') || h.ends_with(']') => match p.parse::<u16>() {
+                Ok(p) => (h.trim_start_matches('[').trim_end_matches(']'), Some(p)),
+                Err(_) => return false,
+            },
+            _ => (pattern.as_str(), None),
+        };
+        if pattern_port.is_some_and(|p| p != port) {
+            return false;
+        }
+        match pattern_host.strip_prefix("*.") {
+            Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+            None => host == pattern_host,
+        }
+    })
+}
+
+/// Create the layer speaking HTTP to the upstream server at `host:port`,
+/// matching Python's HttpLayer.make_http_connection: HTTP/2 if it was
+/// negotiated via ALPN or the server is a plaintext h2c upstream (prior
+/// knowledge), HTTP/1 otherwise.
+pub fn make_client_layer(context: Context, host: &str, port: u16) -> Box<dyn Layer> {
+    let (tls, alpn) = context
+        .server
+        .as_ref()
+        .map(|server| (server.connection.tls, server.connection.alpn.as_deref()))
+        .unwrap_or((false, None));
+    let h2 = if tls {
+        alpn == Some("h2")
+    } else {
+        is_h2c_upstream(&context.options.h2c_upstream, host, port)
+    };
+
+    if h2 {
+        debug!("Using HTTP/2 for upstream {}:{}{}", host, port, if tls { "" } else { " (h2c)" });
+        Box::new(Http2Client::new(context))
+    } else {
+        Box::new(Http1Client::new(context))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -3637,6 +4720,265 @@ mod tests {
         assert_eq!(ErrorCode::Kill.http_status_code(), None);
     }
 
+    #[test]
+    fn test_reverse_mode_rewrites_request() {
+        let mut context = Context::default();
+        context.options.reverse_target = Some(ReverseTarget::parse("https://api.example.com:8443").unwrap());
+        let mut stream = HttpStream::new(context, 1);
+        let mut request = HTTPRequest::new("GET".to_string(), "http".to_string(), "localhost".to_string(), 8080, "/v1/users".to_string());
+        request.headers = vec![("Host".to_string(), "localhost:8080".to_string()), ("Accept".to_string(), "*/*".to_string())];
+        stream.handle_request_headers(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None });
+
+        let request = &stream.flow.request;
+        assert_eq!(request.url(), "https://api.example.com:8443/v1/users");
+        assert_eq!(request.headers[0], ("Host".to_string(), "api.example.com:8443".to_string()));
+    }
+
+    #[test]
+    fn test_onboarding_host_answered_locally() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let ca = Arc::new(crate::certs::CertificateAuthority::new(temp_dir.path()).unwrap());
+        let mut context = Context::default();
+        context.options.onboarding_host = Some("mitm.it".to_string());
+        context.options.ca = Some(ca.clone());
+        let mut stream = HttpStream::new(context, 1);
+        let request = HTTPRequest::new("GET".to_string(), "http".to_string(), "mitm.it".to_string(), 80, "/cert/cer".to_string());
+        let sent = commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None })));
+
+        let events: Vec<&dyn HttpEvent> = sent.iter().filter_map(|c| c.as_any().downcast_ref::<SendHttp>()).map(|s| s.event.as_ref()).collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].as_any().downcast_ref::<ResponseHeaders>().unwrap().response.status_code, 200);
+        assert_eq!(events[1].as_any().downcast_ref::<ResponseData>().unwrap().data, ca.ca_cert_der().unwrap());
+        assert!(events[2].as_any().downcast_ref::<ResponseEndOfMessage>().is_some());
+
+        // With onboarding disabled the request goes upstream
+        let mut stream = HttpStream::new(Context::default(), 1);
+        let request = HTTPRequest::new("GET".to_string(), "http".to_string(), "mitm.it".to_string(), 80, "/".to_string());
+        assert!(commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None }))).is_empty());
+    }
+
+    #[test]
+    fn test_map_local_answers_matching_requests() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("app.css"), "body {}").unwrap();
+        let spec = format!("~d example.com|{}", temp_dir.path().display());
+        let mut context = Context::default();
+        context.options.map_local = Some(Arc::new(crate::maplocal::MapLocal::from_specs(&[spec]).unwrap()));
+
+        let mut stream = HttpStream::new(context.clone(), 1);
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "example.com".to_string(), 443, "/app.css".to_string());
+        let sent = commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None })));
+        let events: Vec<&dyn HttpEvent> = sent.iter().filter_map(|c| c.as_any().downcast_ref::<SendHttp>()).map(|s| s.event.as_ref()).collect();
+        let response = &events[0].as_any().downcast_ref::<ResponseHeaders>().unwrap().response;
+        assert_eq!(response.get_header("content-type").unwrap(), "text/css; charset=utf-8");
+        assert_eq!(events[1].as_any().downcast_ref::<ResponseData>().unwrap().data, "body {}");
+        assert_eq!(stream.flow.response.as_ref().unwrap().status_code, 200);
+
+        let mut stream = HttpStream::new(context, 1);
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "example.org".to_string(), 443, "/app.css".to_string());
+        assert!(commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None }))).is_empty());
+    }
+
+    #[test]
+    fn test_server_replay_answers_matching_requests() {
+        use crate::serverreplay::{ServerReplay, ServerReplayOptions};
+
+        let mut recorded = HTTPFlow::new(HTTPRequest::new("POST".to_string(), "https".to_string(), "api.example".to_string(), 443, "/items".to_string()));
+        recorded.request.set_content(b"name=x".to_vec());
+        let recorded = recorded.with_response(HTTPResponse::new(201, "Created".to_string()));
+        let mut context = Context::default();
+        context.options.server_replay = Some(Arc::new(ServerReplay::from_flows(&ServerReplayOptions::default(), vec![recorded])));
+        let send = |body: &'static [u8]| {
+            let mut stream = HttpStream::new(context.clone(), 1);
+            let request = HTTPRequest::new("POST".to_string(), "https".to_string(), "api.example".to_string(), 443, "/items".to_string());
+            // Matched on the body, so nothing is answered before it is complete
+            assert!(commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: false, replay_flow: None }))).is_empty());
+            commands(stream.handle_event(Box::new(RequestData { stream_id: 1, data: Bytes::from_static(body) })));
+            let sent = commands(stream.handle_event(Box::new(RequestEndOfMessage { stream_id: 1 })));
+            (stream, sent)
+        };
+
+        let (stream, sent) = send(b"name=y");
+        assert!(sent.is_empty() && stream.flow.response.is_none());
+        let (stream, sent) = send(b"name=x");
+        let response = &sent[0].as_any().downcast_ref::<SendHttp>().unwrap().event.as_any().downcast_ref::<ResponseHeaders>().unwrap().response;
+        assert_eq!(response.status_code, 201);
+        assert_eq!(stream.flow.to_json()["is_replay"], "response");
+    }
+
+    #[test]
+    fn test_resumed_request_is_checked_again() {
+        let mut context = Context::default();
+        context.options.intercept = Some(Arc::new(crate::intercept::Intercept::new(Some("~d held.example")).unwrap()));
+        let allowlist = crate::allowlist::AllowlistOptions { enabled: true, hosts: vec!["held.example".to_string()] };
+        context.options.allowlist = Some(Arc::new(std::sync::RwLock::new(crate::allowlist::Allowlist::new(&allowlist).unwrap())));
+        let mut stream = HttpStream::new(context, 1);
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "held.example".to_string(), 443, "/".to_string());
+        let sent = commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None })));
+        let mut flow = sent[0].as_any().downcast_ref::<InterceptHook>().unwrap().flow.clone();
+
+        // Edited to go to a host not on the allowlist while held
+        flow.request.host = "production.example".to_string();
+        let sent = commands(stream.handle_event(Box::new(HookCompleted { command: Box::new(InterceptHook { flow }) })));
+        let response = &sent[0].as_any().downcast_ref::<SendHttp>().unwrap().event.as_any().downcast_ref::<ResponseHeaders>().unwrap().response;
+        assert_eq!(response.status_code, 403);
+        assert_eq!(stream.server_state, "done");
+    }
+
+    #[test]
+    fn test_intercept_holds_matching_flows() {
+        let mut context = Context::default();
+        context.options.intercept = Some(Arc::new(crate::intercept::Intercept::new(Some("~d held.example")).unwrap()));
+        let request = |host: &str| HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, "/".to_string());
+        let completed = |flow: HTTPFlow| Box::new(HookCompleted { command: Box::new(InterceptHook { flow }) });
+
+        let mut stream = HttpStream::new(context.clone(), 1);
+        let sent = commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request: request("held.example"), end_stream: true, replay_flow: None })));
+        let mut flow = sent[0].as_any().downcast_ref::<InterceptHook>().unwrap().flow.clone();
+        assert!(flow.flow.intercepted);
+        assert_eq!(stream.server_state, "request_intercepted");
+
+        // Resumed with an edited request, which is forwarded as edited
+        flow.request.path = "/edited".to_string();
+        assert!(commands(stream.handle_event(completed(flow))).is_empty());
+        assert_eq!(stream.server_state, "wait_for_response_headers");
+        assert_eq!(stream.flow.request.path, "/edited");
+        assert!(!stream.flow.flow.intercepted);
+
+        // The response is held too, and killing it drops the stream
+        let response = HTTPResponse::new(200, "OK".to_string());
+        commands(stream.handle_event(Box::new(ResponseHeaders { stream_id: 1, response, end_stream: false })));
+        let sent = commands(stream.handle_event(Box::new(ResponseEndOfMessage { stream_id: 1 })));
+        let mut flow = sent[0].as_any().downcast_ref::<InterceptHook>().unwrap().flow.clone();
+        assert_eq!(stream.server_state, "response_intercepted");
+        flow.flow.kill();
+        let sent = commands(stream.handle_event(completed(flow)));
+        assert!(sent[0].as_any().downcast_ref::<DropStream>().is_some());
+        assert!(stream.flow.flow.error.is_some());
+
+        let mut stream = HttpStream::new(context, 1);
+        assert!(commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request: request("other.example"), end_stream: true, replay_flow: None }))).is_empty());
+        assert_eq!(stream.server_state, "wait_for_response_headers");
+    }
+
+    #[test]
+    fn test_block_list_answers_matching_requests() {
+        let specs = [":~d ads.example:404".to_string(), ":~d tracker.example:444".to_string()];
+        let mut context = Context::default();
+        context.options.block_list = Some(Arc::new(crate::blocklist::BlockList::from_specs(&specs).unwrap()));
+        let send = |host: &str| {
+            let mut stream = HttpStream::new(context.clone(), 1);
+            let request = HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, "/".to_string());
+            let sent = commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None })));
+            (stream, sent)
+        };
+        fn event(sent: &[Box<dyn Command>]) -> &dyn std::any::Any {
+            sent[0].as_any().downcast_ref::<SendHttp>().unwrap().event.as_any()
+        }
+
+        let (stream, sent) = send("ads.example");
+        assert_eq!(event(&sent).downcast_ref::<ResponseHeaders>().unwrap().response.status_code, 404);
+        assert_eq!(stream.server_state, "done");
+
+        let (stream, sent) = send("tracker.example");
+        assert_eq!(event(&sent).downcast_ref::<ResponseProtocolError>().unwrap().code, ErrorCode::Kill);
+        assert!(stream.flow.response.is_none() && stream.flow.flow.error.is_some());
+
+        let (stream, sent) = send("example.com");
+        assert!(sent.is_empty());
+        assert_eq!(stream.server_state, "wait_for_response_headers");
+    }
+
+    #[test]
+    fn test_allowlist_refuses_unlisted_destinations() {
+        let options = crate::allowlist::AllowlistOptions { enabled: true, hosts: vec!["*.lab.example".to_string()] };
+        let allowlist = crate::allowlist::Allowlist::new(&options).unwrap();
+        let mut context = Context::default();
+        context.options.allowlist = Some(Arc::new(std::sync::RwLock::new(allowlist)));
+
+        let mut stream = HttpStream::new(context.clone(), 1);
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "example.com".to_string(), 443, "/".to_string());
+        let sent = commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None })));
+        let events: Vec<&dyn HttpEvent> = sent.iter().filter_map(|c| c.as_any().downcast_ref::<SendHttp>()).map(|s| s.event.as_ref()).collect();
+        assert_eq!(events[0].as_any().downcast_ref::<ResponseHeaders>().unwrap().response.status_code, 403);
+        assert_eq!(stream.server_state, "done");
+
+        let mut stream = HttpStream::new(context, 1);
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "api.lab.example".to_string(), 443, "/".to_string());
+        assert!(commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None }))).is_empty());
+    }
+
+    #[test]
+    fn test_map_remote_rewrites_request_url() {
+        let mut context = Context::default();
+        let rule = r"^https://staging\.example\.com/|https://example.com/".to_string();
+        context.options.map_remote = Some(Arc::new(crate::mapremote::MapRemote::from_specs(&[rule]).unwrap()));
+        let mut stream = HttpStream::new(context, 1);
+        let mut request = HTTPRequest::new("GET".to_string(), "https".to_string(), "staging.example.com".to_string(), 443, "/v1".to_string());
+        request.headers = vec![("Host".to_string(), "staging.example.com".to_string())];
+        stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None }));
+
+        assert_eq!(stream.flow.request.url(), "https://example.com/v1");
+        assert_eq!(stream.flow.request.get_header("host").unwrap(), "example.com");
+    }
+
+    #[test]
+    fn test_websocket_upgrade_spawns_child_layer() {
+        let mut context = Context::default();
+        context.server = Some(crate::connection::Server::new(crate::connection::TransportProtocol::Tcp));
+        let mut stream = HttpStream::new(context, 1);
+        let mut request = HTTPRequest::new("GET".to_string(), "http".to_string(), "example.com".to_string(), 80, "/chat".to_string());
+        request.headers = vec![("Upgrade".to_string(), "websocket".to_string()), ("Connection".to_string(), "Upgrade".to_string())];
+        stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None }));
+        let response = HTTPResponse::new(101, "Switching Protocols".to_string());
+        stream.handle_event(Box::new(ResponseHeaders { stream_id: 1, response, end_stream: true }));
+        let started = commands(stream.handle_event(Box::new(ResponseEndOfMessage { stream_id: 1 })));
+        assert!(started[0].as_any().downcast_ref::<WebsocketStartHook>().is_some());
+        assert_eq!(stream.child_layer.as_ref().unwrap().layer_name(), "WebSocketLayer");
+
+        // A masked "hi" text frame from the client goes to the server unchanged
+        let frame = vec![0x81, 0x82, 0, 0, 0, 0, b'h', b'i'];
+        let relayed = commands(stream.handle_event(Box::new(RequestData { stream_id: 1, data: Bytes::from(frame.clone()) })));
+        let send = relayed[0].as_any().downcast_ref::<SendHttp>().unwrap();
+        assert_eq!(send.event.as_any().downcast_ref::<RequestData>().unwrap().data, frame);
+        let hook = relayed[1].as_any().downcast_ref::<WebsocketMessageHook>().unwrap();
+        assert_eq!(hook.flow.websocket.as_ref().unwrap().messages[0].content, b"hi");
+
+        // The server going away ends the flow and closes the client side
+        let ended = commands(stream.handle_event(Box::new(ResponseEndOfMessage { stream_id: 1 })));
+        assert!(ended[0].as_any().downcast_ref::<WebsocketEndHook>().is_some());
+        let closes: Vec<&SendHttp> = ended.iter().filter_map(|c| c.as_any().downcast_ref::<SendHttp>()).collect();
+        assert_eq!(closes.len(), 2);
+        assert!(closes[0].event.as_any().downcast_ref::<ResponseEndOfMessage>().is_some());
+        assert!(closes[1].event.as_any().downcast_ref::<RequestEndOfMessage>().is_some());
+    }
+
+    #[test]
+    fn test_event_stream_recorded_live() {
+        let mut stream = HttpStream::new(Context::default(), 1);
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "api.example.com".to_string(), 443, "/v1/stream".to_string());
+        stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request, end_stream: true, replay_flow: None }));
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.headers = vec![("Content-Type".to_string(), "text/event-stream".to_string())];
+        stream.handle_event(Box::new(ResponseHeaders { stream_id: 1, response, end_stream: false }));
+
+        let data = |data: &'static [u8]| Box::new(ResponseData { stream_id: 1, data: Bytes::from_static(data) });
+        let first = commands(stream.handle_event(data(b"data: one\n\ndata: tw")));
+        let hook = first[0].as_any().downcast_ref::<SseEventHook>().unwrap();
+        assert_eq!(hook.flow.sse_events.len(), 1);
+        commands(stream.handle_event(data(b"o\n\n")));
+        assert!(commands(stream.handle_event(data(b": heartbeat\n"))).is_empty());
+        commands(stream.handle_event(data(b"data: three")));
+        commands(stream.handle_event(Box::new(ResponseEndOfMessage { stream_id: 1 })));
+
+        let events: Vec<&str> = stream.flow.sse_events.iter().map(|m| m.event.data.as_str()).collect();
+        assert_eq!(events, ["one", "two", "three"]);
+        let stats = stream.flow.sse_stream.as_ref().unwrap();
+        assert_eq!(stats.event_count, 3);
+        assert!(stats.ended && stats.average_gap.is_some());
+        assert_eq!(stream.flow.to_json()["sse_stream"]["event_count"], 3);
+    }
+
     #[test]
     fn test_receive_buffer() {
         let mut buf = ReceiveBuffer::new();
@@ -3683,4 +5025,528 @@ mod tests {
         assert_eq!(request.get_header("host"), Some(&"example.com".to_string()));
         assert_eq!(request.get_header("user-agent"), Some(&"test".to_string()));
     }
+
+    #[test]
+    fn test_is_h2c_upstream() {
+        let patterns = vec!["grpc.internal".to_string(), "*.svc.cluster.local".to_string(), "10.0.0.5:50051".to_string()];
+        assert!(is_h2c_upstream(&patterns, "grpc.internal", 80));
+        assert!(is_h2c_upstream(&patterns, "GRPC.internal.", 9000));
+        assert!(is_h2c_upstream(&patterns, "users.default.svc.cluster.local", 50051));
+        assert!(is_h2c_upstream(&patterns, "svc.cluster.local", 50051));
+        assert!(is_h2c_upstream(&patterns, "10.0.0.5", 50051));
+        assert!(!is_h2c_upstream(&patterns, "10.0.0.5", 8080));
+        assert!(!is_h2c_upstream(&patterns, "other.internal", 80));
+        assert!(!is_h2c_upstream(&patterns, "evilsvc.cluster.local", 80));
+    }
+
+    fn upstream_context(tls: bool, alpn: Option<&str>) -> Context {
+        use crate::connection::{Server, TransportProtocol};
+        let mut server = Server::new(TransportProtocol::Tcp);
+        server.connection.tls = tls;
+        server.connection.alpn = alpn.map(str::to_string);
+        let mut context = Context::default();
+        context.server = Some(server);
+        context.options.h2c_upstream = vec!["grpc.internal".to_string()];
+        context
+    }
+
+    #[test]
+    fn test_make_client_layer() {
+        let name = |tls, alpn, host| make_client_layer(upstream_context(tls, alpn), host, 80).layer_name();
+        assert_eq!(name(false, None, "grpc.internal"), "Http2Client");
+        assert_eq!(name(false, None, "example.com"), "Http1Client");
+        assert_eq!(name(true, Some("h2"), "example.com"), "Http2Client");
+        assert_eq!(name(true, Some("http/1.1"), "grpc.internal"), "Http1Client");
+    }
+
+    #[test]
+    fn test_h2c_client_preface() {
+        let mut client = Http2Client::new(upstream_context(false, None));
+        let mut commands = client.sync_handle_event(Box::new(Start));
+        let command = commands.next_command().unwrap();
+        let data = &command.as_any().downcast_ref::<SendData>().unwrap().data;
+
+        assert!(data.starts_with(H2_CONNECTION_PREFACE));
+        let settings = &data[H2_CONNECTION_PREFACE.len()..];
+        assert_eq!(settings[3], H2_FRAME_SETTINGS);
+        // ENABLE_PUSH = 0 comes first
+        assert_eq!(&settings[9..15], &[0, 2, 0, 0, 0, 0]);
+        let window_update = &settings[9 + 18..];
+        assert_eq!(window_update[3], H2_FRAME_WINDOW_UPDATE);
+        assert_eq!(window_update.len(), 9 + 4);
+    }
+
+    #[test]
+    fn test_h2c_server_settings() {
+        let mut conn = BufferedH2Connection::new();
+        conn.initiate_connection();
+        conn.data_to_send();
+
+        // SETTINGS with MAX_CONCURRENT_STREAMS = 50, split across two reads
+        let frame = [0, 0, 6, H2_FRAME_SETTINGS, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 50];
+        assert!(conn.receive_data(&frame[..7]).unwrap().is_empty());
+        let events = conn.receive_data(&frame[7..]).unwrap();
+        assert!(matches!(events[..], [H2Event::SettingsChanged]));
+        assert_eq!(conn.remote_settings().max_concurrent_streams, 50);
+        assert_eq!(conn.data_to_send().unwrap().as_ref(), &[0, 0, 0, H2_FRAME_SETTINGS, H2_FLAG_ACK, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_h2c_preface_rejected_by_http1_server() {
+        let mut conn = BufferedH2Connection::new();
+        conn.initiate_connection();
+        let err = conn.receive_data(b"HTTP/1.1 400 Bad Request\r\n\r\n").unwrap_err();
+        assert!(err.to_string().contains("HTTP/2 without TLS"));
+    }
+
+    fn sent(commands: &[Box<dyn Command>]) -> Vec<u8> {
+        commands.iter().filter_map(|c| c.as_any().downcast_ref::<SendData>()).flat_map(|d| d.data.clone()).collect()
+    }
+
+    fn received(commands: &[Box<dyn Command>]) -> Vec<&dyn HttpEvent> {
+        commands.iter().filter_map(|c| c.as_any().downcast_ref::<ReceiveHttp>()).map(|r| r.event.as_ref()).collect()
+    }
+
+    fn names(events: &[&dyn HttpEvent]) -> Vec<(&'static str, StreamId)> {
+        events.iter().map(|e| (e.event_name(), e.stream_id())).collect()
+    }
+
+    fn data_received(data: Vec<u8>) -> Box<dyn Event> {
+        Box::new(DataReceived { connection: Connection::default(), data })
+    }
+
+    #[test]
+    fn test_h2_client_to_server() {
+        let mut client = Http2Client::new(upstream_context(false, None));
+        let mut server = Http2Server::new(Context::default());
+        let mut to_server = sent(&commands(client.sync_handle_event(Box::new(Start))));
+        let to_client = sent(&commands(server.sync_handle_event(Box::new(Start))));
+        to_server.extend(sent(&commands(client.sync_handle_event(data_received(to_client)))));
+
+        let mut request = HTTPRequest::new("POST".to_string(), "http".to_string(), "grpc.internal".to_string(), 80, "/api?x=1".to_string());
+        request.headers = vec![
+            ("Content-Type".to_string(), "text/plain".to_string()),
+            ("Connection".to_string(), "keep-alive".to_string()),
+        ];
+        for event in [
+            Box::new(RequestHeaders { stream_id: 7, request, end_stream: false, replay_flow: None }) as Box<dyn Event>,
+            Box::new(RequestData { stream_id: 7, data: Bytes::from_static(b"hello") }),
+            Box::new(RequestEndOfMessage { stream_id: 7 }),
+        ] {
+            to_server.extend(sent(&commands(client.sync_handle_event(event))));
+        }
+
+        let out = commands(server.sync_handle_event(data_received(to_server)));
+        let events = received(&out);
+        assert_eq!(names(&events), [("RequestHeaders", 1), ("RequestData", 1), ("RequestEndOfMessage", 1)]);
+        let request = &events[0].as_any().downcast_ref::<RequestHeaders>().unwrap().request;
+        assert_eq!((request.method.as_str(), request.host.as_str(), request.path.as_str()), ("POST", "grpc.internal", "/api?x=1"));
+        assert_eq!(request.get_header("content-type").unwrap(), "text/plain");
+        assert!(request.get_header("connection").is_none());
+        assert_eq!(events[1].as_any().downcast_ref::<RequestData>().unwrap().data, "hello");
+
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.headers.push(("Content-Type".to_string(), "application/grpc".to_string()));
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+        let mut to_client = sent(&out);
+        for event in [
+            Box::new(ResponseHeaders { stream_id: 1, response, end_stream: false }) as Box<dyn Event>,
+            Box::new(ResponseData { stream_id: 1, data: Bytes::from_static(b"world") }),
+            Box::new(ResponseTrailers { stream_id: 1, trailers }),
+            Box::new(ResponseEndOfMessage { stream_id: 1 }),
+        ] {
+            to_client.extend(sent(&commands(server.sync_handle_event(event))));
+        }
+        assert!(server.base.streams.is_empty());
+
+        let out = commands(client.sync_handle_event(data_received(to_client)));
+        let events = received(&out);
+        assert_eq!(
+            names(&events),
+            [("ResponseHeaders", 7), ("ResponseData", 7), ("ResponseTrailers", 7), ("ResponseEndOfMessage", 7)]
+        );
+        let response = &events[0].as_any().downcast_ref::<ResponseHeaders>().unwrap().response;
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.get_header("content-type").unwrap(), "application/grpc");
+        assert_eq!(events[2].as_any().downcast_ref::<ResponseTrailers>().unwrap().trailers["grpc-status"], "0");
+        assert_eq!(client.base.h2_conn.open_outbound_streams(), 0);
+
+        // A stream the server gives up on is reset without affecting the connection
+        let request = HTTPRequest::new("GET".to_string(), "http".to_string(), "grpc.internal".to_string(), 80, "/".to_string());
+        let to_server = sent(&commands(client.sync_handle_event(Box::new(RequestHeaders {
+            stream_id: 9,
+            request,
+            end_stream: true,
+            replay_flow: None,
+        }))));
+        let out = commands(server.sync_handle_event(data_received(to_server)));
+        assert_eq!(names(&received(&out)), [("RequestHeaders", 3), ("RequestEndOfMessage", 3)]);
+        let to_client = sent(&commands(server.sync_handle_event(Box::new(ResponseProtocolError {
+            stream_id: 3,
+            message: "killed".to_string(),
+            code: ErrorCode::Kill,
+        }))));
+        let out = commands(client.sync_handle_event(data_received(to_client)));
+        let events = received(&out);
+        assert_eq!(names(&events), [("ResponseProtocolError", 9)]);
+        assert!(events[0].as_any().downcast_ref::<ResponseProtocolError>().unwrap().message.contains("INTERNAL_ERROR"));
+        assert!(out.iter().all(|c| c.as_any().downcast_ref::<CloseConnection>().is_none()));
+    }
+
+    #[test]
+    fn test_h2_client_keepalive_ping() {
+        let clock = Arc::new(crate::clock::ManualClock::new(1000.0));
+        let context = upstream_context(false, None).with_sources(clock.clone(), Arc::new(crate::clock::SeededIds::new(1)));
+        let mut client = Http2Client::new(context);
+        let mut server = Http2Server::new(Context::default());
+        let wakeup = |commands: &[Box<dyn Command>]| {
+            commands.iter().find_map(|c| c.as_any().downcast_ref::<RequestWakeup>()).map(|w| w.delay)
+        };
+        let is_ping = |data: &[u8], ack: u8| data.len() == 17 && data[3] == H2_FRAME_PING && data[4] == ack;
+
+        let started = commands(client.sync_handle_event(Box::new(Start)));
+        assert_eq!(wakeup(&started), Some(58.0));
+        let to_client = sent(&commands(server.sync_handle_event(Box::new(Start))));
+        let mut to_server = sent(&started);
+        to_server.extend(sent(&commands(client.sync_handle_event(data_received(to_client)))));
+        let to_client = sent(&commands(server.sync_handle_event(data_received(to_server))));
+        commands(client.sync_handle_event(data_received(to_client)));
+
+        // Not idle long enough yet: wake up again when it will have been
+        clock.advance(30.0);
+        let early = commands(client.sync_handle_event(Box::new(Wakeup { delay: 58.0 })));
+        assert!(sent(&early).is_empty());
+        assert_eq!(wakeup(&early), Some(28.0));
+
+        clock.advance(28.0);
+        let due = commands(client.sync_handle_event(Box::new(Wakeup { delay: 28.0 })));
+        let ping = sent(&due);
+        assert!(is_ping(&ping, 0));
+        assert_eq!(wakeup(&due), Some(58.0));
+
+        // The server's answer counts as activity and lets the next ping go out
+        let pong = sent(&commands(server.sync_handle_event(data_received(ping))));
+        assert!(is_ping(&pong, H2_FLAG_ACK));
+        clock.advance(10.0);
+        commands(client.sync_handle_event(data_received(pong)));
+        clock.advance(48.0);
+        assert_eq!(wakeup(&commands(client.sync_handle_event(Box::new(Wakeup { delay: 58.0 })))), Some(10.0));
+        clock.advance(10.0);
+        assert!(is_ping(&sent(&commands(client.sync_handle_event(Box::new(Wakeup { delay: 10.0 })))), 0));
+    }
+
+    #[test]
+    fn test_h2_debug_from_context_options() {
+        let mut context = Context::default();
+        assert!(!Http2Server::new(context.clone()).base.debug);
+        context.options.proxy_debug = true;
+        assert!(Http2Server::new(context.clone()).base.debug);
+        assert!(Http2Client::new(context).base.debug);
+    }
+
+    /// An `Http2Server` and a bare client connection to it, past the
+    /// exchange of settings
+    fn h2_server_with_peer(mut peer: BufferedH2Connection) -> (Http2Server, BufferedH2Connection) {
+        let mut server = Http2Server::new(Context::default());
+        peer.initiate_connection();
+        peer.receive_data(&sent(&commands(server.sync_handle_event(Box::new(Start))))).unwrap();
+        let to_server = peer.data_to_send().unwrap().to_vec();
+        peer.receive_data(&sent(&commands(server.sync_handle_event(data_received(to_server))))).unwrap();
+        (server, peer)
+    }
+
+    fn h2_request(method: &'static str) -> Vec<(Bytes, Bytes)> {
+        [(":method", method), (":scheme", "http"), (":authority", "grpc.internal"), (":path", "/")]
+            .into_iter()
+            .map(|(name, value)| (Bytes::from_static(name.as_bytes()), Bytes::from_static(value.as_bytes())))
+            .collect()
+    }
+
+    #[test]
+    fn test_h2_stream_reset_by_client() {
+        let (mut server, mut peer) = h2_server_with_peer(BufferedH2Connection::new());
+        let stream_id = peer.send_request(h2_request("POST"), false).unwrap();
+        let out = commands(server.sync_handle_event(data_received(peer.data_to_send().unwrap().to_vec())));
+        assert_eq!(names(&received(&out)), [("RequestHeaders", 1)]);
+
+        peer.reset_stream(stream_id, h2::Reason::CANCEL);
+        let out = commands(server.sync_handle_event(data_received(peer.data_to_send().unwrap().to_vec())));
+        let events = received(&out);
+        assert_eq!(names(&events), [("RequestProtocolError", 1)]);
+        let error = events[0].as_any().downcast_ref::<RequestProtocolError>().unwrap();
+        assert_eq!(error.code, ErrorCode::Cancel);
+        assert!(error.message.contains("CANCEL"));
+
+        // Other streams go on
+        assert!(out.iter().all(|c| c.as_any().downcast_ref::<CloseConnection>().is_none()));
+        peer.send_request(h2_request("GET"), true).unwrap();
+        let out = commands(server.sync_handle_event(data_received(peer.data_to_send().unwrap().to_vec())));
+        assert_eq!(names(&received(&out)), [("RequestHeaders", 3), ("RequestEndOfMessage", 3)]);
+    }
+
+    #[test]
+    fn test_h2_goaway_closes_open_streams() {
+        let (mut server, mut peer) = h2_server_with_peer(BufferedH2Connection::new());
+        peer.send_request(h2_request("POST"), false).unwrap();
+        commands(server.sync_handle_event(data_received(peer.data_to_send().unwrap().to_vec())));
+
+        peer.close_connection(h2::Reason::NO_ERROR);
+        let out = commands(server.sync_handle_event(data_received(peer.data_to_send().unwrap().to_vec())));
+        assert!(out.iter().any(|c| c.as_any().downcast_ref::<CloseConnection>().is_some()));
+        let events = received(&out);
+        assert_eq!(names(&events), [("RequestProtocolError", 1)]);
+        let error = events[0].as_any().downcast_ref::<RequestProtocolError>().unwrap();
+        assert!(error.message.contains("GOAWAY (NO_ERROR, last stream 0)"));
+        assert!(server.base.streams.is_empty());
+    }
+
+    #[test]
+    fn test_h2_request_trailers() {
+        let (mut server, mut peer) = h2_server_with_peer(BufferedH2Connection::new());
+        let stream_id = peer.send_request(h2_request("POST"), false).unwrap();
+        peer.send_data(stream_id, Bytes::from_static(b"hello"), false).unwrap();
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-timeout", http::HeaderValue::from_static("1S"));
+        peer.send_trailers(stream_id, trailers).unwrap();
+
+        let out = commands(server.sync_handle_event(data_received(peer.data_to_send().unwrap().to_vec())));
+        let events = received(&out);
+        assert_eq!(
+            names(&events),
+            [("RequestHeaders", 1), ("RequestData", 1), ("RequestTrailers", 1), ("RequestEndOfMessage", 1)]
+        );
+        assert_eq!(events[2].as_any().downcast_ref::<RequestTrailers>().unwrap().trailers["grpc-timeout"], "1S");
+    }
+
+    #[test]
+    fn test_h2_response_waits_for_window() {
+        // A client granting 16 bytes per stream
+        let peer = BufferedH2Connection { initial_window_size: 16, ..BufferedH2Connection::new() };
+        let (mut server, mut peer) = h2_server_with_peer(peer);
+        let stream_id = peer.send_request(h2_request("GET"), true).unwrap();
+        commands(server.sync_handle_event(data_received(peer.data_to_send().unwrap().to_vec())));
+
+        let mut to_client = Vec::new();
+        for event in [
+            Box::new(ResponseHeaders { stream_id: 1, response: HTTPResponse::new(200, "OK".to_string()), end_stream: false }) as Box<dyn Event>,
+            Box::new(ResponseData { stream_id: 1, data: Bytes::from(vec![b'x'; 100]) }),
+            Box::new(ResponseEndOfMessage { stream_id: 1 }),
+        ] {
+            to_client.extend(sent(&commands(server.sync_handle_event(event))));
+        }
+
+        // Each flight is held to the window the client gave back
+        let (mut body, mut flights, mut ended) = (0, 0, false);
+        while !ended {
+            let events = peer.receive_data(&to_client).unwrap();
+            let flight: usize = events
+                .iter()
+                .map(|event| match event {
+                    H2Event::DataReceived { data, .. } => data.len(),
+                    _ => 0,
+                })
+                .sum();
+            assert!(flight <= 16, "{} bytes sent into a 16 byte window", flight);
+            body += flight;
+            flights += 1;
+            ended = events.iter().any(|event| matches!(event, H2Event::StreamEnded { stream_id: id } if *id == stream_id));
+            let window_update = peer.data_to_send().map(|data| data.to_vec()).unwrap_or_default();
+            assert!(ended || !window_update.is_empty(), "the client gave no window back");
+            to_client = sent(&commands(server.sync_handle_event(data_received(window_update))));
+            assert!(flights <= 100);
+        }
+        assert_eq!(body, 100);
+        assert!(flights > 1);
+    }
+
+    fn commands(mut gen: Box<dyn CommandGenerator<()>>) -> Vec<Box<dyn Command>> {
+        std::iter::from_fn(|| gen.next_command()).collect()
+    }
+
+    fn request_streams(commands: &[Box<dyn Command>]) -> Vec<StreamId> {
+        commands
+            .iter()
+            .filter_map(|c| c.as_any().downcast_ref::<ReceiveHttp>())
+            .filter_map(|r| r.event.as_any().downcast_ref::<RequestHeaders>())
+            .map(|h| h.stream_id)
+            .collect()
+    }
+
+    fn respond(server: &mut Http1Server, stream_id: StreamId) -> Vec<Box<dyn Command>> {
+        let mut response = HTTPResponse::new(204, "No Content".to_string());
+        response.headers.push(("Content-Length".to_string(), "0".to_string()));
+        let mut sent = commands(server.sync_handle_event(Box::new(ResponseHeaders { stream_id, response, end_stream: true })));
+        sent.extend(commands(server.sync_handle_event(Box::new(ResponseEndOfMessage { stream_id }))));
+        sent
+    }
+
+    fn pipelined_server(reject: bool) -> (Http1Server, Vec<Box<dyn Command>>) {
+        let mut context = Context::default();
+        context.options.reject_pipelining = reject;
+        let mut server = Http1Server::new(context);
+        commands(server.sync_handle_event(Box::new(Start)));
+        let data = b"GET /a HTTP/1.1\r\nHost: example.com\r\n\r\nGET /b HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec();
+        let received = commands(server.sync_handle_event(Box::new(DataReceived {
+            connection: Connection::default(),
+            data,
+        })));
+        (server, received)
+    }
+
+    #[test]
+    fn test_pipelined_requests_are_sequential() {
+        let (mut server, received) = pipelined_server(false);
+        assert_eq!(request_streams(&received), [1]);
+        assert_eq!(server.state, Http1ServerState::Wait);
+
+        // The second request is only read after the first response is sent
+        let sent = respond(&mut server, 1);
+        assert!(sent[0].as_any().downcast_ref::<SendData>().unwrap().data.starts_with(b"HTTP/1.1 204"));
+        assert_eq!(request_streams(&sent), [3]);
+        assert_eq!(server.request.as_ref().unwrap().path, "/b");
+
+        let sent = respond(&mut server, 3);
+        assert!(request_streams(&sent).is_empty());
+        assert_eq!(server.state, Http1ServerState::ReadHeaders);
+    }
+
+    #[test]
+    fn test_chunked_request_across_reads() {
+        let mut server = Http1Server::new(Context::default());
+        commands(server.sync_handle_event(Box::new(Start)));
+        let mut received = Vec::new();
+        for data in [&b"POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhel"[..], b"lo\r\n0\r\n\r\n"] {
+            received.extend(commands(server.sync_handle_event(Box::new(DataReceived {
+                connection: Connection::default(),
+                data: data.to_vec(),
+            }))));
+        }
+        let body: Vec<u8> = received
+            .iter()
+            .filter_map(|c| c.as_any().downcast_ref::<ReceiveHttp>())
+            .filter_map(|r| r.event.as_any().downcast_ref::<RequestData>())
+            .flat_map(|d| d.data.to_vec())
+            .collect();
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn test_http1_requests_and_responses_are_rewritten() {
+        let config = crate::config::Config {
+            anticache: true,
+            modify_headers: vec!["/~s/X-Proxied/yes".to_string()],
+            modify_body: vec!["/~q/hello/goodbye".to_string()],
+            ..Default::default()
+        };
+        let mut context = Context::default();
+        context.options.rewrites =
+            Some(Arc::new(crate::rewrites::Rewrites::new(&config, &crate::sandbox::Sandbox::default())));
+        let receive = |data: &[u8]| {
+            let mut server = Http1Server::new(context.clone());
+            commands(server.sync_handle_event(Box::new(Start)));
+            let mut stream = HttpStream::new(context.clone(), 1);
+            for command in commands(server.sync_handle_event(Box::new(DataReceived { connection: Connection::default(), data: data.to_vec() }))) {
+                let Some(received) = command.as_any().downcast_ref::<ReceiveHttp>() else { continue };
+                let event = received.event.as_any();
+                let event: Box<dyn Event> = if let Some(headers) = event.downcast_ref::<RequestHeaders>() {
+                    Box::new(headers.clone())
+                } else if let Some(data) = event.downcast_ref::<RequestData>() {
+                    Box::new(data.clone())
+                } else {
+                    Box::new(event.downcast_ref::<RequestEndOfMessage>().unwrap().clone())
+                };
+                commands(stream.handle_event(event));
+            }
+            stream
+        };
+
+        // A request without a body is complete with its headers already
+        let stream = receive(b"GET / HTTP/1.1\r\nHost: example.com\r\nIf-None-Match: \"abc\"\r\n\r\n");
+        assert!(stream.flow.request.get_header("if-none-match").is_none());
+        assert_eq!(stream.flow.flow.changes.len(), 1);
+        assert_eq!(stream.flow.flow.changes[0].source, "anticache");
+
+        let mut stream = receive(b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello");
+        assert_eq!(stream.flow.request.content.as_deref().unwrap(), b"goodbye");
+        let response = HTTPResponse::new(200, "OK".to_string());
+        commands(stream.handle_event(Box::new(ResponseHeaders { stream_id: 1, response, end_stream: false })));
+        commands(stream.handle_event(Box::new(ResponseEndOfMessage { stream_id: 1 })));
+        assert_eq!(stream.flow.response.unwrap().get_header("x-proxied").unwrap(), "yes");
+    }
+
+    #[test]
+    fn test_unparsable_response() {
+        let mut client = Http1Client::new(Context::default());
+        commands(Layer::handle_event(&mut client, AnyEvent::Start(Start)));
+        let request = HTTPRequest::new("GET".to_string(), "http".to_string(), "flaky.example".to_string(), 80, "/".to_string());
+        commands(client.send_event(Box::new(RequestHeaders { stream_id: 1, request: request.clone(), end_stream: true, replay_flow: None })));
+        let received = commands(Layer::handle_event(&mut client, AnyEvent::DataReceived(DataReceived {
+            connection: Connection::default(),
+            data: b"HTTP/1.1 abc\r\n\r\n".to_vec(),
+        })));
+        let invalid = received
+            .iter()
+            .filter_map(|c| c.as_any().downcast_ref::<ReceiveHttp>())
+            .find_map(|r| r.event.as_any().downcast_ref::<InvalidResponse>())
+            .unwrap()
+            .clone();
+        assert_eq!((invalid.data.as_slice(), invalid.size), (&b"HTTP/1.1 abc\r\n\r\n"[..], 16));
+        assert!(received.iter().any(|c| c.as_any().is::<CaptureMalformed>()));
+
+        // By default the stream is dropped, with the bytes noted on the flow
+        let answer = |bad_gateway: bool| {
+            let mut context = Context::default();
+            context.options.bad_gateway_on_invalid_response = bad_gateway;
+            let mut stream = HttpStream::new(context, 1);
+            commands(stream.handle_event(Box::new(RequestHeaders { stream_id: 1, request: request.clone(), end_stream: true, replay_flow: None })));
+            let sent = commands(stream.handle_event(Box::new(invalid.clone())));
+            (stream, sent)
+        };
+        let (stream, sent) = answer(false);
+        assert!(sent.iter().any(|c| c.as_any().is::<DropStream>()));
+        assert_eq!(stream.flow.flow.metadata[crate::analysis::unparsable::METADATA_KEY]["size"], 16);
+        assert!(stream.flow.response.is_none() && stream.flow.flow.error.is_some());
+
+        let (stream, sent) = answer(true);
+        let headers = sent.iter().find_map(|c| c.as_any().downcast_ref::<SendHttp>()).unwrap();
+        assert_eq!(headers.event.as_any().downcast_ref::<ResponseHeaders>().unwrap().response.status_code, 502);
+        assert!(stream.flow.flow.metadata.contains_key(crate::analysis::unparsable::METADATA_KEY));
+        assert_eq!(stream.server_state, "done");
+    }
+
+    #[test]
+    fn test_malformed_chunked_request_captured() {
+        let mut server = Http1Server::new(Context::default());
+        commands(Layer::handle_event(&mut server, AnyEvent::Start(Start)));
+        let data = b"POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffffff\r\n".to_vec();
+        let received = commands(Layer::handle_event(&mut server, AnyEvent::DataReceived(DataReceived {
+            connection: Connection::default(),
+            data: data.clone(),
+        })));
+        let captured: Vec<&CaptureMalformed> = received
+            .iter()
+            .filter_map(|c| c.as_any().downcast_ref::<CaptureMalformed>())
+            .collect();
+        assert_eq!(captured.len(), 2);
+        assert_eq!((captured[0].parser, captured[0].data.as_slice()), (corpus::Parser::Chunked, &b"ffffffffffffffffff\r\n"[..]));
+        assert_eq!((captured[1].parser, captured[1].data.as_slice()), (corpus::Parser::Http1, data.as_slice()));
+    }
+
+    #[test]
+    fn test_pipelined_requests_rejected() {
+        let (mut server, received) = pipelined_server(true);
+        assert_eq!(request_streams(&received), [1]);
+
+        let sent = respond(&mut server, 1);
+        let data: Vec<&[u8]> = sent
+            .iter()
+            .filter_map(|c| c.as_any().downcast_ref::<SendData>())
+            .map(|d| d.data.as_slice())
+            .collect();
+        assert!(data[0].starts_with(b"HTTP/1.1 204"));
+        assert!(data[1].starts_with(b"HTTP/1.1 400"));
+        assert!(sent.last().unwrap().as_any().downcast_ref::<CloseConnection>().is_some());
+        assert_eq!(server.state, Http1ServerState::Done);
+    }
 }
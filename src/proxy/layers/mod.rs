@@ -1,11 +1,46 @@
 //! Protocol layer implementations
 
+pub mod dns;
 pub mod tcp;
 pub mod tls;
+#[cfg(feature = "rustls-backend")]
+pub mod tls_rustls;
 pub mod http;
+pub mod socks;
 pub mod websocket;
 
+pub use dns::DnsLayer;
 pub use tcp::TcpLayer;
 pub use tls::{ClientTlsLayer, ServerTlsLayer};
+#[cfg(feature = "rustls-backend")]
+pub use tls_rustls::{RustlsClientTlsLayer, RustlsServerTlsLayer};
 pub use http::{HttpLayer, HttpStream, HTTPMode, ErrorCode, Http1Server, Http1Connection};
-pub use websocket::WebSocketLayer;
\ No newline at end of file
+pub use socks::Socks5Proxy;
+pub use websocket::WebSocketLayer;
+
+use crate::certs::CertificateAuthority;
+use crate::connection::Server;
+use crate::proxy::{context::Context, layer::Layer};
+use std::sync::Arc;
+
+/// Layer intercepting TLS from the client, on the configured backend
+pub fn client_tls_layer(context: Context, ca: Option<Arc<CertificateAuthority>>) -> Box<dyn Layer> {
+    #[cfg(feature = "rustls-backend")]
+    if context.options.tls_backend == crate::config::TlsBackend::Rustls {
+        let mut layer = RustlsClientTlsLayer::new(context);
+        layer.ca = ca;
+        return Box::new(layer);
+    }
+    let mut layer = ClientTlsLayer::new(context);
+    layer.ca = ca;
+    Box::new(layer)
+}
+
+/// Layer speaking TLS to the server, on the configured backend
+pub fn server_tls_layer(context: Context, conn: Option<Server>) -> Box<dyn Layer> {
+    #[cfg(feature = "rustls-backend")]
+    if context.options.tls_backend == crate::config::TlsBackend::Rustls {
+        return Box::new(RustlsServerTlsLayer::new(context, conn));
+    }
+    Box::new(ServerTlsLayer::new(context, conn))
+}
\ No newline at end of file
@@ -0,0 +1,382 @@
+//! SOCKS5 proxy layer
+//! This mirrors the Socks5Proxy layer in mitmproxy/proxy/layers/modes.py
+//!
+//! Clients that cannot be pointed at an HTTP proxy can often still speak
+//! SOCKS5. This layer answers the SOCKS handshake (no authentication,
+//! CONNECT only), records the destination the client asked for on the
+//! server connection and then hands the connection to a `NextLayer`, which
+//! stacks the usual TLS and HTTP layers on top.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::connection::{Server, TransportProtocol};
+use crate::proxy::{
+    commands::{CloseConnection, Command, Log, LogLevel, SendData},
+    context::Context,
+    events::{AnyEvent, DataReceived, Event, Start},
+    layer::{BaseLayer, CommandGenerator, Layer, NextLayer, SimpleCommandGenerator},
+};
+
+const SOCKS5_VERSION: u8 = 0x05;
+
+const METHOD_NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
+const METHOD_NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4_ADDRESS: u8 = 0x01;
+const ATYP_DOMAINNAME: u8 = 0x03;
+const ATYP_IPV6_ADDRESS: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Waiting for the version and authentication methods
+    Greeting,
+    /// Waiting for the CONNECT request
+    Request,
+    /// Handshake done, events go to the child layer
+    Established,
+    /// Handshake failed and the client connection is being closed
+    Done,
+}
+
+/// A handshake message the layer cannot go on from
+#[derive(Debug, PartialEq, Eq)]
+struct Refusal {
+    /// Reply code sent to the client before closing, if any
+    reply: Option<u8>,
+    message: String,
+}
+
+impl Refusal {
+    fn new(reply: Option<u8>, message: impl Into<String>) -> Self {
+        Self { reply, message: message.into() }
+    }
+}
+
+/// SOCKS5 layer that performs the handshake before handing off to the
+/// regular layer stack
+#[derive(Debug)]
+pub struct Socks5Proxy {
+    base: BaseLayer,
+    stage: Stage,
+    buf: Vec<u8>,
+    child_layer: Option<Box<dyn Layer>>,
+}
+
+impl Socks5Proxy {
+    pub fn new(context: Context) -> Self {
+        let mut context = context;
+        context.add_layer("Socks5Proxy".to_string());
+        Self {
+            base: BaseLayer::new(context),
+            stage: Stage::Greeting,
+            buf: Vec::new(),
+            child_layer: None,
+        }
+    }
+
+    /// Host and port the client asked to connect to, once the handshake
+    /// has completed
+    pub fn destination(&self) -> Option<&Destination> {
+        self.base.context.server.as_ref()?.destination.as_ref()
+    }
+
+    fn handle_data_received(&mut self, data: Vec<u8>) -> Vec<Box<dyn Command>> {
+        self.buf.extend_from_slice(&data);
+
+        if self.stage == Stage::Greeting {
+            match parse_greeting(&self.buf) {
+                Ok(None) => return vec![],
+                Ok(Some((methods, len))) => {
+                    if !methods.contains(&METHOD_NO_AUTHENTICATION_REQUIRED) {
+                        // The method selection reply has no address fields
+                        self.stage = Stage::Done;
+                        return vec![
+                            self.send_client(vec![SOCKS5_VERSION, METHOD_NO_ACCEPTABLE_METHODS]),
+                            self.close_client(),
+                            self.log("Client does not support SOCKS5 with no authentication."),
+                        ];
+                    }
+                    self.buf.drain(..len);
+                    self.stage = Stage::Request;
+                    let reply = self.send_client(vec![SOCKS5_VERSION, METHOD_NO_AUTHENTICATION_REQUIRED]);
+                    let mut commands = vec![reply];
+                    // The request may have arrived along with the greeting
+                    if !self.buf.is_empty() {
+                        commands.extend(self.handle_data_received(Vec::new()));
+                    }
+                    return commands;
+                }
+                Err(refusal) => return self.refuse(refusal),
+            }
+        }
+
+        match parse_request(&self.buf) {
+            Ok(None) => vec![],
+            Ok(Some(((host, port), len))) => {
+                self.buf.drain(..len);
+                self.establish(host, port)
+            }
+            Err(refusal) => self.refuse(refusal),
+        }
+    }
+
+    /// Point the server connection at the destination, confirm the
+    /// connection to the client and start the child layer
+    fn establish(&mut self, host: String, port: u16) -> Vec<Box<dyn Command>> {
+        let context = &mut self.base.context;
+        let server = context.server.get_or_insert_with(|| Server::new(TransportProtocol::Tcp));
+        server.address = host.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, port));
+        server.destination = Some((host.clone(), port));
+        self.stage = Stage::Established;
+
+        // mitmproxy does not bind a socket per client, so the bound address
+        // in the reply is left unspecified
+        let mut commands = vec![self.send_client(reply(REP_SUCCEEDED))];
+        if let Some(log_cmd) = self.base.debug_log(&format!("SOCKS5 CONNECT to {}:{}", host, port)) {
+            commands.push(log_cmd);
+        }
+
+        let mut child: Box<dyn Layer> = Box::new(NextLayer::new(self.base.context.clone()));
+        let mut events = vec![AnyEvent::Start(Start)];
+        if !self.buf.is_empty() {
+            events.push(AnyEvent::DataReceived(DataReceived {
+                connection: self.base.context.client.connection.clone(),
+                data: std::mem::take(&mut self.buf),
+            }));
+        }
+        for event in events {
+            commands.extend(drain(child.handle_event(event)));
+        }
+        self.child_layer = Some(child);
+        commands
+    }
+
+    fn refuse(&mut self, refusal: Refusal) -> Vec<Box<dyn Command>> {
+        self.stage = Stage::Done;
+        let mut commands = Vec::new();
+        if let Some(code) = refusal.reply {
+            commands.push(self.send_client(reply(code)));
+        }
+        commands.push(self.close_client());
+        commands.push(self.log(&refusal.message));
+        commands
+    }
+
+    fn send_client(&self, data: Vec<u8>) -> Box<dyn Command> {
+        Box::new(SendData { connection: self.base.context.client.connection.clone(), data })
+    }
+
+    fn close_client(&self) -> Box<dyn Command> {
+        Box::new(CloseConnection { connection: self.base.context.client.connection.clone() })
+    }
+
+    fn log(&self, message: &str) -> Box<dyn Command> {
+        Box::new(Log { message: message.to_string(), level: LogLevel::Info })
+    }
+}
+
+impl Layer for Socks5Proxy {
+    fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
+        if let Some(ref mut child) = self.child_layer {
+            return child.handle_event(event);
+        }
+
+        let commands = match event {
+            AnyEvent::Start(_) => self.base.debug_log("SOCKS5 layer started").into_iter().collect(),
+            AnyEvent::DataReceived(data_event) if self.stage != Stage::Done => {
+                self.handle_data_received(data_event.data)
+            }
+            AnyEvent::ConnectionClosed(_) if self.stage != Stage::Done => {
+                self.stage = Stage::Done;
+                vec![self.close_client()]
+            }
+            _ => self.base.debug_log(&format!("Ignoring {} during SOCKS5 handshake", event.event_name())).into_iter().collect(),
+        };
+        Box::new(SimpleCommandGenerator::new(commands))
+    }
+
+    fn layer_name(&self) -> &'static str {
+        "Socks5Proxy"
+    }
+
+    fn debug_prefix(&self) -> Option<&str> {
+        self.base.debug.as_deref()
+    }
+}
+
+/// Reply to a CONNECT request with an unspecified bound address
+fn reply(code: u8) -> Vec<u8> {
+    vec![SOCKS5_VERSION, code, 0x00, ATYP_IPV4_ADDRESS, 0, 0, 0, 0, 0, 0]
+}
+
+fn drain(mut generator: Box<dyn CommandGenerator<()>>) -> Vec<Box<dyn Command>> {
+    let mut commands = Vec::new();
+    while let Some(cmd) = generator.next_command() {
+        commands.push(cmd);
+    }
+    commands
+}
+
+fn check_version(buf: &[u8]) -> Result<(), Refusal> {
+    if buf[0] == SOCKS5_VERSION {
+        return Ok(());
+    }
+    let mut message = format!("Invalid SOCKS version. Expected 0x05, got 0x{:02x}.", buf[0]);
+    if b"GPCDHO".contains(&buf[0]) {
+        message.push_str(" This looks like an HTTP request, did you mean to use regular proxy mode?");
+    }
+    Err(Refusal::new(None, message))
+}
+
+/// Parse the client greeting: the authentication methods it offers and the
+/// greeting's length, or `None` if more data is needed
+fn parse_greeting(buf: &[u8]) -> Result<Option<(Vec<u8>, usize)>, Refusal> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    check_version(buf)?;
+    let Some(&count) = buf.get(1) else {
+        return Ok(None);
+    };
+    let len = 2 + count as usize;
+    Ok(buf.get(2..len).map(|methods| (methods.to_vec(), len)))
+}
+
+/// Host and port of a CONNECT request
+type Destination = (String, u16);
+
+/// Parse a CONNECT request: the destination and the request's length, or
+/// `None` if more data is needed
+fn parse_request(buf: &[u8]) -> Result<Option<(Destination, usize)>, Refusal> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    check_version(buf)?;
+    if buf[1] != CMD_CONNECT {
+        return Err(Refusal::new(
+            Some(REP_COMMAND_NOT_SUPPORTED),
+            format!("Unsupported SOCKS5 request: command 0x{:02x}", buf[1]),
+        ));
+    }
+    let (host, end) = match buf[3] {
+        ATYP_IPV4_ADDRESS => match buf.get(4..8) {
+            Some(octets) => (Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).to_string(), 8),
+            None => return Ok(None),
+        },
+        ATYP_IPV6_ADDRESS => match buf.get(4..20) {
+            Some(octets) => (Ipv6Addr::from(<[u8; 16]>::try_from(octets).expect("16 bytes")).to_string(), 20),
+            None => return Ok(None),
+        },
+        ATYP_DOMAINNAME => {
+            let Some(&len) = buf.get(4) else {
+                return Ok(None);
+            };
+            let end = 5 + len as usize;
+            match buf.get(5..end) {
+                Some(name) => match std::str::from_utf8(name) {
+                    Ok(name) if !name.is_empty() => (name.to_string(), end),
+                    _ => return Err(Refusal::new(Some(REP_ADDRESS_TYPE_NOT_SUPPORTED), "Invalid SOCKS5 domain name")),
+                },
+                None => return Ok(None),
+            }
+        }
+        atyp => {
+            return Err(Refusal::new(
+                Some(REP_ADDRESS_TYPE_NOT_SUPPORTED),
+                format!("Unknown SOCKS5 address type: 0x{:02x}", atyp),
+            ))
+        }
+    };
+    Ok(buf.get(end..end + 2).map(|port| ((host, u16::from_be_bytes([port[0], port[1]])), end + 2)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::connection::Client;
+    use std::sync::Arc;
+
+    fn layer() -> Socks5Proxy {
+        Socks5Proxy::new(Context::new(Client::new(TransportProtocol::Tcp), Arc::new(Config::default())))
+    }
+
+    fn feed(layer: &mut Socks5Proxy, data: &[u8]) -> Vec<Box<dyn Command>> {
+        let connection = layer.base.context.client.connection.clone();
+        drain(layer.handle_event(AnyEvent::DataReceived(DataReceived { connection, data: data.to_vec() })))
+    }
+
+    fn sent(commands: &[Box<dyn Command>]) -> Vec<Vec<u8>> {
+        commands.iter().filter_map(|c| c.as_any().downcast_ref::<SendData>()).map(|c| c.data.clone()).collect()
+    }
+
+    fn closed(commands: &[Box<dyn Command>]) -> bool {
+        commands.iter().any(|c| c.as_any().is::<CloseConnection>())
+    }
+
+    #[test]
+    fn test_connect_domain() {
+        let mut socks = layer();
+        drain(socks.handle_event(AnyEvent::Start(Start)));
+        // Greeting split across two reads
+        assert!(feed(&mut socks, &[0x05, 0x02]).is_empty());
+        assert_eq!(sent(&feed(&mut socks, &[0x02, 0x00])), [vec![0x05, 0x00]]);
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, 11];
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&443u16.to_be_bytes());
+        request.extend_from_slice(b"hello");
+        let commands = feed(&mut socks, &request);
+        // Data sent after the request goes to the child layers
+        assert_eq!(sent(&commands), [reply(REP_SUCCEEDED), b"hello".to_vec()]);
+        assert_eq!(socks.destination(), Some(&("example.com".to_string(), 443)));
+        assert_eq!(socks.base.context.server().address, None);
+    }
+
+    #[test]
+    fn test_connect_ip() {
+        let mut socks = layer();
+        let mut data = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 10, 0, 0, 1];
+        data.extend_from_slice(&8080u16.to_be_bytes());
+        let commands = feed(&mut socks, &data);
+        assert_eq!(sent(&commands), [vec![0x05, 0x00], reply(REP_SUCCEEDED)]);
+        assert_eq!(socks.base.context.server().address, Some("10.0.0.1:8080".parse().unwrap()));
+
+        let mut socks = layer();
+        let mut data = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x04];
+        data.extend_from_slice(&"::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&80u16.to_be_bytes());
+        feed(&mut socks, &data);
+        assert_eq!(socks.destination(), Some(&("::1".to_string(), 80)));
+    }
+
+    #[test]
+    fn test_refused() {
+        let mut socks = layer();
+        let commands = feed(&mut socks, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(sent(&commands).is_empty());
+        assert!(closed(&commands));
+        assert!(feed(&mut socks, b"more").is_empty());
+
+        let mut socks = layer();
+        let commands = feed(&mut socks, &[0x05, 0x01, 0x02]);
+        assert_eq!(sent(&commands), [vec![0x05, 0xFF]]);
+        assert!(closed(&commands));
+
+        let mut socks = layer();
+        let commands = feed(&mut socks, &[0x05, 0x01, 0x00, 0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 80]);
+        assert_eq!(sent(&commands), [vec![0x05, 0x00], reply(REP_COMMAND_NOT_SUPPORTED)]);
+        assert!(closed(&commands));
+        assert!(socks.destination().is_none());
+
+        let mut socks = layer();
+        let commands = feed(&mut socks, &[0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x09]);
+        assert_eq!(sent(&commands)[1], reply(REP_ADDRESS_TYPE_NOT_SUPPORTED));
+    }
+}
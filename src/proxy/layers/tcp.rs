@@ -1,8 +1,15 @@
 //! TCP layer implementation
 //! This mirrors the Python TCP layer in mitmproxy/proxy/layers/tcp.py
-
+//!
+//! Data is relayed between client and server unchanged. Every chunk is
+//! recorded as a message of the connection's `tcp` flow, which is reported
+//! with the `tcp_start`, `tcp_message` and `tcp_end` hooks, unless the
+//! connection is ignored under `ignore_hosts` or `allow_hosts`.
+
+use crate::connection::Connection;
+use crate::flow::{HTTPFlow, TCPMessage};
 use crate::proxy::{
-    commands::SendData,
+    commands::{CloseConnection, Command, SendData, TcpEndHook, TcpMessageHook, TcpStartHook},
     context::Context,
     events::{AnyEvent, Event},
     layer::{BaseLayer, CommandGenerator, Layer, SimpleCommandGenerator},
@@ -12,53 +19,104 @@ use crate::proxy::{
 #[derive(Debug)]
 pub struct TcpLayer {
     base: BaseLayer,
+    flow: HTTPFlow,
+    closed: bool,
+    /// Relay without recording messages or running hooks
+    ignore: bool,
 }
 
 impl TcpLayer {
     pub fn new(context: Context) -> Self {
         let mut context = context;
         context.add_layer("TCP".to_string());
+
+        let (host, port) = match &context.server {
+            Some(server) => server
+                .destination
+                .clone()
+                .or_else(|| server.address.map(|addr| (addr.ip().to_string(), addr.port())))
+                .unwrap_or_default(),
+            None => Default::default(),
+        };
+        let flow = HTTPFlow::new_tcp(host, port);
         let base = BaseLayer::new(context);
 
-        Self { base }
+        Self { base, flow, closed: false, ignore: false }
+    }
+
+    /// Layer relaying a connection passed through without interception
+    pub fn ignored(context: Context) -> Self {
+        Self { ignore: true, ..Self::new(context) }
+    }
+
+    /// The flow with the messages seen so far
+    pub fn flow(&self) -> &HTTPFlow {
+        &self.flow
+    }
+
+    fn server_conn(&self) -> Connection {
+        self.base.context.server_conn().cloned().unwrap_or_default()
     }
 
     fn handle_start(&mut self) -> Box<dyn CommandGenerator<()>> {
-        let mut commands = Vec::new();
+        let mut commands: Vec<Box<dyn Command>> = Vec::new();
 
         if let Some(log_cmd) = self.base.debug_log("TCP layer started") {
             commands.push(log_cmd);
         }
+        if !self.ignore {
+            commands.push(Box::new(TcpStartHook { flow: self.flow.clone() }));
+        }
 
-        // TCP layer is ready to receive data
         Box::new(SimpleCommandGenerator::new(commands))
     }
 
-    fn handle_data_received(&mut self, data: Vec<u8>) -> Box<dyn CommandGenerator<()>> {
-        let mut commands = Vec::new();
+    fn handle_data_received(&mut self, connection: Connection, data: Vec<u8>) -> Box<dyn CommandGenerator<()>> {
+        let mut commands: Vec<Box<dyn Command>> = Vec::new();
 
         if let Some(log_cmd) = self.base.debug_log(&format!("TCP received {} bytes", data.len())) {
             commands.push(log_cmd);
         }
+        if data.is_empty() {
+            return Box::new(SimpleCommandGenerator::new(commands));
+        }
 
-        // In a real implementation, TCP layer would typically forward data to upper layers
-        // For now, we'll just echo it back (for testing purposes)
-        if let Some(server) = &self.base.context.server {
-            commands.push(Box::new(SendData {
-                connection: server.connection.clone(),
-                data,
-            }));
+        let from_client = connection == *self.base.context.client_conn();
+        let peer = if from_client { self.server_conn() } else { self.base.context.client_conn().clone() };
+        if self.ignore {
+            commands.push(Box::new(SendData { connection: peer, data }));
+            return Box::new(SimpleCommandGenerator::new(commands));
         }
+        commands.push(Box::new(SendData { connection: peer, data: data.clone() }));
+
+        let timestamp = crate::clock::now();
+        self.flow.tcp_messages.push(TCPMessage { from_client, content: data, timestamp });
+        commands.push(Box::new(TcpMessageHook { flow: self.flow.clone() }));
 
         Box::new(SimpleCommandGenerator::new(commands))
     }
 
-    fn handle_connection_closed(&mut self) -> Box<dyn CommandGenerator<()>> {
-        let mut commands = Vec::new();
+    /// Close the other side as well, which ends the flow
+    fn handle_connection_closed(&mut self, connection: Connection) -> Box<dyn CommandGenerator<()>> {
+        let mut commands: Vec<Box<dyn Command>> = Vec::new();
 
         if let Some(log_cmd) = self.base.debug_log("TCP connection closed") {
             commands.push(log_cmd);
         }
+        if self.closed {
+            return Box::new(SimpleCommandGenerator::new(commands));
+        }
+        self.closed = true;
+
+        let peer = if connection == *self.base.context.client_conn() {
+            self.server_conn()
+        } else {
+            self.base.context.client_conn().clone()
+        };
+        commands.push(Box::new(CloseConnection { connection: peer }));
+        if !self.ignore {
+            commands.push(Box::new(TcpEndHook { flow: self.flow.clone() }));
+        }
 
         Box::new(SimpleCommandGenerator::new(commands))
     }
@@ -81,9 +139,9 @@ impl Layer for TcpLayer {
         match event {
             AnyEvent::Start(_) => self.handle_start(),
             AnyEvent::DataReceived(data_event) => {
-                self.handle_data_received(data_event.data)
+                self.handle_data_received(data_event.connection, data_event.data)
             }
-            AnyEvent::ConnectionClosed(_) => self.handle_connection_closed(),
+            AnyEvent::ConnectionClosed(closed) => self.handle_connection_closed(closed.connection),
             _ => {
                 // Unknown event, log it
                 let mut commands = Vec::new();
@@ -102,4 +160,68 @@ impl Layer for TcpLayer {
     fn debug_prefix(&self) -> Option<&str> {
         self.base.debug.as_deref()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{Server, TransportProtocol};
+    use crate::filter::Filter;
+    use crate::proxy::events::{ConnectionClosed, DataReceived, Start};
+
+    fn commands(layer: &mut TcpLayer, event: AnyEvent) -> Vec<Box<dyn Command>> {
+        let mut generator = layer.handle_event(event);
+        std::iter::from_fn(|| generator.next_command()).collect()
+    }
+
+    #[test]
+    fn test_records_messages() {
+        let mut context = Context::default();
+        let mut server = Server::new(TransportProtocol::Tcp);
+        server.destination = Some(("db.internal".to_string(), 5432));
+        context.server = Some(server);
+        let client = context.client_conn().clone();
+        let server = context.server_conn().cloned().unwrap();
+        let mut layer = TcpLayer::new(context);
+
+        let started = commands(&mut layer, AnyEvent::Start(Start));
+        assert!(started.iter().any(|c| c.as_any().downcast_ref::<TcpStartHook>().is_some()));
+
+        let sent = commands(&mut layer, AnyEvent::DataReceived(DataReceived { connection: client.clone(), data: b"ping".to_vec() }));
+        let data = sent.iter().find_map(|c| c.as_any().downcast_ref::<SendData>()).unwrap();
+        assert_eq!(data.connection, server);
+        commands(&mut layer, AnyEvent::DataReceived(DataReceived { connection: server.clone(), data: b"pong".to_vec() }));
+
+        let ended = commands(&mut layer, AnyEvent::ConnectionClosed(ConnectionClosed { connection: server }));
+        let close = ended.iter().find_map(|c| c.as_any().downcast_ref::<CloseConnection>()).unwrap();
+        assert_eq!(close.connection, client);
+        let flow = &ended.iter().find_map(|c| c.as_any().downcast_ref::<TcpEndHook>()).unwrap().flow;
+        let messages: Vec<(bool, &[u8])> = flow.tcp_messages.iter().map(|m| (m.from_client, m.content.as_slice())).collect();
+        assert_eq!(messages, [(true, &b"ping"[..]), (false, &b"pong"[..])]);
+
+        let json = flow.to_json();
+        assert_eq!(json["type"], "tcp");
+        assert_eq!(json["server_address"], serde_json::json!(["db.internal", 5432]));
+        assert_eq!(json["messages"].as_array().unwrap().len(), 2);
+        assert!(Filter::new("tcp".to_string(), "~tcp".to_string()).unwrap().matches(flow));
+        assert!(!Filter::new("http".to_string(), "~http".to_string()).unwrap().matches(flow));
+    }
+    #[test]
+    fn test_ignored_connections_are_relayed_only() {
+        let mut context = Context::default();
+        context.server = Some(Server::new(TransportProtocol::Tcp));
+        let client = context.client_conn().clone();
+        let server = context.server_conn().cloned().unwrap();
+        let mut layer = TcpLayer::ignored(context);
+
+        let is_hook = |c: &Box<dyn Command>| {
+            let c = c.as_any();
+            c.is::<TcpStartHook>() || c.is::<TcpMessageHook>() || c.is::<TcpEndHook>()
+        };
+        assert!(!commands(&mut layer, AnyEvent::Start(Start)).iter().any(is_hook));
+        let sent = commands(&mut layer, AnyEvent::DataReceived(DataReceived { connection: client, data: b"ping".to_vec() }));
+        assert_eq!(sent.iter().find_map(|c| c.as_any().downcast_ref::<SendData>()).unwrap().data, b"ping");
+        assert!(!sent.iter().any(is_hook));
+        assert!(!commands(&mut layer, AnyEvent::ConnectionClosed(ConnectionClosed { connection: server })).iter().any(is_hook));
+        assert!(layer.flow().tcp_messages.is_empty());
+    }
+}
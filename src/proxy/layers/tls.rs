@@ -1,9 +1,10 @@
 //! TLS layer implementation matching mitmproxy's TLS layers
 
-use crate::connection::{Connection, Server, TransportProtocol, TlsVersion};
+use crate::config::EchMode;
+use crate::connection::{Connection, Server, SniFallback, TransportProtocol, TlsVersion};
 use crate::proxy::{
     commands::{
-        ClientHelloData, Command, Log, LogLevel, OpenConnection, SendData,
+        CaptureMalformed, ClientHelloData, CloseConnection, Command, Log, LogLevel, OpenConnection, SendData,
         TlsClienthelloHook, TlsData, TlsEstablishedClientHook, TlsEstablishedServerHook,
         TlsFailedClientHook, TlsFailedServerHook, TlsStartClientHook, TlsStartServerHook,
     },
@@ -12,13 +13,20 @@ use crate::proxy::{
     layer::{CommandGenerator, Layer, NextLayer, SimpleCommandGenerator},
     tunnel::{TunnelLayer, TunnelState},
 };
+use openssl::pkey::{PKey, Private};
 use openssl::ssl::{
-    SslContext, SslMethod, SslVerifyMode, SslOptions,
-    Ssl,
+    select_next_proto, AlpnError, ErrorCode, SslContext, SslMethod, SslVerifyMode, SslOptions,
+    Ssl, SslStream,
 };
+use openssl::x509::X509;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
 use std::time::SystemTime;
 use std::sync::Arc;
 use crate::certs::CertificateAuthority;
+use crate::corpus::Parser;
+use crate::pinning::PinningTestMode;
+use crate::tls_sessions::TlsSessionCache;
 
 /// TLS version constants
 #[allow(dead_code)]
@@ -73,7 +81,7 @@ fn get_client_hello(data: &[u8]) -> Option<Vec<u8>> {
 }
 
 /// Parse ClientHello and extract SNI and ALPN
-fn parse_client_hello(data: &[u8]) -> Option<ClientHelloData> {
+pub fn parse_client_hello(data: &[u8]) -> Option<ClientHelloData> {
     let client_hello = get_client_hello(data)?;
 
     if client_hello.is_empty() || client_hello[0] != 0x01 {
@@ -122,6 +130,7 @@ fn parse_client_hello(data: &[u8]) -> Option<ClientHelloData> {
             alpn_protocols: Vec::new(),
             ignore_connection: false,
             establish_server_tls_first: false,
+            ech: false,
         });
     }
 
@@ -135,24 +144,28 @@ fn parse_client_hello(data: &[u8]) -> Option<ClientHelloData> {
             alpn_protocols: Vec::new(),
             ignore_connection: false,
             establish_server_tls_first: false,
+            ech: false,
         });
     }
 
     let extensions_data = &payload[offset..offset + extensions_len];
-    let (sni, alpn_protocols) = parse_extensions(extensions_data);
+    let (sni, alpn_protocols, ech) = parse_extensions(extensions_data);
 
     Some(ClientHelloData {
         sni,
         alpn_protocols,
         ignore_connection: false,
         establish_server_tls_first: false,
+        ech,
     })
 }
 
-/// Parse TLS extensions to extract SNI and ALPN
-fn parse_extensions(data: &[u8]) -> (Option<String>, Vec<String>) {
+/// Parse TLS extensions to extract SNI and ALPN, and whether the
+/// ClientHello is encrypted
+fn parse_extensions(data: &[u8]) -> (Option<String>, Vec<String>, bool) {
     let mut sni = None;
     let mut alpn_protocols = Vec::new();
+    let mut ech = false;
     let mut offset = 0;
 
     while offset + 4 <= data.len() {
@@ -177,13 +190,17 @@ fn parse_extensions(data: &[u8]) -> (Option<String>, Vec<String>) {
                 // Application Layer Protocol Negotiation
                 alpn_protocols = parse_alpn_extension(ext_data);
             }
+            0xfe0d | 0xffce => {
+                // Encrypted Client Hello, or its ESNI predecessor
+                ech = true;
+            }
             _ => {}
         }
 
         offset += ext_len;
     }
 
-    (sni, alpn_protocols)
+    (sni, alpn_protocols, ech)
 }
 
 /// Parse SNI extension
@@ -265,11 +282,124 @@ fn starts_like_tls_record(data: &[u8]) -> bool {
     matches!(data[0], 20..=23) && data[1] == 0x03 && matches!(data[2], 1..=4)
 }
 
+/// In-memory transport for OpenSSL. Ciphertext received from the peer is
+/// queued in `incoming` for OpenSSL to read, and what OpenSSL writes is
+/// collected in `outgoing` until it is sent with a `SendData` command.
+#[derive(Debug, Default)]
+pub struct MemoryBio {
+    incoming: VecDeque<u8>,
+    outgoing: Vec<u8>,
+}
+
+impl Read for MemoryBio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.incoming.is_empty() {
+            // Makes OpenSSL report WANT_READ instead of end of stream
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.incoming.read(buf)
+    }
+}
+
+impl Write for MemoryBio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run a certificate authority call from the synchronous layer code
+fn block_on<F: std::future::Future>(future: F) -> Result<F::Output, String> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            Ok(tokio::task::block_in_place(|| handle.block_on(future)))
+        }
+        Ok(_) => Err("TLS interception needs the multi-threaded runtime".to_string()),
+        Err(_) => tokio::runtime::Builder::new_current_thread()
+            .build()
+            .map(|runtime| runtime.block_on(future))
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// Name to issue the client's certificate for, see
+/// [`ClientTlsLayer::set_connect_host`]
+pub(super) fn cert_hostname(tunnel: &mut TunnelLayer, connect_host: Option<&str>, sni: Option<&str>) -> Result<String, String> {
+    if let Some(sni) = sni {
+        return Ok(sni.to_string());
+    }
+    let (hostname, fallback) = if let Some(host) = connect_host {
+        (host.to_string(), SniFallback::ConnectHost(host.to_string()))
+    } else if let Some(address) = tunnel.base.context.server.as_ref().and_then(|server| server.address) {
+        (address.ip().to_string(), SniFallback::DestinationAddress(address.ip()))
+    } else {
+        return Err("Client sent no SNI and the destination is unknown".to_string());
+    };
+    tunnel.conn.sni_fallback = Some(fallback);
+    Ok(hostname)
+}
+
+/// Whether a connection is tunneled without interception under the
+/// `ignore_hosts` and `allow_hosts` options. The patterns are searched in
+/// the destination address and host, the CONNECT host and the SNI, as
+/// `host:port`.
+pub(crate) fn ignore_connection(context: &Context, connect_host: Option<&str>, sni: Option<&str>) -> bool {
+    let options = &context.options;
+    if options.ignore_hosts.is_empty() && options.allow_hosts.is_empty() {
+        return false;
+    }
+    let server = context.server.as_ref();
+    let address = server.and_then(|server| server.address);
+    let port = address.map_or(443, |address| address.port());
+    let mut hostnames: Vec<String> = address.iter().map(|address| address.to_string()).collect();
+    hostnames.extend(server.and_then(|server| server.destination.as_ref()).map(|(host, port)| format!("{}:{}", host, port)));
+    hostnames.extend(connect_host.into_iter().chain(sni).map(|host| format!("{}:{}", host, port)));
+    ignore_hostnames(&options.ignore_hosts, &options.allow_hosts, &hostnames)
+}
+
+/// Invalid certificate to present under the pinning test rules, if any
+/// matches the SNI, the CONNECT host or the destination of a connection
+pub(crate) fn pinning_test_mode(context: &Context, connect_host: Option<&str>, sni: Option<&str>) -> Option<PinningTestMode> {
+    let tests = context.options.pinning_tests.as_ref()?;
+    let server = context.server.as_ref();
+    let address = server.and_then(|server| server.address);
+    let port = address.map_or(443, |address| address.port());
+    let (host, port) = match sni.or(connect_host) {
+        Some(host) => (host.to_string(), port),
+        None => server
+            .and_then(|server| server.destination.clone())
+            .or_else(|| address.map(|address| (address.ip().to_string(), address.port())))?,
+    };
+    tests.mode_for(&host, port)
+}
+
+/// Whether a connection to any of `hostnames`, each `host:port`, is
+/// tunneled without interception under the `ignore_hosts` and
+/// `allow_hosts` patterns
+pub(crate) fn ignore_hostnames(ignore_hosts: &[String], allow_hosts: &[String], hostnames: &[String]) -> bool {
+    // Invalid patterns were reported when the proxy started
+    let matches = |patterns: &[String]| {
+        patterns
+            .iter()
+            .filter_map(|pattern| regex::RegexBuilder::new(pattern).case_insensitive(true).build().ok())
+            .any(|regex| hostnames.iter().any(|hostname| regex.is_match(hostname)))
+    };
+    if !ignore_hosts.is_empty() {
+        matches(ignore_hosts)
+    } else {
+        !allow_hosts.is_empty() && !matches(allow_hosts)
+    }
+}
+
 /// Base TLS layer that wraps tunnel functionality
 #[derive(Debug)]
 pub struct TlsLayerBase {
     pub tunnel: TunnelLayer,
-    pub ssl_connection: Option<Ssl>,
+    pub ssl_connection: Option<SslStream<MemoryBio>>,
     pub ssl_context: Option<SslContext>,
     pub is_dtls: bool,
     pub handshake_complete: bool,
@@ -321,78 +451,197 @@ impl TlsLayerBase {
         vec![hook_command]
     }
 
-    /// Handle TLS handshake data
-    pub fn handle_tls_data(&mut self, _data: &[u8]) -> Vec<Box<dyn Command>> {
-        if self.ssl_connection.is_none() {
-            return vec![Box::new(Log {
-                message: "No SSL connection available for handshake".to_string(),
-                level: LogLevel::Error,
-            })];
+    /// Whether `connection` is the one TLS is spoken on. The layer's copy
+    /// gains TLS details during the handshake, the child's copy does not.
+    fn is_tls_connection(&self, connection: &Connection) -> bool {
+        *connection == self.tunnel.tunnel_connection || *connection == self.tunnel.conn
+    }
+
+    /// Feed handshake records from the peer to OpenSSL and advance the
+    /// handshake. Once it completes, the tunnel is opened and plaintext that
+    /// arrived with the last handshake records is passed to the child layer,
+    /// after the events queued while the handshake was running.
+    pub fn receive_handshake_data(&mut self, data: &[u8], is_client: bool) -> Result<Vec<Box<dyn Command>>, String> {
+        let Some(stream) = self.ssl_connection.as_mut() else {
+            return Err("No SSL connection available for handshake".to_string());
+        };
+        stream.get_mut().incoming.extend(data);
+
+        match stream.do_handshake() {
+            Ok(()) => {}
+            Err(e) if e.code() == ErrorCode::WANT_READ => return Ok(self.tls_interact()),
+            Err(e) => return Err(e.to_string()),
         }
 
-        // In a real implementation, this would:
-        // 1. Write data to SSL BIO
-        // 2. Attempt handshake
-        // 3. Read any outgoing data from BIO
-        // 4. Send outgoing data via SendData command
-        // 5. Handle handshake completion or errors
+        self.handshake_complete = true;
+        self.tunnel.tunnel_state = TunnelState::Open;
+        let mut commands = self.tls_interact();
+        commands.extend(self.tls_established(is_client));
+        // Events the child missed while the handshake was running
+        while let Some(event) = self.tunnel.event_queue.pop_front() {
+            let child_commands = self.tunnel.event_to_child_sync(event);
+            commands.extend(self.handle_child_commands(child_commands));
+        }
+        commands.extend(self.receive_data(b""));
+        Ok(commands)
+    }
+
+    /// Decrypt application data from the peer and pass it to the child layer
+    pub fn receive_data(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
+        let Some(stream) = self.ssl_connection.as_mut() else {
+            return self.tunnel.receive_data(data);
+        };
+        stream.get_mut().incoming.extend(data);
+
+        let mut plaintext = Vec::new();
+        let mut closed = false;
+        let mut error = None;
+        let mut buf = [0u8; 16384];
+        loop {
+            match stream.ssl_read(&mut buf) {
+                Ok(n) => plaintext.extend_from_slice(&buf[..n]),
+                Err(e) if e.code() == ErrorCode::WANT_READ => break,
+                Err(e) if e.code() == ErrorCode::ZERO_RETURN => {
+                    closed = true;
+                    break;
+                }
+                Err(e) => {
+                    error = Some(e.to_string());
+                    break;
+                }
+            }
+        }
 
-        // For now, simulate handshake progress
-        vec![]
+        let mut commands = self.tls_interact();
+        if !plaintext.is_empty() {
+            let child_commands = self.tunnel.receive_data(&plaintext);
+            commands.extend(self.handle_child_commands(child_commands));
+        }
+        if let Some(err) = error {
+            commands.push(Box::new(Log {
+                message: format!("{} error on {:?}: {}", self.proto_name(), self.tunnel.conn.peername, err),
+                level: LogLevel::Warning,
+            }));
+            closed = true;
+        }
+        if closed {
+            let child_commands = self.tunnel.receive_close();
+            commands.extend(self.handle_child_commands(child_commands));
+        }
+        commands
     }
 
-    /// Initialize SSL connection for handshake
-    pub fn init_ssl_connection(&mut self, context: SslContext) -> Result<(), String> {
-        match Ssl::new(&context) {
-            Ok(ssl) => {
-                self.ssl_connection = Some(ssl);
-                self.ssl_context = Some(context);
-                Ok(())
+    /// Encrypt what the child layer sends on the TLS connection, and send
+    /// close_notify before the child closes it
+    pub fn handle_child_commands(&mut self, commands: Vec<Box<dyn Command>>) -> Vec<Box<dyn Command>> {
+        if self.ssl_connection.is_none() || !self.handshake_complete {
+            return commands;
+        }
+        let mut result = Vec::new();
+        for command in commands {
+            if let Some(send) = command.as_any().downcast_ref::<SendData>() {
+                if self.is_tls_connection(&send.connection) {
+                    result.extend(self.send_data(&send.data));
+                    continue;
+                }
             }
-            Err(e) => Err(format!("Failed to create SSL connection: {}", e)),
+            if let Some(close) = command.as_any().downcast_ref::<CloseConnection>() {
+                if self.is_tls_connection(&close.connection) {
+                    if let Some(stream) = self.ssl_connection.as_mut() {
+                        let _ = stream.shutdown();
+                    }
+                    result.extend(self.tls_interact());
+                }
+            }
+            result.push(command);
         }
+        result
     }
 
-    /// Create SSL context for client connections
-    pub fn create_client_ssl_context(
-        &self,
-        _ca: &CertificateAuthority,
-        _hostname: &str,
-    ) -> Result<SslContext, String> {
-        // Get certificate for the hostname
-        // TODO: This needs to be converted to sync CA calls or use AsyncToSyncGenerator
-        // For now, return an error as the CA interface is async
-        return Err("Certificate authority calls need to be converted to sync".to_string());
+    /// Encrypt `data` and send it to the peer
+    pub fn send_data(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
+        let Some(stream) = self.ssl_connection.as_mut() else {
+            return self.tunnel.send_data(data);
+        };
+        let mut commands = Vec::new();
+        let mut written = 0;
+        while written < data.len() {
+            match stream.ssl_write(&data[written..]) {
+                Ok(n) => written += n,
+                Err(e) => {
+                    commands.push(Box::new(Log {
+                        message: format!("Cannot send {} data: {}", self.proto_name(), e),
+                        level: LogLevel::Warning,
+                    }) as Box<dyn Command>);
+                    break;
+                }
+            }
+        }
+        let mut result = self.tls_interact();
+        result.extend(commands);
+        result
+    }
 
-        #[allow(unreachable_code)]
-        {
-            // This code is unreachable but kept for reference
-            // When CA interface is converted to sync, uncomment and fix this
+    /// Wrap `ssl` in memory buffers. `accept` is set for the side facing the
+    /// client, where the proxy acts as TLS server.
+    pub fn attach_ssl(&mut self, mut ssl: Ssl, accept: bool) -> Result<(), String> {
+        if accept {
+            ssl.set_accept_state();
+        } else {
+            ssl.set_connect_state();
+        }
+        let stream = SslStream::new(ssl, MemoryBio::default())
+            .map_err(|e| format!("Failed to create SSL connection: {}", e))?;
+        self.ssl_connection = Some(stream);
+        Ok(())
+    }
 
-            // Create SSL context
-            let mut context_builder = SslContext::builder(SslMethod::tls())
-                .map_err(|e| format!("Failed to create SSL context builder: {}", e))?;
+    /// Initialize SSL connection for handshake
+    pub fn init_ssl_connection(&mut self, context: SslContext, accept: bool) -> Result<(), String> {
+        let ssl = Ssl::new(&context).map_err(|e| format!("Failed to create SSL connection: {}", e))?;
+        self.ssl_context = Some(context);
+        self.attach_ssl(ssl, accept)
+    }
 
-            // Set certificate and private key (cert and key would come from CA)
-            // context_builder.set_certificate(&cert)
-            //     .map_err(|e| format!("Failed to set certificate: {}", e))?;
-            // context_builder.set_private_key(&key)
-            //     .map_err(|e| format!("Failed to set private key: {}", e))?;
+    /// Create SSL context for client connections, serving `cert` followed
+    /// by the CA certificate
+    pub fn create_client_ssl_context(
+        &self,
+        ca: &CertificateAuthority,
+        cert: &X509,
+        key: &PKey<Private>,
+    ) -> Result<SslContext, String> {
+        let mut context_builder = SslContext::builder(SslMethod::tls())
+            .map_err(|e| format!("Failed to create SSL context builder: {}", e))?;
 
-            // Configure TLS options
-            context_builder.set_options(SslOptions::NO_SSLV2 | SslOptions::NO_SSLV3);
-            context_builder.set_verify(SslVerifyMode::NONE);
+        context_builder.set_certificate(cert)
+            .map_err(|e| format!("Failed to set certificate: {}", e))?;
+        context_builder.set_private_key(key)
+            .map_err(|e| format!("Failed to set private key: {}", e))?;
+        let ca_cert = ca.ca_cert_der()
+            .and_then(|der| Ok(X509::from_der(&der)?))
+            .map_err(|e| format!("Failed to load CA certificate: {}", e))?;
+        context_builder.add_extra_chain_cert(ca_cert)
+            .map_err(|e| format!("Failed to add CA certificate: {}", e))?;
+
+        // Configure TLS options
+        context_builder.set_options(SslOptions::NO_SSLV2 | SslOptions::NO_SSLV3);
+        context_builder.set_verify(SslVerifyMode::NONE);
 
-            // Set ALPN protocols
-            context_builder.set_alpn_protos(b"\x08http/1.1\x08http/1.0\x02h2")
-                .map_err(|e| format!("Failed to set ALPN protocols: {}", e))?;
+        // Only HTTP/1 is spoken to clients
+        context_builder.set_alpn_select_callback(|_, client| {
+            select_next_proto(b"\x08http/1.1\x08http/1.0", client).ok_or(AlpnError::NOACK)
+        });
 
-            Ok(context_builder.build())
-        }
+        Ok(context_builder.build())
     }
 
-    /// Create SSL context for server connections
-    pub fn create_server_ssl_context(&self) -> Result<SslContext, String> {
+    /// Create SSL context for server connections. With a session cache,
+    /// sessions negotiated on the connection are stored under the key.
+    pub fn create_server_ssl_context(
+        &self,
+        sessions: Option<(&Arc<TlsSessionCache>, &str)>,
+    ) -> Result<SslContext, String> {
         let mut context_builder = SslContext::builder(SslMethod::tls())
             .map_err(|e| format!("Failed to create SSL context builder: {}", e))?;
 
@@ -404,17 +653,26 @@ impl TlsLayerBase {
         context_builder.set_alpn_protos(b"\x08http/1.1\x08http/1.0\x02h2")
             .map_err(|e| format!("Failed to set ALPN protocols: {}", e))?;
 
+        if let Some((cache, key)) = sessions {
+            cache.configure(&mut context_builder, key);
+        }
+
         Ok(context_builder.build())
     }
 
-    /// Perform TLS I/O operations
+    /// Send the ciphertext OpenSSL produced to the peer
     pub fn tls_interact(&mut self) -> Vec<Box<dyn Command>> {
-        // In a real implementation, this would:
-        // 1. Read data from SSL BIO (outgoing encrypted data)
-        // 2. Send it via SendData commands
-        // 3. Handle any errors or state changes
-
-        vec![]
+        let Some(stream) = self.ssl_connection.as_mut() else {
+            return vec![];
+        };
+        let data = std::mem::take(&mut stream.get_mut().outgoing);
+        if data.is_empty() {
+            return vec![];
+        }
+        vec![Box::new(SendData {
+            connection: self.tunnel.tunnel_connection.clone(),
+            data,
+        })]
     }
 
     /// Handle successful TLS establishment
@@ -426,38 +684,20 @@ impl TlsLayerBase {
         self.tunnel.conn.tls = true;
 
         // Extract TLS version, cipher, ALPN from SSL connection if available
-        if let Some(ref ssl) = self.ssl_connection {
-            // Extract TLS version
-            let version_str = ssl.version_str();
-            self.tunnel.conn.tls_version = match version_str {
+        if let Some(ref stream) = self.ssl_connection {
+            let ssl = stream.ssl();
+            self.tunnel.conn.tls_version = match ssl.version_str() {
                 "TLSv1.3" => Some(TlsVersion::TLSv1_3),
                 "TLSv1.2" => Some(TlsVersion::TLSv1_2),
                 "TLSv1.1" => Some(TlsVersion::TLSv1_1),
                 "TLSv1" => Some(TlsVersion::TLSv1),
                 _ => Some(TlsVersion::TLSv1_3),
             };
-
-            // Extract cipher name
-            if let Some(_cipher) = ssl.current_cipher() {
-                // In a real implementation, store cipher name in connection
-                // self.tunnel.conn.cipher = Some(cipher.name().to_string());
-            }
-
-            // Extract negotiated ALPN protocol
-            if let Some(alpn) = ssl.selected_alpn_protocol() {
-                if let Ok(_alpn_str) = std::str::from_utf8(alpn) {
-                    // In a real implementation, store ALPN in connection
-                    // self.tunnel.conn.alpn = Some(alpn_str.to_string());
-                }
-            }
-
-            // Extract peer certificates
-            if let Some(_peer_cert) = ssl.peer_certificate() {
-                // In a real implementation, store certificate list in connection
-                // if let Ok(cert_info) = crate::certs::cert_to_info(&peer_cert) {
-                //     self.tunnel.conn.certificate_list = vec![cert_info];
-                // }
-            }
+            self.tunnel.conn.cipher = ssl.current_cipher().map(|cipher| cipher.name().to_string());
+            self.tunnel.conn.alpn = ssl
+                .selected_alpn_protocol()
+                .and_then(|alpn| std::str::from_utf8(alpn).ok())
+                .map(str::to_string);
         } else {
             self.tunnel.conn.tls_version = Some(TlsVersion::TLSv1_3);
         }
@@ -503,6 +743,11 @@ pub struct ClientTlsLayer {
     pub client_hello_parsed: bool,
     pub server_tls_available: bool,
     pub ca: Option<Arc<CertificateAuthority>>,
+    /// Invalid certificate served instead of the regular one, looked up
+    /// from the pinning test rules once the ClientHello is seen
+    pub pinning_test: Option<PinningTestMode>,
+    /// Host requested with CONNECT, used when the client sends no SNI
+    pub connect_host: Option<String>,
 }
 
 impl ClientTlsLayer {
@@ -522,6 +767,8 @@ impl ClientTlsLayer {
             client_hello_parsed: false,
             server_tls_available,
             ca: None,
+            pinning_test: None,
+            connect_host: None,
         }
     }
 
@@ -530,29 +777,94 @@ impl ClientTlsLayer {
         self.ca = Some(ca);
     }
 
+    /// Remember the CONNECT host as certificate name for SNI-less clients
+    pub fn set_connect_host(&mut self, host: Option<String>) {
+        self.connect_host = host;
+    }
+
+    /// Name to issue the certificate for. Without SNI, fall back to the
+    /// CONNECT host, then to the destination address, and record which one
+    /// was used on the connection.
+    fn cert_hostname(&mut self, sni: Option<&str>) -> Result<String, String> {
+        cert_hostname(&mut self.base.tunnel, self.connect_host.as_deref(), sni)
+    }
+
+    /// Pass through or refuse a client using Encrypted Client Hello, since
+    /// the certificate would have to be issued for the public name only
+    fn handle_ech(&mut self, mut commands: Vec<Box<dyn Command>>) -> Vec<Box<dyn Command>> {
+        let public_name = self.base.tunnel.conn.sni.clone().unwrap_or_else(|| "unknown".to_string());
+        match self.base.tunnel.base.context.options.tls_ech {
+            EchMode::Passthrough => {
+                self.base.tunnel.conn.sni_fallback = Some(SniFallback::EchPassthrough);
+                commands.push(Box::new(Log {
+                    message: format!("Client uses Encrypted Client Hello (public name {}), passing through", public_name),
+                    level: LogLevel::Info,
+                }));
+                commands.extend(self.pass_through());
+                commands
+            }
+            EchMode::Reject => {
+                self.base.tunnel.conn.sni_fallback = Some(SniFallback::EchRejected);
+                commands.push(Box::new(Log {
+                    message: format!("Rejecting client using Encrypted Client Hello (public name {})", public_name),
+                    level: LogLevel::Info,
+                }));
+                commands.extend(self.base.tls_failed(true, "encrypted client hello rejected"));
+                commands.extend(self.base.tunnel.on_handshake_error("encrypted client hello rejected"));
+                commands
+            }
+        }
+    }
+
+    /// Stop intercepting and forward the buffered ClientHello as is
+    fn pass_through(&mut self) -> Vec<Box<dyn Command>> {
+        self.base.tunnel.tunnel_state = TunnelState::Open;
+        let data = std::mem::take(&mut self.recv_buffer);
+        vec![Box::new(SendData {
+            connection: self.base.tunnel.tunnel_connection.clone(),
+            data,
+        })]
+    }
+
     /// Initialize TLS context with certificate for the given hostname
     pub fn init_tls_for_hostname(&mut self, hostname: &str) -> Result<(), String> {
-        if let Some(ref ca) = self.ca {
-            let ssl_context = self.base.create_client_ssl_context(ca, hostname)?;
-            self.base.init_ssl_connection(ssl_context)?;
-            Ok(())
-        } else {
-            Err("No certificate authority available".to_string())
+        let Some(ca) = self.ca.clone() else {
+            return Err("No certificate authority available".to_string());
+        };
+        let (cert, key) = match self.pinning_test {
+            Some(mode) => block_on(ca.get_pinning_test_cert(hostname, mode))?,
+            None => block_on(ca.get_cert_for_host(hostname))?,
+        }
+        .map_err(|e| format!("Failed to get certificate for {}: {}", hostname, e))?;
+        let ssl_context = self.base.create_client_ssl_context(&ca, &cert, &key)?;
+        self.base.init_ssl_connection(ssl_context, true)
+    }
+
+    /// Continue the handshake with the client
+    fn receive_handshake_data(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
+        match self.base.receive_handshake_data(data, true) {
+            Ok(commands) => commands,
+            Err(e) => self.on_client_handshake_error(&e),
         }
     }
 
     /// Handle ClientHello data reception with proper parsing
     pub fn receive_client_hello(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
         if self.client_hello_parsed {
-            return self.base.handle_tls_data(data);
+            return self.receive_handshake_data(data);
         }
 
         self.recv_buffer.extend_from_slice(data);
 
         // Try to parse ClientHello
         match parse_client_hello(&self.recv_buffer) {
-            Some(client_hello_data) => {
+            Some(mut client_hello_data) => {
                 self.client_hello_parsed = true;
+                client_hello_data.ignore_connection |= ignore_connection(
+                    &self.base.tunnel.base.context,
+                    self.connect_host.as_deref(),
+                    client_hello_data.sni.as_deref(),
+                );
 
                 // Update connection with SNI and ALPN
                 if let Some(ref sni) = client_hello_data.sni {
@@ -574,50 +886,62 @@ impl ClientTlsLayer {
 
                 // Check if we should ignore this connection
                 if client_hello_data.ignore_connection {
-                    // Set up pass-through mode - create fake connections
-                    self.base.tunnel.tunnel_state = TunnelState::Open;
-
-                    // In pass-through mode, we need to forward the buffered data
-                    commands.push(Box::new(SendData {
-                        connection: self.base.tunnel.tunnel_connection.clone(),
-                        data: self.recv_buffer.clone(),
+                    commands.push(Box::new(Log {
+                        message: format!(
+                            "Passing through TLS connection to {}",
+                            client_hello_data.sni.as_deref().unwrap_or("unknown")
+                        ),
+                        level: LogLevel::Info,
                     }));
-                    self.recv_buffer.clear();
-
+                    commands.extend(self.pass_through());
                     return commands;
                 }
 
+                if client_hello_data.ech {
+                    return self.handle_ech(commands);
+                }
+
                 // Check if we need to establish server TLS first
                 if client_hello_data.establish_server_tls_first && self.server_tls_available {
                     let server_commands = self.start_server_tls();
                     commands.extend(server_commands);
                 }
 
-                // Initialize TLS context if we have SNI
-                if let Some(ref sni) = client_hello_data.sni {
-                    if let Err(e) = self.init_tls_for_hostname(sni) {
-                        return self.on_client_handshake_error(&format!("Failed to initialize TLS: {}", e));
-                    }
-                } else {
-                    // Use default hostname if no SNI
-                    if let Err(e) = self.init_tls_for_hostname("localhost") {
-                        return self.on_client_handshake_error(&format!("Failed to initialize TLS: {}", e));
-                    }
-                }
+                let hostname = match self.cert_hostname(client_hello_data.sni.as_deref()) {
+                    Ok(hostname) => hostname,
+                    Err(e) => return self.on_client_handshake_error(&e),
+                };
+                self.pinning_test = pinning_test_mode(
+                    &self.base.tunnel.base.context,
+                    self.connect_host.as_deref(),
+                    client_hello_data.sni.as_deref(),
+                );
 
                 // Start client TLS handshake
                 let tls_commands = self.base.start_tls(true);
                 commands.extend(tls_commands);
 
+                if let Err(e) = self.init_tls_for_hostname(&hostname) {
+                    commands.extend(self.on_client_handshake_error(&format!("Failed to initialize TLS: {}", e)));
+                    return commands;
+                }
+
+                // Answer the buffered ClientHello
+                let client_hello = std::mem::take(&mut self.recv_buffer);
+                commands.extend(self.receive_handshake_data(&client_hello));
+
                 commands
             }
             None => {
                 // Check if we have an incomplete ClientHello that might be malformed
                 if self.recv_buffer.len() > 16384 {
                     // Buffer too large, likely not a valid ClientHello
-                    return self.on_client_handshake_error(
+                    let capture = CaptureMalformed { parser: Parser::ClientHello, data: self.recv_buffer.clone() };
+                    let mut commands = self.on_client_handshake_error(
                         &format!("Cannot parse ClientHello: buffer too large ({})", self.recv_buffer.len())
                     );
+                    commands.push(Box::new(capture));
+                    return commands;
                 }
 
                 // Wait for more data
@@ -652,6 +976,12 @@ impl ClientTlsLayer {
 
         let (level, log_msg) = if err.starts_with("Cannot parse ClientHello") {
             (LogLevel::Warning, err.to_string())
+        } else if let Some(mode) = self.pinning_test {
+            // Rejecting the certificate is what a pinning client should do
+            (
+                LogLevel::Info,
+                format!("Pinning test passed: the client rejected the {} certificate for {} ({})", mode, dest, err)
+            )
         } else if err.contains("unsupported protocol") {
             (
                 LogLevel::Warning,
@@ -682,10 +1012,12 @@ impl ClientTlsLayer {
             )
         };
 
-        let mut commands = vec![Box::new(Log {
+        // Send the alert OpenSSL produced, if any
+        let mut commands = self.base.tls_interact();
+        commands.push(Box::new(Log {
             message: format!("Client TLS handshake failed. {}", log_msg),
             level,
-        }) as Box<dyn Command>];
+        }));
 
         commands.extend(self.base.tls_failed(true, err));
         commands.extend(self.base.tunnel.on_handshake_error(err));
@@ -705,19 +1037,9 @@ impl Layer for ClientTlsLayer {
         if let AnyEvent::DataReceived(data_event) = &event {
             if data_event.connection == self.base.tunnel.tunnel_connection {
                 if self.base.tunnel.tunnel_state == TunnelState::Establishing {
-                    let commands = self.receive_client_hello(&data_event.data);
-
-                    // If handshake is complete, update state
-                    if self.client_hello_parsed {
-                        self.base.tunnel.tunnel_state = TunnelState::Open;
-                        let mut all_commands = commands;
-                        all_commands.extend(self.base.tls_established(true));
-                        return Box::new(SimpleCommandGenerator::new(all_commands));
-                    }
-
-                    return Box::new(SimpleCommandGenerator::new(commands));
+                    return Box::new(SimpleCommandGenerator::new(self.receive_client_hello(&data_event.data)));
                 } else {
-                    return Box::new(SimpleCommandGenerator::new(self.base.tunnel.receive_data(&data_event.data)));
+                    return Box::new(SimpleCommandGenerator::new(self.base.receive_data(&data_event.data)));
                 }
             }
         }
@@ -727,12 +1049,14 @@ impl Layer for ClientTlsLayer {
                 if self.base.tunnel.tunnel_state == TunnelState::Establishing {
                     return Box::new(SimpleCommandGenerator::new(self.on_client_handshake_error("connection closed")));
                 } else {
-                    return Box::new(SimpleCommandGenerator::new(self.base.tunnel.receive_close()));
+                    let commands = self.base.tunnel.receive_close();
+                    return Box::new(SimpleCommandGenerator::new(self.base.handle_child_commands(commands)));
                 }
             }
         }
 
-        Box::new(SimpleCommandGenerator::new(self.base.tunnel.event_to_child_sync(event)))
+        let commands = self.base.tunnel.event_to_child_sync(event);
+        Box::new(SimpleCommandGenerator::new(self.base.handle_child_commands(commands)))
     }
 
     fn layer_name(&self) -> &'static str {
@@ -745,6 +1069,8 @@ impl Layer for ClientTlsLayer {
 pub struct ServerTlsLayer {
     pub base: TlsLayerBase,
     pub wait_for_clienthello: bool,
+    /// Key of the upstream TLS session cache entry for this destination
+    session_key: Option<String>,
 }
 
 impl ServerTlsLayer {
@@ -758,14 +1084,70 @@ impl ServerTlsLayer {
         Self {
             base,
             wait_for_clienthello: false,
+            session_key: None,
         }
     }
 
-    /// Initialize TLS context for server connection
+    /// Initialize TLS context for server connection, offering a cached
+    /// session for the destination if there is one
     pub fn init_server_tls(&mut self) -> Result<(), String> {
-        let ssl_context = self.base.create_server_ssl_context()?;
-        self.base.init_ssl_connection(ssl_context)?;
-        Ok(())
+        let cache = self.base.tunnel.base.context.options.tls_sessions.clone();
+        self.session_key = cache.as_ref().and_then(|_| self.destination());
+        let ssl_context = self.base.create_server_ssl_context(cache.as_ref().zip(self.session_key.as_deref()))?;
+        let mut ssl = Ssl::new(&ssl_context).map_err(|e| format!("Failed to create SSL connection: {}", e))?;
+        if let Some(sni) = &self.base.tunnel.conn.sni {
+            ssl.set_hostname(sni).map_err(|e| format!("Invalid server name {}: {}", sni, e))?;
+        }
+        if let (Some(cache), Some(key)) = (cache, &self.session_key) {
+            cache.resume(&mut ssl, key);
+        }
+        self.base.ssl_context = Some(ssl_context);
+        self.base.attach_ssl(ssl, false)
+    }
+
+    /// Send the ClientHello, or continue the handshake with the server
+    fn receive_handshake_data(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
+        match self.base.receive_handshake_data(data, false) {
+            Ok(commands) => {
+                if self.base.handshake_complete {
+                    self.record_resumption();
+                }
+                commands
+            }
+            Err(e) => self.on_server_handshake_error(&e),
+        }
+    }
+
+    /// Start TLS towards the server once the connection is open
+    fn start_server_tls(&mut self) -> Vec<Box<dyn Command>> {
+        let mut commands = self.base.start_tls(false);
+        if let Err(e) = self.init_server_tls() {
+            commands.push(Box::new(Log {
+                message: format!("Failed to initialize server TLS: {}", e),
+                level: LogLevel::Error,
+            }));
+            return commands;
+        }
+        commands.extend(self.receive_handshake_data(b""));
+        commands
+    }
+
+    /// `host:port` of the server, preferring the SNI over the address
+    fn destination(&self) -> Option<String> {
+        let conn = &self.base.tunnel.conn;
+        let address = conn
+            .peername
+            .or_else(|| self.base.tunnel.base.context.server.as_ref().and_then(|s| s.address))?;
+        let host = conn.sni.clone().unwrap_or_else(|| address.ip().to_string());
+        Some(format!("{}:{}", host, address.port()))
+    }
+
+    /// Count the completed handshake towards the session resumption rate
+    fn record_resumption(&self) {
+        let cache = self.base.tunnel.base.context.options.tls_sessions.as_ref();
+        if let (Some(cache), Some(key), Some(stream)) = (cache, &self.session_key, &self.base.ssl_connection) {
+            cache.record_handshake(key, stream.ssl().session_reused());
+        }
     }
 
     /// Start handshake based on configuration
@@ -780,24 +1162,8 @@ impl ServerTlsLayer {
             self.base.tunnel.tunnel_state = TunnelState::Closed;
             vec![]
         } else {
-            // Initialize TLS context
-            if let Err(e) = self.init_server_tls() {
-                return vec![Box::new(Log {
-                    message: format!("Failed to initialize server TLS: {}", e),
-                    level: LogLevel::Error,
-                })];
-            }
-
-            // Start TLS immediately
-            let mut commands = self.base.start_tls(false);
-
-            // If we have an SSL connection, start handshake
-            if self.base.ssl_connection.is_some() {
-                let handshake_commands = self.base.handle_tls_data(b"");
-                commands.extend(handshake_commands);
-            }
-
-            commands
+            self.base.tunnel.tunnel_state = TunnelState::Establishing;
+            self.start_server_tls()
         }
     }
 
@@ -812,10 +1178,12 @@ impl ServerTlsLayer {
 
     /// Handle handshake error for server
     pub fn on_server_handshake_error(&mut self, err: &str) -> Vec<Box<dyn Command>> {
-        let mut commands = vec![Box::new(Log {
+        // Send the alert OpenSSL produced, if any
+        let mut commands = self.base.tls_interact();
+        commands.push(Box::new(Log {
             message: format!("Server TLS handshake failed. {}", err),
             level: LogLevel::Warning,
-        }) as Box<dyn Command>];
+        }));
 
         commands.extend(self.base.tls_failed(false, err));
         commands.extend(self.base.tunnel.on_handshake_error(err));
@@ -833,21 +1201,9 @@ impl Layer for ServerTlsLayer {
         if let AnyEvent::DataReceived(data_event) = &event {
             if data_event.connection == self.base.tunnel.tunnel_connection {
                 if self.base.tunnel.tunnel_state == TunnelState::Establishing {
-                    // Handle TLS handshake data
-                    let mut commands = self.base.handle_tls_data(&data_event.data);
-
-                    // Check if handshake is complete
-                    if self.base.handshake_complete {
-                        self.base.tunnel.tunnel_state = TunnelState::Open;
-                        commands.extend(self.base.tls_established(false));
-                        // Forward any remaining data to child layer
-                        commands.extend(self.base.tunnel.receive_data(b""));
-                    }
-
-                    return Box::new(SimpleCommandGenerator::new(commands));
+                    return Box::new(SimpleCommandGenerator::new(self.receive_handshake_data(&data_event.data)));
                 } else if self.base.tunnel.tunnel_state == TunnelState::Open {
-                    // Forward decrypted data to child layer
-                    return Box::new(SimpleCommandGenerator::new(self.base.tunnel.receive_data(&data_event.data)));
+                    return Box::new(SimpleCommandGenerator::new(self.base.receive_data(&data_event.data)));
                 }
             }
         }
@@ -857,7 +1213,8 @@ impl Layer for ServerTlsLayer {
                 if self.base.tunnel.tunnel_state == TunnelState::Establishing {
                     return Box::new(SimpleCommandGenerator::new(self.on_server_handshake_error("connection closed")));
                 } else {
-                    return Box::new(SimpleCommandGenerator::new(self.base.tunnel.receive_close()));
+                    let commands = self.base.tunnel.receive_close();
+                    return Box::new(SimpleCommandGenerator::new(self.base.handle_child_commands(commands)));
                 }
             }
         }
@@ -884,26 +1241,15 @@ impl Layer for ServerTlsLayer {
 
             // If we found the OpenConnection, start our TLS handshake
             if found_open_connection {
-                // Initialize TLS context
-                if let Err(e) = self.init_server_tls() {
-                    filtered_commands.push(Box::new(Log {
-                        message: format!("Failed to initialize server TLS: {}", e),
-                        level: LogLevel::Error,
-                    }));
-                } else {
-                    let mut tls_commands = self.base.start_tls(false);
-                    if self.base.ssl_connection.is_some() {
-                        let handshake_commands = self.base.handle_tls_data(b"");
-                        tls_commands.extend(handshake_commands);
-                    }
-                    filtered_commands.extend(tls_commands);
-                }
+                self.base.tunnel.tunnel_state = TunnelState::Establishing;
+                filtered_commands.extend(self.start_server_tls());
             }
 
             return Box::new(SimpleCommandGenerator::new(filtered_commands));
         }
 
-        Box::new(SimpleCommandGenerator::new(self.base.tunnel.event_to_child_sync(event)))
+        let commands = self.base.tunnel.event_to_child_sync(event);
+        Box::new(SimpleCommandGenerator::new(self.base.handle_child_commands(commands)))
     }
 
     fn layer_name(&self) -> &'static str {
@@ -934,4 +1280,233 @@ impl Layer for ServerTlsLayer {
 /// - More sophisticated certificate caching
 /// - JA3 fingerprinting integration
 /// - Advanced TLS version and cipher configuration
-pub struct _TlsLayerNotes;
\ No newline at end of file
+pub struct _TlsLayerNotes;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::events::DataReceived;
+    use crate::pinning::{PinningRule, PinningTests};
+
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut ext = Vec::new();
+        for (ext_type, data) in extensions {
+            ext.extend_from_slice(&ext_type.to_be_bytes());
+            ext.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            ext.extend_from_slice(data);
+        }
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        body.extend(ext);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    fn sni(name: &str) -> (u16, Vec<u8>) {
+        let mut data = ((name.len() + 3) as u16).to_be_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        data.extend_from_slice(name.as_bytes());
+        (0x00, data)
+    }
+
+    fn layer(ech: EchMode) -> ClientTlsLayer {
+        let mut context = Context::default();
+        context.options.tls_ech = ech;
+        let mut layer = ClientTlsLayer::new(context);
+        layer.base.tunnel.tunnel_state = TunnelState::Establishing;
+        layer
+    }
+
+    #[test]
+    fn test_parse_ech_client_hello() {
+        let hello = parse_client_hello(&client_hello(&[sni("public.example"), (0xfe0d, vec![0; 8])])).unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("public.example"));
+        assert!(hello.ech);
+        assert!(!parse_client_hello(&client_hello(&[sni("example.com")])).unwrap().ech);
+    }
+
+    #[test]
+    fn test_cert_hostname_without_sni() {
+        let mut tls = layer(EchMode::Passthrough);
+        assert!(tls.cert_hostname(None).is_err());
+        assert_eq!(tls.cert_hostname(Some("example.com")).unwrap(), "example.com");
+        assert_eq!(tls.base.tunnel.conn.sni_fallback, None);
+
+        let address: std::net::SocketAddr = "192.0.2.7:443".parse().unwrap();
+        tls.base.tunnel.base.context.server = Some(Server::with_address(TransportProtocol::Tcp, address));
+        assert_eq!(tls.cert_hostname(None).unwrap(), "192.0.2.7");
+        assert_eq!(tls.base.tunnel.conn.sni_fallback, Some(SniFallback::DestinationAddress(address.ip())));
+
+        tls.set_connect_host(Some("api.example.com".to_string()));
+        assert_eq!(tls.cert_hostname(None).unwrap(), "api.example.com");
+        assert_eq!(
+            tls.base.tunnel.conn.sni_fallback,
+            Some(SniFallback::ConnectHost("api.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_ignore_hosts_pass_through() {
+        let hello = client_hello(&[sni("bank.example.com")]);
+        let address: std::net::SocketAddr = "192.0.2.7:443".parse().unwrap();
+        let tls_layer = |ignore_hosts: &[&str], allow_hosts: &[&str]| {
+            let mut tls = layer(EchMode::Passthrough);
+            let options = &mut tls.base.tunnel.base.context.options;
+            options.ignore_hosts = ignore_hosts.iter().map(|p| p.to_string()).collect();
+            options.allow_hosts = allow_hosts.iter().map(|p| p.to_string()).collect();
+            tls.base.tunnel.base.context.server = Some(Server::with_address(TransportProtocol::Tcp, address));
+            tls
+        };
+
+        let mut tls = tls_layer(&[r"^bank\.example\.com:443$"], &[]);
+        let commands = tls.receive_client_hello(&hello);
+        assert_eq!(tls.base.tunnel.tunnel_state, TunnelState::Open);
+        let forwarded = commands.iter().find_map(|c| c.as_any().downcast_ref::<SendData>()).unwrap();
+        assert_eq!(forwarded.data, hello);
+
+        // Destination addresses match too, and hosts not listed are intercepted
+        let context = &tls_layer(&["^192\\.0\\.2\\."], &[]).base.tunnel.base.context;
+        assert!(ignore_connection(context, None, None));
+        let context = &tls_layer(&["other"], &[]).base.tunnel.base.context;
+        assert!(!ignore_connection(context, None, Some("bank.example.com")));
+
+        // With allow_hosts, everything not listed is passed through
+        let context = &tls_layer(&[], &["BANK"]).base.tunnel.base.context;
+        assert!(!ignore_connection(context, None, Some("bank.example.com")));
+        assert!(ignore_connection(context, Some("shop.example.com"), None));
+
+        // So are destinations known by name, as for SOCKS clients
+        let mut tls = tls_layer(&[], &[r"^bank\.example\.com:"]);
+        let mut server = Server::new(TransportProtocol::Tcp);
+        server.destination = Some(("bank.example.com".to_string(), 443));
+        tls.base.tunnel.base.context.server = Some(server);
+        assert!(!ignore_connection(&tls.base.tunnel.base.context, None, None));
+        assert!(!ignore_hostnames(&[], &[], &["bank.example.com:443".to_string()]));
+    }
+
+    #[test]
+    fn test_pinning_test_mode_looked_up() {
+        let rules = [PinningRule { filter: "~d bank.example.com".to_string(), mode: PinningTestMode::SelfSigned }];
+        let tls_layer = || {
+            let mut tls = layer(EchMode::Passthrough);
+            tls.base.tunnel.base.context.options.pinning_tests = Some(Arc::new(PinningTests::from_rules(&rules).unwrap()));
+            tls
+        };
+
+        let mut tls = tls_layer();
+        tls.receive_client_hello(&client_hello(&[sni("bank.example.com")]));
+        assert_eq!(tls.pinning_test, Some(PinningTestMode::SelfSigned));
+        let mut tls = tls_layer();
+        tls.receive_client_hello(&client_hello(&[sni("shop.example.com")]));
+        assert_eq!(tls.pinning_test, None);
+
+        // Without SNI, the CONNECT host and then the destination are used
+        let context = &mut tls_layer().base.tunnel.base.context;
+        assert_eq!(pinning_test_mode(context, Some("bank.example.com"), None), Some(PinningTestMode::SelfSigned));
+        let mut server = Server::new(TransportProtocol::Tcp);
+        server.destination = Some(("bank.example.com".to_string(), 8443));
+        context.server = Some(server);
+        assert_eq!(pinning_test_mode(context, None, None), Some(PinningTestMode::SelfSigned));
+        assert_eq!(pinning_test_mode(&layer(EchMode::Passthrough).base.tunnel.base.context, Some("bank.example.com"), None), None);
+    }
+
+    #[test]
+    fn test_ech_passthrough_and_reject() {
+        let hello = client_hello(&[sni("public.example"), (0xfe0d, vec![0; 8])]);
+
+        let mut tls = layer(EchMode::Passthrough);
+        let commands = tls.receive_client_hello(&hello);
+        assert_eq!(tls.base.tunnel.conn.sni_fallback, Some(SniFallback::EchPassthrough));
+        assert_eq!(tls.base.tunnel.tunnel_state, TunnelState::Open);
+        let forwarded = commands.iter().find_map(|c| c.as_any().downcast_ref::<SendData>()).unwrap();
+        assert_eq!(forwarded.data, hello);
+
+        let mut tls = layer(EchMode::Reject);
+        let commands = tls.receive_client_hello(&hello);
+        assert_eq!(tls.base.tunnel.conn.sni_fallback, Some(SniFallback::EchRejected));
+        assert!(commands.iter().any(|c| c.as_any().is::<CloseConnection>()));
+        assert!(!commands.iter().any(|c| c.as_any().is::<SendData>()));
+    }
+
+    /// Child layer sending back what it receives
+    #[derive(Debug)]
+    struct Echo;
+
+    impl Layer for Echo {
+        fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
+            let commands: Vec<Box<dyn Command>> = match event {
+                AnyEvent::DataReceived(e) => vec![Box::new(SendData { connection: e.connection, data: e.data })],
+                _ => vec![],
+            };
+            Box::new(SimpleCommandGenerator::new(commands))
+        }
+
+        fn layer_name(&self) -> &'static str {
+            "Echo"
+        }
+    }
+
+    /// Feed `data` from the client and return what the layer sends back
+    fn exchange(tls: &mut ClientTlsLayer, data: Vec<u8>) -> Vec<u8> {
+        let connection = tls.base.tunnel.tunnel_connection.clone();
+        let mut generator = tls.handle_event(AnyEvent::DataReceived(DataReceived { connection: connection.clone(), data }));
+        std::iter::from_fn(|| generator.next_command())
+            .filter_map(|c| c.as_any().downcast_ref::<SendData>().filter(|s| s.connection == connection).map(|s| s.data.clone()))
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn test_client_handshake_decrypts() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = Arc::new(CertificateAuthority::new(dir.path()).unwrap());
+        let mut tls = layer(EchMode::Passthrough);
+        tls.set_ca(ca.clone());
+        tls.base.tunnel.child_layer = Some(Box::new(Echo));
+
+        // A client that verifies the certificate against the proxy's CA
+        let mut builder = SslContext::builder(SslMethod::tls()).unwrap();
+        builder.cert_store_mut().add_cert(X509::from_der(&ca.ca_cert_der().unwrap()).unwrap()).unwrap();
+        builder.set_verify(SslVerifyMode::PEER);
+        builder.set_alpn_protos(b"\x02h2\x08http/1.1").unwrap();
+        let mut ssl = Ssl::new(&builder.build()).unwrap();
+        ssl.set_hostname("example.com").unwrap();
+        ssl.param_mut().set_host("example.com").unwrap();
+        ssl.set_connect_state();
+        let mut client = SslStream::new(ssl, MemoryBio::default()).unwrap();
+
+        for _ in 0..4 {
+            let done = client.do_handshake().is_ok();
+            let data = std::mem::take(&mut client.get_mut().outgoing);
+            if done && data.is_empty() {
+                break;
+            }
+            let reply = exchange(&mut tls, data);
+            client.get_mut().incoming.extend(reply);
+        }
+        assert!(tls.base.handshake_complete);
+        assert_eq!(tls.base.tunnel.tunnel_state, TunnelState::Open);
+        assert_eq!(tls.base.tunnel.conn.sni.as_deref(), Some("example.com"));
+        assert_eq!(tls.base.tunnel.conn.alpn.as_deref(), Some("http/1.1"));
+        assert!(tls.base.tunnel.conn.cipher.is_some());
+
+        // The child sees plaintext, and what it sends is encrypted
+        client.ssl_write(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let data = std::mem::take(&mut client.get_mut().outgoing);
+        assert!(!data.windows(3).any(|w| w == b"GET"));
+        let reply = exchange(&mut tls, data);
+        assert!(!reply.windows(3).any(|w| w == b"GET"));
+        client.get_mut().incoming.extend(reply);
+        let mut buf = [0u8; 64];
+        let n = client.ssl_read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"GET / HTTP/1.1\r\n\r\n");
+    }
+}
@@ -0,0 +1,616 @@
+//! TLS layers on rustls, built with the `rustls-backend` feature and picked
+//! with the `tls_backend` option. They behave like the OpenSSL layers in
+//! [`super::tls`], with leaf certificates issued by rcgen. Upstream session
+//! resumption and pinning tests are only available with OpenSSL.
+
+use crate::certs::CertificateAuthority;
+use crate::config::EchMode;
+use crate::connection::{Connection, Server, SniFallback, TlsVersion};
+use crate::proxy::{
+    commands::{
+        CaptureMalformed, CloseConnection, Command, Log, LogLevel, SendData, TlsClienthelloHook, TlsData,
+        TlsEstablishedClientHook, TlsEstablishedServerHook, TlsFailedClientHook, TlsFailedServerHook,
+        TlsStartClientHook, TlsStartServerHook,
+    },
+    context::Context,
+    events::AnyEvent,
+    layer::{CommandGenerator, Layer, NextLayer, SimpleCommandGenerator},
+    rustls_config::{provider, NoVerification},
+    tunnel::{TunnelLayer, TunnelState},
+};
+use crate::corpus::Parser;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, ProtocolVersion, ServerConfig, ServerConnection};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::tls::{cert_hostname, ignore_connection, parse_client_hello};
+
+/// Tunnel state and rustls connection shared by both layers
+#[derive(Debug)]
+pub struct RustlsLayerBase {
+    pub tunnel: TunnelLayer,
+    pub tls: Option<rustls::Connection>,
+    pub handshake_complete: bool,
+}
+
+impl RustlsLayerBase {
+    pub fn new(context: Context, conn: Connection) -> Self {
+        let mut tunnel = TunnelLayer::new(context, conn.clone(), conn);
+        tunnel.child_layer = Some(Box::new(NextLayer::new(tunnel.base.context.clone())));
+        Self { tunnel, tls: None, handshake_complete: false }
+    }
+
+    fn tls_data(&self) -> TlsData {
+        TlsData { connection: self.tunnel.conn.clone(), is_dtls: false }
+    }
+
+    /// Start TLS handshake
+    pub fn start_tls(&mut self, is_client: bool) -> Vec<Box<dyn Command>> {
+        let data = self.tls_data();
+        if is_client {
+            vec![Box::new(TlsStartClientHook { data })]
+        } else {
+            vec![Box::new(TlsStartServerHook { data })]
+        }
+    }
+
+    /// Whether `connection` is the one TLS is spoken on
+    fn is_tls_connection(&self, connection: &Connection) -> bool {
+        *connection == self.tunnel.tunnel_connection || *connection == self.tunnel.conn
+    }
+
+    /// Hand ciphertext from the peer to rustls
+    fn feed(&mut self, mut data: &[u8]) -> Result<(), String> {
+        let Some(tls) = self.tls.as_mut() else {
+            return Err("No TLS connection available".to_string());
+        };
+        loop {
+            tls.process_new_packets().map_err(|e| e.to_string())?;
+            if data.is_empty() {
+                return Ok(());
+            }
+            tls.read_tls(&mut data).map_err(|e| e.to_string())?;
+        }
+    }
+
+    /// Feed handshake records from the peer and advance the handshake. Once
+    /// it completes, the tunnel is opened, queued events are replayed and
+    /// plaintext that arrived with the last records goes to the child layer.
+    pub fn receive_handshake_data(&mut self, data: &[u8], is_client: bool) -> Result<Vec<Box<dyn Command>>, String> {
+        self.feed(data)?;
+        if self.tls.as_ref().is_some_and(|tls| tls.is_handshaking()) {
+            return Ok(self.tls_interact());
+        }
+
+        self.handshake_complete = true;
+        self.tunnel.tunnel_state = TunnelState::Open;
+        let mut commands = self.tls_interact();
+        commands.extend(self.tls_established(is_client));
+        while let Some(event) = self.tunnel.event_queue.pop_front() {
+            let child_commands = self.tunnel.event_to_child_sync(event);
+            commands.extend(self.handle_child_commands(child_commands));
+        }
+        commands.extend(self.receive_data(b""));
+        Ok(commands)
+    }
+
+    /// Decrypt application data from the peer and pass it to the child layer
+    pub fn receive_data(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
+        if self.tls.is_none() {
+            return self.tunnel.receive_data(data);
+        }
+        let mut error = self.feed(data).err();
+
+        let tls = self.tls.as_mut().expect("checked above");
+        let mut plaintext = Vec::new();
+        let mut closed = false;
+        let mut buf = [0u8; 16384];
+        loop {
+            match tls.reader().read(&mut buf) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => plaintext.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error.get_or_insert(e.to_string());
+                    break;
+                }
+            }
+        }
+
+        let mut commands = self.tls_interact();
+        if !plaintext.is_empty() {
+            let child_commands = self.tunnel.receive_data(&plaintext);
+            commands.extend(self.handle_child_commands(child_commands));
+        }
+        if let Some(err) = error {
+            commands.push(Box::new(Log {
+                message: format!("TLS error on {:?}: {}", self.tunnel.conn.peername, err),
+                level: LogLevel::Warning,
+            }));
+            closed = true;
+        }
+        if closed {
+            let child_commands = self.tunnel.receive_close();
+            commands.extend(self.handle_child_commands(child_commands));
+        }
+        commands
+    }
+
+    /// Encrypt what the child layer sends on the TLS connection, and send
+    /// close_notify before the child closes it
+    pub fn handle_child_commands(&mut self, commands: Vec<Box<dyn Command>>) -> Vec<Box<dyn Command>> {
+        if self.tls.is_none() || !self.handshake_complete {
+            return commands;
+        }
+        let mut result = Vec::new();
+        for command in commands {
+            if let Some(send) = command.as_any().downcast_ref::<SendData>() {
+                if self.is_tls_connection(&send.connection) {
+                    result.extend(self.send_data(&send.data));
+                    continue;
+                }
+            }
+            if let Some(close) = command.as_any().downcast_ref::<CloseConnection>() {
+                if self.is_tls_connection(&close.connection) {
+                    if let Some(tls) = self.tls.as_mut() {
+                        tls.send_close_notify();
+                    }
+                    result.extend(self.tls_interact());
+                }
+            }
+            result.push(command);
+        }
+        result
+    }
+
+    /// Encrypt `data` and send it to the peer
+    pub fn send_data(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
+        let Some(tls) = self.tls.as_mut() else {
+            return self.tunnel.send_data(data);
+        };
+        let written = tls.writer().write_all(data);
+        let mut commands = self.tls_interact();
+        if let Err(e) = written {
+            commands.push(Box::new(Log { message: format!("Cannot send TLS data: {}", e), level: LogLevel::Warning }));
+        }
+        commands
+    }
+
+    /// Send the ciphertext rustls produced to the peer
+    pub fn tls_interact(&mut self) -> Vec<Box<dyn Command>> {
+        let Some(tls) = self.tls.as_mut() else {
+            return vec![];
+        };
+        let mut data = Vec::new();
+        while tls.wants_write() {
+            if tls.write_tls(&mut data).is_err() {
+                break;
+            }
+        }
+        if data.is_empty() {
+            return vec![];
+        }
+        vec![Box::new(SendData { connection: self.tunnel.tunnel_connection.clone(), data })]
+    }
+
+    /// Handle successful TLS establishment
+    pub fn tls_established(&mut self, is_client: bool) -> Vec<Box<dyn Command>> {
+        self.handshake_complete = true;
+        self.tunnel.conn.timestamp_tls_setup = Some(SystemTime::now());
+        self.tunnel.conn.tls = true;
+        if let Some(tls) = &self.tls {
+            self.tunnel.conn.tls_version = match tls.protocol_version() {
+                Some(ProtocolVersion::TLSv1_2) => Some(TlsVersion::TLSv1_2),
+                _ => Some(TlsVersion::TLSv1_3),
+            };
+            self.tunnel.conn.cipher = tls.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite()));
+            self.tunnel.conn.alpn = tls
+                .alpn_protocol()
+                .and_then(|alpn| std::str::from_utf8(alpn).ok())
+                .map(str::to_string);
+        }
+
+        let data = self.tls_data();
+        if is_client {
+            vec![Box::new(TlsEstablishedClientHook { data })]
+        } else {
+            vec![Box::new(TlsEstablishedServerHook { data })]
+        }
+    }
+
+    /// Handle TLS handshake failure
+    pub fn tls_failed(&mut self, is_client: bool, error: &str) -> Vec<Box<dyn Command>> {
+        self.tunnel.conn.error = Some(error.to_string());
+        let data = self.tls_data();
+        let mut commands = self.tls_interact();
+        if is_client {
+            commands.push(Box::new(TlsFailedClientHook { data }));
+        } else {
+            commands.push(Box::new(TlsFailedServerHook { data }));
+        }
+        commands.extend(self.tunnel.on_handshake_error(error));
+        commands
+    }
+}
+
+/// Client TLS layer on rustls
+#[derive(Debug)]
+pub struct RustlsClientTlsLayer {
+    pub base: RustlsLayerBase,
+    pub recv_buffer: Vec<u8>,
+    pub client_hello_parsed: bool,
+    pub ca: Option<Arc<CertificateAuthority>>,
+    /// Host requested with CONNECT, used when the client sends no SNI
+    pub connect_host: Option<String>,
+}
+
+impl RustlsClientTlsLayer {
+    pub fn new(context: Context) -> Self {
+        let client_conn = context.client.connection.clone();
+        Self {
+            base: RustlsLayerBase::new(context, client_conn),
+            recv_buffer: Vec::new(),
+            client_hello_parsed: false,
+            ca: None,
+            connect_host: None,
+        }
+    }
+
+    /// Set the certificate authority for this layer
+    pub fn set_ca(&mut self, ca: Arc<CertificateAuthority>) {
+        self.ca = Some(ca);
+    }
+
+    /// Remember the CONNECT host as certificate name for SNI-less clients
+    pub fn set_connect_host(&mut self, host: Option<String>) {
+        self.connect_host = host;
+    }
+
+    /// Server configuration presenting a certificate for `hostname`
+    fn server_config(&self, hostname: &str) -> Result<ServerConfig, String> {
+        let ca = self.ca.as_ref().ok_or("No certificate authority available")?;
+        let (cert, key) = ca.get_rcgen_cert_for_host(hostname).map_err(|e| e.to_string())?;
+        let ca_cert = ca.ca_cert_der().map_err(|e| e.to_string())?;
+        let chain = vec![CertificateDer::from(cert), CertificateDer::from(ca_cert)];
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+        let mut config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+            .map_err(|e| e.to_string())?;
+        // Only HTTP/1 is spoken to clients
+        config.alpn_protocols = vec![b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+        Ok(config)
+    }
+
+    /// Handle ClientHello data reception
+    pub fn receive_client_hello(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
+        if self.client_hello_parsed {
+            return self.receive_handshake_data(data);
+        }
+        self.recv_buffer.extend_from_slice(data);
+
+        let Some(mut client_hello) = parse_client_hello(&self.recv_buffer) else {
+            if self.recv_buffer.len() > 16384 {
+                let capture = CaptureMalformed { parser: Parser::ClientHello, data: self.recv_buffer.clone() };
+                let mut commands = self.on_handshake_error(
+                    &format!("Cannot parse ClientHello: buffer too large ({})", self.recv_buffer.len())
+                );
+                commands.push(Box::new(capture));
+                return commands;
+            }
+            return vec![];
+        };
+        self.client_hello_parsed = true;
+        client_hello.ignore_connection |=
+            ignore_connection(&self.base.tunnel.base.context, self.connect_host.as_deref(), client_hello.sni.as_deref());
+        if let Some(ref sni) = client_hello.sni {
+            self.base.tunnel.conn.sni = Some(sni.clone());
+        }
+        let mut commands: Vec<Box<dyn Command>> = vec![Box::new(TlsClienthelloHook { data: client_hello.clone() })];
+
+        if client_hello.ignore_connection {
+            commands.push(Box::new(Log {
+                message: format!("Passing through TLS connection to {}", client_hello.sni.as_deref().unwrap_or("unknown")),
+                level: LogLevel::Info,
+            }));
+            commands.extend(self.pass_through());
+            return commands;
+        }
+        if client_hello.ech {
+            let public_name = client_hello.sni.as_deref().unwrap_or("unknown");
+            if self.base.tunnel.base.context.options.tls_ech == EchMode::Passthrough {
+                self.base.tunnel.conn.sni_fallback = Some(SniFallback::EchPassthrough);
+                commands.push(Box::new(Log {
+                    message: format!("Client uses Encrypted Client Hello (public name {}), passing through", public_name),
+                    level: LogLevel::Info,
+                }));
+                commands.extend(self.pass_through());
+            } else {
+                self.base.tunnel.conn.sni_fallback = Some(SniFallback::EchRejected);
+                commands.extend(self.base.tls_failed(true, "encrypted client hello rejected"));
+            }
+            return commands;
+        }
+
+        let hostname = match cert_hostname(&mut self.base.tunnel, self.connect_host.as_deref(), client_hello.sni.as_deref()) {
+            Ok(hostname) => hostname,
+            Err(e) => return self.on_handshake_error(&e),
+        };
+        commands.extend(self.base.start_tls(true));
+        match self.server_config(&hostname).and_then(|config| ServerConnection::new(Arc::new(config)).map_err(|e| e.to_string())) {
+            Ok(tls) => self.base.tls = Some(tls.into()),
+            Err(e) => {
+                commands.extend(self.on_handshake_error(&format!("Failed to initialize TLS: {}", e)));
+                return commands;
+            }
+        }
+
+        let client_hello = std::mem::take(&mut self.recv_buffer);
+        commands.extend(self.receive_handshake_data(&client_hello));
+        commands
+    }
+
+    /// Continue the handshake with the client
+    fn receive_handshake_data(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
+        match self.base.receive_handshake_data(data, true) {
+            Ok(commands) => commands,
+            Err(e) => self.on_handshake_error(&e),
+        }
+    }
+
+    /// Stop intercepting and forward the buffered ClientHello as is
+    fn pass_through(&mut self) -> Vec<Box<dyn Command>> {
+        self.base.tunnel.tunnel_state = TunnelState::Open;
+        let data = std::mem::take(&mut self.recv_buffer);
+        vec![Box::new(SendData { connection: self.base.tunnel.tunnel_connection.clone(), data })]
+    }
+
+    fn on_handshake_error(&mut self, err: &str) -> Vec<Box<dyn Command>> {
+        let dest = self.base.tunnel.conn.sni.clone().unwrap_or_else(|| "unknown".to_string());
+        let mut commands: Vec<Box<dyn Command>> = vec![Box::new(Log {
+            message: format!("Client TLS handshake failed. The client may not trust the proxy's certificate for {} ({})", dest, err),
+            level: LogLevel::Warning,
+        })];
+        commands.extend(self.base.tls_failed(true, err));
+        commands
+    }
+}
+
+impl Layer for RustlsClientTlsLayer {
+    fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
+        let commands = match &event {
+            AnyEvent::Start(_) => {
+                self.base.tunnel.tunnel_state = TunnelState::Establishing;
+                self.base.tunnel.event_to_child_sync(event)
+            }
+            AnyEvent::DataReceived(e) if e.connection == self.base.tunnel.tunnel_connection => {
+                if self.base.tunnel.tunnel_state == TunnelState::Establishing {
+                    self.receive_client_hello(&e.data)
+                } else {
+                    self.base.receive_data(&e.data)
+                }
+            }
+            AnyEvent::ConnectionClosed(e) if e.connection == self.base.tunnel.tunnel_connection => {
+                if self.base.tunnel.tunnel_state == TunnelState::Establishing {
+                    self.on_handshake_error("connection closed")
+                } else {
+                    let commands = self.base.tunnel.receive_close();
+                    self.base.handle_child_commands(commands)
+                }
+            }
+            _ => {
+                let commands = self.base.tunnel.event_to_child_sync(event);
+                self.base.handle_child_commands(commands)
+            }
+        };
+        Box::new(SimpleCommandGenerator::new(commands))
+    }
+
+    fn layer_name(&self) -> &'static str {
+        "RustlsClientTlsLayer"
+    }
+}
+
+/// Server TLS layer on rustls
+#[derive(Debug)]
+pub struct RustlsServerTlsLayer {
+    pub base: RustlsLayerBase,
+}
+
+impl RustlsServerTlsLayer {
+    pub fn new(context: Context, conn: Option<Server>) -> Self {
+        let server_conn = conn
+            .map(|s| s.connection)
+            .unwrap_or_else(|| context.server.as_ref().map(|s| s.connection.clone()).unwrap_or_default());
+        Self { base: RustlsLayerBase::new(context, server_conn) }
+    }
+
+    /// Client connection to the server, which is not verified, as with the
+    /// OpenSSL layer
+    fn client_connection(&self) -> Result<ClientConnection, String> {
+        let conn = &self.base.tunnel.conn;
+        let address = conn
+            .peername
+            .or_else(|| self.base.tunnel.base.context.server.as_ref().and_then(|s| s.address));
+        let name = match (&conn.sni, address) {
+            (Some(sni), _) => ServerName::try_from(sni.clone()).map_err(|e| format!("Invalid server name {}: {}", sni, e))?,
+            (None, Some(address)) => ServerName::from(address.ip()),
+            (None, None) => return Err("The server address is unknown".to_string()),
+        };
+        let mut config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider())))
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec(), b"http/1.0".to_vec(), b"h2".to_vec()];
+        ClientConnection::new(Arc::new(config), name).map_err(|e| e.to_string())
+    }
+
+    /// Send the ClientHello to the server
+    pub fn start_handshake(&mut self) -> Vec<Box<dyn Command>> {
+        self.base.tunnel.tunnel_state = TunnelState::Establishing;
+        let mut commands = self.base.start_tls(false);
+        match self.client_connection() {
+            Ok(tls) => self.base.tls = Some(tls.into()),
+            Err(e) => {
+                commands.extend(self.on_handshake_error(&format!("Failed to initialize server TLS: {}", e)));
+                return commands;
+            }
+        }
+        commands.extend(self.receive_handshake_data(b""));
+        commands
+    }
+
+    fn receive_handshake_data(&mut self, data: &[u8]) -> Vec<Box<dyn Command>> {
+        match self.base.receive_handshake_data(data, false) {
+            Ok(commands) => commands,
+            Err(e) => self.on_handshake_error(&e),
+        }
+    }
+
+    fn on_handshake_error(&mut self, err: &str) -> Vec<Box<dyn Command>> {
+        let mut commands: Vec<Box<dyn Command>> = vec![Box::new(Log {
+            message: format!("Server TLS handshake failed. {}", err),
+            level: LogLevel::Warning,
+        })];
+        commands.extend(self.base.tls_failed(false, err));
+        commands
+    }
+}
+
+impl Layer for RustlsServerTlsLayer {
+    fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
+        let commands = match &event {
+            AnyEvent::Start(_) => self.start_handshake(),
+            AnyEvent::DataReceived(e) if e.connection == self.base.tunnel.tunnel_connection => {
+                match self.base.tunnel.tunnel_state {
+                    TunnelState::Establishing => self.receive_handshake_data(&e.data),
+                    TunnelState::Open => self.base.receive_data(&e.data),
+                    _ => vec![],
+                }
+            }
+            AnyEvent::ConnectionClosed(e) if e.connection == self.base.tunnel.tunnel_connection => {
+                if self.base.tunnel.tunnel_state == TunnelState::Establishing {
+                    self.on_handshake_error("connection closed")
+                } else {
+                    let commands = self.base.tunnel.receive_close();
+                    self.base.handle_child_commands(commands)
+                }
+            }
+            _ => {
+                let commands = self.base.tunnel.event_to_child_sync(event);
+                self.base.handle_child_commands(commands)
+            }
+        };
+        Box::new(SimpleCommandGenerator::new(commands))
+    }
+
+    fn layer_name(&self) -> &'static str {
+        "RustlsServerTlsLayer"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::events::DataReceived;
+
+    /// Child layer sending back what it receives
+    #[derive(Debug)]
+    struct Echo;
+
+    impl Layer for Echo {
+        fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
+            let commands: Vec<Box<dyn Command>> = match event {
+                AnyEvent::DataReceived(e) => vec![Box::new(SendData { connection: e.connection, data: e.data })],
+                _ => vec![],
+            };
+            Box::new(SimpleCommandGenerator::new(commands))
+        }
+
+        fn layer_name(&self) -> &'static str {
+            "Echo"
+        }
+    }
+
+    /// Feed `data` from the client and return what the layer sends back
+    fn exchange(tls: &mut RustlsClientTlsLayer, data: Vec<u8>) -> Vec<u8> {
+        let connection = tls.base.tunnel.tunnel_connection.clone();
+        let mut generator = tls.handle_event(AnyEvent::DataReceived(DataReceived { connection: connection.clone(), data }));
+        std::iter::from_fn(|| generator.next_command())
+            .filter_map(|c| c.as_any().downcast_ref::<SendData>().filter(|s| s.connection == connection).map(|s| s.data.clone()))
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn test_rustls_client_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = Arc::new(CertificateAuthority::new(dir.path()).unwrap());
+        let mut tls = RustlsClientTlsLayer::new(Context::default());
+        tls.set_ca(ca.clone());
+        tls.base.tunnel.tunnel_state = TunnelState::Establishing;
+        tls.base.tunnel.child_layer = Some(Box::new(Echo));
+
+        // A client verifying the rcgen leaf against the proxy's CA
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(ca.ca_cert_der().unwrap())).unwrap();
+        let mut config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let name = ServerName::try_from("example.com").unwrap();
+        let mut client = ClientConnection::new(Arc::new(config), name).unwrap();
+
+        let flush = |client: &mut ClientConnection| {
+            let mut data = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut data).unwrap();
+            }
+            data
+        };
+        while client.is_handshaking() {
+            let reply = exchange(&mut tls, flush(&mut client));
+            client.read_tls(&mut reply.as_slice()).unwrap();
+            client.process_new_packets().unwrap();
+        }
+        let reply = exchange(&mut tls, flush(&mut client));
+        client.read_tls(&mut reply.as_slice()).unwrap();
+        assert!(tls.base.handshake_complete);
+        assert_eq!(tls.base.tunnel.conn.sni.as_deref(), Some("example.com"));
+        assert_eq!(tls.base.tunnel.conn.alpn.as_deref(), Some("http/1.1"));
+
+        client.writer().write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let reply = exchange(&mut tls, flush(&mut client));
+        client.read_tls(&mut reply.as_slice()).unwrap();
+        client.process_new_packets().unwrap();
+        let mut buf = [0u8; 64];
+        let n = client.reader().read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_rustls_server_sends_client_hello() {
+        let mut context = Context::default();
+        context.server = Some(Server::new(crate::connection::TransportProtocol::Tcp));
+        let mut tls = RustlsServerTlsLayer::new(context, None);
+        tls.base.tunnel.conn.sni = Some("example.com".to_string());
+        let mut generator = tls.handle_event(AnyEvent::Start(crate::proxy::events::Start));
+        let hello: Vec<u8> = std::iter::from_fn(|| generator.next_command())
+            .filter_map(|c| c.as_any().downcast_ref::<SendData>().map(|s| s.data.clone()))
+            .flatten()
+            .collect();
+        let parsed = parse_client_hello(&hello).unwrap();
+        assert_eq!(parsed.sni.as_deref(), Some("example.com"));
+        assert!(parsed.alpn_protocols.contains(&"h2".to_string()));
+        assert_eq!(tls.base.tunnel.tunnel_state, TunnelState::Establishing);
+    }
+}
@@ -1,19 +1,188 @@
 //! WebSocket layer implementation
 //! This mirrors the Python WebSocket layer in mitmproxy/proxy/layers/websocket.py
+//!
+//! The layer takes over a connection after a `101 Switching Protocols`
+//! response. Frames are relayed unchanged in both directions as soon as
+//! they are complete; text and binary messages are reassembled from their
+//! fragments, recorded in the flow's `WebSocketFlow` and reported with the
+//! `websocket_message` hook. Messages compressed with `permessage-deflate`
+//! are recorded decompressed. The flow ends once both sides sent a close
+//! frame, or when either connection goes away.
+//!
+//! Close frames are relayed as sent, so both sides see the peer's code and
+//! reason. A connection going away without a close frame is recorded as an
+//! abnormal closure (1006) and flow error, and the other side's connection
+//! is closed the same way. A frame violating the protocol is not relayed;
+//! both sides get a 1002 close frame instead.
+//! Flows matching a configured close rule are closed by the proxy, or have
+//! their close frames rewritten, with the rule's code.
 
-use crate::proxy::{Layer, Context, AnyEvent, CommandGenerator, SimpleCommandGenerator};
-use crate::flow::{WebSocketMessage, WebSocketMessageType};
+use crate::connection::Connection;
+use crate::filter::Filter;
+use crate::flow::{FlowError, HTTPFlow, WebSocketFlow, WebSocketMessage, WebSocketMessageType};
+use crate::proxy::commands::{
+    CloseConnection, Command, Log, LogLevel, SendData, WebsocketEndHook, WebsocketMessageHook, WebsocketStartHook,
+};
+use crate::proxy::{AnyEvent, CommandGenerator, Context, Layer, SimpleCommandGenerator};
+use crate::websocket::{WebSocketCloseAction, WebSocketCloseRule};
+use flate2::{Decompress, FlushDecompress, Status};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
+use tracing::debug;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Close code recorded when the peer violated the protocol
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Close code recorded for close frames without a status code
+const CLOSE_NO_STATUS: u16 = 1005;
+/// Close code recorded when a connection closed without a close frame
+const CLOSE_ABNORMAL: u16 = 1006;
+
+/// Largest message that is reassembled, after decompression
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// A complete frame as read from the wire
+#[derive(Debug)]
+struct Frame {
+    fin: bool,
+    /// Set on the first frame of a `permessage-deflate` compressed message
+    compressed: bool,
+    opcode: u8,
+    /// Unmasked payload
+    payload: Vec<u8>,
+    /// The frame as received, forwarded to the other side
+    raw: Vec<u8>,
+}
+
+/// Frame parser and message state for one direction
+#[derive(Debug, Default)]
+struct Direction {
+    buf: Vec<u8>,
+    /// Opcode, compression and payload of a fragmented message in progress
+    fragments: Option<(u8, bool, Vec<u8>)>,
+    /// Decompression context shared by the messages of this direction
+    inflater: Option<Decompress>,
+    close_received: bool,
+}
+
+impl Direction {
+    /// Take the next complete frame from the buffer
+    fn next_frame(&mut self) -> Result<Option<Frame>, String> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let compressed = buf[0] & 0x40 != 0;
+        if buf[0] & 0x30 != 0 || (compressed && self.inflater.is_none()) {
+            return Err("reserved bits set without a negotiated extension".to_string());
+        }
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+        let (length, mut offset) = match buf[1] & 0x7f {
+            126 if buf.len() < 4 => return Ok(None),
+            126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() < 10 => return Ok(None),
+            127 => (u64::from_be_bytes(buf[2..10].try_into().expect("eight bytes")), 10),
+            length => (length as u64, 2),
+        };
+        if length > MAX_MESSAGE_SIZE as u64 {
+            return Err(format!("frame of {} bytes exceeds the size limit", length));
+        }
+        let mask = if masked {
+            if buf.len() < offset + 4 {
+                return Ok(None);
+            }
+            offset += 4;
+            Some([buf[offset - 4], buf[offset - 3], buf[offset - 2], buf[offset - 1]])
+        } else {
+            None
+        };
+        let end = offset + length as usize;
+        if buf.len() < end {
+            return Ok(None);
+        }
+
+        let mut payload = buf[offset..end].to_vec();
+        if let Some(mask) = mask {
+            payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[i % 4]);
+        }
+        let raw = self.buf.drain(..end).collect();
+        Ok(Some(Frame { fin, compressed, opcode, payload, raw }))
+    }
+
+    /// Decompress a complete `permessage-deflate` message (RFC 7692)
+    fn inflate(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>, String> {
+        let inflater = self.inflater.as_mut().ok_or("compressed message without permessage-deflate")?;
+        data.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+        let start = inflater.total_in();
+        let mut content = Vec::with_capacity(data.len() * 2);
+        loop {
+            if content.len() == content.capacity() {
+                content.reserve(content.len().max(1024));
+            }
+            let read = (inflater.total_in() - start) as usize;
+            let status = inflater
+                .decompress_vec(&data[read..], &mut content, FlushDecompress::Sync)
+                .map_err(|e| format!("invalid compressed message: {}", e))?;
+            let read = (inflater.total_in() - start) as usize;
+            if status == Status::StreamEnd || (read == data.len() && content.len() < content.capacity()) {
+                return Ok(content);
+            }
+            if content.len() > MAX_MESSAGE_SIZE {
+                return Err("decompressed message exceeds the size limit".to_string());
+            }
+        }
+    }
+}
 
 /// WebSocket layer for handling WebSocket connections
 #[derive(Debug)]
 pub struct WebSocketLayer {
-    _context: Context,
+    context: Context,
+    flow: HTTPFlow,
+    client_conn: Connection,
+    server_conn: Connection,
+    client: Direction,
+    server: Direction,
+    /// Close rule matching the flow, if any
+    close_rule: Option<WebSocketCloseRule>,
+    ended: bool,
 }
 
 impl WebSocketLayer {
-    pub fn new(context: Context) -> Self {
-        Self { _context: context }
+    /// Create the layer for a flow whose handshake completed. The flow gets
+    /// an empty `WebSocketFlow` if it has none yet.
+    pub fn new(context: Context, mut flow: HTTPFlow) -> Self {
+        let deflate = flow
+            .response
+            .as_ref()
+            .and_then(|r| r.get_header("sec-websocket-extensions"))
+            .is_some_and(|extensions| extensions.to_ascii_lowercase().contains("permessage-deflate"));
+        flow.websocket.get_or_insert_with(WebSocketFlow::default);
+
+        let direction = || Direction { inflater: deflate.then(|| Decompress::new(false)), ..Default::default() };
+        Self {
+            client_conn: context.client_conn().clone(),
+            server_conn: context.server_conn().cloned().unwrap_or_default(),
+            context,
+            flow,
+            client: direction(),
+            server: direction(),
+            close_rule: None,
+            ended: false,
+        }
+    }
+
+    /// The flow with the messages seen so far
+    pub fn flow(&self) -> &HTTPFlow {
+        &self.flow
     }
 
     /// Convert WebSocket message to tungstenite message
@@ -28,21 +197,492 @@ impl WebSocketLayer {
             WebSocketMessageType::Ping => Ok(Message::Ping(ws_msg.content.clone())),
             WebSocketMessageType::Pong => Ok(Message::Pong(ws_msg.content.clone())),
             WebSocketMessageType::Close => {
-                // For close messages, we just send a generic close frame
-                // The actual close code/reason will be handled by the connection close logic
-                Ok(Message::Close(None))
+                // The content is the close frame's payload: code and reason
+                let frame = (ws_msg.content.len() >= 2).then(|| CloseFrame {
+                    code: u16::from_be_bytes([ws_msg.content[0], ws_msg.content[1]]).into(),
+                    reason: String::from_utf8_lossy(&ws_msg.content[2..]).into_owned().into(),
+                });
+                Ok(Message::Close(frame))
             }
         }
     }
+
+    fn websocket(&mut self) -> &mut WebSocketFlow {
+        self.flow.websocket.get_or_insert_with(WebSocketFlow::default)
+    }
+
+    fn direction(&mut self, from_client: bool) -> &mut Direction {
+        if from_client {
+            &mut self.client
+        } else {
+            &mut self.server
+        }
+    }
+
+    /// The connection messages from this side are forwarded to
+    fn peer(&self, from_client: bool) -> Connection {
+        if from_client {
+            self.server_conn.clone()
+        } else {
+            self.client_conn.clone()
+        }
+    }
+
+    /// Look up the first close rule matching the flow and apply it
+    fn start(&mut self) -> Vec<Box<dyn Command>> {
+        let mut commands: Vec<Box<dyn Command>> = vec![Box::new(WebsocketStartHook { flow: self.flow.clone() })];
+        for rule in &self.context.options.websocket_close_rules {
+            let filter = rule.validate().and_then(|()| Filter::new("websocket_close".to_string(), rule.filter.clone()));
+            match filter {
+                Ok(filter) if filter.matches(&self.flow) => {
+                    self.close_rule = Some(rule.clone());
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => commands.push(Box::new(Log {
+                    message: format!("Ignoring WebSocket close rule {}: {}", rule.filter, e),
+                    level: LogLevel::Warning,
+                })),
+            }
+        }
+        if let Some(rule) = self.close_rule.clone().filter(|rule| rule.action == WebSocketCloseAction::Close) {
+            commands.push(Box::new(Log {
+                message: format!("Closing WebSocket to {} with {} by rule", self.flow.request.host, rule.code),
+                level: LogLevel::Info,
+            }));
+            let websocket = self.websocket();
+            websocket.close_code = Some(rule.code);
+            websocket.close_reason = rule.reason.clone();
+            commands.extend(self.send_close(rule.code, rule.reason.as_deref().unwrap_or("")));
+            commands.extend(self.end());
+        }
+        commands
+    }
+
+    fn receive_data(&mut self, from_client: bool, data: Vec<u8>) -> Vec<Box<dyn Command>> {
+        let mut commands = Vec::new();
+        if self.ended {
+            return commands;
+        }
+        self.direction(from_client).buf.extend(data);
+        while !self.ended {
+            match self.direction(from_client).next_frame() {
+                Ok(Some(frame)) => commands.extend(self.handle_frame(from_client, frame)),
+                Ok(None) => break,
+                Err(e) => commands.extend(self.fail(from_client, e)),
+            }
+        }
+        commands
+    }
+
+    fn handle_frame(&mut self, from_client: bool, frame: Frame) -> Vec<Box<dyn Command>> {
+        let mut commands: Vec<Box<dyn Command>> =
+            vec![Box::new(SendData { connection: self.peer(from_client), data: frame.raw })];
+        match frame.opcode {
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                let direction = self.direction(from_client);
+                let (opcode, compressed, mut content) = match (frame.opcode, direction.fragments.take()) {
+                    (OPCODE_CONTINUATION, Some(fragments)) => fragments,
+                    (OPCODE_CONTINUATION, None) => {
+                        return self.fail(from_client, "continuation frame without a message".to_string());
+                    }
+                    (_, Some(_)) => {
+                        return self.fail(from_client, "new message before the previous one ended".to_string());
+                    }
+                    (opcode, None) => (opcode, frame.compressed, Vec::new()),
+                };
+                content.extend(frame.payload);
+                if content.len() > MAX_MESSAGE_SIZE {
+                    return self.fail(from_client, "message exceeds the size limit".to_string());
+                }
+                if !frame.fin {
+                    direction.fragments = Some((opcode, compressed, content));
+                    return commands;
+                }
+                if compressed {
+                    content = match direction.inflate(content) {
+                        Ok(content) => content,
+                        Err(e) => {
+                            return self.fail(from_client, e);
+                        }
+                    };
+                }
+                let message_type =
+                    if opcode == OPCODE_TEXT { WebSocketMessageType::Text } else { WebSocketMessageType::Binary };
+                commands.extend(self.record(from_client, message_type, content));
+            }
+            OPCODE_CLOSE => {
+                let code = match frame.payload.len() {
+                    0 => CLOSE_NO_STATUS,
+                    1 => {
+                        return self.fail(from_client, "close frame with a truncated code".to_string());
+                    }
+                    _ => u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                };
+                let reason = frame.payload.get(2..).map(|r| String::from_utf8_lossy(r).into_owned()).filter(|r| !r.is_empty());
+                let websocket = self.websocket();
+                if websocket.close_code.is_none() {
+                    websocket.closed_by_client = Some(from_client);
+                    websocket.close_code = Some(code);
+                    websocket.close_reason = reason;
+                }
+                if let Some(rule) = self.close_rule.as_ref().filter(|rule| rule.action == WebSocketCloseAction::Rewrite) {
+                    let payload = close_payload(rule.code, rule.reason.as_deref().unwrap_or(""));
+                    let data = encode_frame(OPCODE_CLOSE, &payload, from_client.then(|| self.mask()));
+                    commands[0] = Box::new(SendData { connection: self.peer(from_client), data });
+                }
+                self.direction(from_client).close_received = true;
+                if self.client.close_received && self.server.close_received {
+                    commands.extend(self.end());
+                }
+            }
+            OPCODE_PING | OPCODE_PONG => {}
+            opcode => return self.fail(from_client, format!("unknown opcode {:#x}", opcode)),
+        }
+        commands
+    }
+
+    /// Store a complete message and report it
+    fn record(&mut self, from_client: bool, message_type: WebSocketMessageType, content: Vec<u8>) -> Vec<Box<dyn Command>> {
+        let timestamp = self.now();
+        let websocket = self.websocket();
+        websocket.messages_meta.count += 1;
+        websocket.messages_meta.content_length += content.len();
+        websocket.messages_meta.timestamp_last = Some(timestamp);
+        websocket.messages.push(WebSocketMessage { content, from_client, timestamp, message_type });
+        vec![Box::new(WebsocketMessageHook { flow: self.flow.clone() })]
+    }
+
+    /// Send a message that was not received from either side
+    fn inject(&mut self, message: WebSocketMessage) -> Vec<Box<dyn Command>> {
+        if self.ended {
+            return Vec::new();
+        }
+        let opcode = match message.message_type {
+            WebSocketMessageType::Text => OPCODE_TEXT,
+            WebSocketMessageType::Binary => OPCODE_BINARY,
+            WebSocketMessageType::Ping => OPCODE_PING,
+            WebSocketMessageType::Pong => OPCODE_PONG,
+            WebSocketMessageType::Close => OPCODE_CLOSE,
+        };
+        // Frames sent to the server must be masked
+        let mask = message.from_client.then(|| self.mask());
+        let data = encode_frame(opcode, &message.content, mask);
+        let mut commands: Vec<Box<dyn Command>> =
+            vec![Box::new(SendData { connection: self.peer(message.from_client), data })];
+        if matches!(message.message_type, WebSocketMessageType::Text | WebSocketMessageType::Binary) {
+            commands.extend(self.record(message.from_client, message.message_type, message.content));
+        }
+        commands
+    }
+
+    fn connection_closed(&mut self, from_client: bool) -> Vec<Box<dyn Command>> {
+        if self.ended {
+            return Vec::new();
+        }
+        let websocket = self.websocket();
+        if websocket.close_code.is_none() {
+            websocket.closed_by_client = Some(from_client);
+            websocket.close_code = Some(CLOSE_ABNORMAL);
+            let side = if from_client { "client" } else { "server" };
+            self.flow.flow.error = Some(FlowError {
+                msg: format!("WebSocket connection closed abnormally by {}", side),
+                timestamp: self.now(),
+            });
+        }
+        self.end()
+    }
+
+    /// Abort after a protocol violation by one side
+    fn fail(&mut self, from_client: bool, reason: String) -> Vec<Box<dyn Command>> {
+        let side = if from_client { "client" } else { "server" };
+        let message = format!("WebSocket protocol error from {}: {}", side, reason);
+        let websocket = self.websocket();
+        if websocket.close_code.is_none() {
+            websocket.closed_by_client = Some(from_client);
+            websocket.close_code = Some(CLOSE_PROTOCOL_ERROR);
+            websocket.close_reason = Some(reason.clone());
+        }
+        self.flow.flow.error = Some(FlowError { msg: message.clone(), timestamp: self.now() });
+        let mut commands: Vec<Box<dyn Command>> = vec![Box::new(Log { message, level: LogLevel::Warning })];
+        commands.extend(self.send_close(CLOSE_PROTOCOL_ERROR, &reason));
+        commands.extend(self.end());
+        commands
+    }
+
+    /// Send a close frame of the proxy's own to each side that was not sent
+    /// one yet
+    fn send_close(&self, code: u16, reason: &str) -> Vec<Box<dyn Command>> {
+        let payload = close_payload(code, reason);
+        let mut commands: Vec<Box<dyn Command>> = Vec::new();
+        // A side that sent a close frame had it relayed to its peer already
+        if !self.server.close_received {
+            let data = encode_frame(OPCODE_CLOSE, &payload, None);
+            commands.push(Box::new(SendData { connection: self.client_conn.clone(), data }));
+        }
+        if !self.client.close_received {
+            let data = encode_frame(OPCODE_CLOSE, &payload, Some(self.mask()));
+            commands.push(Box::new(SendData { connection: self.server_conn.clone(), data }));
+        }
+        commands
+    }
+
+    fn now(&self) -> f64 {
+        self.context.clock.now()
+    }
+
+    /// Masking key of a frame sent to the server
+    fn mask(&self) -> [u8; 4] {
+        let mut mask = [0; 4];
+        self.context.ids.fill_random(&mut mask);
+        mask
+    }
+
+    fn end(&mut self) -> Vec<Box<dyn Command>> {
+        if self.ended {
+            return Vec::new();
+        }
+        self.ended = true;
+        self.websocket().timestamp_end = Some(self.now());
+        vec![
+            Box::new(WebsocketEndHook { flow: self.flow.clone() }),
+            Box::new(CloseConnection { connection: self.client_conn.clone() }),
+            Box::new(CloseConnection { connection: self.server_conn.clone() }),
+        ]
+    }
 }
 
 impl Layer for WebSocketLayer {
-    fn handle_event(&mut self, _event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
-        // TODO: Implement WebSocket event handling
-        Box::new(SimpleCommandGenerator::empty())
+    fn handle_event(&mut self, event: AnyEvent) -> Box<dyn CommandGenerator<()>> {
+        let commands = match event {
+            AnyEvent::Start(_) => {
+                if self.context.options.proxy_debug {
+                    debug!("WebSocket started for {}", self.flow.request.url());
+                }
+                self.start()
+            }
+            AnyEvent::DataReceived(e) => {
+                let from_client = e.connection == self.client_conn;
+                self.receive_data(from_client, e.data)
+            }
+            AnyEvent::ConnectionClosed(e) => {
+                let from_client = e.connection == self.client_conn;
+                self.connection_closed(from_client)
+            }
+            AnyEvent::WebSocketMessageInjected(e) => self.inject(e.message),
+            _ => Vec::new(),
+        };
+        Box::new(SimpleCommandGenerator::new(commands))
     }
 
     fn layer_name(&self) -> &'static str {
         "WebSocketLayer"
     }
-}
\ No newline at end of file
+}
+
+/// Encode a single unfragmented frame
+fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0x00 };
+    match payload.len() {
+        len if len < 126 => frame.push(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
+
+/// Payload of a close frame, with the reason cut to fit a control frame
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{Server, TransportProtocol};
+    use crate::flow::{HTTPRequest, HTTPResponse};
+    use crate::proxy::events::{ConnectionClosed, DataReceived, Start};
+
+    fn websocket_layer() -> WebSocketLayer {
+        let mut context = Context::default();
+        let mut server = Server::new(TransportProtocol::Tcp);
+        server.address = Some("93.184.216.34:80".parse().unwrap());
+        context.server = Some(server);
+        let request = HTTPRequest::new("GET".to_string(), "http".to_string(), "example.com".to_string(), 80, "/ws".to_string());
+        let flow = HTTPFlow::new(request).with_response(HTTPResponse::new(101, "Switching Protocols".to_string()));
+        WebSocketLayer::new(context, flow)
+    }
+
+    fn commands(layer: &mut WebSocketLayer, event: AnyEvent) -> Vec<Box<dyn Command>> {
+        let mut generator = layer.handle_event(event);
+        std::iter::from_fn(|| generator.next_command()).collect()
+    }
+
+    fn receive(layer: &mut WebSocketLayer, from_client: bool, data: Vec<u8>) -> Vec<Box<dyn Command>> {
+        let connection = if from_client { layer.client_conn.clone() } else { layer.server_conn.clone() };
+        commands(layer, AnyEvent::DataReceived(DataReceived { connection, data }))
+    }
+
+    fn sent(commands: &[Box<dyn Command>]) -> Vec<&SendData> {
+        commands.iter().filter_map(|c| c.as_any().downcast_ref::<SendData>()).collect()
+    }
+
+    #[test]
+    fn test_relay_and_record_messages() {
+        let mut layer = websocket_layer();
+        let started = commands(&mut layer, AnyEvent::Start(Start));
+        assert!(started[0].as_any().downcast_ref::<WebsocketStartHook>().is_some());
+
+        // A masked text message from the client, split across two reads
+        let frame = encode_frame(OPCODE_TEXT, b"hello", Some([1, 2, 3, 4]));
+        assert!(receive(&mut layer, true, frame[..4].to_vec()).is_empty());
+        let relayed = receive(&mut layer, true, frame[4..].to_vec());
+        let data = sent(&relayed);
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].data, frame);
+        assert_eq!(data[0].connection, layer.server_conn);
+        let hook = relayed[1].as_any().downcast_ref::<WebsocketMessageHook>().unwrap();
+        assert_eq!(hook.flow.websocket.as_ref().unwrap().messages[0].content, b"hello");
+
+        // A fragmented binary message from the server, with a ping in between
+        let mut first = encode_frame(OPCODE_BINARY, &[1, 2], None);
+        first[0] &= 0x7f;
+        let mut data = first;
+        data.extend(encode_frame(OPCODE_PING, b"", None));
+        data.extend(encode_frame(OPCODE_CONTINUATION, &[3], None));
+        let relayed = receive(&mut layer, false, data);
+        assert_eq!(sent(&relayed).len(), 3);
+        assert!(sent(&relayed).iter().all(|s| s.connection == layer.client_conn));
+
+        let websocket = layer.flow().websocket.as_ref().unwrap();
+        assert_eq!(websocket.messages.len(), 2);
+        assert!(!websocket.messages[1].from_client);
+        assert_eq!(websocket.messages[1].content, vec![1, 2, 3]);
+        assert!(matches!(websocket.messages[1].message_type, WebSocketMessageType::Binary));
+        assert_eq!(websocket.messages_meta.count, 2);
+        assert_eq!(websocket.messages_meta.content_length, 8);
+    }
+
+    #[test]
+    fn test_close_handshake() {
+        let mut layer = websocket_layer();
+        let mut payload = 1000u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        let relayed = receive(&mut layer, true, encode_frame(OPCODE_CLOSE, &payload, Some([9, 9, 9, 9])));
+        assert_eq!(relayed.len(), 1);
+
+        let closed = receive(&mut layer, false, encode_frame(OPCODE_CLOSE, &payload, None));
+        assert!(closed.iter().any(|c| c.as_any().downcast_ref::<WebsocketEndHook>().is_some()));
+        assert_eq!(closed.iter().filter(|c| c.as_any().downcast_ref::<CloseConnection>().is_some()).count(), 2);
+        let websocket = layer.flow().websocket.as_ref().unwrap();
+        assert_eq!(websocket.closed_by_client, Some(true));
+        assert_eq!(websocket.close_code, Some(1000));
+        assert_eq!(websocket.close_reason.as_deref(), Some("bye"));
+        assert!(websocket.timestamp_end.is_some());
+
+        // Nothing happens after the flow ended
+        let connection = layer.server_conn.clone();
+        assert!(commands(&mut layer, AnyEvent::ConnectionClosed(ConnectionClosed { connection })).is_empty());
+    }
+
+    #[test]
+    fn test_abnormal_close_and_protocol_error() {
+        let mut layer = websocket_layer();
+        let connection = layer.server_conn.clone();
+        let ended = commands(&mut layer, AnyEvent::ConnectionClosed(ConnectionClosed { connection }));
+        assert!(ended[0].as_any().downcast_ref::<WebsocketEndHook>().is_some());
+        // The client's connection is closed without a close frame as well
+        assert!(sent(&ended).is_empty());
+        let websocket = layer.flow().websocket.as_ref().unwrap();
+        assert_eq!((websocket.closed_by_client, websocket.close_code), (Some(false), Some(CLOSE_ABNORMAL)));
+        assert!(layer.flow().flow.error.as_ref().unwrap().msg.contains("abnormally by server"));
+
+        let mut layer = websocket_layer();
+        let failed = receive(&mut layer, true, encode_frame(OPCODE_CONTINUATION, b"x", Some([0; 4])));
+        assert!(failed.iter().any(|c| c.as_any().downcast_ref::<Log>().is_some()));
+        assert_eq!(layer.flow().websocket.as_ref().unwrap().close_code, Some(CLOSE_PROTOCOL_ERROR));
+        assert!(layer.flow().flow.error.is_some());
+        // Both sides are told about the error, the server with a masked frame
+        let closes = sent(&failed);
+        assert_eq!(closes.len(), 2);
+        assert_eq!(closes[0].connection, layer.client_conn);
+        assert_eq!((closes[0].data[0], &closes[0].data[2..4]), (0x88, &1002u16.to_be_bytes()[..]));
+        assert_eq!(closes[1].connection, layer.server_conn);
+        assert_ne!(closes[1].data[1] & 0x80, 0);
+    }
+
+    #[test]
+    fn test_close_rules() {
+        let rule = |action| WebSocketCloseRule {
+            filter: "~d example.com".to_string(),
+            code: 4001,
+            reason: Some("blocked".to_string()),
+            action,
+        };
+
+        let mut layer = websocket_layer();
+        layer.context.options.websocket_close_rules = vec![rule(WebSocketCloseAction::Close)];
+        let started = commands(&mut layer, AnyEvent::Start(Start));
+        let closes = sent(&started);
+        assert_eq!(closes.len(), 2);
+        let mut expected = encode_frame(OPCODE_CLOSE, &close_payload(4001, "blocked"), None);
+        assert_eq!(closes[0].data, expected);
+        assert!(started.iter().any(|c| c.as_any().downcast_ref::<WebsocketEndHook>().is_some()));
+        let websocket = layer.flow().websocket.as_ref().unwrap();
+        assert_eq!((websocket.closed_by_client, websocket.close_code), (None, Some(4001)));
+
+        // Close frames are relayed with the rule's code, but recorded as sent
+        let mut layer = websocket_layer();
+        layer.context.options.websocket_close_rules = vec![rule(WebSocketCloseAction::Rewrite)];
+        commands(&mut layer, AnyEvent::Start(Start));
+        let relayed = receive(&mut layer, false, encode_frame(OPCODE_CLOSE, &close_payload(1000, ""), None));
+        expected = encode_frame(OPCODE_CLOSE, &close_payload(4001, "blocked"), None);
+        assert_eq!(sent(&relayed)[0].data, expected);
+        assert_eq!(layer.flow().websocket.as_ref().unwrap().close_code, Some(1000));
+
+        // Close frames without a code are recorded as 1005
+        let mut layer = websocket_layer();
+        receive(&mut layer, true, encode_frame(OPCODE_CLOSE, b"", Some([1; 4])));
+        assert_eq!(layer.flow().websocket.as_ref().unwrap().close_code, Some(CLOSE_NO_STATUS));
+    }
+
+    #[test]
+    fn test_permessage_deflate() {
+        use flate2::{Compress, Compression, FlushCompress};
+
+        let mut layer = websocket_layer();
+        let response = layer.flow.response.as_mut().unwrap();
+        response.headers.push(("Sec-WebSocket-Extensions".to_string(), "permessage-deflate".to_string()));
+        let mut layer = WebSocketLayer::new(layer.context.clone(), layer.flow.clone());
+
+        let mut compressor = Compress::new(Compression::default(), false);
+        let mut compressed = Vec::with_capacity(64);
+        compressor.compress_vec(b"compressed hello", &mut compressed, FlushCompress::Sync).unwrap();
+        compressed.truncate(compressed.len() - 4);
+        let mut frame = encode_frame(OPCODE_TEXT, &compressed, None);
+        frame[0] |= 0x40;
+
+        receive(&mut layer, false, frame);
+        assert_eq!(layer.flow().websocket.as_ref().unwrap().messages[0].content, b"compressed hello");
+    }
+}
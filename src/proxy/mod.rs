@@ -6,9 +6,14 @@
 
 pub mod commands;
 pub mod context;
+pub mod dns;
 pub mod events;
+#[cfg(feature = "http3")]
+pub mod http3;
 pub mod layer;
 pub mod layers;
+#[cfg(any(feature = "http3", feature = "rustls-backend"))]
+pub mod rustls_config;
 pub mod server;
 pub mod tunnel;
 
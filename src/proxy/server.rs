@@ -1,15 +1,58 @@
 //! Proxy server implementation
 //! This mirrors the Python proxy server in mitmproxy/proxy/server.py
 
-use crate::proxy::{Context, Layer, AnyEvent};
+use crate::proxy::{Context, ContextOptions, Layer, AnyEvent};
+use crate::adaptation::{AdaptationService, Adapter, Phase};
+use crate::allowlist::{Allowlist, AllowlistChange, AllowlistOptions};
+use crate::blocklist::BlockList;
+use crate::addons::{Addon, AddonInfo, AddonManager};
+use crate::analysis::cors::CorsTracker;
+use crate::analysis::downgrades;
+use crate::analysis::endpoints::EndpointTemplater;
+use crate::capture_profiles::CaptureProfiles;
+use crate::certs::{CaStatus, CertificateAuthority};
 use crate::connection::{Client, Connection, TransportProtocol};
-use crate::config::Config;
-use crate::flow::HTTPFlow;
+use crate::changelog;
+use crate::coalesce::Coalescer;
+use crate::config::{Config, ProxyMode};
+use crate::cookie_policy::CookiePolicy;
+use crate::corpus::{FuzzCorpus, Parser};
+use crate::csp::{self, CspReport};
+use crate::dns::DnsCache;
+use crate::eventlog::{EventLog, LogEntry, LogLevel};
+use crate::expectations::{ExpectationResult, ExpectationSpec, Expectations};
+use crate::filter::Filter;
+use crate::flow::{HTTPFlow, HTTPRequest, HTTPResponse};
+use crate::gauges::{GaugeSnapshot, Gauges, Throughput};
+use crate::header_profiles::HeaderProfile;
+use crate::intercept::Intercept;
+use crate::janitor::{self, PruneStats};
+use crate::lazybody::{self, LazyBody};
+use crate::listeners::ListenerScopes;
+use crate::maplocal::MapLocal;
+use crate::mapremote::MapRemote;
+use crate::metrics::{Metrics, MetricsSummary};
+use crate::modifybody::ModifyBody;
+use crate::modifyheaders::ModifyHeaders;
+use crate::ranges;
+use crate::pinning::{PinningRule, PinningTests};
+use crate::proxychain::{self, ProxyChain, UpstreamProxyStatus};
+use crate::replay::{self, ReplayOptions, ReplayOutcome};
+use crate::rewrites::Rewrites;
+use crate::sandbox::Sandbox;
+use crate::save::{SaveStream, SaveStreamWriter, Segment};
+use crate::serverreplay::{ServerReplay, ServerReplayStatus};
+use crate::shaping::{Shaper, ShapingPlan};
+use crate::store::{self, FlowStore, MemoryStore};
+use crate::tls_sessions::TlsSessionCache;
+use crate::upstream::UpstreamRouter;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::borrow::Cow;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
-use std::collections::HashMap;
-use tokio::sync::RwLock;
-use tracing::{debug, info, error};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tracing::{debug, info, error, warn};
 
 /// Main proxy server that handles incoming connections
 #[derive(Debug)]
@@ -18,77 +61,1329 @@ pub struct ProxyServer {
     #[allow(dead_code)]
     connections: HashMap<String, Box<dyn Layer>>,
     /// Flow storage for API access
-    flows: RwLock<HashMap<String, HTTPFlow>>,
+    flows: RwLock<Box<dyn FlowStore>>,
+    /// Assertions checked against completed flows
+    expectations: RwLock<Expectations>,
+    /// Segmented recording of completed flows, if a save-stream file is set
+    save_stream: Option<SaveStreamWriter>,
+    /// Save stream pruned flows are archived to
+    archive: Option<Mutex<SaveStream>>,
+    prune_stats: std::sync::Mutex<PruneStats>,
+    /// Response shaping rules
+    shaper: Shaper,
+    /// Registered addons and their timers
+    addons: RwLock<AddonManager>,
+    /// Request and response rewriting rules, shared with the HTTP layer
+    rewrites: Arc<Rewrites>,
+    /// Hosts served invalid certificates to test client pinning
+    pinning_tests: Arc<PinningTests>,
+    /// SOCKS5 proxies server connections are routed through
+    upstream: UpstreamRouter,
+    /// Upstream DNS lookups and connect failures
+    dns_cache: Arc<DnsCache>,
+    /// Upstream TLS sessions and resumption counts
+    tls_sessions: Arc<TlsSessionCache>,
+    /// Requests answered with local files
+    map_local: Arc<MapLocal>,
+    /// Rewriting of request URLs
+    map_remote: Arc<MapRemote>,
+    /// Requests answered with a fixed status code
+    block_list: Arc<BlockList>,
+    /// Recorded responses answering matching requests
+    server_replay: Arc<ServerReplay>,
+    /// Destinations the proxy may connect to in allowlist-only mode
+    allowlist: Arc<std::sync::RwLock<Allowlist>>,
+    /// External services adapting messages before they are forwarded
+    adapter: Adapter,
+    /// Limits and capabilities of user scripts
+    sandbox: Sandbox,
+    /// Identical in-flight GET requests sharing one upstream request
+    coalescer: Coalescer,
+    /// Cutting large response bodies down to a preview
+    lazy_body: LazyBody,
+    /// Captured CSP violation reports, oldest first
+    csp_reports: std::sync::Mutex<Vec<CspReport>>,
+    /// CORS preflights awaiting their request, for flagging mismatches
+    cors: CorsTracker,
+    /// Set-Cookie attribute downgrades
+    cookie_policy: CookiePolicy,
+    /// Serve a static set of flows without accepting modifications
+    read_only: bool,
+    /// Whether proxied traffic is stored as flows
+    recording: AtomicBool,
+    /// Flows proxied while recording was paused
+    unrecorded: AtomicU64,
+    /// Rolling per-host aggregates of proxied traffic
+    metrics: std::sync::Mutex<Metrics>,
+    /// Open connections and transferred bytes
+    gauges: Arc<Gauges>,
+    /// Flows as they complete, for live tails
+    completed: broadcast::Sender<HTTPFlow>,
+    /// Event-stream flows still receiving events, by flow ID
+    open_streams: RwLock<HashMap<String, HTTPFlow>>,
+    /// Flows changed while still in progress, for live dashboards
+    updates: broadcast::Sender<HTTPFlow>,
+    /// Sequence number of the last stored flow
+    last_seq: AtomicU64,
+    /// Event log shown to API clients
+    events: Arc<std::sync::Mutex<EventLog>>,
+    /// CA generated certificates are signed with
+    ca: Option<Arc<CertificateAuthority>>,
+    /// Grouping of flows by endpoint for analysis
+    endpoints: EndpointTemplater,
+    /// Options overriding the global ones for single listeners
+    listeners: ListenerScopes,
+    /// Capture levels and intercept rules per client identity
+    capture_profiles: CaptureProfiles,
+    /// Flows held by the `intercept` rule or a capture profile
+    intercept: Arc<Intercept>,
+    /// Held flows stored only while they are held, since flows like them
+    /// are not recorded
+    held_only: std::sync::Mutex<HashSet<String>>,
+    /// Where input that failed to parse is saved, if enabled
+    fuzz_corpus: Option<FuzzCorpus>,
+}
+
+/// How the response hook wants a response delivered to the client
+#[derive(Debug, Clone, Default)]
+pub struct Delivery {
+    /// Response to send instead of the flow's, when it is re-compressed
+    pub compressed: Option<HTTPResponse>,
+    /// Chunks and pace of the body, when a shaping rule applies
+    pub shaping: Option<ShapingPlan>,
 }
 
 impl ProxyServer {
     /// Create a new proxy server
     pub fn new(config: Arc<Config>) -> Self {
+        crate::panics::install_hook();
+        if let Some(deterministic) = &config.deterministic {
+            crate::clock::install(deterministic);
+        }
+
+        let expectations = Expectations::from_specs(&config.expectations).unwrap_or_else(|e| {
+            warn!("Ignoring configured expectations: {}", e);
+            Expectations::default()
+        });
+
+        let save_stream = config.save_stream_file.as_ref().and_then(|path| {
+            let path = config.expand_path(path);
+            match SaveStream::open(&path, config.save_stream_max_size, config.save_stream_rotate_secs)
+                .and_then(SaveStreamWriter::spawn)
+            {
+                Ok(stream) => Some(stream),
+                Err(e) => {
+                    warn!("Cannot open save stream {}: {}", path, e);
+                    None
+                }
+            }
+        });
+
+        let archive = config.janitor.archive_file.as_ref().and_then(|path| {
+            let path = config.expand_path(path);
+            match SaveStream::open(&path, config.save_stream_max_size, config.save_stream_rotate_secs) {
+                Ok(stream) => Some(Mutex::new(stream)),
+                Err(e) => {
+                    warn!("Cannot open flow archive {}, flows will not be pruned: {}", path, e);
+                    None
+                }
+            }
+        });
+
+        let shaper = Shaper::from_rules(&config.shaping_rules).unwrap_or_else(|e| {
+            warn!("Ignoring configured shaping rules: {}", e);
+            Shaper::default()
+        });
+
+        let pinning_tests = PinningTests::from_rules(&config.pinning_tests).unwrap_or_else(|e| {
+            warn!("Ignoring configured pinning tests: {}", e);
+            PinningTests::default()
+        });
+        if !pinning_tests.is_empty() {
+            warn!(
+                "Certificate pinning test mode is active: {} rule(s) serve certificates that matching clients must reject",
+                pinning_tests.rules().len()
+            );
+        }
+
+        for pattern in config.ignore_hosts.iter().chain(&config.allow_hosts) {
+            if let Err(e) = regex::Regex::new(pattern) {
+                warn!("Ignoring invalid host pattern {}: {}", pattern, e);
+            }
+        }
+        if !config.ignore_hosts.is_empty() && !config.allow_hosts.is_empty() {
+            warn!("Ignoring allow_hosts: ignore_hosts is set");
+        }
+
+        #[cfg(not(feature = "rustls-backend"))]
+        if config.tls_backend == crate::config::TlsBackend::Rustls {
+            warn!("Using OpenSSL for TLS: built without the rustls-backend feature");
+        }
+
+        let dns_cache = Arc::new(DnsCache::new(config.dns_cache.clone()));
+        let mut upstream = UpstreamRouter::new(config.socks_upstream.as_deref(), &config.socks_upstream_rules)
+            .unwrap_or_else(|e| {
+                warn!("Ignoring configured SOCKS5 upstream: {}", e);
+                UpstreamRouter::default()
+            })
+            .with_dns_cache(dns_cache.clone())
+            .with_socket_options(config.sockets.server.clone());
+        if let (ProxyMode::Upstream, Some(server)) = (&config.mode, &config.upstream_server) {
+            match ProxyChain::new(server, &config.upstream_proxies) {
+                Ok(chain) => upstream = upstream.with_proxy_chain(Arc::new(chain)),
+                Err(e) => warn!("Ignoring configured upstream proxies: {}", e),
+            }
+        }
+
+        let tls_sessions = Arc::new(TlsSessionCache::new(config.tls_session_cache.clone()));
+
+        let allowlist = Allowlist::new(&config.allowlist).unwrap_or_else(|e| {
+            // Refusing everything is the safe reading of a broken allowlist
+            warn!("Ignoring configured allowlist hosts: {}", e);
+            let enabled = AllowlistOptions { enabled: config.allowlist.enabled, hosts: Vec::new() };
+            Allowlist::new(&enabled).unwrap_or_default()
+        });
+        if allowlist.is_enabled() {
+            info!("Allowlist-only mode: connecting only to {}", allowlist.options().hosts.join(", "));
+        }
+
+        let map_local = MapLocal::from_specs(&config.map_local).unwrap_or_else(|e| {
+            warn!("Ignoring configured map_local rules: {}", e);
+            MapLocal::default()
+        });
+        let map_remote = MapRemote::from_specs(&config.map_remote).unwrap_or_else(|e| {
+            warn!("Ignoring configured map_remote rules: {}", e);
+            MapRemote::default()
+        });
+        let block_list = BlockList::from_specs(&config.block_list).unwrap_or_else(|e| {
+            warn!("Ignoring configured block_list rules: {}", e);
+            BlockList::default()
+        });
+        let server_replay = ServerReplay::load(&config.server_replay, |path| config.expand_path(path)).unwrap_or_else(|e| {
+            warn!("Ignoring configured server_replay flows: {}", e);
+            ServerReplay::default()
+        });
+
+        let adapter = Adapter::new(&config.adaptation_services).unwrap_or_else(|e| {
+            warn!("Ignoring configured adaptation services: {}", e);
+            Adapter::default()
+        });
+
+        let sandbox = Sandbox::new(&config.script_sandbox).unwrap_or_else(|e| {
+            warn!("Ignoring script capability grants: {}", e);
+            Sandbox::new(&crate::sandbox::SandboxOptions {
+                allow_filesystem: Vec::new(),
+                ..config.script_sandbox.clone()
+            })
+            .unwrap_or_default()
+        });
+
+        let rewrites = Rewrites::new(&config, &sandbox);
+
+        let coalescer = Coalescer::new(&config.request_coalescing).unwrap_or_else(|e| {
+            warn!("Request coalescing disabled: {}", e);
+            Coalescer::default()
+        });
+
+        let lazy_body = LazyBody::new(&config.lazy_body).unwrap_or_else(|e| {
+            warn!("Lazy body fetching disabled: {}", e);
+            LazyBody::default()
+        });
+
+        let cookie_policy = CookiePolicy::new(&config.cookie_policy).unwrap_or_else(|e| {
+            warn!("Ignoring configured cookie rules: {}", e);
+            CookiePolicy::default()
+        });
+
+        let endpoints = EndpointTemplater::new(&config.endpoints).unwrap_or_else(|e| {
+            warn!("Ignoring configured endpoint rules: {}", e);
+            EndpointTemplater::default()
+        });
+
+        let listeners = ListenerScopes::new(&config.listeners).unwrap_or_else(|e| {
+            warn!("Ignoring configured listener options: {}", e);
+            ListenerScopes::default()
+        });
+        for id in listeners.unknown_ids() {
+            warn!("Options configured for unknown listener {}", id);
+        }
+        let capture_profiles = CaptureProfiles::new(&config.capture_profiles).unwrap_or_else(|e| {
+            warn!("Ignoring configured capture profiles: {}", e);
+            CaptureProfiles::default()
+        });
+        let intercept = Intercept::new(config.intercept.as_deref()).unwrap_or_else(|e| {
+            warn!("Ignoring configured intercept filter: {}", e);
+            Intercept::default()
+        });
+
+        let fuzz_corpus = config.fuzz_corpus_dir.as_ref().and_then(|dir| {
+            let dir = config.expand_path(dir);
+            match FuzzCorpus::new(&dir) {
+                Ok(corpus) => Some(corpus),
+                Err(e) => {
+                    warn!("Cannot open fuzz corpus directory {}: {}", dir, e);
+                    None
+                }
+            }
+        });
+
+        let flows = store::open(&config.flow_store, &config.expand_path(&config.flow_store.path)).unwrap_or_else(|e| {
+            warn!("Ignoring configured flow store: {}", e);
+            Box::new(MemoryStore::default())
+        });
+        // Flows kept by a persistent store from earlier runs are numbered on
+        let last_seq = scan(&*flows, None).map(|flow| flow.flow.seq).max().unwrap_or(0);
+        if last_seq > 0 {
+            info!("Flow store holds {} flows of earlier runs", or_warn(flows.len()));
+        }
+
+        let recording = AtomicBool::new(config.record);
+        let metrics = std::sync::Mutex::new(Metrics::new(config.metrics.clone()));
+        if config.aggregate_only {
+            info!("Aggregate-only mode: flows are not stored, only per-host metrics are kept");
+        }
+
         Self {
             config,
             connections: HashMap::new(),
-            flows: RwLock::new(HashMap::new()),
+            flows: RwLock::new(flows),
+            expectations: RwLock::new(expectations),
+            save_stream,
+            archive,
+            prune_stats: std::sync::Mutex::default(),
+            shaper,
+            rewrites: Arc::new(rewrites),
+            pinning_tests: Arc::new(pinning_tests),
+            upstream,
+            dns_cache,
+            tls_sessions,
+            map_local: Arc::new(map_local),
+            map_remote: Arc::new(map_remote),
+            block_list: Arc::new(block_list),
+            server_replay: Arc::new(server_replay),
+            allowlist: Arc::new(std::sync::RwLock::new(allowlist)),
+            adapter,
+            sandbox,
+            coalescer,
+            lazy_body,
+            csp_reports: std::sync::Mutex::new(Vec::new()),
+            cors: CorsTracker::default(),
+            cookie_policy,
+            addons: RwLock::new(AddonManager::new()),
+            read_only: false,
+            recording,
+            unrecorded: AtomicU64::new(0),
+            metrics,
+            gauges: Arc::new(Gauges::default()),
+            completed: broadcast::channel(256).0,
+            open_streams: RwLock::new(HashMap::new()),
+            updates: broadcast::channel(256).0,
+            last_seq: AtomicU64::new(last_seq),
+            events: Arc::default(),
+            ca: None,
+            endpoints,
+            listeners,
+            capture_profiles,
+            intercept: Arc::new(intercept),
+            held_only: std::sync::Mutex::default(),
+            fuzz_corpus,
         }
     }
 
-    /// Get all flows
-    pub async fn get_flows(&self) -> Vec<HTTPFlow> {
+    /// Sign generated certificates with `ca`
+    pub fn with_ca(mut self, ca: Arc<CertificateAuthority>) -> Self {
+        self.ca = Some(ca);
+        self
+    }
+
+    /// CA signing generated certificates, if one is set up
+    pub fn ca(&self) -> Option<&Arc<CertificateAuthority>> {
+        self.ca.as_ref()
+    }
+
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    /// Validity of the CA certificate, if a CA is set up
+    pub fn ca_status(&self) -> Option<CaStatus> {
+        let ca = self.ca.as_ref()?;
+        ca.status().inspect_err(|e| warn!("Cannot read CA certificate validity: {}", e)).ok()
+    }
+
+    /// Mark the server as a read-only viewer
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether flows and state may be modified through the API
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether proxied traffic is currently recorded
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    /// Pause or resume recording. Traffic keeps being proxied either way.
+    pub fn set_recording(&self, recording: bool) {
+        if self.recording.swap(recording, Ordering::Relaxed) != recording {
+            info!("Flow recording {}", if recording { "resumed" } else { "paused" });
+        }
+    }
+
+    /// Number of flows proxied but not recorded while paused
+    pub fn unrecorded_flows(&self) -> u64 {
+        self.unrecorded.load(Ordering::Relaxed)
+    }
+
+    /// Whether only aggregates are kept and flows are never stored
+    pub fn is_aggregate_only(&self) -> bool {
+        self.config.aggregate_only
+    }
+
+    /// Traffic aggregates over the current window
+    pub fn metrics_summary(&self) -> MetricsSummary {
+        self.metrics.lock().unwrap().summary()
+    }
+
+    /// Traffic aggregates in the Prometheus text format
+    pub fn metrics_prometheus(&self) -> String {
+        self.metrics.lock().unwrap().prometheus()
+    }
+
+    pub fn gauges(&self) -> &Arc<Gauges> {
+        &self.gauges
+    }
+
+    /// Current gauges, with byte rates since the subscriber's previous
+    /// snapshot
+    pub async fn gauge_snapshot(&self, throughput: &mut Throughput, now: f64) -> GaugeSnapshot {
+        let (bytes_in, bytes_out) = self.gauges.bytes();
+        let (bytes_in_per_sec, bytes_out_per_sec) = throughput.rate(now, bytes_in, bytes_out);
         let flows = self.flows.read().await;
-        flows.values().cloned().collect()
+        let (mut stored_flows, mut stored_bytes, mut paused_flows) = (0, 0, 0);
+        for flow in scan(&**flows, None) {
+            let request = flow.request.content.as_ref().map_or(0, Vec::len);
+            let response = flow.response.as_ref().and_then(|r| r.content.as_ref()).map_or(0, Vec::len);
+            stored_flows += 1;
+            stored_bytes += (request + response) as u64;
+            paused_flows += usize::from(flow.flow.intercepted);
+        }
+        GaugeSnapshot {
+            client_connections: self.gauges.client_connections(),
+            server_connections: self.gauges.server_connections(),
+            paused_flows,
+            stored_flows,
+            stored_bytes,
+            evicted_flows: self.prune_stats.lock().unwrap().pruned,
+            bytes_in_per_sec,
+            bytes_out_per_sec,
+        }
+    }
+
+    /// Store a flow seen on the wire, unless recording is paused, only
+    /// aggregates are kept or the client's capture profile stores nothing.
+    /// The flow is counted in the metrics either way.
+    /// Flows added through the API are not affected.
+    pub async fn record_flow(&self, mut flow: HTTPFlow) -> bool {
+        self.metrics.lock().unwrap().record(&flow);
+        self.gauges.record_flow(&flow);
+        if self.config.aggregate_only || !self.capture_profiles.capture(&mut flow) {
+            return false;
+        }
+        if !self.is_recording() {
+            self.unrecorded.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.add_flow(flow).await;
+        true
+    }
+
+    /// Get all flows, in the order they were stored
+    pub async fn get_flows(&self) -> Vec<HTTPFlow> {
+        let store = self.flows.read().await;
+        let flows = scan(&**store, None).map(Cow::into_owned).collect();
+        flows
+    }
+
+    /// Flows stored after the one with sequence number `seq`, in order
+    pub async fn get_flows_since(&self, seq: u64) -> Vec<HTTPFlow> {
+        let mut flows = self.get_flows().await;
+        flows.retain(|flow| flow.flow.seq > seq);
+        flows
+    }
+
+    /// Sequence number of the most recently stored flow
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::Relaxed)
     }
 
     /// Get a specific flow by ID
     pub async fn get_flow(&self, id: &str) -> Option<HTTPFlow> {
-        let flows = self.flows.read().await;
-        flows.get(id).cloned()
+        or_warn(self.flows.read().await.get(id))
     }
 
     /// Update a flow
-    pub async fn update_flow(&self, flow: HTTPFlow) -> bool {
+    pub async fn update_flow(&self, mut flow: HTTPFlow) -> bool {
         let mut flows = self.flows.write().await;
-        let id = flow.flow.id.clone();
-        if flows.contains_key(&id) {
-            flows.insert(id, flow);
-            true
+        if let Some(stored) = or_warn(flows.get(&flow.flow.id)) {
+            flow.flow.seq = stored.flow.seq;
+            flow.capture_sse_events();
+            self.expectations.write().await.evaluate(&flow);
+            self.save(&flow);
+            self.notify_completed(&flow);
+            or_warn(flows.update(flow))
         } else {
             false
         }
     }
 
+    /// Save input a parser rejected to the fuzz corpus, if enabled
+    pub fn capture_malformed(&self, parser: Parser, data: &[u8]) {
+        let Some(corpus) = &self.fuzz_corpus else {
+            return;
+        };
+        match corpus.record(parser, data) {
+            Ok(Some(path)) => debug!("Saved malformed {} input to {}", parser.name(), path.display()),
+            Ok(None) => {}
+            Err(e) => warn!("Cannot save malformed {} input: {}", parser.name(), e),
+        }
+    }
+
+    /// Note events that arrived on an open event-stream response, as
+    /// reported by the `sse_event` hook. The flow is watched for stalls
+    /// until it is stored.
+    pub async fn update_stream(&self, flow: HTTPFlow) {
+        self.notify_updated(&flow);
+        self.open_streams.write().await.insert(flow.flow.id.clone(), flow);
+    }
+
+    /// Event-stream flows still receiving events
+    pub async fn get_open_streams(&self) -> Vec<HTTPFlow> {
+        self.open_streams.read().await.values().cloned().collect()
+    }
+
+    /// Mark open streams without data for the configured window as
+    /// stalled. Returns how many stalled since the last check.
+    pub async fn check_stalled_streams(&self, now: f64) -> usize {
+        let window = self.config.sse_monitor.stall_after_secs;
+        let mut stalled = 0;
+        for flow in self.open_streams.write().await.values_mut() {
+            let Some(stats) = flow.sse_stream.as_mut() else {
+                continue;
+            };
+            if stats.check_stall(now, window) {
+                warn!("Event stream {} stalled: no data for {:.0}s", flow.request.url(), now - stats.timestamp_last_data);
+                stalled += 1;
+            }
+            self.notify_updated(flow);
+        }
+        stalled
+    }
+
     /// Add a new flow
-    pub async fn add_flow(&self, flow: HTTPFlow) {
+    pub async fn add_flow(&self, mut flow: HTTPFlow) {
+        self.open_streams.write().await.remove(&flow.flow.id);
+        // An event stream without a single event is kept as a seed for the
+        // SSE parser
+        if !flow.capture_sse_events() && flow.is_event_stream() && flow.sse_events.is_empty() {
+            if let Some(content) = flow.response.as_ref().and_then(|r| r.content.as_deref()) {
+                self.capture_malformed(Parser::Sse, content);
+            }
+        }
+        self.pinning_tests.check(&mut flow);
+        self.capture_csp_reports(&mut flow);
+        self.cors.check(&mut flow);
+        ranges::record(&mut flow);
+        downgrades::record(&mut flow);
+        // A viewer cannot fetch the rest of a body, and has all of it
+        if !self.read_only {
+            self.lazy_body.truncate(&mut flow);
+        }
         let mut flows = self.flows.write().await;
-        flows.insert(flow.flow.id.clone(), flow);
+        // Numbers are taken under the lock so that they follow insertion order
+        flow.flow.seq = self.last_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        self.expectations.write().await.evaluate(&flow);
+        self.save(&flow);
+        self.notify_completed(&flow);
+        or_warn(flows.insert(flow));
+    }
+
+    /// The flow with its full response body, fetched from the origin if
+    /// only a preview was stored. The stored flow keeps the preview, and the
+    /// fetched body is cached for the next call. If the body cannot be
+    /// fetched, or lazy bodies are off or the server is a read-only viewer,
+    /// the flow is returned with the preview.
+    pub async fn with_full_body(&self, mut flow: HTTPFlow) -> HTTPFlow {
+        if self.read_only || !self.lazy_body.is_enabled() {
+            return flow;
+        }
+        let Some(request) = lazybody::remainder_request(&flow) else {
+            return flow;
+        };
+        if self.lazy_body.complete_cached(&mut flow) {
+            return flow;
+        }
+        let fetch = async {
+            self.check_destination(&request.host, request.port)?;
+            crate::client::send(&self.upstream, &request, !self.config.ssl_insecure).await
+        };
+        let result = match tokio::time::timeout(std::time::Duration::from_secs(60), fetch).await {
+            Ok(Ok(answer)) => lazybody::complete(&mut flow, answer),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(crate::Error::Proxy("Timed out fetching the full body".to_string())),
+        };
+        match result {
+            Ok(()) => self.lazy_body.cache(&flow),
+            Err(e) => warn!("Cannot fetch full body of flow {}: {}", flow.flow.id, e),
+        }
+        flow
+    }
+
+    /// Send the request of `flow` again, storing each recorded flow. Sticky
+    /// cookies and injected credentials are added to each request sent,
+    /// cookies are learned from its response, and flows note the upstream
+    /// proxy that carried them.
+    pub async fn replay(&self, flow: &HTTPFlow, options: &ReplayOptions) -> ReplayOutcome {
+        let verify = !self.config.ssl_insecure;
+        let upstream = &self.upstream;
+        // Request as sent, with the metadata it got and the upstream proxy
+        // used, one entry per recorded flow
+        let sent = &std::sync::Mutex::new(Vec::new());
+        let mut outcome = replay::replay(flow, options, |request| async move {
+            let mut attempt = HTTPFlow::new(request);
+            if let Some(sticky) = &self.rewrites.sticky_cookies {
+                sticky.request(&mut attempt);
+            }
+            self.rewrites.auth_injection.apply(&mut attempt);
+            sent.lock().unwrap().push((attempt.request.headers.clone(), attempt.flow.metadata.clone(), None));
+            self.check_destination(&attempt.request.host, attempt.request.port)?;
+            let (response, route) = crate::client::send_via(upstream, &attempt.request, verify).await?;
+            if let Some(sticky) = &self.rewrites.sticky_cookies {
+                attempt.response = Some(response.clone());
+                sticky.response(&attempt);
+            }
+            if let Some(last) = sent.lock().unwrap().last_mut() {
+                last.2 = route;
+            }
+            Ok(response)
+        })
+        .await;
+        for (flow, (headers, metadata, route)) in outcome.flows.iter_mut().zip(sent.lock().unwrap().drain(..)) {
+            flow.request.headers = headers;
+            flow.flow.metadata.extend(metadata);
+            if let Some(route) = route.and_then(|route| serde_json::to_value(route).ok()) {
+                flow.flow.metadata.insert(proxychain::METADATA_KEY.to_string(), route);
+            }
+        }
+        for flow in &outcome.flows {
+            self.add_flow(flow.clone()).await;
+        }
+        outcome
+    }
+
+    /// Load the most recent flows of the previous session from the save
+    /// stream, if warm start is enabled. Restored flows are not saved,
+    /// evaluated or counted again. Returns the number of flows loaded.
+    pub async fn warm_start(&self) -> crate::Result<usize> {
+        let options = &self.config.warm_start;
+        let Some(path) = self.config.save_stream_file.as_ref().filter(|_| options.enabled) else {
+            return Ok(0);
+        };
+        let restored = crate::save::load_recent(self.config.expand_path(path), options)?;
+        let count = restored.len();
+        let mut flows = self.flows.write().await;
+        for mut flow in restored {
+            flow.flow.seq = self.last_seq.fetch_add(1, Ordering::Relaxed) + 1;
+            or_warn(flows.insert(flow));
+        }
+        Ok(count)
+    }
+
+    /// Remove flows older than the janitor's maximum age at `now`, a UNIX
+    /// timestamp, archiving them first. Returns the number of flows pruned.
+    pub async fn prune_flows(&self, now: f64) -> usize {
+        let options = &self.config.janitor;
+        if options.archive_file.is_some() && self.archive.is_none() {
+            return 0;
+        }
+        let cutoff = now - options.max_age_secs as f64;
+        let mut flows = self.flows.write().await;
+        let expired: Vec<String> = scan(&**flows, None)
+            .filter(|flow| janitor::is_expired(flow, cutoff))
+            .map(|flow| flow.flow.id.clone())
+            .collect();
+
+        let addons = self.addons.read().await;
+        let (mut pruned, mut archived, mut failures) = (0, 0, 0);
+        for id in expired {
+            let Some(flow) = or_warn(flows.get(&id)) else {
+                continue;
+            };
+            if let Some(archive) = &self.archive {
+                match archive.lock().await.record(&flow) {
+                    Ok(written) => archived += written as u64,
+                    Err(e) => {
+                        warn!("Cannot archive flow {}, keeping it: {}", id, e);
+                        failures += 1;
+                        continue;
+                    }
+                }
+            }
+            addons.archive(&flow);
+            or_warn(flows.evict(&id));
+            self.expectations.write().await.forget(&id);
+            pruned += 1;
+        }
+
+        let mut stats = self.prune_stats.lock().unwrap();
+        stats.runs += 1;
+        stats.pruned += pruned;
+        stats.archived += archived;
+        stats.archive_failures += failures;
+        stats.last_run = Some(now);
+        stats.last_pruned = pruned;
+        pruned as usize
+    }
+
+    pub fn prune_stats(&self) -> PruneStats {
+        self.prune_stats.lock().unwrap().clone()
+    }
+
+    /// Start the background job pruning old flows, if enabled
+    pub fn spawn_janitor(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let options = &self.config.janitor;
+        if !options.enabled || self.read_only {
+            return None;
+        }
+        info!("Pruning flows older than {}s every {}s", options.max_age_secs, options.interval_secs);
+        let proxy = Arc::clone(self);
+        let period = std::time::Duration::from_secs(options.interval_secs.max(1));
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let now = crate::clock::now();
+                let pruned = proxy.prune_flows(now).await;
+                if pruned > 0 {
+                    debug!("Pruned {} flows", pruned);
+                }
+            }
+        }))
+    }
+
+    /// Start the background job checking the upstream proxies of upstream
+    /// mode, if there are any
+    pub fn spawn_upstream_health_checks(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let interval_secs = self.config.upstream_proxies.health_check_interval_secs;
+        if self.read_only || interval_secs == 0 {
+            return None;
+        }
+        self.upstream.proxy_chain()?;
+        let proxy = Arc::clone(self);
+        let period = std::time::Duration::from_secs(interval_secs);
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if let Some(chain) = proxy.upstream.proxy_chain() {
+                    chain.check_health().await;
+                }
+            }
+        }))
+    }
+
+    /// Start the background job updating open event streams and detecting
+    /// stalls
+    pub fn spawn_sse_monitor(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if self.read_only {
+            return None;
+        }
+        let proxy = Arc::clone(self);
+        let period = std::time::Duration::from_secs(self.config.sse_monitor.check_interval_secs.max(1));
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let now = crate::clock::now();
+                proxy.check_stalled_streams(now).await;
+            }
+        }))
+    }
+
+    /// Start intercepting HTTP/3 on the configured UDP port, if any
+    pub fn spawn_http3(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let port = self.config.http3_port?;
+        if self.read_only {
+            return None;
+        }
+        #[cfg(feature = "http3")]
+        {
+            let addr = format!("{}:{}", self.config.proxy_host, port);
+            let server = addr
+                .parse()
+                .map_err(|e| crate::Error::invalid_request(format!("Invalid HTTP/3 address {}: {}", addr, e)))
+                .and_then(|addr| crate::proxy::http3::Http3Server::bind(Arc::clone(self), addr));
+            match server {
+                Ok(server) => {
+                    info!("HTTP/3 proxy listening on udp://{}", addr);
+                    Some(tokio::spawn(Arc::new(server).run()))
+                }
+                Err(e) => {
+                    error!("Cannot listen for HTTP/3 on {}: {}", addr, e);
+                    None
+                }
+            }
+        }
+        #[cfg(not(feature = "http3"))]
+        {
+            warn!("Ignoring http3_port {}: built without the http3 feature", port);
+            None
+        }
+    }
+
+    /// Start answering DNS queries on the configured UDP port, if any
+    pub fn spawn_dns(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let port = self.config.dns_port?;
+        if self.read_only {
+            return None;
+        }
+        let addr = format!("{}:{}", self.config.proxy_host, port);
+        let server = addr
+            .parse()
+            .map_err(|e| crate::Error::invalid_request(format!("Invalid DNS address {}: {}", addr, e)))
+            .and_then(|addr| crate::proxy::dns::DnsServer::bind(Arc::clone(self), addr));
+        match server {
+            Ok(server) => {
+                info!("DNS server listening on udp://{}", addr);
+                Some(tokio::spawn(Arc::new(server).run()))
+            }
+            Err(e) => {
+                error!("Cannot listen for DNS on {}: {}", addr, e);
+                None
+            }
+        }
+    }
+
+    /// Start accepting reverse tunnels from agents, if enabled. Tunneled
+    /// connections are handed to the proxy listener.
+    pub fn spawn_agent_listener(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let options = &self.config.agents;
+        let port = options.port?;
+        if self.read_only {
+            return None;
+        }
+        let Some(token) = options.token.clone().filter(|token| !token.is_empty()) else {
+            warn!("Not accepting agents on port {}: no agent token is configured", port);
+            return None;
+        };
+        let Some(ca) = self.ca.clone() else {
+            warn!("Not accepting agents on port {}: no certificate authority", port);
+            return None;
+        };
+        let hostname = options.hostname.clone().unwrap_or_else(|| self.config.proxy_host.clone());
+        // Tunneled connections reach a wildcard listener over loopback
+        let proxy_host = match self.config.proxy_host.as_str() {
+            "0.0.0.0" => "127.0.0.1",
+            "::" | "[::]" => "[::1]",
+            host => host,
+        };
+        let target = format!("{}:{}", proxy_host, self.config.proxy_port);
+        let addr = format!("{}:{}", self.config.proxy_host, port);
+        Some(tokio::spawn(async move {
+            let result = async {
+                let target = tokio::net::lookup_host(&target)
+                    .await?
+                    .next()
+                    .ok_or_else(|| crate::Error::invalid_request(format!("Cannot resolve {}", target)))?;
+                let listener = TcpListener::bind(&addr).await?;
+                info!("Accepting agent tunnels on {}", addr);
+                crate::agent::run_listener(listener, &ca, &hostname, token, target).await
+            };
+            if let Err(e) = result.await {
+                error!("Agent listener on {} failed: {}", addr, e);
+            }
+        }))
+    }
+
+    /// Subscribe to flows as they complete
+    pub fn subscribe_completed(&self) -> broadcast::Receiver<HTTPFlow> {
+        self.completed.subscribe()
+    }
+
+    /// Subscribe to flows changing while still in progress
+    pub fn subscribe_updates(&self) -> broadcast::Receiver<HTTPFlow> {
+        self.updates.subscribe()
+    }
+
+    fn notify_updated(&self, flow: &HTTPFlow) {
+        if self.updates.receiver_count() > 0 {
+            let _ = self.updates.send(flow.clone());
+        }
+    }
+
+    fn notify_completed(&self, flow: &HTTPFlow) {
+        if (flow.response.is_some() || flow.flow.error.is_some()) && self.completed.receiver_count() > 0 {
+            let _ = self.completed.send(flow.clone());
+        }
+    }
+
+    /// Queue a flow to be appended to the save stream, if enabled and the
+    /// flow is recorded
+    fn save(&self, flow: &HTTPFlow) {
+        if self.held_only.lock().unwrap().contains(&flow.flow.id) {
+            return;
+        }
+        if let Some(stream) = &self.save_stream {
+            stream.record(flow);
+        }
+    }
+
+    /// Get the save-stream segments, or `None` if no save-stream file is set
+    pub async fn save_stream_segments(&self) -> Option<Vec<Segment>> {
+        match &self.save_stream {
+            Some(stream) => Some(stream.segments().await),
+            None => None,
+        }
+    }
+
+    /// Add an entry to the event log
+    pub fn log_event(&self, level: LogLevel, message: impl Into<String>) {
+        self.events.lock().unwrap().push(level, message);
+    }
+
+    /// Event log entries, oldest first
+    pub fn get_events(&self) -> Vec<LogEntry> {
+        self.events.lock().unwrap().entries()
+    }
+
+    /// Read a body diff reference file, confined to the configured
+    /// reference directory if there is one
+    pub fn read_diff_reference(&self, path: &str) -> crate::Result<Vec<u8>> {
+        let root = self.config.diff_reference_dir.as_deref().map(|dir| self.config.expand_path(dir));
+        crate::bodydiff::read_reference(&self.config.expand_path(path), root.as_deref().map(std::path::Path::new))
+    }
+
+    pub fn get_adaptation_services(&self) -> Vec<AdaptationService> {
+        self.adapter.services()
+    }
+
+    /// Limits and capabilities scripts run under
+    pub fn script_sandbox(&self) -> &Sandbox {
+        &self.sandbox
+    }
+
+    /// Get a flow's response from `upstream`, sharing it with identical GET
+    /// requests in flight at the same time if coalescing is enabled
+    pub async fn fetch_coalesced<F, Fut>(&self, flow: &mut HTTPFlow, upstream: F) -> crate::Result<()>
+    where
+        F: FnOnce(HTTPRequest) -> Fut,
+        Fut: std::future::Future<Output = crate::Result<HTTPResponse>>,
+    {
+        self.coalescer.fetch(flow, upstream).await
+    }
+
+    /// Apply the configured cookie attribute downgrades to the response.
+    /// Returns whether it changed.
+    pub fn rewrite_cookies(&self, flow: &mut HTTPFlow) -> bool {
+        self.cookie_policy.rewrite(flow)
+    }
+
+    fn capture_csp_reports(&self, flow: &mut HTTPFlow) {
+        let reports = self.rewrites.csp.capture(flow);
+        if reports.is_empty() {
+            return;
+        }
+        let mut stored = self.csp_reports.lock().unwrap();
+        stored.extend(reports);
+        let excess = stored.len().saturating_sub(csp::MAX_REPORTS);
+        stored.drain(..excess);
+    }
+
+    pub fn csp_reports(&self) -> Vec<CspReport> {
+        self.csp_reports.lock().unwrap().clone()
+    }
+
+    pub fn clear_csp_reports(&self) {
+        self.csp_reports.lock().unwrap().clear();
+    }
+
+    pub fn get_pinning_tests(&self) -> Vec<PinningRule> {
+        self.pinning_tests.rules()
+    }
+
+    pub fn dns_cache(&self) -> &DnsCache {
+        &self.dns_cache
+    }
+
+    /// Health of the upstream proxies of upstream mode, in configured order
+    pub fn upstream_proxies(&self) -> Vec<UpstreamProxyStatus> {
+        self.upstream.proxy_chain().map(ProxyChain::status).unwrap_or_default()
+    }
+
+    /// Recorded responses loaded for server-side replay and how many are
+    /// left
+    pub fn server_replay(&self) -> ServerReplayStatus {
+        self.server_replay.status()
+    }
+
+    /// Templater grouping flows by endpoint
+    pub fn endpoint_templater(&self) -> &EndpointTemplater {
+        &self.endpoints
+    }
+
+    /// Build details of this binary and the subsystems its config enables
+    pub fn build_info(&self) -> crate::build_info::BuildInfo {
+        crate::build_info::BuildInfo::new(&self.config)
+    }
+
+    pub fn tls_sessions(&self) -> &TlsSessionCache {
+        &self.tls_sessions
+    }
+
+    /// Open a server connection to `host:port`, through the upstream HTTP
+    /// proxies in upstream mode or the SOCKS5 upstream proxy selected for it
+    /// if any
+    pub async fn connect_upstream(&self, host: &str, port: u16) -> crate::Result<TcpStream> {
+        self.check_destination(host, port)?;
+        self.upstream.connect(host, port).await
+    }
+
+    /// Whether connections to `host:port` are passed through without
+    /// interception under `ignore_hosts` or `allow_hosts`
+    pub fn passes_through(&self, host: &str, port: u16) -> bool {
+        let hostname = format!("{}:{}", host, port);
+        crate::proxy::layers::tls::ignore_hostnames(&self.config.ignore_hosts, &self.config.allow_hosts, &[hostname])
+    }
+
+    /// Refuse `host:port` if allowlist-only mode is on and it is not on
+    /// the allowlist
+    pub fn check_destination(&self, host: &str, port: u16) -> crate::Result<()> {
+        self.allowlist.read().unwrap().check(host, port)
+    }
+
+    /// The allowlist settings and the changes made to them, oldest first
+    pub fn allowlist(&self) -> (AllowlistOptions, Vec<AllowlistChange>) {
+        let allowlist = self.allowlist.read().unwrap();
+        (allowlist.options().clone(), allowlist.audit().to_vec())
+    }
+
+    /// Replace the allowlist settings on behalf of `source`, logging the
+    /// change in the event log
+    pub fn set_allowlist(&self, options: AllowlistOptions, source: &str) -> crate::Result<AllowlistChange> {
+        let change = self.allowlist.write().unwrap().update(options, source)?;
+        let message = format!(
+            "Allowlist changed by {}: {} ({}) -> {} ({})",
+            source,
+            if change.before.enabled { "enabled" } else { "disabled" },
+            change.before.hosts.join(", "),
+            if change.after.enabled { "enabled" } else { "disabled" },
+            change.after.hosts.join(", "),
+        );
+        warn!("{}", message);
+        self.log_event(LogLevel::Warn, message);
+        Ok(change)
+    }
+
+    /// Rewrite a flow's request headers according to the active header
+    /// profile. Returns whether any header changed.
+    pub async fn normalize_request_headers(&self, flow: &mut HTTPFlow) -> bool {
+        self.rewrites.header_profiles.read().unwrap().apply(flow)
+    }
+
+    /// Get all header profiles and the name of the active one
+    pub async fn get_header_profiles(&self) -> (Vec<HeaderProfile>, Option<String>) {
+        let profiles = self.rewrites.header_profiles.read().unwrap();
+        (profiles.list(), profiles.active().map(|p| p.name.clone()))
+    }
+
+    /// Select the header profile applied to requests, or disable it
+    pub async fn set_header_profile(&self, name: Option<&str>) -> crate::Result<()> {
+        self.rewrites.header_profiles.write().unwrap().set_active(name)
+    }
+
+    /// The `modify_headers` entries in effect
+    pub async fn modify_headers(&self) -> Vec<String> {
+        self.rewrites.modify_headers.read().unwrap().specs()
+    }
+
+    /// Replace the `modify_headers` rules. Invalid entries leave the rules
+    /// in effect unchanged.
+    pub async fn set_modify_headers(&self, specs: &[String]) -> crate::Result<()> {
+        *self.rewrites.modify_headers.write().unwrap() = ModifyHeaders::from_specs(specs)?;
+        Ok(())
+    }
+
+    /// The `modify_body` entries in effect
+    pub async fn modify_body(&self) -> Vec<String> {
+        self.rewrites.modify_body.read().unwrap().specs()
+    }
+
+    /// Replace the `modify_body` rules, keeping the configured size limit.
+    /// Invalid entries leave the rules in effect unchanged.
+    pub async fn set_modify_body(&self, specs: &[String]) -> crate::Result<()> {
+        *self.rewrites.modify_body.write().unwrap() = ModifyBody::new(specs, self.config.modify_body_max_size)?;
+        Ok(())
+    }
+
+    /// Request hook run before a request received on `listener` is
+    /// forwarded: header normalization, range, cache and compression header
+    /// removal, header and body rewriting, sticky cookies, credential
+    /// injection, then the listener's addons, then the `intercept` rule and
+    /// that of the client's capture profile, holding the request if either
+    /// matches, then the adaptation services. Returns false if the request
+    /// must not be forwarded: it was killed, or a service failed, or blocked
+    /// it, in which case the flow has the response to send.
+    pub async fn request_hook(&self, listener: &str, flow: &mut HTTPFlow) -> bool {
+        self.rewrites.request(flow);
+        self.addons.read().await.request_scoped(flow, self.listeners.get(listener));
+        self.capture_profiles.intercept(flow);
+        self.intercept.intercept(flow);
+        self.hold_intercepted(flow).await;
+        // A request killed while held is not forwarded
+        if flow.flow.error.is_some() {
+            return false;
+        }
+        self.adapter.adapt(flow, Phase::Request).await
+    }
+
+    /// Response hook run before a response is sent to a client of
+    /// `listener`: learning sticky cookies, body substitution, header and
+    /// body rewriting, CSP rewriting, then the listener's addons, then the `intercept`
+    /// rule, holding the response if it matches, then the adaptation
+    /// services, then cookie downgrades, then response shaping, then
+    /// compression of the copy sent to the client. The listener's cookie
+    /// and shaping rules replace the global ones if it has any. Returns None
+    /// if the response must not be sent because it was killed or a service
+    /// failed.
+    pub async fn response_hook(&self, listener: &str, flow: &mut HTTPFlow) -> Option<Delivery> {
+        let scope = self.listeners.get(listener);
+        self.rewrites.response(flow);
+        self.addons.read().await.response_scoped(flow, scope);
+        self.intercept.intercept(flow);
+        self.hold_intercepted(flow).await;
+        // A response killed while held is not sent
+        if flow.flow.error.is_some() {
+            return None;
+        }
+        // Nor is one an adaptation service failed; one it replaced is
+        if !self.adapter.adapt(flow, Phase::Response).await && flow.flow.error.is_some() {
+            return None;
+        }
+        scope.cookie_policy().unwrap_or(&self.cookie_policy).rewrite(flow);
+        let shaper = scope.shaper().unwrap_or(&self.shaper);
+        let before = (!shaper.is_empty()).then(|| flow.clone());
+        let shaping = shaper.shape(flow);
+        if let Some(before) = before.filter(|_| shaping.as_ref().is_some_and(|p| p.padding > 0)) {
+            changelog::record(flow, &before, "shaping", Some("response"));
+        }
+        let compressed = self.config.response_compression.compress(flow);
+        Some(Delivery { compressed, shaping })
+    }
+
+    /// Wait until `flow` is resumed or killed if it is intercepted, then
+    /// continue with the flow as it was left. The flow is stored while it
+    /// is held, whether or not flows are being recorded, so that it can be
+    /// found. A flow held again, on its response, is updated in place. If
+    /// [`Self::record_flow`] would not store the flow, it is neither saved
+    /// nor kept once released.
+    pub async fn hold_intercepted(&self, flow: &mut HTTPFlow) {
+        if !flow.flow.intercepted {
+            return;
+        }
+        let waker = self.intercept.hold(&flow.flow.id);
+        if self.get_flow(&flow.flow.id).await.is_some() {
+            self.update_flow(flow.clone()).await;
+        } else {
+            let recorded = !self.config.aggregate_only && self.is_recording() && self.capture_profiles.stores(flow);
+            if !recorded {
+                self.held_only.lock().unwrap().insert(flow.flow.id.clone());
+            }
+            self.add_flow(flow.clone()).await;
+        }
+        self.log_event(LogLevel::Info, format!("Intercepted {} {}", flow.request.method, flow.request.url()));
+        waker.notified().await;
+        if let Some(held) = self.get_flow(&flow.flow.id).await {
+            *flow = held;
+        }
+        flow.flow.intercepted = false;
+        if self.held_only.lock().unwrap().remove(&flow.flow.id) {
+            self.remove_flow(&flow.flow.id).await;
+        }
+    }
+
+    /// Let held flows that were resumed or killed continue. Returns how
+    /// many did.
+    pub async fn release_intercepted(&self) -> usize {
+        let mut released = 0;
+        for id in self.intercept.held() {
+            let Some(flow) = self.get_flow(&id).await else {
+                // A flow removed while held is let go as it was
+                released += usize::from(self.intercept.release(&id));
+                continue;
+            };
+            if (!flow.flow.intercepted || flow.flow.error.is_some()) && self.intercept.release(&id) {
+                released += 1;
+            }
+        }
+        released
+    }
+
+    /// DNS request hook run on a query received on `listener`: the
+    /// listener's addons, which may answer the query themselves
+    pub async fn dns_request_hook(&self, listener: &str, flow: &mut HTTPFlow) {
+        self.addons.read().await.dns_request_scoped(flow, self.listeners.get(listener));
+    }
+
+    /// DNS response hook run before an answer is sent to a client of
+    /// `listener`: the listener's addons
+    pub async fn dns_response_hook(&self, listener: &str, flow: &mut HTTPFlow) {
+        self.addons.read().await.dns_response_scoped(flow, self.listeners.get(listener));
+    }
+
+    /// Register an addon. Must be called within the proxy runtime, which
+    /// runs the addon's timers.
+    pub async fn add_addon(&self, addon: Arc<dyn Addon>) -> crate::Result<()> {
+        self.addons.write().await.add(addon)
+    }
+
+    /// Unload an addon and cancel its timers
+    pub async fn remove_addon(&self, name: &str) -> bool {
+        self.addons.write().await.remove(name)
+    }
+
+    /// Get registered addons with their pending timers
+    pub async fn get_addons(&self) -> Vec<AddonInfo> {
+        self.addons.read().await.list()
+    }
+
+    /// Unload all addons, cancelling their timers
+    pub async fn shutdown_addons(&self) {
+        self.addons.write().await.shutdown();
     }
 
     /// Remove a flow by ID
     pub async fn remove_flow(&self, id: &str) -> bool {
         let mut flows = self.flows.write().await;
-        flows.remove(id).is_some()
+        self.expectations.write().await.forget(id);
+        or_warn(flows.evict(id)).is_some()
+    }
+
+    /// Remove every flow matching `filter` at once. Returns how many were
+    /// removed.
+    pub async fn remove_matching(&self, filter: &Filter) -> usize {
+        let mut flows = self.flows.write().await;
+        let matching: Vec<String> = scan(&**flows, Some(filter)).map(|flow| flow.flow.id.clone()).collect();
+        let mut expectations = self.expectations.write().await;
+        matching
+            .iter()
+            .filter(|id| {
+                expectations.forget(id);
+                or_warn(flows.evict(id)).is_some()
+            })
+            .count()
+    }
+
+    /// Apply `change` to every flow matching `filter` at once. Flows it
+    /// reports as changed are stored like with `update_flow`. Returns how
+    /// many flows matched and how many changed.
+    pub async fn update_matching(&self, filter: &Filter, mut change: impl FnMut(&mut HTTPFlow) -> bool) -> (usize, usize) {
+        let mut flows = self.flows.write().await;
+        let mut expectations = self.expectations.write().await;
+        let (mut matched, mut changed) = (0, 0);
+        let matching: Vec<HTTPFlow> = scan(&**flows, Some(filter)).map(Cow::into_owned).collect();
+        for mut flow in matching {
+            matched += 1;
+            if !change(&mut flow) {
+                continue;
+            }
+            changed += 1;
+            expectations.evaluate(&flow);
+            self.save(&flow);
+            self.notify_completed(&flow);
+            or_warn(flows.update(flow));
+        }
+        (matched, changed)
     }
 
     /// Clear all flows
     pub async fn clear_flows(&self) {
         let mut flows = self.flows.write().await;
-        flows.clear();
+        self.expectations.write().await.reset();
+        or_warn(flows.clear());
+    }
+
+    /// Get all registered expectations
+    pub async fn get_expectations(&self) -> Vec<ExpectationSpec> {
+        self.expectations.read().await.specs()
+    }
+
+    /// Register an expectation, replacing one with the same name
+    pub async fn add_expectation(&self, spec: ExpectationSpec) -> crate::Result<()> {
+        self.expectations.write().await.add(spec)
+    }
+
+    /// Remove an expectation by name
+    pub async fn remove_expectation(&self, name: &str) -> bool {
+        self.expectations.write().await.remove(name)
     }
 
-    /// Run the proxy server (alternative entry point)
+    /// Get pass/fail results of all expectations
+    pub async fn expectation_results(&self) -> Vec<ExpectationResult> {
+        self.expectations.read().await.results()
+    }
+
+    /// Forget recorded expectation outcomes
+    pub async fn reset_expectation_results(&self) {
+        self.expectations.write().await.reset();
+    }
+
+    /// Store the pseudonym mapping of an anonymized export in the confdir,
+    /// readable by the owner only
+    pub fn save_anonymization_mapping(
+        &self,
+        mapping: &std::collections::BTreeMap<String, String>,
+    ) -> crate::Result<std::path::PathBuf> {
+        let dir = std::path::PathBuf::from(self.config.expand_path(&self.config.confdir)).join("anonymization");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("mapping-{}.json", chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+        crate::certs::write_private(&path, &serde_json::to_vec_pretty(mapping)?)?;
+        Ok(path)
+    }
+
+    /// Run the proxy server (alternative entry point). Clients of the
+    /// main listener are handled according to the proxy mode; clients of
+    /// the SOCKS5 listener, if configured, always speak SOCKS5.
     pub async fn run(&self) -> crate::Result<()> {
         let addr = format!("{}:{}", self.config.proxy_host, self.config.proxy_port);
         let listener = TcpListener::bind(&addr).await?;
-        info!("Proxy server listening on {}", addr);
+        let socks_mode = matches!(self.config.mode, ProxyMode::Socks5);
+        info!("{} listening on {}", if socks_mode { "SOCKS5 proxy" } else { "Proxy server" }, addr);
+        let socks_listener = match self.config.socks5_port {
+            Some(port) => {
+                let addr = format!("{}:{}", self.config.proxy_host, port);
+                let listener = TcpListener::bind(&addr).await?;
+                info!("SOCKS5 proxy listening on {}", addr);
+                Some(listener)
+            }
+            None => None,
+        };
 
         loop {
-            match listener.accept().await {
+            let (accepted, socks) = tokio::select! {
+                accepted = listener.accept() => (accepted, socks_mode),
+                accepted = accept_optional(socks_listener.as_ref()) => (accepted, true),
+            };
+            match accepted {
                 Ok((stream, addr)) => {
                     debug!("New connection from {}", addr);
-                    let config = self.config.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(stream, addr.into(), config).await {
-                            error!("Error handling connection: {}", e);
-                        }
-                    });
+                    if let Err(e) = self.config.sockets.client.apply(&stream) {
+                        warn!("Cannot set socket options on the connection from {}: {}", addr, e);
+                    }
+                    self.spawn_connection(stream, addr, socks);
                 }
                 Err(e) => {
                     error!("Error accepting connection: {}", e);
@@ -99,27 +1394,47 @@ impl ProxyServer {
 
     /// Start the proxy server
     pub async fn start(&mut self) -> crate::Result<()> {
-        let addr = format!("{}:{}", self.config.proxy_host, self.config.proxy_port);
-        let listener = TcpListener::bind(&addr).await?;
-        info!("Proxy server listening on {}", addr);
+        self.run().await
+    }
 
-        loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
-                    debug!("New connection from {}", addr);
-                    // Handle connection in a separate task
-                    let config = self.config.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(stream, addr.into(), config).await {
-                            error!("Error handling connection: {}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("Error accepting connection: {}", e);
+    /// Handle a connection in a separate task. A panic while handling it
+    /// fails only this connection and is logged with its backtrace.
+    fn spawn_connection(&self, stream: TcpStream, addr: std::net::SocketAddr, socks: bool) {
+        let config = self.config.clone();
+        let options = self.context_options();
+        let events = self.events.clone();
+        let connection = self.gauges.client_connection();
+        tokio::spawn(async move {
+            let _connection = connection;
+            match crate::panics::catch_async(Self::handle_connection(stream, addr, config, options, socks)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Error handling connection: {}", e),
+                Err(report) => {
+                    error!("Connection from {} {}\n{}", addr, report, report.backtrace);
+                    events.lock().unwrap().push(
+                        LogLevel::Error,
+                        format!("Connection from {} failed: {}\n{}", addr, report, report.backtrace),
+                    );
                 }
             }
-        }
+        });
+    }
+
+    /// Options of the context connections are handled in: those of the
+    /// config and the state shared between connections
+    fn context_options(&self) -> ContextOptions {
+        let mut options = ContextOptions::from(self.config.clone());
+        options.tls_sessions = self.tls_sessions.enabled().then(|| self.tls_sessions.clone());
+        options.ca = self.ca.clone();
+        options.map_local = (!self.map_local.is_empty()).then(|| self.map_local.clone());
+        options.map_remote = (!self.map_remote.is_empty()).then(|| self.map_remote.clone());
+        options.block_list = (!self.block_list.is_empty()).then(|| self.block_list.clone());
+        options.server_replay = (!self.server_replay.is_empty()).then(|| self.server_replay.clone());
+        options.allowlist = Some(self.allowlist.clone());
+        options.intercept = self.intercept.is_enabled().then(|| self.intercept.clone());
+        options.pinning_tests = (!self.pinning_tests.is_empty()).then(|| self.pinning_tests.clone());
+        options.rewrites = Some(self.rewrites.clone());
+        options
     }
 
     /// Handle a single connection
@@ -127,6 +1442,8 @@ impl ProxyServer {
         _stream: TcpStream,
         addr: std::net::SocketAddr,
         config: Arc<Config>,
+        options: ContextOptions,
+        socks: bool,
     ) -> crate::Result<()> {
         // Create client connection using the connection module's types
         let mut connection = Connection::new(TransportProtocol::Tcp);
@@ -136,14 +1453,19 @@ impl ProxyServer {
 
         let client = Client {
             connection,
-            proxy_mode: None,
+            proxy_mode: Some(if socks { ProxyMode::Socks5.name() } else { config.mode.name() }.to_string()),
         };
 
         // Create context
-        let context = Context::new(client, config);
+        let mut context = Context::new(client, config);
+        context.options = options;
 
-        // Create root layer (NextLayer)
-        let mut root_layer = crate::proxy::NextLayer::new(context);
+        // Create root layer: SOCKS5 clients go through the handshake first
+        let mut root_layer: Box<dyn Layer> = if socks {
+            Box::new(crate::proxy::layers::Socks5Proxy::new(context))
+        } else {
+            Box::new(crate::proxy::NextLayer::new(context))
+        };
 
         // Start processing
         let start_event = AnyEvent::Start(crate::proxy::events::Start);
@@ -157,4 +1479,28 @@ impl ProxyServer {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Flows of `store` matching `filter`, or none if the store cannot be read
+fn scan<'a>(store: &'a dyn FlowStore, filter: Option<&'a Filter>) -> Box<dyn Iterator<Item = Cow<'a, HTTPFlow>> + 'a> {
+    store.iter(filter).unwrap_or_else(|e| {
+        warn!("Cannot read flow store: {}", e);
+        Box::new(std::iter::empty())
+    })
+}
+
+/// The outcome of a flow store operation, or the default if it failed
+fn or_warn<T: Default>(result: crate::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        warn!("Flow store failed: {}", e);
+        T::default()
+    })
+}
+
+/// Accept from `listener`, or wait forever if there is none
+async fn accept_optional(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,320 @@
+//! Byte range requests and partial content responses.
+//!
+//! A `206 Partial Content` response carries part of a resource: one range
+//! described by its `Content-Range` header, or several as the parts of a
+//! `multipart/byteranges` body. The flow records what it covers under
+//! `range` in its metadata, so a partial body is not mistaken for a
+//! truncated one: `complete` tells whether all bytes of the ranges arrived.
+//! Requests matching a `strip_range` filter lose their `Range` and
+//! `If-Range` headers, so the server sends the full body and the proxy
+//! captures it.
+
+use serde_json::json;
+
+use crate::filter::Filter;
+use crate::flow::{HTTPFlow, HTTPResponse};
+use crate::Result;
+
+/// Flow metadata key under which the ranges of a partial response are
+/// described
+pub const METADATA_KEY: &str = "range";
+
+/// Request headers asking for part of a resource
+const RANGE_HEADERS: &[&str] = &["range", "if-range"];
+
+/// A `bytes start-end/total` Content-Range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub start: u64,
+    /// Last byte, inclusive
+    pub end: u64,
+    /// Size of the whole resource, if the server knows it
+    pub total: Option<u64>,
+}
+
+impl ContentRange {
+    /// Parse a satisfied `bytes start-end/total` range; the unsatisfied
+    /// `bytes */total` of a 416 response is not one
+    pub fn parse(value: &str) -> Option<Self> {
+        let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+        let (start, end) = range.split_once('-')?;
+        let (start, end): (u64, u64) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+        let total = match total.trim() {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+        (start <= end && total.is_none_or(|total| end < total)).then_some(Self { start, end, total })
+    }
+
+    /// Number of bytes in the range
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Whether the range covers the whole resource
+    pub fn is_full(&self) -> bool {
+        self.start == 0 && self.total == Some(self.end + 1)
+    }
+}
+
+/// One part of a `multipart/byteranges` body
+#[derive(Debug, Clone, PartialEq)]
+pub struct BytePart {
+    pub content_type: Option<String>,
+    pub range: ContentRange,
+    pub data: Vec<u8>,
+}
+
+/// Body size a response announces through its `Content-Range` alone: the
+/// length of the single range of a 206 response
+pub fn expected_body_size(response: &HTTPResponse) -> Option<u64> {
+    if response.status_code != 206 {
+        return None;
+    }
+    response.get_header("content-range").and_then(|value| ContentRange::parse(value)).map(|range| range.size())
+}
+
+/// Boundary of a `multipart/byteranges` content type
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim().eq_ignore_ascii_case("boundary").then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// The parts of a `multipart/byteranges` body. Without a `boundary`, the
+/// first delimiter line of the body is taken as one.
+pub fn byteranges(body: &[u8], boundary: Option<&str>) -> Option<Vec<BytePart>> {
+    let sniffed;
+    let boundary = match boundary {
+        Some(boundary) => boundary,
+        None => {
+            let start = body.iter().position(|b| !b.is_ascii_whitespace())?;
+            let line = &body[start..];
+            let line = &line[..find(line, b"\r\n", 0)?];
+            sniffed = String::from_utf8(line.strip_prefix(b"--")?.to_vec()).ok()?;
+            &sniffed
+        }
+    };
+    let delimiter = format!("--{}", boundary).into_bytes();
+
+    let mut parts = Vec::new();
+    let mut pos = find(body, &delimiter, 0)? + delimiter.len();
+    loop {
+        if body[pos..].starts_with(b"--") {
+            return Some(parts);
+        }
+        pos = find(body, b"\r\n", pos)? + 2;
+        let head_end = find(body, b"\r\n\r\n", pos.saturating_sub(2))?;
+        let head = std::str::from_utf8(&body[pos..head_end]).ok()?;
+        let mut content_type = None;
+        let mut range = None;
+        for line in head.lines() {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-type" => content_type = Some(value.trim().to_string()),
+                "content-range" => range = ContentRange::parse(value),
+                _ => {}
+            }
+        }
+        let data_start = head_end + 4;
+        let data_end = find(body, &[b"\r\n".as_slice(), &delimiter].concat(), data_start)?;
+        parts.push(BytePart { content_type, range: range?, data: body[data_start..data_end].to_vec() });
+        pos = data_end + 2 + delimiter.len();
+    }
+}
+
+/// Merge the data of overlapping and adjacent parts into contiguous
+/// segments, each with the offset it starts at
+pub fn coalesce(parts: &[BytePart]) -> Vec<(u64, Vec<u8>)> {
+    let mut sorted: Vec<&BytePart> = parts.iter().collect();
+    sorted.sort_by_key(|part| part.range.start);
+    let mut segments: Vec<(u64, Vec<u8>)> = Vec::new();
+    for part in sorted {
+        match segments.last_mut() {
+            Some((start, data)) if part.range.start <= *start + data.len() as u64 => {
+                let skip = (*start + data.len() as u64 - part.range.start) as usize;
+                data.extend(part.data.iter().skip(skip));
+            }
+            _ => segments.push((part.range.start, part.data.clone())),
+        }
+    }
+    segments
+}
+
+/// Describe the ranges of a 206 response in the flow metadata. Returns
+/// whether the flow has a partial response.
+pub fn record(flow: &mut HTTPFlow) -> bool {
+    let Some(response) = flow.response.as_ref().filter(|response| response.status_code == 206) else {
+        return false;
+    };
+    let body = response.content.as_deref().unwrap_or_default();
+    let multipart = response.get_header("content-type").and_then(|content_type| boundary(content_type));
+    let description = match multipart {
+        Some(boundary) => {
+            let parts = byteranges(body, Some(&boundary));
+            let ranges = parts.as_deref().unwrap_or_default();
+            json!({
+                "parts": ranges.len(),
+                "ranges": ranges.iter().map(|part| [part.range.start, part.range.end]).collect::<Vec<_>>(),
+                "total": ranges.first().and_then(|part| part.range.total),
+                "complete": parts.as_ref().is_some_and(|parts| {
+                    parts.iter().all(|part| part.data.len() as u64 == part.range.size())
+                }),
+            })
+        }
+        None => {
+            let range = response.get_header("content-range").and_then(|value| ContentRange::parse(value));
+            json!({
+                "parts": 1,
+                "ranges": range.map(|range| vec![[range.start, range.end]]).unwrap_or_default(),
+                "total": range.and_then(|range| range.total),
+                "complete": range.is_some_and(|range| body.len() as u64 == range.size()),
+            })
+        }
+    };
+    flow.flow.metadata.insert(METADATA_KEY.to_string(), description);
+    true
+}
+
+/// Removal of range headers from requests matching the `strip_range`
+/// filters
+#[derive(Debug, Clone, Default)]
+pub struct StripRange {
+    filters: Vec<Filter>,
+}
+
+impl StripRange {
+    pub fn from_specs(specs: &[String]) -> Result<Self> {
+        let filters = specs
+            .iter()
+            .map(|spec| Filter::new("strip_range".to_string(), spec.clone()))
+            .collect::<Result<_>>()?;
+        Ok(Self { filters })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Remove `Range` and `If-Range` from the request if a filter matches
+    /// it. Returns whether a header was removed.
+    pub fn apply(&self, flow: &mut HTTPFlow) -> bool {
+        if !self.filters.iter().any(|filter| filter.matches(flow)) {
+            return false;
+        }
+        let headers = &mut flow.request.headers;
+        let before = headers.len();
+        headers.retain(|(name, _)| !RANGE_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+        headers.len() != before
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack.get(from..)?.windows(needle.len()).position(|window| window == needle).map(|i| from + i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::HTTPRequest;
+
+    const MULTIPART: &[u8] = b"\r\n--THIS\r\nContent-Type: text/plain\r\nContent-Range: bytes 6-10/20\r\n\r\nworld\r\n\
+        --THIS\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-5/20\r\n\r\nhello \r\n\
+        --THIS\r\nContent-Range: bytes 15-19/20\r\n\r\n\r\n--x\r\n--THIS--\r\n";
+
+    fn partial(content_type: &str, content_range: Option<&str>, body: &[u8]) -> HTTPFlow {
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "example.com".to_string(), 443, "/f".to_string());
+        let mut response = HTTPResponse::new(206, "Partial Content".to_string());
+        response.headers.push(("Content-Type".to_string(), content_type.to_string()));
+        if let Some(content_range) = content_range {
+            response.headers.push(("Content-Range".to_string(), content_range.to_string()));
+        }
+        response.set_content(body.to_vec());
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_content_range() {
+        let range = ContentRange::parse("bytes 10-19/100").unwrap();
+        assert_eq!((range.start, range.end, range.total, range.size()), (10, 19, Some(100), 10));
+        assert!(!range.is_full());
+        assert!(ContentRange::parse("bytes 0-99/100").unwrap().is_full());
+        assert_eq!(ContentRange::parse("bytes 5-9/*").unwrap().total, None);
+        for invalid in ["bytes */100", "bytes 9-5/100", "bytes 0-100/100", "items 0-1/2", "bytes 0-x/2"] {
+            assert_eq!(ContentRange::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_byteranges() {
+        assert_eq!(boundary("multipart/byteranges; boundary=\"THIS\""), Some("THIS".to_string()));
+        assert_eq!(boundary("multipart/mixed; boundary=THIS"), None);
+
+        let parts = byteranges(MULTIPART, Some("THIS")).unwrap();
+        assert_eq!(byteranges(MULTIPART, None).unwrap(), parts);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(parts[0].data, b"world");
+        assert_eq!(parts[2].data, b"\r\n--x");
+        assert_eq!(parts[2].range.start, 15);
+
+        let segments = coalesce(&parts);
+        assert_eq!(segments, [(0, b"hello world".to_vec()), (15, b"\r\n--x".to_vec())]);
+        assert_eq!(byteranges(b"--THIS\r\nContent-Range: bytes 0-1/2\r\n\r\nab", Some("THIS")), None);
+    }
+
+    #[test]
+    fn test_record_partial_responses() {
+        let mut single = partial("video/mp4", Some("bytes 100-109/1000"), b"0123456789");
+        assert!(record(&mut single));
+        assert_eq!(
+            single.flow.metadata[METADATA_KEY],
+            json!({ "parts": 1, "ranges": [[100, 109]], "total": 1000, "complete": true })
+        );
+        assert_eq!(expected_body_size(single.response.as_ref().unwrap()), Some(10));
+
+        let mut cut = partial("video/mp4", Some("bytes 100-109/1000"), b"01234");
+        record(&mut cut);
+        assert_eq!(cut.flow.metadata[METADATA_KEY]["complete"], false);
+
+        let mut multi = partial("multipart/byteranges; boundary=THIS", None, MULTIPART);
+        record(&mut multi);
+        assert_eq!(multi.flow.metadata[METADATA_KEY]["ranges"], json!([[6, 10], [0, 5], [15, 19]]));
+        assert_eq!(multi.flow.metadata[METADATA_KEY]["complete"], true);
+        assert_eq!(expected_body_size(multi.response.as_ref().unwrap()), None);
+
+        let mut full = partial("text/plain", None, b"x");
+        full.response.as_mut().unwrap().status_code = 200;
+        assert!(!record(&mut full));
+        assert!(full.flow.metadata.get(METADATA_KEY).is_none());
+    }
+
+    #[test]
+    fn test_strip_range() {
+        let strip = StripRange::from_specs(&["~d cdn.example.com".to_string()]).unwrap();
+        let mut request =
+            HTTPRequest::new("GET".to_string(), "https".to_string(), "cdn.example.com".to_string(), 443, "/v.mp4".to_string());
+        request.headers = vec![
+            ("Range".to_string(), "bytes=0-".to_string()),
+            ("If-Range".to_string(), "\"v1\"".to_string()),
+            ("Accept".to_string(), "*/*".to_string()),
+        ];
+        let mut flow = HTTPFlow::new(request.clone());
+        assert!(strip.apply(&mut flow));
+        assert_eq!(flow.request.headers, [("Accept".to_string(), "*/*".to_string())]);
+        assert!(!strip.apply(&mut flow));
+
+        request.host = "example.com".to_string();
+        let mut other = HTTPFlow::new(request);
+        assert!(!strip.apply(&mut other));
+        assert_eq!(other.request.headers.len(), 3);
+        assert!(StripRange::from_specs(&["~u [".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,510 @@
+//! Redaction of identifying data in captured flows.
+//!
+//! The redactor replaces host names, IP addresses, credentials and cookie
+//! values with stable pseudonyms derived from a salted hash, so that
+//! anonymized captures keep their structure: the same host is always mapped
+//! to the same pseudonym, and timings, methods, status codes and header
+//! names are left untouched. The mapping from pseudonyms back to the original
+//! values is recorded so that it can be kept locally to reverse the process.
+
+use regex::Regex;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use crate::analysis::downgrades;
+use crate::flow::{dns_type, Connection, DNSMessage, DNSResourceRecord, HTTPFlow, TAGS_KEY};
+use crate::{coalesce, compression, header_profiles, lazybody, pinning, ranges, replay, serverreplay, shaping, stickycookie};
+
+/// Name fragments that mark a header, parameter or field as sensitive
+const SENSITIVE_FRAGMENTS: &[&str] = &[
+    "auth", "token", "secret", "pass", "session", "cookie", "credential", "signature", "apikey", "api_key", "api-key",
+];
+
+/// Names that are sensitive only as a whole
+const SENSITIVE_NAMES: &[&str] = &["key", "sig", "code", "jwt", "otp"];
+
+/// Headers that carry client or server addresses
+const ADDRESS_HEADERS: &[&str] = &["x-forwarded-for", "x-real-ip", "forwarded", "true-client-ip", "cf-connecting-ip"];
+
+/// Headers that carry URLs
+const URL_HEADERS: &[&str] = &["referer", "origin", "location", "content-location"];
+
+/// Metadata that only holds sizes, versions, rule names and flow ids. Any
+/// other metadata may quote hosts, credentials or bodies and is dropped.
+const SAFE_METADATA_KEYS: &[&str] = &[
+    TAGS_KEY,
+    coalesce::METADATA_KEY,
+    compression::METADATA_KEY,
+    downgrades::METADATA_KEY,
+    header_profiles::METADATA_KEY,
+    lazybody::METADATA_KEY,
+    pinning::METADATA_KEY,
+    ranges::METADATA_KEY,
+    replay::METADATA_KEY,
+    serverreplay::METADATA_KEY,
+    shaping::METADATA_KEY,
+    stickycookie::METADATA_KEY,
+];
+
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SENSITIVE_NAMES.contains(&name.as_str()) || SENSITIVE_FRAGMENTS.iter().any(|f| name.contains(f))
+}
+
+fn ipv4_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"\b(?:\d{1,3}\.){3}\d{1,3}\b").unwrap())
+}
+
+/// Replaces identifying values in flows with stable pseudonyms
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    salt: String,
+    /// Pseudonym to original value
+    mapping: BTreeMap<String, String>,
+    /// Original host names seen so far, replaced in text bodies
+    hosts: Vec<String>,
+}
+
+impl Redactor {
+    /// Create a redactor; the salt makes pseudonyms unguessable across exports
+    pub fn new(salt: impl Into<String>) -> Self {
+        Self { salt: salt.into(), mapping: BTreeMap::new(), hosts: Vec::new() }
+    }
+
+    /// Pseudonym to original value mapping for reversing an export
+    pub fn mapping(&self) -> &BTreeMap<String, String> {
+        &self.mapping
+    }
+
+    fn digest(&self, kind: &str, value: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update(kind.as_bytes());
+        hasher.update([0]);
+        hasher.update(value.as_bytes());
+        hasher.finalize().into()
+    }
+
+    fn remember(&mut self, pseudonym: String, original: &str) -> String {
+        self.mapping.entry(pseudonym.clone()).or_insert_with(|| original.to_string());
+        pseudonym
+    }
+
+    /// Pseudonym for a host name or IP address
+    pub fn host(&mut self, host: &str) -> String {
+        if host.is_empty() {
+            return String::new();
+        }
+        if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            return self.ip(ip);
+        }
+        let lower = host.to_ascii_lowercase();
+        if self.mapping.contains_key(&lower) {
+            // Already a pseudonym
+            return lower;
+        }
+        let digest = self.digest("host", &lower);
+        let pseudonym = format!("host-{}.example", hex(&digest[..4]));
+        if !self.hosts.contains(&lower) {
+            self.hosts.push(lower.clone());
+            // Replace longer names first so subdomains are not split
+            self.hosts.sort_by_key(|h| std::cmp::Reverse(h.len()));
+        }
+        self.remember(pseudonym, &lower)
+    }
+
+    /// Pseudonym for an IP address, kept within the same address family
+    pub fn ip(&mut self, ip: IpAddr) -> String {
+        let digest = self.digest("ip", &ip.to_string());
+        let pseudonym = match ip {
+            IpAddr::V4(_) => format!("10.{}.{}.{}", digest[0], digest[1], digest[2]),
+            IpAddr::V6(_) => format!(
+                "fd00::{:x}:{:x}",
+                u16::from_be_bytes([digest[0], digest[1]]),
+                u16::from_be_bytes([digest[2], digest[3]])
+            ),
+        };
+        self.remember(pseudonym, &ip.to_string())
+    }
+
+    /// Pseudonym for a credential or other secret value
+    pub fn secret(&mut self, value: &str) -> String {
+        if value.is_empty() {
+            return String::new();
+        }
+        let digest = self.digest("secret", value);
+        let pseudonym = format!("redacted-{}", hex(&digest[..6]));
+        self.remember(pseudonym, value)
+    }
+
+    /// Anonymize a copy of a flow
+    pub fn anonymize(&mut self, flow: &HTTPFlow) -> HTTPFlow {
+        let mut flow = flow.clone();
+
+        flow.request.host = self.host(&flow.request.host);
+        let default_port = matches!((flow.request.scheme.as_str(), flow.request.port), ("http", 80) | ("https", 443));
+        flow.request.pretty_host = if default_port {
+            flow.request.host.clone()
+        } else {
+            format!("{}:{}", flow.request.host, flow.request.port)
+        };
+        flow.request.path = self.path(&flow.request.path);
+        flow.request.headers = self.headers(&flow.request.headers);
+        if let Some(content) = flow.request.content.take() {
+            let content_type = flow.request.get_header("content-type").cloned();
+            flow.request.set_content(self.body(&content, content_type.as_deref()));
+        }
+
+        if let Some(response) = flow.response.as_mut() {
+            response.headers = self.headers(&response.headers);
+            if let Some(trailers) = &response.trailers {
+                response.trailers = Some(self.headers(trailers));
+            }
+            if let Some(content) = response.content.take() {
+                let content_type = response.get_header("content-type").cloned();
+                response.set_content(self.body(&content, content_type.as_deref()));
+            }
+        }
+
+        if let Some(websocket) = flow.websocket.as_mut() {
+            for message in &mut websocket.messages {
+                message.content = self.body(&message.content, None);
+            }
+        }
+        for message in &mut flow.tcp_messages {
+            message.content = self.body(&message.content, None);
+        }
+        for message in &mut flow.sse_events {
+            message.event.data = String::from_utf8_lossy(&self.body(message.event.data.as_bytes(), None)).into_owned();
+        }
+        if let Some(dns) = flow.dns.as_mut() {
+            for message in std::iter::once(&mut dns.request).chain(dns.response.as_mut()) {
+                self.dns_message(message);
+            }
+        }
+        // The changelog holds original header and body values
+        flow.flow.changes.clear();
+        flow.flow.metadata.retain(|key, _| SAFE_METADATA_KEYS.contains(&key.as_str()));
+
+        for conn in [flow.flow.client_conn.as_mut(), flow.flow.server_conn.as_mut()].into_iter().flatten() {
+            self.connection(conn);
+        }
+
+        flow
+    }
+
+    fn connection(&mut self, conn: &mut Connection) {
+        for address in [&mut conn.peername, &mut conn.sockname, &mut conn.address].into_iter().flatten() {
+            address.0 = self.host(&address.0);
+        }
+        if let Some(sni) = conn.sni.take() {
+            conn.sni = Some(self.host(&sni));
+        }
+        // Certificates identify the server by name and key
+        conn.cert = None;
+    }
+
+    fn dns_message(&mut self, message: &mut DNSMessage) {
+        for question in &mut message.questions {
+            question.name = self.host(&question.name);
+        }
+        for record in message.answers.iter_mut().chain(&mut message.authorities).chain(&mut message.additionals) {
+            record.name = self.host(&record.name);
+            record.data = self.record_data(record);
+        }
+    }
+
+    /// Addresses in record data are replaced by their pseudonyms. Other
+    /// record data holds names and text in wire format and is dropped.
+    fn record_data(&mut self, record: &DNSResourceRecord) -> Vec<u8> {
+        let ip = match record.record_type {
+            dns_type::A => <[u8; 4]>::try_from(record.data.as_slice()).ok().map(IpAddr::from),
+            dns_type::AAAA => <[u8; 16]>::try_from(record.data.as_slice()).ok().map(IpAddr::from),
+            _ => None,
+        };
+        match ip.and_then(|ip| self.ip(ip).parse::<IpAddr>().ok()) {
+            Some(IpAddr::V4(ip)) => ip.octets().to_vec(),
+            Some(IpAddr::V6(ip)) => ip.octets().to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    fn headers(&mut self, headers: &[(String, String)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.clone(), self.header(name, value)))
+            .collect()
+    }
+
+    fn header(&mut self, name: &str, value: &str) -> String {
+        let lower = name.to_ascii_lowercase();
+        match lower.as_str() {
+            "host" | ":authority" => match value.rsplit_once(':').filter(|(_, port)| port.parse::<u16>().is_ok()) {
+                Some((host, port)) => format!("{}:{}", self.host(host), port),
+                None => self.host(value),
+            },
+            "authorization" | "proxy-authorization" => match value.split_once(' ') {
+                Some((scheme, credentials)) => format!("{} {}", scheme, self.secret(credentials)),
+                None => self.secret(value),
+            },
+            "cookie" => value
+                .split(';')
+                .map(|pair| match pair.trim().split_once('=') {
+                    Some((name, value)) => format!("{}={}", name, self.secret(value)),
+                    None => pair.trim().to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("; "),
+            "set-cookie" => self.set_cookie(value),
+            _ if ADDRESS_HEADERS.contains(&lower.as_str()) => self.text(value),
+            _ if URL_HEADERS.contains(&lower.as_str()) => self.url(value),
+            _ if is_sensitive(&lower) => self.secret(value),
+            _ => self.text(value),
+        }
+    }
+
+    fn set_cookie(&mut self, value: &str) -> String {
+        let mut parts = value.split(';');
+        let cookie = match parts.next().and_then(|pair| pair.split_once('=')) {
+            Some((name, value)) => format!("{}={}", name.trim(), self.secret(value.trim())),
+            None => return self.secret(value),
+        };
+        std::iter::once(cookie)
+            .chain(parts.map(|attr| match attr.trim().split_once('=') {
+                Some((name, value)) if name.eq_ignore_ascii_case("domain") => {
+                    format!("{}={}", name, self.host(value.trim_start_matches('.')))
+                }
+                _ => attr.trim().to_string(),
+            }))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn url(&mut self, value: &str) -> String {
+        match url::Url::parse(value) {
+            Ok(mut url) if url.host_str().is_some() => {
+                let host = self.host(url.host_str().unwrap_or_default());
+                if url.set_host(Some(&host)).is_err() {
+                    return self.text(value);
+                }
+                let path = self.path(&url[url::Position::BeforePath..]);
+                format!("{}{}", &url[..url::Position::BeforePath], path)
+            }
+            _ => self.path(value),
+        }
+    }
+
+    /// Hash sensitive query parameters of a request target
+    fn path(&mut self, path: &str) -> String {
+        let Some((base, query)) = path.split_once('?') else {
+            return self.text(path);
+        };
+        let query = query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) if is_sensitive(name) => format!("{}={}", name, self.secret(value)),
+                Some((name, value)) => format!("{}={}", name, self.text(value)),
+                None => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        format!("{}?{}", self.text(base), query)
+    }
+
+    /// Replace known host names and IPv4 addresses in free text
+    fn text(&mut self, text: &str) -> String {
+        let mut text = text.to_string();
+        for host in self.hosts.clone() {
+            if text.contains(&host) {
+                let pseudonym = self.host(&host);
+                text = text.replace(&host, &pseudonym);
+            }
+        }
+        ipv4_regex()
+            .replace_all(&text, |caps: &regex::Captures| match caps[0].parse::<IpAddr>() {
+                Ok(ip) if !self.mapping.contains_key(&caps[0]) => self.ip(ip),
+                _ => caps[0].to_string(),
+            })
+            .into_owned()
+    }
+
+    fn body(&mut self, content: &[u8], content_type: Option<&str>) -> Vec<u8> {
+        let Ok(text) = std::str::from_utf8(content) else {
+            // Binary bodies cannot be inspected and are dropped
+            return Vec::new();
+        };
+
+        let is_form = content_type.is_some_and(|ct| ct.contains("x-www-form-urlencoded"));
+        if is_form {
+            return self.path(&format!("?{}", text)).as_bytes()[1..].to_vec();
+        }
+
+        if let Ok(mut value) = serde_json::from_str::<Value>(text) {
+            self.json(&mut value);
+            if let Ok(serialized) = serde_json::to_vec(&value) {
+                return serialized;
+            }
+        }
+
+        self.text(text).into_bytes()
+    }
+
+    fn json(&mut self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    match value {
+                        Value::String(s) if is_sensitive(key) => *s = self.secret(s),
+                        Value::Number(_) | Value::Bool(_) if is_sensitive(key) => {
+                            *value = Value::String(self.secret(&value.to_string()));
+                        }
+                        _ => self.json(value),
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.json(item)),
+            Value::String(s) => *s = self.text(s),
+            _ => {}
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{cors, unparsable};
+    use crate::flow::{DNSQuestion, HTTPRequest, HTTPResponse, TCPMessage};
+    use crate::{bodydiff, capture_profiles, proxychain};
+    use serde_json::json;
+
+    fn flow() -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            "POST".to_string(),
+            "https".to_string(),
+            "api.corp.com".to_string(),
+            8443,
+            "/v1/login?user=bob&access_token=abc123".to_string(),
+        );
+        request.timestamp_start = Some(10.5);
+        request.set_header("Host".to_string(), "api.corp.com:8443".to_string());
+        request.set_header("Authorization".to_string(), "Bearer abc123".to_string());
+        request.set_header("Cookie".to_string(), "sid=s3cr3t; theme=dark".to_string());
+        request.set_header("X-Forwarded-For".to_string(), "192.168.1.20".to_string());
+        request.set_header("Content-Type".to_string(), "application/json".to_string());
+        request.set_content(br#"{"password": "hunter2", "callback": "https://api.corp.com/cb"}"#.to_vec());
+
+        let mut response = HTTPResponse::new(302, "Found".to_string());
+        response.set_header("Location".to_string(), "https://www.corp.com/home?code=xyz".to_string());
+        response.set_header("Set-Cookie".to_string(), "sid=new; Domain=.corp.com; HttpOnly".to_string());
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_anonymize_flow() {
+        let mut original = flow();
+        unparsable::record(&mut original, "Invalid status line", b"HTTP/9 s3cr3t", 13);
+        let metadata = &mut original.flow.metadata;
+        metadata.insert(bodydiff::METADATA_KEY.to_string(), json!({ "lines": [{ "text": "password=hunter2" }] }));
+        metadata.insert(proxychain::METADATA_KEY.to_string(), json!({ "proxy": "http://proxy.corp.com:3128" }));
+        metadata.insert(capture_profiles::METADATA_KEY.to_string(), json!("bob@corp.com"));
+        metadata.insert(cors::METADATA_KEY.to_string(), json!({ "origin": "https://evil.corp.com" }));
+        original.flow.add_tag("login");
+        let mut redactor = Redactor::new("salt");
+        let anonymized = redactor.anonymize(&original);
+        let serialized = serde_json::to_string(&anonymized).unwrap();
+
+        for secret in ["corp.com", "abc123", "s3cr3t", "hunter2", "192.168.1.20", "xyz", "sid=new"] {
+            assert!(!serialized.contains(secret), "{} leaked", secret);
+        }
+        // Only metadata known to be safe is kept
+        let keys: Vec<&str> = anonymized.flow.metadata.keys().map(String::as_str).collect();
+        assert_eq!(keys, [TAGS_KEY]);
+
+        // Structure and timings are preserved
+        assert_eq!(anonymized.request.method, "POST");
+        assert_eq!(anonymized.request.port, 8443);
+        assert_eq!(anonymized.request.timestamp_start, Some(10.5));
+        assert!(anonymized.request.path.starts_with("/v1/login?user=bob&access_token=redacted-"));
+        assert!(anonymized.request.get_header("Authorization").unwrap().starts_with("Bearer redacted-"));
+        assert!(anonymized.request.get_header("Cookie").unwrap().contains("theme="));
+        let set_cookie = anonymized.response.as_ref().unwrap().get_header("Set-Cookie").unwrap();
+        assert!(set_cookie.ends_with("HttpOnly"));
+
+        // The same host maps to the same pseudonym everywhere
+        let host = anonymized.request.host.clone();
+        assert_eq!(anonymized.request.get_header("Host").unwrap(), &format!("{}:8443", host));
+        let body = String::from_utf8(anonymized.request.content.clone().unwrap()).unwrap();
+        assert!(body.contains(&format!("https://{}/cb", host)));
+    }
+
+    #[test]
+    fn test_anonymize_tcp_messages() {
+        let mut flow = flow();
+        flow.tcp_messages = vec![
+            TCPMessage { from_client: true, content: b"HELO api.corp.com from 192.168.1.20".to_vec(), timestamp: 1.0 },
+            TCPMessage { from_client: false, content: vec![0xff, 0xfe, 0x00], timestamp: 2.0 },
+        ];
+        let anonymized = Redactor::new("salt").anonymize(&flow);
+        let client = String::from_utf8(anonymized.tcp_messages[0].content.clone()).unwrap();
+        assert!(client.starts_with("HELO host-") && client.contains(" from 10."), "{}", client);
+        assert!(anonymized.tcp_messages[1].content.is_empty());
+        assert_eq!(anonymized.tcp_messages[1].timestamp, 2.0);
+    }
+
+    #[test]
+    fn test_anonymize_dns() {
+        let question = DNSQuestion { name: "intranet.corp.com".to_string(), record_type: dns_type::A, class: 1 };
+        let record = |record_type, data: &[u8]| DNSResourceRecord {
+            name: "intranet.corp.com".to_string(),
+            record_type,
+            class: 1,
+            ttl: 60,
+            data: data.to_vec(),
+        };
+        let query = DNSMessage { query: true, questions: vec![question.clone()], ..Default::default() };
+        let mut flow = HTTPFlow::new_dns(query);
+        flow.dns.as_mut().unwrap().response = Some(DNSMessage {
+            questions: vec![question],
+            answers: vec![record(dns_type::A, &[192, 168, 7, 9]), record(dns_type::CNAME, b"\x03web\x04corp\x03com\x00")],
+            ..Default::default()
+        });
+
+        let mut redactor = Redactor::new("salt");
+        let anonymized = redactor.anonymize(&flow);
+        let serialized = serde_json::to_string(&anonymized).unwrap();
+        assert!(!serialized.contains("corp.com") && !serialized.contains("[192,168,7,9]"), "{}", serialized);
+
+        let dns = anonymized.dns.unwrap();
+        let host = redactor.host("intranet.corp.com");
+        assert_eq!(dns.request.questions[0].name, host);
+        let answers = &dns.response.unwrap().answers;
+        assert_eq!(answers[0].name, host);
+        let ip = IpAddr::from(<[u8; 4]>::try_from(answers[0].data.as_slice()).unwrap());
+        assert_eq!(redactor.mapping()[&ip.to_string()], "192.168.7.9");
+        assert!(answers[1].data.is_empty());
+    }
+
+    #[test]
+    fn test_mapping_is_reversible() {
+        let mut redactor = Redactor::new("salt");
+        let host = redactor.host("Example.COM");
+        let ip = redactor.host("2001:db8::1");
+        let secret = redactor.secret("token");
+
+        assert_eq!(redactor.mapping()[&host], "example.com");
+        assert_eq!(redactor.mapping()[&ip], "2001:db8::1");
+        assert_eq!(redactor.mapping()[&secret], "token");
+        assert!(ip.starts_with("fd00::"));
+
+        // Different salts give different pseudonyms
+        assert_ne!(Redactor::new("other").host("example.com"), host);
+    }
+}
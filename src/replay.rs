@@ -0,0 +1,254 @@
+//! Client replay of stored flows.
+//!
+//! A replayed request is sent to its server again and recorded as a new
+//! flow. With `follow_redirects`, 3xx answers are followed for up to
+//! `max_redirects` hops, each hop recorded as its own flow linked to the
+//! one before it under the [`METADATA_KEY`] metadata key. A redirect back
+//! to a request already sent in the chain stops the replay as a loop.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::future::Future;
+
+use crate::flow::{FlowError, HTTPFlow, HTTPRequest, HTTPResponse};
+use crate::Result;
+
+/// Flow metadata key linking a redirect hop to the flow redirected from
+pub const METADATA_KEY: &str = "redirect";
+
+/// Headers not sent along when a redirect leaves the origin
+const CREDENTIAL_HEADERS: &[&str] = &["authorization", "cookie", "proxy-authorization"];
+
+/// Headers describing a body that is dropped when a redirect changes the
+/// method to GET
+const BODY_HEADERS: &[&str] = &["content-encoding", "content-length", "content-type", "transfer-encoding"];
+
+/// Replay options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReplayOptions {
+    /// Follow 3xx answers instead of stopping at the first response
+    pub follow_redirects: bool,
+    /// Redirects followed before the replay stops
+    pub max_redirects: usize,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self { follow_redirects: false, max_redirects: 10 }
+    }
+}
+
+/// Why a replay stopped on a redirect instead of following it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum ReplayStop {
+    /// The redirect points at a request already sent in this replay
+    Loop { url: String },
+    /// `max_redirects` hops were followed
+    TooManyRedirects { hops: usize },
+}
+
+/// Flows recorded by a replay, the replayed request first
+#[derive(Debug, Clone, Default)]
+pub struct ReplayOutcome {
+    pub flows: Vec<HTTPFlow>,
+    pub stopped: Option<ReplayStop>,
+}
+
+/// Replay the request of `flow` through `send`, following redirects as
+/// configured. Failed requests end the replay with the error on their flow.
+pub async fn replay<F, Fut>(flow: &HTTPFlow, options: &ReplayOptions, mut send: F) -> ReplayOutcome
+where
+    F: FnMut(HTTPRequest) -> Fut,
+    Fut: Future<Output = Result<HTTPResponse>>,
+{
+    let mut current = flow.copy();
+    current.response = None;
+    current.flow.error = None;
+    current.flow.metadata.shift_remove(METADATA_KEY);
+
+    let mut outcome = ReplayOutcome::default();
+    let mut sent = HashSet::from([request_key(&current.request)]);
+    loop {
+        current.flow.timestamp_created = now();
+        current.request.timestamp_start = Some(now());
+        match send(current.request.clone()).await {
+            Ok(response) => current.response = Some(response),
+            Err(e) => current.flow.error = Some(FlowError { msg: e.to_string(), timestamp: now() }),
+        }
+        let next = if options.follow_redirects { redirect_request(&current) } else { None };
+        let from = current.flow.id.clone();
+        outcome.flows.push(current);
+
+        let Some(request) = next else {
+            break;
+        };
+        let hops = outcome.flows.len() - 1;
+        if hops >= options.max_redirects {
+            outcome.stopped = Some(ReplayStop::TooManyRedirects { hops });
+            break;
+        }
+        if !sent.insert(request_key(&request)) {
+            outcome.stopped = Some(ReplayStop::Loop { url: request.url() });
+            break;
+        }
+        current = HTTPFlow::new(request);
+        current.flow.is_replay = true;
+        current.flow.metadata.insert(METADATA_KEY.to_string(), json!({ "from": from, "hop": hops + 1 }));
+    }
+    outcome
+}
+
+/// The request a browser would send next for a redirect answer in `flow`
+pub fn redirect_request(flow: &HTTPFlow) -> Option<HTTPRequest> {
+    let response = flow.response.as_ref()?;
+    if !matches!(response.status_code, 301 | 302 | 303 | 307 | 308) {
+        return None;
+    }
+    let location = response.get_header("location")?;
+    let base = url::Url::parse(&flow.request.url()).ok()?;
+    let target = base.join(location.trim()).ok()?;
+    if !matches!(target.scheme(), "http" | "https") {
+        return None;
+    }
+
+    let previous = &flow.request;
+    // 303 always switches to GET; 301 and 302 only for POST, as browsers do
+    let to_get = match response.status_code {
+        303 => !previous.method.eq_ignore_ascii_case("HEAD"),
+        301 | 302 => previous.method.eq_ignore_ascii_case("POST"),
+        _ => false,
+    };
+    let method = if to_get { "GET".to_string() } else { previous.method.clone() };
+    let mut path = target.path().to_string();
+    if let Some(query) = target.query() {
+        path = format!("{}?{}", path, query);
+    }
+    let mut request = HTTPRequest::new(
+        method,
+        target.scheme().to_string(),
+        target.host_str()?.to_string(),
+        target.port_or_known_default()?,
+        path,
+    );
+    request.http_version = previous.http_version.clone();
+    request.headers = previous.headers.clone();
+
+    let same_origin = request.scheme == previous.scheme
+        && request.host.eq_ignore_ascii_case(&previous.host)
+        && request.port == previous.port;
+    request.headers.retain(|(name, _)| {
+        let name = name.to_ascii_lowercase();
+        (same_origin || !CREDENTIAL_HEADERS.contains(&name.as_str()))
+            && (!to_get || !BODY_HEADERS.contains(&name.as_str()))
+    });
+    if request.get_header("host").is_some() {
+        request.set_header("Host".to_string(), request.pretty_host.clone());
+    }
+    if !to_get {
+        if let Some(content) = &previous.content {
+            request.set_content(content.clone());
+        }
+    }
+    Some(request)
+}
+
+fn request_key(request: &HTTPRequest) -> (String, String) {
+    (request.method.to_ascii_uppercase(), request.url())
+}
+
+fn now() -> f64 {
+    crate::clock::now()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(status: u16, location: &str) -> HTTPResponse {
+        let mut response = HTTPResponse::new(status, String::new());
+        response.headers = vec![("Location".to_string(), location.to_string())];
+        response
+    }
+
+    fn post_flow() -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            "POST".to_string(),
+            "https".to_string(),
+            "app.example.com".to_string(),
+            443,
+            "/login".to_string(),
+        );
+        request.headers = vec![
+            ("Host".to_string(), "app.example.com".to_string()),
+            ("Cookie".to_string(), "session=1".to_string()),
+            ("Content-Type".to_string(), "application/x-www-form-urlencoded".to_string()),
+        ];
+        request.set_content(b"user=a".to_vec());
+        HTTPFlow::new(request)
+    }
+
+    #[test]
+    fn test_redirect_request() {
+        let mut flow = post_flow();
+        flow.response = Some(redirect(303, "/home?tab=1"));
+        let next = redirect_request(&flow).unwrap();
+        assert_eq!(next.method, "GET");
+        assert_eq!(next.url(), "https://app.example.com/home?tab=1");
+        assert!(next.content.is_none());
+        assert!(next.get_header("content-type").is_none());
+        assert_eq!(next.get_header("cookie").unwrap(), "session=1");
+
+        flow.response = Some(redirect(307, "http://other.example.com:8080/login"));
+        let next = redirect_request(&flow).unwrap();
+        assert_eq!(next.method, "POST");
+        assert_eq!(next.content.as_deref(), Some(&b"user=a"[..]));
+        assert_eq!(next.get_header("host").unwrap(), "other.example.com:8080");
+        assert!(next.get_header("cookie").is_none());
+
+        flow.response = Some(redirect(200, "/elsewhere"));
+        assert!(redirect_request(&flow).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replay_follows_redirects() {
+        let flow = post_flow();
+        let send = |request: HTTPRequest| async move {
+            Ok(match request.path.as_str() {
+                "/login" => redirect(302, "/step"),
+                "/step" => redirect(302, "/home"),
+                "/loop" => redirect(302, "/step-loop"),
+                "/step-loop" => redirect(302, "/loop"),
+                _ => HTTPResponse::new(200, "OK".to_string()),
+            })
+        };
+
+        let stop_first = replay(&flow, &ReplayOptions::default(), send).await;
+        assert_eq!(stop_first.flows.len(), 1);
+        assert_eq!(stop_first.flows[0].response.as_ref().unwrap().status_code, 302);
+        assert!(stop_first.flows[0].flow.is_replay);
+        assert_ne!(stop_first.flows[0].flow.id, flow.flow.id);
+
+        let options = ReplayOptions { follow_redirects: true, max_redirects: 10 };
+        let followed = replay(&flow, &options, send).await;
+        assert_eq!(followed.flows.len(), 3);
+        assert!(followed.stopped.is_none());
+        assert_eq!(followed.flows[2].request.path, "/home");
+        assert_eq!(followed.flows[2].request.method, "GET");
+        assert_eq!(followed.flows[2].flow.metadata[METADATA_KEY]["from"], json!(followed.flows[1].flow.id));
+        assert_eq!(followed.flows[2].flow.metadata[METADATA_KEY]["hop"], json!(2));
+
+        let limited = replay(&flow, &ReplayOptions { follow_redirects: true, max_redirects: 1 }, send).await;
+        assert_eq!(limited.flows.len(), 2);
+        assert_eq!(limited.stopped, Some(ReplayStop::TooManyRedirects { hops: 1 }));
+
+        let mut looping = flow.clone();
+        looping.request.method = "GET".to_string();
+        looping.request.path = "/loop".to_string();
+        let looped = replay(&looping, &options, send).await;
+        assert_eq!(looped.flows.len(), 2);
+        assert_eq!(looped.stopped, Some(ReplayStop::Loop { url: "https://app.example.com/loop".to_string() }));
+    }
+}
@@ -0,0 +1,182 @@
+//! The rewriting rules requests and responses go through on their way
+//! through the proxy.
+//!
+//! Requests get header normalization, range, cache and compression header
+//! removal, header and body rewriting, sticky cookies and injected
+//! credentials; responses teach sticky cookies, then get body substitution,
+//! header and body rewriting and CSP rewriting. The rules are shared by the
+//! proxy server, which applies them in its request and response hooks, and
+//! the HTTP layer, which applies them inline once a message is complete,
+//! like `map_local` and `block_list`. Each rule that changed a flow is noted
+//! in its change log.
+
+use std::sync::RwLock;
+use tracing::warn;
+
+use crate::auth_injection::AuthInjection;
+use crate::changelog;
+use crate::config::Config;
+use crate::csp::Csp;
+use crate::flow::HTTPFlow;
+use crate::header_profiles::HeaderProfiles;
+use crate::modifybody::ModifyBody;
+use crate::modifyheaders::ModifyHeaders;
+use crate::ranges::StripRange;
+use crate::sandbox::Sandbox;
+use crate::stickycookie::StickyCookies;
+use crate::substitute::Substitute;
+
+#[derive(Debug, Default)]
+pub struct Rewrites {
+    /// Request header normalization
+    pub header_profiles: RwLock<HeaderProfiles>,
+    /// Removal of range headers so that full bodies are captured
+    pub strip_range: StripRange,
+    /// Remove headers that could get a cached answer
+    pub anticache: bool,
+    /// Ask for uncompressed responses
+    pub anticomp: bool,
+    /// Header rewriting rules, replaceable through the options API
+    pub modify_headers: RwLock<ModifyHeaders>,
+    /// Body replacement rules, replaceable through the options API
+    pub modify_body: RwLock<ModifyBody>,
+    /// Cookies sent along with later requests to the servers that set them
+    pub sticky_cookies: Option<StickyCookies>,
+    /// Credentials given to matching requests
+    pub auth_injection: AuthInjection,
+    /// Response bodies replaced with local files
+    pub substitute: Substitute,
+    /// CSP rewriting rules and report capture
+    pub csp: Csp,
+}
+
+impl Rewrites {
+    /// The rules of `config`. Invalid rules are logged and left out.
+    pub fn new(config: &Config, sandbox: &Sandbox) -> Self {
+        let header_profiles = HeaderProfiles::new(&config.header_profiles, config.header_profile.as_deref())
+            .unwrap_or_else(|e| {
+                warn!("Ignoring configured header profiles: {}", e);
+                HeaderProfiles::default()
+            });
+
+        let strip_range = StripRange::from_specs(&config.strip_range).unwrap_or_else(|e| {
+            warn!("Ignoring configured strip_range filters: {}", e);
+            StripRange::default()
+        });
+
+        let modify_headers = ModifyHeaders::from_specs(&config.modify_headers).unwrap_or_else(|e| {
+            warn!("Ignoring configured modify_headers rules: {}", e);
+            ModifyHeaders::default()
+        });
+
+        let modify_body = ModifyBody::new(&config.modify_body, config.modify_body_max_size).unwrap_or_else(|e| {
+            warn!("Ignoring configured modify_body rules: {}", e);
+            ModifyBody::default()
+        });
+
+        let sticky_cookies = config.stickycookie.as_deref().and_then(|filter| {
+            StickyCookies::new(filter)
+                .inspect_err(|e| warn!("Ignoring configured stickycookie filter: {}", e))
+                .ok()
+        });
+
+        let auth_injection = AuthInjection::new(&config.auth_injection, sandbox).unwrap_or_else(|e| {
+            warn!("Ignoring configured auth_injection rules: {}", e);
+            AuthInjection::default()
+        });
+
+        let substitute = Substitute::from_specs(&config.substitute_body).unwrap_or_else(|e| {
+            warn!("Ignoring configured substitute_body rules: {}", e);
+            Substitute::default()
+        });
+
+        let csp = Csp::new(&config.csp).unwrap_or_else(|e| {
+            warn!("Ignoring configured CSP rules: {}", e);
+            Csp::default()
+        });
+
+        Self {
+            header_profiles: RwLock::new(header_profiles),
+            strip_range,
+            anticache: config.anticache,
+            anticomp: config.anticomp,
+            modify_headers: RwLock::new(modify_headers),
+            modify_body: RwLock::new(modify_body),
+            sticky_cookies,
+            auth_injection,
+            substitute,
+            csp,
+        }
+    }
+
+    /// Rewrite a complete request before it is forwarded
+    pub fn request(&self, flow: &mut HTTPFlow) {
+        let header_profiles = self.header_profiles.read().unwrap();
+        step(flow, header_profiles.active().is_some(), "header_profile", "request", |flow| header_profiles.apply(flow));
+        step(flow, !self.strip_range.is_empty(), "strip_range", "request", |flow| self.strip_range.apply(flow));
+        step(flow, self.anticache, "anticache", "request", |flow| flow.request.anticache());
+        step(flow, self.anticomp, "anticomp", "request", |flow| flow.request.anticomp());
+        let modify_headers = self.modify_headers.read().unwrap();
+        step(flow, !modify_headers.is_empty(), "modify_headers", "request", |flow| modify_headers.apply_request(flow));
+        let modify_body = self.modify_body.read().unwrap();
+        step(flow, !modify_body.is_empty(), "modify_body", "request", |flow| modify_body.apply_request(flow));
+        if let Some(sticky) = &self.sticky_cookies {
+            step(flow, true, "stickycookie", "request", |flow| sticky.request(flow));
+        }
+        step(flow, !self.auth_injection.is_empty(), "auth_injection", "request", |flow| self.auth_injection.apply(flow));
+    }
+
+    /// Rewrite a complete response before it is sent to the client
+    pub fn response(&self, flow: &mut HTTPFlow) {
+        if let Some(sticky) = &self.sticky_cookies {
+            sticky.response(flow);
+        }
+        step(flow, !self.substitute.is_empty(), "substitute_body", "response", |flow| self.substitute.apply(flow));
+        let modify_headers = self.modify_headers.read().unwrap();
+        step(flow, !modify_headers.is_empty(), "modify_headers", "response", |flow| modify_headers.apply_response(flow));
+        let modify_body = self.modify_body.read().unwrap();
+        step(flow, !modify_body.is_empty(), "modify_body", "response", |flow| modify_body.apply_response(flow));
+        self.csp.rewrite(flow);
+    }
+}
+
+/// Run `apply` on `flow` if `enabled` and note what it changed in the
+/// change log under `source`. The flow is only copied, to compare it with
+/// afterwards, for steps that are enabled.
+fn step(flow: &mut HTTPFlow, enabled: bool, source: &str, hook: &str, apply: impl FnOnce(&mut HTTPFlow) -> bool) {
+    if !enabled {
+        return;
+    }
+    let before = flow.clone();
+    if apply(flow) {
+        changelog::record(flow, &before, source, Some(hook));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    #[test]
+    fn test_changes_are_noted() {
+        let config = Config {
+            anticomp: true,
+            modify_headers: vec!["/~s/Server/".to_string()],
+            ..Default::default()
+        };
+        let rewrites = Rewrites::new(&config, &Sandbox::default());
+        let mut request = HTTPRequest::new("GET".to_string(), "https".to_string(), "example.com".to_string(), 443, "/".to_string());
+        request.headers = vec![("Accept-Encoding".to_string(), "gzip".to_string())];
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.headers = vec![("Server".to_string(), "nginx".to_string())];
+        let mut flow = HTTPFlow::new(request).with_response(response);
+
+        rewrites.request(&mut flow);
+        rewrites.response(&mut flow);
+        let sources: Vec<(&str, Option<&str>)> =
+            flow.flow.changes.iter().map(|change| (change.source.as_str(), change.hook.as_deref())).collect();
+        assert_eq!(sources, [("anticomp", Some("request")), ("modify_headers", Some("response"))]);
+        assert!(flow.request.headers.is_empty() && flow.response.unwrap().headers.is_empty());
+    }
+}
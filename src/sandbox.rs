@@ -0,0 +1,243 @@
+//! Resource limits and capabilities of user scripts.
+//!
+//! Scripts run on the data path with access to captured traffic, so each
+//! invocation gets a budget of wall-clock time, interpreter operations and
+//! memory, and is stopped once it exceeds any of them. Scripts cannot touch
+//! the filesystem or the network unless the config grants it: filesystem
+//! access is limited to listed directories, network access to listed hosts.
+//! Scripting engines check every such access against the [`Sandbox`] and
+//! charge their work to an [`Invocation`].
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result};
+
+/// Sandbox options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxOptions {
+    /// Wall-clock time a single invocation may take
+    pub max_duration_ms: u64,
+    /// Interpreter operations a single invocation may perform
+    pub max_operations: u64,
+    /// Memory a single invocation may allocate
+    pub max_memory_bytes: usize,
+    /// Directories scripts may read and write below; none by default
+    pub allow_filesystem: Vec<String>,
+    /// Hosts scripts may connect to, as `host`, `host:port` or
+    /// `*.domain`; none by default
+    pub allow_network: Vec<String>,
+}
+
+impl Default for SandboxOptions {
+    fn default() -> Self {
+        Self {
+            max_duration_ms: 100,
+            max_operations: 1_000_000,
+            max_memory_bytes: 16 * 1024 * 1024,
+            allow_filesystem: Vec::new(),
+            allow_network: Vec::new(),
+        }
+    }
+}
+
+/// Limits and capability grants shared by all script invocations
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    options: SandboxOptions,
+    /// Canonical forms of the allowed directories
+    roots: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    pub fn new(options: &SandboxOptions) -> Result<Self> {
+        let roots = options
+            .allow_filesystem
+            .iter()
+            .map(|dir| {
+                Path::new(dir)
+                    .canonicalize()
+                    .map_err(|e| Error::invalid_request(format!("Script filesystem grant {}: {}", dir, e)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { options: options.clone(), roots })
+    }
+
+    pub fn options(&self) -> &SandboxOptions {
+        &self.options
+    }
+
+    /// Resolve a path a script wants to access, failing unless it lies
+    /// within a granted directory. The path may not exist yet.
+    pub fn check_path(&self, path: &Path) -> Result<PathBuf> {
+        let resolved = resolve(path)?;
+        if self.roots.iter().any(|root| resolved.starts_with(root)) {
+            Ok(resolved)
+        } else {
+            Err(violation(format!("filesystem access to {} is not granted", path.display())))
+        }
+    }
+
+    /// Fail unless scripts may connect to `host:port`
+    pub fn check_connect(&self, host: &str, port: u16) -> Result<()> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let granted = self.options.allow_network.iter().any(|grant| {
+            let (pattern, grant_port) = match grant.rsplit_once(':').map(|(h, p)| (h, p.parse::<u16>())) {
+                Some((pattern, Ok(p))) => (pattern, Some(p)),
+                _ => (grant.as_str(), None),
+            };
+            let pattern = pattern.to_ascii_lowercase();
+            let host_matches = match pattern.strip_prefix("*.") {
+                Some(domain) => host.ends_with(&format!(".{}", domain)),
+                None => host == pattern,
+            };
+            host_matches && grant_port.is_none_or(|p| p == port)
+        });
+        if granted {
+            Ok(())
+        } else {
+            Err(violation(format!("network access to {}:{} is not granted", host, port)))
+        }
+    }
+
+    /// Start the budget of one script invocation
+    pub fn invocation(&self) -> Invocation {
+        Invocation {
+            deadline: Instant::now() + Duration::from_millis(self.options.max_duration_ms),
+            max_operations: self.options.max_operations,
+            max_memory: self.options.max_memory_bytes,
+            operations: 0,
+            memory: 0,
+        }
+    }
+}
+
+/// Resource usage of one script invocation
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    deadline: Instant,
+    max_operations: u64,
+    max_memory: usize,
+    operations: u64,
+    memory: usize,
+}
+
+impl Invocation {
+    /// Charge `operations` interpreter operations, failing once the
+    /// operation or time budget is used up
+    pub fn tick(&mut self, operations: u64) -> Result<()> {
+        self.operations = self.operations.saturating_add(operations);
+        if self.operations > self.max_operations {
+            return Err(violation(format!("exceeded {} operations", self.max_operations)));
+        }
+        if Instant::now() > self.deadline {
+            return Err(violation("exceeded its time limit"));
+        }
+        Ok(())
+    }
+
+    /// Charge an allocation of `bytes`, failing once the memory budget is
+    /// used up
+    pub fn allocate(&mut self, bytes: usize) -> Result<()> {
+        self.memory = self.memory.saturating_add(bytes);
+        if self.memory > self.max_memory {
+            return Err(violation(format!("exceeded {} bytes of memory", self.max_memory)));
+        }
+        Ok(())
+    }
+
+    pub fn release(&mut self, bytes: usize) {
+        self.memory = self.memory.saturating_sub(bytes);
+    }
+
+    pub fn operations(&self) -> u64 {
+        self.operations
+    }
+
+    /// Time left before the invocation is stopped
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+}
+
+fn violation(msg: impl std::fmt::Display) -> Error {
+    Error::Proxy(format!("Script {}", msg))
+}
+
+/// Canonicalize a path whose last components may not exist yet, so that
+/// `..` and symlinks cannot lead out of a granted directory
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let mut existing = path.to_path_buf();
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        // `file_name` is `None` for `..`, which is left to canonicalize
+        let Some(name) = existing.file_name() else {
+            break;
+        };
+        missing.push(name.to_os_string());
+        if !existing.pop() {
+            break;
+        }
+    }
+    if existing.as_os_str().is_empty() {
+        existing = PathBuf::from(".");
+    }
+    let mut resolved = existing.canonicalize()?;
+    for name in missing.into_iter().rev() {
+        resolved.push(name);
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_denied_by_default() {
+        let sandbox = Sandbox::new(&SandboxOptions::default()).unwrap();
+        assert!(sandbox.check_path(Path::new("/etc/passwd")).is_err());
+        assert!(sandbox.check_connect("collector.example", 443).is_err());
+    }
+
+    #[test]
+    fn test_capability_grants() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = SandboxOptions {
+            allow_filesystem: vec![dir.path().display().to_string()],
+            allow_network: vec!["*.internal.example".to_string(), "hooks.example:8443".to_string()],
+            ..Default::default()
+        };
+        let sandbox = Sandbox::new(&options).unwrap();
+
+        assert!(sandbox.check_path(&dir.path().join("out/report.json")).is_ok());
+        assert!(sandbox.check_path(&dir.path().join("../escape")).is_err());
+        assert!(sandbox.check_path(&dir.path().join("new/../../escape")).is_err());
+
+        assert!(sandbox.check_connect("api.internal.example", 80).is_ok());
+        assert!(sandbox.check_connect("internal.example", 80).is_err());
+        assert!(sandbox.check_connect("HOOKS.example", 8443).is_ok());
+        assert!(sandbox.check_connect("hooks.example", 443).is_err());
+    }
+
+    #[test]
+    fn test_invocation_budget() {
+        let options = SandboxOptions { max_operations: 100, max_memory_bytes: 1024, ..Default::default() };
+        let sandbox = Sandbox::new(&options).unwrap();
+        let mut invocation = sandbox.invocation();
+        assert!(invocation.tick(100).is_ok());
+        assert!(invocation.tick(1).is_err());
+
+        assert!(invocation.allocate(1000).is_ok());
+        invocation.release(500);
+        assert!(invocation.allocate(524).is_ok());
+        assert!(invocation.allocate(1).is_err());
+
+        let sandbox = Sandbox::new(&SandboxOptions { max_duration_ms: 0, ..Default::default() }).unwrap();
+        let mut invocation = sandbox.invocation();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(invocation.tick(1).unwrap_err().to_string().contains("time limit"));
+    }
+}
@@ -0,0 +1,453 @@
+//! Streaming of completed flows to disk.
+//!
+//! Flows are appended as JSON lines to segment files derived from the
+//! configured save-stream path (`flows.jsonl` becomes `flows.00001.jsonl`,
+//! `flows.00002.jsonl`, ...). A new segment is started once the current one
+//! exceeds the configured size or age. An index file next to the segments
+//! (`flows.index.json`) lists all segments with their time range, flow count
+//! and size; it is rewritten when a segment is started or closed. The proxy
+//! writes through a [`SaveStreamWriter`], so that storing a flow never waits
+//! for the disk.
+//!
+//! On startup, the most recent flows of a previous session can be loaded
+//! back from the segments (see [`WarmStartOptions`]), so that history is
+//! visible before new traffic arrives.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use tokio::sync::oneshot;
+
+use tracing::warn;
+
+use crate::flow::HTTPFlow;
+use crate::Result;
+
+/// Loading of a previous session's flows on startup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WarmStartOptions {
+    pub enabled: bool,
+    /// Most recent flows to load at most
+    pub max_flows: usize,
+    /// Skip flows older than this many seconds
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for WarmStartOptions {
+    fn default() -> Self {
+        Self { enabled: false, max_flows: 1000, max_age_secs: None }
+    }
+}
+
+/// A single save-stream segment file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    pub index: u32,
+    pub path: String,
+    pub started: f64,
+    pub ended: Option<f64>,
+    pub flows: usize,
+    pub bytes: u64,
+}
+
+/// Writer for the save-stream file with size and time based rotation
+#[derive(Debug)]
+pub struct SaveStream {
+    base: PathBuf,
+    max_size: Option<u64>,
+    max_age: Option<f64>,
+    segments: Vec<Segment>,
+    file: Option<File>,
+}
+
+impl SaveStream {
+    /// Open a save stream, continuing the numbering of an existing index
+    pub fn open<P: AsRef<Path>>(base: P, max_size: Option<u64>, max_age_secs: Option<u64>) -> Result<Self> {
+        let base = base.as_ref().to_path_buf();
+        if let Some(parent) = base.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let index_path = index_path(&base);
+        let mut segments: Vec<Segment> = if index_path.exists() {
+            serde_json::from_slice(&fs::read(&index_path)?)?
+        } else {
+            Vec::new()
+        };
+
+        // A previous run may have stopped without closing its last segment
+        let now = now();
+        for segment in segments.iter_mut().filter(|s| s.ended.is_none()) {
+            segment.ended = Some(now);
+        }
+
+        Ok(Self {
+            base,
+            max_size: max_size.filter(|size| *size > 0),
+            max_age: max_age_secs.filter(|secs| *secs > 0).map(|secs| secs as f64),
+            segments,
+            file: None,
+        })
+    }
+
+    /// All segments, oldest first
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Append a completed flow to the current segment. Incomplete flows are
+    /// skipped. A flow recorded again, such as after an edit, is appended
+    /// once more; readers keep its latest version.
+    pub fn record(&mut self, flow: &HTTPFlow) -> Result<bool> {
+        if flow.response.is_none() && flow.flow.error.is_none() {
+            return Ok(false);
+        }
+
+        let mut line = serde_json::to_vec(flow)?;
+        line.push(b'\n');
+
+        let now = now();
+        if self.needs_rotation(now) {
+            self.rotate(now)?;
+        }
+
+        let file = self.file.as_mut().expect("segment is open after rotation");
+        file.write_all(&line)?;
+        file.flush()?;
+
+        let segment = self.segments.last_mut().expect("segment is open after rotation");
+        segment.flows += 1;
+        segment.bytes += line.len() as u64;
+        Ok(true)
+    }
+
+    /// Close the current segment; the next recorded flow starts a new one
+    pub fn close(&mut self) -> Result<()> {
+        if self.file.take().is_some() {
+            if let Some(segment) = self.segments.last_mut() {
+                segment.ended = Some(now());
+            }
+            self.write_index()?;
+        }
+        Ok(())
+    }
+
+    fn needs_rotation(&self, now: f64) -> bool {
+        let Some(segment) = self.segments.last().filter(|_| self.file.is_some()) else {
+            return true;
+        };
+        self.max_size.is_some_and(|max| segment.bytes >= max)
+            || self.max_age.is_some_and(|max| now - segment.started >= max)
+    }
+
+    fn rotate(&mut self, now: f64) -> Result<()> {
+        self.close()?;
+
+        let index = self.segments.last().map_or(1, |s| s.index + 1);
+        let path = segment_path(&self.base, index);
+        self.file = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+        self.segments.push(Segment {
+            index,
+            path: path.to_string_lossy().into_owned(),
+            started: now,
+            ended: None,
+            flows: 0,
+            bytes: 0,
+        });
+        self.write_index()
+    }
+
+    fn write_index(&self) -> Result<()> {
+        // Write atomically so readers never observe a partial index
+        let index_path = index_path(&self.base);
+        let tmp_path = index_path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&self.segments)?)?;
+        fs::rename(&tmp_path, &index_path)?;
+        Ok(())
+    }
+}
+
+impl Drop for SaveStream {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+enum Message {
+    Record(Box<HTTPFlow>),
+    Segments(oneshot::Sender<Vec<Segment>>),
+}
+
+/// A save stream written on a thread of its own. Flows are recorded in the
+/// order they are handed over; the stream is closed when the writer is
+/// dropped.
+#[derive(Debug)]
+pub struct SaveStreamWriter {
+    sender: Option<mpsc::Sender<Message>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SaveStreamWriter {
+    pub fn spawn(mut stream: SaveStream) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let thread = std::thread::Builder::new().name("save-stream".to_string()).spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Record(flow) => {
+                        if let Err(e) = stream.record(&flow) {
+                            warn!("Failed to write flow to save stream: {}", e);
+                        }
+                    }
+                    Message::Segments(reply) => {
+                        let _ = reply.send(stream.segments().to_vec());
+                    }
+                }
+            }
+        })?;
+        Ok(Self { sender: Some(sender), thread: Some(thread) })
+    }
+
+    /// Queue a flow to be recorded, if it is completed
+    pub fn record(&self, flow: &HTTPFlow) {
+        if flow.response.is_none() && flow.flow.error.is_none() {
+            return;
+        }
+        self.send(Message::Record(Box::new(flow.clone())));
+    }
+
+    /// All segments, once the flows queued before were written
+    pub async fn segments(&self) -> Vec<Segment> {
+        let (reply, segments) = oneshot::channel();
+        self.send(Message::Segments(reply));
+        segments.await.unwrap_or_default()
+    }
+
+    fn send(&self, message: Message) {
+        if let Some(sender) = &self.sender {
+            if sender.send(message).is_err() {
+                warn!("Save stream writer stopped, flows are not saved");
+            }
+        }
+    }
+}
+
+impl Drop for SaveStreamWriter {
+    fn drop(&mut self) {
+        // Let the thread write what is queued and close the stream
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Read back the most recent flows recorded under a save-stream path,
+/// oldest first. A flow recorded more than once counts with its latest
+/// version. Lines that cannot be parsed, such as one cut short by a crash,
+/// are skipped.
+pub fn load_recent<P: AsRef<Path>>(base: P, options: &WarmStartOptions) -> Result<Vec<HTTPFlow>> {
+    let index_path = index_path(base.as_ref());
+    if !index_path.exists() {
+        return Ok(Vec::new());
+    }
+    let segments: Vec<Segment> = serde_json::from_slice(&fs::read(&index_path)?)?;
+    let oldest = options.max_age_secs.map(|secs| now() - secs as f64);
+
+    let mut seen = HashSet::new();
+    let mut flows = Vec::new();
+    'segments: for segment in segments.iter().rev() {
+        if oldest.is_some_and(|oldest| segment.ended.is_some_and(|ended| ended < oldest)) {
+            break;
+        }
+        let data = match fs::read(&segment.path) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Skipping save-stream segment {}: {}", segment.path, e);
+                continue;
+            }
+        };
+        let mut skipped = 0;
+        for line in String::from_utf8_lossy(&data).lines().rev().filter(|line| !line.trim().is_empty()) {
+            if flows.len() >= options.max_flows {
+                break 'segments;
+            }
+            let Ok(flow) = serde_json::from_str::<HTTPFlow>(line) else {
+                skipped += 1;
+                continue;
+            };
+            if oldest.is_some_and(|oldest| flow.flow.timestamp_created < oldest) || !seen.insert(flow.flow.id.clone()) {
+                continue;
+            }
+            flows.push(flow);
+        }
+        if skipped > 0 {
+            warn!("Skipped {} unreadable flows in {}", skipped, segment.path);
+        }
+    }
+    flows.reverse();
+    Ok(flows)
+}
+
+fn now() -> f64 {
+    crate::clock::now()
+}
+
+fn stem_and_extension(base: &Path) -> (String, String) {
+    let stem = base
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "flows".to_string());
+    let extension = base
+        .extension()
+        .map(|e| e.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "jsonl".to_string());
+    (stem, extension)
+}
+
+fn segment_path(base: &Path, index: u32) -> PathBuf {
+    let (stem, extension) = stem_and_extension(base);
+    base.with_file_name(format!("{}.{:05}.{}", stem, index, extension))
+}
+
+fn index_path(base: &Path) -> PathBuf {
+    let (stem, _) = stem_and_extension(base);
+    base.with_file_name(format!("{}.index.json", stem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn completed_flow() -> HTTPFlow {
+        let request = HTTPRequest::new(
+            "GET".to_string(),
+            "http".to_string(),
+            "example.com".to_string(),
+            80,
+            "/".to_string(),
+        );
+        HTTPFlow::new(request).with_response(HTTPResponse::new(200, "OK".to_string()))
+    }
+
+    #[test]
+    fn test_rotation_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("flows.jsonl");
+        let mut stream = SaveStream::open(&base, Some(1), None).unwrap();
+
+        let flow = completed_flow();
+        assert!(stream.record(&flow).unwrap());
+        assert!(stream.record(&completed_flow()).unwrap());
+
+        let segments = stream.segments().to_vec();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].flows, 1);
+        assert!(segments[0].ended.is_some());
+        assert!(segments[1].path.ends_with("flows.00002.jsonl"));
+
+        let flows = crate::io::read_flows_file(&segments[0].path).unwrap();
+        assert_eq!(flows[0].flow.id, flow.flow.id);
+    }
+
+    #[test]
+    fn test_updates_are_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("flows.jsonl");
+        let mut stream = SaveStream::open(&base, None, None).unwrap();
+        let mut flow = completed_flow();
+        assert!(stream.record(&flow).unwrap());
+        flow.flow.marked = ":star:".to_string();
+        assert!(stream.record(&flow).unwrap());
+        assert_eq!(stream.segments()[0].flows, 2);
+        drop(stream);
+
+        let loaded = load_recent(&base, &WarmStartOptions { enabled: true, ..Default::default() }).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].flow.marked, ":star:");
+    }
+
+    #[test]
+    fn test_incomplete_flows_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stream = SaveStream::open(dir.path().join("flows.jsonl"), None, None).unwrap();
+        let pending = HTTPFlow::new(completed_flow().request);
+        assert!(!stream.record(&pending).unwrap());
+        assert!(stream.segments().is_empty());
+    }
+
+    #[test]
+    fn test_index_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("capture.jsonl");
+        {
+            let mut stream = SaveStream::open(&base, None, Some(3600)).unwrap();
+            stream.record(&completed_flow()).unwrap();
+            stream.record(&completed_flow()).unwrap();
+        }
+        assert!(dir.path().join("capture.index.json").exists());
+
+        let mut stream = SaveStream::open(&base, None, Some(3600)).unwrap();
+        assert_eq!(stream.segments().len(), 1);
+        assert_eq!(stream.segments()[0].flows, 2);
+        stream.record(&completed_flow()).unwrap();
+        assert_eq!(stream.segments()[1].index, 2);
+    }
+
+    #[tokio::test]
+    async fn test_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("flows.jsonl");
+        let writer = SaveStreamWriter::spawn(SaveStream::open(&base, None, None).unwrap()).unwrap();
+        writer.record(&completed_flow());
+        writer.record(&HTTPFlow::new(completed_flow().request));
+        writer.record(&completed_flow());
+        let segments = writer.segments().await;
+        assert_eq!((segments.len(), segments[0].flows), (1, 2));
+        // The index lists the segment from its start, with its counts once closed
+        let index: Vec<Segment> = serde_json::from_slice(&fs::read(index_path(&base)).unwrap()).unwrap();
+        assert_eq!((index.len(), index[0].flows), (1, 0));
+
+        drop(writer);
+        let index: Vec<Segment> = serde_json::from_slice(&fs::read(index_path(&base)).unwrap()).unwrap();
+        assert_eq!(index[0].flows, 2);
+        assert!(index[0].ended.is_some());
+    }
+
+    #[test]
+    fn test_load_recent() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("flows.jsonl");
+        let mut stream = SaveStream::open(&base, Some(1), None).unwrap();
+        let mut old = completed_flow();
+        old.flow.timestamp_created -= 7200.0;
+        let flows = [old, completed_flow(), completed_flow(), completed_flow()];
+        for flow in &flows {
+            stream.record(flow).unwrap();
+        }
+        // An updated flow recorded again in a later segment
+        let mut marked = flows[1].clone();
+        marked.flow.marked = ":star:".to_string();
+        stream.record(&marked).unwrap();
+        drop(stream);
+        // A line cut short by a crash
+        let mut last = OpenOptions::new().append(true).open(segment_path(&base, 5)).unwrap();
+        last.write_all(b"{\"id\": \"trunc").unwrap();
+
+        let loaded = load_recent(&base, &WarmStartOptions { enabled: true, max_flows: 10, max_age_secs: None }).unwrap();
+        let ids: Vec<&str> = loaded.iter().map(|f| f.flow.id.as_str()).collect();
+        assert_eq!(ids, [&flows[0].flow.id, &flows[2].flow.id, &flows[3].flow.id, &flows[1].flow.id]);
+        assert_eq!(loaded[3].flow.marked, ":star:");
+
+        let options = WarmStartOptions { enabled: true, max_flows: 2, max_age_secs: Some(3600) };
+        assert_eq!(load_recent(&base, &options).unwrap().len(), 2);
+        let options = WarmStartOptions { enabled: true, max_flows: 10, max_age_secs: Some(3600) };
+        assert_eq!(load_recent(&base, &options).unwrap().len(), 3);
+        assert!(load_recent(dir.path().join("other.jsonl"), &options).unwrap().is_empty());
+    }
+}
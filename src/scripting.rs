@@ -0,0 +1,484 @@
+//! Rhai scripts rewriting flows.
+//!
+//! `--script rewrite.rhai` loads a script as an addon. A script defines
+//! `fn request()` and `fn response()`, or either, called with the flow as
+//! `this`:
+//!
+//! ```text
+//! fn request() {
+//!     if this.request.host == "api.example.com" {
+//!         this.request.set_header("Authorization", "Bearer test");
+//!     }
+//! }
+//!
+//! fn response() {
+//!     let text = this.response.text;
+//!     text.replace("prod", "test");
+//!     this.response.text = text;
+//! }
+//! ```
+//!
+//! A flow has `id`, `request`, `response` (`()` until there is one),
+//! `kill()` and `add_tag(tag)`. Requests have `method`, `path`, `scheme`,
+//! `host`, `port` and `url`, responses `status_code` and `reason`; both have
+//! `text`, `header(name)`, `set_header(name, value)` and
+//! `remove_header(name)`. `new_response(status, text)` creates a response,
+//! e.g. to answer a request without asking the server.
+//!
+//! The script file is checked for changes every second and recompiled; a
+//! version that fails to compile leaves the previous one running. Hooks run
+//! within the limits of the script [`Sandbox`] and cannot import modules or
+//! touch files; a hook that fails leaves the flow unchanged.
+//!
+//! Features that ask a script for a value, such as `auth_injection`, load
+//! it with [`ScriptAddon::load_functions`] and call the function they need
+//! with [`ScriptAddon::call_value`] instead.
+
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+use crate::addons::{Addon, AddonContext};
+use crate::flow::{HTTPFlow, HTTPRequest, HTTPResponse};
+use crate::sandbox::Sandbox;
+use crate::{Error, Result};
+
+/// How often script files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Functions called as hooks
+const HOOKS: [&str; 2] = ["request", "response"];
+
+thread_local! {
+    /// When the hook running on this thread is stopped
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// A script file, registered as an addon
+#[derive(Clone)]
+pub struct ScriptAddon {
+    name: String,
+    script: Arc<Script>,
+}
+
+struct Script {
+    path: PathBuf,
+    /// Functions of which the script must define at least one
+    functions: Vec<String>,
+    engine: Engine,
+    sandbox: Sandbox,
+    compiled: RwLock<Compiled>,
+}
+
+/// The running version of a script
+struct Compiled {
+    modified: Option<SystemTime>,
+    ast: AST,
+}
+
+impl std::fmt::Debug for ScriptAddon {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScriptAddon").field("name", &self.name).field("path", &self.script.path).finish()
+    }
+}
+
+impl ScriptAddon {
+    /// Compile the script at `path`, named after its file name
+    pub fn load<P: AsRef<Path>>(path: P, sandbox: &Sandbox) -> Result<Self> {
+        Self::load_functions(path, sandbox, &HOOKS)
+    }
+
+    /// Compile the script at `path`, which must define at least one of
+    /// `functions` rather than the hooks
+    pub fn load_functions<P: AsRef<Path>>(path: P, sandbox: &Sandbox, functions: &[&str]) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned();
+        let functions: Vec<String> = functions.iter().map(|f| f.to_string()).collect();
+        let engine = engine(&name, sandbox);
+        let compiled = compile(&engine, &name, &path, &functions)?;
+        let script = Script { path, functions, engine, sandbox: sandbox.clone(), compiled: RwLock::new(compiled) };
+        Ok(Self { name, script: Arc::new(script) })
+    }
+
+    /// Recompile the script if its file changed since it was compiled.
+    /// Returns whether it was.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = std::fs::metadata(&self.script.path)?.modified().ok();
+        if modified == self.script.compiled.read().unwrap().modified {
+            return Ok(false);
+        }
+        let compiled = compile(&self.script.engine, &self.name, &self.script.path, &self.script.functions);
+        let mut current = self.script.compiled.write().unwrap();
+        // Compile errors are reported once per change of the file
+        current.modified = modified;
+        current.ast = compiled?.ast;
+        Ok(true)
+    }
+
+    /// Run `hook` on `flow`. Hooks the script does not define are skipped;
+    /// a failing hook leaves `flow` unchanged.
+    pub fn call(&self, hook: &str, flow: &mut HTTPFlow) -> Result<()> {
+        let ast = self.script.compiled.read().unwrap().ast.clone();
+        if !ast.iter_functions().any(|f| f.name == hook && f.params.is_empty()) {
+            return Ok(());
+        }
+        let mut this = Dynamic::from(flow.clone());
+        // Hooks act on `this`; what they return is ignored
+        let _ = self.run(&ast, hook, &mut this)?;
+        *flow = this.try_cast().ok_or_else(|| script_error(&self.name, format!("{}: `this` is no longer a flow", hook)))?;
+        Ok(())
+    }
+
+    /// Call `function` with a copy of `flow` as `this` and return its
+    /// result as a string. Changes it makes to the flow are dropped.
+    pub fn call_value(&self, function: &str, flow: &HTTPFlow) -> Result<String> {
+        let ast = self.script.compiled.read().unwrap().ast.clone();
+        if !ast.iter_functions().any(|f| f.name == function && f.params.is_empty()) {
+            return Err(script_error(&self.name, format!("does not define {}()", function)));
+        }
+        let mut this = Dynamic::from(flow.clone());
+        Ok(self.run(&ast, function, &mut this)?.to_string())
+    }
+
+    /// Run `function` within the sandbox limits and return its result
+    fn run(&self, ast: &AST, function: &str, this: &mut Dynamic) -> Result<Dynamic> {
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(this);
+        let deadline = Instant::now() + Duration::from_millis(self.script.sandbox.options().max_duration_ms);
+        DEADLINE.with(|d| d.set(Some(deadline)));
+        let result = self.script.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), ast, function, ());
+        DEADLINE.with(|d| d.set(None));
+        result.map_err(|e| match *e {
+            // Only `on_progress` terminates scripts
+            EvalAltResult::ErrorTerminated(..) => script_error(&self.name, format!("{}: exceeded its time limit", function)),
+            e => script_error(&self.name, format!("{}: {}", function, e)),
+        })
+    }
+
+    fn run_hook(&self, hook: &str, flow: &mut HTTPFlow) {
+        if let Err(e) = self.call(hook, flow) {
+            warn!("{}", e);
+        }
+    }
+}
+
+impl Addon for ScriptAddon {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn load(&self, ctx: &AddonContext) {
+        let script = self.clone();
+        ctx.every(RELOAD_INTERVAL, Duration::ZERO, move || {
+            let script = script.clone();
+            async move {
+                match script.reload_if_changed() {
+                    Ok(true) => info!("Reloaded script {}", script.name),
+                    Ok(false) => {}
+                    Err(e) => warn!("Keeping the previous version of script {}: {}", script.name, e),
+                }
+            }
+        });
+    }
+
+    fn request(&self, flow: &mut HTTPFlow) {
+        self.run_hook("request", flow);
+    }
+
+    fn response(&self, flow: &mut HTTPFlow) {
+        self.run_hook("response", flow);
+    }
+}
+
+fn compile(engine: &Engine, name: &str, path: &Path, functions: &[String]) -> Result<Compiled> {
+    let modified = std::fs::metadata(path)?.modified().ok();
+    let source = std::fs::read_to_string(path)?;
+    let ast = engine.compile(&source).map_err(|e| script_error(name, e))?;
+    if !ast.iter_functions().any(|f| functions.iter().any(|function| function == f.name) && f.params.is_empty()) {
+        let expected: Vec<String> = functions.iter().map(|function| format!("{}()", function)).collect();
+        return Err(script_error(name, format!("defines none of {}", expected.join(", "))));
+    }
+    Ok(Compiled { modified, ast })
+}
+
+/// An engine confined to the sandbox limits, with the flow API registered
+fn engine(name: &str, sandbox: &Sandbox) -> Engine {
+    let options = sandbox.options();
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.set_max_operations(options.max_operations);
+    engine.set_max_string_size(options.max_memory_bytes);
+    let max_items = options.max_memory_bytes / std::mem::size_of::<Dynamic>();
+    engine.set_max_array_size(max_items);
+    engine.set_max_map_size(max_items);
+    engine.on_progress(|operations| {
+        let expired = operations % 256 == 0 && DEADLINE.with(|d| d.get()).is_some_and(|d| Instant::now() > d);
+        expired.then_some(Dynamic::UNIT)
+    });
+    let (print_name, debug_name) = (name.to_string(), name.to_string());
+    engine.on_print(move |text| info!("[{}] {}", print_name, text));
+    engine.on_debug(move |text, _, _| info!("[{}] {}", debug_name, text));
+
+    engine
+        .register_type_with_name::<HTTPFlow>("Flow")
+        .register_get("id", |flow: &mut HTTPFlow| flow.flow.id.clone())
+        .register_get_set(
+            "request",
+            |flow: &mut HTTPFlow| flow.request.clone(),
+            |flow: &mut HTTPFlow, request: HTTPRequest| flow.request = request,
+        )
+        .register_get("response", |flow: &mut HTTPFlow| flow.response.clone().map_or(Dynamic::UNIT, Dynamic::from))
+        .register_set("response", |flow: &mut HTTPFlow, response: HTTPResponse| flow.response = Some(response))
+        .register_fn("kill", |flow: &mut HTTPFlow| flow.flow.kill())
+        .register_fn("add_tag", |flow: &mut HTTPFlow, tag: &str| {
+            flow.flow.add_tag(tag);
+        });
+
+    engine
+        .register_type_with_name::<HTTPRequest>("Request")
+        .register_get_set(
+            "method",
+            |request: &mut HTTPRequest| request.method.clone(),
+            |request: &mut HTTPRequest, method: String| request.method = method,
+        )
+        .register_get_set(
+            "path",
+            |request: &mut HTTPRequest| request.path.clone(),
+            |request: &mut HTTPRequest, path: String| request.path = path,
+        )
+        .register_get("scheme", |request: &mut HTTPRequest| request.scheme.clone())
+        .register_get("host", |request: &mut HTTPRequest| request.host.clone())
+        .register_get("port", |request: &mut HTTPRequest| request.port as i64)
+        .register_get("url", |request: &mut HTTPRequest| request.url());
+    register_message::<HTTPRequest>(&mut engine);
+
+    engine
+        .register_type_with_name::<HTTPResponse>("Response")
+        .register_get_set(
+            "status_code",
+            |response: &mut HTTPResponse| response.status_code as i64,
+            |response: &mut HTTPResponse, status: i64| -> std::result::Result<(), Box<EvalAltResult>> {
+                response.status_code = status_code(status)?;
+                Ok(())
+            },
+        )
+        .register_get_set(
+            "reason",
+            |response: &mut HTTPResponse| response.reason.clone(),
+            |response: &mut HTTPResponse, reason: String| response.reason = reason,
+        )
+        .register_fn("new_response", |status: i64, text: &str| -> std::result::Result<HTTPResponse, Box<EvalAltResult>> {
+            let status = status_code(status)?;
+            let reason = http::StatusCode::from_u16(status).ok().and_then(|s| s.canonical_reason()).unwrap_or("");
+            let mut response = HTTPResponse::new(status, reason.to_string());
+            response.timestamp_start = Some(crate::clock::now());
+            set_text(&mut response, text);
+            Ok(response)
+        });
+    register_message::<HTTPResponse>(&mut engine);
+
+    engine
+}
+
+/// What requests and responses share in scripts
+trait Message: Clone + Send + Sync + 'static {
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)>;
+    fn content(&self) -> Option<&[u8]>;
+    fn set_content(&mut self, content: Vec<u8>);
+}
+
+impl Message for HTTPRequest {
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)> {
+        &mut self.headers
+    }
+
+    fn content(&self) -> Option<&[u8]> {
+        self.content.as_deref()
+    }
+
+    fn set_content(&mut self, content: Vec<u8>) {
+        HTTPRequest::set_content(self, content);
+    }
+}
+
+impl Message for HTTPResponse {
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)> {
+        &mut self.headers
+    }
+
+    fn content(&self) -> Option<&[u8]> {
+        self.content.as_deref()
+    }
+
+    fn set_content(&mut self, content: Vec<u8>) {
+        HTTPResponse::set_content(self, content);
+    }
+}
+
+fn register_message<T: Message>(engine: &mut Engine) {
+    engine
+        .register_fn("header", |message: &mut T, name: &str| {
+            let value = message.headers_mut().iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone());
+            value.map_or(Dynamic::UNIT, Dynamic::from)
+        })
+        .register_fn("set_header", |message: &mut T, name: &str, value: &str| {
+            let headers = message.headers_mut();
+            headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+            headers.push((name.to_string(), value.to_string()));
+        })
+        .register_fn("remove_header", |message: &mut T, name: &str| {
+            message.headers_mut().retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        })
+        .register_get_set(
+            "text",
+            |message: &mut T| String::from_utf8_lossy(message.content().unwrap_or_default()).into_owned(),
+            |message: &mut T, text: String| set_text(message, &text),
+        );
+}
+
+/// Replace the body, keeping a Content-Length header in step
+fn set_text<T: Message>(message: &mut T, text: &str) {
+    message.set_content(text.as_bytes().to_vec());
+    let headers = message.headers_mut();
+    let length = headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case("content-length"));
+    if let Some((_, value)) = length {
+        *value = text.len().to_string();
+    } else if !headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("transfer-encoding")) {
+        headers.push(("Content-Length".to_string(), text.len().to_string()));
+    }
+}
+
+fn status_code(status: i64) -> std::result::Result<u16, Box<EvalAltResult>> {
+    u16::try_from(status).ok().filter(|status| (100..1000).contains(status)).ok_or_else(|| format!("Invalid status code {}", status).into())
+}
+
+fn script_error(name: &str, msg: impl std::fmt::Display) -> Error {
+    Error::Proxy(format!("Script {}: {}", name, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::SandboxOptions;
+
+    fn flow() -> HTTPFlow {
+        let mut request = HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "api.example.com".to_string(),
+            443,
+            "/v1/items".to_string(),
+        );
+        request.headers = vec![("Host".to_string(), "api.example.com".to_string())];
+        HTTPFlow::new(request)
+    }
+
+    #[test]
+    fn test_script_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rewrite.rhai");
+        std::fs::write(
+            &path,
+            r#"
+            fn request() {
+                if this.request.host == "api.example.com" {
+                    this.request.set_header("Authorization", "Bearer test");
+                    this.request.remove_header("host");
+                    this.add_tag("scripted");
+                }
+                if this.request.path == "/blocked" {
+                    this.response = new_response(403, "blocked");
+                }
+            }
+
+            fn response() {
+                let text = this.response.text;
+                text.replace("prod", "test");
+                this.response.text = text;
+                this.response.status_code = 202;
+            }
+            "#,
+        )
+        .unwrap();
+        let script = ScriptAddon::load(&path, &Sandbox::default()).unwrap();
+        assert_eq!(script.name(), "rewrite.rhai");
+
+        let mut flow = flow();
+        script.request(&mut flow);
+        assert_eq!(flow.request.get_header("authorization").unwrap(), "Bearer test");
+        assert!(flow.request.get_header("host").is_none());
+        assert_eq!(flow.flow.tags(), ["scripted"]);
+        assert!(flow.response.is_none());
+
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.headers = vec![("Content-Length".to_string(), "7".to_string())];
+        response.set_content(b"on prod".to_vec());
+        flow.response = Some(response);
+        script.response(&mut flow);
+        let response = flow.response.as_ref().unwrap();
+        assert_eq!(response.content.as_deref(), Some(&b"on test"[..]));
+        assert_eq!(response.status_code, 202);
+        assert_eq!(response.get_header("content-length").unwrap(), "7");
+
+        let mut blocked = self::flow();
+        blocked.request.path = "/blocked".to_string();
+        script.request(&mut blocked);
+        let response = blocked.response.unwrap();
+        assert_eq!((response.status_code, response.reason.as_str()), (403, "Forbidden"));
+        assert_eq!(response.get_header("content-length").unwrap(), "7");
+    }
+
+    #[test]
+    fn test_script_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tag.rhai");
+        std::fs::write(&path, r#"fn request() { this.add_tag("v1"); }"#).unwrap();
+        let script = ScriptAddon::load(&path, &Sandbox::default()).unwrap();
+        assert!(!script.reload_if_changed().unwrap());
+
+        // Moves the modification time on, as a slow editor would
+        let rewrite = |source: &str, secs: u64| {
+            std::fs::write(&path, source).unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::now() + Duration::from_secs(secs)).unwrap();
+        };
+        rewrite(r#"fn request() { this.add_tag("v2"); }"#, 1);
+        assert!(script.reload_if_changed().unwrap());
+        let mut flow = flow();
+        script.request(&mut flow);
+        assert_eq!(flow.flow.tags(), ["v2"]);
+
+        // A broken version keeps the previous one running
+        rewrite("fn request( {", 2);
+        assert!(script.reload_if_changed().is_err());
+        let mut flow = self::flow();
+        script.request(&mut flow);
+        assert_eq!(flow.flow.tags(), ["v2"]);
+    }
+
+    #[test]
+    fn test_script_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spin.rhai");
+        std::fs::write(&path, r#"fn request() { this.add_tag("started"); loop { } }"#).unwrap();
+        let sandbox = Sandbox::new(&SandboxOptions { max_operations: 10_000, ..Default::default() }).unwrap();
+        let script = ScriptAddon::load(&path, &sandbox).unwrap();
+        let mut flow = flow();
+        assert!(script.call("request", &mut flow).is_err());
+        assert!(flow.flow.tags().is_empty());
+
+        let sandbox = Sandbox::new(&SandboxOptions { max_duration_ms: 0, ..Default::default() }).unwrap();
+        let script = ScriptAddon::load(&path, &sandbox).unwrap();
+        assert!(script.call("request", &mut flow).unwrap_err().to_string().contains("time limit"));
+
+        // Modules cannot be imported, not even from the script's directory
+        std::fs::write(dir.path().join("secrets.rhai"), "export const KEY = 1;").unwrap();
+        std::fs::write(&path, r#"fn request() { import "secrets" as s; }"#).unwrap();
+        let script = ScriptAddon::load(&path, &Sandbox::default()).unwrap();
+        assert!(script.call("request", &mut flow).is_err());
+        assert!(ScriptAddon::load(dir.path().join("missing.rhai"), &Sandbox::default()).is_err());
+    }
+}
@@ -1,9 +1,12 @@
 use std::sync::Arc;
 use tokio::signal;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::api;
+use crate::banner::Banner;
+use crate::certs::CertificateAuthority;
 use crate::config::Config;
+use crate::eventlog::LogLevel;
 use crate::proxy::ProxyServer;
 use crate::Result;
 
@@ -14,26 +17,116 @@ pub struct MitmproxyServer {
 
 impl MitmproxyServer {
     pub async fn new(config: Config) -> Result<Self> {
-        let proxy = Arc::new(ProxyServer::new(Arc::new(config.clone())));
+        if let Some(target) = config.reverse_target()? {
+            info!("Reverse proxy mode, forwarding to {}://{}", target.scheme, target.authority());
+        }
+        let mut proxy = ProxyServer::new(Arc::new(config.clone()));
+        let ca = match &config.ca_file {
+            Some(ca_file) => CertificateAuthority::import(
+                config.cert_store_path(),
+                config.expand_path(ca_file),
+                config.ca_passphrase.as_deref(),
+                &config.cert_validity,
+            ),
+            None => CertificateAuthority::with_validity(config.cert_store_path(), &config.cert_validity),
+        };
+        match ca {
+            Ok(ca) => {
+                let ca = ca.with_minting(&config.cert_minting).with_disk_cache(&config.cert_disk_cache);
+                proxy = proxy.with_ca(Arc::new(ca));
+            }
+            Err(e) => warn!("Cannot set up the certificate authority: {}", e),
+        }
+        let proxy = Arc::new(proxy);
+        for path in &config.wasm_plugins {
+            #[cfg(feature = "wasm")]
+            {
+                let plugin = crate::wasm::WasmPlugin::load(config.expand_path(path), proxy.script_sandbox());
+                match plugin {
+                    Ok(plugin) => proxy.add_addon(Arc::new(plugin)).await?,
+                    Err(e) => warn!("Cannot load WASM plugin {}: {}", path, e),
+                }
+            }
+            #[cfg(not(feature = "wasm"))]
+            warn!("Ignoring WASM plugin {}: built without the wasm feature", path);
+        }
+        for path in &config.scripts {
+            #[cfg(feature = "scripting")]
+            {
+                let script = crate::scripting::ScriptAddon::load(config.expand_path(path), proxy.script_sandbox());
+                match script {
+                    Ok(script) => proxy.add_addon(Arc::new(script)).await?,
+                    Err(e) => warn!("Cannot load script {}: {}", path, e),
+                }
+            }
+            #[cfg(not(feature = "scripting"))]
+            warn!("Ignoring script {}: built without the scripting feature", path);
+        }
+        if let Some(warning) = proxy.ca_status().and_then(|status| status.warning) {
+            warn!("{}", warning);
+            proxy.log_event(LogLevel::Warn, warning);
+        }
+        match proxy.warm_start().await {
+            Ok(0) => {}
+            Ok(count) => info!("Restored {} flows from the previous session", count),
+            Err(e) => warn!("Cannot restore flows from the previous session: {}", e),
+        }
 
         Ok(Self { config, proxy })
     }
 
+    /// Create a read-only viewer over the flows of a dump file. The viewer
+    /// only serves the web API and does not listen for proxy traffic.
+    pub async fn view<P: AsRef<std::path::Path>>(mut config: Config, path: P) -> Result<Self> {
+        let flows = crate::io::read_flows_file(&path)?;
+
+        // Loading a capture must never append it to another recording or
+        // store it among the flows of another session
+        config.save_stream_file = None;
+        config.flow_store.backend = crate::store::FlowStoreBackend::Memory;
+
+        let proxy = ProxyServer::new(Arc::new(config.clone())).with_read_only(true);
+        for flow in flows {
+            proxy.add_flow(flow).await;
+        }
+        info!("Loaded {} flows from {}", proxy.get_flows().await.len(), path.as_ref().display());
+
+        Ok(Self { config, proxy: Arc::new(proxy) })
+    }
+
     pub async fn run(self) -> Result<()> {
         info!("Starting mitmproxy-rs server");
-        info!("Proxy listening on: {}", self.config.proxy_addr());
-        info!("Web API listening on: {}", self.config.web_addr());
+        if self.proxy.is_read_only() {
+            info!("Read-only viewer, not accepting proxy connections");
+            info!("Web API listening on: {}", self.config.web_addr());
+        } else {
+            let addons = self.proxy.get_addons().await.into_iter().map(|addon| addon.name).collect();
+            let banner = Banner::new(&self.config, self.proxy.ca().map(|ca| ca.as_ref()), addons);
+            for line in banner.to_string().lines() {
+                info!("{}", line);
+            }
+        }
 
         // Start proxy server
         let proxy_handle = {
             let proxy = Arc::clone(&self.proxy);
             tokio::spawn(async move {
+                if proxy.is_read_only() {
+                    return std::future::pending().await;
+                }
                 if let Err(e) = proxy.run().await {
                     error!("Proxy server error: {}", e);
                 }
             })
         };
 
+        let janitor_handle = self.proxy.spawn_janitor();
+        let sse_monitor_handle = self.proxy.spawn_sse_monitor();
+        let http3_handle = self.proxy.spawn_http3();
+        let dns_handle = self.proxy.spawn_dns();
+        let agent_handle = self.proxy.spawn_agent_listener();
+        let health_check_handle = self.proxy.spawn_upstream_health_checks();
+
         // Start web API server
         let web_handle = {
             let proxy = Arc::clone(&self.proxy);
@@ -71,6 +164,26 @@ impl MitmproxyServer {
             }
         }
 
+        if let Some(janitor) = janitor_handle {
+            janitor.abort();
+        }
+        if let Some(sse_monitor) = sse_monitor_handle {
+            sse_monitor.abort();
+        }
+        if let Some(http3) = http3_handle {
+            http3.abort();
+        }
+        if let Some(dns) = dns_handle {
+            dns.abort();
+        }
+        if let Some(agents) = agent_handle {
+            agents.abort();
+        }
+        if let Some(health_checks) = health_check_handle {
+            health_checks.abort();
+        }
+        self.proxy.shutdown_addons().await;
+
         Ok(())
     }
 }
@@ -85,4 +198,67 @@ mod tests {
         let server = MitmproxyServer::new(config).await;
         assert!(server.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_viewer_loads_dump() {
+        let flow = crate::flow::HTTPFlow::new(crate::flow::HTTPRequest::new(
+            "GET".to_string(),
+            "http".to_string(),
+            "example.com".to_string(),
+            80,
+            "/".to_string(),
+        ));
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), crate::io::write_flows(&[flow]).unwrap()).unwrap();
+
+        let server = MitmproxyServer::view(Config::default(), file.path()).await.unwrap();
+        assert!(server.proxy.is_read_only());
+        assert_eq!(server.proxy.get_flows().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_viewer_keeps_flows_in_memory() {
+        let flow = crate::flow::HTTPFlow::new(crate::flow::HTTPRequest::new(
+            "GET".to_string(),
+            "http".to_string(),
+            "example.com".to_string(),
+            80,
+            "/".to_string(),
+        ));
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), crate::io::write_flows(&[flow]).unwrap()).unwrap();
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store = temp_dir.path().join("flows.sqlite");
+
+        let config = Config {
+            flow_store: crate::store::FlowStoreOptions {
+                backend: crate::store::FlowStoreBackend::Sqlite,
+                path: store.to_string_lossy().into_owned(),
+            },
+            ..Default::default()
+        };
+        let server = MitmproxyServer::view(config, file.path()).await.unwrap();
+        assert_eq!(server.config.flow_store.backend, crate::store::FlowStoreBackend::Memory);
+        assert_eq!(server.proxy.get_flows().await.len(), 1);
+        assert!(!store.exists());
+    }
+
+    #[tokio::test]
+    async fn test_viewer_keeps_large_bodies() {
+        let request = crate::flow::HTTPRequest::new("GET".to_string(), "http".to_string(), "example.com".to_string(), 80, "/".to_string());
+        let mut response = crate::flow::HTTPResponse::new(200, "OK".to_string());
+        response.set_content(vec![b'x'; 100]);
+        let flow = crate::flow::HTTPFlow::new(request).with_response(response);
+        let id = flow.flow.id.clone();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), crate::io::write_flows(&[flow]).unwrap()).unwrap();
+
+        let config = Config {
+            lazy_body: crate::lazybody::LazyBodyOptions { enabled: true, threshold_bytes: 50, preview_bytes: 10, filter: String::new() },
+            ..Default::default()
+        };
+        let server = MitmproxyServer::view(config, file.path()).await.unwrap();
+        let flow = server.proxy.get_flow(&id).await.unwrap();
+        assert_eq!(flow.response.unwrap().content.unwrap().len(), 100);
+    }
 }
\ No newline at end of file
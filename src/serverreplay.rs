@@ -0,0 +1,243 @@
+//! Answering requests from recorded flows (server-side replay).
+//!
+//! With `server_replay.files` set, the flows in those dumps are loaded on
+//! startup and requests matching a recorded request are answered with its
+//! response instead of being sent upstream, which makes test environments
+//! independent of the servers they were recorded against. Requests match
+//! when the parts named in `match_on` are equal: the method, the scheme,
+//! host and port, the path, the query parameters in any order, and the
+//! body. Query parameters in `ignore_params`, such as cache busters, are
+//! left out.
+//!
+//! Recorded responses to the same request are answered in recorded order,
+//! each once; with `reuse` the first one answers every request instead.
+//! Requests without a recorded response are forwarded, or answered with
+//! `unmatched_status` if it is set. Answered flows note the recorded flow
+//! under [`METADATA_KEY`].
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::flow::{content_hash, HTTPFlow, HTTPRequest, HTTPResponse};
+use crate::Result;
+
+/// Flow metadata key of the recorded flow a response was replayed from
+pub const METADATA_KEY: &str = "server_replay";
+
+/// Part of a request compared with recorded requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKey {
+    Method,
+    /// Scheme, host and port
+    Host,
+    Path,
+    Query,
+    Body,
+}
+
+/// Server-side replay of recorded flows
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerReplayOptions {
+    /// Flow dumps whose responses answer matching requests
+    pub files: Vec<String>,
+    /// Parts of a request that must equal those of a recorded request
+    pub match_on: Vec<MatchKey>,
+    /// Query parameters left out when matching
+    pub ignore_params: Vec<String>,
+    /// Answer every matching request with the first recorded response
+    /// instead of using each response once
+    pub reuse: bool,
+    /// Answer requests without a recorded response with this status
+    /// instead of forwarding them
+    pub unmatched_status: Option<u16>,
+}
+
+impl Default for ServerReplayOptions {
+    fn default() -> Self {
+        Self {
+            files: Vec::new(),
+            match_on: vec![MatchKey::Method, MatchKey::Host, MatchKey::Path, MatchKey::Query, MatchKey::Body],
+            ignore_params: Vec::new(),
+            reuse: false,
+            unmatched_status: None,
+        }
+    }
+}
+
+/// How many recorded responses are loaded and left
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerReplayStatus {
+    pub loaded: usize,
+    pub remaining: usize,
+    pub answered: usize,
+}
+
+/// Ids of the recorded flows answering a request and their responses,
+/// oldest first
+type Recorded = VecDeque<(String, HTTPResponse)>;
+
+/// Recorded responses by request
+#[derive(Debug, Default)]
+pub struct ServerReplay {
+    options: ServerReplayOptions,
+    loaded: usize,
+    responses: Mutex<HashMap<Vec<String>, Recorded>>,
+    answered: Mutex<usize>,
+}
+
+impl ServerReplay {
+    /// Load the flows of `options.files`; `expand` resolves their paths
+    pub fn load(options: &ServerReplayOptions, expand: impl Fn(&str) -> String) -> Result<Self> {
+        let mut flows = Vec::new();
+        for file in &options.files {
+            flows.extend(crate::io::read_flows_file(expand(file))?);
+        }
+        Ok(Self::from_flows(options, flows))
+    }
+
+    /// Replay the responses of `flows`, oldest first
+    pub fn from_flows(options: &ServerReplayOptions, mut flows: Vec<HTTPFlow>) -> Self {
+        flows.sort_by(|a, b| a.flow.timestamp_created.total_cmp(&b.flow.timestamp_created));
+        let mut replay = Self { options: options.clone(), ..Self::default() };
+        let responses = replay.responses.get_mut().unwrap();
+        for flow in flows {
+            let Some(response) = flow.response else {
+                continue;
+            };
+            let key = key(&replay.options, &flow.request);
+            responses.entry(key).or_default().push_back((flow.flow.id, response));
+            replay.loaded += 1;
+        }
+        replay
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.loaded == 0 && self.options.unmatched_status.is_none()
+    }
+
+    /// Whether requests are matched on their body, so only complete
+    /// requests can be answered
+    pub fn needs_body(&self) -> bool {
+        self.options.match_on.contains(&MatchKey::Body)
+    }
+
+    pub fn status(&self) -> ServerReplayStatus {
+        ServerReplayStatus {
+            loaded: self.loaded,
+            remaining: self.responses.lock().unwrap().values().map(VecDeque::len).sum(),
+            answered: *self.answered.lock().unwrap(),
+        }
+    }
+
+    /// Recorded response to the request of `flow`, noted in its metadata,
+    /// or the `unmatched_status` answer. None forwards the request.
+    pub fn respond(&self, flow: &mut HTTPFlow) -> Option<HTTPResponse> {
+        let key = key(&self.options, &flow.request);
+        let recorded = {
+            let mut responses = self.responses.lock().unwrap();
+            let queue = responses.get_mut(&key);
+            match queue {
+                Some(queue) if self.options.reuse => queue.front().cloned(),
+                Some(queue) => queue.pop_front(),
+                None => None,
+            }
+        };
+        let Some((id, mut response)) = recorded else {
+            return self.options.unmatched_status.map(crate::blocklist::response);
+        };
+        *self.answered.lock().unwrap() += 1;
+        let now = crate::clock::now();
+        response.timestamp_start = Some(now);
+        response.timestamp_end = Some(now);
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), json!({ "flow": id }));
+        Some(response)
+    }
+}
+
+/// The parts of `request` named in `match_on`
+fn key(options: &ServerReplayOptions, request: &HTTPRequest) -> Vec<String> {
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    options
+        .match_on
+        .iter()
+        .map(|part| match part {
+            MatchKey::Method => request.method.to_ascii_uppercase(),
+            MatchKey::Host => format!("{}://{}:{}", request.scheme, request.host.to_ascii_lowercase(), request.port),
+            MatchKey::Path => path.to_string(),
+            MatchKey::Query => {
+                let mut params: Vec<&str> = query
+                    .split('&')
+                    .filter(|param| !param.is_empty())
+                    .filter(|param| {
+                        let name = param.split('=').next().unwrap_or_default();
+                        !options.ignore_params.iter().any(|ignored| ignored == name)
+                    })
+                    .collect();
+                params.sort_unstable();
+                params.join("&")
+            }
+            MatchKey::Body => content_hash(request.content.as_deref().unwrap_or_default()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(method: &str, path: &str, body: &[u8], status: u16, created: f64) -> HTTPFlow {
+        let mut request = HTTPRequest::new(method.to_string(), "https".to_string(), "api.example".to_string(), 443, path.to_string());
+        request.set_content(body.to_vec());
+        let mut flow = HTTPFlow::new(request).with_response(HTTPResponse::new(status, "OK".to_string()));
+        flow.flow.timestamp_created = created;
+        flow
+    }
+
+    #[test]
+    fn test_server_replay() {
+        let options = ServerReplayOptions { ignore_params: vec!["_".to_string()], ..ServerReplayOptions::default() };
+        let recorded = vec![
+            flow("GET", "/items?b=2&a=1", b"", 201, 2.0),
+            flow("GET", "/items?a=1&b=2", b"", 200, 1.0),
+            flow("POST", "/items", b"{\"name\":\"x\"}", 202, 3.0),
+        ];
+        let ids: Vec<String> = recorded.iter().map(|flow| flow.flow.id.clone()).collect();
+        let replay = ServerReplay::from_flows(&options, recorded);
+        assert_eq!(replay.status().loaded, 3);
+
+        // Responses to the same request come in recorded order, each once
+        let mut request = flow("GET", "/items?_=123&b=2&a=1", b"", 0, 0.0);
+        assert_eq!(replay.respond(&mut request).unwrap().status_code, 200);
+        assert_eq!(request.flow.metadata[METADATA_KEY], json!({ "flow": ids[1] }));
+        assert_eq!(replay.respond(&mut flow("GET", "/items?a=1&b=2", b"", 0, 0.0)).unwrap().status_code, 201);
+        assert!(replay.respond(&mut flow("GET", "/items?a=1&b=2", b"", 0, 0.0)).is_none());
+
+        assert!(replay.respond(&mut flow("POST", "/items", b"{\"name\":\"y\"}", 0, 0.0)).is_none());
+        assert_eq!(replay.respond(&mut flow("POST", "/items", b"{\"name\":\"x\"}", 0, 0.0)).unwrap().status_code, 202);
+        let status = replay.status();
+        assert_eq!((status.remaining, status.answered), (0, 3));
+    }
+
+    #[test]
+    fn test_match_on_and_reuse() {
+        let options = ServerReplayOptions {
+            match_on: vec![MatchKey::Method, MatchKey::Path],
+            reuse: true,
+            unmatched_status: Some(404),
+            ..ServerReplayOptions::default()
+        };
+        let replay = ServerReplay::from_flows(&options, vec![flow("POST", "/login", b"user=a", 200, 1.0)]);
+        assert!(!replay.needs_body());
+        for body in [b"user=b", b"user=c"] {
+            assert_eq!(replay.respond(&mut flow("POST", "/login?next=/", body, 0, 0.0)).unwrap().status_code, 200);
+        }
+        let mut unmatched = flow("GET", "/login", b"", 0, 0.0);
+        assert_eq!(replay.respond(&mut unmatched).unwrap().status_code, 404);
+        assert!(!unmatched.flow.metadata.contains_key(METADATA_KEY));
+        assert_eq!(replay.status().remaining, 1);
+    }
+}
@@ -0,0 +1,291 @@
+//! Rules-based response shaping.
+//!
+//! A shaping rule selects responses by content type and an optional flow
+//! filter, and describes how they are delivered to the client: split into
+//! chunks with an artificial delay between them, throttled to a bandwidth,
+//! and optionally padded. This simulates CDN and edge behaviour such as slow
+//! image delivery or padded responses. The parameters applied to a flow are
+//! recorded in its metadata under `shaping`, so a captured session documents
+//! exactly how each response was shaped.
+
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::contentviews::content_type_matches;
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::{Error, Result};
+
+/// Flow metadata key under which the applied plan is recorded
+pub const METADATA_KEY: &str = "shaping";
+
+/// Chunk size used when a rule only sets a delay or bandwidth
+pub const DEFAULT_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Content types padded with spaces rather than zero bytes
+const TEXT_TYPES: &[&str] = &["text/", "json", "javascript", "xml", "css"];
+
+/// A shaping rule as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShapingRule {
+    pub name: String,
+    /// Content types the rule applies to, matched by substring; empty matches all
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Filter expression further restricting the flows; empty matches all
+    #[serde(default)]
+    pub filter: String,
+    /// Size of the chunks the body is delivered in
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    /// Pause between consecutive chunks
+    #[serde(default)]
+    pub chunk_delay_ms: u64,
+    /// Delivery rate limit in bytes per second
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    /// Number of bytes appended to the body
+    #[serde(default)]
+    pub pad_bytes: usize,
+    /// Minimum body size; shorter bodies are padded up to it
+    #[serde(default)]
+    pub pad_to: Option<usize>,
+}
+
+/// Shaping parameters applied to a single response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShapingPlan {
+    pub rule: String,
+    pub chunk_size: usize,
+    pub chunk_delay_ms: u64,
+    pub bandwidth: Option<u64>,
+    /// Body size before padding
+    pub original_size: usize,
+    /// Bytes of padding appended to the body
+    pub padding: usize,
+}
+
+impl ShapingPlan {
+    /// Read the plan recorded on a flow, if it was shaped
+    pub fn from_flow(flow: &HTTPFlow) -> Option<Self> {
+        let value = flow.flow.metadata.get(METADATA_KEY)?;
+        serde_json::from_value(value.clone()).ok()
+    }
+
+    /// Split a body of `len` bytes into chunks, each with the time to wait
+    /// before sending it
+    pub fn schedule(&self, len: usize) -> Vec<(Range<usize>, Duration)> {
+        let chunk_size = self.chunk_size.max(1);
+        (0..len)
+            .step_by(chunk_size)
+            .enumerate()
+            .map(|(i, start)| {
+                let range = start..(start + chunk_size).min(len);
+                let mut delay = if i > 0 { Duration::from_millis(self.chunk_delay_ms) } else { Duration::ZERO };
+                if let Some(bandwidth) = self.bandwidth.filter(|b| *b > 0) {
+                    delay += Duration::from_secs_f64(range.len() as f64 / bandwidth as f64);
+                }
+                (range, delay)
+            })
+            .collect()
+    }
+
+    /// Total artificial delay for delivering a body of `len` bytes
+    pub fn total_delay(&self, len: usize) -> Duration {
+        self.schedule(len).into_iter().map(|(_, delay)| delay).sum()
+    }
+}
+
+/// Write a response body according to a shaping plan
+pub async fn write_shaped<W: AsyncWrite + Unpin>(writer: &mut W, body: &[u8], plan: &ShapingPlan) -> std::io::Result<()> {
+    for (range, delay) in plan.schedule(body.len()) {
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        writer.write_all(&body[range]).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// The configured shaping rules, checked in order
+#[derive(Debug, Clone, Default)]
+pub struct Shaper {
+    rules: Vec<(ShapingRule, Filter)>,
+}
+
+impl Shaper {
+    /// Build the shaper from configured rules
+    pub fn from_rules(rules: &[ShapingRule]) -> Result<Self> {
+        let mut shaper = Self::default();
+        for rule in rules {
+            if rule.name.is_empty() {
+                return Err(Error::invalid_request("Shaping rule name must not be empty"));
+            }
+            if rule.chunk_size == Some(0) {
+                return Err(Error::invalid_request(format!("Shaping rule {}: chunk_size must be positive", rule.name)));
+            }
+            let filter = Filter::new(rule.name.clone(), rule.filter.clone())?;
+            shaper.rules.push((rule.clone(), filter));
+        }
+        Ok(shaper)
+    }
+
+    pub fn rules(&self) -> Vec<ShapingRule> {
+        self.rules.iter().map(|(rule, _)| rule.clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Apply the first matching rule to the flow's response: pad the body and
+    /// record the plan in the flow metadata. A flow that already carries a
+    /// plan, such as a replayed one, keeps it and is not padded again.
+    pub fn shape(&self, flow: &mut HTTPFlow) -> Option<ShapingPlan> {
+        if let Some(plan) = ShapingPlan::from_flow(flow) {
+            return Some(plan);
+        }
+
+        let response = flow.response.as_ref()?;
+        let content_type = response.get_header("content-type").cloned().unwrap_or_default();
+        let (rule, _) = self.rules.iter().find(|(rule, filter)| {
+            let patterns: Vec<&str> = rule.content_types.iter().map(String::as_str).collect();
+            (patterns.is_empty() || content_type_matches(&content_type, &patterns)) && filter.matches(flow)
+        })?;
+
+        let response = flow.response.as_mut()?;
+        let mut body = response.content.take().unwrap_or_default();
+        let original_size = body.len();
+        let padded_size = rule.pad_to.unwrap_or(0).max(original_size) + rule.pad_bytes;
+        let padding = padded_size - original_size;
+        if padding > 0 {
+            let fill = if content_type_matches(&content_type, TEXT_TYPES) { b' ' } else { 0 };
+            body.resize(padded_size, fill);
+            if response.get_header("content-length").is_some() {
+                response.set_header("Content-Length".to_string(), padded_size.to_string());
+            }
+        }
+        response.set_content(body);
+
+        let plan = ShapingPlan {
+            rule: rule.name.clone(),
+            chunk_size: rule.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+            chunk_delay_ms: rule.chunk_delay_ms,
+            bandwidth: rule.bandwidth,
+            original_size,
+            padding,
+        };
+        let value = serde_json::to_value(&plan).ok()?;
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), value);
+        Some(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+    use serde_json::json;
+
+    fn flow(content_type: &str, body: &[u8]) -> HTTPFlow {
+        let request = HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "cdn.example.com".to_string(),
+            443,
+            "/asset".to_string(),
+        );
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.set_header("Content-Type".to_string(), content_type.to_string());
+        response.set_header("Content-Length".to_string(), body.len().to_string());
+        response.set_content(body.to_vec());
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    fn shaper(rules: serde_json::Value) -> Shaper {
+        let rules: Vec<ShapingRule> = serde_json::from_value(rules).unwrap();
+        Shaper::from_rules(&rules).unwrap()
+    }
+
+    #[test]
+    fn test_shape_pads_and_records_plan() {
+        let shaper = shaper(json!([
+            {"name": "images", "content_types": ["image/"], "chunk_size": 4, "chunk_delay_ms": 50, "pad_to": 10},
+            {"name": "text", "content_types": ["text/"], "pad_bytes": 3},
+        ]));
+
+        let mut image = flow("image/png", b"\x89PNG");
+        let plan = shaper.shape(&mut image).unwrap();
+        assert_eq!(plan.rule, "images");
+        assert_eq!((plan.original_size, plan.padding), (4, 6));
+        let response = image.response.as_ref().unwrap();
+        assert_eq!(response.content.as_deref(), Some(&b"\x89PNG\0\0\0\0\0\0"[..]));
+        assert_eq!(response.get_header("content-length").map(String::as_str), Some("10"));
+        assert_eq!(image.flow.metadata[METADATA_KEY]["chunk_delay_ms"], 50);
+
+        let mut text = flow("text/css; charset=utf-8", b"a{}");
+        shaper.shape(&mut text).unwrap();
+        assert_eq!(text.response.as_ref().unwrap().content.as_deref(), Some(&b"a{}   "[..]));
+
+        let mut other = flow("application/octet-stream", b"data");
+        assert!(shaper.shape(&mut other).is_none());
+        assert!(other.flow.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_recorded_plan_is_reused() {
+        let shaper = shaper(json!([{"name": "pad", "pad_bytes": 2}]));
+        let mut f = flow("text/plain", b"hi");
+        let first = shaper.shape(&mut f).unwrap();
+
+        // Reloading the flow yields the same plan without padding twice
+        let mut reloaded: HTTPFlow = serde_json::from_value(serde_json::to_value(&f).unwrap()).unwrap();
+        assert_eq!(shaper.shape(&mut reloaded), Some(first));
+        assert_eq!(reloaded.response.unwrap().content.unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let plan = ShapingPlan {
+            rule: "slow".to_string(),
+            chunk_size: 4,
+            chunk_delay_ms: 100,
+            bandwidth: Some(8),
+            original_size: 10,
+            padding: 0,
+        };
+        let schedule = plan.schedule(10);
+        let ranges: Vec<Range<usize>> = schedule.iter().map(|(r, _)| r.clone()).collect();
+        assert_eq!(ranges, [0..4, 4..8, 8..10]);
+        assert_eq!(schedule[0].1, Duration::from_millis(500));
+        assert_eq!(schedule[1].1, Duration::from_millis(600));
+        assert_eq!(plan.total_delay(10), Duration::from_millis(1450));
+        assert!(plan.schedule(0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_shaped() {
+        let plan = ShapingPlan {
+            rule: "chunks".to_string(),
+            chunk_size: 3,
+            chunk_delay_ms: 1,
+            bandwidth: None,
+            original_size: 8,
+            padding: 0,
+        };
+        let mut out = Vec::new();
+        write_shaped(&mut out, b"abcdefgh", &plan).await.unwrap();
+        assert_eq!(out, b"abcdefgh");
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let rule = |value| serde_json::from_value::<ShapingRule>(value).unwrap();
+        assert!(Shaper::from_rules(&[rule(json!({"name": ""}))]).is_err());
+        assert!(Shaper::from_rules(&[rule(json!({"name": "a", "chunk_size": 0}))]).is_err());
+        assert!(Shaper::from_rules(&[rule(json!({"name": "a", "filter": "("}))]).is_err());
+    }
+}
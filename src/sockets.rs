@@ -0,0 +1,113 @@
+//! Tuning of client and server TCP sockets.
+//!
+//! Options set under `sockets.client` apply to connections accepted from
+//! clients, those under `sockets.server` to connections the proxy opens to
+//! servers and upstream proxies. Unset options leave the operating system
+//! default. Nagle's algorithm holds back small writes such as the chunks of
+//! a streamed response until earlier data is acknowledged; `nodelay: true`
+//! sends them right away.
+
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+use crate::Result;
+
+/// Socket options as configured in the config file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocketTuning {
+    /// Connections accepted from clients
+    pub client: SocketOptions,
+    /// Connections opened to servers
+    pub server: SocketOptions,
+}
+
+/// Options of one side's sockets
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// Set TCP_NODELAY, disabling Nagle's algorithm
+    pub nodelay: Option<bool>,
+    /// Probe idle connections with TCP keepalives
+    pub keepalive: Option<KeepaliveOptions>,
+    /// SO_SNDBUF in bytes
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF in bytes
+    pub recv_buffer_size: Option<usize>,
+}
+
+/// TCP keepalive timing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeepaliveOptions {
+    /// Idle time before the first probe
+    pub idle_secs: u64,
+    /// Time between unanswered probes
+    pub interval_secs: u64,
+}
+
+impl Default for KeepaliveOptions {
+    fn default() -> Self {
+        Self { idle_secs: 60, interval_secs: 10 }
+    }
+}
+
+impl SocketOptions {
+    /// Whether any option is set
+    pub fn is_set(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Set the configured options on `stream`
+    pub fn apply(&self, stream: &TcpStream) -> Result<()> {
+        let socket = SockRef::from(stream);
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            let params = TcpKeepalive::new()
+                .with_time(Duration::from_secs(keepalive.idle_secs))
+                .with_interval(Duration::from_secs(keepalive.interval_secs));
+            socket.set_tcp_keepalive(&params)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_apply_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let before = SockRef::from(&stream).tcp_nodelay().unwrap();
+        SocketOptions::default().apply(&stream).unwrap();
+        assert_eq!(SockRef::from(&stream).tcp_nodelay().unwrap(), before);
+        assert!(!SocketOptions::default().is_set());
+
+        let options = SocketOptions {
+            nodelay: Some(true),
+            keepalive: Some(KeepaliveOptions { idle_secs: 30, interval_secs: 5 }),
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: Some(64 * 1024),
+        };
+        options.apply(&stream).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        // The kernel reserves room for bookkeeping on top of the request
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+}
@@ -360,6 +360,99 @@ pub trait SseStreamExt: Iterator<Item = Vec<u8>> + Sized {
 
 impl<I: Iterator<Item = Vec<u8>>> SseStreamExt for I {}
 
+/// SSE stream monitoring options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SseMonitorOptions {
+    /// An open stream is marked stalled when no data, not even a comment
+    /// used as heartbeat, arrived for this long
+    pub stall_after_secs: f64,
+    /// Time between two checks of the open streams
+    pub check_interval_secs: u64,
+}
+
+impl Default for SseMonitorOptions {
+    fn default() -> Self {
+        Self { stall_after_secs: 30.0, check_interval_secs: 1 }
+    }
+}
+
+/// Stream-level metrics of an event-stream response, updated as data
+/// arrives. Timestamps are UNIX timestamps in seconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SseStreamStats {
+    /// When the response headers arrived
+    pub timestamp_start: f64,
+    /// When data last arrived, events or not
+    pub timestamp_last_data: f64,
+    pub first_event: Option<f64>,
+    pub last_event: Option<f64>,
+    pub event_count: usize,
+    /// Seconds the stream has been open, or was open once ended
+    pub duration: f64,
+    /// Mean time between two events, once there are two
+    pub average_gap: Option<f64>,
+    /// No data arrived for the configured window while the stream was open
+    pub stalled: bool,
+    pub ended: bool,
+}
+
+impl SseStreamStats {
+    pub fn new(timestamp: f64) -> Self {
+        Self { timestamp_start: timestamp, timestamp_last_data: timestamp, ..Default::default() }
+    }
+
+    /// Metrics of a stream only seen once complete, whose events all got
+    /// the response end time: the gaps between them are not known
+    pub fn completed(timestamp_start: f64, timestamp_end: f64, event_count: usize) -> Self {
+        let mut stats = Self::new(timestamp_start);
+        stats.timestamp_last_data = timestamp_end;
+        stats.event_count = event_count;
+        if event_count > 0 {
+            stats.first_event = Some(timestamp_end);
+            stats.last_event = Some(timestamp_end);
+        }
+        stats.finish(timestamp_end);
+        stats
+    }
+
+    /// Account for a chunk of data carrying `events` complete events
+    pub fn record_data(&mut self, timestamp: f64, events: usize) {
+        self.timestamp_last_data = timestamp;
+        self.duration = timestamp - self.timestamp_start;
+        self.stalled = false;
+        if events == 0 {
+            return;
+        }
+        let first = *self.first_event.get_or_insert(timestamp);
+        self.last_event = Some(timestamp);
+        self.event_count += events;
+        if self.event_count > 1 {
+            self.average_gap = Some((timestamp - first) / (self.event_count - 1) as f64);
+        }
+    }
+
+    /// Update the duration of an open stream and mark it stalled if no data
+    /// arrived for `window` seconds. Returns whether it just stalled.
+    pub fn check_stall(&mut self, now: f64, window: f64) -> bool {
+        if self.ended {
+            return false;
+        }
+        self.duration = now - self.timestamp_start;
+        if self.stalled || now - self.timestamp_last_data < window {
+            return false;
+        }
+        self.stalled = true;
+        true
+    }
+
+    pub fn finish(&mut self, timestamp: f64) {
+        self.ended = true;
+        self.stalled = false;
+        self.duration = timestamp - self.timestamp_start;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -669,4 +762,58 @@ data: [DONE]
         assert_eq!(events.len(), 1);
         assert_eq!(events[0].data, "fresh");
     }
+
+    #[test]
+    fn test_stream_stats() {
+        let mut stats = SseStreamStats::new(100.0);
+        stats.record_data(101.0, 1);
+        stats.record_data(102.0, 0);
+        stats.record_data(104.0, 2);
+        assert_eq!((stats.event_count, stats.first_event, stats.last_event), (3, Some(101.0), Some(104.0)));
+        assert_eq!(stats.average_gap, Some(1.5));
+
+        assert!(!stats.check_stall(110.0, 30.0));
+        assert_eq!(stats.duration, 10.0);
+        assert!(stats.check_stall(134.0, 30.0));
+        assert!(!stats.check_stall(135.0, 30.0));
+        assert!(stats.stalled);
+
+        stats.record_data(136.0, 1);
+        assert!(!stats.stalled);
+        stats.finish(140.0);
+        assert!(!stats.check_stall(200.0, 30.0));
+        assert_eq!(stats.duration, 40.0);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_streams_are_reported() {
+        use crate::config::Config;
+        use crate::flow::{HTTPFlow, HTTPRequest};
+        use crate::proxy::ProxyServer;
+        use std::sync::Arc;
+
+        let proxy = ProxyServer::new(Arc::new(Config::default()));
+        let mut updates = proxy.subscribe_updates();
+        let mut flow = HTTPFlow::new(HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "api.example.com".to_string(),
+            443,
+            "/v1/stream".to_string(),
+        ));
+        flow.sse_stream = Some(SseStreamStats::new(100.0));
+        proxy.update_stream(flow.clone()).await;
+        assert!(updates.try_recv().is_ok());
+
+        assert_eq!(proxy.check_stalled_streams(110.0).await, 0);
+        assert_eq!(proxy.check_stalled_streams(131.0).await, 1);
+        let mut last = None;
+        while let Ok(flow) = updates.try_recv() {
+            last = Some(flow);
+        }
+        assert!(last.unwrap().sse_stream.unwrap().stalled);
+
+        proxy.add_flow(flow).await;
+        assert!(proxy.get_open_streams().await.is_empty());
+    }
 }
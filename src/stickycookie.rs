@@ -0,0 +1,212 @@
+//! Sticky cookies: cookies set by servers are sent along with later
+//! requests.
+//!
+//! With `stickycookie` set to a filter expression, `Set-Cookie` headers of
+//! matching responses are kept in a jar under their domain, port and path,
+//! and matching requests to the same server get the cookies that apply to
+//! them added to their `Cookie` header. Cookies a server expires are taken
+//! out of the jar again. This keeps replays of flows against services that
+//! need a login working once a session cookie was seen. Requests that got
+//! cookies from the jar are marked with the [`METADATA_KEY`] metadata key.
+
+use chrono::DateTime;
+use indexmap::IndexMap;
+use std::sync::Mutex;
+
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::Result;
+
+/// Flow metadata key marking requests cookies were added to
+pub const METADATA_KEY: &str = "stickycookie";
+
+/// Domain, port and path a cookie was set for. The domain starts with a
+/// dot when subdomains get the cookie too.
+type CookieKey = (String, u16, String);
+
+/// The cookie jar of the `stickycookie` option
+#[derive(Debug)]
+pub struct StickyCookies {
+    filter: Filter,
+    jar: Mutex<IndexMap<CookieKey, IndexMap<String, String>>>,
+}
+
+impl StickyCookies {
+    pub fn new(filter: &str) -> Result<Self> {
+        Ok(Self { filter: Filter::new("stickycookie".to_string(), filter.to_string())?, jar: Mutex::default() })
+    }
+
+    /// Keep the cookies the response of `flow` sets, if it matches
+    pub fn response(&self, flow: &HTTPFlow) {
+        let Some(response) = flow.response.as_ref().filter(|_| self.filter.matches(flow)) else {
+            return;
+        };
+        let mut jar = self.jar.lock().unwrap();
+        let set_cookies = response.headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("set-cookie"));
+        for (_, set_cookie) in set_cookies {
+            let Some(cookie) = SetCookie::parse(set_cookie) else {
+                continue;
+            };
+            let key = (
+                cookie.domain.map_or_else(|| flow.request.host.to_ascii_lowercase(), |domain| format!(".{}", domain)),
+                flow.request.port,
+                cookie.path.unwrap_or_else(|| "/".to_string()),
+            );
+            if cookie.expired {
+                if let Some(cookies) = jar.get_mut(&key) {
+                    cookies.shift_remove(&cookie.name);
+                }
+            } else {
+                jar.entry(key).or_default().insert(cookie.name, cookie.value);
+            }
+        }
+        jar.retain(|_, cookies| !cookies.is_empty());
+    }
+
+    /// Add the cookies that apply to the request of `flow`, if it matches.
+    /// Cookies the request has with the same names are replaced. Returns
+    /// whether any were added.
+    pub fn request(&self, flow: &mut HTTPFlow) -> bool {
+        if !self.filter.matches(flow) {
+            return false;
+        }
+        let request = &flow.request;
+        let path = request.path.split(['?', '#']).next().unwrap_or_default();
+        let mut sticky: IndexMap<String, String> = IndexMap::new();
+        for ((domain, port, prefix), cookies) in self.jar.lock().unwrap().iter() {
+            if *port == request.port && domain_matches(&request.host, domain) && path_matches(path, prefix) {
+                sticky.extend(cookies.iter().map(|(name, value)| (name.clone(), value.clone())));
+            }
+        }
+        if sticky.is_empty() {
+            return false;
+        }
+
+        let existing = request.get_header("cookie").map(String::as_str).unwrap_or_default();
+        let kept = existing
+            .split(';')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| !sticky.contains_key(pair.split('=').next().unwrap_or_default().trim()))
+            .map(str::to_string);
+        let cookie = kept.chain(sticky.iter().map(|(name, value)| format!("{}={}", name, value))).collect::<Vec<_>>().join("; ");
+        flow.request.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("cookie"));
+        flow.request.headers.push(("Cookie".to_string(), cookie));
+        flow.flow.metadata.insert(METADATA_KEY.to_string(), serde_json::Value::Bool(true));
+        true
+    }
+
+    /// Number of cookies in the jar
+    pub fn count(&self) -> usize {
+        self.jar.lock().unwrap().values().map(IndexMap::len).sum()
+    }
+
+    pub fn clear(&self) {
+        self.jar.lock().unwrap().clear();
+    }
+}
+
+/// The parts of a `Set-Cookie` value the jar needs
+struct SetCookie {
+    name: String,
+    value: String,
+    domain: Option<String>,
+    path: Option<String>,
+    expired: bool,
+}
+
+impl SetCookie {
+    fn parse(set_cookie: &str) -> Option<Self> {
+        let mut parts = set_cookie.split(';').map(str::trim);
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let mut cookie = Self { name: name.to_string(), value: value.trim().to_string(), domain: None, path: None, expired: false };
+        let mut max_age = None;
+        for attribute in parts {
+            let (attribute, value) = attribute.split_once('=').map_or((attribute, ""), |(a, v)| (a.trim(), v.trim()));
+            match attribute.to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => cookie.domain = Some(value.trim_start_matches('.').to_ascii_lowercase()),
+                "path" if value.starts_with('/') => cookie.path = Some(value.to_string()),
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "expires" if max_age.is_none() => {
+                    cookie.expired = DateTime::parse_from_rfc2822(&value.replace(" GMT", " +0000"))
+                        .is_ok_and(|expires| expires.timestamp() as f64 <= crate::clock::now());
+                }
+                _ => {}
+            }
+        }
+        // Max-Age takes precedence over Expires
+        if let Some(max_age) = max_age {
+            cookie.expired = max_age <= 0;
+        }
+        Some(cookie)
+    }
+}
+
+/// Whether a cookie for `domain` is sent to `host`
+fn domain_matches(host: &str, domain: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    match domain.strip_prefix('.') {
+        Some(domain) => host == domain || host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+        None => host == domain,
+    }
+}
+
+/// Whether a cookie for `prefix` applies to `path`
+fn path_matches(path: &str, prefix: &str) -> bool {
+    path == prefix
+        || path.strip_prefix(prefix).is_some_and(|rest| prefix.ends_with('/') || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow(host: &str, path: &str, set_cookies: &[&str]) -> HTTPFlow {
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), host.to_string(), 443, path.to_string());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.headers = set_cookies.iter().map(|value| ("Set-Cookie".to_string(), value.to_string())).collect();
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_sticky_cookies() {
+        let sticky = StickyCookies::new("~d example.com").unwrap();
+        sticky.response(&flow("example.com", "/login", &["session=abc; Path=/; HttpOnly", "theme=dark; Domain=.example.com"]));
+        sticky.response(&flow("example.com", "/", &["admin=1; Path=/admin"]));
+        sticky.response(&flow("other.org", "/", &["tracker=1"]));
+        assert_eq!(sticky.count(), 3);
+
+        let mut request = flow("api.example.com", "/admin/users?page=2", &[]);
+        request.request.headers = vec![("Cookie".to_string(), "theme=light; lang=en".to_string())];
+        assert!(sticky.request(&mut request));
+        assert_eq!(request.request.get_header("cookie").unwrap(), "lang=en; theme=dark");
+        assert_eq!(request.flow.metadata[METADATA_KEY], true);
+
+        let mut request = flow("example.com", "/administrator", &[]);
+        assert!(sticky.request(&mut request));
+        assert_eq!(request.request.get_header("cookie").unwrap(), "session=abc; theme=dark");
+
+        // Expired cookies are removed, and other servers get nothing
+        sticky.response(&flow("example.com", "/", &["session=; Max-Age=0", "theme=x; Domain=example.com; Expires=Thu, 01 Jan 1970 00:00:00 GMT"]));
+        let mut request = flow("example.com", "/", &[]);
+        assert!(!sticky.request(&mut request));
+        assert!(request.request.get_header("cookie").is_none());
+        assert!(!sticky.request(&mut flow("other.org", "/", &[])));
+        assert_eq!(sticky.count(), 1);
+    }
+
+    #[test]
+    fn test_matching() {
+        assert!(domain_matches("a.example.com", ".example.com") && domain_matches("Example.com", ".example.com"));
+        assert!(!domain_matches("badexample.com", ".example.com"));
+        assert!(domain_matches("example.com", "example.com") && !domain_matches("a.example.com", "example.com"));
+        assert!(path_matches("/admin/x", "/admin") && path_matches("/admin", "/admin") && path_matches("/x", "/"));
+        assert!(!path_matches("/administrator", "/admin"));
+        assert!(SetCookie::parse("novalue").is_none() && SetCookie::parse("=x").is_none());
+    }
+}
@@ -0,0 +1,307 @@
+//! Storage of captured flows.
+//!
+//! The proxy keeps its flows in a [`FlowStore`]. By default that is a
+//! [`MemoryStore`]. Built with the `sqlite` feature, `flow_store.backend:
+//! sqlite` keeps them in a database file instead: flows survive restarts
+//! and do not have to fit in memory, at the cost of a write per stored flow.
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use crate::filter::Filter;
+use crate::flow::HTTPFlow;
+use crate::Result;
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+/// Where flows are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowStoreBackend {
+    #[default]
+    Memory,
+    /// An sqlite database, with the `sqlite` feature
+    Sqlite,
+}
+
+/// Flow store options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlowStoreOptions {
+    pub backend: FlowStoreBackend,
+    /// Database file of the sqlite backend
+    pub path: String,
+}
+
+impl Default for FlowStoreOptions {
+    fn default() -> Self {
+        Self { backend: FlowStoreBackend::Memory, path: "~/.mitmproxy-rs/flows.sqlite".to_string() }
+    }
+}
+
+/// Flows by id
+pub trait FlowStore: Send + Sync + std::fmt::Debug {
+    /// Store `flow`, replacing a stored flow with the same id
+    fn insert(&mut self, flow: HTTPFlow) -> Result<()>;
+
+    /// Replace the stored flow with the id of `flow`. Returns false if
+    /// there is none.
+    fn update(&mut self, flow: HTTPFlow) -> Result<bool>;
+
+    fn get(&self, id: &str) -> Result<Option<HTTPFlow>>;
+
+    /// Flows matching `filter`, or all flows, by sequence number
+    fn iter<'a>(&'a self, filter: Option<&'a Filter>) -> Result<Box<dyn Iterator<Item = Cow<'a, HTTPFlow>> + 'a>>;
+
+    /// Remove the flow with `id`, returning it
+    fn evict(&mut self, id: &str) -> Result<Option<HTTPFlow>>;
+
+    fn len(&self) -> Result<usize>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn clear(&mut self) -> Result<()>;
+}
+
+/// Open the store `options` select. `path` is the database file with `~`
+/// expanded.
+pub fn open(options: &FlowStoreOptions, path: &str) -> Result<Box<dyn FlowStore>> {
+    match options.backend {
+        FlowStoreBackend::Memory => Ok(Box::new(MemoryStore::default())),
+        #[cfg(feature = "sqlite")]
+        FlowStoreBackend::Sqlite => Ok(Box::new(SqliteStore::open(path)?)),
+        #[cfg(not(feature = "sqlite"))]
+        FlowStoreBackend::Sqlite => {
+            let _ = path;
+            Err(crate::Error::invalid_request("The sqlite flow store needs a build with the `sqlite` feature"))
+        }
+    }
+}
+
+/// Flows in memory, in the order they were last stored
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    flows: IndexMap<String, HTTPFlow>,
+}
+
+impl FlowStore for MemoryStore {
+    fn insert(&mut self, flow: HTTPFlow) -> Result<()> {
+        // A flow stored again gets a new sequence number, so it moves last
+        self.flows.shift_remove(&flow.flow.id);
+        self.flows.insert(flow.flow.id.clone(), flow);
+        Ok(())
+    }
+
+    fn update(&mut self, flow: HTTPFlow) -> Result<bool> {
+        match self.flows.get_mut(&flow.flow.id) {
+            Some(stored) => {
+                *stored = flow;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn get(&self, id: &str) -> Result<Option<HTTPFlow>> {
+        Ok(self.flows.get(id).cloned())
+    }
+
+    fn iter<'a>(&'a self, filter: Option<&'a Filter>) -> Result<Box<dyn Iterator<Item = Cow<'a, HTTPFlow>> + 'a>> {
+        Ok(Box::new(
+            self.flows
+                .values()
+                .filter(move |flow| filter.is_none_or(|filter| filter.matches(flow)))
+                .map(Cow::Borrowed),
+        ))
+    }
+
+    fn evict(&mut self, id: &str) -> Result<Option<HTTPFlow>> {
+        Ok(self.flows.shift_remove(id))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.flows.len())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.flows.clear();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use rusqlite::{params, Connection, OptionalExtension};
+    use std::borrow::Cow;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use super::FlowStore;
+    use crate::filter::Filter;
+    use crate::flow::HTTPFlow;
+    use crate::Result;
+
+    /// Flows in an sqlite database, as dump-format JSON
+    #[derive(Debug)]
+    pub struct SqliteStore {
+        conn: Mutex<Connection>,
+    }
+
+    impl SqliteStore {
+        /// Open or create the database at `path`
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+            if let Some(parent) = path.as_ref().parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            Self::with_connection(Connection::open(path)?)
+        }
+
+        /// A database that lives as long as the store
+        pub fn in_memory() -> Result<Self> {
+            Self::with_connection(Connection::open_in_memory()?)
+        }
+
+        fn with_connection(conn: Connection) -> Result<Self> {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS flows (id TEXT PRIMARY KEY, seq INTEGER NOT NULL, flow TEXT NOT NULL);
+                 CREATE INDEX IF NOT EXISTS flows_seq ON flows (seq);",
+            )?;
+            Ok(Self { conn: Mutex::new(conn) })
+        }
+
+        fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+            self.conn.lock().unwrap()
+        }
+    }
+
+    impl FlowStore for SqliteStore {
+        fn insert(&mut self, flow: HTTPFlow) -> Result<()> {
+            self.conn().execute(
+                "INSERT INTO flows (id, seq, flow) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (id) DO UPDATE SET seq = excluded.seq, flow = excluded.flow",
+                params![flow.flow.id, flow.flow.seq as i64, serde_json::to_string(&flow)?],
+            )?;
+            Ok(())
+        }
+
+        fn update(&mut self, flow: HTTPFlow) -> Result<bool> {
+            let changed = self.conn().execute(
+                "UPDATE flows SET seq = ?2, flow = ?3 WHERE id = ?1",
+                params![flow.flow.id, flow.flow.seq as i64, serde_json::to_string(&flow)?],
+            )?;
+            Ok(changed > 0)
+        }
+
+        fn get(&self, id: &str) -> Result<Option<HTTPFlow>> {
+            let json: Option<String> =
+                self.conn().query_row("SELECT flow FROM flows WHERE id = ?1", [id], |row| row.get(0)).optional()?;
+            Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+        }
+
+        fn iter<'a>(&'a self, filter: Option<&'a Filter>) -> Result<Box<dyn Iterator<Item = Cow<'a, HTTPFlow>> + 'a>> {
+            let conn = self.conn();
+            let mut statement = conn.prepare("SELECT flow FROM flows ORDER BY seq")?;
+            let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+            let mut flows = Vec::new();
+            for json in rows {
+                let flow: HTTPFlow = serde_json::from_str(&json?)?;
+                if filter.is_none_or(|filter| filter.matches(&flow)) {
+                    flows.push(Cow::Owned(flow));
+                }
+            }
+            Ok(Box::new(flows.into_iter()))
+        }
+
+        fn evict(&mut self, id: &str) -> Result<Option<HTTPFlow>> {
+            let flow = self.get(id)?;
+            self.conn().execute("DELETE FROM flows WHERE id = ?1", [id])?;
+            Ok(flow)
+        }
+
+        fn len(&self) -> Result<usize> {
+            let count: i64 = self.conn().query_row("SELECT COUNT(*) FROM flows", [], |row| row.get(0))?;
+            Ok(count as usize)
+        }
+
+        fn clear(&mut self) -> Result<()> {
+            self.conn().execute("DELETE FROM flows", [])?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow(path: &str, seq: u64) -> HTTPFlow {
+        let mut flow = HTTPFlow::new(HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "example.com".to_string(),
+            443,
+            path.to_string(),
+        ));
+        flow.flow.seq = seq;
+        flow
+    }
+
+    fn exercise(store: &mut dyn FlowStore) {
+        let (a, b, c) = (flow("/a", 1), flow("/b", 2), flow("/c", 3));
+        for flow in [&a, &b, &c] {
+            store.insert(flow.clone()).unwrap();
+        }
+        assert_eq!(store.len().unwrap(), 3);
+
+        let mut answered = b.clone();
+        answered.response = Some(HTTPResponse::new(404, "Not Found".to_string()));
+        assert!(store.update(answered).unwrap());
+        assert!(!store.update(flow("/d", 4)).unwrap());
+        assert_eq!(store.get(&b.flow.id).unwrap().unwrap().response.unwrap().status_code, 404);
+
+        let paths = |store: &dyn FlowStore, filter: Option<&Filter>| -> Vec<String> {
+            store.iter(filter).unwrap().map(|flow| flow.request.path.clone()).collect()
+        };
+        assert_eq!(paths(store, None), ["/a", "/b", "/c"]);
+        let filter = Filter::new("test".to_string(), "~c 404".to_string()).unwrap();
+        assert_eq!(paths(store, Some(&filter)), ["/b"]);
+
+        assert_eq!(store.evict(&a.flow.id).unwrap().unwrap().request.path, "/a");
+        assert!(store.evict(&a.flow.id).unwrap().is_none());
+        assert!(store.get(&a.flow.id).unwrap().is_none());
+        assert_eq!(paths(store, None), ["/b", "/c"]);
+
+        // A flow stored again comes after the others
+        let mut again = b.clone();
+        again.flow.seq = 5;
+        store.insert(again).unwrap();
+        assert_eq!(paths(store, None), ["/c", "/b"]);
+        assert_eq!(store.len().unwrap(), 2);
+
+        store.clear().unwrap();
+        assert!(store.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        exercise(&mut MemoryStore::default());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store() {
+        exercise(&mut SqliteStore::in_memory().unwrap());
+
+        // Flows are still there when the database is opened again
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("flows.sqlite");
+        SqliteStore::open(&path).unwrap().insert(flow("/kept", 7)).unwrap();
+        let reopened = SqliteStore::open(&path).unwrap();
+        assert_eq!(reopened.iter(None).unwrap().next().unwrap().flow.seq, 7);
+    }
+}
@@ -0,0 +1,216 @@
+//! Substituting response bodies with local files.
+//!
+//! Each `substitute_body` entry is written `|matcher|path`, where the first
+//! character is the separator. The matcher is either `sha256:` followed by
+//! the hex SHA-256 of a body, matched against the body as received and as
+//! decoded, or a flow filter expression such as `~u /appcast\.xml$`. The
+//! body of a matching response is replaced with the file at `path`, which
+//! is read anew for every response. Unlike `map_local`, the request still
+//! goes to the server and its response headers are kept: the new body is
+//! compressed with the response's Content-Encoding and Content-Length is
+//! updated, so clients that pin a URL see the same response with another
+//! payload. The first matching rule wins, and substituted responses note
+//! the rule and both body hashes under [`METADATA_KEY`].
+
+use serde_json::json;
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::warn;
+
+use crate::filter::Filter;
+use crate::flow::{content_hash, HTTPFlow};
+use crate::{Error, Result};
+
+/// Flow metadata key describing a substituted response body
+pub const METADATA_KEY: &str = "substitute";
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// Hex SHA-256 of the body
+    Hash(String),
+    Filter(Filter),
+}
+
+/// Bodies of responses matching `matcher` are replaced with `path`
+#[derive(Debug, Clone)]
+pub struct SubstituteRule {
+    /// The entry the rule was parsed from
+    pub spec: String,
+    matcher: Matcher,
+    pub path: PathBuf,
+}
+
+impl SubstituteRule {
+    /// Parse a `|matcher|path` entry. The path is everything after the
+    /// second separator, so it may use the separator itself.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::invalid_request(format!("Invalid substitute_body entry {}: {}", spec, reason));
+        let separator = spec.chars().next().ok_or_else(|| invalid("expected |matcher|path"))?;
+        let (matcher, path) = spec[separator.len_utf8()..]
+            .split_once(separator)
+            .ok_or_else(|| invalid("expected |matcher|path"))?;
+        let matcher = match matcher.trim().strip_prefix("sha256:") {
+            Some(hash) if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => Matcher::Hash(hash.to_ascii_lowercase()),
+            Some(_) => return Err(invalid("expected a hex SHA-256 after sha256:")),
+            None if matcher.trim().is_empty() => return Err(invalid("no matcher")),
+            None => Matcher::Filter(Filter::new("substitute_body".to_string(), matcher.to_string())?),
+        };
+        let path = path.trim();
+        if path.is_empty() {
+            return Err(invalid("no path"));
+        }
+        let path = match (path.strip_prefix("~/"), dirs::home_dir()) {
+            (Some(rest), Some(home)) => home.join(rest),
+            _ => PathBuf::from(path),
+        };
+        Ok(Self { spec: spec.to_string(), matcher, path })
+    }
+
+    fn matches(&self, flow: &HTTPFlow, hashes: &[String]) -> bool {
+        match &self.matcher {
+            Matcher::Hash(hash) => hashes.contains(hash),
+            Matcher::Filter(filter) => filter.matches(flow),
+        }
+    }
+}
+
+/// The configured `substitute_body` rules
+#[derive(Debug, Clone, Default)]
+pub struct Substitute {
+    rules: Vec<SubstituteRule>,
+}
+
+impl Substitute {
+    pub fn from_specs(specs: &[String]) -> Result<Self> {
+        Ok(Self { rules: specs.iter().map(|spec| SubstituteRule::parse(spec)).collect::<Result<_>>()? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Replace the response body of `flow` if a rule matches it. Returns
+    /// whether it was replaced.
+    pub fn apply(&self, flow: &mut HTTPFlow) -> bool {
+        let Some(response) = flow.response.as_ref().filter(|_| !self.rules.is_empty()) else {
+            return false;
+        };
+        let Some(body) = response.content.as_deref() else {
+            return false;
+        };
+        let encoding = response.get_header("content-encoding").cloned();
+        let decoded = encoding.as_deref().and_then(|encoding| crate::compression::decode(encoding, body).ok());
+        let original = content_hash(decoded.as_deref().unwrap_or(body));
+        let hashes = [content_hash(body), original.clone()];
+        let Some(rule) = self.rules.iter().find(|rule| rule.matches(flow, &hashes)) else {
+            return false;
+        };
+        let payload = match std::fs::read(&rule.path) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("substitute_body cannot read {}: {}", rule.path.display(), e);
+                return false;
+            }
+        };
+
+        let sha256 = content_hash(&payload);
+        let Some(response) = flow.response.as_mut() else {
+            return false;
+        };
+        let body = match encoding.as_deref().map(|encoding| (encoding, encode(encoding, &payload))) {
+            Some((_, Some(encoded))) => encoded,
+            Some((encoding, None)) => {
+                warn!("substitute_body sends {} unencoded: cannot produce Content-Encoding {}", rule.path.display(), encoding);
+                response.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-encoding"));
+                payload
+            }
+            None => payload,
+        };
+        if let Some((_, value)) = response.headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("content-length")) {
+            *value = body.len().to_string();
+        }
+        response.set_content(body);
+        flow.flow.metadata.insert(
+            METADATA_KEY.to_string(),
+            json!({ "rule": rule.spec, "path": rule.path, "original_sha256": original, "sha256": sha256 }),
+        );
+        true
+    }
+}
+
+/// `data` compressed with a single Content-Encoding, if the proxy can
+/// produce it
+fn encode(content_encoding: &str, data: &[u8]) -> Option<Vec<u8>> {
+    use crate::compression::Encoding;
+
+    match content_encoding.trim().to_ascii_lowercase().as_str() {
+        "identity" => Some(data.to_vec()),
+        "gzip" | "x-gzip" => Encoding::Gzip.encode(data).ok(),
+        "br" => Encoding::Br.encode(data).ok(),
+        "deflate" => {
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).ok()?;
+            encoder.finish().ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flow(path: &str, headers: &[(&str, &str)], body: &[u8]) -> HTTPFlow {
+        let request = HTTPRequest::new("GET".to_string(), "https".to_string(), "updates.example".to_string(), 443, path.to_string());
+        let mut response = HTTPResponse::new(200, "OK".to_string());
+        response.headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        response.set_content(body.to_vec());
+        HTTPFlow::new(request).with_response(response)
+    }
+
+    #[test]
+    fn test_substitute() {
+        let dir = tempfile::tempdir().unwrap();
+        let patched = dir.path().join("patched.bin");
+        std::fs::write(&patched, b"\x7fELF patched firmware").unwrap();
+        let original = b"\x7fELF original firmware";
+        let substitute = Substitute::from_specs(&[
+            format!("|sha256:{}|{}", content_hash(original).to_uppercase(), patched.display()),
+            format!("|~u /appcast\\.xml$|{}", patched.display()),
+        ])
+        .unwrap();
+
+        // Matched by the hash of the decoded body, and sent encoded again
+        let gzipped = crate::compression::Encoding::Gzip.encode(original).unwrap();
+        let mut firmware = flow(
+            "/fw/latest",
+            &[("Content-Type", "application/octet-stream"), ("Content-Encoding", "gzip"), ("Content-Length", &gzipped.len().to_string())],
+            &gzipped,
+        );
+        assert!(substitute.apply(&mut firmware));
+        let response = firmware.response.as_ref().unwrap();
+        let body = response.content.as_deref().unwrap();
+        assert_eq!(crate::compression::decode("gzip", body).unwrap(), b"\x7fELF patched firmware");
+        assert_eq!(response.get_header("content-length").unwrap(), &body.len().to_string());
+        assert_eq!(response.get_header("content-type").unwrap(), "application/octet-stream");
+        let note = &firmware.flow.metadata[METADATA_KEY];
+        assert_eq!(note["original_sha256"], content_hash(original));
+        assert_eq!(note["sha256"], content_hash(b"\x7fELF patched firmware"));
+
+        let mut appcast = flow("/appcast.xml", &[("Content-Encoding", "zstd")], b"<rss/>");
+        assert!(substitute.apply(&mut appcast));
+        let response = appcast.response.as_ref().unwrap();
+        assert!(response.get_header("content-encoding").is_none());
+        assert_eq!(response.content.as_deref().unwrap(), b"\x7fELF patched firmware");
+
+        assert!(!substitute.apply(&mut flow("/fw/other", &[], b"other firmware")));
+
+        let missing = Substitute::from_specs(&[format!("|~u fw|{}", dir.path().join("missing.bin").display())]).unwrap();
+        assert!(!missing.apply(&mut flow("/fw/latest", &[], original)));
+
+        for invalid in ["", "|", "|~u fw", "||patched.bin", "|sha256:abc|patched.bin", "|~u fw|", "|~d [|patched.bin"] {
+            assert!(SubstituteRule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Regression test skeletons generated from captured flows.
+//!
+//! Each HTTP flow becomes one test function that sends the recorded request
+//! and asserts the recorded status code and the response headers in
+//! `ASSERTED_HEADERS`: a Rust `#[tokio::test]` using reqwest, or a pytest
+//! function using httpx. Redirects are not followed, so a redirect is
+//! checked as the response it was. The tests are a starting point; bodies,
+//! timestamps and tokens usually need editing before they pass reliably.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::HashSet;
+
+use crate::flow::HTTPFlow;
+use crate::loadtest::SKIPPED_HEADERS;
+
+/// Response headers whose recorded values are asserted
+pub const ASSERTED_HEADERS: &[&str] = &["content-type", "location"];
+
+/// Methods with an associated constant on `reqwest::Method`
+const STANDARD_METHODS: &[&str] = &["GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "CONNECT", "PATCH", "TRACE"];
+
+/// Generate a Rust file with a `#[tokio::test]` per flow, using reqwest
+pub fn to_reqwest(flows: &[HTTPFlow]) -> String {
+    let flows = http_flows(flows);
+    let mut tests = format!("// Tests generated by mitmproxy-rs from {} flows\n", flows.len());
+    for (name, flow) in test_names(&flows) {
+        let request = &flow.request;
+        let method = request.method.to_uppercase();
+        let method = if STANDARD_METHODS.contains(&method.as_str()) {
+            format!("reqwest::Method::{}", method)
+        } else {
+            format!("reqwest::Method::from_bytes(b{:?}).unwrap()", method)
+        };
+        tests.push_str(&format!("\n#[tokio::test]\nasync fn {}() {{\n", name));
+        tests.push_str("    let client = reqwest::Client::builder()\n");
+        tests.push_str("        .redirect(reqwest::redirect::Policy::none())\n");
+        tests.push_str("        .build()\n");
+        tests.push_str("        .unwrap();\n");
+        tests.push_str("    let response = client\n");
+        tests.push_str(&format!("        .request({}, {:?})\n", method, request.url()));
+        for (name, value) in sent_headers(flow) {
+            tests.push_str(&format!("        .header({:?}, {:?})\n", name, value));
+        }
+        if let Some(body) = request.content.as_deref().filter(|body| !body.is_empty()) {
+            match std::str::from_utf8(body) {
+                Ok(text) => tests.push_str(&format!("        .body({:?})\n", text)),
+                Err(_) => tests.push_str(&format!("        .body(b\"{}\".to_vec())\n", body.escape_ascii())),
+            }
+        }
+        tests.push_str("        .send()\n");
+        tests.push_str("        .await\n");
+        tests.push_str("        .unwrap();\n");
+        match &flow.response {
+            Some(response) => {
+                tests.push_str(&format!("    assert_eq!(response.status().as_u16(), {});\n", response.status_code));
+                for (name, value) in asserted_headers(flow) {
+                    tests.push_str(&format!("    assert_eq!(response.headers()[{:?}], {:?});\n", name, value));
+                }
+            }
+            None => tests.push_str("    // No response was recorded\n    let _ = response;\n"),
+        }
+        tests.push_str("}\n");
+    }
+    tests
+}
+
+/// Generate a pytest file with a test function per flow, using httpx
+pub fn to_pytest(flows: &[HTTPFlow]) -> String {
+    let flows = http_flows(flows);
+    let mut tests = format!("# Tests generated by mitmproxy-rs from {} flows\n", flows.len());
+    let binary = flows
+        .iter()
+        .any(|flow| flow.request.content.as_deref().is_some_and(|body| std::str::from_utf8(body).is_err()));
+    if binary {
+        tests.push_str("import base64\n\n");
+    }
+    tests.push_str("import httpx\n");
+    for (name, flow) in test_names(&flows) {
+        let request = &flow.request;
+        tests.push_str(&format!("\n\ndef {}():\n", name));
+        tests.push_str("    response = httpx.request(\n");
+        tests.push_str(&format!("        {},\n", literal(&request.method.to_uppercase())));
+        tests.push_str(&format!("        {},\n", literal(&request.url())));
+        let headers: Vec<String> = sent_headers(flow)
+            .map(|(name, value)| format!("({}, {})", literal(name), literal(value)))
+            .collect();
+        tests.push_str(&format!("        headers=[{}],\n", headers.join(", ")));
+        if let Some(body) = request.content.as_deref().filter(|body| !body.is_empty()) {
+            match std::str::from_utf8(body) {
+                Ok(text) => tests.push_str(&format!("        content={}.encode(),\n", literal(text))),
+                Err(_) => tests.push_str(&format!("        content=base64.b64decode({}),\n", literal(&STANDARD.encode(body)))),
+            }
+        }
+        tests.push_str("        follow_redirects=False,\n");
+        tests.push_str("    )\n");
+        match &flow.response {
+            Some(response) => {
+                tests.push_str(&format!("    assert response.status_code == {}\n", response.status_code));
+                for (name, value) in asserted_headers(flow) {
+                    tests.push_str(&format!("    assert response.headers[{}] == {}\n", literal(name), literal(value)));
+                }
+            }
+            None => tests.push_str("    # No response was recorded\n"),
+        }
+    }
+    tests
+}
+
+fn http_flows(flows: &[HTTPFlow]) -> Vec<&HTTPFlow> {
+    flows
+        .iter()
+        .filter(|flow| !flow.is_tcp() && !flow.is_dns() && flow.websocket.is_none())
+        .collect()
+}
+
+/// Flows with unique test function names derived from their requests,
+/// such as `test_get_api_example_com_users`
+fn test_names<'a>(flows: &[&'a HTTPFlow]) -> Vec<(String, &'a HTTPFlow)> {
+    let mut taken = HashSet::new();
+    flows
+        .iter()
+        .map(|flow| {
+            let request = &flow.request;
+            let path = request.path.split(['?', '#']).next().unwrap_or_default();
+            let mut base = String::from("test_");
+            for c in format!("{} {} {}", request.method, request.host, path).chars() {
+                if c.is_ascii_alphanumeric() {
+                    base.push(c.to_ascii_lowercase());
+                } else if !base.ends_with('_') {
+                    base.push('_');
+                }
+            }
+            let base = base.trim_end_matches('_').to_string();
+            let mut name = base.clone();
+            let mut n = 1;
+            while !taken.insert(name.clone()) {
+                n += 1;
+                name = format!("{}_{}", base, n);
+            }
+            (name, *flow)
+        })
+        .collect()
+}
+
+/// Recorded request headers the HTTP client does not set itself
+fn sent_headers(flow: &HTTPFlow) -> impl Iterator<Item = &(String, String)> {
+    flow.request
+        .headers
+        .iter()
+        .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+}
+
+/// Response headers asserted, with their recorded values
+fn asserted_headers(flow: &HTTPFlow) -> Vec<(&'static str, &String)> {
+    let Some(response) = &flow.response else {
+        return Vec::new();
+    };
+    ASSERTED_HEADERS
+        .iter()
+        .filter_map(|name| response.get_header(name).map(|value| (*name, value)))
+        .collect()
+}
+
+/// A Python string literal
+fn literal(text: &str) -> String {
+    serde_json::to_string(text).unwrap_or_else(|_| "\"\"".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+
+    fn flows() -> Vec<HTTPFlow> {
+        let mut request =
+            HTTPRequest::new("POST".to_string(), "https".to_string(), "api.example.com".to_string(), 443, "/v1/users?x=1".to_string());
+        request.headers = vec![
+            ("Host".to_string(), "api.example.com".to_string()),
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Content-Length".to_string(), "17".to_string()),
+        ];
+        request.set_content(br#"{"name":"a\"lice"}"#.to_vec());
+        let mut response = HTTPResponse::new(201, "Created".to_string());
+        response.headers = vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Location".to_string(), "/v1/users/7".to_string()),
+            ("Date".to_string(), "Thu, 01 Jan 2026 00:00:00 GMT".to_string()),
+        ];
+        let created = HTTPFlow::new(request).with_response(response);
+
+        let mut upload =
+            HTTPRequest::new("PROPFIND".to_string(), "http".to_string(), "api.example.com".to_string(), 80, "/v1/users".to_string());
+        upload.set_content(vec![0xff, 0x00, b'"']);
+        vec![created, HTTPFlow::new(upload), HTTPFlow::new_tcp("example.com".to_string(), 22)]
+    }
+
+    #[test]
+    fn test_reqwest_tests() {
+        let tests = to_reqwest(&flows());
+        assert!(tests.starts_with("// Tests generated by mitmproxy-rs from 2 flows\n"));
+        assert!(tests.contains("#[tokio::test]\nasync fn test_post_api_example_com_v1_users() {\n"));
+        assert!(tests.contains(".request(reqwest::Method::POST, \"https://api.example.com/v1/users?x=1\")\n"));
+        assert!(tests.contains(".header(\"Content-Type\", \"application/json\")\n"));
+        assert!(!tests.contains("Content-Length") && !tests.contains(".header(\"Host\""));
+        assert!(tests.contains(r#".body("{\"name\":\"a\\\"lice\"}")"#));
+        assert!(tests.contains("    assert_eq!(response.status().as_u16(), 201);\n"));
+        assert!(tests.contains("    assert_eq!(response.headers()[\"location\"], \"/v1/users/7\");\n"));
+        assert!(!tests.contains("Date"));
+
+        assert!(tests.contains("async fn test_propfind_api_example_com_v1_users() {\n"));
+        assert!(tests.contains(".request(reqwest::Method::from_bytes(b\"PROPFIND\").unwrap(), "));
+        assert!(tests.contains(".body(b\"\\xff\\x00\\\"\".to_vec())\n"));
+        assert!(tests.contains("    // No response was recorded\n"));
+    }
+
+    #[test]
+    fn test_pytest_tests() {
+        let tests = to_pytest(&flows());
+        assert!(tests.starts_with("# Tests generated by mitmproxy-rs from 2 flows\nimport base64\n\nimport httpx\n"));
+        assert!(tests.contains("\n\ndef test_post_api_example_com_v1_users():\n    response = httpx.request(\n        \"POST\",\n"));
+        assert!(tests.contains("        headers=[(\"Content-Type\", \"application/json\")],\n"));
+        assert!(tests.contains(r#"        content="{\"name\":\"a\\\"lice\"}".encode(),"#));
+        assert!(tests.contains("        follow_redirects=False,\n"));
+        assert!(tests.contains("    assert response.status_code == 201\n"));
+        assert!(tests.contains("    assert response.headers[\"content-type\"] == \"application/json\"\n"));
+        assert!(tests.contains("        content=base64.b64decode(\"/wAi\"),\n"));
+
+        // The same request twice gets numbered test names
+        let mut twice = flows();
+        twice.truncate(1);
+        twice.push(twice[0].clone());
+        assert!(to_pytest(&twice).contains("def test_post_api_example_com_v1_users_2():"));
+    }
+}
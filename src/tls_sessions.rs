@@ -0,0 +1,272 @@
+//! Resumption of upstream TLS sessions.
+//!
+//! Sessions negotiated with upstream servers, whether by session ID or
+//! ticket, are kept per `host:port` and offered again on the next connection
+//! to the same destination, saving a full handshake. Each upstream handshake
+//! is counted as resumed or not, so the resumption rate shows whether the
+//! cache pays off. Disable the cache to observe full handshakes while
+//! debugging.
+
+use openssl::ssl::{SslContextBuilder, SslRef, SslSession, SslSessionCacheMode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// TLS session cache options as configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsSessionCacheOptions {
+    pub enabled: bool,
+    /// Destinations sessions are kept for before the oldest are evicted
+    pub max_entries: usize,
+    /// Upper bound on how long a session is reused, on top of the lifetime
+    /// the server gave it
+    pub ttl_secs: u64,
+}
+
+impl Default for TlsSessionCacheOptions {
+    fn default() -> Self {
+        Self { enabled: true, max_entries: 1024, ttl_secs: 3600 }
+    }
+}
+
+struct Entry {
+    session: SslSession,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl std::fmt::Debug for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Entry").field("stored", &self.stored).field("expires", &self.expires).finish_non_exhaustive()
+    }
+}
+
+/// Upstream handshakes to one destination
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Resumption {
+    pub handshakes: u64,
+    pub resumed: u64,
+}
+
+impl Resumption {
+    pub fn rate(&self) -> f64 {
+        if self.handshakes == 0 {
+            0.0
+        } else {
+            self.resumed as f64 / self.handshakes as f64
+        }
+    }
+}
+
+/// Cache contents and resumption counts as reported by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsSessionStats {
+    pub enabled: bool,
+    pub cached_sessions: usize,
+    pub handshakes: u64,
+    pub resumed: u64,
+    pub resumption_rate: f64,
+    /// Per destination, most handshakes first
+    pub hosts: Vec<HostResumption>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostResumption {
+    pub host: String,
+    pub handshakes: u64,
+    pub resumed: u64,
+    pub resumption_rate: f64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    sessions: HashMap<String, Entry>,
+    total: Resumption,
+    hosts: HashMap<String, Resumption>,
+}
+
+#[derive(Debug, Default)]
+pub struct TlsSessionCache {
+    options: TlsSessionCacheOptions,
+    state: Mutex<State>,
+}
+
+impl TlsSessionCache {
+    pub fn new(options: TlsSessionCacheOptions) -> Self {
+        Self { options, state: Mutex::default() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.options.enabled
+    }
+
+    /// Make connections from an upstream context hand their new sessions
+    /// to the cache under `key`
+    pub fn configure(self: &Arc<Self>, builder: &mut SslContextBuilder, key: &str) {
+        if !self.options.enabled {
+            builder.set_session_cache_mode(SslSessionCacheMode::OFF);
+            return;
+        }
+        builder.set_session_cache_mode(SslSessionCacheMode::CLIENT | SslSessionCacheMode::NO_INTERNAL);
+        let cache = self.clone();
+        let key = key.to_string();
+        builder.set_new_session_callback(move |_, session| cache.store(&key, session));
+    }
+
+    /// Offer the cached session for `key`, if any, on a new connection.
+    /// Returns whether one was offered.
+    pub fn resume(&self, ssl: &mut SslRef, key: &str) -> bool {
+        let Some(session) = self.get(key, Instant::now()) else {
+            return false;
+        };
+        // SAFETY: every upstream context is built the same way, with the
+        // same method, so a session from one is valid for the others.
+        match unsafe { ssl.set_session(&session) } {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Cannot offer cached TLS session for {}: {}", key, e);
+                false
+            }
+        }
+    }
+
+    fn get(&self, key: &str, now: Instant) -> Option<SslSession> {
+        if !self.options.enabled {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        match state.sessions.get(key) {
+            Some(entry) if entry.expires > now => Some(entry.session.clone()),
+            Some(_) => {
+                state.sessions.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn store(&self, key: &str, session: SslSession) {
+        // Keep a copy: OpenSSL marks the connection's own session as not
+        // resumable if the connection is dropped without a clean shutdown
+        let Ok(session) = session.to_der().and_then(|der| SslSession::from_der(&der)) else {
+            return;
+        };
+        let now = Instant::now();
+        let lifetime = Duration::from_secs((session.timeout().max(0) as u64).min(self.options.ttl_secs));
+        let mut state = self.state.lock().unwrap();
+        if !state.sessions.contains_key(key) && state.sessions.len() >= self.options.max_entries {
+            state.sessions.retain(|_, entry| entry.expires > now);
+            if state.sessions.len() >= self.options.max_entries {
+                let oldest = state.sessions.iter().min_by_key(|(_, entry)| entry.stored).map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    state.sessions.remove(&oldest);
+                }
+            }
+        }
+        if self.options.max_entries > 0 {
+            state.sessions.insert(key.to_string(), Entry { session, stored: now, expires: now + lifetime });
+        }
+    }
+
+    /// Count a completed upstream handshake to `key`
+    pub fn record_handshake(&self, key: &str, resumed: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.total.handshakes += 1;
+        state.total.resumed += resumed as u64;
+        // Destinations beyond `max_entries` only count towards the totals
+        if state.hosts.len() < self.options.max_entries || state.hosts.contains_key(key) {
+            let host = state.hosts.entry(key.to_string()).or_default();
+            host.handshakes += 1;
+            host.resumed += resumed as u64;
+        }
+    }
+
+    pub fn stats(&self) -> TlsSessionStats {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        let mut hosts: Vec<HostResumption> = state
+            .hosts
+            .iter()
+            .map(|(host, r)| HostResumption {
+                host: host.clone(),
+                handshakes: r.handshakes,
+                resumed: r.resumed,
+                resumption_rate: r.rate(),
+            })
+            .collect();
+        hosts.sort_by(|a, b| b.handshakes.cmp(&a.handshakes).then_with(|| a.host.cmp(&b.host)));
+        TlsSessionStats {
+            enabled: self.options.enabled,
+            cached_sessions: state.sessions.values().filter(|entry| entry.expires > now).count(),
+            handshakes: state.total.handshakes,
+            resumed: state.total.resumed,
+            resumption_rate: state.total.rate(),
+            hosts,
+        }
+    }
+
+    /// Drop all sessions and counts
+    pub fn clear(&self) {
+        *self.state.lock().unwrap() = State::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::PKey;
+    use openssl::ssl::{Ssl, SslAcceptor, SslContext, SslMethod, SslStream, SslVersion};
+    use openssl::x509::X509;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_sessions_resumed() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&X509::from_pem(cert.serialize_pem().unwrap().as_bytes()).unwrap()).unwrap();
+        acceptor.set_private_key(&PKey::private_key_from_pem(cert.serialize_private_key_pem().as_bytes()).unwrap()).unwrap();
+        // With TLS 1.2 the session is known when the handshake completes
+        acceptor.set_max_proto_version(Some(SslVersion::TLS1_2)).unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let key = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            for stream in listener.incoming().take(3) {
+                let mut tls = acceptor.accept(stream.unwrap()).unwrap();
+                tls.write_all(b"ok").unwrap();
+                tls.shutdown().ok();
+            }
+        });
+
+        let connect = |cache: &Arc<TlsSessionCache>| {
+            let mut builder = SslContext::builder(SslMethod::tls()).unwrap();
+            builder.set_verify(openssl::ssl::SslVerifyMode::NONE);
+            cache.configure(&mut builder, &key);
+            let mut ssl = Ssl::new(&builder.build()).unwrap();
+            cache.resume(&mut ssl, &key);
+            let mut tls = SslStream::new(ssl, TcpStream::connect(addr).unwrap()).unwrap();
+            tls.connect().unwrap();
+            tls.read_exact(&mut [0u8; 2]).unwrap();
+            let resumed = tls.ssl().session_reused();
+            cache.record_handshake(&key, resumed);
+            resumed
+        };
+
+        let cache = Arc::new(TlsSessionCache::new(TlsSessionCacheOptions::default()));
+        assert!(!connect(&cache));
+        assert!(connect(&cache));
+        let stats = cache.stats();
+        assert_eq!((stats.cached_sessions, stats.handshakes, stats.resumed), (1, 2, 1));
+        assert_eq!(stats.hosts[0].resumption_rate, 0.5);
+
+        let disabled = Arc::new(TlsSessionCache::new(TlsSessionCacheOptions { enabled: false, ..Default::default() }));
+        assert!(!connect(&disabled));
+        assert_eq!(disabled.stats().cached_sessions, 0);
+        server.join().unwrap();
+    }
+}
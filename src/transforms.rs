@@ -0,0 +1,186 @@
+//! Body transforms registered by addons.
+//!
+//! A transform decodes an organization-specific body encoding, such as an
+//! encrypted envelope or base64-wrapped JSON, into the bytes that should be
+//! inspected. Transforms are keyed by content type and run before content
+//! views and the `~b` body filter; the stored body is never changed. A
+//! transform that fails or panics leaves the body as it is.
+
+use serde::Serialize;
+use std::borrow::Cow;
+use std::sync::{Arc, OnceLock, RwLock};
+use tracing::{debug, error};
+
+use crate::panics;
+use crate::{Error, Result};
+
+/// Function decoding a body into its inspectable form
+pub type BodyTransformFn = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+/// A transform registered for a content type
+#[derive(Clone)]
+pub struct BodyTransform {
+    pub name: String,
+    /// Media type the transform applies to, e.g. `application/x-envelope`,
+    /// or all subtypes of a type with `application/*`
+    pub content_type: String,
+    /// Addon that registered the transform
+    pub owner: String,
+    transform: BodyTransformFn,
+}
+
+impl std::fmt::Debug for BodyTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BodyTransform")
+            .field("name", &self.name)
+            .field("content_type", &self.content_type)
+            .field("owner", &self.owner)
+            .finish()
+    }
+}
+
+impl BodyTransform {
+    fn applies_to(&self, media_type: &str) -> bool {
+        match self.content_type.strip_suffix("/*") {
+            Some(prefix) => media_type.split('/').next() == Some(prefix),
+            None => media_type == self.content_type,
+        }
+    }
+
+    fn apply(&self, body: &[u8]) -> Option<Vec<u8>> {
+        match panics::catch(|| (self.transform)(body)) {
+            Ok(Ok(data)) => Some(data),
+            Ok(Err(e)) => {
+                debug!("Body transform {} of addon {} failed: {}", self.name, self.owner, e);
+                None
+            }
+            Err(report) => {
+                error!("Body transform {} of addon {} {}", self.name, self.owner, report);
+                None
+            }
+        }
+    }
+}
+
+/// Summary of a registered transform
+#[derive(Debug, Clone, Serialize)]
+pub struct BodyTransformInfo {
+    pub name: String,
+    pub content_type: String,
+    pub owner: String,
+}
+
+/// A body after its transform ran
+#[derive(Debug, Clone)]
+pub struct Transformed<'a> {
+    pub data: Cow<'a, [u8]>,
+    /// Name of the transform that produced `data`, if any applied
+    pub transform: Option<String>,
+}
+
+fn transforms() -> &'static RwLock<Vec<BodyTransform>> {
+    static TRANSFORMS: OnceLock<RwLock<Vec<BodyTransform>>> = OnceLock::new();
+    TRANSFORMS.get_or_init(RwLock::default)
+}
+
+/// Lowercase media type of a `Content-Type` value, without parameters
+fn media_type(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+/// Register a transform for a content type. An exact media type takes
+/// precedence over a `type/*` pattern; among equal patterns the first
+/// registration wins. Names are unique across owners.
+pub fn register_body_transform(owner: &str, name: &str, content_type: &str, transform: BodyTransformFn) -> Result<()> {
+    let content_type = media_type(content_type);
+    let valid = content_type
+        .split_once('/')
+        .is_some_and(|(kind, subtype)| {
+            !kind.is_empty() && !kind.contains('*') && (subtype == "*" || !(subtype.is_empty() || subtype.contains('*')))
+        });
+    if !valid {
+        return Err(Error::invalid_request(format!("Invalid content type for body transform: {}", content_type)));
+    }
+    let mut transforms = transforms().write().unwrap();
+    if let Some(existing) = transforms.iter().find(|t| t.name == name) {
+        return Err(Error::invalid_request(format!(
+            "Body transform {} is already registered by {}",
+            name, existing.owner
+        )));
+    }
+    transforms.push(BodyTransform {
+        name: name.to_string(),
+        content_type,
+        owner: owner.to_string(),
+        transform,
+    });
+    Ok(())
+}
+
+/// Remove the transforms registered by `owner`
+pub fn unregister_body_transforms(owner: &str) {
+    transforms().write().unwrap().retain(|t| t.owner != owner);
+}
+
+/// Registered transforms in registration order
+pub fn list_body_transforms() -> Vec<BodyTransformInfo> {
+    transforms()
+        .read()
+        .unwrap()
+        .iter()
+        .map(|t| BodyTransformInfo { name: t.name.clone(), content_type: t.content_type.clone(), owner: t.owner.clone() })
+        .collect()
+}
+
+/// The body as it should be inspected: the output of the transform
+/// registered for its content type, or the body itself
+pub fn apply<'a>(content_type: Option<&str>, body: &'a [u8]) -> Transformed<'a> {
+    let untouched = Transformed { data: Cow::Borrowed(body), transform: None };
+    let Some(content_type) = content_type else {
+        return untouched;
+    };
+    let media_type = media_type(content_type);
+    let transforms = transforms().read().unwrap();
+    let exact = transforms.iter().find(|t| t.content_type == media_type);
+    let Some(transform) = exact.or_else(|| transforms.iter().find(|t| t.applies_to(&media_type))) else {
+        return untouched;
+    };
+    match transform.apply(body) {
+        Some(data) => Transformed { data: Cow::Owned(data), transform: Some(transform.name.clone()) },
+        None => untouched,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    #[test]
+    fn test_transforms_by_content_type() {
+        let base64: BodyTransformFn = Arc::new(|body| STANDARD.decode(body).map_err(|e| Error::invalid_request(e.to_string())));
+        let upper: BodyTransformFn = Arc::new(|body| Ok(body.to_ascii_uppercase()));
+        register_body_transform("envelope", "b64-json", "application/x-b64-json; charset=utf-8", base64).unwrap();
+        register_body_transform("envelope", "upper", "x-test/envelope-*", upper.clone()).unwrap_err();
+        register_body_transform("envelope", "upper", "x-test/*", upper.clone()).unwrap();
+        assert!(register_body_transform("other", "upper", "x-test/plain", upper).is_err());
+
+        let encoded = STANDARD.encode(br#"{"a":1}"#);
+        let transformed = apply(Some("Application/X-B64-JSON"), encoded.as_bytes());
+        assert_eq!(transformed.data.as_ref(), br#"{"a":1}"#);
+        assert_eq!(transformed.transform.as_deref(), Some("b64-json"));
+
+        assert_eq!(apply(Some("x-test/envelope"), b"abc").data.as_ref(), b"ABC");
+        assert!(apply(Some("application/json"), b"abc").transform.is_none());
+        assert!(apply(None, b"abc").transform.is_none());
+        // A body the transform cannot decode is left as it is
+        let failed = apply(Some("application/x-b64-json"), b"not base64!");
+        assert_eq!(failed.data.as_ref(), b"not base64!");
+        assert!(failed.transform.is_none());
+
+        unregister_body_transforms("envelope");
+        assert!(!list_body_transforms().iter().any(|t| t.owner == "envelope"));
+        assert!(apply(Some("x-test/envelope"), b"abc").transform.is_none());
+    }
+}
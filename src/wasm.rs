@@ -0,0 +1,245 @@
+//! WebAssembly plugins with flow hooks.
+//!
+//! Modules listed in `wasm_plugins` are loaded at startup and registered as
+//! addons. Their hooks see the flow as dump-format JSON and may hand back a
+//! changed flow in the same format:
+//!
+//! - the module exports its `memory` and `alloc(len: i32) -> i32`, returning
+//!   space for the `len` bytes of JSON the proxy writes into it;
+//! - `on_request(ptr: i32, len: i32) -> i64` and `on_response` with the same
+//!   signature are called with that buffer and return `0` to leave the flow
+//!   as it is, or `ptr << 32 | len` of the JSON of the changed flow;
+//! - `mitmproxy.log(ptr: i32, len: i32)` may be imported to log a UTF-8
+//!   message.
+//!
+//! Each hook call runs in a fresh instance, within the limits of the script
+//! [`Sandbox`]: operations are metered as fuel, memory is capped, and the
+//! changes of a call that overran its time budget are dropped. Plugins have
+//! no filesystem or network access.
+
+use std::fmt;
+use std::path::Path;
+use tracing::{info, warn};
+use wasmi::{Caller, Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::addons::Addon;
+use crate::flow::HTTPFlow;
+use crate::sandbox::Sandbox;
+use crate::{Error, Result};
+
+/// Exported functions called as hooks
+const HOOKS: [&str; 2] = ["on_request", "on_response"];
+
+/// Host state of one hook call
+struct State {
+    plugin: String,
+    limits: StoreLimits,
+}
+
+/// A loaded WebAssembly module, registered as an addon
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    linker: Linker<State>,
+    sandbox: Sandbox,
+}
+
+impl fmt::Debug for WasmPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmPlugin").field("name", &self.name).finish()
+    }
+}
+
+impl WasmPlugin {
+    /// Load the module at `path`, named after its file name
+    pub fn load<P: AsRef<Path>>(path: P, sandbox: &Sandbox) -> Result<Self> {
+        let path = path.as_ref();
+        let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned();
+        Self::new(name, &std::fs::read(path)?, sandbox)
+    }
+
+    /// A plugin running the binary module `wasm`
+    pub fn new(name: impl Into<String>, wasm: &[u8], sandbox: &Sandbox) -> Result<Self> {
+        let name = name.into();
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(|e| plugin_error(&name, e))?;
+
+        let exports_func = |export: &str| module.get_export(export).is_some_and(|ty| ty.func().is_some());
+        if module.get_export("memory").and_then(|ty| ty.memory().copied()).is_none() || !exports_func("alloc") {
+            return Err(plugin_error(&name, "does not export `memory` and `alloc`"));
+        }
+        if !HOOKS.iter().any(|hook| exports_func(hook)) {
+            return Err(plugin_error(&name, format!("exports none of {}", HOOKS.join(", "))));
+        }
+
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap("mitmproxy", "log", |caller: Caller<'_, State>, ptr: i32, len: i32| {
+                let Some(memory) = caller.get_export("memory").and_then(|export| export.into_memory()) else {
+                    return;
+                };
+                let start = ptr as u32 as usize;
+                if let Some(message) = memory.data(&caller).get(start..start.saturating_add(len as u32 as usize)) {
+                    info!("[{}] {}", caller.data().plugin, String::from_utf8_lossy(message));
+                }
+            })
+            .map_err(|e| plugin_error(&name, e))?;
+
+        Ok(Self { name, engine, module, linker, sandbox: sandbox.clone() })
+    }
+
+    /// Run `hook` on `flow`, replacing it with the flow the hook returns.
+    /// Hooks the module does not export are skipped.
+    pub fn call(&self, hook: &str, flow: &mut HTTPFlow) -> Result<()> {
+        if self.module.get_export(hook).is_none() {
+            return Ok(());
+        }
+        let error = |e: &dyn fmt::Display| plugin_error(&self.name, format!("{}: {}", hook, e));
+        let input = serde_json::to_vec(&*flow)?;
+        let input_len = i32::try_from(input.len()).map_err(|e| error(&e))?;
+
+        let options = self.sandbox.options();
+        let mut invocation = self.sandbox.invocation();
+        let limits = StoreLimitsBuilder::new().memory_size(options.max_memory_bytes).build();
+        let mut store = Store::new(&self.engine, State { plugin: self.name.clone(), limits });
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(options.max_operations).map_err(|e| error(&e))?;
+
+        let instance = self
+            .linker
+            .instantiate(&mut store, &self.module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(|e| error(&e))?;
+        let memory = instance.get_memory(&store, "memory").ok_or_else(|| error(&"no memory export"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc").map_err(|e| error(&e))?;
+        let ptr = alloc.call(&mut store, input_len).map_err(|e| error(&e))?;
+        memory.write(&mut store, ptr as u32 as usize, &input).map_err(|e| error(&e))?;
+        let func = instance.get_typed_func::<(i32, i32), i64>(&store, hook).map_err(|e| error(&e))?;
+        let result = func.call(&mut store, (ptr, input_len)).map_err(|e| error(&e))?;
+
+        let fuel = store.get_fuel().map_err(|e| error(&e))?;
+        invocation.tick(options.max_operations.saturating_sub(fuel)).map_err(|e| error(&e))?;
+        if result == 0 {
+            return Ok(());
+        }
+        let (ptr, len) = ((result as u64 >> 32) as usize, (result as u64 & 0xffff_ffff) as usize);
+        let mut output = vec![0; len];
+        memory.read(&store, ptr, &mut output).map_err(|e| error(&e))?;
+        let mut changed: HTTPFlow = serde_json::from_slice(&output).map_err(|e| error(&e))?;
+        // A plugin edits the flow, it cannot make it another one
+        changed.flow.id = flow.flow.id.clone();
+        *flow = changed;
+        Ok(())
+    }
+
+    fn run_hook(&self, hook: &str, flow: &mut HTTPFlow) {
+        if let Err(e) = self.call(hook, flow) {
+            warn!("{}", e);
+        }
+    }
+}
+
+impl Addon for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn request(&self, flow: &mut HTTPFlow) {
+        self.run_hook("on_request", flow);
+    }
+
+    fn response(&self, flow: &mut HTTPFlow) {
+        self.run_hook("on_response", flow);
+    }
+}
+
+fn plugin_error(name: &str, msg: impl fmt::Display) -> Error {
+    Error::Proxy(format!("WASM plugin {}: {}", name, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{HTTPRequest, HTTPResponse};
+    use crate::sandbox::SandboxOptions;
+
+    const ALLOC: &str = r#"(memory (export "memory") 2) (func (export "alloc") (param i32) (result i32) i32.const 65536)"#;
+
+    fn plugin(body: &str, options: &SandboxOptions) -> Result<WasmPlugin> {
+        let wasm = wat::parse_str(format!("(module {})", body)).unwrap();
+        WasmPlugin::new("test.wasm", &wasm, &Sandbox::new(options).unwrap())
+    }
+
+    fn flow() -> HTTPFlow {
+        HTTPFlow::new(HTTPRequest::new(
+            "GET".to_string(),
+            "https".to_string(),
+            "example.com".to_string(),
+            443,
+            "/".to_string(),
+        ))
+    }
+
+    #[test]
+    fn test_wasm_plugin_hooks() {
+        let options = SandboxOptions::default();
+        assert!(plugin(ALLOC, &options).is_err());
+
+        // Answers every request with a flow held in the module's data
+        let mut answered = flow();
+        answered.response = Some(HTTPResponse::new(418, "I'm a teapot".to_string()));
+        let json = serde_json::to_string(&answered).unwrap();
+        let escaped: String = json.bytes().map(|b| format!("\\{:02x}", b)).collect();
+        let body = format!(
+            r#"(import "mitmproxy" "log" (func $log (param i32 i32))) {} (data (i32.const 0) "{}")
+               (func (export "on_request") (param i32 i32) (result i64)
+                 (call $log (i32.const 0) (i32.const 9))
+                 (i64.const {}))"#,
+            ALLOC,
+            escaped,
+            json.len()
+        );
+        let teapot = plugin(&body, &options).unwrap();
+        let mut flow = flow();
+        teapot.request(&mut flow);
+        assert_eq!(flow.response.as_ref().unwrap().status_code, 418);
+        assert_ne!(flow.flow.id, answered.flow.id);
+        // Without an on_response export the hook leaves the flow alone
+        flow.response = None;
+        teapot.response(&mut flow);
+        assert!(flow.response.is_none());
+
+        let unchanged = plugin(
+            &format!(r#"{} (func (export "on_response") (param i32 i32) (result i64) i64.const 0)"#, ALLOC),
+            &options,
+        )
+        .unwrap();
+        let before = serde_json::to_value(&flow).unwrap();
+        unchanged.call("on_response", &mut flow).unwrap();
+        assert_eq!(serde_json::to_value(&flow).unwrap(), before);
+    }
+
+    #[test]
+    fn test_wasm_plugin_limits() {
+        let options = SandboxOptions { max_operations: 10_000, max_memory_bytes: 4 * 65536, ..Default::default() };
+        let spinning = plugin(
+            &format!(r#"{} (func (export "on_request") (param i32 i32) (result i64) (loop (br 0)) i64.const 0)"#, ALLOC),
+            &options,
+        )
+        .unwrap();
+        let mut flow = flow();
+        assert!(spinning.call("on_request", &mut flow).unwrap_err().to_string().contains("fuel"));
+
+        // Traps when it cannot grow its memory by eight pages
+        let growing = format!(
+            r#"{} (func (export "on_request") (param i32 i32) (result i64)
+                 (if (i32.eq (memory.grow (i32.const 8)) (i32.const -1)) (then unreachable)) i64.const 0)"#,
+            ALLOC
+        );
+        assert!(plugin(&growing, &options).unwrap().call("on_request", &mut flow).is_err());
+        assert!(plugin(&growing, &SandboxOptions::default()).unwrap().call("on_request", &mut flow).is_ok());
+    }
+}
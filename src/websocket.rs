@@ -40,7 +40,7 @@ impl WebSocketConnection {
         self.closed_by_client = Some(by_client);
         self.close_code = code;
         self.close_reason = reason;
-        self.timestamp_end = Some(chrono::Utc::now().timestamp() as f64);
+        self.timestamp_end = Some(crate::clock::now());
     }
 
     pub fn to_flow(&self) -> WebSocketFlow {
@@ -67,7 +67,7 @@ impl WebSocketConnection {
         msg: &Message,
         from_client: bool,
     ) -> Result<WebSocketMessage> {
-        let timestamp = chrono::Utc::now().timestamp() as f64;
+        let timestamp = crate::clock::now();
 
         let (content, message_type) = match msg {
             Message::Text(text) => (text.as_bytes().to_vec(), WebSocketMessageType::Text),
@@ -251,6 +251,49 @@ impl WebSocketUpgradeInfo {
     }
 }
 
+/// What a close rule does to matching WebSocket connections
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebSocketCloseAction {
+    /// Close both sides with the rule's code as soon as the connection opens
+    #[default]
+    Close,
+    /// Relay close frames from either side with the rule's code and reason
+    Rewrite,
+}
+
+/// Rule closing WebSocket connections, or rewriting their close frames, as
+/// configured in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSocketCloseRule {
+    /// Filter expression selecting flows, e.g. `~d chat.example.com`
+    pub filter: String,
+    /// Status code of the close frames sent
+    pub code: u16,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub action: WebSocketCloseAction,
+}
+
+impl WebSocketCloseRule {
+    pub fn validate(&self) -> Result<()> {
+        if !is_sendable_close_code(self.code) {
+            return Err(Error::invalid_request(format!("WebSocket close code {} cannot be sent", self.code)));
+        }
+        if self.reason.as_ref().is_some_and(|r| r.len() > 123) {
+            return Err(Error::invalid_request("WebSocket close reasons are limited to 123 bytes"));
+        }
+        Ok(())
+    }
+}
+
+/// Whether a close frame may carry `code` (RFC 6455, section 7.4). Codes
+/// such as 1006 only describe what happened and never go on the wire.
+pub fn is_sendable_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -356,4 +399,12 @@ mod tests {
         assert_eq!(upgrade_info.websocket_accept, "test-accept");
         assert_eq!(upgrade_info.websocket_protocol, Some("chat".to_string()));
     }
+
+    #[test]
+    fn test_close_rule_validation() {
+        let rule = WebSocketCloseRule { filter: "~websocket".to_string(), code: 4000, reason: None, action: Default::default() };
+        assert!(rule.validate().is_ok());
+        assert!(WebSocketCloseRule { code: 1006, ..rule.clone() }.validate().is_err());
+        assert!(WebSocketCloseRule { reason: Some("x".repeat(124)), ..rule }.validate().is_err());
+    }
 }
\ No newline at end of file